@@ -12,14 +12,20 @@ struct TerrainConfig {
     minmax_size: f32,
     albedo_size: f32,
     _empty: f32,
+    _empty: vec4<f32>,
     height_scale: f32,
     minmax_scale: f32,
     albedo_scale: f32,
     _empty: f32,
+    _empty: vec4<f32>,
     height_offset: f32,
     minmax_offset: f32,
     albedo_offset: f32,
     _empty: f32,
+    _empty: vec4<f32>,
+
+    terrain_extent: vec2<f32>,
+    planet_radius: f32,
 }
 
 // view bindings
@@ -46,6 +52,19 @@ var minmax_atlas: texture_2d_array<f32>;
 #ifdef ALBEDO
 @group(2) @binding(4)
 var albedo_atlas: texture_2d_array<f32>;
+#ifdef ATLAS_SHARDS
+// The further shards of the attachment, if the node atlas is sharded.
+@group(2) @binding(#{ATTACHMENT_2_SHARD_1_BINDING})
+var albedo_atlas_1: texture_2d_array<f32>;
+#ifdef ATLAS_SHARD_2
+@group(2) @binding(#{ATTACHMENT_2_SHARD_2_BINDING})
+var albedo_atlas_2: texture_2d_array<f32>;
+#endif
+#ifdef ATLAS_SHARD_3
+@group(2) @binding(#{ATTACHMENT_2_SHARD_3_BINDING})
+var albedo_atlas_3: texture_2d_array<f32>;
+#endif
+#endif
 #endif
 
 // Customize your material data here.
@@ -98,19 +117,34 @@ fn lookup_fragment_data(input: FragmentInput, lookup: NodeLookup, ddx: vec2<f32>
     let world_normal = calculate_normal(height_coords, atlas_index, atlas_lod, height_ddx, height_ddy);
 
 #ifdef ALBEDO
+#ifdef ATLAS_SHARDS
+    // Sample your attachments through the shard, which stores the atlas index.
+    // The third and fourth shard are only bound, if the device supports enough textures.
+    var color = sample_sharded(
+        albedo_atlas,
+        albedo_atlas_1,
+#ifdef ATLAS_SHARD_2
+        albedo_atlas_2,
+#endif
+#ifdef ATLAS_SHARD_3
+        albedo_atlas_3,
+#endif
+        albedo_coords, atlas_index, albedo_ddx, albedo_ddy);
+#else
 #ifdef SAMPLE_GRAD
     var color = textureSampleGrad(albedo_atlas, atlas_sampler, albedo_coords, atlas_index, albedo_ddx, albedo_ddy);
 #else
     var color = textureSample(albedo_atlas, atlas_sampler, albedo_coords, atlas_index);
     // var color = textureSampleLevel(albedo_atlas, atlas_sampler, albedo_coords, atlas_index, 0.0);
 #endif
+#endif
 
 #else
     var color = vec4<f32>(0.5);
 #endif
 
 #ifdef SHOW_LOD
-    color = mix(color, show_lod(atlas_lod, input.world_position.xyz), 0.4);
+    color = mix(color, show_lod(atlas_lod, input.terrain_position.xyz), 0.4);
 #endif
 
     return FragmentData(world_normal, color);
@@ -131,7 +165,7 @@ fn process_fragment(in: FragmentInput, data: FragmentData) -> Fragment {
     var color = data.color;
 
 #ifndef ALBEDO
-    let height = in.world_position.y / config.height;
+    let height = in.terrain_position.y / config.height;
     let slope = world_normal.y;
 
     let min_slope = 0.6;
@@ -160,9 +194,9 @@ fn process_fragment(in: FragmentInput, data: FragmentData) -> Fragment {
     pbr_input.material.reflectance = 0.1;
     pbr_input.frag_coord = in.frag_coord;
     pbr_input.world_position = in.world_position;
-    pbr_input.world_normal = world_normal;
+    pbr_input.world_normal = planet_normal(in.local_position, world_normal);
     pbr_input.is_orthographic = view.projection[3].w == 1.0;
-    pbr_input.N = world_normal;
+    pbr_input.N = pbr_input.world_normal;
     pbr_input.V = calculate_view(in.world_position, pbr_input.is_orthographic);
 
     color = pbr(pbr_input);
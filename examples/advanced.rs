@@ -36,6 +36,7 @@ fn main() {
         }))
         .add_plugin(TerrainPlugin {
             attachment_count: 3, // has to match the attachments of the terrain
+            ..default()
         })
         .add_plugin(TerrainDebugPlugin)
         .add_plugin(TerrainMaterialPlugin::<TerrainMaterial>::default())
@@ -78,6 +79,7 @@ fn setup(
             path: "assets/terrain/source/height".to_string(),
             size: TERRAIN_SIZE,
             file_format: FileFormat::PNG,
+            ..default()
         },
     );
 
@@ -95,6 +97,7 @@ fn setup(
             path: "assets/terrain/source/albedo.png".to_string(),
             size: TERRAIN_SIZE,
             file_format: FileFormat::PNG,
+            ..default()
         },
     );
 
@@ -0,0 +1,297 @@
+//! Node archives, which bundle all node files of a terrain into a single `.pak` or `.zip` file.
+//!
+//! Shipping a terrain as tens of thousands of loose node files is slow to install and
+//! to scan, so the preprocessed `data` directory can be packed into one archive instead.
+//! The archive stores the node files back to back, followed by an index, which maps
+//! the path of each node file (relative to the `data` directory) to its location.
+//! Only the index is read, when the archive is opened, the node files are read on demand.
+//! The node files are read with positional reads, so that the loading tasks can read
+//! from the archive concurrently.
+//!
+//! With the `zip` feature, zip archives (stored or deflated), which contain the node files
+//! of the `data` directory at their root, can be opened as well.
+//! Their central directory serves as the index, but the node files are decompressed
+//! one at a time, since the zip reader is shared between the loading tasks.
+//! Other archive formats have to be unpacked and packed again with [`NodeArchive::pack`].
+//!
+//! Pack the archive with [`NodeArchive::pack`] and load from it with
+//! [`AttachmentFromDiskLoader::set_archive`](crate::attachment_loader::AttachmentFromDiskLoader::set_archive).
+
+use anyhow::{anyhow, Result};
+use bevy::utils::HashMap;
+#[cfg(feature = "zip")]
+use bevy::utils::HashSet;
+use bincode::{config, Decode, Encode};
+#[cfg(feature = "zip")]
+use std::sync::Mutex;
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+#[cfg(feature = "zip")]
+use zip::ZipArchive;
+
+/// The magic number at the start of the index.
+const PAK_MAGIC: [u8; 4] = *b"TPAK";
+
+/// The version of the current layout of the archives.
+pub const PAK_VERSION: u32 = 1;
+
+/// The location of a node file inside the archive.
+#[derive(Encode, Decode, Clone, Copy, Debug)]
+struct PakEntry {
+    offset: u64,
+    size: u64,
+}
+
+/// The index at the end of the archive.
+#[derive(Encode, Decode, Debug)]
+struct PakIndex {
+    magic: [u8; 4],
+    version: u32,
+    entries: Vec<(String, PakEntry)>,
+}
+
+/// The layout of an opened archive.
+enum ArchiveSource {
+    Pak {
+        file: File,
+        entries: HashMap<String, PakEntry>,
+        /// The end of the node files, where the index starts.
+        data_end: u64,
+    },
+    #[cfg(feature = "zip")]
+    Zip {
+        archive: Mutex<ZipArchive<File>>,
+        names: HashSet<String>,
+    },
+}
+
+/// An archive of node files, which is read on demand.
+pub struct NodeArchive {
+    source: ArchiveSource,
+}
+
+impl NodeArchive {
+    /// Opens the archive at the path and reads its index.
+    ///
+    /// Files with the `zip` extension are opened as zip archives, which requires the `zip` feature.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let is_zip = path
+            .as_ref()
+            .extension()
+            .map_or(false, |extension| extension.eq_ignore_ascii_case("zip"));
+
+        if is_zip {
+            return Self::open_zip(path.as_ref());
+        }
+
+        let mut file = File::open(path.as_ref())?;
+
+        // the last eight bytes store the offset of the index
+        let mut index_offset = [0; 8];
+        let end = file.seek(SeekFrom::End(-(index_offset.len() as i64)))?;
+        file.read_exact(&mut index_offset)?;
+        let index_offset = u64::from_le_bytes(index_offset);
+
+        if index_offset > end {
+            return Err(anyhow!(
+                "The file {:?} is not a node archive.",
+                path.as_ref()
+            ));
+        }
+
+        let mut encoded = vec![0; (end - index_offset) as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut encoded)?;
+
+        let (index, _): (PakIndex, _) = bincode::decode_from_slice(&encoded, config::standard())?;
+
+        if index.magic != PAK_MAGIC {
+            return Err(anyhow!(
+                "The file {:?} is not a node archive.",
+                path.as_ref()
+            ));
+        }
+
+        if index.version != PAK_VERSION {
+            return Err(anyhow!(
+                "The node archive version {} is not supported.",
+                index.version
+            ));
+        }
+
+        Ok(Self {
+            source: ArchiveSource::Pak {
+                file,
+                entries: index.entries.into_iter().collect(),
+                data_end: index_offset,
+            },
+        })
+    }
+
+    #[cfg(feature = "zip")]
+    fn open_zip(path: &Path) -> Result<Self> {
+        let archive = ZipArchive::new(File::open(path)?)?;
+        let names = archive.file_names().map(String::from).collect();
+
+        Ok(Self {
+            source: ArchiveSource::Zip {
+                archive: Mutex::new(archive),
+                names,
+            },
+        })
+    }
+
+    #[cfg(not(feature = "zip"))]
+    fn open_zip(path: &Path) -> Result<Self> {
+        Err(anyhow!(
+            "The zip archive {path:?} can only be opened with the `zip` feature."
+        ))
+    }
+
+    /// Packs all files inside the directory (e.g. the `data` directory of a terrain)
+    /// into a new archive at the path, which must lie outside of the directory.
+    pub fn pack(directory: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<()> {
+        let mut files = Vec::new();
+        collect_files(directory.as_ref(), "", &mut files)?;
+        files.sort();
+
+        let mut archive = File::create(path)?;
+        let mut entries = Vec::with_capacity(files.len());
+        let mut offset = 0;
+
+        for name in files {
+            let bytes = fs::read(directory.as_ref().join(&name))?;
+            archive.write_all(&bytes)?;
+
+            let size = bytes.len() as u64;
+            entries.push((name, PakEntry { offset, size }));
+            offset += size;
+        }
+
+        let index = PakIndex {
+            magic: PAK_MAGIC,
+            version: PAK_VERSION,
+            entries,
+        };
+
+        archive.write_all(&bincode::encode_to_vec(index, config::standard())?)?;
+        archive.write_all(&offset.to_le_bytes())?;
+
+        Ok(())
+    }
+
+    /// Returns whether the archive contains the file (relative to the packed directory).
+    pub fn contains(&self, name: &str) -> bool {
+        match &self.source {
+            ArchiveSource::Pak { entries, .. } => entries.contains_key(name),
+            #[cfg(feature = "zip")]
+            ArchiveSource::Zip { names, .. } => names.contains(name),
+        }
+    }
+
+    /// Reads the file (relative to the packed directory) from the archive.
+    pub fn read(&self, name: &str) -> Result<Vec<u8>> {
+        match &self.source {
+            ArchiveSource::Pak {
+                file,
+                entries,
+                data_end,
+            } => {
+                let entry = entries
+                    .get(name)
+                    .ok_or_else(|| anyhow!("The node archive contains no file {name}."))?;
+
+                // a corrupted index must not allocate more than the archive holds
+                if entry
+                    .offset
+                    .checked_add(entry.size)
+                    .map_or(true, |end| end > *data_end)
+                {
+                    return Err(anyhow!("The file {name} lies outside of the node archive."));
+                }
+
+                let mut bytes = vec![0; entry.size as usize];
+                read_exact_at(file, &mut bytes, entry.offset)?;
+
+                Ok(bytes)
+            }
+            #[cfg(feature = "zip")]
+            ArchiveSource::Zip { archive, .. } => {
+                let mut archive = archive.lock().unwrap();
+                let mut file = archive.by_name(name)?;
+
+                // the size stored in the archive is not trusted for the allocation
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+/// Fills the buffer with the bytes of the file starting at the offset,
+/// without moving the cursor of the file.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_exact_at(buffer, offset)
+}
+
+/// Fills the buffer with the bytes of the file starting at the offset.
+///
+/// Unlike on unix, this moves the cursor of the file, which the archive does not rely on.
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buffer: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buffer = &mut buffer[read..];
+                offset += read as u64;
+            }
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_exact_at(_file: &File, _buffer: &mut [u8], _offset: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Collects the paths of all files below the directory, separated by forward slashes.
+fn collect_files(root: &Path, prefix: &str, files: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(root.join(prefix))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        // skip hidden files, like the preprocessor does
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let name = if prefix.is_empty() {
+            name
+        } else {
+            format!("{prefix}/{name}")
+        };
+
+        if entry.file_type()?.is_dir() {
+            collect_files(root, &name, files)?;
+        } else {
+            files.push(name);
+        }
+    }
+
+    Ok(())
+}
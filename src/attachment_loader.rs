@@ -1,104 +1,440 @@
 //! The default attachment loader, which loads node data from disk.
+//!
+//! The loose node files of each attachment are read through a [`DiskSource`],
+//! while the attachments of packed node files share a single read of each file.
 
-use crate::terrain_data::{
-    node_atlas::NodeAtlas, AttachmentConfig, AttachmentIndex, FileFormat, NodeId,
+#[cfg(feature = "tnf")]
+use crate::formats::tnf::TNF;
+use crate::{
+    archive::NodeArchive,
+    formats::{decode_image, quantized_mesh::QuantizedMesh, terrain_rgb::decode_heights},
+    node_source::{finish_loading_node, NodeSource},
+    terrain::TerrainConfig,
+    terrain_data::{node_atlas::NodeAtlas, AttachmentConfig, AttachmentIndex, FileFormat, NodeId},
 };
+use anyhow::{anyhow, Result};
 use bevy::{
-    asset::{AssetServer, HandleId, LoadState},
+    asset::AssetServer,
     prelude::*,
-    render::render_resource::*,
+    render::{render_resource::*, renderer::RenderDevice, texture::CompressedImageFormats},
+    tasks::IoTaskPool,
     utils::HashMap,
 };
+use crossbeam_channel::{Receiver, Sender};
+use std::{path::Path, sync::Arc};
 
+/// A custom decoder, which turns the bytes of a node file into the image of an attachment.
+///
+/// It runs on the [`IoTaskPool`], so that expensive decoding (e.g. decompression or dequantization)
+/// does not block the main thread.
+pub type NodeDecoder = Arc<dyn Fn(&[u8]) -> Result<Image> + Send + Sync>;
+
+#[derive(Clone)]
 pub(crate) struct AttachmentFromDisk {
+    pub(crate) name: String,
+    /// The directory of the node files, relative to the `data` directory of the terrain.
+    pub(crate) directory: String,
     pub(crate) path: String,
+    pub(crate) texture_size: u32,
+    pub(crate) border_size: u32,
     pub(crate) format: TextureFormat,
     pub(crate) file_format: FileFormat,
+    /// Replaces the decoding of the file format, if present.
+    pub(crate) decoder: Option<NodeDecoder>,
+    /// Replaces the loose node files, if present.
+    pub(crate) archive: Option<Arc<NodeArchive>>,
 }
 
 impl AttachmentFromDisk {
     pub(crate) fn new(attachment: &AttachmentConfig, path: &str) -> Self {
+        // packed nodes store all attachments in one shared file
+        let directory = match attachment.file_format {
+            FileFormat::TNF => "nodes".to_string(),
+            _ => attachment.name.clone(),
+        };
+
         Self {
-            path: format!("{}/data/{}", path, attachment.name),
+            name: attachment.name.clone(),
+            path: format!("{path}/data/{directory}"),
+            directory,
+            texture_size: attachment.texture_size,
+            border_size: attachment.border_size,
             format: attachment.format.into(),
             file_format: attachment.file_format,
+            decoder: None,
+            archive: None,
+        }
+    }
+
+    /// Reads the node file of the node, either from the archive or through the asset server.
+    pub(crate) async fn read(
+        &self,
+        asset_server: &AssetServer,
+        node_id: NodeId,
+    ) -> Result<Vec<u8>> {
+        let file_name = format!("{node_id}.{}", self.file_format.extension());
+
+        match &self.archive {
+            Some(archive) => archive.read(&format!("{}/{file_name}", self.directory)),
+            None => {
+                let path = format!("{}/{file_name}", self.path);
+                Ok(asset_server.asset_io().load_path(Path::new(&path)).await?)
+            }
+        }
+    }
+
+    /// Decodes the node file into the data of the node.
+    pub(crate) fn decode(
+        &self,
+        bytes: &[u8],
+        height: f32,
+        compressed_formats: CompressedImageFormats,
+    ) -> Result<Image> {
+        if let Some(decoder) = &self.decoder {
+            return decoder(bytes);
+        }
+
+        let image = match self.file_format {
+            FileFormat::QuantizedMesh { min_elevation } => QuantizedMesh::decode(bytes)?.to_image(
+                self.texture_size,
+                self.border_size,
+                self.format,
+                min_elevation,
+                height,
+            )?,
+            #[cfg(feature = "tnf")]
+            FileFormat::TNF => self.decode_packed(&TNF::decode_alloc(bytes)?)?,
+            #[cfg(not(feature = "tnf"))]
+            FileFormat::TNF => return Err(anyhow!("Enable the tnf feature to load packed nodes.")),
+            file_format => {
+                let mut image = decode_image(bytes, file_format, compressed_formats)?;
+
+                if let FileFormat::TerrainRGB { min_elevation } = file_format {
+                    image.data = decode_heights(&image.data, self.format, min_elevation, height)?;
+                }
+
+                image
+            }
+        };
+
+        Ok(image)
+    }
+
+    /// Decodes the attachment from the packed node file.
+    #[cfg(feature = "tnf")]
+    fn decode_packed(&self, node: &TNF) -> Result<Image> {
+        node.attachment(&self.name)
+            .ok_or_else(|| anyhow!("The node file has no attachment {}.", self.name))?
+            .to_image()
+    }
+}
+
+/// Decodes the attachments from the bytes of a packed node file, which is decompressed only once.
+fn decode_packed_node(
+    bytes: &[u8],
+    attachments: &[(AttachmentIndex, AttachmentFromDisk)],
+    height: f32,
+    compressed_formats: CompressedImageFormats,
+) -> Vec<(AttachmentIndex, Result<Image>)> {
+    #[cfg(feature = "tnf")]
+    let node = TNF::decode_alloc(bytes);
+
+    attachments
+        .iter()
+        .map(|(attachment_index, attachment)| {
+            #[cfg(feature = "tnf")]
+            let image = match (&attachment.decoder, &node) {
+                (None, Ok(node)) => attachment.decode_packed(node),
+                (None, Err(error)) => Err(anyhow!("{error}")),
+                (Some(_), _) => attachment.decode(bytes, height, compressed_formats),
+            };
+            #[cfg(not(feature = "tnf"))]
+            let image = attachment.decode(bytes, height, compressed_formats);
+
+            (*attachment_index, image)
+        })
+        .collect()
+}
+
+/// A node source, that reads and decodes the node files of one attachment from disk.
+///
+/// The node files are read and decoded asynchronously on the [`IoTaskPool`] and
+/// the finished nodes are delivered back through a channel.
+/// Nodes, whose files are missing or invalid, finish loading without the attachment.
+pub struct DiskSource {
+    attachment: AttachmentFromDisk,
+    asset_server: AssetServer,
+    height: f32,
+    compressed_formats: CompressedImageFormats,
+    sender: Sender<(NodeId, Result<Image>)>,
+    receiver: Receiver<(NodeId, Result<Image>)>,
+}
+
+impl DiskSource {
+    /// Creates a disk source for the preprocessed attachment of the terrain.
+    ///
+    /// Compressed node files are only decoded, if their format is in the compressed formats.
+    pub fn new(
+        config: &TerrainConfig,
+        attachment: &AttachmentConfig,
+        asset_server: AssetServer,
+        compressed_formats: CompressedImageFormats,
+    ) -> Self {
+        Self::from_attachment(
+            AttachmentFromDisk::new(attachment, &config.path),
+            asset_server,
+            config.height,
+            compressed_formats,
+        )
+    }
+
+    fn from_attachment(
+        attachment: AttachmentFromDisk,
+        asset_server: AssetServer,
+        height: f32,
+        compressed_formats: CompressedImageFormats,
+    ) -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        Self {
+            attachment,
+            asset_server,
+            height,
+            compressed_formats,
+            sender,
+            receiver,
         }
     }
 }
 
+impl NodeSource for DiskSource {
+    fn request(&mut self, node_id: NodeId) {
+        let attachment = self.attachment.clone();
+        let asset_server = self.asset_server.clone();
+        let (height, compressed_formats) = (self.height, self.compressed_formats);
+        let sender = self.sender.clone();
+
+        IoTaskPool::get()
+            .spawn(async move {
+                let image = attachment
+                    .read(&asset_server, node_id)
+                    .await
+                    .and_then(|bytes| attachment.decode(&bytes, height, compressed_formats));
+
+                // the source might have been dropped in the meantime
+                let _ = sender.send((node_id, image));
+            })
+            .detach();
+    }
+
+    fn poll(&mut self, images: &mut Assets<Image>) -> Vec<(NodeId, Result<Handle<Image>>)> {
+        self.receiver
+            .try_iter()
+            .map(|(node_id, image)| {
+                let handle = image.map(|mut image| {
+                    image.texture_descriptor.format = self.attachment.format;
+                    images.add(image)
+                });
+
+                (node_id, handle)
+            })
+            .collect()
+    }
+}
+
+/// A packed attachment of a node, which has been read and decoded on the [`IoTaskPool`].
+struct LoadedAttachment {
+    node_id: NodeId,
+    attachment_index: AttachmentIndex,
+    image: Result<Image>,
+}
+
 /// This component is used to load attachments from disk memory into the corresponding [`NodeAtlas`].
-#[derive(Default, Component)]
+///
+/// The loose node files are loaded by one [`DiskSource`] per attachment, while the packed
+/// node files are read once for all of their attachments and delivered back through a channel.
+#[derive(Component)]
 pub struct AttachmentFromDiskLoader {
     pub(crate) attachments: HashMap<AttachmentIndex, AttachmentFromDisk>,
-    /// Maps the id of an asset to the corresponding node id.
-    handle_mapping: HashMap<HandleId, (NodeId, AttachmentIndex)>,
+    /// The sources of the loose attachments, which are created once the first nodes are
+    /// requested, and recreated after the attachments have been reconfigured.
+    sources: Option<HashMap<AttachmentIndex, DiskSource>>,
+    sender: Sender<LoadedAttachment>,
+    receiver: Receiver<LoadedAttachment>,
+}
+
+impl Default for AttachmentFromDiskLoader {
+    fn default() -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        Self {
+            attachments: default(),
+            sources: None,
+            sender,
+            receiver,
+        }
+    }
+}
+
+impl AttachmentFromDiskLoader {
+    /// Replaces the decoding of the node files of the attachment with a custom decoder.
+    ///
+    /// The attachment has to be added to the loader beforehand.
+    pub fn set_decoder(
+        &mut self,
+        attachment_index: AttachmentIndex,
+        decoder: impl Fn(&[u8]) -> Result<Image> + Send + Sync + 'static,
+    ) {
+        self.attachments
+            .get_mut(&attachment_index)
+            .expect("The attachment is not loaded from disk.")
+            .decoder = Some(Arc::new(decoder));
+        self.sources = None;
+    }
+
+    /// Reads the node files of all attachments from the archive, instead of the loose files.
+    ///
+    /// The archive has to be packed from the `data` directory of the terrain
+    /// and all attachments have to be added to the loader beforehand.
+    pub fn set_archive(&mut self, archive: Arc<NodeArchive>) {
+        for attachment in self.attachments.values_mut() {
+            attachment.archive = Some(archive.clone());
+        }
+
+        self.sources = None;
+    }
 }
 
 pub(crate) fn start_loading_attachment_from_disk(
     asset_server: Res<AssetServer>,
-    mut terrain_query: Query<(&mut NodeAtlas, &mut AttachmentFromDiskLoader)>,
+    render_device: Option<Res<RenderDevice>>,
+    mut terrain_query: Query<(&NodeAtlas, &mut AttachmentFromDiskLoader)>,
 ) {
-    for (mut node_atlas, mut config) in terrain_query.iter_mut() {
-        let NodeAtlas {
-            ref mut loading_nodes,
-            ref mut load_events,
-            ..
-        } = node_atlas.as_mut();
+    let task_pool = IoTaskPool::get();
+
+    let compressed_formats = render_device.map_or(CompressedImageFormats::NONE, |device| {
+        CompressedImageFormats::from_features(device.features())
+    });
+
+    for (node_atlas, mut loader) in terrain_query.iter_mut() {
+        let height = node_atlas.height;
 
         let AttachmentFromDiskLoader {
-            ref mut attachments,
-            ref mut handle_mapping,
-        } = config.as_mut();
+            ref attachments,
+            ref mut sources,
+            ref sender,
+            ..
+        } = *loader;
 
-        for &node_id in load_events.iter() {
-            let node = loading_nodes.get_mut(&node_id).unwrap();
+        let packed = Arc::new(
+            attachments
+                .iter()
+                .filter(|(_, attachment)| matches!(attachment.file_format, FileFormat::TNF))
+                .map(|(&attachment_index, attachment)| (attachment_index, attachment.clone()))
+                .collect::<Vec<_>>(),
+        );
 
-            for (
-                attachment_index,
-                AttachmentFromDisk {
-                    ref path,
-                    ref file_format,
-                    ..
-                },
-            ) in attachments.iter()
-            {
-                let handle: Handle<Image> =
-                    asset_server.load(&format!("{path}/{node_id}.{}", file_format.extension()));
-
-                if asset_server.get_load_state(handle.clone()) == LoadState::Loaded {
-                    node.loaded(*attachment_index);
-                } else {
-                    handle_mapping.insert(handle.id(), (node_id, *attachment_index));
-                };
-
-                node.set_attachment(*attachment_index, handle);
+        let sources = sources.get_or_insert_with(|| {
+            attachments
+                .iter()
+                .filter(|(_, attachment)| !matches!(attachment.file_format, FileFormat::TNF))
+                .map(|(&attachment_index, attachment)| {
+                    let source = DiskSource::from_attachment(
+                        attachment.clone(),
+                        asset_server.clone(),
+                        height,
+                        compressed_formats,
+                    );
+
+                    (attachment_index, source)
+                })
+                .collect()
+        });
+
+        for &node_id in node_atlas.load_events.iter() {
+            for source in sources.values_mut() {
+                source.request(node_id);
+            }
+
+            if packed.is_empty() {
+                continue;
             }
+
+            let asset_server = asset_server.clone();
+            let packed = packed.clone();
+            let sender = sender.clone();
+
+            task_pool
+                .spawn(async move {
+                    // all packed attachments share the same node file
+                    let images = match packed[0].1.read(&asset_server, node_id).await {
+                        Ok(bytes) => {
+                            decode_packed_node(&bytes, &packed, height, compressed_formats)
+                        }
+                        Err(error) => packed
+                            .iter()
+                            .map(|&(attachment_index, _)| {
+                                (attachment_index, Err(anyhow!("{error}")))
+                            })
+                            .collect(),
+                    };
+
+                    for (attachment_index, image) in images {
+                        // the loader might have been removed in the meantime
+                        let _ = sender.send(LoadedAttachment {
+                            node_id,
+                            attachment_index,
+                            image,
+                        });
+                    }
+                })
+                .detach();
         }
     }
 }
 
 pub(crate) fn finish_loading_attachment_from_disk(
-    mut asset_events: EventReader<AssetEvent<Image>>,
     mut images: ResMut<Assets<Image>>,
     mut terrain_query: Query<(&mut NodeAtlas, &mut AttachmentFromDiskLoader)>,
 ) {
-    for event in asset_events.iter() {
-        if let AssetEvent::Created { handle } = event {
-            for (mut node_atlas, mut config) in terrain_query.iter_mut() {
-                if let Some((node_id, attachment_index)) =
-                    config.handle_mapping.remove(&handle.id())
-                {
-                    let image = images.get_mut(handle).unwrap();
-                    let attachment = config.attachments.get(&attachment_index).unwrap();
-
-                    image.texture_descriptor.format = attachment.format;
-                    image.texture_descriptor.usage |= TextureUsages::COPY_SRC;
-
-                    let node = node_atlas.loading_nodes.get_mut(&node_id).unwrap();
-                    node.loaded(attachment_index);
-                    break;
-                }
+    for (mut node_atlas, mut loader) in terrain_query.iter_mut() {
+        let AttachmentFromDiskLoader {
+            ref attachments,
+            ref mut sources,
+            ref receiver,
+            ..
+        } = *loader;
+
+        for (&attachment_index, source) in sources.iter_mut().flatten() {
+            for (node_id, result) in source.poll(&mut images) {
+                finish_loading_node(
+                    &mut node_atlas,
+                    &mut images,
+                    node_id,
+                    attachment_index,
+                    result,
+                );
             }
         }
+
+        for LoadedAttachment {
+            node_id,
+            attachment_index,
+            image,
+        } in receiver.try_iter()
+        {
+            let result = image.map(|mut image| {
+                image.texture_descriptor.format = attachments[&attachment_index].format;
+                images.add(image)
+            });
+
+            finish_loading_node(
+                &mut node_atlas,
+                &mut images,
+                node_id,
+                attachment_index,
+                result,
+            );
+        }
     }
 }
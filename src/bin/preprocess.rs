@@ -0,0 +1,313 @@
+//! Preprocesses source data into the streamable nodes of a terrain.
+//!
+//! Splits one large heightmap (or a directory of tiles named `<name>_<x>_<y>`) and optional
+//! additional attachments into the nodes of the quadtree, down samples them for each lod
+//! and writes the node files and the node config, which are loaded at runtime.
+//!
+//! ```text
+//! cargo run --release --bin preprocess -- <terrain path> --height <source> [options]
+//! ```
+//!
+//! The terrain path is relative to the assets directory (unless it is absolute) and has to match
+//! the path of the [`TerrainConfig`] at runtime, as well as the texture size, the mip level count
+//! and the lod count.
+
+use bevy::prelude::*;
+use bevy_terrain::prelude::*;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process,
+};
+
+const USAGE: &str = "\
+Usage: preprocess <terrain path> --height <source> [options]
+
+Options:
+    --height <source>           The heightmap file or directory of tiles.
+    --tile-size <pixels>        The size of the source tile(s), defaults to the width of the heightmap.
+    --terrain-size <pixels>     The size of the (square) terrain, defaults to the size of the heightmap,
+                                which may be rectangular.
+    --texture-size <pixels>     The size of the node textures, defaults to 512.
+    --mip-level-count <count>   The number of mip levels of the nodes, defaults to 1.
+    --lod-count <count>         The number of lods, defaults to the count covering the terrain with a single node.
+    --float-heights             Stores the heights as 32 bit floats instead of 16 bit integers.
+    --elevation-range <min,max> The elevation range of GeoTIFF or DEM sources, which is mapped onto the terrain height.
+    --attachment <name>:<format>:<source>
+                                Adds an additional attachment (formats: rgb8, rgba8, rgba8linear, r16, rg16, r32f),
+                                which shares the size of the height attachment.
+    --terrain-height <height>   The height of the terrain at runtime, which the baked attachments depend on,
+                                defaults to 1.
+    --thermal-erosion <iterations>
+                                Erodes the heights with a thermal erosion pass, whose slopes depend on the
+                                --terrain-height.
+    --ambient-occlusion         Bakes an ambient occlusion attachment, which is sampled by the default terrain shader.
+    --horizon                   Bakes a horizon attachment, which shadows the terrain from the sun in the default
+                                terrain shader. Requires --ambient-occlusion.
+    --normal                    Bakes a normal attachment, which replaces the normals derived from the heights
+                                in the default terrain shader (configure its index 4 as the normal attachment
+                                of the terrain plugin). Requires --horizon.
+    --albedo <source>           Adds an albedo attachment from color imagery (e.g. an orthophoto), which is used as
+                                the base color by the default terrain shader (configure its index 5 as the albedo
+                                attachment of the terrain plugin). Its resolution may be a multiple of the heightmap.
+                                Requires --normal.
+    --help                      Prints this message.";
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {message}\n\n{USAGE}");
+    process::exit(1);
+}
+
+fn parse<T: std::str::FromStr>(option: &str, value: Option<String>) -> T {
+    value
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| fail(&format!("Invalid value for {option}.")))
+}
+
+/// Returns the source file or the first tile of the source directory.
+fn first_tile(path: &str) -> PathBuf {
+    Path::new(path)
+        .read_dir()
+        .ok()
+        .and_then(|mut entries| entries.next()?.ok())
+        .map_or_else(|| path.into(), |entry| entry.path())
+}
+
+/// Infers the file format of the source from its extension.
+fn file_format(path: &str) -> FileFormat {
+    match first_tile(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase)
+        .as_deref()
+    {
+        Some("png") => FileFormat::PNG,
+        #[cfg(feature = "elevation")]
+        Some("tif" | "tiff") => FileFormat::GeoTIFF,
+        #[cfg(not(feature = "elevation"))]
+        Some("tif" | "tiff") => FileFormat::TIF,
+        #[cfg(feature = "elevation")]
+        Some("dem") => FileFormat::DEM,
+        Some("qoi") => FileFormat::QOI,
+        Some("dtm") => FileFormat::DTM,
+        Some("tdf") => FileFormat::TDF,
+        _ => fail(&format!("Unsupported source format of {path}.")),
+    }
+}
+
+fn attachment_format(format: &str) -> AttachmentFormat {
+    match format {
+        "rgb8" => AttachmentFormat::Rgb8,
+        "rgba8" => AttachmentFormat::Rgba8,
+        "rgba8linear" => AttachmentFormat::Rgba8Linear,
+        "r16" => AttachmentFormat::R16,
+        "rg16" => AttachmentFormat::Rg16,
+        "r32f" => AttachmentFormat::R32F,
+        _ => fail(&format!("Unknown attachment format {format}.")),
+    }
+}
+
+/// Determines the size of the source tile(s).
+fn source_size(path: &str) -> UVec2 {
+    image::image_dimensions(first_tile(path))
+        .map(|(width, height)| UVec2::new(width, height))
+        .unwrap_or_else(|_| fail("Could not determine the tile size, specify --tile-size."))
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+
+    let mut path = None;
+    let mut height = None;
+    let mut tile_size_override = None;
+    let mut terrain_size = None;
+    let mut texture_size = 512;
+    let mut mip_level_count = 1;
+    let mut lod_count = None;
+    let mut float_heights = false;
+    let mut elevation_range = None;
+    let mut attachments = Vec::new();
+    let mut terrain_height = 1.0;
+    let mut ambient_occlusion = false;
+    let mut horizon = false;
+    let mut normal = false;
+    let mut albedo = None;
+    let mut thermal_erosion = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--height" => height = args.next(),
+            "--tile-size" => tile_size_override = Some(parse(&arg, args.next())),
+            "--terrain-size" => terrain_size = Some(parse(&arg, args.next())),
+            "--texture-size" => texture_size = parse(&arg, args.next()),
+            "--mip-level-count" => mip_level_count = parse(&arg, args.next()),
+            "--lod-count" => lod_count = Some(parse(&arg, args.next())),
+            "--float-heights" => float_heights = true,
+            "--elevation-range" => {
+                let range: String = parse(&arg, args.next());
+                let (min, max) = range
+                    .split_once(',')
+                    .unwrap_or_else(|| fail("Invalid value for --elevation-range."));
+
+                elevation_range = Some(Vec2::new(
+                    parse(&arg, Some(min.to_string())),
+                    parse(&arg, Some(max.to_string())),
+                ));
+            }
+            "--attachment" => {
+                let attachment: String = parse(&arg, args.next());
+                let mut parts = attachment.splitn(3, ':');
+
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(name), Some(format), Some(source)) => attachments.push((
+                        name.to_string(),
+                        attachment_format(format),
+                        source.to_string(),
+                    )),
+                    _ => fail("Invalid value for --attachment."),
+                }
+            }
+            "--terrain-height" => terrain_height = parse(&arg, args.next()),
+            "--thermal-erosion" => thermal_erosion = Some(parse(&arg, args.next())),
+            "--ambient-occlusion" => ambient_occlusion = true,
+            "--horizon" => horizon = true,
+            "--normal" => normal = true,
+            "--albedo" => albedo = args.next(),
+            "--help" => {
+                println!("{USAGE}");
+                return;
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => fail(&format!("Unexpected argument {arg}.")),
+        }
+    }
+
+    let path = path.unwrap_or_else(|| fail("Missing the terrain path."));
+    let height = height.unwrap_or_else(|| fail("Missing the --height source."));
+
+    let source_size = tile_size_override.map_or_else(|| source_size(&height), UVec2::splat);
+    let tile_size = source_size.x;
+    let terrain_extent = terrain_size.map_or(source_size, UVec2::splat);
+    let terrain_size = terrain_extent.max_element();
+
+    let mut base = BaseConfig::new(texture_size, mip_level_count);
+
+    if float_heights {
+        base = base.with_float_heights();
+    }
+
+    // one node of the highest lod covers the entire terrain
+    let leaf_node_size = texture_size - 2 * base.border_size;
+    let lod_count = lod_count.unwrap_or_else(|| {
+        let node_count = (terrain_size + leaf_node_size - 1) / leaf_node_size;
+        node_count.next_power_of_two().trailing_zeros() + 1
+    });
+
+    let mut preprocessor = Preprocessor::default();
+    let mut loader = AttachmentFromDiskLoader::default();
+
+    // the height is only used at runtime, unless attachments are baked
+    let mut config = TerrainConfig::new(terrain_size, lod_count, terrain_height, 0, path.clone())
+        .with_extent(terrain_extent);
+
+    config.add_base_attachment_from_disk(
+        &mut preprocessor,
+        &mut loader,
+        base,
+        TileConfig {
+            file_format: file_format(&height),
+            path: height,
+            size: tile_size,
+            elevation_range,
+        },
+    );
+
+    if let Some(iterations) = thermal_erosion {
+        preprocessor.set_thermal_erosion(ThermalErosion {
+            iterations,
+            ..default()
+        });
+    }
+
+    // the default terrain shader expects the baked attachments directly after the base attachment
+    if horizon && !ambient_occlusion {
+        fail("--horizon requires --ambient-occlusion.");
+    }
+
+    if normal && !horizon {
+        fail("--normal requires --horizon.");
+    }
+
+    if albedo.is_some() && !normal {
+        fail("--albedo requires --normal.");
+    }
+
+    if ambient_occlusion {
+        config.add_ambient_occlusion_attachment_from_disk(
+            &mut preprocessor,
+            &mut loader,
+            AmbientOcclusionConfig::attachment(texture_size, mip_level_count),
+            default(),
+        );
+    }
+
+    if horizon {
+        config.add_horizon_attachment_from_disk(
+            &mut preprocessor,
+            &mut loader,
+            HorizonConfig::attachment(texture_size, mip_level_count),
+            default(),
+        );
+    }
+
+    if normal {
+        config.add_normal_attachment_from_disk(
+            &mut preprocessor,
+            &mut loader,
+            normal_attachment(texture_size, mip_level_count),
+        );
+    }
+
+    if let Some(albedo) = albedo {
+        let albedo_size = source_size(&albedo).x;
+
+        if albedo_size % tile_size != 0 {
+            fail("The resolution of the --albedo source has to be a multiple of the heightmap.");
+        }
+
+        config.add_albedo_attachment_from_disk(
+            &mut preprocessor,
+            &mut loader,
+            albedo_attachment(leaf_node_size, albedo_size / tile_size, mip_level_count),
+            TileConfig {
+                file_format: file_format(&albedo),
+                path: albedo,
+                size: albedo_size,
+                ..default()
+            },
+        );
+    }
+
+    for (name, format, source) in attachments {
+        config.add_attachment_from_disk(
+            &mut preprocessor,
+            &mut loader,
+            AttachmentConfig::new(name, texture_size, 1, mip_level_count, format),
+            TileConfig {
+                file_format: file_format(&source),
+                path: source,
+                size: tile_size,
+                ..default()
+            },
+        );
+    }
+
+    println!(
+        "Preprocessing the terrain {path} with a size of {}x{} pixels into {lod_count} lods.",
+        terrain_extent.x, terrain_extent.y,
+    );
+
+    preprocessor.preprocess(&config);
+
+    println!("Finished preprocessing the terrain {path}.");
+}
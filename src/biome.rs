@@ -0,0 +1,532 @@
+//! Divides the terrain into biomes, which are derived from climate maps or explicit biome maps.
+//!
+//! The biomes of a terrain are either classified from the temperature and moisture stored in the
+//! climate attachment (see [`climate_attachment`]), where each [`Biome`] covers a range of both,
+//! or read from a biome map, which stores the id of the biome of each pixel.
+//! The biomes are blended across the borders of their climate ranges.
+//!
+//! Each biome can bring its own [`TexturingRule`]s, which generate the splat weights of all pixels
+//! of the splatmap, that have not been painted yet, once their node is activated.
+//! The layers of the [`ScatterLayer`](crate::scatter::ScatterLayer)s and
+//! [`PropKind`](crate::props::PropKind)s can be restricted to biomes, which then scale their
+//! density. Use the [`BiomeSampler`] to query the biome at a position for gameplay.
+//! Add the [`TerrainBiomePlugin`] to generate the splat weights of the biomes.
+
+use crate::{
+    render::splat_material::{TexturingRule, SPLAT_ATTACHMENT},
+    terrain::{world_to_terrain, Terrain},
+    terrain_data::{
+        node_atlas::{NodeAtlas, NodeLifecycle},
+        AtlasAttachment, AttachmentConfig, AttachmentFormat, AttachmentIndex, NodeCoordinate,
+        NodeId, HEIGHT_ATTACHMENT,
+    },
+    TerrainSystemSet,
+};
+use bevy::{ecs::system::SystemParam, math::Vec3Swizzles, prelude::*};
+use itertools::iproduct;
+
+/// The index of a biome in the [`TerrainBiomes`], which is also the id stored in biome maps.
+pub type BiomeId = usize;
+
+/// Creates the config of the climate attachment, which stores the temperature in its first
+/// and the moisture in its second channel (both normalized from zero to one).
+pub fn climate_attachment(texture_size: u32, mip_level_count: u32) -> AttachmentConfig {
+    AttachmentConfig::new(
+        "climate".to_string(),
+        texture_size,
+        1,
+        mip_level_count,
+        AttachmentFormat::Rg16,
+    )
+}
+
+/// The source of the biomes of a terrain.
+#[derive(Clone, Copy, Debug)]
+pub enum BiomeMap {
+    /// Classifies the biomes by the temperature and moisture of the climate attachment.
+    Climate(AttachmentIndex),
+    /// Reads the id of the biome from the first channel of the attachment,
+    /// e.g. `255 * id` in an 8 bit attachment.
+    Ids(AttachmentIndex),
+}
+
+impl BiomeMap {
+    fn attachment_index(self) -> AttachmentIndex {
+        match self {
+            BiomeMap::Climate(attachment_index) | BiomeMap::Ids(attachment_index) => {
+                attachment_index
+            }
+        }
+    }
+}
+
+/// A biome of the terrain.
+#[derive(Clone, Debug)]
+pub struct Biome {
+    /// The name of the biome.
+    pub name: String,
+    /// The minimum and maximum temperature of the biome (from zero to one).
+    pub temperature: Vec2,
+    /// The minimum and maximum moisture of the biome (from zero to one).
+    pub moisture: Vec2,
+    /// The width of the transition at the borders of both ranges.
+    pub blend_width: f32,
+    /// The rules used to generate the splat weights of the biome.
+    pub rules: Vec<TexturingRule>,
+}
+
+impl Biome {
+    /// Creates a new biome, that covers the entire climate.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            temperature: Vec2::new(0.0, 1.0),
+            moisture: Vec2::new(0.0, 1.0),
+            blend_width: 0.05,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Restricts the biome to the temperature and moisture ranges.
+    pub fn with_climate(mut self, temperature: Vec2, moisture: Vec2) -> Self {
+        self.temperature = temperature;
+        self.moisture = moisture;
+        self
+    }
+
+    /// Adds the texturing rule to the biome.
+    pub fn with_rule(mut self, rule: TexturingRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Calculates the weight of the biome (from zero to one) for the climate.
+    fn climate_weight(&self, climate: Vec2) -> f32 {
+        range_coverage(climate.x, self.temperature, self.blend_width)
+            * range_coverage(climate.y, self.moisture, self.blend_width)
+    }
+
+    /// Calculates the distance of the climate to the center of the climate ranges of the biome.
+    fn climate_distance(&self, climate: Vec2) -> f32 {
+        let center = Vec2::new(
+            self.temperature.x + self.temperature.y,
+            self.moisture.x + self.moisture.y,
+        ) / 2.0;
+
+        climate.distance(center)
+    }
+
+    /// Generates the splat weights by layering the texturing rules on top of each other,
+    /// like the [`SplatMaterial`](crate::render::splat_material::SplatMaterial) does.
+    fn rule_weights(&self, height: f32, slope: f32) -> Vec4 {
+        self.rules.iter().fold(Vec4::ZERO, |weights, rule| {
+            let coverage = range_coverage(height, rule.height, rule.blend_width)
+                * range_coverage(slope, rule.slope, rule.blend_width);
+
+            let mut layer = Vec4::ZERO;
+            layer[rule.layer.min(3) as usize] = 1.0;
+
+            weights.lerp(layer, coverage)
+        })
+    }
+}
+
+/// Calculates how much of the value is covered by the range with smooth borders of the width.
+fn range_coverage(value: f32, range: Vec2, width: f32) -> f32 {
+    let smoothstep = |edge0: f32, edge1: f32, x: f32| {
+        let t = ((x - edge0) / (edge1 - edge0).max(f32::EPSILON)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    };
+
+    smoothstep(range.x - width, range.x, value)
+        * (1.0 - smoothstep(range.y, range.y + width, value))
+}
+
+/// The biome data of a single loaded node.
+pub(crate) struct NodeBiomes<'a> {
+    biomes: &'a TerrainBiomes,
+    attachment: &'a AtlasAttachment,
+    image: &'a Image,
+}
+
+impl<'a> NodeBiomes<'a> {
+    /// Creates the biome data from the biome map of a node.
+    pub(crate) fn new(
+        biomes: &'a TerrainBiomes,
+        attachment: &'a AtlasAttachment,
+        image: &'a Image,
+    ) -> Self {
+        Self {
+            biomes,
+            attachment,
+            image,
+        }
+    }
+
+    /// Calculates the weights of all biomes at the coordinates inside the node.
+    pub(crate) fn weights(&self, coords: Vec2) -> Vec<f32> {
+        match self.biomes.map {
+            BiomeMap::Climate(_) => {
+                let climate = self.climate(coords);
+                let mut weights = self
+                    .biomes
+                    .biomes
+                    .iter()
+                    .map(|biome| biome.climate_weight(climate))
+                    .collect::<Vec<_>>();
+
+                let total = weights.iter().sum::<f32>();
+
+                if total > f32::EPSILON {
+                    weights.iter_mut().for_each(|weight| *weight /= total);
+                } else if let Some(nearest) = self.nearest_biome(climate) {
+                    // climates outside of all ranges belong to the closest biome
+                    weights[nearest] = 1.0;
+                }
+
+                weights
+            }
+            BiomeMap::Ids(_) => {
+                let mut weights = vec![0.0; self.biomes.biomes.len()];
+
+                if let Some(weight) = weights.get_mut(self.biome_id(coords)) {
+                    *weight = 1.0;
+                }
+
+                weights
+            }
+        }
+    }
+
+    /// Determines the biome with the largest weight at the coordinates inside the node.
+    pub(crate) fn biome(&self, coords: Vec2) -> Option<BiomeId> {
+        match self.biomes.map {
+            BiomeMap::Climate(_) => self
+                .weights(coords)
+                .into_iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(biome_id, _)| biome_id),
+            BiomeMap::Ids(_) => {
+                let biome_id = self.biome_id(coords);
+                (biome_id < self.biomes.biomes.len()).then_some(biome_id)
+            }
+        }
+    }
+
+    /// Sums up the weights of the biomes at the coordinates inside the node.
+    pub(crate) fn weight(&self, coords: Vec2, biome_ids: &[BiomeId]) -> f32 {
+        let weights = self.weights(coords);
+
+        biome_ids
+            .iter()
+            .filter_map(|&biome_id| weights.get(biome_id))
+            .sum::<f32>()
+            .min(1.0)
+    }
+
+    fn climate(&self, coords: Vec2) -> Vec2 {
+        Vec2::new(
+            self.attachment.sample(self.image, coords, 0),
+            self.attachment.sample(self.image, coords, 1),
+        )
+    }
+
+    fn nearest_biome(&self, climate: Vec2) -> Option<BiomeId> {
+        self.biomes
+            .biomes
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                a.climate_distance(climate)
+                    .total_cmp(&b.climate_distance(climate))
+            })
+            .map(|(biome_id, _)| biome_id)
+    }
+
+    /// Reads the id of the nearest pixel, since ids can not be interpolated.
+    fn biome_id(&self, coords: Vec2) -> BiomeId {
+        let attachment = self.attachment;
+        let pixel = (coords * attachment.center_size as f32 + attachment.border_size as f32)
+            .floor()
+            .as_uvec2()
+            .min(UVec2::splat(attachment.texture_size - 1));
+
+        let max = match attachment.pixel_layout() {
+            Some((1, _)) => u8::MAX as f32,
+            _ => u16::MAX as f32,
+        };
+
+        (attachment.load(self.image, pixel.x, pixel.y, 0) * max).round() as BiomeId
+    }
+}
+
+/// Configures the biomes of a terrain.
+#[derive(Clone, Component)]
+pub struct TerrainBiomes {
+    /// The source of the biomes.
+    pub map: BiomeMap,
+    /// The biomes, indexed by their id.
+    pub biomes: Vec<Biome>,
+    /// The index of the splatmap attachment, whose unpainted pixels are generated by the
+    /// texturing rules of the biomes.
+    pub splat_attachment: AttachmentIndex,
+}
+
+impl TerrainBiomes {
+    /// Creates a new biome config, which derives the biomes from the map.
+    pub fn new(map: BiomeMap) -> Self {
+        Self {
+            map,
+            biomes: Vec::new(),
+            splat_attachment: SPLAT_ATTACHMENT,
+        }
+    }
+
+    /// Adds the biome, whose id is the number of previously added biomes.
+    pub fn with_biome(mut self, biome: Biome) -> Self {
+        self.biomes.push(biome);
+        self
+    }
+
+    /// Returns the biome with the id.
+    pub fn biome(&self, biome_id: BiomeId) -> Option<&Biome> {
+        self.biomes.get(biome_id)
+    }
+
+    /// Looks up the biome data of the loaded node.
+    ///
+    /// Returns `None` if the biome map of the node is not available on the CPU.
+    pub(crate) fn node<'a>(
+        &'a self,
+        node_atlas: &'a NodeAtlas,
+        images: &'a Assets<Image>,
+        node_id: NodeId,
+    ) -> Option<NodeBiomes<'a>> {
+        let (attachment, image) = self.node_map(node_atlas, images, node_id)?;
+
+        Some(NodeBiomes::new(self, attachment, image))
+    }
+
+    /// Looks up the attachment and the image of the biome map of the loaded node.
+    ///
+    /// Returns `None` if the biome map of the node is not available on the CPU.
+    pub(crate) fn node_map<'a>(
+        &self,
+        node_atlas: &'a NodeAtlas,
+        images: &'a Assets<Image>,
+        node_id: NodeId,
+    ) -> Option<(&'a AtlasAttachment, &'a Image)> {
+        let attachment_index = self.map.attachment_index();
+        let node = node_atlas.nodes.get(&node_id)?;
+        let handle = node_atlas.data[node.atlas_index as usize]
+            .attachments
+            .get(&attachment_index)?;
+
+        Some((
+            node_atlas.attachments.get(attachment_index)?,
+            images.get(handle)?,
+        ))
+    }
+
+    /// Determines the biome at the position (in the local space of the terrain).
+    ///
+    /// The biome is looked up using the best currently loaded node.
+    /// Returns `None` if no node containing the position is loaded.
+    pub fn biome_at(
+        &self,
+        node_atlas: &NodeAtlas,
+        images: &Assets<Image>,
+        position: Vec2,
+    ) -> Option<BiomeId> {
+        let attachment_index = self.map.attachment_index();
+        let (_, handle, coords) = node_atlas.lookup_attachment(attachment_index, position)?;
+
+        NodeBiomes {
+            biomes: self,
+            attachment: node_atlas.attachments.get(attachment_index)?,
+            image: images.get(handle)?,
+        }
+        .biome(coords)
+    }
+
+    /// Generates the splat weights of the unpainted pixels of the activated node
+    /// from the texturing rules of its biomes.
+    fn generate_splat(
+        &self,
+        node_atlas: &mut NodeAtlas,
+        images: &mut Assets<Image>,
+        node_id: NodeId,
+    ) {
+        let Some(node) = node_atlas.nodes.get(&node_id) else {
+            return;
+        };
+
+        let atlas_index = node.atlas_index;
+        let data = &node_atlas.data[atlas_index as usize];
+
+        let (Some(splat_attachment), Some(splat_handle), Some(height_image)) = (
+            node_atlas.attachments.get(self.splat_attachment),
+            data.attachments.get(&self.splat_attachment).cloned(),
+            data.attachments
+                .get(&HEIGHT_ATTACHMENT)
+                .and_then(|handle| images.get(handle)),
+        ) else {
+            return;
+        };
+
+        let Some(node_biomes) = self.node(node_atlas, images, node_id) else {
+            return;
+        };
+
+        let height_attachment = &node_atlas.attachments[HEIGHT_ATTACHMENT];
+        let NodeCoordinate { lod, x, y } = node_id.into();
+        let node_size = (node_atlas.leaf_node_size << lod) as f32;
+        let node_origin = Vec2::new(x as f32, y as f32) * node_size;
+        let size = splat_attachment.texture_size;
+        let step = 1.0 / height_attachment.center_size as f32;
+
+        let sample_height = |coords: Vec2| {
+            height_attachment.sample(height_image, coords.clamp(Vec2::ZERO, Vec2::ONE), 0)
+        };
+
+        // the weights are computed, before the splatmap is borrowed mutably
+        let weights = iproduct!(0..size, 0..size)
+            .map(|(y, x)| {
+                let position =
+                    splat_attachment.pixel_position(node_origin, node_size, UVec2::new(x, y));
+                let coords = ((position - node_origin) / node_size).clamp(Vec2::ZERO, Vec2::ONE);
+
+                let gradient = Vec2::new(
+                    sample_height(coords + Vec2::X * step) - sample_height(coords - Vec2::X * step),
+                    sample_height(coords + Vec2::Y * step) - sample_height(coords - Vec2::Y * step),
+                ) * node_atlas.height
+                    / (2.0 * step * node_size);
+
+                let height = sample_height(coords);
+                let slope = 1.0 - 1.0 / (1.0 + gradient.length_squared()).sqrt();
+
+                node_biomes
+                    .weights(coords)
+                    .into_iter()
+                    .zip(&self.biomes)
+                    .fold(Vec4::ZERO, |weights, (weight, biome)| {
+                        weights + weight * biome.rule_weights(height, slope)
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        let Some(splat_image) = images.get_mut(&splat_handle) else {
+            return;
+        };
+
+        let Some((_, channel_count)) = splat_attachment.pixel_layout() else {
+            return;
+        };
+        let channel_count = channel_count.min(4);
+        let mut modified = false;
+
+        for (y, x) in iproduct!(0..size, 0..size) {
+            let painted = (0..channel_count)
+                .any(|channel| splat_attachment.load(splat_image, x, y, channel) > 0.0);
+
+            // the painted weights take precedence over the ones generated by the biomes
+            if painted {
+                continue;
+            }
+
+            let weights = weights[(y * size + x) as usize];
+
+            for channel in 0..channel_count {
+                splat_attachment.store(splat_image, x, y, channel, weights[channel]);
+            }
+
+            modified = true;
+        }
+
+        if modified {
+            let updates = splat_attachment.updates(
+                splat_image,
+                atlas_index,
+                self.splat_attachment,
+                UVec2::ZERO,
+                UVec2::splat(size - 1),
+            );
+
+            node_atlas.attachment_updates.extend(updates);
+        }
+    }
+}
+
+/// A system parameter, used to query the biomes of all terrains in world space.
+#[derive(SystemParam)]
+pub struct BiomeSampler<'w, 's> {
+    images: Res<'w, Assets<Image>>,
+    terrain_query: Query<
+        'w,
+        's,
+        (
+            &'static NodeAtlas,
+            &'static TerrainBiomes,
+            &'static GlobalTransform,
+        ),
+        With<Terrain>,
+    >,
+}
+
+impl<'w, 's> BiomeSampler<'w, 's> {
+    /// Determines the biome of the terrain at the horizontal world position.
+    ///
+    /// Returns `None` if the entity is not a terrain with biomes
+    /// or no node containing the position is loaded.
+    pub fn biome_at(&self, terrain: Entity, position: Vec2) -> Option<BiomeId> {
+        let (node_atlas, biomes, transform) = self.terrain_query.get(terrain).ok()?;
+        let local_position = world_to_terrain(transform, Vec3::new(position.x, 0.0, position.y));
+
+        biomes.biome_at(node_atlas, &self.images, local_position.xz())
+    }
+
+    /// Returns the biome of the terrain at the horizontal world position.
+    pub fn biome(&self, terrain: Entity, position: Vec2) -> Option<&Biome> {
+        let biome_id = self.biome_at(terrain, position)?;
+        let (_, biomes, _) = self.terrain_query.get(terrain).ok()?;
+
+        biomes.biome(biome_id)
+    }
+}
+
+/// Generates the splat weights of the biomes of the terrains with a [`TerrainBiomes`] component.
+pub struct TerrainBiomePlugin;
+
+impl Plugin for TerrainBiomePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(
+            generate_biome_splats
+                .after(TerrainSystemSet::Update)
+                .in_base_set(CoreSet::Last),
+        );
+    }
+}
+
+/// Generates the splat weights of the biomes for all newly activated nodes.
+pub(crate) fn generate_biome_splats(
+    mut images: ResMut<Assets<Image>>,
+    mut terrain_query: Query<(&mut NodeAtlas, &TerrainBiomes)>,
+) {
+    for (mut node_atlas, biomes) in terrain_query.iter_mut() {
+        if biomes.biomes.iter().all(|biome| biome.rules.is_empty()) {
+            continue;
+        }
+
+        let activated = node_atlas
+            .lifecycle_events
+            .iter()
+            .filter(|&&(_, lifecycle)| lifecycle == NodeLifecycle::Activated)
+            .map(|&(node_id, _)| node_id)
+            .collect::<Vec<_>>();
+
+        for node_id in activated {
+            biomes.generate_splat(&mut node_atlas, &mut images, node_id);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Heightfield colliders for the [`bevy_xpbd_3d`] (Avian) physics engine.
+
+use crate::{
+    collision::{NodeHeights, TerrainCollider},
+    props::PropCollider,
+    terrain_data::node_atlas::NodeAtlas,
+};
+use bevy::prelude::*;
+use bevy_xpbd_3d::prelude::*;
+
+/// Creates the heightfield collider of the node.
+fn heightfield(node_heights: &NodeHeights) -> Collider {
+    let NodeHeights {
+        heights,
+        resolution,
+        size,
+        ..
+    } = node_heights;
+    let (resolution, size) = (*resolution, *size);
+
+    // xpbd expects one vector of heights per column along the x axis
+    let heights = (0..resolution)
+        .map(|x| {
+            (0..resolution)
+                .map(|z| heights[z * resolution + x])
+                .collect()
+        })
+        .collect();
+
+    Collider::heightfield(heights, Vec3::new(size, 1.0, size))
+}
+
+/// Spawns and despawns the xpbd heightfield colliders of all terrains,
+/// according to the loaded nodes of their node atlas.
+pub(crate) fn update_avian_colliders(
+    mut commands: Commands,
+    images: Res<Assets<Image>>,
+    mut terrain_query: Query<(Entity, &NodeAtlas, &mut TerrainCollider)>,
+) {
+    for (terrain, node_atlas, mut terrain_collider) in terrain_query.iter_mut() {
+        let (added, removed) = terrain_collider.update(node_atlas);
+
+        for entity in removed {
+            commands.entity(entity).despawn_recursive();
+        }
+
+        for node_id in added {
+            let Some(node_heights) = NodeHeights::new(
+                node_atlas,
+                &images,
+                node_id,
+                terrain_collider.hole_attachment,
+            ) else {
+                continue;
+            };
+
+            let center = node_heights.center;
+
+            let shape = match &node_heights.holes {
+                Some(holes) => {
+                    let (vertices, indices) = node_heights.trimesh(holes);
+                    Collider::trimesh(vertices, indices)
+                }
+                None => heightfield(&node_heights),
+            };
+
+            let collider = commands
+                .spawn((
+                    RigidBody::Static,
+                    shape,
+                    TransformBundle::from_transform(Transform::from_xyz(center.x, 0.0, center.y)),
+                ))
+                .id();
+
+            commands.entity(terrain).add_child(collider);
+            terrain_collider.insert(node_id, collider);
+        }
+    }
+}
+
+/// Inserts the xpbd colliders of the props (see [`crate::props`]), which have been spawned
+/// since the last update.
+pub(crate) fn insert_avian_prop_colliders(
+    mut commands: Commands,
+    prop_query: Query<(Entity, &PropCollider), Added<PropCollider>>,
+) {
+    for (entity, &prop_collider) in prop_query.iter() {
+        // xpbd measures the colliders by their full lengths
+        let collider = match prop_collider {
+            PropCollider::Ball(radius) => Collider::ball(radius),
+            PropCollider::Cylinder {
+                half_height,
+                radius,
+            } => Collider::cylinder(2.0 * half_height, radius),
+            PropCollider::Cuboid(half_extents) => {
+                let extents = 2.0 * half_extents;
+                Collider::cuboid(extents.x, extents.y, extents.z)
+            }
+        };
+
+        commands
+            .entity(entity)
+            .insert((RigidBody::Static, collider));
+    }
+}
@@ -0,0 +1,219 @@
+//! Generates physics colliders for the terrain, which stay in sync with the streamed terrain data.
+//!
+//! Each terrain with a [`TerrainCollider`] component spawns one heightfield collider child
+//! per loaded node of the configured lod. Once the node has been unloaded from the [`NodeAtlas`]
+//! (for longer than the optional hysteresis), its collider is despawned again.
+//! Nodes intersecting the holes of the terrain (see [`crate::holes`]) are approximated by
+//! triangle meshes instead, since the heightfields of the physics engines can not be cut open.
+//! The physics engine specific systems are enabled by the corresponding feature flag.
+
+use crate::{
+    holes::HOLE_THRESHOLD,
+    terrain_data::{
+        node_atlas::NodeAtlas, node_entities::NodeEntities, AttachmentIndex, NodeCoordinate,
+        NodeId, HEIGHT_ATTACHMENT,
+    },
+};
+use bevy::prelude::*;
+
+#[cfg(feature = "avian")]
+pub mod avian;
+#[cfg(feature = "rapier")]
+pub mod rapier;
+
+/// Configures the generation of physics colliders for a terrain.
+#[derive(Component)]
+pub struct TerrainCollider {
+    /// The lod of the nodes, for which colliders are generated.
+    pub lod: u32,
+    /// The amount of frames a node has to be unloaded, before its collider is despawned.
+    /// This prevents colliders from being churned, when nodes are reloaded shortly after.
+    /// Defaults to zero, which despawns the colliders as soon as their nodes are unloaded.
+    pub hysteresis: u32,
+    /// The index of the hole attachment, whose holes are punched into the colliders.
+    pub hole_attachment: Option<AttachmentIndex>,
+    /// The collider entities of the currently active nodes.
+    pub(crate) colliders: NodeEntities,
+}
+
+impl TerrainCollider {
+    /// Creates a new terrain collider config, which generates colliders for the nodes of the lod.
+    pub fn new(lod: u32) -> Self {
+        Self {
+            lod,
+            hysteresis: 0,
+            hole_attachment: None,
+            colliders: default(),
+        }
+    }
+
+    /// Keeps the colliders of unloaded nodes for the amount of frames.
+    pub fn with_hysteresis(mut self, hysteresis: u32) -> Self {
+        self.hysteresis = hysteresis;
+        self
+    }
+
+    /// Punches the holes of the hole attachment into the colliders.
+    pub fn with_holes(mut self, hole_attachment: AttachmentIndex) -> Self {
+        self.hole_attachment = Some(hole_attachment);
+        self
+    }
+
+    /// Stores the collider entity of the node.
+    #[cfg_attr(not(any(feature = "rapier", feature = "avian")), allow(dead_code))]
+    pub(crate) fn insert(&mut self, node_id: NodeId, entity: Entity) {
+        self.colliders.insert(node_id, entity);
+    }
+
+    /// Determines the nodes of the lod, that have been loaded since the last update,
+    /// and removes the colliders of all nodes, that have not been loaded for too long.
+    /// The colliders of edited or snowed nodes and of nodes with painted holes are regenerated.
+    ///
+    /// Returns the ids of the newly loaded nodes and the entities of the outdated colliders.
+    #[cfg_attr(not(any(feature = "rapier", feature = "avian")), allow(dead_code))]
+    pub(crate) fn update(&mut self, node_atlas: &NodeAtlas) -> (Vec<NodeId>, Vec<Entity>) {
+        let hole_attachment = self.hole_attachment;
+
+        self.colliders
+            .update(node_atlas, self.lod, self.hysteresis, |node_id| {
+                node_atlas.edited_nodes.contains(&node_id)
+                    || node_atlas.snowed_nodes.contains(&node_id)
+                    || hole_attachment.map_or(false, |attachment_index| {
+                        node_atlas
+                            .painted_nodes
+                            .contains(&(node_id, attachment_index))
+                    })
+            })
+    }
+}
+
+/// The heights of a node, sampled in a regular grid.
+pub(crate) struct NodeHeights {
+    /// The heights, with the x axis varying fastest.
+    pub(crate) heights: Vec<f32>,
+    /// The amount of samples along each axis.
+    pub(crate) resolution: usize,
+    /// The size of the node.
+    pub(crate) size: f32,
+    /// The center of the node (in the local space of the terrain).
+    pub(crate) center: Vec2,
+    /// Whether the cells between the samples are cut out, with the x axis varying fastest.
+    /// This is `None` if the node does not intersect any holes.
+    pub(crate) holes: Option<Vec<bool>>,
+}
+
+impl NodeHeights {
+    /// Samples the heights of the node in a grid with one sample per pixel corner,
+    /// which include the accumulated snow.
+    /// The holes of the hole attachment are sampled at the centers of the cells.
+    ///
+    /// Returns `None` if the height data of the node is not available on the CPU.
+    pub(crate) fn new(
+        node_atlas: &NodeAtlas,
+        images: &Assets<Image>,
+        node_id: NodeId,
+        hole_attachment: Option<AttachmentIndex>,
+    ) -> Option<Self> {
+        let NodeCoordinate { lod, x, y } = node_id.into();
+        let node = node_atlas.nodes.get(&node_id)?;
+        let image = images.get(
+            node_atlas.data[node.atlas_index as usize]
+                .attachments
+                .get(&HEIGHT_ATTACHMENT)?,
+        )?;
+
+        let attachment = &node_atlas.attachments[HEIGHT_ATTACHMENT];
+        let resolution = attachment.center_size as usize + 1;
+        let size = (node_atlas.leaf_node_size << lod) as f32;
+
+        let snow = node_atlas.snow_attachment.and_then(|attachment_index| {
+            let image = images.get(
+                node_atlas.data[node.atlas_index as usize]
+                    .attachments
+                    .get(&attachment_index)?,
+            )?;
+
+            Some((&node_atlas.attachments[attachment_index], image))
+        });
+
+        let heights = (0..resolution * resolution)
+            .map(|index| {
+                let coords = Vec2::new((index % resolution) as f32, (index / resolution) as f32)
+                    / attachment.center_size as f32;
+                let snow_depth = snow.map_or(0.0, |(snow_attachment, snow_image)| {
+                    snow_attachment.sample(snow_image, coords, 0)
+                });
+
+                (attachment.sample(image, coords, 0) + snow_depth) * node_atlas.height
+            })
+            .collect();
+
+        let holes = hole_attachment
+            .and_then(|attachment_index| {
+                let image = images.get(
+                    node_atlas.data[node.atlas_index as usize]
+                        .attachments
+                        .get(&attachment_index)?,
+                )?;
+                let attachment = &node_atlas.attachments[attachment_index];
+                let cells = resolution - 1;
+
+                Some(
+                    (0..cells * cells)
+                        .map(|index| {
+                            let coords =
+                                (Vec2::new((index % cells) as f32, (index / cells) as f32) + 0.5)
+                                    / cells as f32;
+
+                            attachment.sample(image, coords, 0) >= HOLE_THRESHOLD
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .filter(|holes| holes.contains(&true));
+
+        Some(Self {
+            heights,
+            resolution,
+            size,
+            center: (Vec2::new(x as f32, y as f32) + 0.5) * size,
+            holes,
+        })
+    }
+
+    /// Triangulates the cells of the node, which are not cut out, relative to its center.
+    ///
+    /// Returns the vertices and the indices of the triangles.
+    pub(crate) fn trimesh(&self, holes: &[bool]) -> (Vec<Vec3>, Vec<[u32; 3]>) {
+        let resolution = self.resolution;
+        let cells = resolution - 1;
+
+        let vertices = self
+            .heights
+            .iter()
+            .enumerate()
+            .map(|(index, &height)| {
+                let coords = Vec2::new((index % resolution) as f32, (index / resolution) as f32)
+                    / cells as f32
+                    - 0.5;
+
+                Vec3::new(coords.x * self.size, height, coords.y * self.size)
+            })
+            .collect();
+
+        let indices = (0..cells * cells)
+            .filter(|&cell| !holes[cell])
+            .flat_map(|cell| {
+                let index = ((cell / cells) * resolution + cell % cells) as u32;
+                let resolution = resolution as u32;
+
+                [
+                    [index, index + resolution, index + 1],
+                    [index + 1, index + resolution, index + resolution + 1],
+                ]
+            })
+            .collect();
+
+        (vertices, indices)
+    }
+}
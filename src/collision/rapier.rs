@@ -0,0 +1,96 @@
+//! Heightfield colliders for the [`bevy_rapier3d`] physics engine.
+
+use crate::{
+    collision::{NodeHeights, TerrainCollider},
+    props::PropCollider,
+    terrain_data::node_atlas::NodeAtlas,
+};
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+/// Creates the heightfield collider of the node.
+fn heightfield(node_heights: &NodeHeights) -> Collider {
+    let NodeHeights {
+        heights,
+        resolution,
+        size,
+        ..
+    } = node_heights;
+    let (resolution, size) = (*resolution, *size);
+
+    // rapier expects the heights in column major order, with the rows along the z axis
+    let heights = (0..resolution * resolution)
+        .map(|index| heights[(index % resolution) * resolution + index / resolution])
+        .collect();
+
+    Collider::heightfield(heights, resolution, resolution, Vec3::new(size, 1.0, size))
+}
+
+/// Spawns and despawns the rapier heightfield colliders of all terrains,
+/// according to the loaded nodes of their node atlas.
+pub(crate) fn update_rapier_colliders(
+    mut commands: Commands,
+    images: Res<Assets<Image>>,
+    mut terrain_query: Query<(Entity, &NodeAtlas, &mut TerrainCollider)>,
+) {
+    for (terrain, node_atlas, mut terrain_collider) in terrain_query.iter_mut() {
+        let (added, removed) = terrain_collider.update(node_atlas);
+
+        for entity in removed {
+            commands.entity(entity).despawn_recursive();
+        }
+
+        for node_id in added {
+            let Some(node_heights) = NodeHeights::new(
+                node_atlas,
+                &images,
+                node_id,
+                terrain_collider.hole_attachment,
+            ) else {
+                continue;
+            };
+
+            let center = node_heights.center;
+
+            let shape = match &node_heights.holes {
+                Some(holes) => {
+                    let (vertices, indices) = node_heights.trimesh(holes);
+                    Collider::trimesh(vertices, indices)
+                }
+                None => heightfield(&node_heights),
+            };
+
+            let collider = commands
+                .spawn((
+                    shape,
+                    TransformBundle::from_transform(Transform::from_xyz(center.x, 0.0, center.y)),
+                ))
+                .id();
+
+            commands.entity(terrain).add_child(collider);
+            terrain_collider.insert(node_id, collider);
+        }
+    }
+}
+
+/// Inserts the rapier colliders of the props (see [`crate::props`]), which have been spawned
+/// since the last update.
+pub(crate) fn insert_rapier_prop_colliders(
+    mut commands: Commands,
+    prop_query: Query<(Entity, &PropCollider), Added<PropCollider>>,
+) {
+    for (entity, &prop_collider) in prop_query.iter() {
+        let collider = match prop_collider {
+            PropCollider::Ball(radius) => Collider::ball(radius),
+            PropCollider::Cylinder {
+                half_height,
+                radius,
+            } => Collider::cylinder(half_height, radius),
+            PropCollider::Cuboid(half_extents) => {
+                Collider::cuboid(half_extents.x, half_extents.y, half_extents.z)
+            }
+        };
+
+        commands.entity(entity).insert(collider);
+    }
+}
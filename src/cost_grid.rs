@@ -0,0 +1,327 @@
+//! Rasterizes the terrain into cost grids for grid-based pathfinders.
+//!
+//! A [`CostGrid`] covers a horizontal world space region of a terrain with a regular grid of cells.
+//! The cost of each cell is derived from the slope of the terrain at its center and the weights
+//! of the configured attachment layers (e.g. the layers of the splatmap or the water depth),
+//! where cells, that are too steep or covered by a blocking layer, are not walkable.
+//!
+//! The cells are first rasterized, once the grid is added, and then recomputed incrementally
+//! for the regions of all nodes, that have been activated, deactivated, edited or painted,
+//! since the best available data of these regions has changed.
+//! A [`CostGridChanged`] event is sent with the range of the updated cells, so that pathfinders
+//! can invalidate the affected paths. Add the [`CostGridPlugin`] to update the grids.
+
+use crate::{
+    navigation::node_rect,
+    terrain_data::{
+        node_atlas::{NodeAtlas, NodeLifecycle},
+        AttachmentIndex,
+    },
+    TerrainSystemSet,
+};
+use bevy::{math::Vec3Swizzles, prelude::*};
+use itertools::iproduct;
+use std::f32::consts::FRAC_PI_4;
+
+/// The cost contributed by the layer (one channel of an attachment) to the cells it covers.
+#[derive(Clone, Copy, Debug)]
+pub struct LayerCost {
+    /// The index of the attachment.
+    pub attachment_index: AttachmentIndex,
+    /// The channel of the attachment, whose value (from zero to one) is the weight of the layer.
+    pub channel: usize,
+    /// The additional cost of a cell fully covered by the layer.
+    pub cost: f32,
+    /// The weight, above which the cells are not walkable, e.g. for deep water.
+    pub blocking_weight: Option<f32>,
+}
+
+impl LayerCost {
+    /// Creates a new layer cost, which adds the cost scaled by the weight of the layer.
+    pub fn new(attachment_index: AttachmentIndex, channel: usize, cost: f32) -> Self {
+        Self {
+            attachment_index,
+            channel,
+            cost,
+            blocking_weight: None,
+        }
+    }
+
+    /// Blocks all cells, where the weight of the layer exceeds the threshold.
+    pub fn with_blocking_weight(mut self, blocking_weight: f32) -> Self {
+        self.blocking_weight = Some(blocking_weight);
+        self
+    }
+}
+
+/// Configures how the costs of the cells are derived from the terrain.
+#[derive(Clone, Debug)]
+pub struct CostConfig {
+    /// The slope angle in radians, above which the cells are not walkable.
+    pub max_slope: f32,
+    /// The additional cost per unit of gradient (the tangent of the slope angle).
+    pub slope_cost: f32,
+    /// The costs of the attachment layers.
+    pub layers: Vec<LayerCost>,
+}
+
+impl Default for CostConfig {
+    fn default() -> Self {
+        Self {
+            max_slope: FRAC_PI_4,
+            slope_cost: 4.0,
+            layers: Vec::new(),
+        }
+    }
+}
+
+impl CostConfig {
+    /// Adds the cost of the layer.
+    pub fn with_layer(mut self, layer: LayerCost) -> Self {
+        self.layers.push(layer);
+        self
+    }
+}
+
+/// Sent, when cells of a cost grid have been recomputed.
+#[derive(Clone, Copy, Debug)]
+pub struct CostGridChanged {
+    /// The entity of the cost grid.
+    pub grid: Entity,
+    /// The first recomputed cell.
+    pub first: UVec2,
+    /// The last recomputed cell (inclusive).
+    pub last: UVec2,
+}
+
+/// A grid of path costs covering a horizontal world space region of a terrain.
+///
+/// Each cell stores the cost of traversing it, which is at least one for flat and uncovered
+/// ground and infinite for unwalkable cells and cells, whose terrain data is not loaded yet.
+#[derive(Clone, Component)]
+pub struct CostGrid {
+    /// The terrain entity rasterized into the grid.
+    pub terrain: Entity,
+    /// The configuration of the costs.
+    pub config: CostConfig,
+    region: Rect,
+    resolution: UVec2,
+    costs: Vec<f32>,
+    rasterized: bool,
+}
+
+impl CostGrid {
+    /// Creates a new cost grid, which covers the region with the number of cells along each axis.
+    pub fn new(terrain: Entity, region: Rect, resolution: UVec2, config: CostConfig) -> Self {
+        let resolution = resolution.max(UVec2::ONE);
+
+        Self {
+            terrain,
+            config,
+            region,
+            resolution,
+            costs: vec![f32::INFINITY; (resolution.x * resolution.y) as usize],
+            rasterized: false,
+        }
+    }
+
+    /// The horizontal world space region covered by the grid.
+    pub fn region(&self) -> Rect {
+        self.region
+    }
+
+    /// The number of cells along each axis.
+    pub fn resolution(&self) -> UVec2 {
+        self.resolution
+    }
+
+    /// The size of a cell in world space.
+    pub fn cell_size(&self) -> Vec2 {
+        self.region.size() / self.resolution.as_vec2()
+    }
+
+    /// The costs of all cells, with the x axis varying fastest.
+    pub fn costs(&self) -> &[f32] {
+        &self.costs
+    }
+
+    /// The cost of the cell or `None` if it is outside of the grid.
+    pub fn cost(&self, cell: UVec2) -> Option<f32> {
+        cell.cmplt(self.resolution)
+            .all()
+            .then(|| self.costs[(cell.y * self.resolution.x + cell.x) as usize])
+    }
+
+    /// Whether the cell can be traversed.
+    pub fn is_walkable(&self, cell: UVec2) -> bool {
+        self.cost(cell).map_or(false, f32::is_finite)
+    }
+
+    /// The cell containing the horizontal world position or `None` if it is outside of the grid.
+    pub fn cell_at(&self, position: Vec2) -> Option<UVec2> {
+        let cell = ((position - self.region.min) / self.cell_size()).floor();
+
+        (cell.cmpge(Vec2::ZERO).all() && cell.cmplt(self.resolution.as_vec2()).all())
+            .then(|| cell.as_uvec2())
+    }
+
+    /// The horizontal world position of the center of the cell.
+    pub fn cell_center(&self, cell: UVec2) -> Vec2 {
+        self.region.min + (cell.as_vec2() + 0.5) * self.cell_size()
+    }
+
+    /// Recomputes the whole grid, e.g. after its config has been changed.
+    pub fn invalidate(&mut self) {
+        self.rasterized = false;
+    }
+
+    /// Determines the range of cells overlapping the world space region.
+    fn covered_cells(&self, region: Rect) -> Option<(UVec2, UVec2)> {
+        let region = region.intersect(self.region);
+
+        if region.is_empty() {
+            return None;
+        }
+
+        let cell_size = self.cell_size();
+        let max = self.resolution - 1;
+        let first = ((region.min - self.region.min) / cell_size)
+            .floor()
+            .as_uvec2()
+            .min(max);
+        let last = ((region.max - self.region.min) / cell_size)
+            .floor()
+            .as_uvec2()
+            .min(max);
+
+        Some((first, last))
+    }
+
+    /// Computes the cost of the terrain at the local position.
+    fn sample_cost(
+        &self,
+        node_atlas: &NodeAtlas,
+        images: &Assets<Image>,
+        normal_matrix: &Mat4,
+        position: Vec2,
+    ) -> f32 {
+        let Some(normal) = node_atlas.normal_at(images, position) else {
+            return f32::INFINITY;
+        };
+
+        let normal = normal_matrix.transform_vector3(normal).normalize();
+        let slope = normal.y.clamp(-1.0, 1.0).acos();
+
+        if slope > self.config.max_slope {
+            return f32::INFINITY;
+        }
+
+        let mut cost = 1.0 + self.config.slope_cost * slope.tan();
+
+        for layer in &self.config.layers {
+            let weight = node_atlas
+                .lookup_attachment(layer.attachment_index, position)
+                .and_then(|(_, handle, coords)| {
+                    let attachment = node_atlas.attachments.get(layer.attachment_index)?;
+                    let image = images.get(handle)?;
+
+                    Some(attachment.sample(image, coords, layer.channel))
+                })
+                .unwrap_or(0.0);
+
+            if layer
+                .blocking_weight
+                .map_or(false, |blocking_weight| weight > blocking_weight)
+            {
+                return f32::INFINITY;
+            }
+
+            cost += weight * layer.cost;
+        }
+
+        cost
+    }
+
+    /// Recomputes the costs of the range of cells.
+    fn rasterize(
+        &mut self,
+        transform: &GlobalTransform,
+        node_atlas: &NodeAtlas,
+        images: &Assets<Image>,
+        first: UVec2,
+        last: UVec2,
+    ) {
+        let local_to_world = transform.compute_matrix();
+        let world_to_local = local_to_world.inverse();
+        let normal_matrix = world_to_local.transpose();
+
+        for (y, x) in iproduct!(first.y..=last.y, first.x..=last.x) {
+            let center = self.cell_center(UVec2::new(x, y));
+            let position = world_to_local.transform_point3(Vec3::new(center.x, 0.0, center.y));
+
+            self.costs[(y * self.resolution.x + x) as usize] =
+                self.sample_cost(node_atlas, images, &normal_matrix, position.xz());
+        }
+    }
+}
+
+/// Updates the cost grids of all terrains.
+pub struct CostGridPlugin;
+
+impl Plugin for CostGridPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<CostGridChanged>().add_system(
+            update_cost_grids
+                .after(TerrainSystemSet::Update)
+                .in_base_set(CoreSet::Last),
+        );
+    }
+}
+
+/// Rasterizes the new cost grids and recomputes the cells of the changed nodes.
+fn update_cost_grids(
+    images: Res<Assets<Image>>,
+    mut changed_events: EventWriter<CostGridChanged>,
+    terrain_query: Query<(&GlobalTransform, &NodeAtlas)>,
+    mut grid_query: Query<(Entity, &mut CostGrid)>,
+) {
+    for (entity, mut grid) in grid_query.iter_mut() {
+        let Ok((transform, node_atlas)) = terrain_query.get(grid.terrain) else {
+            continue;
+        };
+
+        let regions: Vec<Rect> = if grid.rasterized {
+            node_atlas
+                .lifecycle_events
+                .iter()
+                .filter(|&&(_, lifecycle)| {
+                    matches!(
+                        lifecycle,
+                        NodeLifecycle::Activated | NodeLifecycle::Deactivated
+                    )
+                })
+                .map(|&(node_id, _)| node_id)
+                .chain(node_atlas.edited_nodes.iter().copied())
+                .chain(node_atlas.painted_nodes.iter().map(|&(node_id, _)| node_id))
+                .map(|node_id| node_rect(transform, node_atlas, node_id))
+                .collect()
+        } else {
+            grid.rasterized = true;
+            vec![grid.region]
+        };
+
+        for region in regions {
+            let Some((first, last)) = grid.covered_cells(region) else {
+                continue;
+            };
+
+            grid.rasterize(transform, node_atlas, &images, first, last);
+
+            changed_events.send(CostGridChanged {
+                grid: entity,
+                first,
+                last,
+            });
+        }
+    }
+}
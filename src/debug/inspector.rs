@@ -0,0 +1,194 @@
+//! Integrates the terrain with `bevy-inspector-egui`.
+//!
+//! The [`TerrainInspectorPlugin`] adds a window, which shows the configuration of each terrain,
+//! the usage of its [`NodeAtlas`] and the occupancy of the quadtrees of its views.
+//! The streaming budgets and the quality settings of the views can be tweaked live.
+//!
+//! As all terrain components are reflected, they can also be edited with the generic
+//! inspectors of `bevy-inspector-egui` (e.g. the `WorldInspectorPlugin`).
+
+use crate::{
+    terrain::{Terrain, TerrainConfig},
+    terrain_data::{node_atlas::NodeAtlas, quadtree::Quadtree},
+    terrain_view::{TerrainViewComponents, TerrainViewConfig},
+};
+use bevy::prelude::*;
+use bevy_inspector_egui::{
+    bevy_egui::{EguiContexts, EguiPlugin},
+    egui,
+};
+
+/// Adds an inspector window for the terrains and their views.
+pub struct TerrainInspectorPlugin;
+
+impl Plugin for TerrainInspectorPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugin(EguiPlugin);
+        }
+
+        app.add_system(terrain_inspector);
+    }
+}
+
+/// Edits a budget, where `usize::MAX` stands for an unlimited budget.
+fn budget_ui(ui: &mut egui::Ui, label: &str, budget: &mut usize) {
+    ui.horizontal(|ui| {
+        let mut limited = *budget != usize::MAX;
+
+        ui.checkbox(&mut limited, label);
+
+        if limited {
+            let mut value = (*budget).min(u16::MAX as usize);
+            ui.add(egui::DragValue::new(&mut value).clamp_range(1..=u16::MAX as usize));
+            *budget = value;
+        } else {
+            *budget = usize::MAX;
+        }
+    });
+}
+
+fn config_ui(ui: &mut egui::Ui, config: &TerrainConfig) {
+    egui::Grid::new("config").show(ui, |ui| {
+        ui.label("path");
+        ui.label(&config.path);
+        ui.end_row();
+        ui.label("lod count");
+        ui.label(config.lod_count.to_string());
+        ui.end_row();
+        ui.label("leaf node size");
+        ui.label(config.leaf_node_size.to_string());
+        ui.end_row();
+        ui.label("height");
+        ui.label(config.height.to_string());
+        ui.end_row();
+        ui.label("attachments");
+        ui.label(
+            config
+                .attachments
+                .iter()
+                .map(|attachment| attachment.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+        ui.end_row();
+    });
+}
+
+fn node_atlas_ui(ui: &mut egui::Ui, node_atlas: &mut NodeAtlas) {
+    let usage = node_atlas.usage();
+    let used = usage.loaded + usage.loading;
+
+    ui.add(
+        egui::ProgressBar::new(used as f32 / usage.size.max(1) as f32)
+            .text(format!("{used} / {} nodes", usage.size)),
+    );
+    ui.label(format!(
+        "{} loaded ({} cached), {} loading, {} queued",
+        usage.loaded, usage.cached, usage.loading, usage.queued
+    ));
+    ui.label(format!("{} fragmented atlas indices", usage.fragmented));
+
+    ui.horizontal(|ui| {
+        ui.label("cache size");
+        ui.add(egui::DragValue::new(&mut node_atlas.cache_size).clamp_range(0..=node_atlas.size));
+    });
+    budget_ui(ui, "load budget", &mut node_atlas.load_budget);
+    budget_ui(ui, "activation budget", &mut node_atlas.activation_budget);
+    budget_ui(ui, "write budget", &mut node_atlas.write_budget);
+
+    ui.horizontal(|ui| {
+        ui.label("compaction budget");
+        ui.add(egui::DragValue::new(&mut node_atlas.compaction_budget).clamp_range(0..=64));
+    });
+}
+
+fn view_config_ui(ui: &mut egui::Ui, view_config: &mut TerrainViewConfig) {
+    egui::Grid::new("view_config").show(ui, |ui| {
+        ui.label("view distance");
+        ui.add(egui::DragValue::new(&mut view_config.view_distance).speed(0.05));
+        ui.end_row();
+        ui.label("load distance");
+        ui.add(egui::DragValue::new(&mut view_config.load_distance).speed(0.05));
+        ui.end_row();
+        ui.label("prefetch distance");
+        ui.add(egui::DragValue::new(&mut view_config.prefetch_distance).speed(0.05));
+        ui.end_row();
+        ui.label("refinement count");
+        ui.add(egui::DragValue::new(&mut view_config.refinement_count).clamp_range(0..=32));
+        ui.end_row();
+        ui.label("additional refinement");
+        ui.add(egui::DragValue::new(&mut view_config.additional_refinement).clamp_range(0..=8));
+        ui.end_row();
+        ui.label("tile scale");
+        ui.add(
+            egui::DragValue::new(&mut view_config.tile_scale)
+                .speed(0.05)
+                .clamp_range(0.25..=64.0),
+        );
+        ui.end_row();
+        ui.label("grid size");
+        ui.add(
+            egui::DragValue::new(&mut view_config.grid_size)
+                .speed(2)
+                .clamp_range(2..=64),
+        );
+        ui.end_row();
+        ui.label("morph range");
+        ui.add(egui::Slider::new(&mut view_config.morph_range, 0.0..=1.0));
+        ui.end_row();
+        ui.label("blend range");
+        ui.add(egui::Slider::new(&mut view_config.blend_range, 0.0..=1.0));
+        ui.end_row();
+    });
+}
+
+fn quadtree_ui(ui: &mut egui::Ui, quadtree: &Quadtree) {
+    for (lod, (requested, loaded)) in quadtree.occupancy().into_iter().enumerate() {
+        let fraction = if requested == 0 {
+            1.0
+        } else {
+            loaded as f32 / requested as f32
+        };
+
+        ui.add(egui::ProgressBar::new(fraction).text(format!("lod {lod}: {loaded} / {requested}")));
+    }
+}
+
+fn terrain_inspector(
+    mut contexts: EguiContexts,
+    mut terrain_query: Query<(Entity, &TerrainConfig, &mut NodeAtlas), With<Terrain>>,
+    mut view_configs: ResMut<TerrainViewComponents<TerrainViewConfig>>,
+    quadtrees: Res<TerrainViewComponents<Quadtree>>,
+) {
+    egui::Window::new("Terrain").show(contexts.ctx_mut(), |ui| {
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for (terrain, config, mut node_atlas) in &mut terrain_query {
+                egui::CollapsingHeader::new(format!("Terrain {terrain:?}"))
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        ui.push_id(terrain, |ui| {
+                            config_ui(ui, config);
+
+                            ui.separator();
+                            ui.heading("Node Atlas");
+                            node_atlas_ui(ui, &mut node_atlas);
+
+                            for (view, view_config) in view_configs.iter_terrain_mut(terrain) {
+                                ui.separator();
+                                ui.push_id(view, |ui| {
+                                    ui.heading(format!("View {view:?}"));
+                                    view_config_ui(ui, view_config);
+
+                                    if let Some(quadtree) = quadtrees.get(&(terrain, view)) {
+                                        ui.label("quadtree occupancy");
+                                        quadtree_ui(ui, quadtree);
+                                    }
+                                });
+                            }
+                        });
+                    });
+            }
+        });
+    });
+}
@@ -1,22 +1,30 @@
 //! Contains a debug resource and systems controlling it to visualize different internal
 //! data of the plugin.
-use crate::{debug::camera::debug_camera_control, TerrainViewComponents, TerrainViewConfig};
+use crate::{
+    debug::{camera::debug_camera_control, node_bounds::update_node_bounds_overlay},
+    TerrainViewComponents, TerrainViewConfig,
+};
 use bevy::{
     prelude::*,
     render::{Extract, RenderApp},
 };
 
 pub mod camera;
+#[cfg(feature = "inspector")]
+pub mod inspector;
+mod node_bounds;
 
 /// Adds a terrain debug config, a debug camera and debug control systems.
 pub struct TerrainDebugPlugin;
 
 impl Plugin for TerrainDebugPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<DebugTerrain>()
+        app.register_type::<DebugTerrain>()
+            .init_resource::<DebugTerrain>()
             .add_system(debug_camera_control)
             .add_system(toggle_debug)
-            .add_system(change_config);
+            .add_system(change_config)
+            .add_system(update_node_bounds_overlay);
 
         app.sub_app_mut(RenderApp)
             .init_resource::<DebugTerrain>()
@@ -24,7 +32,8 @@ impl Plugin for TerrainDebugPlugin {
     }
 }
 
-#[derive(Clone, Resource)]
+#[derive(Clone, Resource, Reflect)]
+#[reflect(Resource)]
 pub struct DebugTerrain {
     pub wireframe: bool,
     pub show_tiles: bool,
@@ -32,6 +41,18 @@ pub struct DebugTerrain {
     pub show_uv: bool,
     pub show_nodes: bool,
     pub show_minmax_error: bool,
+    /// Draws the bounding boxes of the requested nodes, colored by their lod.
+    pub show_node_bounds: bool,
+    /// Replaces the color of the terrain with a heatmap of the lod of the sampled nodes.
+    pub show_lod_heatmap: bool,
+    /// Replaces the color of the terrain with a checkerboard of the sampled nodes,
+    /// which are colored by their atlas index.
+    pub show_node_checkers: bool,
+    /// Replaces the color of the terrain with the amount of node texels per pixel,
+    /// from blue (magnified) over green to red (minified).
+    pub show_uv_density: bool,
+    /// Replaces the color of the terrain with the morph factor of the mesh.
+    pub show_morph: bool,
     pub minmax: bool,
     pub mesh_morph: bool,
     pub albedo: bool,
@@ -53,6 +74,11 @@ impl Default for DebugTerrain {
             show_uv: false,
             show_nodes: false,
             show_minmax_error: false,
+            show_node_bounds: false,
+            show_lod_heatmap: false,
+            show_node_checkers: false,
+            show_uv_density: false,
+            show_morph: false,
             minmax: false,
             mesh_morph: true,
             albedo: false,
@@ -114,6 +140,45 @@ pub fn toggle_debug(input: Res<Input<KeyCode>>, mut debug: ResMut<DebugTerrain>)
             if debug.show_minmax_error { "on" } else { "off" }
         )
     }
+    if input.just_pressed(KeyCode::K) {
+        debug.show_node_bounds = !debug.show_node_bounds;
+        println!(
+            "Toggled the node bounds view {}.",
+            if debug.show_node_bounds { "on" } else { "off" }
+        )
+    }
+    if input.just_pressed(KeyCode::Q) {
+        debug.show_lod_heatmap = !debug.show_lod_heatmap;
+        println!(
+            "Toggled the lod heatmap view {}.",
+            if debug.show_lod_heatmap { "on" } else { "off" }
+        )
+    }
+    if input.just_pressed(KeyCode::R) {
+        debug.show_node_checkers = !debug.show_node_checkers;
+        println!(
+            "Toggled the node checkerboard view {}.",
+            if debug.show_node_checkers {
+                "on"
+            } else {
+                "off"
+            }
+        )
+    }
+    if input.just_pressed(KeyCode::V) {
+        debug.show_uv_density = !debug.show_uv_density;
+        println!(
+            "Toggled the uv density view {}.",
+            if debug.show_uv_density { "on" } else { "off" }
+        )
+    }
+    if input.just_pressed(KeyCode::X) {
+        debug.show_morph = !debug.show_morph;
+        println!(
+            "Toggled the morph view {}.",
+            if debug.show_morph { "on" } else { "off" }
+        )
+    }
     if input.just_pressed(KeyCode::M) {
         debug.minmax = !debug.minmax;
         println!(
@@ -0,0 +1,149 @@
+//! Draws the bounding boxes of the nodes requested by the quadtrees of each terrain.
+//!
+//! The loaded nodes are colored by their lod, while the requested nodes,
+//! that have not finished loading yet, are drawn in gray.
+//! This helps to diagnose, why a node is or is not activated.
+
+use crate::{
+    debug::DebugTerrain,
+    planet::face_to_sphere,
+    terrain::{Terrain, TerrainConfig},
+    terrain_data::{node_atlas::NodeAtlas, NodeCoordinate},
+};
+use bevy::{
+    pbr::{NotShadowCaster, NotShadowReceiver},
+    prelude::*,
+    render::{mesh::PrimitiveTopology, view::NoFrustumCulling},
+};
+
+/// The color of the requested nodes, that have not finished loading yet.
+const LOADING_COLOR: Color = Color::GRAY;
+
+/// The pairs of corners, which are connected by the edges of a box.
+const EDGES: [(usize, usize); 12] = [
+    (0, 1),
+    (1, 3),
+    (3, 2),
+    (2, 0),
+    (4, 5),
+    (5, 7),
+    (7, 6),
+    (6, 4),
+    (0, 4),
+    (1, 5),
+    (2, 6),
+    (3, 7),
+];
+
+/// Marks the child entity of a terrain, whose line mesh displays the bounds of its nodes.
+#[derive(Component)]
+pub(crate) struct NodeBoundsOverlay;
+
+/// Returns the color of the nodes of the lod.
+fn lod_color(lod: u32, lod_count: u32) -> Color {
+    Color::hsl(300.0 * lod as f32 / lod_count.max(1) as f32, 1.0, 0.5)
+}
+
+/// Creates the line mesh of the bounds of all requested nodes of the terrain
+/// (in the local space of the terrain).
+fn node_bounds_mesh(config: &TerrainConfig, node_atlas: &NodeAtlas) -> Mesh {
+    let mut positions = Vec::new();
+    let mut colors = Vec::new();
+
+    for (node_id, loaded, height_bounds) in node_atlas.requested_nodes() {
+        let NodeCoordinate { lod, x, y } = node_id.into();
+        let node_size = (config.leaf_node_size << lod) as f32;
+
+        let color = if loaded {
+            lod_color(lod, config.lod_count)
+        } else {
+            LOADING_COLOR
+        };
+
+        let corners = [
+            (0, 0, height_bounds.x),
+            (1, 0, height_bounds.x),
+            (0, 1, height_bounds.x),
+            (1, 1, height_bounds.x),
+            (0, 0, height_bounds.y),
+            (1, 0, height_bounds.y),
+            (0, 1, height_bounds.y),
+            (1, 1, height_bounds.y),
+        ]
+        .map(|(dx, dy, height)| {
+            let local_position = Vec2::new((x + dx) as f32, (y + dy) as f32) * node_size;
+
+            match config.planet_radius {
+                Some(radius) => face_to_sphere(
+                    local_position.as_dvec2(),
+                    height as f64,
+                    radius as f64,
+                    config.terrain_extent.as_dvec2(),
+                )
+                .as_vec3(),
+                None => Vec3::new(local_position.x, height, local_position.y),
+            }
+        });
+
+        for (start, end) in EDGES {
+            positions.extend([corners[start].to_array(), corners[end].to_array()]);
+            colors.extend([color.as_rgba_f32(); 2]);
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::LineList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    mesh
+}
+
+/// Updates the node bounds overlays of all terrains, while they are enabled in the [`DebugTerrain`]
+/// resource, and removes them otherwise.
+pub(crate) fn update_node_bounds_overlay(
+    mut commands: Commands,
+    debug: Res<DebugTerrain>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    terrain_query: Query<(Entity, &TerrainConfig, &NodeAtlas), With<Terrain>>,
+    overlay_query: Query<(Entity, &Parent, &Handle<Mesh>), With<NodeBoundsOverlay>>,
+) {
+    if !debug.show_node_bounds {
+        for (overlay, _, _) in &overlay_query {
+            commands.entity(overlay).despawn_recursive();
+        }
+
+        return;
+    }
+
+    for (terrain, config, node_atlas) in &terrain_query {
+        let mesh = node_bounds_mesh(config, node_atlas);
+
+        if let Some((_, _, handle)) = overlay_query
+            .iter()
+            .find(|(_, parent, _)| parent.get() == terrain)
+        {
+            if let Some(overlay_mesh) = meshes.get_mut(handle) {
+                *overlay_mesh = mesh;
+            }
+        } else {
+            let material = materials.add(StandardMaterial {
+                unlit: true,
+                ..default()
+            });
+
+            commands.entity(terrain).with_children(|parent| {
+                parent.spawn((
+                    PbrBundle {
+                        mesh: meshes.add(mesh),
+                        material,
+                        ..default()
+                    },
+                    NoFrustumCulling,
+                    NotShadowCaster,
+                    NotShadowReceiver,
+                    NodeBoundsOverlay,
+                ));
+            });
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Registers [`Diagnostics`] of the streaming of the terrain data.
+//!
+//! The measurements are summed across all terrains and can be graphed or logged
+//! alongside the other diagnostics of the app (e.g. with the `LogDiagnosticsPlugin`).
+
+use crate::{terrain_data::node_atlas::NodeAtlas, TerrainSystemSet};
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticId, Diagnostics},
+    prelude::*,
+};
+
+/// The amount of measurements kept for each diagnostic.
+const MAX_HISTORY_LENGTH: usize = 20;
+
+/// Adds the terrain streaming diagnostics.
+pub struct TerrainDiagnosticsPlugin;
+
+impl Plugin for TerrainDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_startup_system(Self::setup_system).add_system(
+            Self::diagnostic_system
+                .after(TerrainSystemSet::Update)
+                .in_base_set(CoreSet::Last),
+        );
+    }
+}
+
+impl TerrainDiagnosticsPlugin {
+    /// The amount of loaded nodes, that are requested by a quadtree.
+    pub const ACTIVE_NODES: DiagnosticId =
+        DiagnosticId::from_u128(261_436_878_977_750_176_088_955_635_107_316_586_113);
+    /// The amount of requested nodes, that have not started loading yet.
+    pub const QUEUED_NODES: DiagnosticId =
+        DiagnosticId::from_u128(108_883_645_244_334_628_433_968_275_802_584_821_526);
+    /// The percentage of the node atlas, that is occupied by loaded or loading nodes.
+    pub const ATLAS_OCCUPANCY: DiagnosticId =
+        DiagnosticId::from_u128(60_447_707_491_729_957_261_543_846_821_367_885_391);
+    /// The amount of nodes, that have been activated this frame.
+    pub const ACTIVATIONS: DiagnosticId =
+        DiagnosticId::from_u128(226_930_585_188_865_001_084_845_607_769_112_064_976);
+    /// The average time (in milliseconds) between the start of loading and the activation
+    /// of the nodes, that have been activated this frame.
+    pub const LOAD_LATENCY: DiagnosticId =
+        DiagnosticId::from_u128(316_955_754_053_768_966_005_134_617_585_712_539_102);
+
+    pub fn setup_system(mut diagnostics: ResMut<Diagnostics>) {
+        for (id, name, suffix) in [
+            (Self::ACTIVE_NODES, "terrain_active_nodes", ""),
+            (Self::QUEUED_NODES, "terrain_queued_nodes", ""),
+            (Self::ATLAS_OCCUPANCY, "terrain_atlas_occupancy", "%"),
+            (Self::ACTIVATIONS, "terrain_activations", ""),
+            (Self::LOAD_LATENCY, "terrain_load_latency", "ms"),
+        ] {
+            diagnostics.add(Diagnostic::new(id, name, MAX_HISTORY_LENGTH).with_suffix(suffix));
+        }
+    }
+
+    pub fn diagnostic_system(
+        mut diagnostics: ResMut<Diagnostics>,
+        terrain_query: Query<&NodeAtlas>,
+    ) {
+        let (mut active, mut queued, mut used, mut size, mut activations) = (0, 0, 0, 0, 0);
+        let (mut latency, mut latency_count) = (0.0, 0);
+
+        for node_atlas in &terrain_query {
+            let usage = node_atlas.usage();
+
+            active += usage.active;
+            queued += usage.queued;
+            used += usage.loaded + usage.loading;
+            size += usage.size;
+            activations += node_atlas.activation_count;
+
+            if let Some(load_latency) = node_atlas.load_latency {
+                latency += load_latency as f64 * node_atlas.activation_count as f64;
+                latency_count += node_atlas.activation_count;
+            }
+        }
+
+        diagnostics.add_measurement(Self::ACTIVE_NODES, || active as f64);
+        diagnostics.add_measurement(Self::QUEUED_NODES, || queued as f64);
+        diagnostics.add_measurement(Self::ATLAS_OCCUPANCY, || {
+            100.0 * used as f64 / size.max(1) as f64
+        });
+        diagnostics.add_measurement(Self::ACTIVATIONS, || activations as f64);
+
+        // the latency is only measured in frames, in which nodes have been activated
+        if latency_count > 0 {
+            diagnostics.add_measurement(Self::LOAD_LATENCY, || {
+                1000.0 * latency / latency_count as f64
+            });
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! Sculpting brushes built on top of the [`TerrainEdit`] API.
+//!
+//! A [`Brush`] combines a shape, which determines the falloff of the brush,
+//! with an operation, that modifies the height inside of it.
+//! Each call to [`Brush::stroke`] creates one [`EditTerrain`] event.
+
+use crate::{
+    edit::{EditTerrain, TerrainEdit},
+    terrain_data::sampling::TerrainSampler,
+};
+use anyhow::{anyhow, Result};
+use bevy::{prelude::*, render::render_resource::TextureFormat};
+use bincode::{Decode, Encode};
+use itertools::iproduct;
+use std::sync::Arc;
+
+/// The amount of samples along each axis of the grid used for smoothing.
+const SMOOTH_RESOLUTION: usize = 32;
+
+/// The shape of a brush, which determines its falloff.
+#[derive(Clone)]
+pub enum BrushShape {
+    /// A circular brush.
+    Circle,
+    /// A square brush.
+    Square,
+    /// A brush, whose falloff is read from a grayscale texture.
+    Texture {
+        /// The size of the texture in pixels.
+        size: UVec2,
+        /// The falloff values of the texture, stored row by row.
+        values: Arc<[f32]>,
+    },
+}
+
+impl BrushShape {
+    /// Creates a texture shape from the first channel of the image.
+    ///
+    /// Returns an error if the format of the image is not supported or its data is incomplete.
+    pub fn from_image(image: &Image) -> Result<Self> {
+        let size = image.size().as_uvec2();
+
+        let (pixel_size, channel_count) = match image.texture_descriptor.format {
+            TextureFormat::R8Unorm => (1, 1),
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => (1, 4),
+            TextureFormat::R16Unorm => (2, 1),
+            TextureFormat::R32Float => (4, 1),
+            format => {
+                return Err(anyhow!(
+                    "The brush texture format {format:?} is not supported."
+                ))
+            }
+        };
+
+        if image.data.len() < pixel_size * channel_count * (size.x * size.y) as usize {
+            return Err(anyhow!("The brush texture data does not cover its size."));
+        }
+
+        let values = image
+            .data
+            .chunks_exact(pixel_size * channel_count)
+            .take((size.x * size.y) as usize)
+            .map(|pixel| match pixel_size {
+                1 => pixel[0] as f32 / u8::MAX as f32,
+                4 => f32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]),
+                _ => u16::from_le_bytes([pixel[0], pixel[1]]) as f32 / u16::MAX as f32,
+            })
+            .collect();
+
+        Ok(Self::Texture { size, values })
+    }
+
+    /// Calculates the falloff of the shape at the offset from its center,
+    /// which is relative to the radius of the brush.
+    pub(crate) fn falloff(&self, offset: Vec2, hardness: f32) -> f32 {
+        let hardness = hardness.clamp(0.0, 0.999);
+        let smooth_falloff = |distance: f32| {
+            let t = ((distance - hardness) / (1.0 - hardness)).clamp(0.0, 1.0);
+            1.0 - t * t * (3.0 - 2.0 * t)
+        };
+
+        match self {
+            BrushShape::Circle => smooth_falloff(offset.length()),
+            BrushShape::Square => smooth_falloff(offset.abs().max_element()),
+            BrushShape::Texture { size, values } => {
+                let coords = (offset * 0.5 + 0.5) * size.as_vec2();
+
+                if coords.cmplt(Vec2::ZERO).any() || coords.cmpge(size.as_vec2()).any() {
+                    return 0.0;
+                }
+
+                // shapes with too few values (e.g. received from a peer) fall off outside of them
+                let pixel = coords.as_uvec2();
+                values
+                    .get((pixel.y * size.x + pixel.x) as usize)
+                    .copied()
+                    .unwrap_or(0.0)
+            }
+        }
+    }
+}
+
+/// The operation a brush applies to the terrain height.
+#[derive(Encode, Decode, Clone, Copy, Debug)]
+pub enum BrushOperation {
+    /// Raises the terrain.
+    Raise,
+    /// Lowers the terrain.
+    Lower,
+    /// Smooths the terrain, by blending it with the average of its surrounding.
+    Smooth,
+    /// Blends the terrain towards the height (in the local space of the terrain).
+    Flatten(f32),
+    /// Adds value noise of the frequency to the terrain.
+    Noise {
+        /// The frequency of the noise in world space.
+        frequency: f32,
+        /// The seed of the noise.
+        seed: u32,
+    },
+}
+
+/// A sculpting brush.
+#[derive(Clone)]
+pub struct Brush {
+    /// The shape of the brush.
+    pub shape: BrushShape,
+    /// The operation applied by the brush.
+    pub operation: BrushOperation,
+    /// The radius of the brush in world space.
+    pub radius: f32,
+    /// The strength of the brush.
+    ///
+    /// For raising, lowering and noise this is the height change per stroke,
+    /// otherwise it is the blend factor (from zero to one) per stroke.
+    pub strength: f32,
+    /// The relative distance from the center, at which the falloff starts.
+    pub hardness: f32,
+}
+
+impl Brush {
+    /// Creates a new circular brush with the operation, radius and strength.
+    pub fn new(operation: BrushOperation, radius: f32, strength: f32) -> Self {
+        Self {
+            shape: BrushShape::Circle,
+            operation,
+            radius,
+            strength,
+            hardness: 0.5,
+        }
+    }
+
+    /// Creates the terrain edit of a brush stroke at the horizontal world position.
+    ///
+    /// The sampler is only used for smoothing, which depends on the surrounding heights.
+    pub fn stroke(&self, terrain: Entity, position: Vec2, sampler: &TerrainSampler) -> EditTerrain {
+        let heights = self.smoothed_heights(terrain, position, sampler);
+
+        self.stroke_with_heights(terrain, position, heights)
+    }
+
+    /// Samples and blurs the heights inside of a smoothing stroke at the position.
+    ///
+    /// Returns `None` for all other operations.
+    pub(crate) fn smoothed_heights(
+        &self,
+        terrain: Entity,
+        position: Vec2,
+        sampler: &TerrainSampler,
+    ) -> Option<Vec<f32>> {
+        matches!(self.operation, BrushOperation::Smooth)
+            .then(|| SmoothGrid::sample_heights(terrain, position, self.radius, sampler))
+    }
+
+    /// Creates the terrain edit of a brush stroke, which smooths towards the previously
+    /// sampled heights (see [`Brush::smoothed_heights`]).
+    ///
+    /// Heights with an unexpected length are ignored.
+    pub(crate) fn stroke_with_heights(
+        &self,
+        terrain: Entity,
+        position: Vec2,
+        heights: Option<Vec<f32>>,
+    ) -> EditTerrain {
+        let smoothed = heights
+            .filter(|heights| heights.len() == SMOOTH_RESOLUTION * SMOOTH_RESOLUTION)
+            .map(|heights| SmoothGrid::new(position, self.radius, heights));
+
+        EditTerrain {
+            terrain,
+            edit: Arc::new(BrushStroke {
+                brush: self.clone(),
+                position,
+                smoothed,
+            }),
+        }
+    }
+
+    /// Calculates the falloff of the brush at the offset from its center.
+    fn falloff(&self, offset: Vec2) -> f32 {
+        self.shape.falloff(offset / self.radius, self.hardness)
+    }
+}
+
+/// The blurred heights inside of a smoothing brush stroke.
+struct SmoothGrid {
+    /// The world space position of the first sample.
+    origin: Vec2,
+    /// The distance between two samples.
+    spacing: f32,
+    heights: Vec<f32>,
+}
+
+impl SmoothGrid {
+    fn new(position: Vec2, radius: f32, heights: Vec<f32>) -> Self {
+        Self {
+            origin: position - radius,
+            spacing: 2.0 * radius / (SMOOTH_RESOLUTION - 1) as f32,
+            heights,
+        }
+    }
+
+    /// Samples the heights around the position and blurs them.
+    fn sample_heights(
+        terrain: Entity,
+        position: Vec2,
+        radius: f32,
+        sampler: &TerrainSampler,
+    ) -> Vec<f32> {
+        let origin = position - radius;
+        let spacing = 2.0 * radius / (SMOOTH_RESOLUTION - 1) as f32;
+
+        let heights = iproduct!(0..SMOOTH_RESOLUTION, 0..SMOOTH_RESOLUTION)
+            .map(|(y, x)| {
+                let position = origin + Vec2::new(x as f32, y as f32) * spacing;
+                sampler.local_height_at(terrain, position).unwrap_or(0.0)
+            })
+            .collect::<Vec<_>>();
+
+        // blur the heights with a 3x3 box filter
+        let size = SMOOTH_RESOLUTION as i32;
+        iproduct!(0..size, 0..size)
+            .map(|(y, x)| {
+                let samples = iproduct!(-1..=1, -1..=1).map(|(dy, dx)| {
+                    let (x, y) = ((x + dx).clamp(0, size - 1), (y + dy).clamp(0, size - 1));
+                    heights[(y * size + x) as usize]
+                });
+
+                samples.sum::<f32>() / 9.0
+            })
+            .collect()
+    }
+
+    /// Samples the blurred heights bilinearly at the world position.
+    fn sample(&self, position: Vec2) -> f32 {
+        let max = SMOOTH_RESOLUTION as f32 - 1.0;
+        let coords = ((position - self.origin) / self.spacing).clamp(Vec2::ZERO, Vec2::splat(max));
+        let base = coords.floor().min(Vec2::splat(max - 1.0));
+        let fract = coords - base;
+
+        let load = |x: usize, y: usize| self.heights[y * SMOOTH_RESOLUTION + x];
+        let (x, y) = (base.x as usize, base.y as usize);
+
+        let top = load(x, y) * (1.0 - fract.x) + load(x + 1, y) * fract.x;
+        let bottom = load(x, y + 1) * (1.0 - fract.x) + load(x + 1, y + 1) * fract.x;
+
+        top * (1.0 - fract.y) + bottom * fract.y
+    }
+}
+
+/// One application of a brush to the terrain.
+struct BrushStroke {
+    brush: Brush,
+    position: Vec2,
+    smoothed: Option<SmoothGrid>,
+}
+
+impl TerrainEdit for BrushStroke {
+    fn region(&self) -> Rect {
+        Rect::from_center_half_size(self.position, Vec2::splat(self.brush.radius))
+    }
+
+    fn apply(&self, position: Vec2, height: f32) -> f32 {
+        let falloff = self.brush.falloff(position - self.position);
+
+        if falloff <= 0.0 {
+            return height;
+        }
+
+        let strength = self.brush.strength * falloff;
+        let blend = strength.clamp(0.0, 1.0);
+
+        match self.brush.operation {
+            BrushOperation::Raise => height + strength,
+            BrushOperation::Lower => height - strength,
+            BrushOperation::Flatten(target) => height + (target - height) * blend,
+            BrushOperation::Smooth => match &self.smoothed {
+                Some(smoothed) => height + (smoothed.sample(position) - height) * blend,
+                None => height,
+            },
+            BrushOperation::Noise { frequency, seed } => {
+                height + strength * value_noise(position * frequency, seed)
+            }
+        }
+    }
+}
+
+/// Hashes the integer coordinate into a pseudo random value between minus one and one.
+fn hash(x: i32, y: i32, seed: u32) -> f32 {
+    let mut hash = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ seed.wrapping_mul(0xcb1a_b31f);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0x5bd1_e995);
+    hash ^= hash >> 15;
+
+    hash as f32 / u32::MAX as f32 * 2.0 - 1.0
+}
+
+/// Calculates smoothly interpolated value noise between minus one and one.
+fn value_noise(position: Vec2, seed: u32) -> f32 {
+    let base = position.floor();
+    let fract = position - base;
+    let t = fract * fract * (3.0 - 2.0 * fract);
+    let (x, y) = (base.x as i32, base.y as i32);
+
+    let top = hash(x, y, seed) * (1.0 - t.x) + hash(x + 1, y, seed) * t.x;
+    let bottom = hash(x, y + 1, seed) * (1.0 - t.x) + hash(x + 1, y + 1, seed) * t.x;
+
+    top * (1.0 - t.y) + bottom * t.y
+}
@@ -0,0 +1,97 @@
+//! Craters and explosion deformations built on top of the [`TerrainEdit`] API.
+//!
+//! A [`Crater`] digs a bowl into the terrain and throws up a rim around it, which slopes back
+//! down to the surrounding terrain. The crater follows the existing surface, so that it can be
+//! placed on slopes and on top of other craters.
+//! Like every other edit, the crater only touches the nodes it overlaps, rebuilds their colliders
+//! and can be undone. Use [`deform_crater`] to create the edit of an explosion with a single call.
+
+use crate::edit::{EditTerrain, TerrainEdit};
+use bevy::prelude::*;
+use std::sync::Arc;
+
+/// A crater, which is dug into the terrain.
+#[derive(Clone, Copy, Debug)]
+pub struct Crater {
+    /// The horizontal world position of the center of the crater.
+    pub center: Vec2,
+    /// The radius of the crater up to the crest of its rim in world space.
+    pub radius: f32,
+    /// The depth of the bowl below the surface (in the local space of the terrain).
+    pub depth: f32,
+    /// The height of the rim above the surface (in the local space of the terrain).
+    pub rim_height: f32,
+    /// The width of the outer slope of the rim in world space.
+    pub rim_width: f32,
+}
+
+impl Crater {
+    /// Creates a new crater, whose rim slopes down across half of its radius.
+    pub fn new(center: Vec2, radius: f32, depth: f32, rim_height: f32) -> Self {
+        Self {
+            center,
+            radius,
+            depth,
+            rim_height,
+            rim_width: 0.5 * radius,
+        }
+    }
+
+    /// Uses the width for the outer slope of the rim.
+    pub fn with_rim_width(mut self, rim_width: f32) -> Self {
+        self.rim_width = rim_width;
+        self
+    }
+
+    /// Creates the terrain edit of the crater.
+    pub fn edit(&self, terrain: Entity) -> EditTerrain {
+        EditTerrain {
+            terrain,
+            edit: Arc::new(Crater {
+                radius: self.radius.max(f32::EPSILON),
+                depth: self.depth.max(0.0),
+                rim_height: self.rim_height.max(0.0),
+                rim_width: self.rim_width.max(0.0),
+                ..*self
+            }),
+        }
+    }
+
+    /// Calculates the height offset of the crater at the distance from its center.
+    fn offset(&self, distance: f32) -> f32 {
+        if distance < self.radius {
+            // the bowl rises from the bottom of the crater up to the crest of the rim
+            let t = distance / self.radius;
+            -self.depth + (self.depth + self.rim_height) * t * t
+        } else if distance < self.radius + self.rim_width {
+            let t = (distance - self.radius) / self.rim_width;
+            self.rim_height * (1.0 - t * t * (3.0 - 2.0 * t))
+        } else {
+            0.0
+        }
+    }
+}
+
+impl TerrainEdit for Crater {
+    fn region(&self) -> Rect {
+        Rect::from_center_half_size(self.center, Vec2::splat(self.radius + self.rim_width))
+    }
+
+    fn apply(&self, position: Vec2, height: f32) -> f32 {
+        height + self.offset(position.distance(self.center))
+    }
+}
+
+/// Creates the terrain edit of a crater at the horizontal world position,
+/// e.g. for an explosion.
+///
+/// See [`Crater`] for the meaning of the parameters and additional options.
+pub fn deform_crater(
+    terrain: Entity,
+    center: Vec2,
+    radius: f32,
+    depth: f32,
+    rim_height: f32,
+) -> EditTerrain {
+    Crater::new(center, radius, depth, rim_height).edit(terrain)
+}
@@ -0,0 +1,220 @@
+//! Exports the (edited) height data of a terrain as a single stitched heightmap.
+//!
+//! Because only a fraction of the terrain is loaded at any time, the height data is read from
+//! the preprocessed nodes on disk (or from their saved data, once edits have been baked into it)
+//! and the remaining edits are reapplied to it on the CPU.
+//! The export runs asynchronously on the [`IoTaskPool`].
+
+use crate::{
+    attachment_loader::AttachmentFromDiskLoader,
+    edit::{history::BakedEdit, save::SavedData, AppliedEdit},
+    preprocess::file_io::{format_directory, load_image},
+    terrain::TerrainConfig,
+    terrain_data::{
+        calc_node_id, node_atlas::NodeAtlas, AtlasAttachment, FileFormat, NodeCoordinate, NodeId,
+        HEIGHT_ATTACHMENT,
+    },
+};
+use anyhow::{anyhow, Result};
+use bevy::{
+    prelude::*,
+    tasks::{IoTaskPool, Task},
+    utils::{HashMap, HashSet},
+};
+use image::{DynamicImage, ImageBuffer, Luma, Rgb};
+use itertools::iproduct;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// An event, that exports the heightmap of the terrain to the path.
+///
+/// The format of the heightmap is determined by the file extension.
+/// EXR files store the heights in the local space of the terrain as floats,
+/// all other formats store normalized 16 bit heights.
+#[derive(Clone)]
+pub struct ExportHeightmap {
+    /// The terrain entity to export.
+    pub terrain: Entity,
+    /// The path of the exported heightmap.
+    pub path: PathBuf,
+}
+
+/// The data required to export the heightmap of a terrain.
+struct HeightmapExport {
+    /// The directory of the preprocessed height nodes.
+    directory: String,
+    file_format: FileFormat,
+    attachment: AtlasAttachment,
+    nodes: HashSet<NodeId>,
+    terrain_extent: UVec2,
+    leaf_node_size: u32,
+    height: f32,
+    /// The saved height data of the nodes with the highest level of detail.
+    saved_nodes: HashMap<NodeId, Arc<SavedData>>,
+    baked_edits: Vec<BakedEdit>,
+    edits: Vec<AppliedEdit>,
+}
+
+impl HeightmapExport {
+    /// Stitches the heights of all nodes with the highest level of detail and saves them.
+    fn export(self, path: &Path) -> Result<()> {
+        let HeightmapExport {
+            directory,
+            file_format,
+            attachment,
+            nodes,
+            terrain_extent,
+            leaf_node_size,
+            height,
+            saved_nodes,
+            baked_edits,
+            edits,
+        } = self;
+
+        let (width, depth) = (terrain_extent.x, terrain_extent.y);
+        let mut heights = vec![0.0; (width * depth) as usize];
+        let node_count = (terrain_extent + leaf_node_size - 1) / leaf_node_size;
+        let border = UVec2::splat(attachment.border_size);
+
+        for (x, y) in iproduct!(0..node_count.x, 0..node_count.y) {
+            let node_id = calc_node_id(0, x, y);
+
+            if !nodes.contains(&node_id) {
+                continue;
+            }
+
+            let node_image = load_image(&format!("{directory}/{node_id}"), file_format)
+                .ok_or_else(|| anyhow!("Failed to load the height data of node {node_id}."))?;
+            // normalizes both 16 bit and float heights
+            let node_image = node_image.to_rgb32f();
+
+            // the saved data already contains the baked edits
+            let saved_image = match saved_nodes.get(&node_id).map(|saved| &**saved) {
+                Some(SavedData::Full(data)) => Some(attachment.mip_image(data.clone())),
+                _ => None,
+            };
+
+            let node_edits = baked_edits
+                .iter()
+                .filter(|baked| baked.pending_nodes.contains(&node_id))
+                .map(|baked| &baked.edit)
+                .chain(&edits)
+                .collect::<Vec<_>>();
+
+            let node_origin = Vec2::new(x as f32, y as f32) * leaf_node_size as f32;
+
+            for (i, j) in iproduct!(0..attachment.center_size, 0..attachment.center_size) {
+                let terrain_pixel = UVec2::new(x, y) * attachment.center_size + UVec2::new(i, j);
+
+                if terrain_pixel.x >= width || terrain_pixel.y >= depth {
+                    continue;
+                }
+
+                let pixel = UVec2::new(i, j) + border;
+                let position = attachment.pixel_position(node_origin, leaf_node_size as f32, pixel);
+                let mut value = match &saved_image {
+                    Some(image) => attachment.load(image, pixel.x, pixel.y, 0),
+                    None => node_image.get_pixel(pixel.x, pixel.y).0[0],
+                } * height;
+
+                for edit in node_edits
+                    .iter()
+                    .filter(|edit| edit.region.contains(position))
+                {
+                    value = edit.apply(position, value).clamp(0.0, height);
+                }
+
+                heights[(terrain_pixel.y * width + terrain_pixel.x) as usize] = value;
+            }
+        }
+
+        let heightmap = if path
+            .extension()
+            .map_or(false, |extension| extension == "exr")
+        {
+            DynamicImage::ImageRgb32F(ImageBuffer::from_fn(width, depth, |x, y| {
+                Rgb([heights[(y * width + x) as usize]; 3])
+            }))
+        } else {
+            DynamicImage::ImageLuma16(ImageBuffer::from_fn(width, depth, |x, y| {
+                let value = heights[(y * width + x) as usize] / height;
+                Luma([(value * u16::MAX as f32).round() as u16])
+            }))
+        };
+
+        heightmap.save(path)?;
+
+        Ok(())
+    }
+}
+
+impl NodeAtlas {
+    /// Exports the heightmap of the terrain, including all edits, to the path asynchronously.
+    ///
+    /// The heights are read from the preprocessed nodes, which are loaded by the loader.
+    pub fn export_heightmap(
+        &self,
+        config: &TerrainConfig,
+        loader: &AttachmentFromDiskLoader,
+        path: impl Into<PathBuf>,
+    ) -> Task<Result<()>> {
+        let path = path.into();
+
+        let export = loader
+            .attachments
+            .get(&HEIGHT_ATTACHMENT)
+            .map(|file_attachment| {
+                let attachment = self.attachments[HEIGHT_ATTACHMENT].clone();
+
+                HeightmapExport {
+                    directory: format_directory(&config.path, &attachment.name),
+                    file_format: file_attachment.file_format,
+                    attachment,
+                    nodes: self.existing_nodes.clone(),
+                    terrain_extent: config.terrain_extent,
+                    leaf_node_size: self.leaf_node_size,
+                    height: self.height,
+                    saved_nodes: self
+                        .saved_nodes
+                        .iter()
+                        .filter(|&(&(node_id, attachment_index), _)| {
+                            attachment_index == HEIGHT_ATTACHMENT
+                                && NodeCoordinate::from(node_id).lod == 0
+                        })
+                        .map(|(&(node_id, _), saved)| (node_id, saved.clone()))
+                        .collect(),
+                    baked_edits: self.baked_edits.iter().map(BakedEdit::detached).collect(),
+                    edits: self.edits.iter().map(AppliedEdit::detached).collect(),
+                }
+            });
+
+        IoTaskPool::get().spawn(async move {
+            export
+                .ok_or_else(|| anyhow!("The terrain does not load its height data from disk."))?
+                .export(&path)
+        })
+    }
+}
+
+/// Starts the export of the heightmaps requested this frame and logs failed exports.
+pub(crate) fn export_heightmaps(
+    mut export_events: EventReader<ExportHeightmap>,
+    terrain_query: Query<(&NodeAtlas, &TerrainConfig, &AttachmentFromDiskLoader)>,
+) {
+    for ExportHeightmap { terrain, path } in export_events.iter() {
+        if let Ok((node_atlas, config, loader)) = terrain_query.get(*terrain) {
+            let path = path.clone();
+            let task = node_atlas.export_heightmap(config, loader, path.clone());
+
+            IoTaskPool::get()
+                .spawn(async move {
+                    if let Err(error) = task.await {
+                        error!("Failed to export the heightmap to {path:?}: {error}");
+                    }
+                })
+                .detach();
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Undo and redo of terrain edits.
+//!
+//! Each applied edit records the changes it made to the attachments of every loaded node.
+//! These changes are stored compressed, as the run-length encoded XOR difference of the
+//! pixel data before and after the edit.
+//! Because the XOR difference is its own inverse, the same delta is used to revert the edit.
+//! Edits continuing the previous edit (e.g. the paint pass of a road) are undone and redone
+//! together with it.
+//!
+//! The history is limited to the `edit_history_size` of the [`TerrainConfig`](crate::terrain::TerrainConfig).
+//! Older edits can no longer be undone and are baked into the saved data of the nodes they
+//! affect (like the data restored from a save), so that they are not reapplied to every
+//! loaded node. Affected nodes, which are not loaded at that time, apply and bake the edit
+//! once they are loaded.
+
+use crate::{
+    edit::{replication::TerrainReplication, save::SavedData, AppliedEdit},
+    formats::tsf::{toggle_xor_runs, xor_runs},
+    terrain::Terrain,
+    terrain_data::{
+        node_atlas::{LoadingState, NodeAtlas},
+        AtlasAttachment, AttachmentIndex, NodeId, HEIGHT_ATTACHMENT,
+    },
+};
+use bevy::{prelude::*, utils::HashSet};
+use itertools::Itertools;
+use std::{mem, sync::Arc};
+
+/// The default amount of edits, that can be undone.
+pub(crate) const DEFAULT_EDIT_HISTORY_SIZE: u32 = 64;
+
+/// An event, that undoes the last edit of the terrain.
+#[derive(Clone, Copy)]
+pub struct UndoTerrainEdit {
+    /// The terrain entity to undo the edit of.
+    pub terrain: Entity,
+}
+
+/// An event, that redoes the last undone edit of the terrain.
+#[derive(Clone, Copy)]
+pub struct RedoTerrainEdit {
+    /// The terrain entity to redo the edit of.
+    pub terrain: Entity,
+}
+
+/// An edit, which has dropped out of the history and can no longer be undone.
+pub(crate) struct BakedEdit {
+    pub(crate) edit: AppliedEdit,
+    /// The affected nodes, which were not loaded, when the edit was baked.
+    /// The edit is applied to them and baked into their saved data, once they are loaded.
+    pub(crate) pending_nodes: HashSet<NodeId>,
+}
+
+impl BakedEdit {
+    /// Copies the baked edit, e.g. to reapply it outside of the atlas.
+    pub(crate) fn detached(&self) -> Self {
+        Self {
+            edit: self.edit.detached(),
+            pending_nodes: self.pending_nodes.clone(),
+        }
+    }
+}
+
+/// The changes made by an edit to a region of a node attachment.
+pub(crate) struct AttachmentDelta {
+    pub(crate) attachment_index: AttachmentIndex,
+    /// The first pixel of the region.
+    first: UVec2,
+    /// The last pixel of the region.
+    last: UVec2,
+    /// Pairs of the amount of unchanged bytes and the amount of following changed bytes.
+    runs: Vec<(u32, u32)>,
+    /// The XOR differences of the changed bytes.
+    changes: Vec<u8>,
+}
+
+impl AttachmentDelta {
+    /// Records the changes between the pixel data of the region before and after the edit.
+    pub(crate) fn new(
+        attachment_index: AttachmentIndex,
+        first: UVec2,
+        last: UVec2,
+        before: &[u8],
+        after: &[u8],
+    ) -> Self {
+        let (runs, changes) = xor_runs(before, after);
+
+        Self {
+            attachment_index,
+            first,
+            last,
+            runs,
+            changes,
+        }
+    }
+
+    /// Toggles the changes of the edit in the pixel data of the region,
+    /// which either reverts or reapplies them.
+    fn toggle(&self, data: &mut [u8]) {
+        toggle_xor_runs(data, &self.runs, &self.changes);
+    }
+
+    /// Toggles the changes of the edit in the region of the attachment image.
+    pub(crate) fn toggle_image(&self, attachment: &AtlasAttachment, image: &mut Image) {
+        let mut region = attachment.read_region(image, self.first, self.last);
+        self.toggle(&mut region);
+        attachment.write_region(image, self.first, self.last, &region);
+    }
+}
+
+impl NodeAtlas {
+    /// Bakes the oldest edits, which exceed the history, into the saved data of the nodes.
+    pub(crate) fn limit_edit_history(&mut self, images: &Assets<Image>) {
+        let mut excess_count = self.edits.len().saturating_sub(self.edit_history_size);
+
+        // the edits continuing a baked edit can not be undone on their own
+        while excess_count > 0
+            && self
+                .edits
+                .get(excess_count)
+                .map_or(false, AppliedEdit::continues_previous)
+        {
+            excess_count += 1;
+        }
+
+        for edit in self.edits.drain(..excess_count).collect::<Vec<_>>() {
+            self.bake_edit(images, edit);
+        }
+    }
+
+    /// Bakes the oldest edit of the history into the saved data of the loaded nodes,
+    /// while the affected nodes, which are not loaded, bake it once they are loaded.
+    fn bake_edit(&mut self, images: &Assets<Image>, edit: AppliedEdit) {
+        let mut pending_nodes = HashSet::new();
+
+        for (node_id, attachment_index) in self.edited_node_attachments(&edit) {
+            let Some(data) = self.node_data(node_id) else {
+                pending_nodes.insert(node_id);
+                continue;
+            };
+
+            let attachment = &self.attachments[attachment_index];
+
+            let Some(image) = data
+                .attachment(attachment_index)
+                .and_then(|handle| images.get(handle))
+            else {
+                continue;
+            };
+
+            let mut image = attachment.mip_image(image.data[..attachment.mip_size()].to_vec());
+
+            // the loaded data contains the newer edits as well, which are reverted
+            for delta in self
+                .edits
+                .iter()
+                .filter_map(|edit| edit.deltas.get(&node_id))
+                .flatten()
+                .filter(|delta| delta.attachment_index == attachment_index)
+            {
+                delta.toggle_image(attachment, &mut image);
+            }
+
+            self.saved_nodes.insert(
+                (node_id, attachment_index),
+                Arc::new(SavedData::Full(image.data)),
+            );
+        }
+
+        if !pending_nodes.is_empty() {
+            self.baked_edits.push(BakedEdit {
+                edit: edit.detached(),
+                pending_nodes,
+            });
+        }
+    }
+
+    /// Applies the baked edits, which are pending for the node, after it has been loaded
+    /// and bakes them into its saved data.
+    pub(crate) fn bake_pending_edits(&mut self, images: &mut Assets<Image>, node_id: NodeId) {
+        let mut baked_edits = mem::take(&mut self.baked_edits);
+        let mut attachment_indices = Vec::new();
+
+        for baked in &mut baked_edits {
+            if baked.pending_nodes.remove(&node_id) {
+                let deltas = self.edit_node(images, node_id, &baked.edit);
+                attachment_indices.extend(deltas.iter().map(|delta| delta.attachment_index));
+            }
+        }
+
+        baked_edits.retain(|baked| !baked.pending_nodes.is_empty());
+        self.baked_edits = baked_edits;
+
+        for attachment_index in attachment_indices.into_iter().unique() {
+            let attachment = &self.attachments[attachment_index];
+
+            let Some(image) = self
+                .node_data(node_id)
+                .and_then(|data| data.attachment(attachment_index))
+                .and_then(|handle| images.get(handle))
+            else {
+                continue;
+            };
+
+            let data = image.data[..attachment.mip_size()].to_vec();
+            self.saved_nodes
+                .insert((node_id, attachment_index), Arc::new(SavedData::Full(data)));
+        }
+    }
+
+    /// Reverts the changes of the edit in all loaded nodes.
+    fn revert_edit(&mut self, images: &mut Assets<Image>, edit: &AppliedEdit) {
+        let height = self.height;
+        let NodeAtlas {
+            ref attachments,
+            ref nodes,
+            ref mut data,
+            ref mut attachment_updates,
+            ref mut edited_nodes,
+            ref mut edited_regions,
+            ref mut painted_nodes,
+            ..
+        } = self;
+
+        for (&node_id, deltas) in &edit.deltas {
+            // nodes loaded later do not contain the edit anymore
+            let Some(node) = nodes
+                .get(&node_id)
+                .filter(|node| node.state == LoadingState::Loaded)
+            else {
+                continue;
+            };
+
+            let data = &mut data[node.atlas_index as usize];
+
+            for delta in deltas {
+                let attachment = &attachments[delta.attachment_index];
+
+                let Some(image) = data
+                    .attachments
+                    .get(&delta.attachment_index)
+                    .and_then(|handle| images.get_mut(handle))
+                else {
+                    continue;
+                };
+
+                let mut region = attachment.read_region(image, delta.first, delta.last);
+                delta.toggle(&mut region);
+                attachment.write_region(image, delta.first, delta.last, &region);
+
+                attachment_updates.extend(attachment.updates(
+                    image,
+                    node.atlas_index,
+                    delta.attachment_index,
+                    delta.first,
+                    delta.last,
+                ));
+
+                if delta.attachment_index == HEIGHT_ATTACHMENT {
+                    data.height_bounds = attachment.bounds(image, 0) * height;
+                }
+            }
+
+            match edit.edit.painted_attachment() {
+                Some(attachment_index) => painted_nodes.push((node_id, attachment_index)),
+                None => {
+                    edited_nodes.push(node_id);
+                    edited_regions.push((node_id, edit.region));
+                }
+            }
+        }
+    }
+
+    /// Undoes the last edit of the terrain.
+    ///
+    /// Returns whether there was an edit to undo.
+    pub fn undo(&mut self, images: &mut Assets<Image>) -> bool {
+        let mut undone = false;
+
+        // the edits continuing the previous edit are undone together with it
+        while let Some(mut edit) = self.edits.pop() {
+            self.revert_edit(images, &edit);
+            edit.deltas.clear();
+
+            let continues_previous = edit.continues_previous();
+            self.undone_edits.push(edit);
+            undone = true;
+
+            if !continues_previous {
+                break;
+            }
+        }
+
+        undone
+    }
+
+    /// Redoes the last undone edit of the terrain.
+    ///
+    /// Returns whether there was an edit to redo.
+    pub fn redo(&mut self, images: &mut Assets<Image>) -> bool {
+        let Some(mut edit) = self.undone_edits.pop() else {
+            return false;
+        };
+
+        self.apply_edit(images, &mut edit);
+        self.edits.push(edit);
+
+        // the edits continuing the redone edit are redone together with it
+        while self
+            .undone_edits
+            .last()
+            .map_or(false, AppliedEdit::continues_previous)
+        {
+            let mut edit = self.undone_edits.pop().unwrap();
+            self.apply_edit(images, &mut edit);
+            self.edits.push(edit);
+        }
+
+        true
+    }
+}
+
+/// Undoes and redoes the edits of the terrains according to the events of this frame.
+///
+/// Replicated terrains reject the local events, which have to be issued as the
+/// [`TerrainCommand::Undo`](crate::edit::replication::TerrainCommand::Undo) and
+/// [`TerrainCommand::Redo`](crate::edit::replication::TerrainCommand::Redo) commands instead.
+pub(crate) fn apply_terrain_history(
+    mut undo_events: EventReader<UndoTerrainEdit>,
+    mut redo_events: EventReader<RedoTerrainEdit>,
+    mut images: ResMut<Assets<Image>>,
+    mut terrain_query: Query<(&mut NodeAtlas, Option<&TerrainReplication>), With<Terrain>>,
+) {
+    for &UndoTerrainEdit { terrain } in undo_events.iter() {
+        match terrain_query.get_mut(terrain) {
+            Ok((_, Some(_))) => {
+                warn!("Rejected a local undo of a replicated terrain, issue a `TerrainCommand` instead.")
+            }
+            Ok((mut node_atlas, None)) => {
+                node_atlas.undo(&mut images);
+            }
+            Err(_) => {}
+        }
+    }
+
+    for &RedoTerrainEdit { terrain } in redo_events.iter() {
+        match terrain_query.get_mut(terrain) {
+            Ok((_, Some(_))) => {
+                warn!("Rejected a local redo of a replicated terrain, issue a `TerrainCommand` instead.")
+            }
+            Ok((mut node_atlas, None)) => {
+                node_atlas.redo(&mut images);
+            }
+            Err(_) => {}
+        }
+    }
+}
@@ -0,0 +1,729 @@
+//! Provides runtime editing of the terrain height data and painting of the layer weights.
+//!
+//! Edits are sent as [`EditTerrain`] events and applied to all loaded nodes of the
+//! [`NodeAtlas`] they overlap, at every level of detail.
+//! Only the modified region of each node is uploaded to the GPU.
+//! Additionally the node atlas keeps track of the recent edits and reapplies them
+//! to nodes that are loaded later on, so that the changes persist while streaming.
+//! Older edits are baked into the saved data of the nodes instead (see [`history`]).
+
+use crate::{
+    edit::{history::AttachmentDelta, replication::TerrainReplication},
+    formats::tdf::generate_mipmaps,
+    terrain::{world_to_terrain, Terrain},
+    terrain_data::{
+        calc_node_id, node_atlas::NodeAtlas, AtlasAttachment, AtlasIndex, AttachmentIndex,
+        NodeCoordinate, NodeId, HEIGHT_ATTACHMENT, MINMAX_ATTACHMENT,
+    },
+};
+use bevy::{math::Vec3Swizzles, prelude::*, render::render_resource::*, utils::HashMap};
+use itertools::iproduct;
+use std::{mem, sync::Arc};
+
+pub mod brush;
+pub mod crater;
+pub mod export;
+pub mod history;
+pub mod paint;
+pub mod replication;
+pub mod river;
+pub mod road;
+pub mod save;
+pub mod spline;
+
+/// A modification of the terrain height.
+///
+/// Positions are horizontal world space coordinates, while heights are measured in the
+/// local space of the terrain and range from zero to the height of the terrain.
+pub trait TerrainEdit: Send + Sync + 'static {
+    /// The horizontal world space region affected by the edit.
+    fn region(&self) -> Rect;
+
+    /// Calculates the new height at the position from the current height.
+    fn apply(&self, position: Vec2, height: f32) -> f32;
+
+    /// The attachment, whose layer weights are painted by the edit instead of the height.
+    fn painted_attachment(&self) -> Option<AttachmentIndex> {
+        None
+    }
+
+    /// Modifies the layer weights at the position, one for each channel of the painted attachment.
+    fn paint(&self, _position: Vec2, _weights: &mut [f32]) {}
+
+    /// Whether the edit continues the previous edit of the terrain (e.g. the paint pass of a road),
+    /// so that both are undone and redone together.
+    fn continues_previous(&self) -> bool {
+        false
+    }
+}
+
+/// An event, that applies the edit to the terrain.
+#[derive(Clone)]
+pub struct EditTerrain {
+    /// The terrain entity to edit.
+    pub terrain: Entity,
+    /// The edit to apply.
+    pub edit: Arc<dyn TerrainEdit>,
+}
+
+/// An edit, which has been applied to a terrain.
+pub(crate) struct AppliedEdit {
+    edit: Arc<dyn TerrainEdit>,
+    /// The region affected by the edit (in the local space of the terrain).
+    region: Rect,
+    /// The transform of the terrain at the time of the edit.
+    terrain_to_world: Mat4,
+    /// The changes made to the attachments of the loaded nodes, used for undoing the edit.
+    pub(crate) deltas: HashMap<NodeId, Vec<AttachmentDelta>>,
+}
+
+impl AppliedEdit {
+    fn new(edit: Arc<dyn TerrainEdit>, terrain_transform: &GlobalTransform) -> Self {
+        let region = edit.region();
+
+        let corners = [
+            region.min,
+            region.max,
+            Vec2::new(region.min.x, region.max.y),
+            Vec2::new(region.max.x, region.min.y),
+        ]
+        .map(|corner| world_to_terrain(terrain_transform, Vec3::new(corner.x, 0.0, corner.y)).xz());
+
+        let region = corners.into_iter().fold(
+            Rect::from_corners(corners[0], corners[0]),
+            |region, corner| region.union_point(corner),
+        );
+
+        Self {
+            edit,
+            region,
+            terrain_to_world: terrain_transform.compute_matrix(),
+            deltas: default(),
+        }
+    }
+
+    /// Copies the edit without the recorded changes, e.g. to reapply it outside of the atlas.
+    pub(crate) fn detached(&self) -> Self {
+        Self {
+            edit: self.edit.clone(),
+            region: self.region,
+            terrain_to_world: self.terrain_to_world,
+            deltas: default(),
+        }
+    }
+
+    /// Whether the edit is undone and redone together with the previous edit.
+    fn continues_previous(&self) -> bool {
+        self.edit.continues_previous()
+    }
+
+    /// Transforms the position from the local space of the terrain into world space.
+    fn world_position(&self, position: Vec2) -> Vec2 {
+        self.terrain_to_world
+            .transform_point3(Vec3::new(position.x, 0.0, position.y))
+            .xz()
+    }
+
+    /// Applies the edit to the height at the position (in the local space of the terrain).
+    fn apply(&self, position: Vec2, height: f32) -> f32 {
+        self.edit.apply(self.world_position(position), height)
+    }
+
+    /// Applies the edit to the layer weights at the position (in the local space of the terrain).
+    fn paint(&self, position: Vec2, weights: &mut [f32]) {
+        self.edit.paint(self.world_position(position), weights)
+    }
+}
+
+/// The edited heights of a node, which its minmax attachment is recomputed from.
+pub(crate) struct NodeHeights<'a> {
+    pub(crate) image: &'a Image,
+    /// The depth of the snow (in the local space of the terrain), which raises the maximum heights.
+    pub(crate) snow_depth: f32,
+}
+
+/// A region of an atlas attachment, which has been modified and has to be uploaded to the GPU.
+pub(crate) struct AttachmentUpdate {
+    pub(crate) atlas_index: AtlasIndex,
+    pub(crate) attachment_index: AttachmentIndex,
+    pub(crate) mip_level: u32,
+    /// The first pixel of the region.
+    pub(crate) origin: UVec2,
+    /// The size of the region in pixels.
+    pub(crate) size: UVec2,
+    /// The pixel data of the region, stored row by row.
+    pub(crate) data: Vec<u8>,
+}
+
+impl AtlasAttachment {
+    /// Calculates the position of the pixel center (in the local space of the terrain).
+    pub(crate) fn pixel_position(&self, node_origin: Vec2, node_size: f32, pixel: UVec2) -> Vec2 {
+        let pixel_size = node_size / self.center_size as f32;
+
+        node_origin + (pixel.as_vec2() + 0.5 - self.border_size as f32) * pixel_size
+    }
+
+    /// Determines the first and the last pixel of the node, that are covered by the region.
+    fn covered_pixels(
+        &self,
+        node_origin: Vec2,
+        node_size: f32,
+        region: Rect,
+    ) -> Option<(UVec2, UVec2)> {
+        let pixel_size = node_size / self.center_size as f32;
+        let to_pixel =
+            |position: Vec2| (position - node_origin) / pixel_size + self.border_size as f32 - 0.5;
+
+        let first = to_pixel(region.min).ceil().max(Vec2::ZERO);
+        let last = to_pixel(region.max)
+            .floor()
+            .min(Vec2::splat(self.texture_size as f32 - 1.0));
+
+        first
+            .cmple(last)
+            .all()
+            .then(|| (first.as_uvec2(), last.as_uvec2()))
+    }
+
+    /// Determines the normalized bounds of the first channel across the footprint
+    /// (in the local space of the terrain) centered at the position.
+    ///
+    /// The pixels, which the footprint is interpolated from, are included as well.
+    fn footprint_bounds(
+        &self,
+        image: &Image,
+        node_origin: Vec2,
+        node_size: f32,
+        position: Vec2,
+        footprint: f32,
+    ) -> Vec2 {
+        let pixel_size = node_size / self.center_size as f32;
+        let to_pixel =
+            |position: Vec2| (position - node_origin) / pixel_size + self.border_size as f32 - 0.5;
+
+        let max = Vec2::splat(self.texture_size as f32 - 1.0);
+        let first = to_pixel(position - 0.5 * footprint)
+            .floor()
+            .clamp(Vec2::ZERO, max)
+            .as_uvec2();
+        let last = to_pixel(position + 0.5 * footprint)
+            .ceil()
+            .clamp(Vec2::ZERO, max)
+            .as_uvec2();
+
+        iproduct!(first.y..=last.y, first.x..=last.x).fold(
+            Vec2::new(f32::MAX, f32::MIN),
+            |bounds, (y, x)| {
+                let value = self.load(image, x, y, 0);
+                Vec2::new(bounds.x.min(value), bounds.y.max(value))
+            },
+        )
+    }
+
+    /// Reads the pixel data of the first mip level inside the region, row by row.
+    ///
+    /// Attachments, which can not be accessed on the CPU, have no pixel data.
+    pub(crate) fn read_region(&self, image: &Image, first: UVec2, last: UVec2) -> Vec<u8> {
+        let Some((pixel_size, channel_count)) = self.pixel_layout() else {
+            return Vec::new();
+        };
+        let pixel_bytes = pixel_size * channel_count;
+        let width = self.texture_size as usize;
+        let row_bytes = pixel_bytes * (last.x - first.x + 1) as usize;
+
+        (first.y..=last.y)
+            .flat_map(|y| {
+                let start = pixel_bytes * (y as usize * width + first.x as usize);
+                image.data[start..start + row_bytes].iter().copied()
+            })
+            .collect()
+    }
+
+    /// Writes the pixel data of the first mip level inside the region, row by row.
+    pub(crate) fn write_region(&self, image: &mut Image, first: UVec2, last: UVec2, data: &[u8]) {
+        let Some((pixel_size, channel_count)) = self.pixel_layout() else {
+            return;
+        };
+        let pixel_bytes = pixel_size * channel_count;
+        let width = self.texture_size as usize;
+        let row_bytes = pixel_bytes * (last.x - first.x + 1) as usize;
+
+        for (row, y) in (first.y..=last.y).enumerate() {
+            let start = pixel_bytes * (y as usize * width + first.x as usize);
+            image.data[start..start + row_bytes]
+                .copy_from_slice(&data[row * row_bytes..(row + 1) * row_bytes]);
+        }
+    }
+
+    /// Regenerates the mip levels of the node image and collects the modified region
+    /// of each mip level, to be uploaded to the GPU.
+    pub(crate) fn updates(
+        &self,
+        image: &mut Image,
+        atlas_index: AtlasIndex,
+        attachment_index: AttachmentIndex,
+        first: UVec2,
+        last: UVec2,
+    ) -> Vec<AttachmentUpdate> {
+        let Some((pixel_size, channel_count)) = self.pixel_layout() else {
+            return Vec::new();
+        };
+        let pixel_bytes = pixel_size * channel_count;
+        let mip_level_count = self
+            .mip_level_count
+            .min(image.texture_descriptor.mip_level_count);
+
+        generate_mipmaps(
+            &mut image.data,
+            self.texture_size,
+            pixel_size as u32,
+            channel_count as u32,
+            mip_level_count,
+        );
+
+        let mut mip_start = 0;
+
+        (0..mip_level_count)
+            .map(|mip_level| {
+                let mip_size = (self.texture_size >> mip_level) as usize;
+                let origin = first >> mip_level;
+                let size = (last >> mip_level) - origin + 1;
+
+                let data = (origin.y..origin.y + size.y)
+                    .flat_map(|y| {
+                        let start =
+                            mip_start + pixel_bytes * (y as usize * mip_size + origin.x as usize);
+                        image.data[start..start + pixel_bytes * size.x as usize]
+                            .iter()
+                            .copied()
+                    })
+                    .collect();
+
+                mip_start += pixel_bytes * mip_size * mip_size;
+
+                AttachmentUpdate {
+                    atlas_index,
+                    attachment_index,
+                    mip_level,
+                    origin,
+                    size,
+                    data,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Modifies the pixels of the node attachment, which are covered by the region.
+///
+/// The modified pixels are queued to be uploaded to the GPU and the changes
+/// are recorded, so that they can be undone.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn edit_attachment(
+    attachment: &AtlasAttachment,
+    attachment_index: AttachmentIndex,
+    image: &mut Image,
+    atlas_index: AtlasIndex,
+    node_origin: Vec2,
+    node_size: f32,
+    region: Rect,
+    attachment_updates: &mut Vec<AttachmentUpdate>,
+    mut edit_pixel: impl FnMut(&mut Image, UVec2, Vec2),
+) -> Option<AttachmentDelta> {
+    let (first, last) = attachment.covered_pixels(node_origin, node_size, region)?;
+    let before = attachment.read_region(image, first, last);
+
+    for (x, y) in iproduct!(first.x..=last.x, first.y..=last.y) {
+        let pixel = UVec2::new(x, y);
+        edit_pixel(
+            image,
+            pixel,
+            attachment.pixel_position(node_origin, node_size, pixel),
+        );
+    }
+
+    let after = attachment.read_region(image, first, last);
+
+    attachment_updates.extend(attachment.updates(
+        image,
+        atlas_index,
+        attachment_index,
+        first,
+        last,
+    ));
+
+    Some(AttachmentDelta::new(
+        attachment_index,
+        first,
+        last,
+        &before,
+        &after,
+    ))
+}
+
+impl AppliedEdit {
+    /// Returns the attachments modified by the edit, which are either the painted attachment
+    /// or the height and minmax attachments.
+    pub(crate) fn modified_attachments(
+        &self,
+        attachments: &[AtlasAttachment],
+    ) -> Vec<AttachmentIndex> {
+        if let Some(attachment_index) = self.edit.painted_attachment() {
+            return vec![attachment_index];
+        }
+
+        let mut attachment_indices = vec![HEIGHT_ATTACHMENT];
+
+        if attachments
+            .get(MINMAX_ATTACHMENT)
+            .map_or(false, |attachment| {
+                attachment.format() == TextureFormat::Rg16Unorm
+            })
+        {
+            attachment_indices.push(MINMAX_ATTACHMENT);
+        }
+
+        attachment_indices
+    }
+
+    /// Applies the edit to the image of the node attachment.
+    ///
+    /// The minmax attachment is recomputed from the edited heights of the node, if they are
+    /// available, otherwise its bounds are widened to approximately contain the new heights.
+    ///
+    /// Returns the changes made to the image, if it was affected by the edit.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn edit_image(
+        &self,
+        attachments: &[AtlasAttachment],
+        attachment_index: AttachmentIndex,
+        height: f32,
+        leaf_node_size: u32,
+        node_id: NodeId,
+        atlas_index: AtlasIndex,
+        heights: Option<NodeHeights>,
+        image: &mut Image,
+        attachment_updates: &mut Vec<AttachmentUpdate>,
+    ) -> Option<AttachmentDelta> {
+        let attachment = attachments.get(attachment_index)?;
+
+        let NodeCoordinate { lod, x, y } = node_id.into();
+        let node_size = (leaf_node_size << lod) as f32;
+        let node_origin = Vec2::new(x as f32, y as f32) * node_size;
+
+        if let Some(painted_attachment) = self.edit.painted_attachment() {
+            if painted_attachment != attachment_index {
+                return None;
+            }
+
+            let (_, channel_count) = attachment.pixel_layout()?;
+            let mut weights = vec![0.0; channel_count];
+
+            return edit_attachment(
+                attachment,
+                attachment_index,
+                image,
+                atlas_index,
+                node_origin,
+                node_size,
+                self.region,
+                attachment_updates,
+                |image, pixel, position| {
+                    for (channel, weight) in weights.iter_mut().enumerate() {
+                        *weight = attachment.load(image, pixel.x, pixel.y, channel);
+                    }
+
+                    self.paint(position, &mut weights);
+
+                    for (channel, &weight) in weights.iter().enumerate() {
+                        attachment.store(image, pixel.x, pixel.y, channel, weight);
+                    }
+                },
+            );
+        }
+
+        match attachment_index {
+            HEIGHT_ATTACHMENT => edit_attachment(
+                attachment,
+                attachment_index,
+                image,
+                atlas_index,
+                node_origin,
+                node_size,
+                self.region,
+                attachment_updates,
+                |image, pixel, position| {
+                    let value = attachment.load(image, pixel.x, pixel.y, 0) * height;
+                    let value = self.apply(position, value).clamp(0.0, height);
+
+                    attachment.store(image, pixel.x, pixel.y, 0, value / height);
+                },
+            ),
+            MINMAX_ATTACHMENT if attachment.format() == TextureFormat::Rg16Unorm => {
+                let Some((heights, height_attachment)) =
+                    heights.zip(attachments.get(HEIGHT_ATTACHMENT))
+                else {
+                    // widen the minmax bounds, so that they approximately contain the new heights
+                    return edit_attachment(
+                        attachment,
+                        attachment_index,
+                        image,
+                        atlas_index,
+                        node_origin,
+                        node_size,
+                        self.region,
+                        attachment_updates,
+                        |image, pixel, position| {
+                            let min = attachment.load(image, pixel.x, pixel.y, 0) * height;
+                            let max = attachment.load(image, pixel.x, pixel.y, 1) * height;
+                            let new_min = self.apply(position, min).clamp(0.0, height);
+                            let new_max = self.apply(position, max).clamp(0.0, height);
+
+                            attachment.store(image, pixel.x, pixel.y, 0, min.min(new_min) / height);
+                            attachment.store(image, pixel.x, pixel.y, 1, max.max(new_max) / height);
+                        },
+                    );
+                };
+
+                // the footprints of the pixels, which overlap the region, contain edited heights
+                let footprint = node_size / attachment.center_size as f32;
+                let region = Rect::from_corners(
+                    self.region.min - 0.5 * footprint,
+                    self.region.max + 0.5 * footprint,
+                );
+                let snow_depth = heights.snow_depth / height;
+
+                edit_attachment(
+                    attachment,
+                    attachment_index,
+                    image,
+                    atlas_index,
+                    node_origin,
+                    node_size,
+                    region,
+                    attachment_updates,
+                    |image, pixel, position| {
+                        let bounds = height_attachment.footprint_bounds(
+                            heights.image,
+                            node_origin,
+                            node_size,
+                            position,
+                            footprint,
+                        );
+
+                        attachment.store(image, pixel.x, pixel.y, 0, bounds.x);
+                        attachment.store(image, pixel.x, pixel.y, 1, bounds.y + snow_depth);
+                    },
+                )
+            }
+            _ => None,
+        }
+    }
+}
+
+impl NodeAtlas {
+    /// Applies the edit to the height and minmax attachments of the loaded node,
+    /// or to the painted attachment, if the edit paints layer weights.
+    ///
+    /// Returns the changes made to the attachments of the node, which are empty
+    /// if the node was not affected by the edit.
+    pub(crate) fn edit_node(
+        &mut self,
+        images: &mut Assets<Image>,
+        node_id: NodeId,
+        edit: &AppliedEdit,
+    ) -> Vec<AttachmentDelta> {
+        let (height, leaf_node_size) = (self.height, self.leaf_node_size);
+        let NodeAtlas {
+            ref attachments,
+            ref nodes,
+            ref mut data,
+            ref mut attachment_updates,
+            ..
+        } = self;
+
+        let Some(node) = nodes.get(&node_id) else {
+            return Vec::new();
+        };
+
+        let atlas_index = node.atlas_index;
+        let data = &mut data[atlas_index as usize];
+
+        edit.modified_attachments(attachments)
+            .into_iter()
+            .filter_map(|attachment_index| {
+                let handle = data.attachments.get(&attachment_index)?.clone();
+
+                // the image is taken out, so that the edited heights can be read alongside it
+                let mut image = mem::take(images.get_mut(&handle)?);
+
+                // the heights are edited first, which the minmax attachment is recomputed from
+                let heights = data
+                    .attachments
+                    .get(&HEIGHT_ATTACHMENT)
+                    .filter(|_| attachment_index == MINMAX_ATTACHMENT)
+                    .and_then(|handle| images.get(handle))
+                    .map(|image| NodeHeights {
+                        image,
+                        snow_depth: data.snow_depth,
+                    });
+
+                let delta = edit.edit_image(
+                    attachments,
+                    attachment_index,
+                    height,
+                    leaf_node_size,
+                    node_id,
+                    atlas_index,
+                    heights,
+                    &mut image,
+                    attachment_updates,
+                );
+
+                if delta.is_some() && attachment_index == HEIGHT_ATTACHMENT {
+                    data.height_bounds = attachments[HEIGHT_ATTACHMENT].bounds(&image, 0) * height;
+                }
+
+                *images.get_mut(&handle).unwrap() = image;
+
+                delta
+            })
+            .collect()
+    }
+
+    /// Returns all existing node attachments at every level of detail, which are modified
+    /// by the edit, regardless of whether they are loaded.
+    pub(crate) fn edited_node_attachments(
+        &self,
+        edit: &AppliedEdit,
+    ) -> Vec<(NodeId, AttachmentIndex)> {
+        let mut node_attachments = Vec::new();
+
+        for attachment_index in edit.modified_attachments(&self.attachments) {
+            let Some(attachment) = self.attachments.get(attachment_index) else {
+                continue;
+            };
+
+            for lod in 0..self.lod_count {
+                let node_size = (self.leaf_node_size << lod) as f32;
+
+                // the borders of the neighbouring nodes may overlap the region as well
+                let first = ((edit.region.min / node_size).floor() - 1.0)
+                    .as_ivec2()
+                    .max(IVec2::ZERO)
+                    .as_uvec2();
+                let last = ((edit.region.max / node_size).floor() + 1.0)
+                    .as_ivec2()
+                    .max(IVec2::ZERO)
+                    .as_uvec2();
+
+                for (x, y) in iproduct!(first.x..=last.x, first.y..=last.y) {
+                    let node_id = calc_node_id(lod, x, y);
+                    let node_origin = Vec2::new(x as f32, y as f32) * node_size;
+
+                    if self.existing_nodes.contains(&node_id)
+                        && attachment
+                            .covered_pixels(node_origin, node_size, edit.region)
+                            .is_some()
+                    {
+                        node_attachments.push((node_id, attachment_index));
+                    }
+                }
+            }
+        }
+
+        node_attachments
+    }
+
+    /// Applies the edit to all loaded nodes and records the changes.
+    pub(crate) fn apply_edit(&mut self, images: &mut Assets<Image>, edit: &mut AppliedEdit) {
+        let node_ids = self.loaded_node_ids().collect::<Vec<_>>();
+
+        for node_id in node_ids {
+            let deltas = self.edit_node(images, node_id, edit);
+
+            if deltas.is_empty() {
+                continue;
+            }
+
+            // painting does not change the heights, which the edited nodes are rebuilt from
+            match edit.edit.painted_attachment() {
+                Some(attachment_index) => self.painted_nodes.push((node_id, attachment_index)),
+                None => {
+                    self.edited_nodes.push(node_id);
+                    self.edited_regions.push((node_id, edit.region));
+                }
+            }
+
+            edit.deltas.insert(node_id, deltas);
+        }
+    }
+
+    /// Applies the new edit to all loaded nodes and adds it to the history of the terrain,
+    /// which discards the undone edits.
+    pub(crate) fn push_edit(
+        &mut self,
+        images: &mut Assets<Image>,
+        edit: Arc<dyn TerrainEdit>,
+        terrain_transform: &GlobalTransform,
+    ) {
+        let mut edit = AppliedEdit::new(edit, terrain_transform);
+        self.apply_edit(images, &mut edit);
+        self.edits.push(edit);
+        self.undone_edits.clear();
+        self.limit_edit_history(images);
+    }
+
+    /// Reapplies all edits of the history to the node, after it has been loaded.
+    pub(crate) fn reapply_edits(&mut self, images: &mut Assets<Image>, node_id: NodeId) {
+        let mut edits = mem::take(&mut self.edits);
+
+        for edit in &mut edits {
+            let deltas = self.edit_node(images, node_id, edit);
+
+            // replace the changes recorded before the node was unloaded
+            if deltas.is_empty() {
+                edit.deltas.remove(&node_id);
+            } else {
+                edit.deltas.insert(node_id, deltas);
+            }
+        }
+
+        self.edits = edits;
+    }
+}
+
+/// Applies all terrain edits of this frame to the loaded nodes of the corresponding terrains.
+///
+/// All affected nodes are marked as edited, so that data derived from them
+/// (e.g. colliders) can be updated.
+///
+/// Replicated terrains reject the local edits, which have to be issued as commands instead.
+pub(crate) fn apply_terrain_edits(
+    mut edit_events: EventReader<EditTerrain>,
+    mut images: ResMut<Assets<Image>>,
+    mut terrain_query: Query<
+        (
+            &mut NodeAtlas,
+            &GlobalTransform,
+            Option<&TerrainReplication>,
+        ),
+        With<Terrain>,
+    >,
+) {
+    for EditTerrain { terrain, edit } in edit_events.iter() {
+        let Ok((mut node_atlas, terrain_transform, replication)) = terrain_query.get_mut(*terrain)
+        else {
+            continue;
+        };
+
+        if replication.is_some() {
+            warn!(
+                "Rejected a local edit of a replicated terrain, issue a `TerrainCommand` instead."
+            );
+            continue;
+        }
+
+        node_atlas.push_edit(&mut images, edit.clone(), terrain_transform);
+    }
+}
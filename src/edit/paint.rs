@@ -0,0 +1,130 @@
+//! Painting of the layer weights of the splatmap, built on top of the [`TerrainEdit`] API.
+//!
+//! A [`PaintBrush`] blends the weight of its target layer towards one, while the weights of
+//! the other layers are scaled down accordingly, so that normalized weights stay normalized.
+//! Each call to [`PaintBrush::stroke`] creates one [`EditTerrain`] event, which can be undone
+//! and redone like any other edit.
+//! The painted nodes are announced with [`NodePainted`] events, so that their splatmaps
+//! can be persisted (e.g. by saving the node data of the painted attachment).
+
+use crate::{
+    edit::{brush::BrushShape, EditTerrain, TerrainEdit},
+    render::splat_material::SPLAT_ATTACHMENT,
+    terrain::Terrain,
+    terrain_data::{node_atlas::NodeAtlas, AttachmentIndex, NodeId},
+};
+use bevy::prelude::*;
+use std::sync::Arc;
+
+/// Sent, when the layer weights of a loaded node have been painted, undone or redone.
+#[derive(Clone, Copy, Debug)]
+pub struct NodePainted {
+    /// The terrain entity of the node.
+    pub terrain: Entity,
+    /// The id of the node.
+    pub node_id: NodeId,
+    /// The index of the painted attachment.
+    pub attachment_index: AttachmentIndex,
+}
+
+/// A brush, that paints the weight of a layer into the splatmap.
+#[derive(Clone)]
+pub struct PaintBrush {
+    /// The shape of the brush.
+    pub shape: BrushShape,
+    /// The painted layer, which is the channel of the splatmap.
+    pub layer: usize,
+    /// The radius of the brush in world space.
+    pub radius: f32,
+    /// The opacity of the brush (from zero to one), which is the blend factor per stroke.
+    pub opacity: f32,
+    /// The relative distance from the center, at which the falloff starts.
+    pub hardness: f32,
+    /// The index of the painted attachment.
+    pub attachment_index: AttachmentIndex,
+}
+
+impl PaintBrush {
+    /// Creates a new circular brush, that paints the layer into the splatmap attachment.
+    pub fn new(layer: usize, radius: f32, opacity: f32) -> Self {
+        Self {
+            shape: BrushShape::Circle,
+            layer,
+            radius,
+            opacity,
+            hardness: 0.5,
+            attachment_index: SPLAT_ATTACHMENT,
+        }
+    }
+
+    /// Paints into the attachment instead of the splatmap attachment.
+    pub fn with_attachment(mut self, attachment_index: AttachmentIndex) -> Self {
+        self.attachment_index = attachment_index;
+        self
+    }
+
+    /// Creates the terrain edit of a paint stroke at the horizontal world position.
+    pub fn stroke(&self, terrain: Entity, position: Vec2) -> EditTerrain {
+        EditTerrain {
+            terrain,
+            edit: Arc::new(PaintStroke {
+                brush: self.clone(),
+                position,
+            }),
+        }
+    }
+}
+
+/// One application of a paint brush to the terrain.
+struct PaintStroke {
+    brush: PaintBrush,
+    position: Vec2,
+}
+
+impl TerrainEdit for PaintStroke {
+    fn region(&self) -> Rect {
+        Rect::from_center_half_size(self.position, Vec2::splat(self.brush.radius))
+    }
+
+    fn apply(&self, _position: Vec2, height: f32) -> f32 {
+        height
+    }
+
+    fn painted_attachment(&self) -> Option<AttachmentIndex> {
+        Some(self.brush.attachment_index)
+    }
+
+    fn paint(&self, position: Vec2, weights: &mut [f32]) {
+        let offset = (position - self.position) / self.brush.radius;
+        let opacity = self.brush.opacity.clamp(0.0, 1.0);
+        let blend = opacity * self.brush.shape.falloff(offset, self.brush.hardness);
+
+        if blend <= 0.0 || self.brush.layer >= weights.len() {
+            return;
+        }
+
+        for (layer, weight) in weights.iter_mut().enumerate() {
+            if layer == self.brush.layer {
+                *weight += (1.0 - *weight) * blend;
+            } else {
+                *weight *= 1.0 - blend;
+            }
+        }
+    }
+}
+
+/// Sends the [`NodePainted`] events for the nodes painted this frame.
+pub(crate) fn send_paint_events(
+    terrain_query: Query<(Entity, &NodeAtlas), With<Terrain>>,
+    mut painted_events: EventWriter<NodePainted>,
+) {
+    for (terrain, node_atlas) in &terrain_query {
+        for &(node_id, attachment_index) in &node_atlas.painted_nodes {
+            painted_events.send(NodePainted {
+                terrain,
+                node_id,
+                attachment_index,
+            });
+        }
+    }
+}
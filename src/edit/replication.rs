@@ -0,0 +1,497 @@
+//! Replicates terrain modifications across the clients of a multiplayer game.
+//!
+//! The built-in edits are exposed as serializable [`TerrainCommand`]s, which the game sends
+//! through its own transport. One peer (usually the server) acts as the authority of a terrain
+//! and issues the commands with consecutive sequence numbers (see [`TerrainReplication::issue`]).
+//! Every peer applies the received commands strictly in the order of their sequence numbers,
+//! buffering the ones that arrive early, so that all peers end up with the same terrain.
+//! To keep the edits deterministic, commands store everything they depend on, e.g. the
+//! sampled heights of smoothing strokes, instead of reading them from the local terrain.
+//!
+//! Replicated terrains (those with a [`TerrainReplication`] component) reject all local
+//! [`EditTerrain`](crate::edit::EditTerrain), [`UndoTerrainEdit`](crate::edit::history::UndoTerrainEdit)
+//! and [`RedoTerrainEdit`](crate::edit::history::RedoTerrainEdit) events, including the edits of
+//! add-ons like the erosion, because their changes would diverge between the peers.
+//! Every modification has to be issued as a command through the authority instead.
+//! The edits of a command (e.g. the carving and the water of a river) are undone together.
+//!
+//! The peers acknowledge the applied commands (see [`TerrainReplication::applied`]),
+//! which lets the authority resend the missing ones and discard the acknowledged ones.
+//! Late joiners should receive a save of the terrain (see [`NodeAtlas::create_save`]),
+//! and continue after the commands contained in it (see [`TerrainReplication::synchronize`]).
+
+use crate::{
+    edit::{
+        brush::{Brush, BrushOperation, BrushShape},
+        crater::Crater,
+        paint::PaintBrush,
+        river::{River, RiverProfile},
+        road::Road,
+        spline::TerrainSpline,
+        TerrainEdit,
+    },
+    terrain::Terrain,
+    terrain_data::{node_atlas::NodeAtlas, sampling::TerrainSampler, AttachmentIndex},
+};
+use anyhow::Result;
+use bevy::{prelude::*, utils::HashMap};
+use bincode::{config, Decode, Encode};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+
+/// An identifier of a peer, which is chosen by the transport of the game.
+pub type PeerId = u64;
+
+/// The serializable shape of a brush.
+#[derive(Encode, Decode, Clone, Debug)]
+pub enum CommandShape {
+    Circle,
+    Square,
+    Texture { size: [u32; 2], values: Vec<f32> },
+}
+
+impl From<&BrushShape> for CommandShape {
+    fn from(shape: &BrushShape) -> Self {
+        match shape {
+            BrushShape::Circle => Self::Circle,
+            BrushShape::Square => Self::Square,
+            BrushShape::Texture { size, values } => Self::Texture {
+                size: size.to_array(),
+                values: values.to_vec(),
+            },
+        }
+    }
+}
+
+impl From<&CommandShape> for BrushShape {
+    fn from(shape: &CommandShape) -> Self {
+        match shape {
+            CommandShape::Circle => Self::Circle,
+            CommandShape::Square => Self::Square,
+            CommandShape::Texture { size, values } => Self::Texture {
+                size: UVec2::from_array(*size),
+                values: values.as_slice().into(),
+            },
+        }
+    }
+}
+
+/// A serializable modification of a terrain.
+///
+/// Positions are horizontal world space coordinates, like those of the corresponding edits.
+#[derive(Encode, Decode, Clone, Debug)]
+pub enum TerrainCommand {
+    /// A stroke of a sculpting [`Brush`].
+    Sculpt {
+        shape: CommandShape,
+        operation: BrushOperation,
+        radius: f32,
+        strength: f32,
+        hardness: f32,
+        position: [f32; 2],
+        /// The blurred heights sampled by the issuer of a smoothing stroke.
+        smoothed: Option<Vec<f32>>,
+    },
+    /// A stroke of a [`PaintBrush`].
+    Paint {
+        shape: CommandShape,
+        layer: usize,
+        radius: f32,
+        opacity: f32,
+        hardness: f32,
+        attachment_index: AttachmentIndex,
+        position: [f32; 2],
+    },
+    /// A [`Crater`].
+    Crater {
+        center: [f32; 2],
+        radius: f32,
+        depth: f32,
+        rim_height: f32,
+        rim_width: f32,
+    },
+    /// A [`Road`] along the control points of its spline.
+    Road {
+        points: Vec<[f32; 3]>,
+        width: f32,
+        falloff: f32,
+        splat_layer: Option<usize>,
+        attachment_index: AttachmentIndex,
+    },
+    /// A [`River`] along the control points of its spline, whose channel is trapezoid,
+    /// if the bed is specified, and parabolic otherwise.
+    River {
+        points: Vec<[f32; 3]>,
+        width: f32,
+        depth: f32,
+        bank_width: f32,
+        bed: Option<f32>,
+        water: Option<(AttachmentIndex, f32)>,
+    },
+    /// Undoes the last edit of the terrain.
+    Undo,
+    /// Redoes the last undone edit of the terrain.
+    Redo,
+}
+
+impl TerrainCommand {
+    /// Creates the command of a brush stroke at the horizontal world position.
+    ///
+    /// The sampler is only used for smoothing, whose sampled heights are part of the command.
+    pub fn sculpt(
+        brush: &Brush,
+        terrain: Entity,
+        position: Vec2,
+        sampler: &TerrainSampler,
+    ) -> Self {
+        Self::Sculpt {
+            shape: (&brush.shape).into(),
+            operation: brush.operation,
+            radius: brush.radius,
+            strength: brush.strength,
+            hardness: brush.hardness,
+            position: position.to_array(),
+            smoothed: brush.smoothed_heights(terrain, position, sampler),
+        }
+    }
+
+    /// Creates the command of a paint stroke at the horizontal world position.
+    pub fn paint(brush: &PaintBrush, position: Vec2) -> Self {
+        Self::Paint {
+            shape: (&brush.shape).into(),
+            layer: brush.layer,
+            radius: brush.radius,
+            opacity: brush.opacity,
+            hardness: brush.hardness,
+            attachment_index: brush.attachment_index,
+            position: position.to_array(),
+        }
+    }
+
+    /// Creates the command of the crater.
+    pub fn crater(crater: &Crater) -> Self {
+        Self::Crater {
+            center: crater.center.to_array(),
+            radius: crater.radius,
+            depth: crater.depth,
+            rim_height: crater.rim_height,
+            rim_width: crater.rim_width,
+        }
+    }
+
+    /// Creates the command of the road.
+    pub fn road(road: &Road) -> Self {
+        Self::Road {
+            points: road
+                .spline
+                .points
+                .iter()
+                .map(|point| point.to_array())
+                .collect(),
+            width: road.width,
+            falloff: road.falloff,
+            splat_layer: road.splat_layer,
+            attachment_index: road.attachment_index,
+        }
+    }
+
+    /// Creates the command of the river.
+    ///
+    /// Returns `None` for custom profiles, which can not be serialized.
+    pub fn river(river: &River) -> Option<Self> {
+        let bed = match river.profile {
+            RiverProfile::Parabolic => None,
+            RiverProfile::Trapezoid { bed } => Some(bed),
+            RiverProfile::Custom(_) => return None,
+        };
+
+        Some(Self::River {
+            points: river
+                .spline
+                .points
+                .iter()
+                .map(|point| point.to_array())
+                .collect(),
+            width: river.width,
+            depth: river.depth,
+            bank_width: river.bank_width,
+            bed,
+            water: river.water,
+        })
+    }
+
+    /// Creates the edits of the command, which are applied in order.
+    fn edits(&self, terrain: Entity) -> Vec<Arc<dyn TerrainEdit>> {
+        let spline = |points: &[[f32; 3]]| {
+            TerrainSpline::new(points.iter().copied().map(Vec3::from_array).collect())
+        };
+
+        let edits = match self {
+            TerrainCommand::Sculpt {
+                shape,
+                operation,
+                radius,
+                strength,
+                hardness,
+                position,
+                smoothed,
+            } => {
+                let brush = Brush {
+                    shape: shape.into(),
+                    hardness: *hardness,
+                    ..Brush::new(*operation, *radius, *strength)
+                };
+
+                vec![brush.stroke_with_heights(
+                    terrain,
+                    Vec2::from_array(*position),
+                    smoothed.clone(),
+                )]
+            }
+            TerrainCommand::Paint {
+                shape,
+                layer,
+                radius,
+                opacity,
+                hardness,
+                attachment_index,
+                position,
+            } => {
+                let brush = PaintBrush {
+                    shape: shape.into(),
+                    hardness: *hardness,
+                    ..PaintBrush::new(*layer, *radius, *opacity).with_attachment(*attachment_index)
+                };
+
+                vec![brush.stroke(terrain, Vec2::from_array(*position))]
+            }
+            &TerrainCommand::Crater {
+                center,
+                radius,
+                depth,
+                rim_height,
+                rim_width,
+            } => vec![
+                Crater::new(Vec2::from_array(center), radius, depth, rim_height)
+                    .with_rim_width(rim_width)
+                    .edit(terrain),
+            ],
+            TerrainCommand::Road {
+                points,
+                width,
+                falloff,
+                splat_layer,
+                attachment_index,
+            } => Road {
+                splat_layer: *splat_layer,
+                attachment_index: *attachment_index,
+                ..Road::new(spline(points), *width, *falloff)
+            }
+            .edits(terrain),
+            TerrainCommand::River {
+                points,
+                width,
+                depth,
+                bank_width,
+                bed,
+                water,
+            } => River {
+                profile: match *bed {
+                    Some(bed) => RiverProfile::Trapezoid { bed },
+                    None => RiverProfile::Parabolic,
+                },
+                water: *water,
+                ..River::new(spline(points), *width, *depth, *bank_width)
+            }
+            .edits(terrain),
+            TerrainCommand::Undo | TerrainCommand::Redo => Vec::new(),
+        };
+
+        edits.into_iter().map(|edit| edit.edit).collect()
+    }
+}
+
+/// A command, which has been issued by the authority of the terrain.
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct ReplicatedCommand {
+    /// The position of the command in the application order.
+    pub sequence: u64,
+    pub command: TerrainCommand,
+}
+
+impl ReplicatedCommand {
+    pub fn decode_alloc(encoded: &[u8]) -> Result<Self> {
+        let config = config::standard();
+        let decoded = bincode::decode_from_slice(encoded, config)?;
+        Ok(decoded.0)
+    }
+
+    pub fn encode_alloc(&self) -> Result<Vec<u8>> {
+        let config = config::standard();
+        let encoded = bincode::encode_to_vec(self, config)?;
+        Ok(encoded)
+    }
+}
+
+/// Keeps track of the replicated commands of a terrain.
+///
+/// Insert this component into the terrain entity on every peer.
+/// The received commands are applied to the terrain in order each frame,
+/// before the local [`EditTerrain`](crate::edit::EditTerrain) events.
+#[derive(Default, Component)]
+pub struct TerrainReplication {
+    /// The sequence number of the next issued command.
+    next_sequence: u64,
+    /// The issued commands, which have not been acknowledged by all peers.
+    log: VecDeque<ReplicatedCommand>,
+    /// The amount of commands applied by each peer.
+    acknowledged: HashMap<PeerId, u64>,
+    /// The received commands, which have not been applied yet.
+    received: BTreeMap<u64, TerrainCommand>,
+    /// The amount of commands applied to the terrain.
+    applied: u64,
+}
+
+impl TerrainReplication {
+    /// Issues the command as the authority of the terrain and queues it for the local terrain.
+    ///
+    /// Returns the replicated command, which has to be sent to all other peers.
+    pub fn issue(&mut self, command: TerrainCommand) -> ReplicatedCommand {
+        let command = ReplicatedCommand {
+            sequence: self.next_sequence,
+            command,
+        };
+
+        self.next_sequence += 1;
+        self.log.push_back(command.clone());
+        self.received
+            .insert(command.sequence, command.command.clone());
+
+        command
+    }
+
+    /// Queues the command received from the authority, which is applied once all previous
+    /// commands have been applied.
+    ///
+    /// Commands, which have already been received or applied, are ignored.
+    pub fn receive(&mut self, command: ReplicatedCommand) {
+        if command.sequence >= self.applied {
+            self.received
+                .entry(command.sequence)
+                .or_insert(command.command);
+        }
+    }
+
+    /// The amount of commands applied to the terrain, which acknowledges all commands
+    /// with a lower sequence number.
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    /// Continues after the commands with a sequence number lower than the amount,
+    /// e.g. after restoring a save of the authority, which already contains their edits.
+    pub fn synchronize(&mut self, applied: u64) {
+        self.applied = applied;
+        self.next_sequence = self.next_sequence.max(applied);
+        self.received = self.received.split_off(&applied);
+    }
+
+    /// Records, that the peer has applied the amount of commands,
+    /// and discards the commands acknowledged by all peers.
+    pub fn acknowledge(&mut self, peer: PeerId, applied: u64) {
+        let acknowledged = self.acknowledged.entry(peer).or_default();
+        *acknowledged = (*acknowledged).max(applied);
+
+        self.discard_acknowledged();
+    }
+
+    /// Stops waiting for the acknowledgements of the peer, e.g. after it disconnected.
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        self.acknowledged.remove(&peer);
+
+        self.discard_acknowledged();
+    }
+
+    /// The issued commands, which have not been acknowledged by the peer yet
+    /// and have to be (re)sent to it.
+    pub fn unacknowledged(&self, peer: PeerId) -> impl Iterator<Item = &ReplicatedCommand> {
+        let acknowledged = self.acknowledged.get(&peer).copied().unwrap_or(0);
+
+        self.log
+            .iter()
+            .filter(move |command| command.sequence >= acknowledged)
+    }
+
+    fn discard_acknowledged(&mut self) {
+        let acknowledged = self
+            .acknowledged
+            .values()
+            .copied()
+            .min()
+            .unwrap_or(self.applied)
+            .min(self.applied);
+
+        while matches!(self.log.front(), Some(command) if command.sequence < acknowledged) {
+            self.log.pop_front();
+        }
+    }
+
+    /// Removes the next command, if all previous commands have been applied.
+    fn next_command(&mut self) -> Option<TerrainCommand> {
+        let command = self.received.remove(&self.applied)?;
+        self.applied += 1;
+
+        Some(command)
+    }
+}
+
+impl NodeAtlas {
+    /// Applies the command to all loaded nodes of the terrain.
+    ///
+    /// The commands have to be applied in the same order on every peer.
+    pub fn apply_command(
+        &mut self,
+        images: &mut Assets<Image>,
+        terrain: Entity,
+        terrain_transform: &GlobalTransform,
+        command: &TerrainCommand,
+    ) {
+        match command {
+            TerrainCommand::Undo => {
+                self.undo(images);
+            }
+            TerrainCommand::Redo => {
+                self.redo(images);
+            }
+            command => {
+                for edit in command.edits(terrain) {
+                    self.push_edit(images, edit, terrain_transform);
+                }
+            }
+        }
+    }
+}
+
+/// Applies the received commands of all replicated terrains in order.
+pub(crate) fn apply_replicated_commands(
+    mut images: ResMut<Assets<Image>>,
+    mut terrain_query: Query<
+        (
+            Entity,
+            &mut NodeAtlas,
+            &GlobalTransform,
+            &mut TerrainReplication,
+        ),
+        With<Terrain>,
+    >,
+) {
+    for (terrain, mut node_atlas, terrain_transform, mut replication) in &mut terrain_query {
+        while let Some(command) = replication.next_command() {
+            node_atlas.apply_command(&mut images, terrain, terrain_transform, &command);
+        }
+
+        replication.discard_acknowledged();
+    }
+}
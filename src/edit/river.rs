@@ -0,0 +1,228 @@
+//! Carving of river channels along splines, built on top of the [`TerrainEdit`] API.
+//!
+//! The control points of the [`TerrainSpline`] of a [`River`] describe its water level from its
+//! source to its mouth. The level is clamped to never rise downstream, so that the water flows
+//! believably, even if the spline was authored carelessly.
+//! The channel is carved below the water level according to the [`RiverProfile`] of its cross-section,
+//! while its banks slope from the water level up to the surrounding terrain.
+//! Optionally the river updates the water attachment (see [`crate::water`]) along its channel.
+//! Each call to [`River::edits`] creates the [`EditTerrain`] events of the river, which can be
+//! undone and redone like any other edit.
+
+use crate::{
+    edit::{
+        spline::{closest_point, TerrainSpline},
+        EditTerrain, TerrainEdit,
+    },
+    terrain_data::AttachmentIndex,
+};
+use bevy::{math::Vec3Swizzles, prelude::*};
+use std::sync::Arc;
+
+/// The cross-section of a river channel.
+#[derive(Clone)]
+pub enum RiverProfile {
+    /// A rounded channel, which is deepest at its center.
+    Parabolic,
+    /// A channel with a flat bed, that covers the fraction (from zero to one) of its width,
+    /// and straight slopes up to its edges.
+    Trapezoid {
+        /// The fraction of the width covered by the flat bed.
+        bed: f32,
+    },
+    /// A custom cross-section, which maps the relative distance from the center (from zero to one)
+    /// to the relative depth (one at the center and zero at the edges).
+    Custom(Arc<dyn Fn(f32) -> f32 + Send + Sync>),
+}
+
+impl RiverProfile {
+    /// Evaluates the relative depth at the relative distance from the center of the channel.
+    fn depth(&self, offset: f32) -> f32 {
+        let offset = offset.clamp(0.0, 1.0);
+
+        let depth = match self {
+            RiverProfile::Parabolic => 1.0 - offset * offset,
+            RiverProfile::Trapezoid { bed } => {
+                let bed = bed.clamp(0.0, 0.999);
+                1.0 - ((offset - bed) / (1.0 - bed)).max(0.0)
+            }
+            RiverProfile::Custom(profile) => profile(offset),
+        };
+
+        depth.clamp(0.0, 1.0)
+    }
+}
+
+/// A river, whose channel is carved into the terrain along a spline.
+#[derive(Clone)]
+pub struct River {
+    /// The spline along the center of the river, whose heights are the water level.
+    pub spline: TerrainSpline,
+    /// The width of the channel at the water level in world space.
+    pub width: f32,
+    /// The depth of the channel below the water level (in the local space of the terrain).
+    pub depth: f32,
+    /// The width of the banks on both sides of the river, across which
+    /// the channel blends into the surrounding terrain.
+    pub bank_width: f32,
+    /// The cross-section of the channel.
+    pub profile: RiverProfile,
+    /// The index of the water attachment and the height of the terrain,
+    /// which the water level is normalized with.
+    pub water: Option<(AttachmentIndex, f32)>,
+}
+
+impl River {
+    /// Creates a new river with a parabolic channel along the spline.
+    pub fn new(spline: TerrainSpline, width: f32, depth: f32, bank_width: f32) -> Self {
+        Self {
+            spline,
+            width,
+            depth,
+            bank_width,
+            profile: RiverProfile::Parabolic,
+            water: None,
+        }
+    }
+
+    /// Uses the cross-section for the channel.
+    pub fn with_profile(mut self, profile: RiverProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Updates the water mask and level of the water attachment along the channel.
+    ///
+    /// The height of the terrain is required to normalize the water level.
+    pub fn with_water_attachment(
+        mut self,
+        attachment_index: AttachmentIndex,
+        terrain_height: f32,
+    ) -> Self {
+        self.water = Some((attachment_index, terrain_height));
+        self
+    }
+
+    /// Creates the terrain edits of the river, which carve its channel and
+    /// update the water attachment (if any).
+    pub fn edits(&self, terrain: Entity) -> Vec<EditTerrain> {
+        let mut polyline = self.spline.polyline();
+
+        if polyline.is_empty() {
+            return Vec::new();
+        }
+
+        // the water level never rises downstream
+        let mut level = f32::INFINITY;
+        for point in &mut polyline {
+            level = level.min(point.y);
+            point.y = level;
+        }
+
+        let half_width = 0.5 * self.width.max(0.0);
+        let bank_width = self.bank_width.max(0.0);
+
+        let region = polyline
+            .iter()
+            .fold(
+                Rect::from_center_size(polyline[0].xz(), Vec2::ZERO),
+                |region, point| region.union_point(point.xz()),
+            )
+            .inset(half_width + bank_width);
+
+        let carve = RiverEdit {
+            polyline: polyline.into(),
+            half_width,
+            depth: self.depth.max(0.0),
+            bank_width,
+            profile: self.profile.clone(),
+            region,
+            water: None,
+        };
+
+        let water = self.water.map(|water| RiverEdit {
+            polyline: carve.polyline.clone(),
+            profile: carve.profile.clone(),
+            water: Some(water),
+            ..carve
+        });
+
+        [Some(carve), water]
+            .into_iter()
+            .flatten()
+            .map(|edit| EditTerrain {
+                terrain,
+                edit: Arc::new(edit),
+            })
+            .collect()
+    }
+}
+
+/// Carves the channel of a river or updates its water attachment.
+struct RiverEdit {
+    /// The polyline along the center of the river, whose heights never rise downstream.
+    polyline: Arc<[Vec3]>,
+    half_width: f32,
+    depth: f32,
+    bank_width: f32,
+    profile: RiverProfile,
+    region: Rect,
+    /// The water attachment and the height of the terrain, if the edit paints instead of carving.
+    water: Option<(AttachmentIndex, f32)>,
+}
+
+impl TerrainEdit for RiverEdit {
+    fn region(&self) -> Rect {
+        self.region
+    }
+
+    fn apply(&self, position: Vec2, height: f32) -> f32 {
+        if self.water.is_some() {
+            return height;
+        }
+
+        let Some((distance, level)) = closest_point(&self.polyline, position) else {
+            return height;
+        };
+
+        let target = if distance < self.half_width {
+            let offset = distance / self.half_width.max(f32::EPSILON);
+            level - self.depth * self.profile.depth(offset)
+        } else if distance < self.half_width + self.bank_width {
+            // the banks slope from the water level up to the surrounding terrain
+            let t = (distance - self.half_width) / self.bank_width;
+            level + (height - level) * t * t * (3.0 - 2.0 * t)
+        } else {
+            return height;
+        };
+
+        // the channel is only carved, the terrain below it is not filled up
+        height.min(target)
+    }
+
+    fn painted_attachment(&self) -> Option<AttachmentIndex> {
+        self.water.map(|(attachment_index, _)| attachment_index)
+    }
+
+    fn paint(&self, position: Vec2, weights: &mut [f32]) {
+        let Some((_, terrain_height)) = self.water else {
+            return;
+        };
+
+        let Some((distance, level)) = closest_point(&self.polyline, position) else {
+            return;
+        };
+
+        if distance >= self.half_width || weights.len() < 2 {
+            return;
+        }
+
+        weights[0] = 1.0;
+        weights[1] = (level / terrain_height.max(f32::EPSILON)).clamp(0.0, 1.0);
+    }
+
+    // the water is painted along with the carving of the channel
+    fn continues_previous(&self) -> bool {
+        self.water.is_some()
+    }
+}
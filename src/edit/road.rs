@@ -0,0 +1,181 @@
+//! Flattening of roads and paths along splines, built on top of the [`TerrainEdit`] API.
+//!
+//! A [`Road`] follows a [`TerrainSpline`] through its control points and blends the terrain
+//! towards the height of the spline across its width, with a smooth falloff at its shoulders,
+//! so that the road conforms to the terrain around it.
+//! Optionally the road paints a layer of the splatmap along the same region.
+//! Each call to [`Road::edits`] creates the [`EditTerrain`] events of the road, which can be
+//! undone and redone like any other edit.
+
+use crate::{
+    edit::{
+        spline::{closest_point, TerrainSpline},
+        EditTerrain, TerrainEdit,
+    },
+    render::splat_material::SPLAT_ATTACHMENT,
+    terrain_data::AttachmentIndex,
+};
+use bevy::{math::Vec3Swizzles, prelude::*};
+use std::sync::Arc;
+
+/// A road or path, which is flattened into the terrain along a spline.
+#[derive(Clone, Debug)]
+pub struct Road {
+    /// The spline along the center of the road.
+    pub spline: TerrainSpline,
+    /// The width of the flat part of the road in world space.
+    pub width: f32,
+    /// The width of the shoulders on both sides of the road, across which
+    /// the road blends into the surrounding terrain.
+    pub falloff: f32,
+    /// The layer of the splatmap, which is painted along the road.
+    pub splat_layer: Option<usize>,
+    /// The index of the painted attachment.
+    pub attachment_index: AttachmentIndex,
+}
+
+impl Road {
+    /// Creates a new road along the spline.
+    pub fn new(spline: TerrainSpline, width: f32, falloff: f32) -> Self {
+        Self {
+            spline,
+            width,
+            falloff,
+            splat_layer: None,
+            attachment_index: SPLAT_ATTACHMENT,
+        }
+    }
+
+    /// Paints the layer of the splatmap along the road.
+    pub fn with_splat_layer(mut self, layer: usize) -> Self {
+        self.splat_layer = Some(layer);
+        self
+    }
+
+    /// Creates the terrain edits of the road, which flatten the terrain and
+    /// paint the splat layer (if any).
+    pub fn edits(&self, terrain: Entity) -> Vec<EditTerrain> {
+        let polyline: Arc<[Vec3]> = self.spline.polyline().into();
+
+        if polyline.is_empty() {
+            return Vec::new();
+        }
+
+        let half_width = 0.5 * self.width.max(0.0);
+        let falloff = self.falloff.max(0.0);
+        let reach = half_width + falloff;
+
+        let region = polyline
+            .iter()
+            .fold(
+                Rect::from_center_size(polyline[0].xz(), Vec2::ZERO),
+                |region, point| region.union_point(point.xz()),
+            )
+            .inset(reach);
+
+        let flatten = RoadEdit {
+            polyline: polyline.clone(),
+            half_width,
+            falloff,
+            region,
+            paint: None,
+        };
+
+        let mut edits = vec![EditTerrain {
+            terrain,
+            edit: Arc::new(flatten),
+        }];
+
+        if let Some(layer) = self.splat_layer {
+            edits.push(EditTerrain {
+                terrain,
+                edit: Arc::new(RoadEdit {
+                    polyline,
+                    half_width,
+                    falloff,
+                    region,
+                    paint: Some((self.attachment_index, layer)),
+                }),
+            });
+        }
+
+        edits
+    }
+}
+
+/// Flattens the terrain along a road or paints its splat layer.
+struct RoadEdit {
+    polyline: Arc<[Vec3]>,
+    half_width: f32,
+    falloff: f32,
+    region: Rect,
+    /// The painted attachment and layer, if the edit paints instead of flattening.
+    paint: Option<(AttachmentIndex, usize)>,
+}
+
+impl RoadEdit {
+    /// Determines the blend factor towards the road and the height of the road
+    /// at the closest point of its center line.
+    fn road_at(&self, position: Vec2) -> Option<(f32, f32)> {
+        let reach = self.half_width + self.falloff;
+
+        let (distance, height) = closest_point(&self.polyline, position)?;
+
+        if distance >= reach {
+            return None;
+        }
+
+        let t = ((distance - self.half_width) / self.falloff.max(f32::EPSILON)).clamp(0.0, 1.0);
+        let blend = 1.0 - t * t * (3.0 - 2.0 * t);
+
+        Some((blend, height))
+    }
+}
+
+impl TerrainEdit for RoadEdit {
+    fn region(&self) -> Rect {
+        self.region
+    }
+
+    fn apply(&self, position: Vec2, height: f32) -> f32 {
+        if self.paint.is_some() {
+            return height;
+        }
+
+        match self.road_at(position) {
+            Some((blend, road_height)) => height + (road_height - height) * blend,
+            None => height,
+        }
+    }
+
+    fn painted_attachment(&self) -> Option<AttachmentIndex> {
+        self.paint.map(|(attachment_index, _)| attachment_index)
+    }
+
+    fn paint(&self, position: Vec2, weights: &mut [f32]) {
+        let Some((_, layer)) = self.paint else {
+            return;
+        };
+
+        let Some((blend, _)) = self.road_at(position) else {
+            return;
+        };
+
+        if layer >= weights.len() {
+            return;
+        }
+
+        for (index, weight) in weights.iter_mut().enumerate() {
+            if index == layer {
+                *weight += (1.0 - *weight) * blend;
+            } else {
+                *weight *= 1.0 - blend;
+            }
+        }
+    }
+
+    // the splat layer is painted along with the flattening
+    fn continues_previous(&self) -> bool {
+        self.paint.is_some()
+    }
+}
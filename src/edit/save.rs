@@ -0,0 +1,759 @@
+//! Saves the runtime modifications of a terrain and restores them later on.
+//!
+//! A save contains the data of all node attachments, that have been modified by edits
+//! (e.g. sculpting, craters or painting), at every level of detail, stored in the versioned
+//! [`TSF`] container. Unlike the edits, which are only kept for the current session,
+//! the saved data does not depend on the types of the edits.
+//! Modified nodes, that have not been loaded since the edits, are read from their node files
+//! and the edits are reapplied to them on the [`IoTaskPool`].
+//!
+//! Instead of the full data, the save may record the modified nodes as deltas, which only
+//! store the dirty rectangles of the edits as the differences to the base data of the nodes.
+//! These are much smaller for local edits, e.g. for synchronizing them over the network,
+//! but can only be applied on top of the same base terrain.
+//! Attachments, which are not loaded from disk (e.g. procedural ones), are always saved in full.
+//!
+//! The modified data of evicted nodes is kept in memory, so that it can be saved
+//! without reading the node from its source again.
+//!
+//! Restoring a save replaces all current edits of the terrain. Whenever a saved node is loaded,
+//! its saved data takes the place of (or is applied to) the loaded data,
+//! before newer edits are reapplied.
+
+use crate::{
+    attachment_loader::{AttachmentFromDisk, AttachmentFromDiskLoader},
+    edit::{history::BakedEdit, AppliedEdit},
+    formats::tsf::{
+        TSFAttachment, TSFChunk, TSFChunkData, TSFCompression, TSFDirtyRect, TSFRectData, TSF,
+    },
+    terrain_data::{
+        node_atlas::NodeAtlas, AtlasAttachment, AttachmentIndex, NodeId, HEIGHT_ATTACHMENT,
+    },
+};
+use anyhow::{anyhow, Result};
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{Extent3d, TextureFormat},
+        texture::CompressedImageFormats,
+    },
+    tasks::{futures_lite::future, IoTaskPool, Task},
+    utils::{HashMap, HashSet},
+};
+use itertools::Itertools;
+use std::{mem, path::PathBuf, sync::Arc};
+
+/// An event, that saves the modified node data of the terrain to the path.
+#[derive(Clone)]
+pub struct SaveTerrain {
+    /// The terrain entity to save.
+    pub terrain: Entity,
+    /// The path of the save file.
+    pub path: PathBuf,
+    /// The compression of the saved node data, including the dirty rectangles of the deltas.
+    pub compression: TSFCompression,
+    /// Whether the modified nodes are saved as the deltas to their base data,
+    /// instead of their full data.
+    pub deltas: bool,
+}
+
+/// An event, that restores the modified node data of the terrain from the save file at the path.
+#[derive(Clone)]
+pub struct LoadTerrainSave {
+    /// The terrain entity to restore.
+    pub terrain: Entity,
+    /// The path of the save file.
+    pub path: PathBuf,
+}
+
+/// The data of a node attachment restored from a save.
+pub(crate) enum SavedData {
+    /// The pixel data of the first mip level, which replaces the loaded data.
+    Full(Vec<u8>),
+    /// The dirty rectangles, which are applied on top of the loaded data.
+    Delta(Vec<TSFDirtyRect>),
+}
+
+/// The source of the data of a saved node attachment.
+enum ChunkData {
+    /// The data of a loaded node, which already includes all edits.
+    Loaded(Vec<u8>),
+    /// The data restored from a previous save, which the edits are reapplied to.
+    Saved(Arc<SavedData>),
+    /// The base data of the node file, which the edits are reapplied to.
+    Base,
+}
+
+/// A node attachment, which is saved.
+struct SavedChunk {
+    node_id: NodeId,
+    attachment_index: AttachmentIndex,
+    data: ChunkData,
+    /// The node file containing the base data, if it is required.
+    file: Option<AttachmentFromDisk>,
+}
+
+/// The data required to save the modified node data of a terrain.
+struct TerrainSave {
+    attachments: Vec<AtlasAttachment>,
+    lod_count: u32,
+    leaf_node_size: u32,
+    height: f32,
+    chunks: Vec<SavedChunk>,
+    baked_edits: Vec<BakedEdit>,
+    edits: Vec<AppliedEdit>,
+    compression: TSFCompression,
+    deltas: bool,
+}
+
+impl TerrainSave {
+    /// Reapplies the pending baked edits and all edits of the history to the pixel data
+    /// of the first mip level of the node attachment.
+    fn reapply_edits(
+        &self,
+        node_id: NodeId,
+        attachment_index: AttachmentIndex,
+        data: Vec<u8>,
+    ) -> Vec<u8> {
+        let mut image = self.attachments[attachment_index].mip_image(data);
+
+        let baked_edits = self
+            .baked_edits
+            .iter()
+            .filter(|baked| baked.pending_nodes.contains(&node_id))
+            .map(|baked| &baked.edit);
+
+        for edit in baked_edits.chain(&self.edits) {
+            edit.edit_image(
+                &self.attachments,
+                attachment_index,
+                self.height,
+                self.leaf_node_size,
+                node_id,
+                0,
+                None,
+                &mut image,
+                &mut Vec::new(),
+            );
+        }
+
+        image.data
+    }
+
+    /// Reads the base data of the first mip level of the node attachment from its node file.
+    async fn read_base(
+        &self,
+        asset_server: &AssetServer,
+        node_id: NodeId,
+        attachment_index: AttachmentIndex,
+        file_attachment: &AttachmentFromDisk,
+    ) -> Result<Vec<u8>> {
+        let bytes = file_attachment.read(asset_server, node_id).await?;
+        let mut image =
+            file_attachment.decode(&bytes, self.height, CompressedImageFormats::NONE)?;
+        image
+            .data
+            .truncate(self.attachments[attachment_index].mip_size());
+
+        Ok(image.data)
+    }
+
+    /// Collects the data of all saved node attachments.
+    async fn collect(mut self, asset_server: AssetServer) -> Result<TSF> {
+        let chunks = std::mem::take(&mut self.chunks);
+
+        // the save only lists the attachments, which contain modified nodes
+        let attachment_indices = chunks
+            .iter()
+            .map(|chunk| chunk.attachment_index)
+            .unique()
+            .sorted()
+            .collect::<Vec<_>>();
+
+        let mut saved_chunks = Vec::with_capacity(chunks.len());
+
+        for SavedChunk {
+            node_id,
+            attachment_index,
+            data,
+            file,
+        } in chunks
+        {
+            let attachment = &self.attachments[attachment_index];
+            let Some(pixel_layout) = attachment.pixel_layout() else {
+                return Err(anyhow!(
+                    "The attachment {} is not accessible on the CPU.",
+                    attachment.name
+                ));
+            };
+            let (pixel_size, channel_count) = pixel_layout;
+
+            let base = match &file {
+                Some(file_attachment) => Some(
+                    self.read_base(&asset_server, node_id, attachment_index, file_attachment)
+                        .await?,
+                ),
+                None => None,
+            };
+
+            let data = match (data, &base) {
+                (ChunkData::Loaded(data), _) => data,
+                (ChunkData::Saved(saved), base) => match &*saved {
+                    SavedData::Full(data) => {
+                        self.reapply_edits(node_id, attachment_index, data.clone())
+                    }
+                    SavedData::Delta(rects) => {
+                        let mut data = base.clone().unwrap();
+
+                        for rect in rects {
+                            rect.apply(
+                                &mut data,
+                                attachment.texture_size,
+                                pixel_size * channel_count,
+                            );
+                        }
+
+                        self.reapply_edits(node_id, attachment_index, data)
+                    }
+                },
+                (ChunkData::Base, base) => {
+                    self.reapply_edits(node_id, attachment_index, base.clone().unwrap())
+                }
+            };
+
+            let table_index = attachment_indices
+                .iter()
+                .position(|&index| index == attachment_index)
+                .unwrap() as u32;
+
+            if let (true, Some(base)) = (self.deltas, &base) {
+                let delta = TSFChunkData::delta(
+                    base,
+                    &data,
+                    attachment.texture_size,
+                    pixel_layout,
+                    self.compression,
+                );
+
+                // nodes, whose edits cancel out, match the base data again
+                if matches!(&delta, TSFChunkData::Delta { rects } if rects.is_empty()) {
+                    continue;
+                }
+
+                saved_chunks.push(TSFChunk {
+                    node_id,
+                    attachment: table_index,
+                    data: delta,
+                });
+            } else {
+                saved_chunks.push(TSFChunk::new(
+                    node_id,
+                    table_index,
+                    attachment.texture_size,
+                    pixel_layout,
+                    self.compression,
+                    &data,
+                ));
+            }
+        }
+
+        Ok(TSF {
+            lod_count: self.lod_count,
+            leaf_node_size: self.leaf_node_size,
+            height: self.height,
+            attachments: attachment_indices
+                .iter()
+                .map(|&attachment_index| {
+                    let attachment = &self.attachments[attachment_index];
+
+                    TSFAttachment {
+                        name: attachment.name.clone(),
+                        texture_size: attachment.texture_size,
+                        format: attachment.attachment_format,
+                    }
+                })
+                .collect(),
+            chunks: saved_chunks,
+        })
+    }
+}
+
+impl AtlasAttachment {
+    /// The size of the pixel data of the first mip level in bytes.
+    ///
+    /// Attachments, which can not be accessed on the CPU, have no pixel data.
+    pub(crate) fn mip_size(&self) -> usize {
+        let Some((pixel_size, channel_count)) = self.pixel_layout() else {
+            return 0;
+        };
+
+        pixel_size * channel_count * (self.texture_size * self.texture_size) as usize
+    }
+
+    /// Wraps the pixel data of the first mip level into an image, which can be edited on the CPU.
+    pub(crate) fn mip_image(&self, data: Vec<u8>) -> Image {
+        let mut image = Image { data, ..default() };
+        image.texture_descriptor.size = Extent3d {
+            width: self.texture_size,
+            height: self.texture_size,
+            depth_or_array_layers: 1,
+        };
+
+        image
+    }
+
+    /// Checks whether the dirty rectangle lies inside the first mip level.
+    fn contains_bounds(&self, rect: &TSFDirtyRect) -> bool {
+        rect.first[0] <= rect.last[0]
+            && rect.first[1] <= rect.last[1]
+            && rect.last[0] < self.texture_size
+            && rect.last[1] < self.texture_size
+    }
+
+    /// Checks whether the decoded dirty rectangle lies inside the first mip level
+    /// and its data covers exactly its pixels.
+    fn contains_rect(&self, rect: &TSFDirtyRect) -> bool {
+        let Some((pixel_size, channel_count)) = self.pixel_layout() else {
+            return false;
+        };
+
+        if !self.contains_bounds(rect) {
+            return false;
+        }
+
+        let Ok(size) = rect.size() else {
+            return false;
+        };
+
+        let rect_bytes = pixel_size * channel_count * size;
+
+        match &rect.data {
+            TSFRectData::Changes { runs, changes } => {
+                let bytes = runs
+                    .iter()
+                    .map(|&(unchanged, changed)| unchanged as usize + changed as usize)
+                    .sum::<usize>();
+                let changed = runs
+                    .iter()
+                    .map(|&(_, changed)| changed as usize)
+                    .sum::<usize>();
+
+                bytes == rect_bytes && changed == changes.len()
+            }
+            TSFRectData::Pixels {
+                compression: TSFCompression::None,
+                data,
+            } => data.len() == rect_bytes,
+            TSFRectData::Pixels { .. } => false,
+        }
+    }
+}
+
+impl NodeAtlas {
+    /// Returns all node attachments, whose data has been modified by the edits
+    /// or restored from a save.
+    fn modified_nodes(&self) -> HashSet<(NodeId, AttachmentIndex)> {
+        let mut modified_nodes = self.saved_nodes.keys().copied().collect::<HashSet<_>>();
+
+        for edit in &self.edits {
+            modified_nodes.extend(self.edited_node_attachments(edit));
+        }
+
+        // the baked edits have not been saved for the nodes, that were not loaded since
+        for baked in &self.baked_edits {
+            modified_nodes.extend(
+                self.edited_node_attachments(&baked.edit)
+                    .into_iter()
+                    .filter(|(node_id, _)| baked.pending_nodes.contains(node_id)),
+            );
+        }
+
+        modified_nodes
+    }
+
+    /// Keeps the modified data of the nodes evicted during the last update in their saved data,
+    /// so that it can still be saved, without reading the base data from the node source.
+    ///
+    /// The edits of the history are reverted in the kept data, because they are reapplied
+    /// to the node, whenever it is loaded again.
+    pub(crate) fn preserve_evicted_nodes(&mut self, images: &Assets<Image>) {
+        if self.evicted_nodes.is_empty() {
+            return;
+        }
+
+        let modified_nodes = self.modified_nodes();
+
+        for (node_id, node_data) in mem::take(&mut self.evicted_nodes) {
+            for (attachment_index, attachment) in self.attachments.iter().enumerate() {
+                if !modified_nodes.contains(&(node_id, attachment_index)) {
+                    continue;
+                }
+
+                let Some(image) = node_data
+                    .attachment(attachment_index)
+                    .and_then(|handle| images.get(handle))
+                else {
+                    continue;
+                };
+
+                let mut image = attachment.mip_image(image.data[..attachment.mip_size()].to_vec());
+
+                for delta in self
+                    .edits
+                    .iter()
+                    .filter_map(|edit| edit.deltas.get(&node_id))
+                    .flatten()
+                    .filter(|delta| delta.attachment_index == attachment_index)
+                {
+                    delta.toggle_image(attachment, &mut image);
+                }
+
+                self.saved_nodes.insert(
+                    (node_id, attachment_index),
+                    Arc::new(SavedData::Full(image.data)),
+                );
+            }
+        }
+    }
+
+    /// Collects the modified node data of the terrain asynchronously, e.g. for sending it
+    /// over the network.
+    ///
+    /// The data of the modified nodes, which have never been loaded, is read from the node files
+    /// of the loader. If the nodes are saved as deltas, the base data of all modified nodes is read
+    /// from the node files as well, while the nodes without node files are saved in full.
+    pub fn create_save(
+        &self,
+        images: &Assets<Image>,
+        loader: Option<&AttachmentFromDiskLoader>,
+        asset_server: &AssetServer,
+        compression: TSFCompression,
+        deltas: bool,
+    ) -> Task<Result<TSF>> {
+        let asset_server = asset_server.clone();
+
+        let chunks = self
+            .modified_nodes()
+            .into_iter()
+            .map(|(node_id, attachment_index)| {
+                let attachment = &self.attachments[attachment_index];
+
+                let loaded = self
+                    .node_data(node_id)
+                    .and_then(|data| data.attachment(attachment_index))
+                    .and_then(|handle| images.get(handle));
+
+                let data = if let Some(image) = loaded {
+                    ChunkData::Loaded(image.data[..attachment.mip_size()].to_vec())
+                } else if let Some(saved) = self.saved_nodes.get(&(node_id, attachment_index)) {
+                    ChunkData::Saved(saved.clone())
+                } else {
+                    ChunkData::Base
+                };
+
+                // the saved deltas are applied relative to the base data
+                let requires_base = match &data {
+                    ChunkData::Loaded(_) => false,
+                    ChunkData::Saved(saved) => matches!(**saved, SavedData::Delta(_)),
+                    ChunkData::Base => true,
+                };
+
+                let file_attachment =
+                    loader.and_then(|loader| loader.attachments.get(&attachment_index));
+
+                if requires_base && file_attachment.is_none() {
+                    return Err(anyhow!(
+                        "The base data of the attachment {attachment_index} of node \
+                         {node_id} is not loaded from disk."
+                    ));
+                }
+
+                // attachments, which are not loaded from disk, are saved in full instead of as deltas
+                let file = if requires_base || deltas {
+                    file_attachment.cloned()
+                } else {
+                    None
+                };
+
+                Ok(SavedChunk {
+                    node_id,
+                    attachment_index,
+                    data,
+                    file,
+                })
+            })
+            .collect::<Result<Vec<_>>>();
+
+        let save = chunks.map(|chunks| TerrainSave {
+            attachments: self.attachments.clone(),
+            lod_count: self.lod_count,
+            leaf_node_size: self.leaf_node_size,
+            height: self.height,
+            chunks,
+            baked_edits: self.baked_edits.iter().map(BakedEdit::detached).collect(),
+            edits: self.edits.iter().map(AppliedEdit::detached).collect(),
+            compression,
+            deltas,
+        });
+
+        IoTaskPool::get().spawn(async move { save?.collect(asset_server).await })
+    }
+
+    /// Saves the modified node data of the terrain to the path asynchronously.
+    ///
+    /// See [`NodeAtlas::create_save`] for the data read from the node files.
+    pub fn save(
+        &self,
+        images: &Assets<Image>,
+        loader: Option<&AttachmentFromDiskLoader>,
+        asset_server: &AssetServer,
+        path: impl Into<PathBuf>,
+        compression: TSFCompression,
+        deltas: bool,
+    ) -> Task<Result<()>> {
+        let path = path.into();
+        let task = self.create_save(images, loader, asset_server, compression, deltas);
+
+        IoTaskPool::get().spawn(async move { task.await?.save_file(path) })
+    }
+
+    /// Restores the modified node data of the save, which replaces all current edits.
+    ///
+    /// The present nodes, whose data changes, are reloaded.
+    /// Saved attachments, which the terrain no longer has, are skipped.
+    /// Saved deltas are applied to the base data of the nodes, whenever they are loaded.
+    pub fn restore(&mut self, save: &TSF) -> Result<()> {
+        if save.lod_count != self.lod_count
+            || save.leaf_node_size != self.leaf_node_size
+            || save.height != self.height
+        {
+            return Err(anyhow!(
+                "The save does not match the configuration of the terrain."
+            ));
+        }
+
+        let attachment_indices = save
+            .attachments
+            .iter()
+            .map(|saved_attachment| {
+                let attachment_index =
+                    self.attachment_index(&saved_attachment.name)
+                        .filter(|&attachment_index| {
+                            let attachment = &self.attachments[attachment_index];
+
+                            attachment.texture_size == saved_attachment.texture_size
+                                && TextureFormat::from(attachment.attachment_format)
+                                    == TextureFormat::from(saved_attachment.format)
+                        });
+
+                if attachment_index.is_none() {
+                    warn!(
+                        "Skipped the saved attachment {}, which does not match the terrain.",
+                        saved_attachment.name
+                    );
+                }
+
+                attachment_index
+            })
+            .collect::<Vec<_>>();
+
+        let mut saved_nodes = HashMap::new();
+
+        for chunk in &save.chunks {
+            let Some(&Some(attachment_index)) = attachment_indices.get(chunk.attachment as usize)
+            else {
+                continue;
+            };
+
+            let attachment = &self.attachments[attachment_index];
+
+            let Some(pixel_layout) = attachment.pixel_layout() else {
+                return Err(anyhow!(
+                    "The saved attachment {} is not accessible on the CPU.",
+                    attachment.name
+                ));
+            };
+
+            let data = match &chunk.data {
+                TSFChunkData::Delta { rects }
+                    if rects.iter().all(|rect| attachment.contains_bounds(rect)) =>
+                {
+                    // the compressed rectangles are decoded once, instead of on every load,
+                    // but only after their bounds are validated, which limit the decoded size
+                    let rects = rects
+                        .iter()
+                        .map(|rect| rect.decode(pixel_layout))
+                        .collect::<Result<Vec<_>>>()?;
+
+                    rects
+                        .iter()
+                        .all(|rect| attachment.contains_rect(rect))
+                        .then_some(SavedData::Delta(rects))
+                }
+                TSFChunkData::Delta { .. } => None,
+                TSFChunkData::Full { .. } => chunk
+                    .decode()?
+                    .filter(|data| data.len() == attachment.mip_size())
+                    .map(SavedData::Full),
+            };
+
+            let Some(data) = data else {
+                return Err(anyhow!(
+                    "The saved data of node {} is corrupted.",
+                    chunk.node_id
+                ));
+            };
+
+            saved_nodes.insert((chunk.node_id, attachment_index), Arc::new(data));
+        }
+
+        // both the previously modified and the restored nodes change
+        let reloaded_nodes = self
+            .modified_nodes()
+            .into_iter()
+            .chain(saved_nodes.keys().copied())
+            .map(|(node_id, _)| node_id)
+            .unique()
+            .collect::<Vec<_>>();
+
+        self.edits.clear();
+        self.undone_edits.clear();
+        self.baked_edits.clear();
+        self.saved_nodes = saved_nodes;
+
+        for node_id in reloaded_nodes {
+            self.reload_node(node_id);
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the loaded data of the node with the data restored from a save,
+    /// or applies the restored deltas to it.
+    pub(crate) fn restore_saved_node(&mut self, images: &mut Assets<Image>, node_id: NodeId) {
+        let NodeAtlas {
+            ref attachments,
+            ref saved_nodes,
+            ref nodes,
+            ref mut data,
+            ref mut attachment_updates,
+            height,
+            ..
+        } = *self;
+
+        if saved_nodes.is_empty() {
+            return;
+        }
+
+        let Some(node) = nodes.get(&node_id) else {
+            return;
+        };
+
+        let atlas_index = node.atlas_index;
+        let data = &mut data[atlas_index as usize];
+
+        for (attachment_index, attachment) in attachments.iter().enumerate() {
+            let (Some(saved), Some(image)) = (
+                saved_nodes.get(&(node_id, attachment_index)),
+                data.attachments
+                    .get(&attachment_index)
+                    .and_then(|handle| images.get_mut(handle)),
+            ) else {
+                continue;
+            };
+
+            match &**saved {
+                SavedData::Full(saved) => image.data[..saved.len()].copy_from_slice(saved),
+                SavedData::Delta(rects) => {
+                    let Some((pixel_size, channel_count)) = attachment.pixel_layout() else {
+                        continue;
+                    };
+
+                    for rect in rects {
+                        rect.apply(
+                            &mut image.data,
+                            attachment.texture_size,
+                            pixel_size * channel_count,
+                        );
+                    }
+                }
+            }
+
+            attachment_updates.extend(attachment.updates(
+                image,
+                atlas_index,
+                attachment_index,
+                UVec2::ZERO,
+                UVec2::splat(attachment.texture_size - 1),
+            ));
+
+            if attachment_index == HEIGHT_ATTACHMENT {
+                data.height_bounds = attachment.bounds(image, 0) * height;
+            }
+        }
+    }
+}
+
+/// Starts saving the terrains requested this frame and logs failed saves.
+pub(crate) fn save_terrains(
+    mut save_events: EventReader<SaveTerrain>,
+    images: Res<Assets<Image>>,
+    asset_server: Res<AssetServer>,
+    terrain_query: Query<(&NodeAtlas, Option<&AttachmentFromDiskLoader>)>,
+) {
+    for SaveTerrain {
+        terrain,
+        path,
+        compression,
+        deltas,
+    } in save_events.iter()
+    {
+        if let Ok((node_atlas, loader)) = terrain_query.get(*terrain) {
+            let path = path.clone();
+            let task = node_atlas.save(
+                &images,
+                loader,
+                &asset_server,
+                path.clone(),
+                *compression,
+                *deltas,
+            );
+
+            IoTaskPool::get()
+                .spawn(async move {
+                    if let Err(error) = task.await {
+                        error!("Failed to save the terrain to {path:?}: {error}");
+                    }
+                })
+                .detach();
+        }
+    }
+}
+
+/// Reads the save files requested to be loaded and restores them, once they have been read.
+pub(crate) fn load_terrain_saves(
+    mut load_events: EventReader<LoadTerrainSave>,
+    mut tasks: Local<Vec<(Entity, PathBuf, Task<Result<TSF>>)>>,
+    mut terrain_query: Query<&mut NodeAtlas>,
+) {
+    for LoadTerrainSave { terrain, path } in load_events.iter() {
+        let file_path = path.clone();
+        let task = IoTaskPool::get().spawn(async move { TSF::load_file(file_path) });
+
+        tasks.push((*terrain, path.clone(), task));
+    }
+
+    tasks.retain_mut(|(terrain, path, task)| {
+        let Some(save) = future::block_on(future::poll_once(task)) else {
+            return true;
+        };
+
+        let result = save.and_then(|save| terrain_query.get_mut(*terrain)?.restore(&save));
+
+        if let Err(error) = result {
+            error!("Failed to load the terrain save {path:?}: {error}");
+        }
+
+        false
+    });
+}
@@ -0,0 +1,84 @@
+//! Splines, along which roads and rivers are shaped into the terrain.
+
+use bevy::{math::Vec3Swizzles, prelude::*};
+
+/// The amount of line segments per span of the spline, used to approximate the distance to it.
+const SEGMENTS_PER_SPAN: usize = 16;
+
+/// A Catmull-Rom spline, which passes through all of its control points.
+#[derive(Clone, Debug, Default)]
+pub struct TerrainSpline {
+    /// The control points, whose x and z coordinates are horizontal world space positions,
+    /// while their y coordinates are heights in the local space of the terrain.
+    pub points: Vec<Vec3>,
+}
+
+impl TerrainSpline {
+    /// Creates a new spline through the control points.
+    pub fn new(points: Vec<Vec3>) -> Self {
+        Self { points }
+    }
+
+    /// Evaluates the span between the control points `index` and `index + 1` at `t`.
+    fn evaluate(&self, index: usize, t: f32) -> Vec3 {
+        let last = self.points.len() - 1;
+        let point = |index: isize| self.points[index.clamp(0, last as isize) as usize];
+        let index = index as isize;
+
+        let (p0, p1, p2, p3) = (
+            point(index - 1),
+            point(index),
+            point(index + 1),
+            point(index + 2),
+        );
+
+        let (t2, t3) = (t * t, t * t * t);
+
+        0.5 * (2.0 * p1
+            + (p2 - p0) * t
+            + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+            + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+    }
+
+    /// Approximates the spline with a polyline.
+    pub(crate) fn polyline(&self) -> Vec<Vec3> {
+        if self.points.len() < 2 {
+            return self.points.clone();
+        }
+
+        let spans = self.points.len() - 1;
+
+        (0..spans * SEGMENTS_PER_SPAN)
+            .map(|segment| {
+                let (span, step) = (segment / SEGMENTS_PER_SPAN, segment % SEGMENTS_PER_SPAN);
+                self.evaluate(span, step as f32 / SEGMENTS_PER_SPAN as f32)
+            })
+            .chain([self.points[spans]])
+            .collect()
+    }
+}
+
+/// Finds the closest point of the polyline to the horizontal position.
+///
+/// Returns the horizontal distance to the point and its height,
+/// or `None` if the polyline is empty.
+pub(crate) fn closest_point(polyline: &[Vec3], position: Vec2) -> Option<(f32, f32)> {
+    let closest = |point: Vec3| (position.distance(point.xz()), point.y);
+
+    if polyline.len() == 1 {
+        return Some(closest(polyline[0]));
+    }
+
+    polyline
+        .windows(2)
+        .map(|segment| {
+            let (start, end) = (segment[0], segment[1]);
+            let direction = end.xz() - start.xz();
+            let t = ((position - start.xz()).dot(direction)
+                / direction.length_squared().max(f32::EPSILON))
+            .clamp(0.0, 1.0);
+
+            closest(start.lerp(end, t))
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+}
@@ -0,0 +1,467 @@
+//! Erodes the terrain with a hydraulic erosion simulation on the GPU.
+//!
+//! The hydraulic erosion is complemented by a thermal erosion pass (see [`thermal`]).
+//!
+//! Send an [`ErodeTerrain`] event, to erode a region of a terrain or the whole terrain.
+//! The heights of the region are sampled from the loaded nodes into a regular grid, which is
+//! eroded by the virtual pipe model in compute shaders (see [`crate::render::erosion`]).
+//! Once the simulation has finished, the difference between the eroded and the original heights
+//! is applied as a regular [`TerrainEdit`], which writes the results back into the node atlas
+//! and can be undone like any other edit. Optionally the edited heightmap is exported to disk.
+//!
+//! Only the loaded nodes are sampled, so the eroded region should be loaded with its highest
+//! level of detail, e.g. by moving a viewer above it, before the erosion is started.
+//! The erosion is aborted, if any part of the region has not been loaded.
+//! Add the [`TerrainErosionPlugin`] to enable the erosion.
+
+use crate::{
+    edit::{export::ExportHeightmap, EditTerrain, TerrainEdit},
+    erosion::thermal::ErodeTerrainThermally,
+    render::erosion::{prepare_erosion, ErosionNode, ErosionPipelines, GpuErosion},
+    terrain::TerrainConfig,
+    terrain_data::sampling::TerrainSampler,
+    TerrainSystemSet,
+};
+use bevy::{
+    math::Vec3Swizzles,
+    prelude::*,
+    render::{main_graph::node::CAMERA_DRIVER, render_graph::RenderGraph, RenderApp, RenderSet},
+    utils::HashMap,
+};
+use crossbeam_channel::{Receiver, Sender};
+use itertools::iproduct;
+use std::{path::PathBuf, sync::Arc};
+
+pub mod thermal;
+
+/// The maximum number of cells along each side of the eroded grid,
+/// which keeps the buffers of the simulation within the default limits of the GPU.
+pub const MAX_EROSION_RESOLUTION: u32 = 2048;
+
+/// The number of cells at the border of the eroded grid, across which the erosion fades out,
+/// because the simulation treats the border as a wall.
+const BORDER_FADE: f32 = 8.0;
+
+/// The parameters of the hydraulic erosion simulation.
+///
+/// The heights and rates are measured in the local space of the terrain,
+/// so they have to be tuned relative to the height of the terrain.
+#[derive(Clone, Copy, Debug)]
+pub struct HydraulicErosion {
+    /// The number of simulated time steps.
+    pub iterations: u32,
+    /// The duration of a time step.
+    pub time_step: f32,
+    /// The height of the rain, that falls onto each cell per unit of time.
+    pub rain_rate: f32,
+    /// The fraction of the water, that evaporates per unit of time.
+    pub evaporation_rate: f32,
+    /// The amount of sediment, that the water can carry relative to its speed and the slope.
+    pub sediment_capacity: f32,
+    /// The rate, at which the water dissolves the terrain, while it carries less sediment than it could.
+    pub dissolving_rate: f32,
+    /// The rate, at which the water deposits sediment, while it carries more sediment than it could.
+    pub deposition_rate: f32,
+    /// The gravitational acceleration, which drives the flow of the water.
+    pub gravity: f32,
+    /// The minimal sine of the slope, so that flat regions erode as well.
+    pub min_tilt: f32,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        Self {
+            iterations: 512,
+            time_step: 0.02,
+            rain_rate: 0.01,
+            evaporation_rate: 0.015,
+            sediment_capacity: 1.0,
+            dissolving_rate: 0.5,
+            deposition_rate: 0.5,
+            gravity: 9.81,
+            min_tilt: 0.05,
+        }
+    }
+}
+
+/// An event, that erodes a region of the terrain.
+#[derive(Clone)]
+pub struct ErodeTerrain {
+    /// The terrain entity to erode.
+    pub terrain: Entity,
+    /// The horizontal world space region to erode, or the whole terrain if `None`.
+    pub region: Option<Rect>,
+    /// The number of cells along the longer side of the region.
+    pub resolution: u32,
+    /// The parameters of the simulation.
+    pub erosion: HydraulicErosion,
+    /// The path, which the edited heightmap is exported to, once the erosion has been applied.
+    pub export: Option<PathBuf>,
+}
+
+impl ErodeTerrain {
+    /// Creates a new event, which erodes the whole terrain.
+    pub fn new(terrain: Entity, erosion: HydraulicErosion) -> Self {
+        Self {
+            terrain,
+            region: None,
+            resolution: 1024,
+            erosion,
+            export: None,
+        }
+    }
+
+    /// Only erodes the horizontal world space region.
+    pub fn with_region(mut self, region: Rect) -> Self {
+        self.region = Some(region);
+        self
+    }
+
+    /// Uses the number of cells along the longer side of the region.
+    pub fn with_resolution(mut self, resolution: u32) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// Exports the edited heightmap to the path, once the erosion has been applied.
+    pub fn with_export(mut self, path: impl Into<PathBuf>) -> Self {
+        self.export = Some(path.into());
+        self
+    }
+}
+
+/// The grid of heights, which is sent to the render world to be eroded.
+pub(crate) struct ErosionJob {
+    pub(crate) id: u32,
+    pub(crate) size: UVec2,
+    pub(crate) spacing: f32,
+    pub(crate) heights: Vec<f32>,
+    pub(crate) erosion: HydraulicErosion,
+}
+
+/// The eroded heights of a job, which are sent back from the render world.
+pub(crate) struct ErosionResult {
+    pub(crate) id: u32,
+    pub(crate) heights: Vec<f32>,
+}
+
+/// An erosion job, which is simulated in the render world.
+struct PendingErosion {
+    terrain: Entity,
+    /// The original heights of the job.
+    grid: ErosionGrid,
+    export: Option<PathBuf>,
+}
+
+/// Tracks the erosion jobs, until their results have been sent back from the render world.
+#[derive(Resource)]
+struct ErosionJobs {
+    next_id: u32,
+    pending: HashMap<u32, PendingErosion>,
+    sender: Sender<ErosionJob>,
+    receiver: Receiver<ErosionResult>,
+}
+
+/// A regular grid of heights (in the local space of the terrain), which is sampled from the
+/// loaded nodes of a terrain.
+struct ErosionGrid {
+    /// The horizontal world space position of the first cell.
+    origin: Vec2,
+    /// The distance between adjacent cells in world space.
+    spacing: f32,
+    size: UVec2,
+    heights: Vec<f32>,
+    /// Whether the height of each cell has been sampled from a loaded node.
+    /// The cells, that have not been loaded, are masked out of the edit.
+    loaded: Vec<bool>,
+}
+
+impl ErosionGrid {
+    /// Samples the region with the number of cells along its longer side.
+    fn sample(sampler: &TerrainSampler, terrain: Entity, region: Rect, resolution: u32) -> Self {
+        let resolution = resolution.clamp(2, MAX_EROSION_RESOLUTION);
+        let spacing = region.size().max_element() / (resolution - 1) as f32;
+        let size = ((region.size() / spacing).ceil().as_uvec2() + 1)
+            .clamp(UVec2::splat(2), UVec2::splat(resolution));
+
+        let (heights, loaded) = iproduct!(0..size.y, 0..size.x)
+            .map(|(y, x)| {
+                let position = region.min + UVec2::new(x, y).as_vec2() * spacing;
+
+                match sampler.local_height_at(terrain, position) {
+                    Some(height) => (height, true),
+                    None => (0.0, false),
+                }
+            })
+            .unzip();
+
+        Self {
+            origin: region.min,
+            spacing,
+            size,
+            heights,
+            loaded,
+        }
+    }
+
+    /// Returns the number of cells, that have not been loaded.
+    fn missing(&self) -> usize {
+        self.loaded.iter().filter(|&&loaded| !loaded).count()
+    }
+
+    /// Creates the edit, which applies the eroded heights of the grid to the terrain.
+    fn edit(&self, eroded: &[f32]) -> ErosionEdit {
+        let deltas = eroded
+            .iter()
+            .zip(&self.heights)
+            .zip(&self.loaded)
+            .map(|((eroded, original), &loaded)| if loaded { eroded - original } else { 0.0 })
+            .collect();
+
+        ErosionEdit {
+            origin: self.origin,
+            spacing: self.spacing,
+            size: self.size,
+            deltas,
+        }
+    }
+}
+
+/// Applies the eroded heights of a grid to the terrain.
+struct ErosionEdit {
+    origin: Vec2,
+    spacing: f32,
+    size: UVec2,
+    /// The difference between the eroded and the original heights of each cell,
+    /// which preserves the details of the terrain between the cells.
+    deltas: Arc<[f32]>,
+}
+
+impl ErosionEdit {
+    fn delta_at(&self, position: Vec2) -> f32 {
+        let coords = (position - self.origin) / self.spacing;
+        let last = (self.size - 1).as_vec2();
+
+        if coords.cmplt(Vec2::ZERO).any() || coords.cmpgt(last).any() {
+            return 0.0;
+        }
+
+        let cell = coords.floor().as_uvec2().min(self.size - 2);
+        let t = coords - cell.as_vec2();
+
+        let delta =
+            |x: u32, y: u32| self.deltas[((cell.y + y) * self.size.x + cell.x + x) as usize];
+
+        let top = delta(0, 0) + (delta(1, 0) - delta(0, 0)) * t.x;
+        let bottom = delta(0, 1) + (delta(1, 1) - delta(0, 1)) * t.x;
+        let delta = top + (bottom - top) * t.y;
+
+        let fade = (coords.min(last - coords).min_element() / BORDER_FADE).clamp(0.0, 1.0);
+
+        delta * fade * fade * (3.0 - 2.0 * fade)
+    }
+}
+
+impl TerrainEdit for ErosionEdit {
+    fn region(&self) -> Rect {
+        Rect::from_corners(
+            self.origin,
+            self.origin + (self.size - 1).as_vec2() * self.spacing,
+        )
+    }
+
+    fn apply(&self, position: Vec2, height: f32) -> f32 {
+        height + self.delta_at(position)
+    }
+}
+
+/// Calculates the horizontal world space region covered by the terrain.
+fn terrain_region(config: &TerrainConfig, transform: &GlobalTransform) -> Rect {
+    let extent = config.terrain_extent.as_vec2();
+
+    let corners = [
+        Vec2::ZERO,
+        Vec2::new(extent.x, 0.0),
+        Vec2::new(0.0, extent.y),
+        extent,
+    ]
+    .map(|corner| {
+        transform
+            .transform_point(Vec3::new(corner.x, 0.0, corner.y))
+            .xz()
+    });
+
+    corners.iter().fold(
+        Rect::from_center_size(corners[0], Vec2::ZERO),
+        |region, &corner| region.union_point(corner),
+    )
+}
+
+/// Samples the heights of the eroded regions and sends them to the render world.
+fn start_terrain_erosion(
+    mut erode_events: EventReader<ErodeTerrain>,
+    mut jobs: ResMut<ErosionJobs>,
+    sampler: TerrainSampler,
+    terrain_query: Query<(&TerrainConfig, &GlobalTransform)>,
+) {
+    for event in erode_events.iter() {
+        let Ok((config, transform)) = terrain_query.get(event.terrain) else {
+            continue;
+        };
+
+        let region = event
+            .region
+            .unwrap_or_else(|| terrain_region(config, transform));
+
+        if region.is_empty() {
+            continue;
+        }
+
+        let grid = ErosionGrid::sample(&sampler, event.terrain, region, event.resolution);
+
+        // the simulation can not mask out the missing cells, because the water flows across them
+        let missing = grid.missing();
+
+        if missing > 0 {
+            warn!("{missing} samples of the eroded region are not loaded, the erosion is aborted.");
+            continue;
+        }
+
+        let id = jobs.next_id;
+        jobs.next_id += 1;
+
+        jobs.sender
+            .send(ErosionJob {
+                id,
+                size: grid.size,
+                spacing: grid.spacing,
+                heights: grid.heights.clone(),
+                erosion: event.erosion,
+            })
+            .ok();
+
+        // the original heights are kept, to compute the difference to the eroded ones
+        jobs.pending.insert(
+            id,
+            PendingErosion {
+                terrain: event.terrain,
+                grid,
+                export: event.export.clone(),
+            },
+        );
+    }
+}
+
+/// Applies the eroded heights, that have been sent back from the render world.
+fn finish_terrain_erosion(
+    mut jobs: ResMut<ErosionJobs>,
+    mut edit_events: EventWriter<EditTerrain>,
+    mut export_events: EventWriter<ExportHeightmap>,
+) {
+    let ErosionJobs {
+        ref mut pending,
+        ref receiver,
+        ..
+    } = *jobs;
+
+    for result in receiver.try_iter() {
+        let Some(job) = pending.remove(&result.id) else {
+            continue;
+        };
+
+        edit_events.send(EditTerrain {
+            terrain: job.terrain,
+            edit: Arc::new(job.grid.edit(&result.heights)),
+        });
+
+        if let Some(path) = job.export {
+            export_events.send(ExportHeightmap {
+                terrain: job.terrain,
+                path,
+            });
+        }
+    }
+}
+
+/// Erodes the regions of the thermal erosion events on the CPU and applies the results.
+fn apply_thermal_erosion(
+    mut erode_events: EventReader<ErodeTerrainThermally>,
+    mut edit_events: EventWriter<EditTerrain>,
+    sampler: TerrainSampler,
+    terrain_query: Query<&GlobalTransform>,
+) {
+    for event in erode_events.iter() {
+        let Ok(transform) = terrain_query.get(event.terrain) else {
+            continue;
+        };
+
+        if event.region.is_empty() {
+            continue;
+        }
+
+        let grid = ErosionGrid::sample(&sampler, event.terrain, event.region, event.resolution);
+
+        // the slopes are measured in the local space of the terrain, like the heights,
+        // so the world space spacing of the cells is transformed into it along each axis
+        let inverse = transform.affine().inverse();
+        let spacing = Vec2::new(
+            inverse.transform_vector3(Vec3::X * grid.spacing).length(),
+            inverse.transform_vector3(Vec3::Z * grid.spacing).length(),
+        );
+
+        let mut heights = grid.heights.clone();
+        event
+            .erosion
+            .erode_masked(&mut heights, Some(&grid.loaded), grid.size, spacing);
+
+        edit_events.send(EditTerrain {
+            terrain: event.terrain,
+            edit: Arc::new(grid.edit(&heights)),
+        });
+    }
+}
+
+/// Simulates the erosion jobs on the GPU and applies their results to the terrains.
+///
+/// The thermal erosion runs on the CPU and is available in `headless` mode as well.
+/// The [`TerrainPlugin`](crate::TerrainPlugin) has to be added beforehand.
+pub struct TerrainErosionPlugin;
+
+impl Plugin for TerrainErosionPlugin {
+    fn build(&self, app: &mut App) {
+        let (job_sender, job_receiver) = crossbeam_channel::unbounded();
+        let (result_sender, result_receiver) = crossbeam_channel::unbounded();
+
+        app.add_event::<ErodeTerrain>()
+            .add_event::<ErodeTerrainThermally>()
+            .insert_resource(ErosionJobs {
+                next_id: 0,
+                pending: default(),
+                sender: job_sender,
+                receiver: result_receiver,
+            })
+            .add_systems(
+                (
+                    start_terrain_erosion,
+                    finish_terrain_erosion,
+                    apply_thermal_erosion,
+                )
+                    .after(TerrainSystemSet::Update)
+                    .in_base_set(CoreSet::Last),
+            );
+
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            warn!("The terrain erosion requires the render app, no erosion will be simulated.");
+            return;
+        };
+
+        render_app
+            .insert_resource(GpuErosion::new(job_receiver, result_sender))
+            .init_resource::<ErosionPipelines>()
+            .add_system(prepare_erosion.in_set(RenderSet::Prepare));
+
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        render_graph.add_node("terrain_erosion", ErosionNode);
+        render_graph.add_node_edge("terrain_erosion", CAMERA_DRIVER);
+    }
+}
@@ -0,0 +1,173 @@
+//! Erodes the terrain with a thermal erosion (talus) pass on the CPU.
+//!
+//! Weathered material slides down all slopes, that are steeper than its angle of repose,
+//! until the slopes settle at the angle. This breaks up the sharp cliffs and spikes left behind
+//! by the hydraulic erosion, by editing or by the source data.
+//!
+//! The pass runs either during preprocessing over the heights of the whole terrain
+//! (see [`Preprocessor::set_thermal_erosion`](crate::preprocess::Preprocessor::set_thermal_erosion)),
+//! or at runtime over a region of a loaded terrain by sending an [`ErodeTerrainThermally`] event,
+//! e.g. with the region of an edit, to settle the slopes it has steepened.
+//! The runtime pass is applied as a regular terrain edit, which can be undone like any other edit.
+
+use bevy::prelude::*;
+use itertools::iproduct;
+use std::f32::consts::FRAC_PI_4;
+
+/// The offsets of the eight neighbours of a cell.
+const NEIGHBOURS: [IVec2; 8] = [
+    IVec2::new(-1, -1),
+    IVec2::new(0, -1),
+    IVec2::new(1, -1),
+    IVec2::new(-1, 0),
+    IVec2::new(1, 0),
+    IVec2::new(-1, 1),
+    IVec2::new(0, 1),
+    IVec2::new(1, 1),
+];
+
+/// The parameters of the thermal erosion pass.
+#[derive(Clone, Copy, Debug)]
+pub struct ThermalErosion {
+    /// The number of iterations of the pass.
+    pub iterations: u32,
+    /// The angle of repose in radians, above which the material slides down the slope.
+    pub talus_angle: f32,
+    /// The fraction (from zero to one) of the excess material, that slides down per iteration.
+    pub strength: f32,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        Self {
+            iterations: 50,
+            talus_angle: 0.8 * FRAC_PI_4,
+            strength: 0.5,
+        }
+    }
+}
+
+impl ThermalErosion {
+    /// Erodes the grid of heights, whose adjacent cells are the spacing (per axis) apart.
+    ///
+    /// The heights and the spacing have to be measured in the same units.
+    /// The pass conserves the material, none of it slides out of the grid.
+    pub fn erode(&self, heights: &mut [f32], size: UVec2, spacing: Vec2) {
+        self.erode_masked(heights, None, size, spacing);
+    }
+
+    /// Erodes the grid of heights like [`Self::erode`], but only the cells, which are set in the mask.
+    ///
+    /// The masked out cells neither give nor receive any material,
+    /// so that missing data does not act as a cliff down to zero.
+    pub fn erode_masked(
+        &self,
+        heights: &mut [f32],
+        mask: Option<&[bool]>,
+        size: UVec2,
+        spacing: Vec2,
+    ) {
+        assert_eq!(heights.len(), (size.x * size.y) as usize);
+
+        if let Some(mask) = mask {
+            assert_eq!(mask.len(), heights.len());
+        }
+
+        let talus = self.talus_angle.tan();
+        let strength = self.strength.clamp(0.0, 1.0);
+        let index = |cell: IVec2| (cell.y as u32 * size.x + cell.x as u32) as usize;
+        let valid = |index: usize| mask.map_or(true, |mask| mask[index]);
+
+        let mut deltas = vec![0.0; heights.len()];
+
+        for _ in 0..self.iterations {
+            deltas.fill(0.0);
+
+            for (x, y) in iproduct!(0..size.x as i32, 0..size.y as i32) {
+                let cell = IVec2::new(x, y);
+
+                if !valid(index(cell)) {
+                    continue;
+                }
+
+                let height = heights[index(cell)];
+
+                let mut excesses = [(0, 0.0); 8];
+                let mut count = 0;
+                let mut total_excess = 0.0;
+                let mut max_excess: f32 = 0.0;
+
+                for offset in NEIGHBOURS {
+                    let neighbour = cell + offset;
+
+                    if neighbour.cmplt(IVec2::ZERO).any()
+                        || neighbour.cmpge(size.as_ivec2()).any()
+                        || !valid(index(neighbour))
+                    {
+                        continue;
+                    }
+
+                    let distance = (spacing * offset.as_vec2()).length();
+                    let excess = height - heights[index(neighbour)] - talus * distance;
+
+                    if excess > 0.0 {
+                        excesses[count] = (index(neighbour), excess);
+                        count += 1;
+                        total_excess += excess;
+                        max_excess = max_excess.max(excess);
+                    }
+                }
+
+                if count == 0 {
+                    continue;
+                }
+
+                // moving half of the largest excess settles the steepest slope without overshooting
+                let moved = 0.5 * max_excess * strength;
+
+                for &(neighbour, excess) in &excesses[..count] {
+                    let amount = moved * excess / total_excess;
+                    deltas[neighbour] += amount;
+                    deltas[index(cell)] -= amount;
+                }
+            }
+
+            for (height, delta) in heights.iter_mut().zip(&deltas) {
+                *height += delta;
+            }
+        }
+    }
+}
+
+/// An event, that erodes a region of the terrain with a thermal erosion pass.
+///
+/// The region is eroded on the main thread, so it should be kept small.
+#[derive(Clone)]
+pub struct ErodeTerrainThermally {
+    /// The terrain entity to erode.
+    pub terrain: Entity,
+    /// The horizontal world space region to erode.
+    pub region: Rect,
+    /// The number of cells along the longer side of the region.
+    pub resolution: u32,
+    /// The parameters of the pass.
+    pub erosion: ThermalErosion,
+}
+
+impl ErodeTerrainThermally {
+    /// Creates a new event, which erodes the region.
+    pub fn new(terrain: Entity, region: Rect, erosion: ThermalErosion) -> Self {
+        Self {
+            terrain,
+            region,
+            resolution: 128,
+            erosion,
+        }
+    }
+
+    /// Uses the number of cells along the longer side of the region.
+    pub fn with_resolution(mut self, resolution: u32) -> Self {
+        self.resolution = resolution;
+        self
+    }
+}
@@ -2,16 +2,79 @@
 //!
 //! It is based on the DTM and QOI format internally.
 
+pub mod quantized_mesh;
 pub mod tc;
 pub mod tdf;
+pub mod terrain_rgb;
+#[cfg(feature = "tnf")]
+pub mod tnf;
+pub mod tsf;
 
-use crate::formats::tdf::TDF;
+use crate::{formats::tdf::TDF, terrain_data::FileFormat};
+use anyhow::Result;
 use bevy::{
     asset::{AssetLoader, Error, LoadedAsset},
     prelude::*,
-    render::render_resource::*,
+    render::{
+        render_resource::*,
+        texture::{CompressedImageFormats, ImageType},
+    },
 };
 
+/// Decodes a TDF file into an image.
+///
+/// The format of the image has to be set according to the attachment afterwards.
+pub(crate) fn decode_tdf(bytes: &[u8]) -> Result<Image> {
+    let (descriptor, mut data) = TDF::decode_alloc(bytes, true)?;
+
+    // extend alpha channel
+    if descriptor.channel_count == 3 && descriptor.pixel_size == 1 {
+        data = data
+            .chunks_exact(3)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], u8::MAX])
+            .collect();
+    };
+
+    Ok(Image {
+        data,
+        texture_descriptor: TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: descriptor.size,
+                height: descriptor.size,
+                ..default()
+            },
+            mip_level_count: descriptor.mip_level_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            view_formats: &[],
+        },
+        sampler_descriptor: Default::default(),
+        texture_view_descriptor: None,
+    })
+}
+
+/// Decodes an image file of the file format, without the asset system.
+pub(crate) fn decode_image(
+    bytes: &[u8],
+    file_format: FileFormat,
+    compressed_formats: CompressedImageFormats,
+) -> Result<Image> {
+    let image = match file_format {
+        FileFormat::TDF => decode_tdf(bytes)?,
+        file_format => Image::from_buffer(
+            bytes,
+            ImageType::Extension(file_format.extension()),
+            compressed_formats,
+            false,
+        )?,
+    };
+
+    Ok(image)
+}
+
 struct TDFAssetLoader;
 
 impl AssetLoader for TDFAssetLoader {
@@ -21,34 +84,33 @@ impl AssetLoader for TDFAssetLoader {
         load_context: &'a mut bevy::asset::LoadContext,
     ) -> bevy::utils::BoxedFuture<'a, Result<(), Error>> {
         Box::pin(async move {
-            let (descriptor, mut data) = TDF::decode_alloc(bytes, true).unwrap();
-
-            // extend alpha channel
-            if descriptor.channel_count == 3 && descriptor.pixel_size == 1 {
-                data = data
-                    .chunks_exact(3)
-                    .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], u8::MAX])
-                    .collect();
-            };
+            let image = decode_tdf(bytes)?;
+
+            load_context.set_default_asset(LoadedAsset::new(image));
+
+            Ok(())
+        })
+    }
 
+    fn extensions(&self) -> &[&str] {
+        &["tdf"]
+    }
+}
+
+/// Loads the undecoded quantized-mesh tiles, which are rasterized once the
+/// configuration of their attachment is known.
+struct QuantizedMeshAssetLoader;
+
+impl AssetLoader for QuantizedMeshAssetLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut bevy::asset::LoadContext,
+    ) -> bevy::utils::BoxedFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
             let image = Image {
-                data,
-                texture_descriptor: TextureDescriptor {
-                    label: None,
-                    size: Extent3d {
-                        width: descriptor.size,
-                        height: descriptor.size,
-                        ..default()
-                    },
-                    mip_level_count: descriptor.mip_level_count,
-                    sample_count: 1,
-                    dimension: TextureDimension::D2,
-                    format: TextureFormat::R8Unorm,
-                    usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                    view_formats: &[],
-                },
-                sampler_descriptor: Default::default(),
-                texture_view_descriptor: None,
+                data: bytes.to_vec(),
+                ..default()
             };
 
             load_context.set_default_asset(LoadedAsset::new(image));
@@ -58,15 +120,21 @@ impl AssetLoader for TDFAssetLoader {
     }
 
     fn extensions(&self) -> &[&str] {
-        &["tdf"]
+        &["terrain"]
     }
 }
 
-/// Plugin that registers the `TDFAssetLoader`.
+/// Plugin that registers the `TDFAssetLoader` and the `QuantizedMeshAssetLoader`,
+/// as well as the [`ElevationAssetLoader`](crate::preprocess::elevation::ElevationAssetLoader)
+/// with the `elevation` feature.
 pub struct TDFPlugin;
 
 impl Plugin for TDFPlugin {
     fn build(&self, app: &mut App) {
-        app.add_asset_loader(TDFAssetLoader);
+        app.add_asset_loader(TDFAssetLoader)
+            .add_asset_loader(QuantizedMeshAssetLoader);
+
+        #[cfg(feature = "elevation")]
+        app.add_asset_loader(crate::preprocess::elevation::ElevationAssetLoader);
     }
 }
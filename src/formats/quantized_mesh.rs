@@ -0,0 +1,243 @@
+//! Decodes Cesium quantized-mesh tiles, which store the terrain as an irregular triangle mesh.
+//!
+//! The triangles are rasterized into the regular grid of the height attachment,
+//! so that they can be streamed and rendered like any other node.
+//! Extensions (e.g. vertex normals or water masks) are ignored.
+//!
+//! The tiles are only supported as an import format. The triangles are not rendered directly,
+//! because the terrain renders every node as a regular grid, whose level of detail is morphed
+//! and selected per tile on the GPU. The vertices of a tile are thus resampled at the
+//! resolution of the height attachment, which should match the density of the finest tiles.
+
+use anyhow::{anyhow, Result};
+use bevy::{
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+
+/// The size of the tile header, which precedes the vertex data.
+const HEADER_SIZE: usize = 88;
+/// The maximum value of the quantized vertex coordinates.
+const QUANTIZED_MAX: f32 = 32767.0;
+
+/// A decoded quantized-mesh tile.
+#[derive(Default)]
+pub struct QuantizedMesh {
+    /// The vertices of the tile, with the horizontal coordinates ranging from zero to one
+    /// (from west to east and south to north) and the elevation in meters.
+    pub vertices: Vec<Vec3>,
+    /// The vertex indices of the triangles of the tile.
+    pub indices: Vec<u32>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + N)
+            .ok_or_else(|| anyhow!("The quantized-mesh tile is truncated."))?;
+        self.position += N;
+
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn align(&mut self, alignment: usize) {
+        self.position = (self.position + alignment - 1) / alignment * alignment;
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    /// Reads the zig-zag and delta encoded vertex coordinates.
+    fn coordinates(&mut self, count: usize) -> Result<Vec<f32>> {
+        let mut value = 0_i32;
+
+        (0..count)
+            .map(|_| {
+                let code = self.u16()? as i32;
+                value += (code >> 1) ^ -(code & 1);
+
+                Ok(value as f32 / QUANTIZED_MAX)
+            })
+            .collect()
+    }
+
+    /// Reads the high water mark encoded triangle indices.
+    fn indices(&mut self, count: usize, wide: bool) -> Result<Vec<u32>> {
+        let mut highest = 0;
+
+        (0..count)
+            .map(|_| {
+                let code = if wide {
+                    self.u32()?
+                } else {
+                    self.u16()? as u32
+                };
+                let index = highest.wrapping_sub(code);
+
+                if code == 0 {
+                    highest += 1;
+                }
+
+                Ok(index)
+            })
+            .collect()
+    }
+}
+
+impl QuantizedMesh {
+    /// Decodes an uncompressed quantized-mesh tile.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        reader.position = 24; // skip the tile center
+        let min_height = reader.f32()?;
+        let max_height = reader.f32()?;
+        reader.position = HEADER_SIZE; // skip the bounding sphere and horizon occlusion point
+
+        let vertex_count = reader.u32()? as usize;
+        let u = reader.coordinates(vertex_count)?;
+        let v = reader.coordinates(vertex_count)?;
+        let h = reader.coordinates(vertex_count)?;
+
+        let wide = vertex_count > 65536;
+        reader.align(if wide { 4 } else { 2 });
+
+        let triangle_count = reader.u32()? as usize;
+        let indices = reader.indices(3 * triangle_count, wide)?;
+
+        if indices.iter().any(|&index| index as usize >= vertex_count) {
+            return Err(anyhow!("The quantized-mesh tile contains invalid indices."));
+        }
+
+        let vertices = itertools::izip!(u, v, h)
+            .map(|(u, v, h)| Vec3::new(u, v, min_height + h * (max_height - min_height)))
+            .collect();
+
+        Ok(Self { vertices, indices })
+    }
+
+    /// Rasterizes the elevations of the triangles into the center of a square grid.
+    ///
+    /// Pixels not covered by any triangle are `NaN`.
+    fn rasterize(&self, size: u32) -> Vec<f32> {
+        let mut elevations = vec![f32::NAN; (size * size) as usize];
+
+        // transforms a vertex into pixel space, where the rows are counted from the north
+        let to_pixel = |vertex: Vec3| {
+            Vec3::new(
+                vertex.x * size as f32 - 0.5,
+                (1.0 - vertex.y) * size as f32 - 0.5,
+                vertex.z,
+            )
+        };
+
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| to_pixel(self.vertices[triangle[i] as usize]));
+
+            let area = (b.x - a.x) * (c.y - a.y) - (c.x - a.x) * (b.y - a.y);
+
+            if area.abs() < f32::EPSILON {
+                continue;
+            }
+
+            let min = a.min(b).min(c).truncate().ceil().max(Vec2::ZERO).as_uvec2();
+            let max = a
+                .max(b)
+                .max(c)
+                .truncate()
+                .floor()
+                .min(Vec2::splat(size as f32 - 1.0))
+                .as_uvec2();
+
+            for (x, y) in itertools::iproduct!(min.x..=max.x, min.y..=max.y) {
+                let p = Vec2::new(x as f32, y as f32);
+
+                let w_a = ((b.x - p.x) * (c.y - p.y) - (c.x - p.x) * (b.y - p.y)) / area;
+                let w_b = ((c.x - p.x) * (a.y - p.y) - (a.x - p.x) * (c.y - p.y)) / area;
+                let w_c = 1.0 - w_a - w_b;
+
+                if w_a >= -1e-5 && w_b >= -1e-5 && w_c >= -1e-5 {
+                    elevations[(y * size + x) as usize] = w_a * a.z + w_b * b.z + w_c * c.z;
+                }
+            }
+        }
+
+        elevations
+    }
+
+    /// Converts the tile into a height node of the format.
+    ///
+    /// The `min_elevation` is mapped to zero and `min_elevation + height` to one.
+    /// The border of the node is extended from the edges of the tile.
+    /// Fails if the format is not the one of a height attachment.
+    pub(crate) fn to_image(
+        &self,
+        texture_size: u32,
+        border_size: u32,
+        format: TextureFormat,
+        min_elevation: f32,
+        height: f32,
+    ) -> Result<Image> {
+        let center_size = texture_size - 2 * border_size;
+        let elevations = self.rasterize(center_size);
+
+        let fallback = self
+            .vertices
+            .iter()
+            .map(|vertex| vertex.z)
+            .reduce(f32::min)
+            .unwrap_or(min_elevation);
+
+        let heights = itertools::iproduct!(0..texture_size, 0..texture_size).map(|(y, x)| {
+            let x = x.saturating_sub(border_size).min(center_size - 1);
+            let y = y.saturating_sub(border_size).min(center_size - 1);
+
+            let elevation = elevations[(y * center_size + x) as usize];
+            let elevation = if elevation.is_nan() {
+                fallback
+            } else {
+                elevation
+            };
+
+            ((elevation - min_elevation) / height).clamp(0.0, 1.0)
+        });
+
+        let data = match format {
+            TextureFormat::R16Unorm => heights
+                .flat_map(|value| ((value * u16::MAX as f32).round() as u16).to_le_bytes())
+                .collect(),
+            TextureFormat::R32Float => heights.flat_map(|value| value.to_le_bytes()).collect(),
+            _ => {
+                return Err(anyhow!(
+                    "Quantized-mesh tiles can only be decoded into height attachments."
+                ))
+            }
+        };
+
+        Ok(Image::new(
+            Extent3d {
+                width: texture_size,
+                height: texture_size,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            format,
+        ))
+    }
+}
@@ -6,6 +6,8 @@ use std::{fs, path::Path};
 #[derive(Encode, Decode, Debug)]
 pub struct TC {
     pub nodes: Vec<NodeId>,
+    /// The normalized minimum and maximum height of each node, in the same order as the nodes.
+    pub height_bounds: Vec<(f32, f32)>,
 }
 
 impl TC {
@@ -59,26 +59,13 @@ impl TDF {
             }
         }
 
-        let mut decoded_start = 0;
-
-        for mip_level in 1..descriptor.mip_level_count {
-            let decoded_size = descriptor.decoded_size(mip_level - 1);
-
-            let p_size = (descriptor.size >> (mip_level - 1)) as usize;
-            let c_size = (descriptor.size >> mip_level) as usize;
-            let p_start = decoded_start;
-            let c_start = decoded_start + decoded_size;
-
-            match (descriptor.channel_count, descriptor.pixel_size) {
-                (1, 2) => generate_mipmap::<1, 2>(&mut decoded, p_size, c_size, p_start, c_start),
-                (2, 2) => generate_mipmap::<2, 2>(&mut decoded, p_size, c_size, p_start, c_start),
-                (3, 1) => generate_mipmap::<3, 1>(&mut decoded, p_size, c_size, p_start, c_start),
-                (4, 1) => generate_mipmap::<4, 1>(&mut decoded, p_size, c_size, p_start, c_start),
-                (_, _) => {}
-            }
-
-            decoded_start += decoded_size;
-        }
+        generate_mipmaps(
+            &mut decoded,
+            descriptor.size,
+            descriptor.pixel_size,
+            descriptor.channel_count,
+            descriptor.mip_level_count,
+        );
 
         Ok((descriptor, decoded))
     }
@@ -162,6 +149,36 @@ impl TDF {
     }
 }
 
+/// Generates all mip levels of the image data from its first level, by averaging the pixels.
+pub(crate) fn generate_mipmaps(
+    decoded: &mut [u8],
+    size: u32,
+    pixel_size: u32,
+    channel_count: u32,
+    mip_level_count: u32,
+) {
+    let mut decoded_start = 0;
+
+    for mip_level in 1..mip_level_count {
+        let p_size = (size >> (mip_level - 1)) as usize;
+        let c_size = (size >> mip_level) as usize;
+        let decoded_size = p_size * p_size * (pixel_size * channel_count) as usize;
+        let p_start = decoded_start;
+        let c_start = decoded_start + decoded_size;
+
+        match (channel_count, pixel_size) {
+            (1, 2) => generate_mipmap::<1, 2>(decoded, p_size, c_size, p_start, c_start),
+            (2, 2) => generate_mipmap::<2, 2>(decoded, p_size, c_size, p_start, c_start),
+            (3, 1) => generate_mipmap::<3, 1>(decoded, p_size, c_size, p_start, c_start),
+            (4, 1) => generate_mipmap::<4, 1>(decoded, p_size, c_size, p_start, c_start),
+            (1, 4) => generate_mipmap_f32(decoded, p_size, c_size, p_start, c_start),
+            (_, _) => {}
+        }
+
+        decoded_start += decoded_size;
+    }
+}
+
 fn generate_mipmap<const C: usize, const P: usize>(
     decoded: &mut [u8],
     p_size: usize,
@@ -192,3 +209,31 @@ fn generate_mipmap<const C: usize, const P: usize>(
         }
     }
 }
+
+/// Averages single channel 32 bit float pixels, which can not be summed up bytewise.
+fn generate_mipmap_f32(
+    decoded: &mut [u8],
+    p_size: usize,
+    c_size: usize,
+    p_start: usize,
+    c_start: usize,
+) {
+    for (c_y, c_x) in iproduct!(0..c_size, 0..c_size) {
+        let mut value = 0.0;
+
+        for i in 0..4 {
+            let p_x = (c_x << 1) + (i >> 1);
+            let p_y = (c_y << 1) + (i & 1);
+
+            let index = p_start + 4 * (p_y * p_size + p_x);
+
+            value += f32::from_le_bytes(decoded[index..index + 4].try_into().unwrap());
+        }
+
+        value /= 4.0;
+
+        let index = c_start + 4 * (c_y * c_size + c_x);
+
+        decoded[index..index + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
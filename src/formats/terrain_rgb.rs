@@ -0,0 +1,46 @@
+//! Decodes Mapbox Terrain-RGB tiles, which pack the elevation into the three color channels.
+//!
+//! The elevation (in meters) is encoded as `-10000 + (r * 256 * 256 + g * 256 + b) * 0.1`.
+
+use anyhow::{anyhow, Result};
+use bevy::render::render_resource::TextureFormat;
+
+/// Decodes the elevation of a Terrain-RGB pixel in meters.
+#[inline]
+pub fn decode_elevation(pixel: &[u8]) -> f32 {
+    let value = (pixel[0] as u32) << 16 | (pixel[1] as u32) << 8 | pixel[2] as u32;
+
+    -10000.0 + value as f32 * 0.1
+}
+
+/// Converts the four channel Terrain-RGB data into normalized heights of the format.
+///
+/// The `min_elevation` is mapped to zero and `min_elevation + height` to one.
+/// Fails if the format is not the one of a height attachment.
+pub(crate) fn decode_heights(
+    data: &[u8],
+    format: TextureFormat,
+    min_elevation: f32,
+    height: f32,
+) -> Result<Vec<u8>> {
+    let normalize =
+        |pixel: &[u8]| ((decode_elevation(pixel) - min_elevation) / height).clamp(0.0, 1.0);
+
+    let heights = match format {
+        TextureFormat::R16Unorm => data
+            .chunks_exact(4)
+            .flat_map(|pixel| ((normalize(pixel) * u16::MAX as f32).round() as u16).to_le_bytes())
+            .collect(),
+        TextureFormat::R32Float => data
+            .chunks_exact(4)
+            .flat_map(|pixel| normalize(pixel).to_le_bytes())
+            .collect(),
+        _ => {
+            return Err(anyhow!(
+                "Terrain-RGB nodes can only be decoded into height attachments."
+            ))
+        }
+    };
+
+    Ok(heights)
+}
@@ -0,0 +1,378 @@
+//! The compact Terrain Node Format (TNF), which stores all attachments of a node in one file.
+//!
+//! The height-like attachments (R16, Rg16 and R32F) are quantized relative to the value range
+//! of each node, which is stored as a per-node offset and scale. As the values of a node
+//! usually span only a fraction of the terrain height, even 16 bit quantization is lossless
+//! for 16 bit attachments, while 8 bit quantization trades precision for size.
+//! The pixels along the edges, which overlap the neighbouring nodes, are stored losslessly,
+//! so that the differently quantized nodes still match at their seams.
+//! The min and max of the Rg16 height bounds are rounded down and up respectively,
+//! so that the quantized bounds stay conservative.
+//! The color attachments are stored as is. Only the first mip level is stored and the whole
+//! file is compressed with zstd. The other mip levels are regenerated while decoding.
+//! Requires the `tnf` feature.
+
+use crate::{formats::tdf::generate_mipmaps, terrain_data::AttachmentFormat};
+use anyhow::{anyhow, Result};
+use bevy::{prelude::*, render::render_resource::*};
+use bincode::{config, Decode, Encode};
+use std::{fs, path::Path};
+
+/// The magic number at the start of each node file.
+const TNF_MAGIC: [u8; 4] = *b"TNF\0";
+
+/// The version of the current layout of the node files.
+pub const TNF_VERSION: u32 = 2;
+
+/// The zstd compression level used for encoding.
+const COMPRESSION_LEVEL: i32 = 19;
+
+/// The encoding of the pixel data of an attachment.
+#[derive(Encode, Decode, Clone, Copy, Debug)]
+pub enum TNFEncoding {
+    /// The pixel data is stored as is.
+    Raw,
+    /// The normalized values are quantized into unsigned integers of the bits (8 or 16),
+    /// where each value is reconstructed as `offset + quantized * scale`.
+    /// The pixels within the border width of the edges are stored as is instead.
+    Quantized {
+        bits: u8,
+        offset: f32,
+        scale: f32,
+        border_width: u32,
+    },
+}
+
+/// The first mip level of one attachment of a node.
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct TNFAttachment {
+    /// The name of the attachment.
+    pub name: String,
+    pub texture_size: u32,
+    pub mip_level_count: u32,
+    pub format: AttachmentFormat,
+    pub encoding: TNFEncoding,
+    /// The encoded pixel data of the first mip level.
+    pub data: Vec<u8>,
+    /// The raw pixel data of the quantized attachments along the edges of the node.
+    pub border: Vec<u8>,
+}
+
+impl AttachmentFormat {
+    /// The size of one channel in bytes and the number of channels.
+    fn tnf_layout(self) -> (usize, usize) {
+        match self {
+            AttachmentFormat::Rgb8 => (1, 3),
+            AttachmentFormat::Rgba8 | AttachmentFormat::Rgba8Linear => (1, 4),
+            AttachmentFormat::R16 => (2, 1),
+            AttachmentFormat::Rg16 => (2, 2),
+            AttachmentFormat::R32F => (4, 1),
+        }
+    }
+}
+
+/// Returns whether the pixel lies within the border width of the edges.
+fn is_border(x: u32, y: u32, texture_size: u32, border_width: u32) -> bool {
+    x.min(y) < border_width || x.max(y) >= texture_size.saturating_sub(border_width)
+}
+
+/// Rounds the quantized value of the channel.
+///
+/// The min and max of the Rg16 height bounds are rounded conservatively.
+fn round_channel(format: AttachmentFormat, channel: usize, value: f32) -> f32 {
+    match (format, channel) {
+        (AttachmentFormat::Rg16, 0) => value.floor(),
+        (AttachmentFormat::Rg16, _) => value.ceil(),
+        _ => value.round(),
+    }
+}
+
+/// Reads the normalized values of the 16 bit or float pixel data.
+fn normalized_values(format: AttachmentFormat, data: &[u8]) -> Vec<f32> {
+    match format {
+        AttachmentFormat::R32F => data
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect(),
+        _ => data
+            .chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32)
+            .collect(),
+    }
+}
+
+impl TNFAttachment {
+    /// Encodes the pixel data of the first mip level of the attachment.
+    ///
+    /// The 16 bit and float attachments are quantized with the bits (8 or 16),
+    /// except for the pixels, which overlap the neighbouring nodes (twice the border size).
+    pub fn new(
+        name: String,
+        texture_size: u32,
+        border_size: u32,
+        mip_level_count: u32,
+        format: AttachmentFormat,
+        bits: u8,
+        decoded: &[u8],
+    ) -> Self {
+        let (encoding, data, border) = match format {
+            AttachmentFormat::Rgb8 | AttachmentFormat::Rgba8 | AttachmentFormat::Rgba8Linear => {
+                (TNFEncoding::Raw, decoded.to_vec(), Vec::new())
+            }
+            format => {
+                let bits = if bits <= 8 { 8 } else { 16 };
+                let levels = ((1u32 << bits) - 1) as f32;
+                let border_width = (2 * border_size).max(1);
+                let (pixel_size, channel_count) = format.tnf_layout();
+                let pixel_bytes = pixel_size * channel_count;
+
+                // the border pixels are stored as is, only the inner ones are quantized
+                let mut border = Vec::new();
+                let mut values = Vec::new();
+
+                for (index, (pixel, pixel_values)) in decoded
+                    .chunks_exact(pixel_bytes)
+                    .zip(normalized_values(format, decoded).chunks_exact(channel_count))
+                    .enumerate()
+                {
+                    let (x, y) = (index as u32 % texture_size, index as u32 / texture_size);
+
+                    if is_border(x, y, texture_size, border_width) {
+                        border.extend_from_slice(pixel);
+                    } else {
+                        values.extend_from_slice(pixel_values);
+                    }
+                }
+
+                let (min, max) = values
+                    .iter()
+                    .fold((f32::MAX, f32::MIN), |(min, max), &value| {
+                        (min.min(value), max.max(value))
+                    });
+                let (min, max) = if min > max { (0.0, 0.0) } else { (min, max) };
+
+                // 16 bit attachments are never quantized finer than their own precision
+                let mut scale = (max - min) / levels;
+                if !matches!(format, AttachmentFormat::R32F) {
+                    scale = scale.max(1.0 / u16::MAX as f32);
+                }
+                let scale = if scale > 0.0 { scale } else { 1.0 };
+
+                let quantized = values.iter().enumerate().map(|(index, &value)| {
+                    round_channel(format, index % channel_count, (value - min) / scale)
+                        .clamp(0.0, levels) as u16
+                });
+
+                let data = if bits == 8 {
+                    quantized.map(|value| value as u8).collect()
+                } else {
+                    quantized.flat_map(u16::to_le_bytes).collect()
+                };
+
+                (
+                    TNFEncoding::Quantized {
+                        bits,
+                        offset: min,
+                        scale,
+                        border_width,
+                    },
+                    data,
+                    border,
+                )
+            }
+        };
+
+        Self {
+            name,
+            texture_size,
+            mip_level_count,
+            format,
+            encoding,
+            data,
+            border,
+        }
+    }
+
+    /// Decodes the pixel data of the attachment, including all mip levels.
+    ///
+    /// Like the GPU texture, the decoded Rgb8 data contains an opaque alpha channel.
+    pub fn decode(&self) -> Result<Vec<u8>> {
+        let (pixel_size, channel_count) = self.format.tnf_layout();
+        let value_count = (self.texture_size * self.texture_size) as usize * channel_count;
+        let corrupted = || anyhow!("The data of the attachment {} is corrupted.", self.name);
+
+        let mut decoded = match self.encoding {
+            TNFEncoding::Raw => self.data.clone(),
+            TNFEncoding::Quantized {
+                bits,
+                offset,
+                scale,
+                border_width,
+            } => {
+                let quantized: Vec<u16> = match bits {
+                    8 => self.data.iter().map(|&value| value as u16).collect(),
+                    16 => self
+                        .data
+                        .chunks_exact(2)
+                        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                        .collect(),
+                    _ => return Err(anyhow!("The quantization of {bits} bits is invalid.")),
+                };
+
+                let mut quantized = quantized.into_iter();
+                let mut border = self.border.chunks_exact(pixel_size * channel_count);
+                let mut decoded = Vec::with_capacity(value_count * pixel_size);
+
+                for y in 0..self.texture_size {
+                    for x in 0..self.texture_size {
+                        if is_border(x, y, self.texture_size, border_width) {
+                            decoded.extend_from_slice(border.next().ok_or_else(corrupted)?);
+                            continue;
+                        }
+
+                        for channel in 0..channel_count {
+                            let value =
+                                offset + quantized.next().ok_or_else(corrupted)? as f32 * scale;
+
+                            match self.format {
+                                AttachmentFormat::R32F => {
+                                    decoded.extend_from_slice(&value.to_le_bytes())
+                                }
+                                format => decoded.extend_from_slice(
+                                    &(round_channel(
+                                        format,
+                                        channel,
+                                        value.clamp(0.0, 1.0) * u16::MAX as f32,
+                                    ) as u16)
+                                        .to_le_bytes(),
+                                ),
+                            }
+                        }
+                    }
+                }
+
+                if quantized.next().is_some() || border.next().is_some() {
+                    return Err(corrupted());
+                }
+
+                decoded
+            }
+        };
+
+        if decoded.len() != value_count * pixel_size {
+            return Err(corrupted());
+        }
+
+        let mip_size = |mip_level: u32| {
+            ((self.texture_size >> mip_level).pow(2) as usize) * pixel_size * channel_count
+        };
+        decoded.resize((0..self.mip_level_count).map(mip_size).sum(), 0);
+        generate_mipmaps(
+            &mut decoded,
+            self.texture_size,
+            pixel_size as u32,
+            channel_count as u32,
+            self.mip_level_count,
+        );
+
+        if let AttachmentFormat::Rgb8 = self.format {
+            decoded = decoded
+                .chunks_exact(3)
+                .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], u8::MAX])
+                .collect();
+        }
+
+        Ok(decoded)
+    }
+
+    /// Decodes the attachment into an image, including all mip levels.
+    ///
+    /// The format of the image has to be set according to the attachment afterwards.
+    pub fn to_image(&self) -> Result<Image> {
+        Ok(Image {
+            data: self.decode()?,
+            texture_descriptor: TextureDescriptor {
+                label: None,
+                size: Extent3d {
+                    width: self.texture_size,
+                    height: self.texture_size,
+                    ..default()
+                },
+                mip_level_count: self.mip_level_count,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::R8Unorm,
+                usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                view_formats: &[],
+            },
+            sampler_descriptor: Default::default(),
+            texture_view_descriptor: None,
+        })
+    }
+}
+
+/// The header preceding the compressed attachments.
+#[derive(Encode, Decode, Debug)]
+struct TNFHeader {
+    magic: [u8; 4],
+    version: u32,
+}
+
+/// All attachments of a node.
+#[derive(Encode, Decode, Clone, Debug, Default)]
+pub struct TNF {
+    pub attachments: Vec<TNFAttachment>,
+}
+
+impl TNF {
+    /// Returns the attachment with the name.
+    pub fn attachment(&self, name: &str) -> Option<&TNFAttachment> {
+        self.attachments
+            .iter()
+            .find(|attachment| attachment.name == name)
+    }
+
+    pub fn decode_alloc(encoded: &[u8]) -> Result<Self> {
+        let config = config::standard();
+        let (header, header_size): (TNFHeader, _) = bincode::decode_from_slice(encoded, config)?;
+
+        if header.magic != TNF_MAGIC {
+            return Err(anyhow!("The file is not a terrain node file."));
+        }
+
+        if header.version != TNF_VERSION {
+            return Err(anyhow!(
+                "The node file version {} is not supported.",
+                header.version
+            ));
+        }
+
+        let body = zstd::decode_all(&encoded[header_size..])?;
+        let decoded = bincode::decode_from_slice(&body, config)?;
+        Ok(decoded.0)
+    }
+
+    pub fn encode_alloc(&self) -> Result<Vec<u8>> {
+        let config = config::standard();
+        let header = TNFHeader {
+            magic: TNF_MAGIC,
+            version: TNF_VERSION,
+        };
+
+        let mut encoded = bincode::encode_to_vec(header, config)?;
+        let body = bincode::encode_to_vec(self, config)?;
+        encoded.extend(zstd::encode_all(body.as_slice(), COMPRESSION_LEVEL)?);
+        Ok(encoded)
+    }
+
+    pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let encoded = fs::read(path)?;
+        Self::decode_alloc(&encoded)
+    }
+
+    pub fn save_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let encoded = self.encode_alloc()?;
+        fs::write(path, encoded)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,694 @@
+//! The Terrain Save Format (TSF), a versioned container for the runtime-modified node data.
+//!
+//! A save starts with a header, which identifies the file and the version of its layout,
+//! followed by the configuration of the saved terrain, the table of the saved attachments
+//! and one chunk per saved attachment of each node.
+//! The chunks store either the pixel data of the first mip level, optionally compressed with
+//! the lossless encoders of the [`TDF`], or the sparse differences to the base data of the node.
+//! These deltas only cover the dirty rectangles of the node, whose pixels have been modified,
+//! which keeps local edits compact, e.g. for synchronizing them over the network.
+//! With the lossless compression, the modified pixels of the rectangles are encoded
+//! like the full data, whenever this is smaller than their differences.
+//!
+//! Saves of other versions are rejected, when they are decoded.
+
+use crate::{
+    formats::tdf::TDF,
+    terrain_data::{AttachmentFormat, NodeId},
+};
+use anyhow::{anyhow, Result};
+use bincode::{config, Decode, Encode};
+use dtm::DTM;
+use rapid_qoi::{Colors, Qoi};
+use std::{fs, path::Path};
+
+/// The magic bytes, which identify a save file.
+const TSF_MAGIC: [u8; 4] = *b"TSF\0";
+
+/// The version of the current layout of the save files.
+pub const TSF_VERSION: u32 = 1;
+
+/// The size (in pixels) of the blocks, which make up the dirty rectangles of the deltas.
+const DIRTY_BLOCK_SIZE: u32 = 16;
+
+/// Identifies the save file and the version of its layout.
+#[derive(Encode, Decode, Debug)]
+struct TSFHeader {
+    magic: [u8; 4],
+    version: u32,
+}
+
+/// The compression of the pixel data of a chunk.
+#[derive(Encode, Decode, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TSFCompression {
+    /// The pixel data is stored as is.
+    None,
+    /// The pixel data is compressed losslessly with the QOI or DTM encoder.
+    ///
+    /// Attachments, which are not supported by the encoders, are stored uncompressed.
+    #[default]
+    Lossless,
+}
+
+/// An attachment of the saved terrain.
+///
+/// The attachments are matched by name, when the save is restored,
+/// so that the order of the attachments of the terrain may change.
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct TSFAttachment {
+    pub name: String,
+    pub texture_size: u32,
+    pub format: AttachmentFormat,
+}
+
+/// Records the changes between two byte slices of the same length,
+/// as the run-length encoded XOR difference.
+///
+/// Returns pairs of the amount of unchanged bytes and the amount of following changed bytes,
+/// as well as the XOR differences of the changed bytes.
+pub(crate) fn xor_runs(before: &[u8], after: &[u8]) -> (Vec<(u32, u32)>, Vec<u8>) {
+    let mut runs = Vec::new();
+    let mut changes = Vec::new();
+    let mut index = 0;
+
+    while index < before.len() {
+        let start = index;
+
+        while index < before.len() && before[index] == after[index] {
+            index += 1;
+        }
+
+        let unchanged = index - start;
+        let start = index;
+
+        while index < before.len() && before[index] != after[index] {
+            changes.push(before[index] ^ after[index]);
+            index += 1;
+        }
+
+        runs.push((unchanged as u32, (index - start) as u32));
+    }
+
+    (runs, changes)
+}
+
+/// Toggles the run-length encoded XOR difference in the data.
+///
+/// Because the XOR difference is its own inverse, this either applies or reverts the changes.
+pub(crate) fn toggle_xor_runs(data: &mut [u8], runs: &[(u32, u32)], changes: &[u8]) {
+    let mut index = 0;
+    let mut changes = changes.iter();
+
+    for &(unchanged, changed) in runs {
+        index += unchanged as usize;
+
+        for (byte, change) in data[index..index + changed as usize]
+            .iter_mut()
+            .zip(&mut changes)
+        {
+            *byte ^= change;
+        }
+
+        index += changed as usize;
+    }
+}
+
+/// Encodes the pixels of a rectangle losslessly with the QOI or DTM encoder.
+///
+/// Returns `None`, if the pixel layout is not supported by the encoders.
+fn encode_pixels(
+    width: u32,
+    height: u32,
+    pixel_layout: (usize, usize),
+    decoded: &[u8],
+) -> Option<Vec<u8>> {
+    match pixel_layout {
+        (1, channel_count @ (3 | 4)) => {
+            let colors = if channel_count == 3 {
+                Colors::Rgb
+            } else {
+                Colors::Rgba
+            };
+
+            Qoi {
+                width,
+                height,
+                colors,
+            }
+            .encode_alloc(decoded)
+            .ok()
+        }
+        (2, channel_count) => DTM {
+            pixel_size: 2,
+            channel_count: channel_count as u32,
+            width,
+            height,
+        }
+        .encode_alloc(decoded)
+        .ok(),
+        _ => None,
+    }
+}
+
+/// Decodes the pixels of a rectangle, which have been encoded with [`encode_pixels`].
+fn decode_pixels(pixel_layout: (usize, usize), encoded: &[u8], decoded: &mut [u8]) -> Result<()> {
+    match pixel_layout {
+        (1, _) => {
+            Qoi::decode(encoded, decoded)?;
+        }
+        (2, _) => {
+            DTM::decode(encoded, decoded)?;
+        }
+        _ => return Err(anyhow!("The pixel layout can not be decoded.")),
+    }
+
+    Ok(())
+}
+
+/// The saved pixels of a dirty rectangle.
+#[derive(Encode, Decode, Clone, Debug)]
+pub enum TSFRectData {
+    /// The XOR differences of the pixels to the base data.
+    Changes {
+        /// Pairs of the amount of unchanged bytes and the amount of following changed bytes,
+        /// for the pixels of the rectangle row by row.
+        runs: Vec<(u32, u32)>,
+        /// The XOR differences of the changed bytes.
+        changes: Vec<u8>,
+    },
+    /// The (compressed) modified pixels of the rectangle row by row,
+    /// which replace the base data.
+    Pixels {
+        compression: TSFCompression,
+        data: Vec<u8>,
+    },
+}
+
+/// A rectangle of pixels of a node attachment, which differ from the base data.
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct TSFDirtyRect {
+    /// The first pixel of the rectangle.
+    pub first: [u32; 2],
+    /// The last pixel of the rectangle (inclusive).
+    pub last: [u32; 2],
+    pub data: TSFRectData,
+}
+
+/// Splits the byte offsets of the pixel rows of the rectangle of a texture.
+fn rect_rows(
+    texture_size: u32,
+    pixel_bytes: usize,
+    first: [u32; 2],
+    last: [u32; 2],
+) -> impl Iterator<Item = std::ops::Range<usize>> {
+    let row_bytes = pixel_bytes * (last[0] - first[0] + 1) as usize;
+
+    (first[1]..=last[1]).map(move |y| {
+        let start = pixel_bytes * (y * texture_size + first[0]) as usize;
+        start..start + row_bytes
+    })
+}
+
+/// The saved data of one attachment of a node.
+#[derive(Encode, Decode, Clone, Debug)]
+pub enum TSFChunkData {
+    /// The (compressed) pixel data of the first mip level.
+    Full {
+        compression: TSFCompression,
+        data: Vec<u8>,
+    },
+    /// The differences of the pixel data of the first mip level to the base data of the node.
+    Delta { rects: Vec<TSFDirtyRect> },
+}
+
+impl TSFChunkData {
+    /// Records the differences between the base and the modified pixel data of the first
+    /// mip level of a node attachment.
+    ///
+    /// The texture is split into blocks and the consecutive modified blocks of each row
+    /// of blocks are merged into one dirty rectangle.
+    /// With the lossless compression, each rectangle stores its encoded pixels instead,
+    /// if they are smaller than the differences.
+    pub fn delta(
+        before: &[u8],
+        after: &[u8],
+        texture_size: u32,
+        pixel_layout: (usize, usize),
+        compression: TSFCompression,
+    ) -> Self {
+        let pixel_bytes = pixel_layout.0 * pixel_layout.1;
+        let block_count = (texture_size + DIRTY_BLOCK_SIZE - 1) / DIRTY_BLOCK_SIZE;
+        let block_rect = |x: u32, y: u32| {
+            let first = [x * DIRTY_BLOCK_SIZE, y * DIRTY_BLOCK_SIZE];
+            let last = [
+                (first[0] + DIRTY_BLOCK_SIZE).min(texture_size) - 1,
+                (first[1] + DIRTY_BLOCK_SIZE).min(texture_size) - 1,
+            ];
+
+            (first, last)
+        };
+
+        let is_dirty = |x: u32, y: u32| {
+            let (first, last) = block_rect(x, y);
+
+            rect_rows(texture_size, pixel_bytes, first, last)
+                .any(|row| before[row.clone()] != after[row])
+        };
+
+        let mut rects = Vec::new();
+
+        for y in 0..block_count {
+            let mut x = 0;
+
+            while x < block_count {
+                if !is_dirty(x, y) {
+                    x += 1;
+                    continue;
+                }
+
+                let start = x;
+
+                while x < block_count && is_dirty(x, y) {
+                    x += 1;
+                }
+
+                let (first, _) = block_rect(start, y);
+                let (_, last) = block_rect(x - 1, y);
+
+                let (before, after): (Vec<u8>, Vec<u8>) =
+                    rect_rows(texture_size, pixel_bytes, first, last)
+                        .flat_map(|row| before[row.clone()].iter().zip(&after[row]))
+                        .unzip();
+
+                let (runs, changes) = xor_runs(&before, &after);
+                let changes_size = changes.len() + 8 * runs.len();
+
+                let encoded = match compression {
+                    TSFCompression::Lossless => encode_pixels(
+                        last[0] - first[0] + 1,
+                        last[1] - first[1] + 1,
+                        pixel_layout,
+                        &after,
+                    )
+                    .filter(|encoded| encoded.len() < changes_size),
+                    TSFCompression::None => None,
+                };
+
+                let data = match encoded {
+                    Some(data) => TSFRectData::Pixels {
+                        compression: TSFCompression::Lossless,
+                        data,
+                    },
+                    None => TSFRectData::Changes { runs, changes },
+                };
+
+                rects.push(TSFDirtyRect { first, last, data });
+            }
+        }
+
+        Self::Delta { rects }
+    }
+}
+
+impl TSFDirtyRect {
+    /// The amount of pixels of the rectangle.
+    ///
+    /// Fails if the last pixel lies before the first one or the amount overflows.
+    pub fn size(&self) -> Result<usize> {
+        let extent = |axis: usize| {
+            self.last[axis]
+                .checked_sub(self.first[axis])?
+                .checked_add(1)
+        };
+
+        extent(0)
+            .zip(extent(1))
+            .and_then(|(width, height)| width.checked_mul(height))
+            .and_then(|size| usize::try_from(size).ok())
+            .ok_or_else(|| anyhow!("The dirty rectangle has an invalid size."))
+    }
+
+    /// Decompresses the pixels of the rectangle, so that it can be applied quickly.
+    pub fn decode(&self, pixel_layout: (usize, usize)) -> Result<Self> {
+        let data = match &self.data {
+            TSFRectData::Pixels {
+                compression: TSFCompression::Lossless,
+                data,
+            } => {
+                let bytes = (pixel_layout.0 * pixel_layout.1)
+                    .checked_mul(self.size()?)
+                    .ok_or_else(|| anyhow!("The dirty rectangle has an invalid size."))?;
+
+                let mut decoded = vec![0; bytes];
+                decode_pixels(pixel_layout, data, &mut decoded)?;
+
+                TSFRectData::Pixels {
+                    compression: TSFCompression::None,
+                    data: decoded,
+                }
+            }
+            data => data.clone(),
+        };
+
+        Ok(Self {
+            first: self.first,
+            last: self.last,
+            data,
+        })
+    }
+
+    /// Applies the differences of the rectangle to the base pixel data of the first mip level.
+    ///
+    /// The pixels of the rectangle have to be decoded beforehand.
+    pub fn apply(&self, data: &mut [u8], texture_size: u32, pixel_bytes: usize) {
+        let rows = rect_rows(texture_size, pixel_bytes, self.first, self.last).collect::<Vec<_>>();
+
+        let region = match &self.data {
+            TSFRectData::Changes { runs, changes } => {
+                let mut region = rows
+                    .iter()
+                    .flat_map(|row| data[row.clone()].iter().copied())
+                    .collect::<Vec<_>>();
+
+                toggle_xor_runs(&mut region, runs, changes);
+                region
+            }
+            TSFRectData::Pixels {
+                compression: TSFCompression::None,
+                data,
+            } => data.clone(),
+            TSFRectData::Pixels { .. } => return,
+        };
+
+        let mut region = region.as_slice();
+
+        for row in rows {
+            let (row_data, rest) = region.split_at(row.len());
+            data[row].copy_from_slice(row_data);
+            region = rest;
+        }
+    }
+}
+
+/// The saved data of one attachment of a node.
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct TSFChunk {
+    pub node_id: NodeId,
+    /// The index of the attachment in the attachment table of the save.
+    pub attachment: u32,
+    pub data: TSFChunkData,
+}
+
+impl TSFChunk {
+    /// Creates a new chunk from the pixel data of the first mip level of the node attachment.
+    pub fn new(
+        node_id: NodeId,
+        attachment: u32,
+        texture_size: u32,
+        pixel_layout: (usize, usize),
+        compression: TSFCompression,
+        decoded: &[u8],
+    ) -> Self {
+        let (pixel_size, channel_count) = pixel_layout;
+
+        let descriptor = TDF {
+            pixel_size: pixel_size as u32,
+            channel_count: channel_count as u32,
+            mip_level_count: 1,
+            size: texture_size,
+        };
+
+        let (compression, data) = match compression {
+            TSFCompression::Lossless => match descriptor.encode_alloc(decoded) {
+                Ok(encoded) => (TSFCompression::Lossless, encoded),
+                Err(_) => (TSFCompression::None, decoded.to_vec()),
+            },
+            TSFCompression::None => (TSFCompression::None, decoded.to_vec()),
+        };
+
+        Self {
+            node_id,
+            attachment,
+            data: TSFChunkData::Full { compression, data },
+        }
+    }
+
+    /// Decodes the pixel data of the first mip level, if the chunk stores the full data.
+    pub fn decode(&self) -> Result<Option<Vec<u8>>> {
+        match &self.data {
+            TSFChunkData::Full {
+                compression: TSFCompression::None,
+                data,
+            } => Ok(Some(data.clone())),
+            TSFChunkData::Full {
+                compression: TSFCompression::Lossless,
+                data,
+            } => Ok(Some(TDF::decode_alloc(data, false)?.1)),
+            TSFChunkData::Delta { .. } => Ok(None),
+        }
+    }
+}
+
+/// A save of the modified node data of a terrain.
+#[derive(Encode, Decode, Debug)]
+pub struct TSF {
+    pub lod_count: u32,
+    pub leaf_node_size: u32,
+    /// The maximum height of the terrain, which the height data is normalized with.
+    pub height: f32,
+    pub attachments: Vec<TSFAttachment>,
+    pub chunks: Vec<TSFChunk>,
+}
+
+impl TSF {
+    pub fn decode_alloc(encoded: &[u8]) -> Result<Self> {
+        let config = config::standard();
+        let (header, offset): (TSFHeader, _) = bincode::decode_from_slice(encoded, config)?;
+
+        if header.magic != TSF_MAGIC {
+            return Err(anyhow!("The data is not a terrain save."));
+        }
+
+        let body = &encoded[offset..];
+
+        let save = match header.version {
+            TSF_VERSION => bincode::decode_from_slice(body, config)?.0,
+            version => {
+                return Err(anyhow!(
+                    "The version {version} of the terrain save is not supported."
+                ))
+            }
+        };
+
+        Ok(save)
+    }
+
+    pub fn encode_alloc(&self) -> Result<Vec<u8>> {
+        let config = config::standard();
+        let header = TSFHeader {
+            magic: TSF_MAGIC,
+            version: TSF_VERSION,
+        };
+
+        let mut encoded = bincode::encode_to_vec(header, config)?;
+        encoded.extend(bincode::encode_to_vec(self, config)?);
+        Ok(encoded)
+    }
+
+    pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let encoded = fs::read(path)?;
+        Self::decode_alloc(&encoded)
+    }
+
+    pub fn save_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let encoded = self.encode_alloc()?;
+        fs::write(path, encoded)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates the pixel data of a texture with a distinct value per byte.
+    fn texture(texture_size: u32, pixel_bytes: usize) -> Vec<u8> {
+        (0..texture_size * texture_size * pixel_bytes as u32)
+            .map(|index| (index * 7 % 251) as u8)
+            .collect()
+    }
+
+    /// Modifies the pixels of the square inside of the texture.
+    fn modify(data: &mut [u8], texture_size: u32, pixel_bytes: usize, first: u32, last: u32) {
+        for (x, y) in itertools::iproduct!(first..=last, first..=last) {
+            let start = pixel_bytes * (y * texture_size + x) as usize;
+
+            for byte in &mut data[start..start + pixel_bytes] {
+                *byte = byte.wrapping_add(101);
+            }
+        }
+    }
+
+    #[test]
+    fn xor_runs_round_trip() {
+        let before = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let after = [1, 2, 0, 0, 5, 6, 7, 9, 9];
+
+        let (runs, changes) = xor_runs(&before, &after);
+
+        assert_eq!(runs, vec![(2, 2), (3, 1), (1, 0)]);
+        assert_eq!(changes, vec![3, 4, 1]);
+
+        let mut data = before;
+        toggle_xor_runs(&mut data, &runs, &changes);
+        assert_eq!(data, after);
+
+        toggle_xor_runs(&mut data, &runs, &changes);
+        assert_eq!(data, before);
+
+        let (runs, changes) = xor_runs(&before, &before);
+
+        assert_eq!(runs, vec![(9, 0)]);
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let texture_size = 40;
+
+        for (pixel_layout, compression) in [
+            ((1, 4), TSFCompression::None),
+            ((1, 4), TSFCompression::Lossless),
+            ((2, 1), TSFCompression::None),
+            ((2, 1), TSFCompression::Lossless),
+        ] {
+            let pixel_bytes = pixel_layout.0 * pixel_layout.1;
+            let before = texture(texture_size, pixel_bytes);
+            let mut after = before.clone();
+
+            // the first square spans two blocks of the first row, the second one lies
+            // in the smaller block of the last row and column
+            modify(&mut after, texture_size, pixel_bytes, 10, 20);
+            modify(&mut after, texture_size, pixel_bytes, 36, 38);
+
+            let TSFChunkData::Delta { rects } =
+                TSFChunkData::delta(&before, &after, texture_size, pixel_layout, compression)
+            else {
+                panic!("The delta has to store dirty rectangles.");
+            };
+
+            let bounds = rects
+                .iter()
+                .map(|rect| (rect.first, rect.last))
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                bounds,
+                vec![
+                    ([0, 0], [31, 15]),
+                    ([0, 16], [31, 31]),
+                    ([32, 32], [39, 39]),
+                ]
+            );
+
+            let mut data = before.clone();
+
+            for rect in &rects {
+                rect.decode(pixel_layout)
+                    .unwrap()
+                    .apply(&mut data, texture_size, pixel_bytes);
+            }
+
+            assert_eq!(data, after);
+        }
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let texture_size = 32;
+        let pixel_layout = (2, 1);
+        let before = texture(texture_size, 2);
+        let mut after = before.clone();
+        modify(&mut after, texture_size, 2, 3, 7);
+
+        let save = TSF {
+            lod_count: 4,
+            leaf_node_size: 32,
+            height: 500.0,
+            attachments: vec![TSFAttachment {
+                name: "height".to_string(),
+                texture_size,
+                format: AttachmentFormat::R16,
+            }],
+            chunks: vec![
+                TSFChunk::new(
+                    3,
+                    0,
+                    texture_size,
+                    pixel_layout,
+                    TSFCompression::Lossless,
+                    &after,
+                ),
+                TSFChunk {
+                    node_id: 5,
+                    attachment: 0,
+                    data: TSFChunkData::delta(
+                        &before,
+                        &after,
+                        texture_size,
+                        pixel_layout,
+                        TSFCompression::None,
+                    ),
+                },
+            ],
+        };
+
+        let encoded = save.encode_alloc().unwrap();
+        let decoded = TSF::decode_alloc(&encoded).unwrap();
+
+        assert_eq!(decoded.lod_count, 4);
+        assert_eq!(decoded.leaf_node_size, 32);
+        assert_eq!(decoded.height, 500.0);
+        assert_eq!(decoded.attachments.len(), 1);
+        assert_eq!(decoded.attachments[0].name, "height");
+        assert_eq!(decoded.attachments[0].texture_size, texture_size);
+        assert_eq!(decoded.chunks.len(), 2);
+
+        assert_eq!(decoded.chunks[0].node_id, 3);
+        assert_eq!(decoded.chunks[0].decode().unwrap(), Some(after.clone()));
+
+        assert_eq!(decoded.chunks[1].node_id, 5);
+        let TSFChunkData::Delta { rects } = &decoded.chunks[1].data else {
+            panic!("The delta has to be preserved.");
+        };
+
+        let mut data = before;
+
+        for rect in rects {
+            rect.apply(&mut data, texture_size, 2);
+        }
+
+        assert_eq!(data, after);
+    }
+
+    #[test]
+    fn save_rejects_other_versions() {
+        let save = TSF {
+            lod_count: 1,
+            leaf_node_size: 32,
+            height: 1.0,
+            attachments: Vec::new(),
+            chunks: Vec::new(),
+        };
+
+        let mut encoded = save.encode_alloc().unwrap();
+        assert!(TSF::decode_alloc(&encoded).is_ok());
+
+        // the version directly follows the magic bytes
+        encoded[TSF_MAGIC.len()] = TSF_VERSION as u8 + 1;
+        assert!(TSF::decode_alloc(&encoded).is_err());
+
+        encoded[0] = b'X';
+        assert!(TSF::decode_alloc(&encoded).is_err());
+    }
+}
@@ -0,0 +1,26 @@
+//! Punches holes into the terrain, e.g. to connect caves, mines and basements to the surface.
+//!
+//! The hole attachment stores a mask in its first channel, where one marks a hole.
+//! Once it is configured as the [`TerrainPlugin::hole_attachment`](crate::TerrainPlugin),
+//! the shared fragment entry point discards the fragments of all materials inside the holes.
+//! The shadow cascades discard them with the shared depth entry point as well, so that the holes
+//! let the light through.
+//! The material shader must not bind the hole attachment itself.
+//! Terrain colliders punch matching holes, if their hole attachment is set with
+//! [`TerrainCollider::with_holes`](crate::collision::TerrainCollider::with_holes).
+
+use crate::terrain_data::{AttachmentConfig, AttachmentFormat};
+
+/// The mask value, above which a pixel of the hole attachment is cut out of the terrain.
+pub(crate) const HOLE_THRESHOLD: f32 = 0.5;
+
+/// Creates the config of the hole attachment.
+pub fn hole_attachment(texture_size: u32, mip_level_count: u32) -> AttachmentConfig {
+    AttachmentConfig::new(
+        "hole".to_string(),
+        texture_size,
+        1,
+        mip_level_count,
+        AttachmentFormat::R16,
+    )
+}
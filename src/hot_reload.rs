@@ -0,0 +1,120 @@
+//! Hot reloads the nodes loaded from disk, once their files are modified.
+//!
+//! The directories of the attachments of each [`AttachmentFromDiskLoader`] are watched for changes.
+//! Modified nodes are loaded again in place, which re-uploads their region of the atlas and
+//! replaces their cpu accessible data. Nodes, whose files have been removed, are invalidated
+//! instead, so that their stale data is discarded and the terrain falls back to their ancestors,
+//! until they are loaded again.
+//! Requires the `hot_reload` feature and the file system IO of the asset server.
+
+use crate::{
+    attachment_loader::AttachmentFromDiskLoader,
+    terrain_data::{node_atlas::NodeAtlas, NodeId},
+};
+use bevy::{asset::FileAssetIo, prelude::*, utils::HashSet};
+use crossbeam_channel::Receiver;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{path::Path, sync::Mutex};
+
+/// Watches the node files of the attachments of a terrain, that are loaded from disk.
+#[derive(Component)]
+pub(crate) struct NodeWatcher {
+    receiver: Receiver<notify::Result<notify::Event>>,
+    // the files are only watched, while the watcher is alive
+    _watcher: Mutex<RecommendedWatcher>,
+}
+
+/// Parses the id of the node from the path of one of its files (e.g. `data/height/1234.tdf`).
+fn node_id_from_path(path: &Path) -> Option<NodeId> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Starts watching the node files of the newly added [`AttachmentFromDiskLoader`]s.
+pub(crate) fn watch_attachments_from_disk(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    loader_query: Query<(Entity, &AttachmentFromDiskLoader), Added<AttachmentFromDiskLoader>>,
+) {
+    for (terrain, loader) in loader_query.iter() {
+        let Some(asset_io) = asset_server.asset_io().downcast_ref::<FileAssetIo>() else {
+            warn!("The node files can only be watched, if they are loaded from the file system.");
+            continue;
+        };
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        let mut watcher = match notify::recommended_watcher(move |event| {
+            // the terrain might have been despawned in the meantime
+            let _ = sender.send(event);
+        }) {
+            Ok(watcher) => watcher,
+            Err(error) => {
+                warn!("Failed to watch the node files: {error}");
+                continue;
+            }
+        };
+
+        for attachment in loader.attachments.values() {
+            let directory = asset_io.root_path().join(&attachment.path);
+
+            if let Err(error) = watcher.watch(&directory, RecursiveMode::NonRecursive) {
+                warn!("Failed to watch the node files in {directory:?}: {error}");
+            }
+        }
+
+        commands.entity(terrain).insert(NodeWatcher {
+            receiver,
+            _watcher: Mutex::new(watcher),
+        });
+    }
+}
+
+/// Reloads the nodes, whose files have been created or modified since the last frame,
+/// and invalidates the nodes, whose files have been removed.
+pub(crate) fn reload_modified_nodes(mut terrain_query: Query<(&mut NodeAtlas, &NodeWatcher)>) {
+    for (mut node_atlas, watcher) in terrain_query.iter_mut() {
+        // a single write usually causes multiple events
+        let mut modified_nodes = HashSet::new();
+        let mut removed_nodes = HashSet::new();
+
+        for event in watcher.receiver.try_iter() {
+            let event = match event {
+                Ok(event) => event,
+                Err(error) => {
+                    warn!("Failed to watch the node files: {error}");
+                    continue;
+                }
+            };
+
+            let node_ids = event
+                .paths
+                .iter()
+                .filter_map(|path| node_id_from_path(path));
+
+            // the last event of a node decides, whether it is reloaded or invalidated
+            match event.kind {
+                EventKind::Create(_) | EventKind::Modify(_) => {
+                    for node_id in node_ids {
+                        removed_nodes.remove(&node_id);
+                        modified_nodes.insert(node_id);
+                    }
+                }
+                EventKind::Remove(_) => {
+                    for node_id in node_ids {
+                        modified_nodes.remove(&node_id);
+                        removed_nodes.insert(node_id);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for node_id in removed_nodes {
+            node_atlas.invalidate_node(node_id);
+        }
+
+        for node_id in modified_nodes {
+            node_atlas.reload_node(node_id);
+        }
+    }
+}
@@ -48,67 +48,221 @@ extern crate core;
 use crate::{
     attachment_loader::{finish_loading_attachment_from_disk, start_loading_attachment_from_disk},
     debug::DebugTerrain,
+    edit::{
+        apply_terrain_edits,
+        export::{export_heightmaps, ExportHeightmap},
+        history::{apply_terrain_history, RedoTerrainEdit, UndoTerrainEdit},
+        paint::{send_paint_events, NodePainted},
+        replication::apply_replicated_commands,
+        save::{load_terrain_saves, save_terrains, LoadTerrainSave, SaveTerrain},
+        EditTerrain,
+    },
     formats::TDFPlugin,
+    node_source::{finish_loading_attachment_from_source, start_loading_attachment_from_source},
+    origin::{shift_origin, ShiftOrigin, WorldOrigin},
     render::{
         compute_pipelines::{
             queue_terrain_compute_pipelines, TerrainComputeNode, TerrainComputePipelines,
         },
         culling::{prepare_and_queue_terrain_culling_bind_group, CullingBindGroup},
+        decals::{extract_terrain_decals, prepare_terrain_decals, DecalFallback, GpuTerrainDecals},
+        depth_pyramid::{
+            prepare_depth_pyramids, queue_depth_pyramids, DepthPyramidNode, DepthPyramidPipelines,
+            DepthPyramids,
+        },
+        detail_layer::{
+            extract_terrain_detail_layers, prepare_terrain_detail_layers, update_detail_layers,
+            DetailLayerFallback, GpuTerrainDetailLayer,
+        },
+        node_generator::{
+            extract_node_generator, finish_loading_attachment_from_gpu,
+            initialize_gpu_node_generator, prepare_node_generator, queue_node_generator_pipelines,
+            report_generated_nodes, start_loading_attachment_from_gpu, GpuNodeGenerator,
+            NodeGeneratorNode, NodeGeneratorPipelines,
+        },
+        normals::{
+            extract_normal_recomputation, initialize_gpu_normal_recomputation,
+            prepare_normal_recomputation, GpuNormalRecomputation, NormalRecomputationNode,
+            NormalRecomputationPipeline,
+        },
         render_pipeline::TerrainPipelineConfig,
         shaders::add_shader,
-        terrain_data::{initialize_terrain_data, TerrainData},
+        shadows::{
+            extract_terrain_shadows, prepare_terrain_shadows, queue_terrain_shadow_culling,
+            TerrainShadowData, TerrainShadowViews,
+        },
+        terrain_data::{
+            initialize_terrain_data, max_atlas_shard_count, sampled_texture_count, TerrainData,
+        },
         terrain_view_data::{
             extract_terrain_view_config, initialize_terrain_view_data, prepare_terrain_view_config,
             TerrainViewConfigUniform, TerrainViewData,
         },
     },
-    terrain::{Terrain, TerrainComponents, TerrainConfig},
+    terrain::{
+        SeamGeometry, Terrain, TerrainComponents, TerrainConfig, TerrainGeometry, TerrainTile,
+    },
     terrain_data::{
         gpu_node_atlas::{
-            extract_node_atlas, initialize_gpu_node_atlas, prepare_node_atlas, GpuNodeAtlas,
+            extract_node_atlas, initialize_gpu_node_atlas, node_atlas_limit, prepare_node_atlas,
+            GpuNodeAtlas,
         },
         gpu_quadtree::{extract_quadtree, initialize_gpu_quadtree, prepare_quadtree, GpuQuadtree},
-        node_atlas::{update_node_atlas, NodeAtlas},
+        node_atlas::{
+            clear_node_atlas_events, initialize_scene_terrains, update_node_atlas, NodeAtlas,
+            NodeAtlasLimit, StreamingState,
+        },
+        node_events::{send_node_events, NodeActivated, NodeDeactivated, NodeLoaded, NodeQueued},
         quadtree::{
-            adjust_quadtree, compute_quadtree_request, update_height_under_viewer, Quadtree,
+            adjust_quadtree, compute_quadtree_request, remove_terrain_views, stitch_quadtrees,
+            update_height_under_viewer, Quadtree,
         },
+        AtlasAttachment, AttachmentConfig, AttachmentFormat, AttachmentIndex, FileFormat, NodeId,
+        MAX_ATLAS_SHARDS,
     },
-    terrain_view::{TerrainView, TerrainViewComponents, TerrainViewConfig},
+    terrain_grid::{update_terrain_grid, TerrainGrid},
+    terrain_view::{LodMetric, TerrainView, TerrainViewComponents, TerrainViewConfig},
 };
 use bevy::{
+    ecs::schedule::ScheduleLabel,
     prelude::*,
     render::{
         extract_component::ExtractComponentPlugin, main_graph::node::CAMERA_DRIVER,
-        render_graph::RenderGraph, render_resource::*, view::NoFrustumCulling, RenderApp,
-        RenderSet,
+        render_graph::RenderGraph, render_resource::*, renderer::RenderDevice,
+        view::NoFrustumCulling, RenderApp, RenderSet,
     },
+    utils::HashMap,
 };
 
+pub mod archive;
 pub mod attachment_loader;
+pub mod biome;
+pub mod collision;
+pub mod cost_grid;
 pub mod debug;
+pub mod diagnostics;
+pub mod edit;
+pub mod erosion;
 pub mod formats;
+pub mod holes;
+#[cfg(all(feature = "hot_reload", target_arch = "wasm32"))]
+compile_error!("The `hot_reload` feature is not supported on the web.");
+#[cfg(feature = "hot_reload")]
+pub mod hot_reload;
+#[cfg(all(feature = "mmap", target_arch = "wasm32"))]
+compile_error!("The `mmap` feature is not supported on the web.");
+#[cfg(feature = "mmap")]
+pub mod mapped_source;
+pub mod minimap;
+pub mod navigation;
+pub mod node_source;
+pub mod origin;
+pub mod picking;
+pub mod planet;
 pub mod preprocess;
+pub mod props;
+#[cfg(all(feature = "remote", target_arch = "wasm32"))]
+compile_error!("The `remote` feature is not supported on the web.");
+#[cfg(feature = "remote")]
+pub mod remote_loader;
 pub mod render;
+pub mod scatter;
+pub mod seeded;
+pub mod snow;
 pub mod terrain;
 pub mod terrain_data;
+pub mod terrain_grid;
 pub mod terrain_view;
+pub mod volume;
+pub mod water;
 
 pub mod prelude {
     //! `use bevy_terrain::prelude::*;` to import common components, bundles, and plugins.
     // #[doc(hidden)]
     pub use crate::{
-        attachment_loader::AttachmentFromDiskLoader,
+        archive::NodeArchive,
+        attachment_loader::{AttachmentFromDiskLoader, DiskSource, NodeDecoder},
+        biome::{
+            climate_attachment, Biome, BiomeId, BiomeMap, BiomeSampler, TerrainBiomePlugin,
+            TerrainBiomes,
+        },
+        collision::TerrainCollider,
+        cost_grid::{CostConfig, CostGrid, CostGridChanged, CostGridPlugin, LayerCost},
         debug::{camera::DebugCamera, TerrainDebugPlugin},
-        preprocess::{config::load_node_config, BaseConfig, Preprocessor, TileConfig},
-        render::render_pipeline::TerrainMaterialPlugin,
-        terrain::{Terrain, TerrainConfig},
+        diagnostics::TerrainDiagnosticsPlugin,
+        edit::{
+            brush::{Brush, BrushOperation, BrushShape},
+            crater::{deform_crater, Crater},
+            export::ExportHeightmap,
+            history::{RedoTerrainEdit, UndoTerrainEdit},
+            paint::{NodePainted, PaintBrush},
+            replication::{ReplicatedCommand, TerrainCommand, TerrainReplication},
+            river::{River, RiverProfile},
+            road::Road,
+            save::{LoadTerrainSave, SaveTerrain},
+            spline::TerrainSpline,
+            EditTerrain, TerrainEdit,
+        },
+        erosion::{
+            thermal::{ErodeTerrainThermally, ThermalErosion},
+            ErodeTerrain, HydraulicErosion, TerrainErosionPlugin,
+        },
+        formats::tsf::TSFCompression,
+        holes::hole_attachment,
+        minimap::{TerrainCapture, TerrainCapturePlugin, TerrainCaptured},
+        navigation::{
+            NavigationPatch, NavigationPatchChanged, TerrainNavigation, TerrainNavigationPlugin,
+        },
+        node_source::{
+            AttachmentFromSourceLoader, HeightFunction, ImageSource, MemorySource, NodeSource,
+            ProceduralSource,
+        },
+        origin::{ShiftOrigin, WorldOrigin},
+        picking::{TerrainCursor, TerrainPicker, TerrainPickingCamera, TerrainPickingPlugin},
+        planet::CubeFace,
+        preprocess::{
+            albedo::{albedo_attachment, ALBEDO_ATTACHMENT},
+            ambient_occlusion::{AmbientOcclusionConfig, AMBIENT_OCCLUSION_ATTACHMENT},
+            config::load_node_config,
+            horizon::{HorizonConfig, HORIZON_ATTACHMENT},
+            normal::{normal_attachment, NORMAL_ATTACHMENT},
+            BaseConfig, Preprocessor, TileConfig,
+        },
+        props::{PropCollider, PropKind, TerrainProps, TerrainPropsPlugin},
+        render::{
+            decals::{Decal, TerrainDecals},
+            detail_layer::{DetailStamp, TerrainDetailLayer},
+            node_generator::{default_generator, AttachmentFromGpuLoader},
+            normals::RecomputeNormals,
+            render_pipeline::TerrainMaterialPlugin,
+            splat_material::{SplatMaterial, TexturingRule, Triplanar},
+        },
+        scatter::{ScatterLayer, TerrainScatter, TerrainScatterPlugin},
+        seeded::SeededNoise,
+        snow::{snow_attachment, TerrainSnow, TerrainSnowPlugin},
+        terrain::{SeamGeometry, Terrain, TerrainConfig, TerrainGeometry, TerrainTile},
         terrain_data::{
-            node_atlas::NodeAtlas, quadtree::Quadtree, AttachmentConfig, AttachmentFormat,
-            FileFormat,
+            node_atlas::{NodeAtlas, NodeAtlasUsage, NodeData, StreamingState},
+            node_events::{NodeActivated, NodeDeactivated, NodeEvent, NodeLoaded, NodeQueued},
+            quadtree::Quadtree,
+            raycast::TerrainHit,
+            sampling::TerrainSampler,
+            statistics::{RegionStatistics, StatisticsRegion},
+            AttachmentConfig, AttachmentFormat, FileFormat,
         },
-        terrain_view::{TerrainView, TerrainViewComponents, TerrainViewConfig},
-        TerrainBundle, TerrainPlugin,
+        terrain_grid::TerrainGrid,
+        terrain_view::{LodMetric, TerrainView, TerrainViewComponents, TerrainViewConfig},
+        volume::{DensityFunction, TerrainVolumePlugin, TerrainVolumes, VolumetricRegion},
+        water::{water_attachment, TerrainWater, TerrainWaterPlugin},
+        TerrainBundle, TerrainPlugin, TerrainSchedule, TerrainScheduling, TerrainSystemSet,
     };
+
+    #[cfg(feature = "inspector")]
+    pub use crate::debug::inspector::TerrainInspectorPlugin;
+    #[cfg(feature = "mmap")]
+    pub use crate::mapped_source::{MappedHeightmap, MappedSource, RawFormat};
+    #[cfg(feature = "remote")]
+    pub use crate::remote_loader::{AttachmentFromUrlLoader, TileCache, TileServer, UrlSource};
 }
 
 /// The components of a terrain.
@@ -118,6 +272,7 @@ pub mod prelude {
 pub struct TerrainBundle {
     terrain: Terrain,
     node_atlas: NodeAtlas,
+    streaming_state: StreamingState,
     config: TerrainConfig,
     transform: Transform,
     global_transform: GlobalTransform,
@@ -132,6 +287,7 @@ impl TerrainBundle {
         Self {
             terrain: Terrain,
             node_atlas: NodeAtlas::from_config(&config),
+            streaming_state: StreamingState::Active,
             config,
             transform: default(),
             global_transform: default(),
@@ -141,81 +297,448 @@ impl TerrainBundle {
     }
 }
 
+/// The system sets of the terrain, which can be used to order custom systems relative to them.
+///
+/// The [`Traverse`](TerrainSystemSet::Traverse) set runs before the
+/// [`Update`](TerrainSystemSet::Update) set, in the schedule selected by the [`TerrainScheduling`].
+#[derive(SystemSet, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TerrainSystemSet {
+    /// Traverses the quadtrees of the views and determines the nodes they request.
+    Traverse,
+    /// Loads the requested nodes into the node atlases, adjusts the quadtrees to them,
+    /// and applies the edits of the terrain.
+    Update,
+    /// Extracts the terrain data and prepares it for rendering (in the render app).
+    Render,
+}
+
+/// A schedule containing the [`TerrainSystemSet::Traverse`] and [`TerrainSystemSet::Update`]
+/// sets, which has to be run manually (e.g. with `world.run_schedule(TerrainSchedule)`),
+/// if the [`TerrainScheduling::Manual`] is selected.
+#[derive(ScheduleLabel, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TerrainSchedule;
+
+/// Selects the schedule, which the streaming systems of the terrain are added to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TerrainScheduling {
+    /// The systems run in the [`CoreSet::Last`] of the main schedule.
+    #[default]
+    Last,
+    /// The systems run in the [`CoreSchedule::FixedUpdate`] schedule, e.g. to stay in sync
+    /// with the physics simulation.
+    FixedUpdate,
+    /// The systems run in the [`TerrainSchedule`], which is never run by the plugin itself.
+    ///
+    /// Run it before the [`CoreSet::Last`], in which the node events of the frame are sent.
+    Manual,
+}
+
 /// The plugin for the terrain renderer.
 pub struct TerrainPlugin {
     /// The number of terrain attachments.
     pub attachment_count: usize,
+    /// The index of the hole attachment, whose masked regions are cut out of the terrain.
+    /// It has to be one of the terrain attachments, which is not bound by the material shader.
+    pub hole_attachment: Option<AttachmentIndex>,
+    /// The index of the snow attachment (see [`TerrainSnow`](snow::TerrainSnow)), whose snow
+    /// raises and covers the surface of the terrain.
+    /// Like the hole attachment, it must not be bound by the material shader.
+    pub snow_attachment: Option<AttachmentIndex>,
+    /// The index of the normal attachment
+    /// (see [`normal_attachment`](preprocess::normal::normal_attachment)),
+    /// which the default material shades the surface with, instead of the normals derived
+    /// from the heights.
+    pub normal_attachment: Option<AttachmentIndex>,
+    /// The index of the albedo attachment
+    /// (see [`albedo_attachment`](preprocess::albedo::albedo_attachment)),
+    /// which the default material uses as the base color of the surface.
+    pub albedo_attachment: Option<AttachmentIndex>,
+    /// Whether the decals of the terrains (see [`TerrainDecals`](render::decals::TerrainDecals))
+    /// are projected onto their surface.
+    pub decals: bool,
+    /// Whether the detail layers of the terrains
+    /// (see [`TerrainDetailLayer`](render::detail_layer::TerrainDetailLayer))
+    /// are composited over their heights.
+    pub detail_layer: bool,
+    /// The amount of atlas indices per texture, once the attachments of the node atlases are
+    /// sharded across multiple textures (at most [`MAX_ATLAS_SHARDS`](terrain_data::MAX_ATLAS_SHARDS),
+    /// fewer if the device can not bind as many textures per shader stage).
+    ///
+    /// Sharding lifts the limit of the layer count of a single array texture
+    /// (`max_texture_array_layers`) from the size of the node atlas.
+    /// The materials have to sample the attachments through the shard of each node,
+    /// like the default material does.
+    pub atlas_shard_size: Option<u32>,
+    /// The schedule, which the streaming systems are added to.
+    pub scheduling: TerrainScheduling,
 }
 
 impl Default for TerrainPlugin {
     fn default() -> Self {
         Self {
             attachment_count: 2,
+            hole_attachment: None,
+            snow_attachment: None,
+            normal_attachment: None,
+            albedo_attachment: None,
+            decals: false,
+            detail_layer: false,
+            atlas_shard_size: None,
+            scheduling: default(),
         }
     }
 }
 
 impl Plugin for TerrainPlugin {
     fn build(&self, app: &mut App) {
-        add_shader(app);
+        // without a renderer the images only store the cpu side data of the nodes
+        #[cfg(feature = "headless")]
+        {
+            if !app.world.contains_resource::<AssetServer>() {
+                app.add_plugin(AssetPlugin::default());
+            }
+
+            app.add_asset::<Image>();
+        }
+
+        // the types are registered for scenes and reflection-based tools
+        app.register_type::<Terrain>()
+            .register_type::<TerrainTile>()
+            .register_type::<TerrainView>()
+            .register_type::<TerrainConfig>()
+            .register_type::<TerrainViewConfig>()
+            .register_type::<TerrainGeometry>()
+            .register_type::<SeamGeometry>()
+            .register_type::<LodMetric>()
+            .register_type::<AtlasAttachment>()
+            .register_type::<AttachmentConfig>()
+            .register_type::<AttachmentFormat>()
+            .register_type::<FileFormat>()
+            .register_type::<NodeAtlas>()
+            .register_type::<StreamingState>()
+            .register_type::<Quadtree>()
+            .register_type::<WorldOrigin>()
+            .register_type::<Vec<AtlasAttachment>>()
+            .register_type::<HashMap<NodeId, Vec2>>()
+            .register_type::<Option<u32>>()
+            .register_type::<Option<f32>>();
 
         app.add_plugin(TDFPlugin)
-            .add_plugin(ExtractComponentPlugin::<Terrain>::default())
-            .add_plugin(ExtractComponentPlugin::<TerrainView>::default())
             .init_resource::<TerrainViewComponents<Quadtree>>()
             .init_resource::<TerrainViewComponents<TerrainViewConfig>>()
-            .add_systems(
-                (
-                    finish_loading_attachment_from_disk.before(update_node_atlas),
-                    compute_quadtree_request.before(update_node_atlas),
-                    update_node_atlas,
-                    adjust_quadtree.after(update_node_atlas),
-                    start_loading_attachment_from_disk.after(update_node_atlas),
-                    update_height_under_viewer.after(adjust_quadtree),
-                )
-                    .in_base_set(CoreSet::Last),
-            );
+            .init_resource::<WorldOrigin>()
+            .add_event::<ShiftOrigin>()
+            .add_event::<EditTerrain>()
+            .add_event::<UndoTerrainEdit>()
+            .add_event::<RedoTerrainEdit>()
+            .add_event::<ExportHeightmap>()
+            .add_event::<SaveTerrain>()
+            .add_event::<LoadTerrainSave>()
+            .add_event::<NodePainted>()
+            .add_event::<NodeQueued>()
+            .add_event::<NodeLoaded>()
+            .add_event::<NodeActivated>()
+            .add_event::<NodeDeactivated>()
+            .add_system(shift_origin.in_base_set(CoreSet::First))
+            .add_system(clear_node_atlas_events.in_base_set(CoreSet::First))
+            .add_system(initialize_scene_terrains.in_base_set(CoreSet::PostUpdate))
+            .add_system(update_terrain_grid.run_if(resource_exists::<TerrainGrid>()));
+
+        let sets = (TerrainSystemSet::Traverse, TerrainSystemSet::Update).chain();
+
+        match self.scheduling {
+            TerrainScheduling::Last => {
+                app.configure_sets(sets);
+            }
+            TerrainScheduling::FixedUpdate => {
+                app.edit_schedule(CoreSchedule::FixedUpdate, |schedule| {
+                    schedule.configure_sets(sets);
+                });
+            }
+            TerrainScheduling::Manual => {
+                app.edit_schedule(TerrainSchedule, |schedule| {
+                    schedule.configure_sets(sets);
+                });
+            }
+        }
+
+        self.add_systems(
+            app,
+            (
+                remove_terrain_views.before(compute_quadtree_request),
+                compute_quadtree_request,
+            )
+                .in_set(TerrainSystemSet::Traverse),
+        );
+
+        self.add_systems(
+            app,
+            (
+                finish_loading_attachment_from_disk.before(update_node_atlas),
+                finish_loading_attachment_from_source.before(update_node_atlas),
+                update_node_atlas,
+                adjust_quadtree.after(update_node_atlas),
+                stitch_quadtrees.after(adjust_quadtree),
+                apply_terrain_history.after(update_node_atlas),
+                apply_terrain_edits.after(apply_terrain_history),
+                export_heightmaps.after(apply_terrain_edits),
+                start_loading_attachment_from_disk.after(update_node_atlas),
+                start_loading_attachment_from_source.after(update_node_atlas),
+                start_loading_attachment_from_gpu.after(update_node_atlas),
+                finish_loading_attachment_from_gpu.before(update_node_atlas),
+                update_height_under_viewer
+                    .after(stitch_quadtrees)
+                    .after(apply_terrain_edits),
+            )
+                .in_set(TerrainSystemSet::Update),
+        );
+
+        // the events are sent once per frame, for all updates of the node atlas during the frame
+        app.add_systems(
+            (send_node_events, send_paint_events)
+                .after(TerrainSystemSet::Update)
+                .in_base_set(CoreSet::Last),
+        );
+
+        self.add_systems(
+            app,
+            (
+                apply_replicated_commands
+                    .after(apply_terrain_history)
+                    .before(apply_terrain_edits),
+                save_terrains.after(apply_terrain_edits),
+                load_terrain_saves
+                    .after(update_node_atlas)
+                    .before(start_loading_attachment_from_disk)
+                    .before(start_loading_attachment_from_source)
+                    .before(start_loading_attachment_from_gpu),
+            )
+                .in_set(TerrainSystemSet::Update),
+        );
+
+        #[cfg(feature = "remote")]
+        self.add_systems(
+            app,
+            (
+                remote_loader::finish_loading_attachment_from_url.before(update_node_atlas),
+                remote_loader::start_loading_attachment_from_url.after(update_node_atlas),
+            )
+                .in_set(TerrainSystemSet::Update),
+        );
+
+        #[cfg(feature = "hot_reload")]
+        self.add_systems(
+            app,
+            (
+                hot_reload::watch_attachments_from_disk,
+                hot_reload::reload_modified_nodes
+                    .after(hot_reload::watch_attachments_from_disk)
+                    .after(update_node_atlas)
+                    .before(start_loading_attachment_from_disk)
+                    .before(start_loading_attachment_from_source)
+                    .before(start_loading_attachment_from_gpu),
+            )
+                .in_set(TerrainSystemSet::Update),
+        );
+
+        #[cfg(feature = "rapier")]
+        self.add_systems(
+            app,
+            (collision::rapier::update_rapier_colliders.after(apply_terrain_edits),)
+                .in_set(TerrainSystemSet::Update),
+        );
+
+        #[cfg(feature = "avian")]
+        self.add_systems(
+            app,
+            (collision::avian::update_avian_colliders.after(apply_terrain_edits),)
+                .in_set(TerrainSystemSet::Update),
+        );
+
+        #[cfg(feature = "headless")]
+        self.add_systems(
+            app,
+            (terrain_data::node_atlas::discard_gpu_updates
+                .after(apply_terrain_edits)
+                .after(update_node_atlas),)
+                .in_set(TerrainSystemSet::Update),
+        );
+
+        #[cfg(not(feature = "headless"))]
+        self.build_render(app);
+    }
+}
+
+impl TerrainPlugin {
+    /// Adds the streaming systems to the schedule selected by the [`TerrainScheduling`].
+    fn add_systems<M>(&self, app: &mut App, systems: impl IntoSystemConfigs<M>) {
+        match self.scheduling {
+            TerrainScheduling::Last => app.add_systems(systems.in_base_set(CoreSet::Last)),
+            TerrainScheduling::FixedUpdate => {
+                app.add_systems(systems.in_schedule(CoreSchedule::FixedUpdate))
+            }
+            TerrainScheduling::Manual => app.add_systems(systems.in_schedule(TerrainSchedule)),
+        };
+    }
+
+    /// Sets up the rendering of the terrains, which is skipped in `headless` mode.
+    #[cfg_attr(feature = "headless", allow(dead_code))]
+    fn build_render(&self, app: &mut App) {
+        let mut atlas_shard_size = self.atlas_shard_size;
+        let mut atlas_shard_count = MAX_ATLAS_SHARDS;
+
+        if let Some(device) = app.world.get_resource::<RenderDevice>() {
+            let limits = device.limits();
+
+            let sampled_textures =
+                sampled_texture_count(self.attachment_count, self.decals, self.detail_layer, 1);
+
+            // each shard of an attachment is bound as a separate texture
+            if sampled_textures > limits.max_sampled_textures_per_shader_stage {
+                panic!(
+                    "The terrain binds {sampled_textures} textures per shader stage ({} attachments), but this device only supports {}. Reduce the attachment count.",
+                    self.attachment_count, limits.max_sampled_textures_per_shader_stage
+                );
+            }
+
+            if self.atlas_shard_size.is_some() {
+                atlas_shard_count = atlas_shard_count.min(max_atlas_shard_count(
+                    &limits,
+                    self.attachment_count,
+                    self.decals,
+                    self.detail_layer,
+                ));
+
+                if atlas_shard_count < MAX_ATLAS_SHARDS {
+                    error!(
+                        "This device only supports {} sampled textures per shader stage, the attachments of the node atlas are split into {atlas_shard_count} instead of {MAX_ATLAS_SHARDS} shards.",
+                        limits.max_sampled_textures_per_shader_stage
+                    );
+                }
+
+                // a single shard is the same as an unsharded atlas
+                if atlas_shard_count == 1 {
+                    atlas_shard_size = None;
+                }
+            }
+
+            app.insert_resource(NodeAtlasLimit(node_atlas_limit(
+                &limits,
+                atlas_shard_size,
+                atlas_shard_count,
+            )));
+        }
+
+        add_shader(app);
+
+        app.add_plugin(ExtractComponentPlugin::<Terrain>::default())
+            .add_plugin(ExtractComponentPlugin::<TerrainView>::default())
+            // the detail layers follow the propagated transforms of their focus entities
+            .add_system(update_detail_layers.in_base_set(CoreSet::Last));
 
         let render_app = app
             .sub_app_mut(RenderApp)
             .insert_resource(TerrainPipelineConfig {
                 attachment_count: self.attachment_count,
+                hole_attachment: self.hole_attachment,
+                snow_attachment: self.snow_attachment,
+                normal_attachment: self.normal_attachment,
+                albedo_attachment: self.albedo_attachment,
+                decals: self.decals,
+                detail_layer: self.detail_layer,
+                atlas_shard_size,
+                atlas_shard_count,
             })
             .init_resource::<TerrainComputePipelines>()
             .init_resource::<SpecializedComputePipelines<TerrainComputePipelines>>()
+            .init_resource::<NodeGeneratorPipelines>()
+            .init_resource::<NormalRecomputationPipeline>()
+            .init_resource::<DepthPyramidPipelines>()
+            .init_resource::<DepthPyramids>()
+            .init_resource::<DecalFallback>()
+            .init_resource::<DetailLayerFallback>()
+            .init_resource::<SpecializedComputePipelines<NodeGeneratorPipelines>>()
             .init_resource::<TerrainComponents<GpuNodeAtlas>>()
+            .init_resource::<TerrainComponents<GpuNodeGenerator>>()
+            .init_resource::<TerrainComponents<GpuNormalRecomputation>>()
+            .init_resource::<TerrainComponents<GpuTerrainDecals>>()
+            .init_resource::<TerrainComponents<GpuTerrainDetailLayer>>()
             .init_resource::<TerrainComponents<TerrainData>>()
             .init_resource::<TerrainViewComponents<GpuQuadtree>>()
             .init_resource::<TerrainViewComponents<TerrainViewData>>()
             .init_resource::<TerrainViewComponents<TerrainViewConfigUniform>>()
             .init_resource::<TerrainViewComponents<CullingBindGroup>>()
+            .init_resource::<TerrainViewComponents<TerrainShadowData>>()
+            .init_resource::<TerrainShadowViews>()
             .add_systems(
                 (
                     extract_terrain_view_config,
                     initialize_gpu_node_atlas,
                     initialize_gpu_quadtree,
+                    initialize_gpu_node_generator.after(initialize_gpu_node_atlas),
+                    initialize_gpu_normal_recomputation.after(initialize_gpu_node_atlas),
                     initialize_terrain_data.after(initialize_gpu_node_atlas),
                     initialize_terrain_view_data.after(initialize_gpu_quadtree),
                     extract_node_atlas.after(initialize_gpu_node_atlas),
                     extract_quadtree.after(initialize_gpu_quadtree),
+                    extract_node_generator.after(initialize_gpu_node_generator),
+                    extract_normal_recomputation
+                        .after(initialize_gpu_normal_recomputation)
+                        .after(extract_node_atlas),
+                    extract_terrain_decals,
+                    extract_terrain_detail_layers,
+                    extract_terrain_shadows
+                        .after(extract_terrain_view_config)
+                        .after(initialize_gpu_quadtree),
                 )
+                    .in_set(TerrainSystemSet::Render)
                     .in_schedule(ExtractSchedule),
             )
-            .add_system(queue_terrain_compute_pipelines.in_set(RenderSet::Queue))
+            .add_systems(
+                (
+                    queue_terrain_compute_pipelines,
+                    queue_node_generator_pipelines,
+                    queue_depth_pyramids,
+                    queue_terrain_shadow_culling,
+                )
+                    .in_set(TerrainSystemSet::Render)
+                    .in_set(RenderSet::Queue),
+            )
             .add_systems(
                 (
                     prepare_quadtree,
                     prepare_node_atlas,
+                    prepare_node_generator.after(prepare_node_atlas),
+                    prepare_normal_recomputation.after(prepare_node_atlas),
+                    prepare_terrain_decals,
+                    prepare_terrain_detail_layers,
                     prepare_terrain_view_config,
+                    prepare_terrain_shadows,
+                    prepare_depth_pyramids.before(prepare_and_queue_terrain_culling_bind_group),
                     prepare_and_queue_terrain_culling_bind_group,
                 )
+                    .in_set(TerrainSystemSet::Render)
                     .in_set(RenderSet::Prepare),
+            )
+            .add_system(
+                report_generated_nodes
+                    .in_set(TerrainSystemSet::Render)
+                    .in_set(RenderSet::Cleanup),
             );
 
         let compute_node = TerrainComputeNode::from_world(&mut render_app.world);
 
         let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        render_graph.add_node("terrain_generation", NodeGeneratorNode);
         render_graph.add_node("terrain_compute", compute_node);
+        render_graph.add_node_edge("terrain_generation", "terrain_compute");
+        // the normals are recomputed from the heights written this frame
+        render_graph.add_node("terrain_normals", NormalRecomputationNode);
+        render_graph.add_node_edge("terrain_generation", "terrain_normals");
+        render_graph.add_node_edge("terrain_normals", CAMERA_DRIVER);
         render_graph.add_node_edge("terrain_compute", CAMERA_DRIVER);
+        // the depth pyramids are built from the depth buffers of the rendered views
+        render_graph.add_node("terrain_depth_pyramid", DepthPyramidNode);
+        render_graph.add_node_edge(CAMERA_DRIVER, "terrain_depth_pyramid");
     }
 }
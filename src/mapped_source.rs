@@ -0,0 +1,321 @@
+//! A node source, which slices the nodes from one large memory-mapped elevation file.
+//!
+//! Instead of preprocessing the heightmap into thousands of small node files, the raw
+//! elevation file is mapped into memory once, and the requested nodes are resampled straight
+//! from it in the [`AsyncComputeTaskPool`]. The operating system pages in only the parts of the
+//! file, that are actually touched, which keeps the load latency low for very large heightmaps.
+//! The heightmap is stretched across the entire terrain.
+//! The minmax nodes store the true height bounds of the footprint of each texel, which are looked
+//! up in a pyramid of block bounds, that is built from the whole file on the first minmax request.
+//! Requires the `mmap` feature and is not available on the web.
+
+use crate::{
+    node_source::{node_image, synthesize_bounds, synthesize_data, NodeSource},
+    terrain::TerrainConfig,
+    terrain_data::{AtlasAttachment, AttachmentConfig, NodeCoordinate, NodeId},
+};
+use anyhow::{anyhow, Result};
+use bevy::{
+    prelude::*,
+    render::render_resource::TextureFormat,
+    tasks::{futures_lite::future, AsyncComputeTaskPool, Task},
+};
+use itertools::iproduct;
+use memmap2::Mmap;
+use std::{
+    fs::File,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+/// The size of the blocks of the finest level of the bounds pyramid in pixels.
+const BOUNDS_BLOCK_SIZE: u32 = 4;
+
+/// One level of the bounds pyramid, which stores the min and max height of each block.
+struct BoundsLevel {
+    block_size: u32,
+    size: UVec2,
+    bounds: Vec<Vec2>,
+}
+
+impl BoundsLevel {
+    fn get(&self, x: u32, y: u32) -> Vec2 {
+        self.bounds[(y * self.size.x + x) as usize]
+    }
+}
+
+/// The layout of the samples of a raw elevation file.
+#[derive(Clone, Copy, Debug)]
+pub enum RawFormat {
+    /// Little endian 16 bit unsigned integers, which map to the full height of the terrain.
+    R16,
+    /// Little endian 32 bit floats, which are already normalized to the height of the terrain.
+    R32F,
+}
+
+impl RawFormat {
+    fn sample_size(self) -> usize {
+        match self {
+            RawFormat::R16 => 2,
+            RawFormat::R32F => 4,
+        }
+    }
+}
+
+/// A raw elevation file, which is mapped into memory.
+///
+/// The samples are stored row by row without any header.
+pub struct MappedHeightmap {
+    map: Mmap,
+    size: UVec2,
+    format: RawFormat,
+    /// The bounds pyramid, which is built on demand.
+    bounds: OnceLock<Vec<BoundsLevel>>,
+}
+
+impl MappedHeightmap {
+    /// Maps the raw elevation file of the size and format at the path into memory.
+    ///
+    /// The file must not be modified, while it is mapped.
+    pub fn open(path: impl AsRef<Path>, size: UVec2, format: RawFormat) -> Result<Self> {
+        let file = File::open(path.as_ref())?;
+
+        // Safety: the file is treated as read only for the lifetime of the map.
+        let map = unsafe { Mmap::map(&file)? };
+
+        if size.cmpeq(UVec2::ZERO).any()
+            || map.len() < (size.x * size.y) as usize * format.sample_size()
+        {
+            return Err(anyhow!(
+                "The elevation file {:?} is smaller than its size of {size}.",
+                path.as_ref()
+            ));
+        }
+
+        Ok(Self {
+            map,
+            size,
+            format,
+            bounds: OnceLock::new(),
+        })
+    }
+
+    /// The size of the heightmap in pixels.
+    pub fn size(&self) -> UVec2 {
+        self.size
+    }
+
+    /// Reads the normalized height of the pixel, clamped to the edges of the heightmap.
+    fn get(&self, x: i32, y: i32) -> f32 {
+        let x = x.clamp(0, self.size.x as i32 - 1) as usize;
+        let y = y.clamp(0, self.size.y as i32 - 1) as usize;
+
+        let sample_size = self.format.sample_size();
+        let index = (y * self.size.x as usize + x) * sample_size;
+        let bytes = &self.map[index..index + sample_size];
+
+        match self.format {
+            RawFormat::R16 => u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32,
+            RawFormat::R32F => f32::from_le_bytes(bytes.try_into().unwrap()),
+        }
+    }
+
+    /// Bilinearly samples the height at the pixel coordinate.
+    fn sample(&self, pixel: Vec2) -> f32 {
+        let base = pixel.floor();
+        let t = pixel - base;
+        let (x, y) = (base.x as i32, base.y as i32);
+
+        let top = self.get(x, y) * (1.0 - t.x) + self.get(x + 1, y) * t.x;
+        let bottom = self.get(x, y + 1) * (1.0 - t.x) + self.get(x + 1, y + 1) * t.x;
+
+        top * (1.0 - t.y) + bottom * t.y
+    }
+
+    /// Samples the height at the pixel coordinate, averaged across the footprint (in pixels).
+    ///
+    /// Large footprints of coarse nodes are approximated with four samples,
+    /// so that the cost of each node does not depend on its lod.
+    fn sample_footprint(&self, pixel: Vec2, footprint: f32) -> f32 {
+        if footprint <= 1.0 {
+            return self.sample(pixel);
+        }
+
+        let offset = 0.25 * footprint;
+
+        [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)]
+            .into_iter()
+            .map(|(x, y)| self.sample(pixel + Vec2::new(x, y) * offset))
+            .sum::<f32>()
+            / 4.0
+    }
+
+    /// Returns the bounds pyramid, which is built from all pixels of the heightmap the first time.
+    fn bounds_levels(&self) -> &[BoundsLevel] {
+        self.bounds.get_or_init(|| {
+            let block_size = BOUNDS_BLOCK_SIZE;
+            let size = (self.size + block_size - 1) / block_size;
+
+            let bounds = iproduct!(0..size.y, 0..size.x)
+                .map(|(y, x)| {
+                    iproduct!(0..block_size, 0..block_size)
+                        .map(|(dy, dx)| {
+                            let height = self
+                                .get((x * block_size + dx) as i32, (y * block_size + dy) as i32);
+                            Vec2::splat(height)
+                        })
+                        .fold(Vec2::new(f32::MAX, f32::MIN), |bounds, height| {
+                            Vec2::new(bounds.x.min(height.x), bounds.y.max(height.y))
+                        })
+                })
+                .collect();
+
+            let mut levels = vec![BoundsLevel {
+                block_size,
+                size,
+                bounds,
+            }];
+
+            while levels.last().unwrap().size.max_element() > 1 {
+                let previous = levels.last().unwrap();
+                let size = (previous.size + 1) / 2;
+
+                let bounds = iproduct!(0..size.y, 0..size.x)
+                    .map(|(y, x)| {
+                        iproduct!(0..2, 0..2)
+                            .map(|(dy, dx)| {
+                                let max = previous.size - 1;
+                                previous.get((2 * x + dx).min(max.x), (2 * y + dy).min(max.y))
+                            })
+                            .fold(Vec2::new(f32::MAX, f32::MIN), |bounds, child| {
+                                Vec2::new(bounds.x.min(child.x), bounds.y.max(child.y))
+                            })
+                    })
+                    .collect();
+
+                levels.push(BoundsLevel {
+                    block_size: previous.block_size * 2,
+                    size,
+                    bounds,
+                });
+            }
+
+            levels
+        })
+    }
+
+    /// Returns the min and max height of the bilinear heightmap across the footprint (in pixels)
+    /// centered at the pixel coordinate.
+    ///
+    /// Small footprints are bounded by their pixels directly, large ones by the blocks of the
+    /// bounds pyramid, which cover them. Both are conservative.
+    fn bounds_footprint(&self, pixel: Vec2, footprint: f32) -> Vec2 {
+        let max = self.size.as_ivec2() - 1;
+        let start = (pixel - 0.5 * footprint)
+            .floor()
+            .as_ivec2()
+            .clamp(IVec2::ZERO, max);
+        let end = (pixel + 0.5 * footprint)
+            .ceil()
+            .as_ivec2()
+            .clamp(IVec2::ZERO, max);
+
+        let fold =
+            |bounds: Vec2, value: Vec2| Vec2::new(bounds.x.min(value.x), bounds.y.max(value.y));
+
+        if footprint < (2 * BOUNDS_BLOCK_SIZE) as f32 {
+            return iproduct!(start.y..=end.y, start.x..=end.x)
+                .map(|(y, x)| Vec2::splat(self.get(x, y)))
+                .fold(Vec2::new(f32::MAX, f32::MIN), fold);
+        }
+
+        // the coarsest level, whose blocks are at most half the footprint
+        let levels = self.bounds_levels();
+        let level = levels
+            .iter()
+            .rev()
+            .find(|level| level.block_size as f32 <= 0.5 * footprint)
+            .unwrap_or(&levels[0]);
+
+        let (start, end) = (start.as_uvec2(), end.as_uvec2());
+
+        iproduct!(
+            start.y / level.block_size..=end.y / level.block_size,
+            start.x / level.block_size..=end.x / level.block_size
+        )
+        .map(|(y, x)| level.get(x, y))
+        .fold(Vec2::new(f32::MAX, f32::MIN), fold)
+    }
+}
+
+/// A node source, that slices a memory-mapped heightmap into the height or minmax data
+/// of the nodes.
+pub struct MappedSource {
+    heightmap: Arc<MappedHeightmap>,
+    attachment: AtlasAttachment,
+    leaf_node_size: u32,
+    terrain_extent: UVec2,
+    tasks: Vec<(NodeId, Task<Image>)>,
+}
+
+impl MappedSource {
+    /// Creates a mapped source for the height (R16 or R32F) or minmax (Rg16) attachment.
+    pub fn new(
+        config: &TerrainConfig,
+        attachment: &AttachmentConfig,
+        heightmap: Arc<MappedHeightmap>,
+    ) -> Self {
+        Self {
+            heightmap,
+            attachment: attachment.clone().into(),
+            leaf_node_size: config.leaf_node_size,
+            terrain_extent: config.terrain_extent,
+            tasks: Vec::new(),
+        }
+    }
+}
+
+impl NodeSource for MappedSource {
+    fn request(&mut self, node_id: NodeId) {
+        let heightmap = self.heightmap.clone();
+        let attachment = self.attachment.clone();
+        let leaf_node_size = self.leaf_node_size;
+        let scale = heightmap.size.as_vec2() / self.terrain_extent.as_vec2();
+
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            let lod = NodeCoordinate::from(node_id).lod;
+            let pixel_size = (leaf_node_size << lod) as f32 / attachment.center_size as f32;
+            let footprint = pixel_size * scale.max_element();
+
+            let data = if attachment.format() == TextureFormat::Rg16Unorm {
+                synthesize_bounds(node_id, &attachment, leaf_node_size, |position| {
+                    heightmap.bounds_footprint(position * scale - 0.5, footprint)
+                })
+            } else {
+                synthesize_data(node_id, &attachment, leaf_node_size, |position| {
+                    heightmap.sample_footprint(position * scale - 0.5, footprint)
+                })
+            };
+
+            node_image(&attachment, data)
+        });
+
+        self.tasks.push((node_id, task));
+    }
+
+    fn poll(&mut self, images: &mut Assets<Image>) -> Vec<(NodeId, Result<Handle<Image>>)> {
+        let mut finished = Vec::new();
+
+        self.tasks.retain_mut(
+            |(node_id, task)| match future::block_on(future::poll_once(task)) {
+                Some(image) => {
+                    finished.push((*node_id, Ok(images.add(image))));
+                    false
+                }
+                None => true,
+            },
+        );
+
+        finished
+    }
+}
@@ -0,0 +1,282 @@
+//! Captures top-down renders of terrain regions into textures, e.g. for minimaps and loading screens.
+//!
+//! A [`TerrainCapture`] renders a horizontal world space region of a terrain with an orthographic
+//! camera looking straight down. The capture registers itself as a separate view of the terrain,
+//! whose quadtree requests the nodes of the region with the uniform level of detail of the
+//! capture (see [`LodMetric::Uniform`]). As each view selects its own nodes, the temporary
+//! selection of the capture does not interfere with the level of detail of the main viewers.
+//!
+//! Once all nodes of the region are loaded and rendered, a [`TerrainCaptured`] event is sent.
+//! One-shot captures then stop rendering and release their nodes, while continuous captures
+//! keep rendering each frame, e.g. for live minimaps of edited terrains.
+//! Add the [`TerrainCapturePlugin`] to enable the captures.
+
+use crate::{
+    terrain::{Terrain, TerrainConfig},
+    terrain_data::{node_atlas::NodeAtlas, quadtree::Quadtree},
+    terrain_view::{LodMetric, TerrainView, TerrainViewComponents, TerrainViewConfig},
+    TerrainSystemSet,
+};
+use bevy::{
+    core_pipeline::clear_color::ClearColorConfig,
+    prelude::*,
+    render::{
+        camera::{RenderTarget, ScalingMode},
+        render_resource::{
+            Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+        },
+    },
+};
+
+/// The number of frames a capture keeps rendering, after all of its nodes have been loaded,
+/// so that the node atlas and the quadtree have been synced with the GPU.
+const SETTLE_FRAMES: u32 = 2;
+
+/// The vertical margin between the capture camera and the terrain surface.
+const CAMERA_MARGIN: f32 = 1.0;
+
+/// Sent, once a capture has rendered all nodes of its region.
+#[derive(Clone, Debug)]
+pub struct TerrainCaptured {
+    /// The entity of the capture.
+    pub capture: Entity,
+    /// The texture, the region has been rendered into.
+    pub image: Handle<Image>,
+}
+
+/// Renders a horizontal world space region of a terrain top-down into a texture.
+///
+/// The plugin turns the entity of the capture into an orthographic camera, which renders
+/// into the [`image`](TerrainCapture::image) of the capture.
+/// The node atlas of the terrain has to be large enough to hold the nodes of the region.
+#[derive(Clone, Component)]
+pub struct TerrainCapture {
+    /// The terrain entity rendered by the capture.
+    pub terrain: Entity,
+    /// The uniform lod of the nodes rendered by the capture.
+    pub lod: u32,
+    /// The color of the texture, where the region is not covered by the terrain.
+    pub clear_color: Color,
+    /// Whether the capture keeps rendering, after it has been completed.
+    pub continuous: bool,
+    region: Rect,
+    image: Handle<Image>,
+    settle_frames: Option<u32>,
+    completed: bool,
+}
+
+impl TerrainCapture {
+    /// Creates a new one-shot capture of the region, which renders the nodes of the lod
+    /// into a new texture with the resolution.
+    pub fn new(
+        images: &mut Assets<Image>,
+        terrain: Entity,
+        region: Rect,
+        resolution: UVec2,
+        lod: u32,
+    ) -> Self {
+        let size = Extent3d {
+            width: resolution.x.max(1),
+            height: resolution.y.max(1),
+            depth_or_array_layers: 1,
+        };
+
+        let mut image = Image {
+            texture_descriptor: TextureDescriptor {
+                label: None,
+                size,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Bgra8UnormSrgb,
+                mip_level_count: 1,
+                sample_count: 1,
+                usage: TextureUsages::TEXTURE_BINDING
+                    | TextureUsages::COPY_DST
+                    | TextureUsages::RENDER_ATTACHMENT,
+                view_formats: &[],
+            },
+            ..default()
+        };
+        image.resize(size);
+
+        Self {
+            terrain,
+            lod,
+            clear_color: Color::NONE,
+            continuous: false,
+            region,
+            image: images.add(image),
+            settle_frames: None,
+            completed: false,
+        }
+    }
+
+    /// Keeps rendering the region each frame, after the capture has been completed.
+    pub fn with_continuous(mut self) -> Self {
+        self.continuous = true;
+        self
+    }
+
+    /// Fills the parts of the texture, which are not covered by the terrain, with the color.
+    pub fn with_clear_color(mut self, clear_color: Color) -> Self {
+        self.clear_color = clear_color;
+        self
+    }
+
+    /// The horizontal world space region rendered by the capture.
+    pub fn region(&self) -> Rect {
+        self.region
+    }
+
+    /// The texture, the region is rendered into.
+    ///
+    /// The top of the texture faces the negative z axis.
+    pub fn image(&self) -> &Handle<Image> {
+        &self.image
+    }
+
+    /// Whether all nodes of the region have been rendered into the texture.
+    pub fn is_completed(&self) -> bool {
+        self.completed
+    }
+}
+
+/// Renders the [`TerrainCapture`]s.
+pub struct TerrainCapturePlugin;
+
+impl Plugin for TerrainCapturePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<TerrainCaptured>()
+            .add_system(setup_terrain_captures)
+            .add_system(
+                complete_terrain_captures
+                    .after(TerrainSystemSet::Update)
+                    .in_base_set(CoreSet::Last),
+            );
+    }
+}
+
+/// Spawns the cameras of the new captures and registers them as views of their terrains.
+fn setup_terrain_captures(
+    mut commands: Commands,
+    mut quadtrees: ResMut<TerrainViewComponents<Quadtree>>,
+    mut view_configs: ResMut<TerrainViewComponents<TerrainViewConfig>>,
+    capture_query: Query<(Entity, &TerrainCapture), Added<TerrainCapture>>,
+    terrain_query: Query<(&GlobalTransform, &TerrainConfig, &NodeAtlas), With<Terrain>>,
+) {
+    for (entity, capture) in capture_query.iter() {
+        let Ok((transform, config, node_atlas)) = terrain_query.get(capture.terrain) else {
+            warn!("The terrain of the capture {entity:?} does not exist.");
+            continue;
+        };
+
+        let lod = capture.lod.min(config.lod_count - 1);
+        let center = capture.region.center();
+        let size = capture.region.size();
+
+        // the camera is placed above the highest possible point of the terrain
+        let top = transform.transform_point(Vec3::Y * node_atlas.height).y + CAMERA_MARGIN;
+        let bottom = transform.translation().y;
+
+        let camera_transform = Transform::from_xyz(center.x, top, center.y)
+            .looking_at(Vec3::new(center.x, bottom, center.y), Vec3::NEG_Z);
+
+        // the node grid of the view has to cover the region with the nodes of the lod
+        let node_size =
+            (config.leaf_node_size << lod) as f32 * transform.compute_transform().scale.x;
+        let node_count = (size.max_element() / node_size).ceil() as u32 + 2;
+
+        let view_config = TerrainViewConfig {
+            node_count,
+            lod_metric: LodMetric::Uniform { lod },
+            occlusion_culling: false,
+            ..default()
+        };
+
+        quadtrees.insert(
+            (capture.terrain, entity),
+            Quadtree::from_configs(config, &view_config),
+        );
+        view_configs.insert((capture.terrain, entity), view_config);
+
+        commands.entity(entity).insert((
+            TerrainView,
+            Camera3dBundle {
+                camera: Camera {
+                    // render the captures before the main cameras, which may display them
+                    order: -1,
+                    target: RenderTarget::Image(capture.image.clone()),
+                    ..default()
+                },
+                camera_3d: Camera3d {
+                    clear_color: ClearColorConfig::Custom(capture.clear_color),
+                    ..default()
+                },
+                projection: OrthographicProjection {
+                    near: 0.0,
+                    far: top - bottom + CAMERA_MARGIN,
+                    scaling_mode: ScalingMode::Fixed {
+                        width: size.x,
+                        height: size.y,
+                    },
+                    ..default()
+                }
+                .into(),
+                transform: camera_transform,
+                ..default()
+            },
+            UiCameraConfig { show_ui: false },
+        ));
+    }
+}
+
+/// Completes the captures, once all nodes of their regions have been loaded and rendered.
+///
+/// The views of completed one-shot captures are removed, which releases their nodes.
+fn complete_terrain_captures(
+    mut commands: Commands,
+    quadtrees: Res<TerrainViewComponents<Quadtree>>,
+    mut captured_events: EventWriter<TerrainCaptured>,
+    mut capture_query: Query<(Entity, &mut TerrainCapture, &mut Camera)>,
+) {
+    for (entity, mut capture, mut camera) in capture_query.iter_mut() {
+        let capture = &mut *capture;
+
+        if capture.completed {
+            continue;
+        }
+
+        let Some(quadtree) = quadtrees.get(&(capture.terrain, entity)) else {
+            continue;
+        };
+
+        match capture.settle_frames {
+            None => {
+                let occupancy = quadtree.occupancy();
+
+                // the coarsest lod is always requested, once the quadtree has been traversed
+                let loaded = occupancy.iter().any(|&(requested, _)| requested > 0)
+                    && occupancy
+                        .iter()
+                        .all(|&(requested, loaded)| requested == loaded);
+
+                if loaded {
+                    capture.settle_frames = Some(SETTLE_FRAMES);
+                }
+            }
+            Some(0) => {
+                capture.completed = true;
+
+                captured_events.send(TerrainCaptured {
+                    capture: entity,
+                    image: capture.image.clone(),
+                });
+
+                if !capture.continuous {
+                    camera.is_active = false;
+                    commands.entity(entity).remove::<TerrainView>();
+                }
+            }
+            Some(ref mut frames) => *frames -= 1,
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Provides the geometry of the streamed terrain for building AI navigation meshes.
+//!
+//! Each terrain with a [`TerrainNavigation`] component triangulates the heights of each activated
+//! node of the configured lod into a [`NavigationPatch`], a plain triangle soup in world space,
+//! which can be fed into any navigation mesh builder.
+//! Holes of the hole attachment (see [`crate::holes`]) are cut out of the patches.
+//!
+//! A [`NavigationPatchChanged`] event is sent whenever a patch is added, rebuilt after its node has
+//! been edited or removed after its node has been deactivated, so that the navigation mesh tiles
+//! overlapping its region can be invalidated. All current patches can also be polled
+//! with [`TerrainNavigation::patches`].
+//! Add the [`TerrainNavigationPlugin`] to enable the patches.
+
+use crate::{
+    collision::NodeHeights,
+    terrain_data::{
+        node_atlas::{NodeAtlas, NodeLifecycle},
+        AttachmentIndex, NodeCoordinate, NodeId,
+    },
+    TerrainSystemSet,
+};
+use bevy::{math::Vec3Swizzles, prelude::*, utils::HashMap};
+use itertools::Itertools;
+use std::sync::Arc;
+
+/// The triangulated geometry of a node.
+#[derive(Clone, Debug)]
+pub struct NavigationPatch {
+    /// The terrain entity of the node.
+    pub terrain: Entity,
+    /// The id of the node.
+    pub node_id: NodeId,
+    /// The region covered by the patch along the x and z axes (in world space).
+    pub rect: Rect,
+    /// The world space positions of the vertices.
+    pub vertices: Vec<Vec3>,
+    /// The indices of the vertices of each triangle.
+    pub indices: Vec<[u32; 3]>,
+}
+
+/// Sent, when the navigation patch of a node has been added, rebuilt or removed.
+#[derive(Clone, Debug)]
+pub struct NavigationPatchChanged {
+    /// The terrain entity of the node.
+    pub terrain: Entity,
+    /// The id of the node.
+    pub node_id: NodeId,
+    /// The region, whose navigation mesh has to be rebuilt (in world space).
+    pub rect: Rect,
+    /// The new patch of the node or `None` if it has been removed.
+    pub patch: Option<Arc<NavigationPatch>>,
+}
+
+/// Configures the navigation patches of a terrain.
+#[derive(Component)]
+pub struct TerrainNavigation {
+    /// The lod of the nodes, for which patches are built.
+    pub lod: u32,
+    /// The index of the hole attachment, whose holes are cut out of the patches.
+    pub hole_attachment: Option<AttachmentIndex>,
+    /// The patches of the currently activated nodes.
+    patches: HashMap<NodeId, Arc<NavigationPatch>>,
+}
+
+impl TerrainNavigation {
+    /// Creates a new navigation config, which builds patches for the nodes of the lod.
+    pub fn new(lod: u32) -> Self {
+        Self {
+            lod,
+            hole_attachment: None,
+            patches: default(),
+        }
+    }
+
+    /// Cuts the holes of the hole attachment out of the patches.
+    pub fn with_holes(mut self, hole_attachment: AttachmentIndex) -> Self {
+        self.hole_attachment = Some(hole_attachment);
+        self
+    }
+
+    /// Returns the patches of all currently activated nodes.
+    pub fn patches(&self) -> impl Iterator<Item = &Arc<NavigationPatch>> {
+        self.patches.values()
+    }
+
+    /// Returns the patch of the node, if it is activated.
+    pub fn patch(&self, node_id: NodeId) -> Option<&Arc<NavigationPatch>> {
+        self.patches.get(&node_id)
+    }
+
+    /// Triangulates the heights of the node.
+    ///
+    /// Returns `None` if the height data of the node is not available on the CPU.
+    fn build_patch(
+        &self,
+        terrain: Entity,
+        transform: &GlobalTransform,
+        node_atlas: &NodeAtlas,
+        images: &Assets<Image>,
+        node_id: NodeId,
+    ) -> Option<NavigationPatch> {
+        let node_heights = NodeHeights::new(node_atlas, images, node_id, self.hole_attachment)?;
+        let cells = node_heights.resolution - 1;
+
+        let (vertices, indices) = match &node_heights.holes {
+            Some(holes) => node_heights.trimesh(holes),
+            None => node_heights.trimesh(&vec![false; cells * cells]),
+        };
+
+        let offset = Vec3::new(node_heights.center.x, 0.0, node_heights.center.y);
+        let vertices = vertices
+            .into_iter()
+            .map(|vertex| transform.transform_point(vertex + offset))
+            .collect::<Vec<_>>();
+
+        Some(NavigationPatch {
+            terrain,
+            node_id,
+            rect: node_rect(transform, node_atlas, node_id),
+            vertices,
+            indices,
+        })
+    }
+}
+
+/// Determines the region covered by the node along the x and z axes (in world space).
+pub(crate) fn node_rect(
+    transform: &GlobalTransform,
+    node_atlas: &NodeAtlas,
+    node_id: NodeId,
+) -> Rect {
+    let NodeCoordinate { lod, x, y } = node_id.into();
+    let node_size = (node_atlas.leaf_node_size << lod) as f32;
+
+    let corners = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(dx, dy)| {
+        let local_position = Vec2::new((x + dx) as f32, (y + dy) as f32) * node_size;
+
+        transform
+            .transform_point(Vec3::new(local_position.x, 0.0, local_position.y))
+            .xz()
+    });
+
+    Rect {
+        min: corners.into_iter().reduce(Vec2::min).unwrap(),
+        max: corners.into_iter().reduce(Vec2::max).unwrap(),
+    }
+}
+
+/// Builds the navigation patches of the terrains with a [`TerrainNavigation`] component.
+pub struct TerrainNavigationPlugin;
+
+impl Plugin for TerrainNavigationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<NavigationPatchChanged>().add_system(
+            update_navigation_patches
+                .after(TerrainSystemSet::Update)
+                .in_base_set(CoreSet::Last),
+        );
+    }
+}
+
+/// Builds and removes the navigation patches of all terrains,
+/// according to the lifecycle of the nodes of their node atlas.
+fn update_navigation_patches(
+    images: Res<Assets<Image>>,
+    mut patch_events: EventWriter<NavigationPatchChanged>,
+    mut terrain_query: Query<(Entity, &GlobalTransform, &NodeAtlas, &mut TerrainNavigation)>,
+) {
+    for (terrain, transform, node_atlas, mut navigation) in terrain_query.iter_mut() {
+        let (lod, hole_attachment) = (navigation.lod, navigation.hole_attachment);
+
+        // painting holes changes the walkable area of the nodes
+        let modified = node_atlas
+            .edited_nodes
+            .iter()
+            .copied()
+            .chain(
+                node_atlas
+                    .painted_nodes
+                    .iter()
+                    .filter(|&&(_, attachment_index)| Some(attachment_index) == hole_attachment)
+                    .map(|&(node_id, _)| node_id),
+            )
+            .collect::<Vec<_>>();
+
+        let deactivated = node_atlas
+            .lifecycle_events
+            .iter()
+            .filter(|&&(_, lifecycle)| lifecycle == NodeLifecycle::Deactivated)
+            .map(|&(node_id, _)| node_id)
+            .chain(modified.iter().copied())
+            .unique()
+            .filter(|node_id| navigation.patches.contains_key(node_id))
+            .collect::<Vec<_>>();
+
+        for node_id in deactivated {
+            let patch = navigation.patches.remove(&node_id).unwrap();
+
+            patch_events.send(NavigationPatchChanged {
+                terrain,
+                node_id,
+                rect: patch.rect,
+                patch: None,
+            });
+        }
+
+        let activated = node_atlas
+            .lifecycle_events
+            .iter()
+            .filter(|&&(_, lifecycle)| lifecycle == NodeLifecycle::Activated)
+            .map(|&(node_id, _)| node_id)
+            .chain(modified)
+            .unique()
+            .filter(|&node_id| {
+                NodeCoordinate::from(node_id).lod == lod
+                    && node_atlas.nodes.contains_key(&node_id)
+                    && !navigation.patches.contains_key(&node_id)
+            })
+            .collect::<Vec<_>>();
+
+        for node_id in activated {
+            let Some(patch) =
+                navigation.build_patch(terrain, transform, node_atlas, &images, node_id)
+            else {
+                continue;
+            };
+
+            let patch = Arc::new(patch);
+            navigation.patches.insert(node_id, patch.clone());
+
+            patch_events.send(NavigationPatchChanged {
+                terrain,
+                node_id,
+                rect: patch.rect,
+                patch: Some(patch),
+            });
+        }
+    }
+}
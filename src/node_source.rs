@@ -0,0 +1,720 @@
+//! A pluggable attachment loader, which requests the node data from custom backends.
+//!
+//! Implement the [`NodeSource`] trait for a data backend (e.g. a database, a generator or
+//! a custom network protocol) and add it to the terrain with
+//! [`TerrainConfig::add_attachment_from_source`](crate::terrain::TerrainConfig::add_attachment_from_source).
+//! The built-in loaders read their nodes through node sources as well
+//! (see [`DiskSource`](crate::attachment_loader::DiskSource) and `UrlSource`),
+//! so that they can be combined with or wrapped by custom sources.
+
+use crate::{
+    formats::tdf::generate_mipmaps,
+    preprocess::file_io::{format_directory, load_image},
+    seeded::{SeededNoise, FRACTION_BITS},
+    terrain::TerrainConfig,
+    terrain_data::{
+        node_atlas::NodeAtlas, AtlasAttachment, AttachmentConfig, AttachmentIndex, FileFormat,
+        NodeCoordinate, NodeId,
+    },
+};
+use anyhow::{anyhow, Result};
+use bevy::{
+    prelude::*,
+    render::render_resource::{Extent3d, TextureFormat, TextureUsages},
+    tasks::{futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::HashMap,
+};
+use image::DynamicImage;
+use itertools::iproduct;
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+};
+
+/// A backend, that provides the data of one attachment for the requested nodes.
+///
+/// Requests may be answered immediately or over multiple frames.
+pub trait NodeSource: Send + Sync + 'static {
+    /// Starts loading the attachment data of the node.
+    fn request(&mut self, node_id: NodeId);
+
+    /// Returns the nodes, that have finished loading since the last call, alongside their data.
+    ///
+    /// The images have to match the configuration of the attachment.
+    /// Nodes, whose data is not available, have to be returned with an error,
+    /// so that they finish loading without the attachment, instead of loading forever.
+    fn poll(&mut self, images: &mut Assets<Image>) -> Vec<(NodeId, Result<Handle<Image>>)>;
+}
+
+/// A node source, that serves node data which is already stored in memory.
+#[derive(Default)]
+pub struct MemorySource {
+    /// The data of all available nodes.
+    pub nodes: HashMap<NodeId, Handle<Image>>,
+    requested: Vec<NodeId>,
+}
+
+impl MemorySource {
+    pub fn new(nodes: HashMap<NodeId, Handle<Image>>) -> Self {
+        Self {
+            nodes,
+            requested: Vec::new(),
+        }
+    }
+}
+
+impl NodeSource for MemorySource {
+    fn request(&mut self, node_id: NodeId) {
+        self.requested.push(node_id);
+    }
+
+    fn poll(&mut self, _images: &mut Assets<Image>) -> Vec<(NodeId, Result<Handle<Image>>)> {
+        self.requested
+            .drain(..)
+            .map(|node_id| {
+                let handle = self.nodes.get(&node_id).cloned().ok_or_else(|| {
+                    anyhow!("The node {node_id} is not stored in the memory source.")
+                });
+
+                (node_id, handle)
+            })
+            .collect()
+    }
+}
+
+/// This component is used to load attachments from node sources into the corresponding [`NodeAtlas`].
+#[derive(Default, Component)]
+pub struct AttachmentFromSourceLoader {
+    pub(crate) sources: HashMap<AttachmentIndex, Box<dyn NodeSource>>,
+}
+
+pub(crate) fn start_loading_attachment_from_source(
+    mut terrain_query: Query<(&NodeAtlas, &mut AttachmentFromSourceLoader)>,
+) {
+    for (node_atlas, mut loader) in terrain_query.iter_mut() {
+        for &node_id in node_atlas.load_events.iter() {
+            for source in loader.sources.values_mut() {
+                source.request(node_id);
+            }
+        }
+    }
+}
+
+/// Sets the loaded attachment of the node, or logs why it failed to load.
+///
+/// Failed attachments are marked as loaded as well, so that the node does not load forever.
+pub(crate) fn finish_loading_node(
+    node_atlas: &mut NodeAtlas,
+    images: &mut Assets<Image>,
+    node_id: NodeId,
+    attachment_index: AttachmentIndex,
+    result: Result<Handle<Image>>,
+) {
+    // the node might have been unloaded in the meantime
+    let Some(node) = node_atlas.loading_nodes.get_mut(&node_id) else {
+        return;
+    };
+
+    match result {
+        Ok(handle) => {
+            if let Some(image) = images.get_mut(&handle) {
+                image.texture_descriptor.usage |= TextureUsages::COPY_SRC;
+            }
+
+            node.set_attachment(attachment_index, handle);
+        }
+        Err(error) => {
+            warn!("Failed to load the attachment {attachment_index} of node {node_id}: {error}");
+        }
+    }
+
+    node.loaded(attachment_index);
+}
+
+pub(crate) fn finish_loading_attachment_from_source(
+    mut images: ResMut<Assets<Image>>,
+    mut terrain_query: Query<(&mut NodeAtlas, &mut AttachmentFromSourceLoader)>,
+) {
+    for (mut node_atlas, mut loader) in terrain_query.iter_mut() {
+        for (&attachment_index, source) in loader.sources.iter_mut() {
+            for (node_id, result) in source.poll(&mut images) {
+                finish_loading_node(
+                    &mut node_atlas,
+                    &mut images,
+                    node_id,
+                    attachment_index,
+                    result,
+                );
+            }
+        }
+    }
+}
+
+/// A function, that returns the normalized height (from zero to one) of the terrain
+/// at the position (in the local space of the terrain).
+pub type HeightFunction = Arc<dyn Fn(Vec2) -> f32 + Send + Sync>;
+
+/// The generator of the heights of a procedural source.
+#[derive(Clone)]
+enum ProceduralHeight {
+    /// A height function evaluated at the pixel positions.
+    Function(HeightFunction),
+    /// Seeded noise evaluated with integer arithmetic, which is bit-identical across platforms.
+    Seeded(SeededNoise),
+}
+
+/// A node source, that synthesizes the height or minmax data of the nodes from a height function
+/// or from [`SeededNoise`].
+///
+/// Nodes that were already authored (and preprocessed) are loaded from disk instead,
+/// which allows hybrid terrains, whose authored parts are surrounded by procedural ones.
+/// The nodes are generated in the [`AsyncComputeTaskPool`].
+pub struct ProceduralSource {
+    attachment: AtlasAttachment,
+    file_format: FileFormat,
+    directory: String,
+    leaf_node_size: u32,
+    height: ProceduralHeight,
+    tasks: Vec<(NodeId, Task<Image>)>,
+}
+
+impl ProceduralSource {
+    /// Creates a procedural source for the height (R16 or R32F) or minmax (Rg16) attachment.
+    pub fn new(
+        config: &TerrainConfig,
+        attachment: &AttachmentConfig,
+        height: HeightFunction,
+    ) -> Self {
+        Self::with_height(config, attachment, ProceduralHeight::Function(height))
+    }
+
+    /// Creates a procedural source for the height (R16 or R32F) or minmax (Rg16) attachment,
+    /// whose nodes are generated deterministically from the seeded noise.
+    pub fn seeded(
+        config: &TerrainConfig,
+        attachment: &AttachmentConfig,
+        noise: SeededNoise,
+    ) -> Self {
+        Self::with_height(config, attachment, ProceduralHeight::Seeded(noise))
+    }
+
+    fn with_height(
+        config: &TerrainConfig,
+        attachment: &AttachmentConfig,
+        height: ProceduralHeight,
+    ) -> Self {
+        Self {
+            attachment: attachment.clone().into(),
+            file_format: attachment.file_format,
+            directory: format_directory(&config.path, &attachment.name),
+            leaf_node_size: config.leaf_node_size,
+            height,
+            tasks: Vec::new(),
+        }
+    }
+}
+
+/// Converts an authored node into the texel data of the attachment format.
+fn authored_data(image: DynamicImage, format: TextureFormat) -> Option<Vec<u8>> {
+    match format {
+        TextureFormat::R16Unorm if image.as_luma16().is_some() => Some(image.as_bytes().to_vec()),
+        TextureFormat::Rg16Unorm if image.as_luma_alpha16().is_some() => {
+            Some(image.as_bytes().to_vec())
+        }
+        TextureFormat::R32Float => Some(
+            image
+                .as_rgb32f()?
+                .pixels()
+                .flat_map(|pixel| pixel.0[0].to_le_bytes())
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Encodes the normalized height, given as a float and as a 16 bit unsigned integer,
+/// into the texel data of the attachment format.
+fn encode_height(format: TextureFormat, value: f32, unorm: u16) -> Vec<u8> {
+    match format {
+        TextureFormat::R32Float => value.to_le_bytes().to_vec(),
+        TextureFormat::Rg16Unorm => [unorm.to_le_bytes(), unorm.to_le_bytes()].concat(),
+        _ => unorm.to_le_bytes().to_vec(),
+    }
+}
+
+/// Synthesizes the texel data of the node (without mips) from the height function.
+pub(crate) fn synthesize_data(
+    node_id: NodeId,
+    attachment: &AtlasAttachment,
+    leaf_node_size: u32,
+    height: impl Fn(Vec2) -> f32,
+) -> Vec<u8> {
+    let size = attachment.texture_size;
+    let coord = NodeCoordinate::from(node_id);
+    let node_size = (leaf_node_size << coord.lod) as f32;
+    let node_origin = Vec2::new(coord.x as f32, coord.y as f32) * node_size;
+
+    iproduct!(0..size, 0..size)
+        .flat_map(|(y, x)| {
+            let position = attachment.pixel_position(node_origin, node_size, UVec2::new(x, y));
+            let value = height(position).clamp(0.0, 1.0);
+
+            encode_height(
+                attachment.format(),
+                value,
+                (value * u16::MAX as f32).round() as u16,
+            )
+        })
+        .collect()
+}
+
+/// Synthesizes the minmax texel data of the node (without mips) from the bounds of the footprint
+/// of each texel.
+pub(crate) fn synthesize_bounds(
+    node_id: NodeId,
+    attachment: &AtlasAttachment,
+    leaf_node_size: u32,
+    bounds: impl Fn(Vec2) -> Vec2,
+) -> Vec<u8> {
+    let size = attachment.texture_size;
+    let coord = NodeCoordinate::from(node_id);
+    let node_size = (leaf_node_size << coord.lod) as f32;
+    let node_origin = Vec2::new(coord.x as f32, coord.y as f32) * node_size;
+
+    iproduct!(0..size, 0..size)
+        .flat_map(|(y, x)| {
+            let position = attachment.pixel_position(node_origin, node_size, UVec2::new(x, y));
+            let bounds = bounds(position).clamp(Vec2::ZERO, Vec2::ONE) * u16::MAX as f32;
+
+            // the bounds are rounded conservatively
+            [bounds.x.floor() as u16, bounds.y.ceil() as u16]
+                .into_iter()
+                .flat_map(u16::to_le_bytes)
+        })
+        .collect()
+}
+
+/// Synthesizes the texel data of the node (without mips) from the seeded noise,
+/// using integer arithmetic only.
+fn synthesize_seeded_data(
+    node_id: NodeId,
+    attachment: &AtlasAttachment,
+    leaf_node_size: u32,
+    noise: &SeededNoise,
+) -> Vec<u8> {
+    let size = attachment.texture_size;
+
+    iproduct!(0..size, 0..size)
+        .flat_map(|(y, x)| {
+            let position =
+                attachment.fixed_pixel_position(node_id, leaf_node_size, UVec2::new(x, y));
+            let value = noise.sample(position);
+
+            // both conversions are exact
+            encode_height(
+                attachment.format(),
+                value as f32 / (1 << FRACTION_BITS) as f32,
+                ((value * u16::MAX as i64 + (1 << (FRACTION_BITS - 1))) >> FRACTION_BITS) as u16,
+            )
+        })
+        .collect()
+}
+
+/// Appends and fills the mip chain of the texel data and wraps it into a node image.
+pub(crate) fn node_image(attachment: &AtlasAttachment, mut data: Vec<u8>) -> Image {
+    let size = attachment.texture_size;
+    // the data of the formats inaccessible on the CPU is synthesized as 16 bit heights
+    let (pixel_size, channel_count) = attachment.pixel_layout().unwrap_or((2, 1));
+
+    let mip_size =
+        |mip_level: u32| ((size >> mip_level).pow(2) as usize) * pixel_size * channel_count;
+    data.resize((0..attachment.mip_level_count).map(mip_size).sum(), 0);
+    generate_mipmaps(
+        &mut data,
+        size,
+        pixel_size as u32,
+        channel_count as u32,
+        attachment.mip_level_count,
+    );
+
+    let mut image = Image { data, ..default() };
+    image.texture_descriptor.size = Extent3d {
+        width: size,
+        height: size,
+        depth_or_array_layers: 1,
+    };
+    image.texture_descriptor.mip_level_count = attachment.mip_level_count;
+    image.texture_descriptor.format = attachment.format();
+    image.texture_descriptor.usage |= TextureUsages::COPY_SRC;
+    image
+}
+
+/// Loads the authored node from disk or synthesizes it from the procedural height.
+fn generate_node(
+    node_id: NodeId,
+    attachment: &AtlasAttachment,
+    authored_path: &str,
+    file_format: FileFormat,
+    leaf_node_size: u32,
+    height: &ProceduralHeight,
+) -> Image {
+    let data = load_image(authored_path, file_format)
+        .and_then(|image| authored_data(image, attachment.format()))
+        .unwrap_or_else(|| match height {
+            ProceduralHeight::Function(height) => {
+                synthesize_data(node_id, attachment, leaf_node_size, height.as_ref())
+            }
+            ProceduralHeight::Seeded(noise) => {
+                synthesize_seeded_data(node_id, attachment, leaf_node_size, noise)
+            }
+        });
+
+    node_image(attachment, data)
+}
+
+impl NodeSource for ProceduralSource {
+    fn request(&mut self, node_id: NodeId) {
+        let attachment = self.attachment.clone();
+        let authored_path = format!("{}/{node_id}", self.directory);
+        let file_format = self.file_format;
+        let leaf_node_size = self.leaf_node_size;
+        let height = self.height.clone();
+
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            generate_node(
+                node_id,
+                &attachment,
+                &authored_path,
+                file_format,
+                leaf_node_size,
+                &height,
+            )
+        });
+
+        self.tasks.push((node_id, task));
+    }
+
+    fn poll(&mut self, images: &mut Assets<Image>) -> Vec<(NodeId, Result<Handle<Image>>)> {
+        let mut finished = Vec::new();
+
+        self.tasks.retain_mut(
+            |(node_id, task)| match future::block_on(future::poll_once(task)) {
+                Some(image) => {
+                    finished.push((*node_id, Ok(images.add(image))));
+                    false
+                }
+                None => true,
+            },
+        );
+
+        finished
+    }
+}
+
+/// The heights of a heightmap, down sampled once per lod, alongside the height bounds of
+/// blocks of pixels, which double in size per level.
+struct HeightPyramid {
+    /// The size and the normalized heights of each level.
+    levels: Vec<(UVec2, Vec<f32>)>,
+    /// The size and the min and max heights of the blocks of each level.
+    bounds: Vec<(UVec2, Vec<Vec2>)>,
+    /// The number of heightmap pixels per unit of the terrain (at level zero).
+    scale: f32,
+}
+
+impl HeightPyramid {
+    fn new(heightmap: &Image, terrain_extent: UVec2, lod_count: u32) -> Self {
+        let size = heightmap.size().as_uvec2();
+        let data = &heightmap.data;
+
+        let heights = match heightmap.texture_descriptor.format {
+            TextureFormat::R8Unorm => data.iter().map(|&value| value as f32 / 255.0).collect(),
+            TextureFormat::R16Unorm => data
+                .chunks_exact(2)
+                .map(|pixel| u16::from_le_bytes([pixel[0], pixel[1]]) as f32 / u16::MAX as f32)
+                .collect(),
+            TextureFormat::R32Float => data
+                .chunks_exact(4)
+                .map(|pixel| f32::from_le_bytes(pixel.try_into().unwrap()))
+                .collect(),
+            // only the red channel contains the height
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => data
+                .chunks_exact(4)
+                .map(|pixel| pixel[0] as f32 / 255.0)
+                .collect(),
+            format => {
+                error!("Heightmaps of the format {format:?} are not supported.");
+                vec![0.0; (size.x * size.y) as usize]
+            }
+        };
+
+        let mut bounds = vec![(
+            size,
+            heights.iter().map(|&height| Vec2::splat(height)).collect(),
+        )];
+
+        // the odd pixels at the edges are kept, so that the blocks cover the entire heightmap
+        while bounds.last().unwrap().0.max_element() > 1 {
+            let (size, block_bounds) = bounds.last().unwrap();
+            let next_size = (*size + 1) / 2;
+
+            let get = |x: u32, y: u32| {
+                block_bounds[(y.min(size.y - 1) * size.x + x.min(size.x - 1)) as usize]
+            };
+
+            let next_bounds = iproduct!(0..next_size.y, 0..next_size.x)
+                .map(|(y, x)| {
+                    iproduct!(0..2, 0..2)
+                        .map(|(dy, dx)| get(2 * x + dx, 2 * y + dy))
+                        .fold(Vec2::new(f32::MAX, f32::MIN), |bounds, child| {
+                            Vec2::new(bounds.x.min(child.x), bounds.y.max(child.y))
+                        })
+                })
+                .collect();
+
+            bounds.push((next_size, next_bounds));
+        }
+
+        let mut levels = vec![(size, heights)];
+
+        for _ in 1..lod_count {
+            let (size, heights) = levels.last().unwrap();
+            let next_size = (*size / 2).max(UVec2::ONE);
+
+            let get =
+                |x: u32, y: u32| heights[(y.min(size.y - 1) * size.x + x.min(size.x - 1)) as usize];
+
+            let next_heights = iproduct!(0..next_size.y, 0..next_size.x)
+                .map(|(y, x)| {
+                    (get(2 * x, 2 * y)
+                        + get(2 * x + 1, 2 * y)
+                        + get(2 * x, 2 * y + 1)
+                        + get(2 * x + 1, 2 * y + 1))
+                        / 4.0
+                })
+                .collect();
+
+            levels.push((next_size, next_heights));
+        }
+
+        Self {
+            levels,
+            bounds,
+            scale: size.x as f32 / terrain_extent.x as f32,
+        }
+    }
+
+    /// Returns the min and max height of the bilinear heightmap across the footprint
+    /// (in the local space of the terrain) centered at the position.
+    ///
+    /// The footprint is covered by the blocks of the coarsest level, that are at most half
+    /// its size, which bounds it conservatively.
+    fn bounds(&self, position: Vec2, footprint: f32) -> Vec2 {
+        let pixel = position * self.scale - 0.5;
+        let footprint = footprint * self.scale;
+
+        let max = self.bounds[0].0.as_ivec2() - 1;
+        let start = (pixel - 0.5 * footprint)
+            .floor()
+            .as_ivec2()
+            .clamp(IVec2::ZERO, max)
+            .as_uvec2();
+        let end = (pixel + 0.5 * footprint)
+            .ceil()
+            .as_ivec2()
+            .clamp(IVec2::ZERO, max)
+            .as_uvec2();
+
+        let level = ((0.5 * footprint).max(1.0).log2().floor() as usize).min(self.bounds.len() - 1);
+        let (size, bounds) = &self.bounds[level];
+
+        iproduct!(
+            start.y >> level..=end.y >> level,
+            start.x >> level..=end.x >> level
+        )
+        .map(|(y, x)| bounds[(y.min(size.y - 1) * size.x + x.min(size.x - 1)) as usize])
+        .fold(Vec2::new(f32::MAX, f32::MIN), |bounds, block| {
+            Vec2::new(bounds.x.min(block.x), bounds.y.max(block.y))
+        })
+    }
+
+    /// Bilinearly samples the height at the position (in the local space of the terrain),
+    /// from the level matching the lod.
+    fn sample(&self, lod: u32, position: Vec2) -> f32 {
+        let level = (lod as usize).min(self.levels.len() - 1);
+        let (size, heights) = &self.levels[level];
+
+        let pixel = position * self.scale / (1 << level) as f32 - 0.5;
+        let get = |x: i32, y: i32| {
+            let x = x.clamp(0, size.x as i32 - 1) as u32;
+            let y = y.clamp(0, size.y as i32 - 1) as u32;
+            heights[(y * size.x + x) as usize]
+        };
+
+        let base = pixel.floor();
+        let t = pixel - base;
+        let (x, y) = (base.x as i32, base.y as i32);
+
+        let top = get(x, y) * (1.0 - t.x) + get(x + 1, y) * t.x;
+        let bottom = get(x, y + 1) * (1.0 - t.x) + get(x + 1, y + 1) * t.x;
+
+        top * (1.0 - t.y) + bottom * t.y
+    }
+}
+
+/// The pyramid of a heightmap, which is shared by the sources of its attachments.
+#[derive(Default)]
+struct SharedPyramid {
+    pyramid: Option<Arc<HeightPyramid>>,
+    task: Option<Task<HeightPyramid>>,
+}
+
+/// A node source, that slices a heightmap image into the height or minmax data of the nodes.
+///
+/// Once the image has been loaded, it is down sampled for each lod in the [`AsyncComputeTaskPool`].
+/// Afterwards the requested nodes are resampled from the matching level, also asynchronously.
+/// The minmax nodes store the true height bounds of the footprint of each texel.
+/// The sources of the height and the minmax attachment share the pyramid, so that it is only
+/// built once.
+/// This is intended for prototyping small terrains, without preprocessing them upfront.
+/// The heightmap is stretched across the entire terrain.
+pub struct ImageSource {
+    heightmap: Handle<Image>,
+    attachment: AtlasAttachment,
+    leaf_node_size: u32,
+    terrain_extent: UVec2,
+    lod_count: u32,
+    pyramid: Arc<Mutex<SharedPyramid>>,
+    /// The nodes, that have been requested before the pyramid was ready.
+    requested: Vec<NodeId>,
+    tasks: Vec<(NodeId, Task<Image>)>,
+}
+
+impl ImageSource {
+    /// Creates an image source for the height (R16 or R32F) or minmax (Rg16) attachment.
+    ///
+    /// The heightmap may be stored as R8, R16, R32F or RGBA8 (using the red channel) image.
+    pub fn new(
+        config: &TerrainConfig,
+        attachment: &AttachmentConfig,
+        heightmap: Handle<Image>,
+    ) -> Self {
+        Self {
+            heightmap,
+            attachment: attachment.clone().into(),
+            leaf_node_size: config.leaf_node_size,
+            terrain_extent: config.terrain_extent,
+            lod_count: config.lod_count,
+            pyramid: default(),
+            requested: Vec::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Creates an image source for another attachment of the same heightmap,
+    /// which shares the pyramid with this one.
+    pub fn sibling(&self, attachment: &AttachmentConfig) -> Self {
+        Self {
+            heightmap: self.heightmap.clone(),
+            attachment: attachment.clone().into(),
+            leaf_node_size: self.leaf_node_size,
+            terrain_extent: self.terrain_extent,
+            lod_count: self.lod_count,
+            pyramid: self.pyramid.clone(),
+            requested: Vec::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Returns the pyramid, once it has been built.
+    ///
+    /// The pyramid is built by the first source, that polls it after the image has been loaded.
+    fn poll_pyramid(&self, images: &Assets<Image>) -> Option<Arc<HeightPyramid>> {
+        let mut shared = self.pyramid.lock().unwrap();
+
+        if shared.pyramid.is_none() {
+            let pyramid = match &mut shared.task {
+                Some(task) => future::block_on(future::poll_once(task)),
+                None => {
+                    if let Some(heightmap) = images.get(&self.heightmap) {
+                        let heightmap = heightmap.clone();
+                        let (terrain_extent, lod_count) = (self.terrain_extent, self.lod_count);
+
+                        shared.task = Some(AsyncComputeTaskPool::get().spawn(async move {
+                            HeightPyramid::new(&heightmap, terrain_extent, lod_count)
+                        }));
+                    }
+
+                    None
+                }
+            };
+
+            if let Some(pyramid) = pyramid {
+                shared.pyramid = Some(Arc::new(pyramid));
+                shared.task = None;
+            }
+        }
+
+        shared.pyramid.clone()
+    }
+
+    fn spawn_node(&mut self, node_id: NodeId, pyramid: Arc<HeightPyramid>) {
+        let attachment = self.attachment.clone();
+        let leaf_node_size = self.leaf_node_size;
+
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            let lod = NodeCoordinate::from(node_id).lod;
+
+            let data = if attachment.format() == TextureFormat::Rg16Unorm {
+                let footprint = (leaf_node_size << lod) as f32 / attachment.center_size as f32;
+
+                synthesize_bounds(node_id, &attachment, leaf_node_size, |position| {
+                    pyramid.bounds(position, footprint)
+                })
+            } else {
+                synthesize_data(node_id, &attachment, leaf_node_size, |position| {
+                    pyramid.sample(lod, position)
+                })
+            };
+
+            node_image(&attachment, data)
+        });
+
+        self.tasks.push((node_id, task));
+    }
+}
+
+impl NodeSource for ImageSource {
+    fn request(&mut self, node_id: NodeId) {
+        let pyramid = self.pyramid.lock().unwrap().pyramid.clone();
+
+        match pyramid {
+            Some(pyramid) => self.spawn_node(node_id, pyramid),
+            None => self.requested.push(node_id),
+        }
+    }
+
+    fn poll(&mut self, images: &mut Assets<Image>) -> Vec<(NodeId, Result<Handle<Image>>)> {
+        if !self.requested.is_empty() {
+            if let Some(pyramid) = self.poll_pyramid(images) {
+                for node_id in mem::take(&mut self.requested) {
+                    self.spawn_node(node_id, pyramid.clone());
+                }
+            }
+        }
+
+        let mut finished = Vec::new();
+
+        self.tasks.retain_mut(
+            |(node_id, task)| match future::block_on(future::poll_once(task)) {
+                Some(image) => {
+                    finished.push((*node_id, Ok(images.add(image))));
+                    false
+                }
+                None => true,
+            },
+        );
+
+        finished
+    }
+}
@@ -0,0 +1,56 @@
+//! Floating origin support for very large terrains.
+//!
+//! Far away from the origin, the precision of the world space positions degrades, which
+//! causes jittering of the camera and the terrain.
+//! A floating origin counters this, by periodically shifting the entire world, so that the
+//! viewer stays close to the origin.
+//!
+//! The streaming of the terrain happens entirely in the local space of the terrain, which means
+//! that the requested nodes are not affected by shifting the world, as long as the terrains and
+//! their views are moved together.
+//! Therefore floating origin crates, that move all entities (or compute the [`GlobalTransform`]s
+//! relative to the origin), work out of the box.
+//! Alternatively, send a [`ShiftOrigin`] event to move all top-level entities.
+
+use bevy::{math::DVec3, prelude::*};
+
+/// The accumulated offset of the world origin (in double precision).
+///
+/// Adding this offset to a world space position yields the absolute position of the
+/// point, as if the origin has never been shifted.
+#[derive(Clone, Copy, Default, Resource, Reflect)]
+#[reflect(Resource)]
+pub struct WorldOrigin(pub DVec3);
+
+/// An event, which shifts the origin of the world to the position (in world space).
+///
+/// All top-level entities, including the terrains and their views, are moved by the negated
+/// offset at the beginning of the next frame.
+/// The state of other crates, that keeps track of world space positions (e.g. camera controllers),
+/// has to be updated manually.
+pub struct ShiftOrigin {
+    /// The offset from the current origin to the new one.
+    pub offset: Vec3,
+}
+
+/// Moves all top-level entities, so that the offset becomes the new origin of the world.
+pub(crate) fn shift_origin(
+    mut origin: ResMut<WorldOrigin>,
+    mut shift_events: EventReader<ShiftOrigin>,
+    mut transform_query: Query<&mut Transform, Without<Parent>>,
+) {
+    let offset = shift_events
+        .iter()
+        .fold(Vec3::ZERO, |offset, event| offset + event.offset);
+
+    if offset == Vec3::ZERO {
+        return;
+    }
+
+    origin.0 += offset.as_dvec3();
+
+    // children are moved with their parents
+    for mut transform in transform_query.iter_mut() {
+        transform.translation -= offset;
+    }
+}
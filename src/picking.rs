@@ -0,0 +1,104 @@
+//! Picks the position of the cursor on the terrain.
+//!
+//! The [`TerrainPicker`] system parameter converts a screen position into a ray of the camera,
+//! which is cast against the streamed heightfields of all terrains (see [`TerrainSampler`]),
+//! so that editors and strategy games get the exact position under the cursor,
+//! without any physics collider.
+//!
+//! Add the [`TerrainPickingPlugin`] to update the [`TerrainCursor`] resource each frame
+//! with the terrain position under the cursor of the [`TerrainPickingCamera`].
+
+use crate::terrain_data::{raycast::TerrainHit, sampling::TerrainSampler};
+use bevy::{
+    ecs::system::SystemParam,
+    prelude::*,
+    render::camera::RenderTarget,
+    transform::TransformSystem,
+    window::{PrimaryWindow, WindowRef},
+};
+
+/// Marks the camera, whose cursor position is picked by the [`TerrainPickingPlugin`].
+#[derive(Clone, Copy, Default, Component)]
+pub struct TerrainPickingCamera;
+
+/// The terrain position under the cursor of the [`TerrainPickingCamera`].
+#[derive(Clone, Copy, Debug, Default, Resource)]
+pub struct TerrainCursor {
+    /// The picked terrain entity and the intersection with it,
+    /// or `None` if the cursor is not above any loaded part of a terrain.
+    pub hit: Option<(Entity, TerrainHit)>,
+}
+
+impl TerrainCursor {
+    /// The world space position under the cursor.
+    pub fn position(&self) -> Option<Vec3> {
+        self.hit.map(|(_, hit)| hit.position)
+    }
+}
+
+/// A system parameter, used to pick the terrain at screen positions.
+#[derive(SystemParam)]
+pub struct TerrainPicker<'w, 's> {
+    sampler: TerrainSampler<'w, 's>,
+    camera_query: Query<'w, 's, (&'static Camera, &'static GlobalTransform)>,
+    window_query: Query<'w, 's, (&'static Window, Option<&'static PrimaryWindow>)>,
+}
+
+impl<'w, 's> TerrainPicker<'w, 's> {
+    /// Casts the ray of the camera through the screen position against all terrains.
+    ///
+    /// The position is measured in logical pixels from the bottom left corner of the viewport,
+    /// like the cursor position of the window.
+    ///
+    /// Returns the closest intersection and the corresponding terrain entity.
+    pub fn screen_to_terrain(&self, cursor: Vec2, camera: Entity) -> Option<(Entity, TerrainHit)> {
+        let (camera, transform) = self.camera_query.get(camera).ok()?;
+        let ray = camera.viewport_to_world(transform, cursor)?;
+
+        self.sampler.raycast(ray)
+    }
+
+    /// Casts the ray of the camera through the cursor of its window against all terrains.
+    ///
+    /// Returns `None` if the camera does not render to a window or the cursor is outside of it.
+    pub fn cursor_to_terrain(&self, camera: Entity) -> Option<(Entity, TerrainHit)> {
+        let (camera_component, _) = self.camera_query.get(camera).ok()?;
+
+        let window = match camera_component.target {
+            RenderTarget::Window(WindowRef::Primary) => self
+                .window_query
+                .iter()
+                .find(|(_, primary)| primary.is_some())
+                .map(|(window, _)| window)?,
+            RenderTarget::Window(WindowRef::Entity(entity)) => {
+                self.window_query.get(entity).ok()?.0
+            }
+            RenderTarget::Image(_) => return None,
+        };
+
+        self.screen_to_terrain(window.cursor_position()?, camera)
+    }
+}
+
+/// Updates the [`TerrainCursor`] resource each frame.
+pub struct TerrainPickingPlugin;
+
+impl Plugin for TerrainPickingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TerrainCursor>().add_system(
+            update_terrain_cursor
+                .after(TransformSystem::TransformPropagate)
+                .in_base_set(CoreSet::PostUpdate),
+        );
+    }
+}
+
+fn update_terrain_cursor(
+    picker: TerrainPicker,
+    mut cursor: ResMut<TerrainCursor>,
+    camera_query: Query<Entity, With<TerrainPickingCamera>>,
+) {
+    cursor.hit = camera_query
+        .iter()
+        .find_map(|camera| picker.cursor_to_terrain(camera));
+}
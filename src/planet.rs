@@ -0,0 +1,101 @@
+//! Support for spherical planets, which consist of six terrains on the faces of a cube.
+//!
+//! Each face is a regular terrain, whose transform rotates it onto its side of the cube and
+//! whose local positions are projected onto the sphere (see [`TerrainConfig::with_planet_radius`]).
+//! Therefore the streaming and the node atlas work exactly like for flat terrains,
+//! only the viewer is projected onto the faces and the lod is selected based on the distance
+//! along the curved surface.
+//!
+//! The node data of the faces is still addressed in the (flat) local space of each face.
+//! Procedural sources can use [`face_to_sphere`] to sample continuous 3D functions, which
+//! keeps the edges between the faces seamless.
+//! Height queries, raycasts and collisions are not aware of the curvature yet.
+
+use crate::terrain::TerrainConfig;
+use bevy::{
+    math::{DVec2, DVec3},
+    prelude::*,
+};
+use std::f32::consts::FRAC_PI_2;
+
+/// A face of the cube, which is projected onto the sphere of a planet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CubeFace {
+    PositiveX,
+    NegativeX,
+    PositiveY,
+    NegativeY,
+    PositiveZ,
+    NegativeZ,
+}
+
+impl CubeFace {
+    /// All six faces of the cube.
+    pub const ALL: [CubeFace; 6] = [
+        CubeFace::PositiveX,
+        CubeFace::NegativeX,
+        CubeFace::PositiveY,
+        CubeFace::NegativeY,
+        CubeFace::PositiveZ,
+        CubeFace::NegativeZ,
+    ];
+
+    /// The rotation, which turns the up axis of the terrain into the normal of the face.
+    pub fn rotation(self) -> Quat {
+        match self {
+            CubeFace::PositiveX => Quat::from_rotation_z(-FRAC_PI_2),
+            CubeFace::NegativeX => Quat::from_rotation_z(FRAC_PI_2),
+            CubeFace::PositiveY => Quat::IDENTITY,
+            CubeFace::NegativeY => Quat::from_rotation_x(2.0 * FRAC_PI_2),
+            CubeFace::PositiveZ => Quat::from_rotation_x(FRAC_PI_2),
+            CubeFace::NegativeZ => Quat::from_rotation_x(-FRAC_PI_2),
+        }
+    }
+
+    /// The transform of the terrain of this face, for a planet centered at the position.
+    ///
+    /// The scale converts the local units of the terrain (pixels) into world units.
+    pub fn transform(self, center: Vec3, scale: f32) -> Transform {
+        Transform {
+            translation: center,
+            rotation: self.rotation(),
+            scale: Vec3::splat(scale),
+        }
+    }
+}
+
+/// Maps a position in the local space of a face onto the sphere with the radius (in local units).
+///
+/// The resulting position is relative to the center of the planet, but still oriented
+/// like the face (the normal of the face points along the up axis).
+pub fn face_to_sphere(local_position: DVec2, height: f64, radius: f64, extent: DVec2) -> DVec3 {
+    let cube_position = DVec3::new(
+        2.0 * local_position.x / extent.x - 1.0,
+        1.0,
+        2.0 * local_position.y / extent.y - 1.0,
+    );
+
+    cube_position.normalize() * (radius + height)
+}
+
+/// Projects a position relative to the center of the planet (oriented like the face)
+/// onto the face.
+///
+/// Positions, which lie above other faces, are clamped onto the edge of this face.
+pub fn project_onto_face(position: DVec3, extent: DVec2) -> DVec2 {
+    let cube_position =
+        (position.xz() / position.y.max(f64::EPSILON)).clamp(-DVec2::ONE, DVec2::ONE);
+
+    (cube_position + 1.0) / 2.0 * extent
+}
+
+impl TerrainConfig {
+    /// Turns the terrain into a face of a planet, with the radius (in local units).
+    ///
+    /// A radius of half the terrain size keeps the resolution of the cube faces roughly uniform.
+    /// Use [`CubeFace::transform`] to position the six faces of the planet.
+    pub fn with_planet_radius(mut self, radius: f32) -> Self {
+        self.planet_radius = Some(radius);
+        self
+    }
+}
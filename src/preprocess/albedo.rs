@@ -0,0 +1,30 @@
+//! Drapes color imagery (e.g. aerial or satellite orthophotos) over the terrain.
+//!
+//! The albedo attachment is split into nodes like any other attachment and sampled by the
+//! default terrain shader as the base color of the surface, once its index is configured as the
+//! [`TerrainPlugin::albedo_attachment`](crate::TerrainPlugin).
+//! The imagery has to cover the same region as the heightmap, but its resolution may be a
+//! multiple of the one of the heightmap, as long as the center size of the albedo attachment
+//! is scaled by the same factor.
+
+use crate::terrain_data::{AttachmentConfig, AttachmentFormat, AttachmentIndex};
+
+/// The index of the albedo attachment, if it is added directly after the normal attachment,
+/// like the preprocessing binary does.
+pub const ALBEDO_ATTACHMENT: AttachmentIndex = 5;
+
+/// Creates the config of the albedo attachment, whose center covers `scale` pixels of the imagery
+/// per pixel of the height attachment.
+pub fn albedo_attachment(
+    leaf_node_size: u32,
+    scale: u32,
+    mip_level_count: u32,
+) -> AttachmentConfig {
+    AttachmentConfig::new(
+        "albedo".to_string(),
+        leaf_node_size * scale + 2,
+        1,
+        mip_level_count,
+        AttachmentFormat::Rgb8,
+    )
+}
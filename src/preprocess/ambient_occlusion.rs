@@ -0,0 +1,106 @@
+//! Bakes the ambient occlusion of the terrain from its heights.
+//!
+//! The occlusion of each texel is estimated from the elevation of the horizon along several
+//! directions around it. This darkens valleys and crevices, which only see a part of the sky.
+//! The occlusion is baked for the nodes of the highest level of detail and then down sampled
+//! and streamed like any other attachment.
+
+use crate::{
+    preprocess::bake::{bake_attachment, slope_to_sine, HeightSampler},
+    terrain_data::{AttachmentConfig, AttachmentFormat, AttachmentIndex},
+    TerrainConfig,
+};
+use bevy::prelude::*;
+use image::Luma;
+use std::f32::consts::TAU;
+
+/// The index of the ambient occlusion attachment, which is sampled by the default terrain shader,
+/// if it is added directly after the base attachments.
+pub const AMBIENT_OCCLUSION_ATTACHMENT: AttachmentIndex = 2;
+
+/// The configuration of an ambient occlusion attachment, which is baked from the heights.
+#[derive(Clone, Copy, Debug)]
+pub struct AmbientOcclusionConfig {
+    /// The number of directions, along which the horizon is searched.
+    pub direction_count: u32,
+    /// The number of heights sampled along each direction.
+    pub step_count: u32,
+    /// The distance (measured in texels of the highest lod), up to which the horizon is searched.
+    pub radius: f32,
+    /// Scales the darkening of the occluded texels, where zero disables it.
+    pub strength: f32,
+}
+
+impl Default for AmbientOcclusionConfig {
+    fn default() -> Self {
+        Self {
+            direction_count: 8,
+            step_count: 8,
+            radius: 32.0,
+            strength: 1.0,
+        }
+    }
+}
+
+impl AmbientOcclusionConfig {
+    /// Creates the config of the ambient occlusion attachment.
+    pub fn attachment(texture_size: u32, mip_level_count: u32) -> AttachmentConfig {
+        AttachmentConfig::new(
+            "ambient_occlusion".to_string(),
+            texture_size,
+            1,
+            mip_level_count,
+            AttachmentFormat::R16,
+        )
+    }
+}
+
+/// Estimates the ambient occlusion (from zero for occluded to one for unoccluded) of the texel.
+fn occlusion(
+    sampler: &mut HeightSampler,
+    ambient_occlusion: &AmbientOcclusionConfig,
+    height: f32,
+    position: IVec2,
+) -> f32 {
+    let direction_count = ambient_occlusion.direction_count.max(1);
+
+    let mut occlusion = 0.0;
+
+    for direction in 0..direction_count {
+        let angle = TAU * direction as f32 / direction_count as f32;
+        let direction = Vec2::new(angle.cos(), angle.sin());
+
+        let Some(slope) = sampler.horizon_slope(
+            position,
+            direction,
+            ambient_occlusion.radius,
+            ambient_occlusion.step_count,
+            height,
+        ) else {
+            return 1.0;
+        };
+
+        // the sine of the elevation of the horizon
+        occlusion += slope_to_sine(slope);
+    }
+
+    (1.0 - ambient_occlusion.strength * occlusion / direction_count as f32).clamp(0.0, 1.0)
+}
+
+pub(crate) fn bake_ambient_occlusion(
+    config: &TerrainConfig,
+    height_attachment: &AttachmentConfig,
+    ambient_occlusion: &AmbientOcclusionConfig,
+    attachment: &AttachmentConfig,
+) {
+    bake_attachment(
+        config,
+        height_attachment,
+        attachment,
+        |sampler, position| {
+            let value = occlusion(sampler, ambient_occlusion, config.height, position);
+
+            Luma([(value * u16::MAX as f32).round() as u16])
+        },
+    );
+}
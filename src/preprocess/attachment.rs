@@ -1,6 +1,8 @@
 use crate::{
+    erosion::thermal::ThermalErosion,
     preprocess::{
         down_sample::{down_sample_layer, linear, minmax},
+        erosion::erode_height_nodes,
         file_io::{
             format_directory, format_node_path, iterate_directory, load_image, reset_directory,
             save_image,
@@ -9,7 +11,7 @@ use crate::{
         stitch::stitch_layer,
         BaseConfig, TileConfig, UVec2Utils,
     },
-    terrain_data::{AttachmentConfig, NodeCoordinate, NodeId},
+    terrain_data::{AttachmentConfig, AttachmentFormat, NodeCoordinate, NodeId},
     TerrainConfig,
 };
 use image::{DynamicImage, ImageBuffer, LumaA};
@@ -30,23 +32,40 @@ fn height_to_minmax(
         let minmax_path = format_node_path(minmax_directory, coord.lod, coord.x, coord.y);
 
         let height_image = load_image(&height_path, height_attachment.file_format).unwrap();
-        let height_image = height_image.as_luma16().unwrap();
 
-        let minmax_image = DynamicImage::from(ImageBuffer::from_fn(
-            height_image.width(),
-            height_image.height(),
-            |x, y| {
-                let value = height_image.get_pixel(x, y).0[0];
+        let minmax_image = match height_attachment.format {
+            AttachmentFormat::R32F => {
+                let height_image = height_image.as_rgb32f().unwrap();
 
-                LumaA([value, value])
-            },
-        ));
+                // round outwards, so that the 16 bit bounds contain the float height
+                ImageBuffer::from_fn(height_image.width(), height_image.height(), |x, y| {
+                    let value = height_image.get_pixel(x, y).0[0].clamp(0.0, 1.0) * u16::MAX as f32;
+
+                    LumaA([value.floor() as u16, value.ceil() as u16])
+                })
+            }
+            _ => {
+                let height_image = height_image.as_luma16().unwrap();
+
+                ImageBuffer::from_fn(height_image.width(), height_image.height(), |x, y| {
+                    let value = height_image.get_pixel(x, y).0[0];
+
+                    LumaA([value, value])
+                })
+            }
+        };
+        let minmax_image = DynamicImage::from(minmax_image);
 
         save_image(&minmax_path, &minmax_image, minmax_attachment);
     }
 }
 
-pub(crate) fn preprocess_base(config: &TerrainConfig, tile: &TileConfig, base: &BaseConfig) {
+pub(crate) fn preprocess_base(
+    config: &TerrainConfig,
+    tile: &TileConfig,
+    base: &BaseConfig,
+    thermal_erosion: Option<&ThermalErosion>,
+) {
     let height_attachment = base.height_attachment();
     let minmax_attachment = base.minmax_attachment();
 
@@ -58,6 +77,17 @@ pub(crate) fn preprocess_base(config: &TerrainConfig, tile: &TileConfig, base: &
 
     let temp = split_tiles(&height_directory, tile, &height_attachment);
 
+    if let Some(erosion) = thermal_erosion {
+        erode_height_nodes(
+            config,
+            &height_directory,
+            &height_attachment,
+            erosion,
+            temp.0,
+            temp.1,
+        );
+    }
+
     let (mut first, mut last) = temp;
 
     for lod in 1..config.lod_count {
@@ -0,0 +1,175 @@
+//! Shared functionality for attachments, which are baked from the preprocessed heights
+//! instead of being split from source tiles, e.g. the ambient occlusion or the horizon map.
+
+use crate::{
+    preprocess::{
+        down_sample::{down_sample_layer, linear},
+        file_io::{
+            format_directory, format_node_path, iterate_directory, load_image, reset_directory,
+            save_image,
+        },
+        stitch::stitch_layer,
+        UVec2Utils,
+    },
+    terrain_data::{AttachmentConfig, NodeCoordinate, NodeId},
+    TerrainConfig,
+};
+use bevy::{prelude::*, utils::HashMap};
+use image::{DynamicImage, ImageBuffer, Luma, Pixel};
+
+type HeightImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Samples the heights of the highest lod across the borders of the nodes.
+///
+/// The nodes are loaded on demand and kept, until they are evicted explicitly.
+pub(crate) struct HeightSampler<'a> {
+    directory: String,
+    attachment: &'a AttachmentConfig,
+    /// The size of one texel of the highest lod in the local space of the terrain.
+    texel_size: f32,
+    nodes: HashMap<UVec2, Option<HeightImage>>,
+}
+
+impl<'a> HeightSampler<'a> {
+    fn new(config: &TerrainConfig, attachment: &'a AttachmentConfig) -> Self {
+        Self {
+            directory: format_directory(&config.path, &attachment.name),
+            attachment,
+            texel_size: config.leaf_node_size as f32 / attachment.center_size as f32,
+            nodes: default(),
+        }
+    }
+
+    /// Returns the normalized height at the texel, which is specified in the local space
+    /// of the terrain, or `None` outside of the nodes.
+    pub(crate) fn height(&mut self, position: IVec2) -> Option<f32> {
+        if position.min_element() < 0 {
+            return None;
+        }
+
+        let position = position.as_uvec2();
+        let node = position / self.attachment.center_size;
+        let pixel = position % self.attachment.center_size + self.attachment.border_size;
+
+        let (directory, attachment) = (&self.directory, self.attachment);
+
+        let node_image = self.nodes.entry(node).or_insert_with(|| {
+            let path = format_node_path(directory, 0, node.x, node.y);
+
+            load_image(&path, attachment.file_format).map(|node_image| node_image.to_luma32f())
+        });
+
+        node_image
+            .as_ref()
+            .map(|node_image| node_image.get_pixel(pixel.x, pixel.y).0[0])
+    }
+
+    /// Returns the steepest slope from the texel towards the horizon in the direction,
+    /// which is searched up to the radius (measured in texels) with the number of steps.
+    ///
+    /// The slope is measured in the local space of the terrain, where the normalized heights
+    /// are scaled by the height and the distances by the size of the texels.
+    pub(crate) fn horizon_slope(
+        &mut self,
+        position: IVec2,
+        direction: Vec2,
+        radius: f32,
+        step_count: u32,
+        height: f32,
+    ) -> Option<f32> {
+        let center = self.height(position)?;
+        let step_count = step_count.max(1);
+
+        let mut slope = 0.0_f32;
+
+        for step in 1..=step_count {
+            let distance = (radius * step as f32 / step_count as f32).max(1.0);
+            let sample_position = (position.as_vec2() + direction * distance)
+                .round()
+                .as_ivec2();
+
+            if let Some(sample) = self.height(sample_position) {
+                slope = slope.max((sample - center) * height / (distance * self.texel_size));
+            }
+        }
+
+        Some(slope)
+    }
+
+    /// Releases the nodes, which lie above the row of nodes.
+    fn evict_rows_above(&mut self, row: u32) {
+        self.nodes.retain(|node, _| node.y + 1 >= row);
+    }
+}
+
+/// Converts the slope into the sine of its elevation angle.
+pub(crate) fn slope_to_sine(slope: f32) -> f32 {
+    slope / (1.0 + slope * slope).sqrt()
+}
+
+/// Bakes an attachment from the heights of the highest lod.
+///
+/// The `bake_texel` function is called with the position of each texel (in the local space
+/// of the terrain) and the nodes are baked row by row, so that only the neighbouring
+/// rows of height nodes are kept in memory.
+/// Afterwards the lower lods are down sampled and stitched like any other attachment.
+pub(crate) fn bake_attachment<P, F>(
+    config: &TerrainConfig,
+    height_attachment: &AttachmentConfig,
+    attachment: &AttachmentConfig,
+    mut bake_texel: F,
+) where
+    P: Pixel,
+    DynamicImage: From<ImageBuffer<P, Vec<P::Subpixel>>>,
+    F: FnMut(&mut HeightSampler, IVec2) -> P,
+{
+    let height_directory = format_directory(&config.path, &height_attachment.name);
+    let directory = format_directory(&config.path, &attachment.name);
+
+    reset_directory(&directory);
+
+    let mut nodes = iterate_directory(&height_directory)
+        .map(|(name, _)| NodeCoordinate::from(name.parse::<NodeId>().unwrap()))
+        .filter(|coord| coord.lod == 0)
+        .map(|coord| UVec2::new(coord.x, coord.y))
+        .collect::<Vec<_>>();
+
+    nodes.sort_by_key(|node| (node.y, node.x));
+
+    let mut sampler = HeightSampler::new(config, height_attachment);
+
+    // the texels of the node are measured relative to the node of the height attachment
+    let scale = height_attachment.center_size as f32 / attachment.center_size as f32;
+
+    let mut first = UVec2::splat(u32::MAX);
+    let mut last = UVec2::splat(u32::MIN);
+
+    for node in nodes {
+        sampler.evict_rows_above(node.y);
+
+        let origin = (node * height_attachment.center_size).as_vec2();
+
+        let node_image =
+            ImageBuffer::from_fn(attachment.texture_size, attachment.texture_size, |x, y| {
+                let pixel = UVec2::new(x, y).as_vec2() + 0.5 - attachment.border_size as f32;
+                let position = (origin + pixel * scale).floor().as_ivec2();
+
+                bake_texel(&mut sampler, position)
+            });
+        let node_image = DynamicImage::from(node_image);
+
+        let node_path = format_node_path(&directory, 0, node.x, node.y);
+        save_image(&node_path, &node_image, attachment);
+
+        first = first.min(node);
+        last = last.max(node + 1);
+    }
+
+    for lod in 1..config.lod_count {
+        first = first.div_floor(2);
+        last = last.div_ceil(2);
+
+        down_sample_layer(linear, &directory, attachment, lod, first, last);
+        stitch_layer(&directory, attachment, lod, first, last);
+    }
+}
@@ -1,19 +1,44 @@
 use crate::{
     formats::tc::TC,
-    preprocess::file_io::{format_directory, iterate_directory},
-    terrain_data::NodeId,
+    preprocess::file_io::{format_directory, iterate_directory, load_image},
+    terrain_data::{FileFormat, NodeId, HEIGHT_ATTACHMENT},
     TerrainConfig,
 };
+use bevy::prelude::*;
+use image::DynamicImage;
 
-/// Saves. s the node configuration of the terrain, which stores the [`NodeId`]s of all the nodes
-/// of the terrain.
-pub fn save_config(config: &TerrainConfig) {
-    let mut tc = TC { nodes: vec![] };
-    let attachment_directory = format_directory(&config.path, &config.attachments[0].name);
+/// Determines the normalized minimum and maximum height of the node image.
+fn height_bounds(node_image: &DynamicImage) -> (f32, f32) {
+    node_image
+        .to_luma32f()
+        .pixels()
+        .fold((f32::MAX, f32::MIN), |(min, max), pixel| {
+            (min.min(pixel[0]), max.max(pixel[0]))
+        })
+}
+
+/// Saves the node configuration of the terrain, which stores the [`NodeId`]s of all the nodes
+/// of the terrain, alongside their height bounds.
+///
+/// The height bounds are read from the height nodes, which are stored in the `file_format`.
+pub fn save_config(config: &TerrainConfig, file_format: FileFormat) {
+    let mut tc = TC {
+        nodes: vec![],
+        height_bounds: vec![],
+    };
+
+    let attachment_directory =
+        format_directory(&config.path, &config.attachments[HEIGHT_ATTACHMENT].name);
 
-    for (name, _) in iterate_directory(&attachment_directory) {
+    for (name, path) in iterate_directory(&attachment_directory) {
         let node_id = name.parse::<NodeId>().unwrap();
+
+        // compressed nodes can not be inspected, so they span the entire height range
+        let bounds = load_image(&path, file_format)
+            .map_or((0.0, 1.0), |node_image| height_bounds(&node_image));
+
         tc.nodes.push(node_id);
+        tc.height_bounds.push(bounds);
     }
 
     tc.save_file(format_directory(&config.path, "../config.tc"))
@@ -21,8 +46,15 @@ pub fn save_config(config: &TerrainConfig) {
 }
 
 /// Loads the node configuration of the terrain, which stores the [`NodeId`]s of all the nodes
-/// of the terrain.
+/// of the terrain, alongside their height bounds.
 pub fn load_node_config(config: &mut TerrainConfig) {
     let tc = TC::load_file(format_directory(&config.path, "../config.tc")).unwrap();
+
+    config.height_bounds = tc
+        .nodes
+        .iter()
+        .zip(&tc.height_bounds)
+        .map(|(&node_id, &(min, max))| (node_id, Vec2::new(min, max)))
+        .collect();
     config.nodes = tc.nodes.into_iter().collect();
 }
@@ -54,6 +54,15 @@ impl AveragePixel for LumaA<u16> {
     }
 }
 
+impl AveragePixel for Rgb<f32> {
+    fn average(a: Self, b: Self, c: Self, d: Self) -> Self {
+        let mut value = Rgb([0.0; 3]);
+        izip!(&mut value.0, &a.0, &b.0, &c.0, &d.0)
+            .for_each(|(out, &a, &b, &c, &d)| *out = (a + b + c + d) / 4.0);
+        value
+    }
+}
+
 type Filter = fn(&mut DynamicImage, &DynamicImage, &AttachmentConfig, UVec2);
 
 pub(crate) fn imageops_linear<I, J>(
@@ -104,7 +113,7 @@ pub(crate) fn linear(
                 attachment.border_size,
             );
         }
-        AttachmentFormat::Rgba8 => {
+        AttachmentFormat::Rgba8 | AttachmentFormat::Rgba8Linear => {
             imageops_linear(
                 parent_image.as_mut_rgba8().unwrap(),
                 child_image.as_rgba8().unwrap(),
@@ -134,6 +143,16 @@ pub(crate) fn linear(
                 attachment.border_size,
             );
         }
+        AttachmentFormat::R32F => {
+            imageops_linear(
+                parent_image.as_mut_rgb32f().unwrap(),
+                child_image.as_rgb32f().unwrap(),
+                child_size,
+                node_x,
+                node_y,
+                attachment.border_size,
+            );
+        }
     }
 }
 
@@ -0,0 +1,309 @@
+//! Imports elevation data from GIS formats as source tiles of the height attachment.
+//!
+//! Supported are GeoTIFF files (with an optional `GDAL_NODATA` tag) and classic USGS DEM files.
+//! The elevations are mapped from their elevation range onto the normalized height of the terrain
+//! and cells without data are filled with the lowest valid elevation of the tile.
+//!
+//! Besides the preprocessing, the [`ElevationAssetLoader`] loads these files as normalized
+//! R32F heightmaps at runtime, which are sliced into the nodes by the
+//! [`ImageSource`](crate::node_source::ImageSource), e.g. with
+//! [`TerrainConfig::add_base_attachment_from_image`](crate::terrain::TerrainConfig::add_base_attachment_from_image).
+//! Because `.tif` files are loaded as plain images, the GeoTIFF files have to use
+//! the `.geotiff` or `.gtif` extension for that.
+
+use crate::{
+    preprocess::{R16Image, R32FImage, TileConfig},
+    terrain_data::{AttachmentConfig, AttachmentFormat, FileFormat},
+};
+use anyhow::anyhow;
+use bevy::{
+    asset::{AssetLoader, Error, LoadContext, LoadedAsset},
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+    utils::BoxedFuture,
+};
+use image::{DynamicImage, Luma, Rgb};
+use std::{fs, io::Cursor};
+use tiff::{
+    decoder::{Decoder, DecodingResult, Limits},
+    tags::Tag,
+    ColorType,
+};
+
+/// The TIFF tag, which GDAL uses to store the value of cells without data.
+const GDAL_NODATA: u16 = 42113;
+/// The elevation value of cells without data in DEM files.
+const DEM_NODATA: f32 = -32767.0;
+/// The size of a logical record of a DEM file.
+const DEM_RECORD_SIZE: usize = 1024;
+
+/// The raw elevations of a tile, in row major order from north to south.
+struct Elevation {
+    width: u32,
+    height: u32,
+    values: Vec<f32>,
+    nodata: Option<f32>,
+}
+
+impl Elevation {
+    fn is_valid(&self, value: f32) -> bool {
+        value.is_finite() && self.nodata.map_or(true, |nodata| value != nodata)
+    }
+
+    /// Maps the elevations from the range (defaulting to the bounds of the valid elevations)
+    /// onto normalized heights between zero and one.
+    fn normalize(&self, range: Option<Vec2>) -> Vec<f32> {
+        let bounds = self
+            .values
+            .iter()
+            .filter(|&&value| self.is_valid(value))
+            .fold(Vec2::new(f32::MAX, f32::MIN), |bounds, &value| {
+                Vec2::new(bounds.x.min(value), bounds.y.max(value))
+            });
+
+        let range = range.unwrap_or(bounds);
+        let scale = if range.y > range.x {
+            1.0 / (range.y - range.x)
+        } else {
+            0.0
+        };
+
+        self.values
+            .iter()
+            .map(|&value| {
+                let value = if self.is_valid(value) {
+                    value
+                } else {
+                    bounds.x
+                };
+
+                ((value - range.x) * scale).clamp(0.0, 1.0)
+            })
+            .collect()
+    }
+
+    /// Converts the elevations into a height tile with the format of the attachment.
+    fn into_tile(self, tile: &TileConfig, attachment: &AttachmentConfig) -> DynamicImage {
+        let heights = self.normalize(tile.elevation_range);
+        let normalize = |x: u32, y: u32| heights[(y * self.width + x) as usize];
+
+        match attachment.format {
+            AttachmentFormat::R16 => {
+                DynamicImage::from(R16Image::from_fn(self.width, self.height, |x, y| {
+                    Luma([(normalize(x, y) * u16::MAX as f32).round() as u16])
+                }))
+            }
+            AttachmentFormat::R32F => {
+                DynamicImage::from(R32FImage::from_fn(self.width, self.height, |x, y| {
+                    Rgb([normalize(x, y); 3])
+                }))
+            }
+            _ => panic!("Elevation data can only be imported into a height attachment."),
+        }
+    }
+
+    /// Converts the elevations into a normalized R32F heightmap.
+    fn into_image(self) -> Image {
+        let data = self
+            .normalize(None)
+            .into_iter()
+            .flat_map(f32::to_le_bytes)
+            .collect();
+
+        Image::new(
+            Extent3d {
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::R32Float,
+        )
+    }
+}
+
+fn load_geotiff(bytes: &[u8]) -> Option<Elevation> {
+    let mut decoder = Decoder::new(Cursor::new(bytes))
+        .ok()?
+        .with_limits(Limits::unlimited());
+
+    let (width, height) = decoder.dimensions().ok()?;
+
+    let channel_count = match decoder.colortype().ok()? {
+        ColorType::Gray(_) => 1,
+        ColorType::GrayA(_) => 2,
+        ColorType::RGB(_) => 3,
+        ColorType::RGBA(_) => 4,
+        _ => return None,
+    };
+
+    let nodata = decoder
+        .get_tag_ascii_string(Tag::Unknown(GDAL_NODATA))
+        .ok()
+        .and_then(|nodata| nodata.trim_matches(char::from(0)).trim().parse().ok());
+
+    // only the first band contains the elevation
+    let values = match decoder.read_image().ok()? {
+        DecodingResult::U8(data) => data.into_iter().map(|value| value as f32).collect(),
+        DecodingResult::U16(data) => data.into_iter().map(|value| value as f32).collect(),
+        DecodingResult::U32(data) => data.into_iter().map(|value| value as f32).collect(),
+        DecodingResult::U64(data) => data.into_iter().map(|value| value as f32).collect(),
+        DecodingResult::I8(data) => data.into_iter().map(|value| value as f32).collect(),
+        DecodingResult::I16(data) => data.into_iter().map(|value| value as f32).collect(),
+        DecodingResult::I32(data) => data.into_iter().map(|value| value as f32).collect(),
+        DecodingResult::I64(data) => data.into_iter().map(|value| value as f32).collect(),
+        DecodingResult::F32(data) => data,
+        DecodingResult::F64(data) => data.into_iter().map(|value| value as f32).collect(),
+    }
+    .into_iter()
+    .step_by(channel_count)
+    .collect();
+
+    Some(Elevation {
+        width,
+        height,
+        values,
+        nodata,
+    })
+}
+
+/// Parses a fixed width field of a DEM record, which may use a Fortran style exponent.
+fn dem_field(record: &[u8], start: usize, width: usize) -> Option<f64> {
+    let field = std::str::from_utf8(record.get(start..start + width)?).ok()?;
+
+    field.trim().replace(['D', 'd'], "E").parse().ok()
+}
+
+/// Loads a classic USGS DEM file, which stores the elevations as south to north profiles.
+///
+/// The file consists of a header (type A record) followed by one type B record per profile,
+/// which are each split into blocks of 1024 bytes.
+fn load_dem(data: &[u8]) -> Option<Elevation> {
+    let header = data.get(..DEM_RECORD_SIZE)?;
+
+    let resolution_y = dem_field(header, 828, 12)?;
+    let resolution_z = dem_field(header, 840, 12)?;
+    let profile_count = dem_field(header, 858, 6)? as usize;
+
+    let mut profiles = Vec::with_capacity(profile_count);
+    let mut position = DEM_RECORD_SIZE;
+
+    for _ in 0..profile_count {
+        let block = data.get(position..position + DEM_RECORD_SIZE)?;
+
+        let count = dem_field(block, 12, 6)? as usize;
+        let origin_y = dem_field(block, 48, 24)?;
+        let datum = dem_field(block, 72, 24)?;
+
+        // the first block holds 146 elevations after the profile header, all others 170
+        let mut elevations = Vec::with_capacity(count);
+        let mut offset = 144;
+
+        while elevations.len() < count {
+            if offset + 6 > DEM_RECORD_SIZE - 4 {
+                position += DEM_RECORD_SIZE;
+                offset = 0;
+            }
+
+            let block = data.get(position..position + DEM_RECORD_SIZE)?;
+            let value = dem_field(block, offset, 6)? as f32;
+
+            elevations.push(if value == DEM_NODATA {
+                f32::NAN
+            } else {
+                (datum + value as f64 * resolution_z) as f32
+            });
+
+            offset += 6;
+        }
+
+        position += DEM_RECORD_SIZE;
+        profiles.push((origin_y, elevations));
+    }
+
+    // profiles of geographic DEMs start at different rows
+    let origin_y = profiles
+        .iter()
+        .map(|&(origin_y, _)| origin_y)
+        .fold(f64::MAX, f64::min);
+    let rows = profiles
+        .iter()
+        .map(|(start, elevations)| {
+            let start = ((start - origin_y) / resolution_y).round() as usize;
+            (start, elevations)
+        })
+        .collect::<Vec<_>>();
+
+    let width = profiles.len() as u32;
+    let height = rows
+        .iter()
+        .map(|(start, elevations)| start + elevations.len())
+        .max()? as u32;
+
+    let mut values = vec![f32::NAN; (width * height) as usize];
+
+    for (x, (start, elevations)) in rows.into_iter().enumerate() {
+        for (i, &value) in elevations.iter().enumerate() {
+            let y = height as usize - 1 - (start + i);
+            values[y * width as usize + x] = value;
+        }
+    }
+
+    Some(Elevation {
+        width,
+        height,
+        values,
+        nodata: None,
+    })
+}
+
+/// Loads the elevation tile and converts it into a tile of the height attachment.
+pub(crate) fn load_elevation_tile(
+    tile: &TileConfig,
+    attachment: &AttachmentConfig,
+) -> Option<DynamicImage> {
+    let data = fs::read(&tile.path).ok()?;
+
+    let elevation = match tile.file_format {
+        FileFormat::GeoTIFF => load_geotiff(&data)?,
+        FileFormat::DEM => load_dem(&data)?,
+        _ => return None,
+    };
+
+    Some(elevation.into_tile(tile, attachment))
+}
+
+/// Loads GeoTIFF (`.geotiff`, `.gtif`) and DEM (`.dem`) files as normalized R32F heightmaps,
+/// whose cells without data are filled with the lowest valid elevation.
+pub struct ElevationAssetLoader;
+
+impl AssetLoader for ElevationAssetLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let is_dem = load_context
+                .path()
+                .extension()
+                .map_or(false, |extension| extension == "dem");
+
+            let elevation = if is_dem {
+                load_dem(bytes)
+            } else {
+                load_geotiff(bytes)
+            }
+            .ok_or_else(|| anyhow!("Could not decode the elevation data."))?;
+
+            load_context.set_default_asset(LoadedAsset::new(elevation.into_image()));
+
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["geotiff", "gtif", "dem"]
+    }
+}
@@ -0,0 +1,113 @@
+//! Applies the thermal erosion to the heights of the terrain during preprocessing.
+//!
+//! The nodes of the highest lod are stitched into a single grid, because the material slides
+//! across the borders of the nodes. The eroded heights are written back into the nodes
+//! (including their borders), before the lower lods are down sampled from them.
+
+use crate::{
+    erosion::thermal::ThermalErosion,
+    preprocess::{
+        file_io::{format_node_path, load_image, save_image},
+        UVec2Utils,
+    },
+    terrain_data::AttachmentConfig,
+    TerrainConfig,
+};
+use bevy::prelude::*;
+use image::{DynamicImage, Luma, Rgb};
+use itertools::iproduct;
+
+/// Reads the normalized height of the pixel from a 16 bit or float height node.
+fn load_height(node_image: &DynamicImage, x: u32, y: u32) -> f32 {
+    match node_image {
+        DynamicImage::ImageRgb32F(node_image) => node_image.get_pixel(x, y).0[0],
+        node_image => node_image.as_luma16().unwrap().get_pixel(x, y).0[0] as f32 / u16::MAX as f32,
+    }
+}
+
+/// Writes the normalized height of the pixel into a 16 bit or float height node.
+fn store_height(node_image: &mut DynamicImage, x: u32, y: u32, height: f32) {
+    let height = height.clamp(0.0, 1.0);
+
+    match node_image {
+        DynamicImage::ImageRgb32F(node_image) => {
+            node_image.put_pixel(x, y, Rgb([height; 3]));
+        }
+        node_image => node_image.as_mut_luma16().unwrap().put_pixel(
+            x,
+            y,
+            Luma([(height * u16::MAX as f32).round() as u16]),
+        ),
+    }
+}
+
+/// Erodes the height nodes of the highest lod between the first and the last node coordinate.
+///
+/// One pixel of the highest lod measures one unit in the local space of the terrain,
+/// while the heights are scaled by the height of the terrain.
+pub(crate) fn erode_height_nodes(
+    config: &TerrainConfig,
+    directory: &str,
+    height_attachment: &AttachmentConfig,
+    erosion: &ThermalErosion,
+    first: UVec2,
+    last: UVec2,
+) {
+    let center_size = height_attachment.center_size;
+    let border_size = height_attachment.border_size;
+    let size = (last - first) * center_size;
+
+    let index = |pixel: UVec2| (pixel.y * size.x + pixel.x) as usize;
+
+    let mut heights = vec![0.0; (size.x * size.y) as usize];
+    // the pixels of missing nodes are masked out, instead of eroding them as a cliff down to zero
+    let mut loaded = vec![false; (size.x * size.y) as usize];
+
+    for (x, y) in first.product(last) {
+        let node_path = format_node_path(directory, 0, x, y);
+
+        let Some(node_image) = load_image(&node_path, height_attachment.file_format) else {
+            continue;
+        };
+
+        let origin = (UVec2::new(x, y) - first) * center_size;
+
+        for (i, j) in iproduct!(0..center_size, 0..center_size) {
+            let pixel = index(origin + UVec2::new(i, j));
+            heights[pixel] =
+                load_height(&node_image, border_size + i, border_size + j) * config.height;
+            loaded[pixel] = true;
+        }
+    }
+
+    erosion.erode_masked(&mut heights, Some(&loaded), size, Vec2::ONE);
+
+    for (x, y) in first.product(last) {
+        let node_path = format_node_path(directory, 0, x, y);
+
+        let Some(mut node_image) = load_image(&node_path, height_attachment.file_format) else {
+            continue;
+        };
+
+        let origin = ((UVec2::new(x, y) - first) * center_size).as_ivec2() - border_size as i32;
+
+        // the borders are filled from the adjacent nodes, or clamped at the edge of the terrain,
+        // while the borders towards missing nodes keep their original heights
+        for (i, j) in iproduct!(
+            0..height_attachment.texture_size,
+            0..height_attachment.texture_size
+        ) {
+            let pixel = (origin + UVec2::new(i, j).as_ivec2())
+                .clamp(IVec2::ZERO, size.as_ivec2() - 1)
+                .as_uvec2();
+
+            if !loaded[index(pixel)] {
+                continue;
+            }
+
+            store_height(&mut node_image, i, j, heights[index(pixel)] / config.height);
+        }
+
+        save_image(&node_path, &node_image, height_attachment);
+    }
+}
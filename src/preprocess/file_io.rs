@@ -1,6 +1,6 @@
 use crate::{
     formats::tdf::TDF,
-    preprocess::{R16Image, Rg16Image, Rgb8Image, Rgba8Image},
+    preprocess::{R16Image, R32FImage, Rg16Image, Rgb8Image, Rgba8Image},
     terrain_data::{calc_node_id, AttachmentConfig, AttachmentFormat, FileFormat},
 };
 use bytemuck::cast_slice;
@@ -67,6 +67,11 @@ pub fn load_image(path: &str, file_format: FileFormat) -> Option<DynamicImage> {
         FileFormat::PNG | FileFormat::TIF => load_image_rs(path),
         FileFormat::QOI => load_qoi(path),
         FileFormat::DTM => load_dtm(path),
+        FileFormat::KTX2 => None, // compressed nodes can not be processed on the CPU
+        FileFormat::GeoTIFF | FileFormat::DEM => None, // elevation data has to be imported as a tile
+        // the decoding requires the height of the terrain
+        FileFormat::TerrainRGB { .. } | FileFormat::QuantizedMesh { .. } => None,
+        FileFormat::TNF => None, // packed nodes are only decoded at runtime
     }
 }
 
@@ -78,9 +83,12 @@ pub(crate) fn load_or_create_node(path: &str, attachment: &AttachmentConfig) ->
 
         match attachment.format {
             AttachmentFormat::Rgb8 => DynamicImage::from(Rgb8Image::new(size, size)),
-            AttachmentFormat::Rgba8 => DynamicImage::from(Rgba8Image::new(size, size)),
+            AttachmentFormat::Rgba8 | AttachmentFormat::Rgba8Linear => {
+                DynamicImage::from(Rgba8Image::new(size, size))
+            }
             AttachmentFormat::R16 => DynamicImage::from(R16Image::new(size, size)),
             AttachmentFormat::Rg16 => DynamicImage::from(Rg16Image::new(size, size)),
+            AttachmentFormat::R32F => DynamicImage::from(R32FImage::new(size, size)),
         }
     }
 }
@@ -95,6 +103,14 @@ pub fn save_image(path: &str, node_image: &DynamicImage, attachment: &Attachment
         FileFormat::PNG | FileFormat::TIF => save_image_rs(path, node_image, attachment),
         FileFormat::QOI => save_qoi(path, node_image, attachment),
         FileFormat::DTM => save_dtm(path, node_image, attachment),
+        FileFormat::KTX2 => panic!("Can not save KTX2, convert the preprocessed nodes instead."),
+        FileFormat::TNF => panic!("Can not save TNF, pack the preprocessed nodes instead."),
+        FileFormat::GeoTIFF
+        | FileFormat::DEM
+        | FileFormat::TerrainRGB { .. }
+        | FileFormat::QuantizedMesh { .. } => {
+            panic!("Can not save nodes as elevation data.")
+        }
     }
 }
 
@@ -129,6 +145,15 @@ fn load_tdf(path: &str) -> Option<DynamicImage> {
             let image = Rg16Image::from_raw(size, size, data).unwrap();
             Some(DynamicImage::from(image))
         }
+        (4, 1) => {
+            let data: Vec<f32> = data
+                .chunks_exact(4)
+                .flat_map(|pixel| [f32::from_le_bytes(pixel.try_into().unwrap()); 3])
+                .collect();
+
+            let image = R32FImage::from_raw(size, size, data).unwrap();
+            Some(DynamicImage::from(image))
+        }
         _ => None,
     }
 }
@@ -185,9 +210,10 @@ fn load_qoi(path: &str) -> Option<DynamicImage> {
 fn save_tdf(path: &str, node_image: &DynamicImage, attachment: &AttachmentConfig) {
     let (pixel_size, channel_count) = match attachment.format {
         AttachmentFormat::Rgb8 => (1, 3),
-        AttachmentFormat::Rgba8 => (1, 4),
+        AttachmentFormat::Rgba8 | AttachmentFormat::Rgba8Linear => (1, 4),
         AttachmentFormat::R16 => (2, 1),
         AttachmentFormat::Rg16 => (2, 2),
+        AttachmentFormat::R32F => (4, 1),
     };
 
     let descriptor = TDF {
@@ -197,7 +223,20 @@ fn save_tdf(path: &str, node_image: &DynamicImage, attachment: &AttachmentConfig
         mip_level_count: attachment.mip_level_count,
     };
 
-    descriptor.save_file(path, node_image.as_bytes()).unwrap();
+    match attachment.format {
+        // only the red channel of the float image is stored
+        AttachmentFormat::R32F => {
+            let data: Vec<u8> = node_image
+                .as_rgb32f()
+                .unwrap()
+                .pixels()
+                .flat_map(|pixel| pixel.0[0].to_le_bytes())
+                .collect();
+
+            descriptor.save_file(path, &data).unwrap();
+        }
+        _ => descriptor.save_file(path, node_image.as_bytes()).unwrap(),
+    }
 }
 
 fn save_image_rs(path: &str, node_image: &DynamicImage, _attachment: &AttachmentConfig) {
@@ -209,9 +248,12 @@ fn save_dtm(path: &str, node_image: &DynamicImage, attachment: &AttachmentConfig
         pixel_size: 2,
         channel_count: match attachment.format {
             AttachmentFormat::Rgb8 => panic!("Can not save Rgb8 as DTM."),
-            AttachmentFormat::Rgba8 => panic!("Can not save Rgba8 as DTM."),
+            AttachmentFormat::Rgba8 | AttachmentFormat::Rgba8Linear => {
+                panic!("Can not save Rgba8 as DTM.")
+            }
             AttachmentFormat::R16 => 1,
             AttachmentFormat::Rg16 => 2,
+            AttachmentFormat::R32F => panic!("Can not save R32F as DTM."),
         },
         width: node_image.width(),
         height: node_image.height(),
@@ -228,9 +270,10 @@ fn save_qoi(path: &str, node_image: &DynamicImage, attachment: &AttachmentConfig
         height: node_image.height(),
         colors: match attachment.format {
             AttachmentFormat::Rgb8 => Colors::Rgb,
-            AttachmentFormat::Rgba8 => Colors::Rgba,
+            AttachmentFormat::Rgba8 | AttachmentFormat::Rgba8Linear => Colors::Rgba,
             AttachmentFormat::R16 => panic!("Can not save R16 as QOI."),
             AttachmentFormat::Rg16 => panic!("Can not save Rg16 as QOI."),
+            AttachmentFormat::R32F => panic!("Can not save R32F as QOI."),
         },
     };
 
@@ -0,0 +1,82 @@
+//! Bakes the horizon map of the terrain from its heights.
+//!
+//! Each texel stores the elevation of the horizon in the four axis directions of the terrain.
+//! The terrain shader interpolates these for the direction of the sun and compares them
+//! with its elevation, which shadows the terrain from the sun analytically.
+//! This results in long and soft mountain shadows, which are a lot cheaper in the distance
+//! than shadow maps.
+
+use crate::{
+    preprocess::bake::{bake_attachment, slope_to_sine},
+    terrain_data::{AttachmentConfig, AttachmentFormat, AttachmentIndex},
+    TerrainConfig,
+};
+use bevy::prelude::*;
+use image::Rgba;
+
+/// The index of the horizon attachment, which is sampled by the default terrain shader,
+/// if it is added directly after the ambient occlusion attachment.
+pub const HORIZON_ATTACHMENT: AttachmentIndex = 3;
+
+/// The directions of the channels of the horizon map (in the local space of the terrain).
+const DIRECTIONS: [Vec2; 4] = [Vec2::X, Vec2::Y, Vec2::NEG_X, Vec2::NEG_Y];
+
+/// The configuration of a horizon attachment, which is baked from the heights.
+#[derive(Clone, Copy, Debug)]
+pub struct HorizonConfig {
+    /// The number of heights sampled along each direction.
+    pub step_count: u32,
+    /// The distance (measured in texels of the highest lod), up to which the horizon is searched.
+    /// This limits the length of the shadows.
+    pub radius: f32,
+}
+
+impl Default for HorizonConfig {
+    fn default() -> Self {
+        Self {
+            step_count: 32,
+            radius: 256.0,
+        }
+    }
+}
+
+impl HorizonConfig {
+    /// Creates the config of the horizon attachment.
+    pub fn attachment(texture_size: u32, mip_level_count: u32) -> AttachmentConfig {
+        AttachmentConfig::new(
+            "horizon".to_string(),
+            texture_size,
+            1,
+            mip_level_count,
+            AttachmentFormat::Rgba8Linear,
+        )
+    }
+}
+
+pub(crate) fn bake_horizon(
+    config: &TerrainConfig,
+    height_attachment: &AttachmentConfig,
+    horizon: &HorizonConfig,
+    attachment: &AttachmentConfig,
+) {
+    bake_attachment(
+        config,
+        height_attachment,
+        attachment,
+        |sampler, position| {
+            Rgba(DIRECTIONS.map(|direction| {
+                let slope = sampler
+                    .horizon_slope(
+                        position,
+                        direction,
+                        horizon.radius,
+                        horizon.step_count,
+                        config.height,
+                    )
+                    .unwrap_or(0.0);
+
+                (slope_to_sine(slope) * u8::MAX as f32).round() as u8
+            }))
+        },
+    );
+}
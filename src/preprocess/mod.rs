@@ -1,23 +1,38 @@
 //! Contains the implementation for preprocessing source tiles into streamable nodes.
 
+pub mod albedo;
+pub mod ambient_occlusion;
 pub mod attachment;
+pub mod bake;
 pub mod config;
 pub mod down_sample;
+#[cfg(feature = "elevation")]
+pub mod elevation;
+pub mod erosion;
 pub mod file_io;
+pub mod horizon;
+pub mod normal;
+#[cfg(feature = "tnf")]
+pub mod pack;
 pub mod split;
 pub mod stitch;
 
 use crate::{
+    erosion::thermal::ThermalErosion,
     preprocess::{
+        ambient_occlusion::{bake_ambient_occlusion, AmbientOcclusionConfig},
         attachment::{preprocess_attachment, preprocess_base},
         config::save_config,
+        horizon::{bake_horizon, HorizonConfig},
+        normal::bake_normal,
+        stitch::stitch_terrain,
     },
     terrain_data::{AttachmentConfig, AttachmentFormat, FileFormat},
     TerrainConfig,
 };
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
 use image::{ImageBuffer, Luma, LumaA, Rgb, Rgba};
-use itertools::{Itertools, Product};
+use itertools::{iproduct, Itertools, Product};
 use std::ops::Range;
 
 #[macro_export]
@@ -39,6 +54,8 @@ pub struct BaseConfig {
     pub border_size: u32,
     pub mip_level_count: u32,
     pub file_format: FileFormat,
+    /// The format of the height data, either [`AttachmentFormat::R16`] or [`AttachmentFormat::R32F`].
+    pub height_format: AttachmentFormat,
 }
 
 impl BaseConfig {
@@ -48,16 +65,25 @@ impl BaseConfig {
             border_size: 2,
             mip_level_count,
             file_format: FileFormat::TDF,
+            height_format: AttachmentFormat::R16,
         }
     }
 
+    /// Stores the height data as 32 bit floats instead of 16 bit integers.
+    ///
+    /// See [`AttachmentFormat::R32F`] for the required GPU features.
+    pub fn with_float_heights(mut self) -> Self {
+        self.height_format = AttachmentFormat::R32F;
+        self
+    }
+
     pub(crate) fn height_attachment(&self) -> AttachmentConfig {
         let mut attachment = AttachmentConfig::new(
             "height".to_string(),
             self.texture_size,
             self.border_size,
             self.mip_level_count,
-            AttachmentFormat::R16,
+            self.height_format,
         );
 
         attachment.file_format = self.file_format;
@@ -86,6 +112,12 @@ pub struct TileConfig {
     pub size: u32,
     /// The file format of the tile.
     pub file_format: FileFormat,
+    /// The elevation range, that is mapped onto the height of the terrain.
+    ///
+    /// Only used by the [`FileFormat::GeoTIFF`] and [`FileFormat::DEM`] formats,
+    /// which default to the elevation range of the tile itself.
+    /// Tiles of the same terrain should share a common range.
+    pub elevation_range: Option<Vec2>,
 }
 
 /// The preprocessor converts attachments from source data to streamable nodes.
@@ -95,20 +127,146 @@ pub struct TileConfig {
 pub struct Preprocessor {
     pub(crate) base: Option<(TileConfig, BaseConfig)>,
     pub(crate) attachments: Vec<(TileConfig, AttachmentConfig)>,
+    pub(crate) ambient_occlusions: Vec<(AmbientOcclusionConfig, AttachmentConfig)>,
+    pub(crate) horizons: Vec<(HorizonConfig, AttachmentConfig)>,
+    pub(crate) normals: Vec<AttachmentConfig>,
+    pub(crate) thermal_erosion: Option<ThermalErosion>,
 }
 
 impl Preprocessor {
     /// Preprocesses all attachments of the terrain.
-    pub fn preprocess(self, config: &TerrainConfig) {
-        if let Some(base) = self.base {
-            preprocess_base(config, &base.0, &base.1);
+    pub fn preprocess(&self, config: &TerrainConfig) {
+        if let Some((tile, base)) = &self.base {
+            preprocess_base(config, tile, base, self.thermal_erosion.as_ref());
         }
 
-        for (tile, attachment) in self.attachments {
-            preprocess_attachment(config, &tile, &attachment);
+        for (tile, attachment) in &self.attachments {
+            preprocess_attachment(config, tile, attachment);
+        }
+
+        self.bake_attachments(config);
+
+        save_config(config, self.height_file_format());
+    }
+
+    /// Erodes the heights of the highest lod with a thermal erosion pass,
+    /// before the lower lods are down sampled from them.
+    ///
+    /// The entire height data of the highest lod is kept in memory during the pass.
+    pub fn set_thermal_erosion(&mut self, erosion: ThermalErosion) {
+        self.thermal_erosion = Some(erosion);
+    }
+
+    /// Bakes the ambient occlusion, horizon and normal attachments from the already preprocessed heights.
+    ///
+    /// This is part of [`Self::preprocess`], but can be rerun on its own, e.g. after the heights
+    /// have been edited and exported.
+    pub fn bake_attachments(&self, config: &TerrainConfig) {
+        if self.ambient_occlusions.is_empty() && self.horizons.is_empty() && self.normals.is_empty()
+        {
+            return;
         }
 
-        save_config(config);
+        let (_, base) = self
+            .base
+            .as_ref()
+            .expect("The attachments are baked from the base attachment.");
+        let height_attachment = base.height_attachment();
+
+        for (ambient_occlusion, attachment) in &self.ambient_occlusions {
+            bake_ambient_occlusion(config, &height_attachment, ambient_occlusion, attachment);
+        }
+
+        for (horizon, attachment) in &self.horizons {
+            bake_horizon(config, &height_attachment, horizon, attachment);
+        }
+
+        for attachment in &self.normals {
+            bake_normal(config, &height_attachment, attachment);
+        }
+    }
+
+    /// Packs the preprocessed nodes of all attachments into compact node files,
+    /// which are significantly smaller than the loose node files of the attachments.
+    ///
+    /// The height-like attachments are quantized relative to the value range of each node
+    /// with the bits (8 or 16) and all attachments are compressed with zstd.
+    /// Afterwards, load the terrain with the file format of all attachments set to
+    /// [`FileFormat::TNF`]. Requires the `tnf` feature.
+    #[cfg(feature = "tnf")]
+    pub fn pack_nodes(&self, config: &TerrainConfig, bits: u8) {
+        pack::pack_nodes(config, &self.all_attachments(), bits);
+    }
+
+    /// Returns the configurations of all attachments, including the ones of the base.
+    fn all_attachments(&self) -> Vec<AttachmentConfig> {
+        let mut attachments = self
+            .base
+            .iter()
+            .flat_map(|(_, base)| [base.height_attachment(), base.minmax_attachment()])
+            .collect::<Vec<_>>();
+        attachments.extend(
+            self.attachments
+                .iter()
+                .map(|(_, attachment)| attachment.clone()),
+        );
+        attachments.extend(
+            self.ambient_occlusions
+                .iter()
+                .map(|(_, attachment)| attachment.clone()),
+        );
+        attachments.extend(
+            self.horizons
+                .iter()
+                .map(|(_, attachment)| attachment.clone()),
+        );
+        attachments.extend(self.normals.iter().cloned());
+
+        attachments
+    }
+
+    /// Returns the file format of the height nodes.
+    fn height_file_format(&self) -> FileFormat {
+        self.base
+            .as_ref()
+            .map_or(FileFormat::TDF, |(_, base)| base.file_format)
+    }
+
+    /// Stitches the borders of terrains, which are placed edge to edge in a grid.
+    ///
+    /// The terrains are identified by their offset (measured in terrains) inside the grid
+    /// and have to be preprocessed with this preprocessor beforehand.
+    /// Afterwards the edge nodes of each lod overlap the ones of the adjacent terrains,
+    /// so that the shared edges are sampled identically from both sides,
+    /// which prevents seams and cracks between the terrains.
+    ///
+    /// All terrains have to share the same extent, lod count and height. Additionally the terrain
+    /// extent has to be a multiple of the size of the largest nodes, so that the nodes of every lod line up.
+    pub fn stitch_terrains(&self, terrains: &[(IVec2, TerrainConfig)]) {
+        let terrains = terrains
+            .iter()
+            .map(|(offset, config)| {
+                assert_eq!(
+                    config.terrain_extent % (config.leaf_node_size << (config.lod_count - 1)),
+                    UVec2::ZERO,
+                    "The terrain extent of {} has to be a multiple of the largest node size.",
+                    config.path
+                );
+
+                (*offset, config)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let attachments = self.all_attachments();
+
+        for (&offset, attachment) in iproduct!(terrains.keys(), &attachments) {
+            stitch_terrain(&terrains, offset, attachment);
+        }
+
+        // the stitched borders may extend the height bounds of the edge nodes
+        for config in terrains.values() {
+            save_config(config, self.height_file_format());
+        }
     }
 }
 
@@ -136,3 +294,6 @@ pub type Rgb8Image = ImageBuffer<Rgb<u8>, Vec<u8>>;
 pub type Rgba8Image = ImageBuffer<Rgba<u8>, Vec<u8>>;
 pub type R16Image = ImageBuffer<Luma<u16>, Vec<u16>>;
 pub type Rg16Image = ImageBuffer<LumaA<u16>, Vec<u16>>;
+/// The image crate has no single channel float image, so R32F data is stored
+/// in all three channels of a float RGB image instead.
+pub type R32FImage = ImageBuffer<Rgb<f32>, Vec<f32>>;
@@ -0,0 +1,70 @@
+//! Bakes the normal map of the terrain from its heights.
+//!
+//! Deriving the normals from the heights in the shader produces faceting artifacts at low lods,
+//! because the heights of the coarse nodes are too sparse to capture the slopes of the surface.
+//! Instead the normals are baked from the heights of the highest lod and then down sampled,
+//! so that the coarse nodes keep the averaged detail of the fine ones.
+//! Only the x and z components of the normals (in the local space of the terrain) are stored,
+//! the y component is reconstructed in the shader.
+
+use crate::{
+    preprocess::bake::bake_attachment,
+    terrain_data::{AttachmentConfig, AttachmentFormat, AttachmentIndex},
+    TerrainConfig,
+};
+use bevy::prelude::*;
+use image::LumaA;
+
+/// The index of the normal attachment, if it is added directly after the horizon attachment,
+/// like the preprocessing binary does.
+///
+/// The default terrain shader samples it, once it is configured as the
+/// [`TerrainPlugin::normal_attachment`](crate::TerrainPlugin).
+pub const NORMAL_ATTACHMENT: AttachmentIndex = 4;
+
+/// Creates the config of the normal attachment.
+pub fn normal_attachment(texture_size: u32, mip_level_count: u32) -> AttachmentConfig {
+    AttachmentConfig::new(
+        "normal".to_string(),
+        texture_size,
+        1,
+        mip_level_count,
+        AttachmentFormat::Rg16,
+    )
+}
+
+/// Encodes a component of the normal from the range of -1 to 1 into an unsigned integer.
+fn encode(value: f32) -> u16 {
+    ((0.5 * value + 0.5) * u16::MAX as f32).round() as u16
+}
+
+pub(crate) fn bake_normal(
+    config: &TerrainConfig,
+    height_attachment: &AttachmentConfig,
+    attachment: &AttachmentConfig,
+) {
+    bake_attachment(
+        config,
+        height_attachment,
+        attachment,
+        |sampler, position| {
+            let Some(center) = sampler.height(position) else {
+                return LumaA([encode(0.0), encode(0.0)]);
+            };
+
+            // the missing heights at the edge of the terrain are replaced by the center one
+            let mut height =
+                |offset: IVec2| sampler.height(position + offset).unwrap_or(center) * config.height;
+
+            let left = height(IVec2::new(-1, 0));
+            let up = height(IVec2::new(0, -1));
+            let right = height(IVec2::new(1, 0));
+            let down = height(IVec2::new(0, 1));
+
+            // matches the normals derived from the heights by the shader
+            let normal = Vec3::new(right - left, 2.0, down - up).normalize();
+
+            LumaA([encode(normal.x), encode(normal.z)])
+        },
+    );
+}
@@ -0,0 +1,64 @@
+use crate::{
+    formats::tnf::{TNFAttachment, TNF},
+    preprocess::file_io::{format_directory, iterate_directory, load_image, reset_directory},
+    skip_none,
+    terrain_data::{AttachmentConfig, AttachmentFormat},
+    TerrainConfig,
+};
+use image::DynamicImage;
+use std::collections::BTreeSet;
+
+/// Returns the pixel data of the node image in the layout of the attachment.
+fn attachment_data(node_image: &DynamicImage, attachment: &AttachmentConfig) -> Vec<u8> {
+    match attachment.format {
+        // only the red channel of the float image is stored
+        AttachmentFormat::R32F => node_image
+            .as_rgb32f()
+            .unwrap()
+            .pixels()
+            .flat_map(|pixel| pixel.0[0].to_le_bytes())
+            .collect(),
+        _ => node_image.as_bytes().to_vec(),
+    }
+}
+
+/// Packs the preprocessed nodes of all attachments into one compact node file per node.
+///
+/// The height-like attachments are quantized with the bits (8 or 16).
+/// The node files are stored in the `nodes` directory next to the attachments.
+pub(crate) fn pack_nodes(config: &TerrainConfig, attachments: &[AttachmentConfig], bits: u8) {
+    let directory = format_directory(&config.path, "nodes");
+    reset_directory(&directory);
+
+    let nodes = attachments
+        .iter()
+        .flat_map(|attachment| {
+            iterate_directory(&format_directory(&config.path, &attachment.name))
+                .map(|(name, _)| name)
+        })
+        .collect::<BTreeSet<_>>();
+
+    for name in nodes {
+        let mut node = TNF::default();
+
+        for attachment in attachments {
+            let path = format!(
+                "{}/{name}",
+                format_directory(&config.path, &attachment.name)
+            );
+            let node_image = skip_none!(load_image(&path, attachment.file_format));
+
+            node.attachments.push(TNFAttachment::new(
+                attachment.name.clone(),
+                attachment.texture_size,
+                attachment.border_size,
+                attachment.mip_level_count,
+                attachment.format,
+                bits,
+                &attachment_data(&node_image, attachment),
+            ));
+        }
+
+        node.save_file(format!("{directory}/{name}.tnf")).unwrap();
+    }
+}
@@ -5,7 +5,7 @@ use crate::{
         },
         TileConfig, UVec2Utils,
     },
-    terrain_data::{AttachmentConfig, AttachmentFormat},
+    terrain_data::{AttachmentConfig, AttachmentFormat, FileFormat},
 };
 use bevy::prelude::*;
 use image::{
@@ -14,6 +14,9 @@ use image::{
 };
 use std::fs;
 
+#[cfg(feature = "elevation")]
+use crate::preprocess::elevation::load_elevation_tile;
+
 fn tile_to_node(
     node_image: &mut DynamicImage,
     tile_image: &DynamicImage,
@@ -31,7 +34,7 @@ fn tile_to_node(
             x,
             y,
         ),
-        AttachmentFormat::Rgba8 => imageops::replace(
+        AttachmentFormat::Rgba8 | AttachmentFormat::Rgba8Linear => imageops::replace(
             node_image.as_mut_rgba8().unwrap(),
             tile_image.as_rgba8().unwrap(),
             x,
@@ -49,15 +52,39 @@ fn tile_to_node(
             x,
             y,
         ),
+        // 16 bit tiles are converted as well, so that they can be stored with more precision
+        AttachmentFormat::R32F => imageops::replace(
+            node_image.as_mut_rgb32f().unwrap(),
+            &tile_image.to_rgb32f(),
+            x,
+            y,
+        ),
     };
 }
 
-fn split_tile(directory: &str, tile: &TileConfig, attachment: &AttachmentConfig, offset: UVec2) {
-    let tile_image = load_image(&tile.path, tile.file_format).expect("Could not load tile.");
+/// Splits the tile into the nodes it overlaps and returns its size, which may be rectangular.
+fn split_tile(
+    directory: &str,
+    tile: &TileConfig,
+    attachment: &AttachmentConfig,
+    offset: UVec2,
+) -> UVec2 {
+    let tile_image = match tile.file_format {
+        #[cfg(feature = "elevation")]
+        FileFormat::GeoTIFF | FileFormat::DEM => load_elevation_tile(tile, attachment),
+        #[cfg(not(feature = "elevation"))]
+        FileFormat::GeoTIFF | FileFormat::DEM => {
+            panic!("Enable the elevation feature to import GeoTIFF and DEM files.")
+        }
+        _ => load_image(&tile.path, tile.file_format),
+    }
+    .expect("Could not load tile.");
+
+    let size = UVec2::new(tile_image.width(), tile_image.height());
 
     // first and last node coordinate
     let first = offset.div_floor(attachment.center_size);
-    let last = (offset + tile.size + attachment.border_size).div_ceil(attachment.center_size);
+    let last = (offset + size + attachment.border_size).div_ceil(attachment.center_size);
 
     for (x, y) in first.product(last) {
         let node_path = format_node_path(directory, 0, x, y);
@@ -74,6 +101,8 @@ fn split_tile(directory: &str, tile: &TileConfig, attachment: &AttachmentConfig,
 
         save_image(&node_path, &node_image, attachment);
     }
+
+    size
 }
 
 pub(crate) fn split_tiles(
@@ -110,9 +139,9 @@ pub(crate) fn split_tiles(
 
         (offset, size)
     } else {
-        split_tile(directory, tile, attachment, UVec2::splat(0));
+        let size = split_tile(directory, tile, attachment, UVec2::splat(0));
 
-        (UVec2::splat(0), UVec2::splat(tile.size))
+        (UVec2::splat(0), size)
     };
 
     let first = offset.div_floor(attachment.center_size);
@@ -1,12 +1,13 @@
 use crate::{
     preprocess::{
-        file_io::{format_node_path, load_image, save_image},
+        file_io::{format_directory, format_node_path, load_image, save_image},
         UVec2Utils,
     },
     skip_none,
     terrain_data::{AttachmentConfig, AttachmentFormat},
+    TerrainConfig,
 };
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
 use image::DynamicImage;
 use itertools::iproduct;
 
@@ -45,7 +46,7 @@ fn stitch(
                 node_image.put_pixel(x1, y1, *adjacent_image.get_pixel(x2, y2));
             }
         }
-        AttachmentFormat::Rgba8 => {
+        AttachmentFormat::Rgba8 | AttachmentFormat::Rgba8Linear => {
             let node_image = node_image.as_mut_rgba8().unwrap();
             let adjacent_image = adjacent_image.as_rgba8().unwrap();
 
@@ -65,6 +66,14 @@ fn stitch(
             let node_image = node_image.as_mut_luma_alpha16().unwrap();
             let adjacent_image = adjacent_image.as_luma_alpha16().unwrap();
 
+            for (x1, y1, x2, y2) in iter {
+                node_image.put_pixel(x1, y1, *adjacent_image.get_pixel(x2, y2));
+            }
+        }
+        AttachmentFormat::R32F => {
+            let node_image = node_image.as_mut_rgb32f().unwrap();
+            let adjacent_image = adjacent_image.as_rgb32f().unwrap();
+
             for (x1, y1, x2, y2) in iter {
                 node_image.put_pixel(x1, y1, *adjacent_image.get_pixel(x2, y2));
             }
@@ -101,7 +110,7 @@ fn extend(node_image: &mut DynamicImage, attachment: &AttachmentConfig, directio
                 node_image.put_pixel(x1, y1, *node_image.get_pixel(x2, y2));
             }
         }
-        AttachmentFormat::Rgba8 => {
+        AttachmentFormat::Rgba8 | AttachmentFormat::Rgba8Linear => {
             let node_image = node_image.as_mut_rgba8().unwrap();
 
             for (x1, y1, x2, y2) in iter {
@@ -118,6 +127,13 @@ fn extend(node_image: &mut DynamicImage, attachment: &AttachmentConfig, directio
         AttachmentFormat::Rg16 => {
             let node_image = node_image.as_mut_luma_alpha16().unwrap();
 
+            for (x1, y1, x2, y2) in iter {
+                node_image.put_pixel(x1, y1, *node_image.get_pixel(x2, y2));
+            }
+        }
+        AttachmentFormat::R32F => {
+            let node_image = node_image.as_mut_rgb32f().unwrap();
+
             for (x1, y1, x2, y2) in iter {
                 node_image.put_pixel(x1, y1, *node_image.get_pixel(x2, y2));
             }
@@ -160,3 +176,57 @@ pub(crate) fn stitch_layer(
         save_image(&node_path, &node_image, attachment);
     }
 }
+
+/// Replaces the borders of the edge nodes of the terrain with the data of the adjacent terrains.
+///
+/// The terrains are identified by their offset (measured in terrains) inside the grid.
+pub(crate) fn stitch_terrain(
+    terrains: &HashMap<IVec2, &TerrainConfig>,
+    offset: IVec2,
+    attachment: &AttachmentConfig,
+) {
+    if attachment.border_size == 0 {
+        return;
+    }
+
+    let config = terrains[&offset];
+    let directory = format_directory(&config.path, &attachment.name);
+
+    for lod in 0..config.lod_count {
+        let node_count = (config.terrain_extent / (config.leaf_node_size << lod)).as_ivec2();
+
+        for (x, y) in iproduct!(0..node_count.x, 0..node_count.y) {
+            if x != 0 && x != node_count.x - 1 && y != 0 && y != node_count.y - 1 {
+                continue;
+            }
+
+            let node_path = format_node_path(&directory, lod, x as u32, y as u32);
+            let mut node_image = skip_none!(load_image(&node_path, attachment.file_format));
+
+            for direction in iproduct!(-1..=1, -1..=1) {
+                let coord = offset * node_count + IVec2::new(x, y) + IVec2::from(direction);
+                let adjacent_offset = coord.div_euclid(node_count);
+
+                // nodes inside the same terrain have already been stitched
+                if adjacent_offset == offset {
+                    continue;
+                }
+
+                let Some(adjacent_config) = terrains.get(&adjacent_offset) else {
+                    continue;
+                };
+
+                let coord = coord.rem_euclid(node_count);
+                let adjacent_directory = format_directory(&adjacent_config.path, &attachment.name);
+                let adjacent_path =
+                    format_node_path(&adjacent_directory, lod, coord.x as u32, coord.y as u32);
+
+                if let Some(adjacent_image) = load_image(&adjacent_path, attachment.file_format) {
+                    stitch(&mut node_image, &adjacent_image, attachment, direction);
+                }
+            }
+
+            save_image(&node_path, &node_image, attachment);
+        }
+    }
+}
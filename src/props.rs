@@ -0,0 +1,468 @@
+//! Scatters larger props (e.g. trees or rocks) across the terrain as regular entities.
+//!
+//! Each [`PropKind`] places instances of one scene on a jittered grid, whose density is scaled
+//! by a density map, which can be any channel of any attachment (e.g. a biome or a splat layer),
+//! and by the height range of the kind. Slopes steeper than the maximum slope of the kind stay empty.
+//! Unlike the detail objects of [`crate::scatter`], the props are spawned as one entity each,
+//! so that they can be interacted with and optionally carry a [`PropCollider`].
+//!
+//! Each terrain with a [`TerrainProps`] component spawns the props of a node, once the node
+//! of the configured lod is activated, and despawns them again, once it is deactivated.
+//! Edits only re-place the props of the grid cells inside the edited region.
+//! The placement only depends on the position of each grid cell, so that the same props
+//! reappear each time a node is activated.
+//! Add the [`TerrainPropsPlugin`] to enable the props.
+
+use crate::{
+    biome::{generate_biome_splats, BiomeId, TerrainBiomes},
+    scatter::cell_rng,
+    terrain_data::{
+        node_atlas::{NodeAtlas, NodeLifecycle},
+        AttachmentIndex, NodeCoordinate, NodeId, HEIGHT_ATTACHMENT,
+    },
+    TerrainSystemSet,
+};
+use bevy::{prelude::*, utils::HashMap};
+use itertools::{iproduct, Itertools};
+use std::f32::consts::{FRAC_PI_6, TAU};
+
+/// The maximum number of grid cells along each axis of a node, which limits the number of
+/// props per node and kind.
+pub const MAX_PROP_CELLS: u32 = 64;
+
+/// The salt of the random number generators of the grid cells (see [`cell_rng`]).
+const PROP_SALT: u64 = 2;
+
+/// The shape of the physics collider of a prop.
+///
+/// The collider is inserted by the physics engine selected by the `rapier` or `avian` feature
+/// and scaled along with its prop.
+#[derive(Clone, Copy, Debug, Component)]
+pub enum PropCollider {
+    /// A sphere with the radius.
+    Ball(f32),
+    /// A vertical cylinder with the half height and the radius, which is centered on its prop.
+    Cylinder { half_height: f32, radius: f32 },
+    /// A box with the half extents, which is centered on its prop.
+    Cuboid(Vec3),
+}
+
+/// A kind of props, which are scattered across the terrain.
+#[derive(Clone)]
+pub struct PropKind {
+    /// The scene of the props.
+    pub scene: Handle<Scene>,
+    /// The channel of the attachment, whose value (from zero to one) scales the density,
+    /// or `None` to scatter the props across the entire terrain.
+    pub density_map: Option<(AttachmentIndex, usize)>,
+    /// The biomes (see [`crate::biome`]), whose weights scale the density,
+    /// or an empty list to scatter the props across all biomes.
+    pub biomes: Vec<BiomeId>,
+    /// The number of props per square unit (in the local space of the terrain) at full density.
+    pub density: f32,
+    /// The height range (in the local space of the terrain), in which the props are placed.
+    pub height_range: (f32, f32),
+    /// The smallest scale of the props.
+    pub min_scale: f32,
+    /// The largest scale of the props.
+    pub max_scale: f32,
+    /// The slope angle in radians, above which no props are placed.
+    pub max_slope: f32,
+    /// The collider of the props.
+    pub collider: Option<PropCollider>,
+}
+
+impl PropKind {
+    /// Creates a new kind, which scatters the scene with the density across the entire terrain.
+    pub fn new(scene: Handle<Scene>, density: f32) -> Self {
+        Self {
+            scene,
+            density_map: None,
+            biomes: Vec::new(),
+            density,
+            height_range: (f32::MIN, f32::MAX),
+            min_scale: 0.8,
+            max_scale: 1.2,
+            max_slope: FRAC_PI_6,
+            collider: None,
+        }
+    }
+
+    /// Scales the density by the channel of the attachment.
+    pub fn with_density_map(mut self, attachment_index: AttachmentIndex, channel: usize) -> Self {
+        self.density_map = Some((attachment_index, channel));
+        self
+    }
+
+    /// Restricts the kind to the biomes, whose weights scale the density.
+    pub fn with_biomes(mut self, biomes: impl Into<Vec<BiomeId>>) -> Self {
+        self.biomes = biomes.into();
+        self
+    }
+
+    /// Only places the props between the heights.
+    pub fn with_height_range(mut self, min_height: f32, max_height: f32) -> Self {
+        self.height_range = (min_height, max_height);
+        self
+    }
+
+    /// Adds the collider to the props.
+    pub fn with_collider(mut self, collider: PropCollider) -> Self {
+        self.collider = Some(collider);
+        self
+    }
+}
+
+/// Configures the props of a terrain.
+#[derive(Component)]
+pub struct TerrainProps {
+    /// The lod of the nodes, for which props are spawned.
+    pub lod: u32,
+    /// The seed of the placement.
+    pub seed: u64,
+    /// The scattered kinds of props.
+    pub kinds: Vec<PropKind>,
+    /// The props of the currently activated nodes.
+    props: HashMap<NodeId, NodeProps>,
+}
+
+/// The props spawned for a node.
+struct NodeProps {
+    /// The parent entity of the props.
+    parent: Entity,
+    /// The kind index and the grid cell (within the node) of each prop.
+    props: Vec<(usize, UVec2, Entity)>,
+}
+
+impl TerrainProps {
+    /// Creates a new props config, which spawns props for the nodes of the lod.
+    pub fn new(lod: u32) -> Self {
+        Self {
+            lod,
+            seed: 0,
+            kinds: Vec::new(),
+            props: default(),
+        }
+    }
+
+    /// Adds the scattered kind of props.
+    pub fn with_kind(mut self, kind: PropKind) -> Self {
+        self.kinds.push(kind);
+        self
+    }
+
+    /// Places the props of the kind on the node, optionally only in the grid cells
+    /// intersecting the region (relative to the node).
+    ///
+    /// Returns the grid cells and the local transforms relative to the node, which are empty
+    /// if the height density or biome data of the node is not available on the CPU.
+    fn place(
+        &self,
+        node_atlas: &NodeAtlas,
+        biomes: Option<&TerrainBiomes>,
+        images: &Assets<Image>,
+        node_id: NodeId,
+        kind_index: usize,
+        region: Option<Rect>,
+    ) -> Vec<(UVec2, Transform)> {
+        let kind = &self.kinds[kind_index];
+
+        let Some(node) = node_atlas.nodes.get(&node_id) else {
+            return Vec::new();
+        };
+
+        let data = &node_atlas.data[node.atlas_index as usize];
+        let height_attachment = &node_atlas.attachments[HEIGHT_ATTACHMENT];
+
+        let Some(height_image) = data
+            .attachments
+            .get(&HEIGHT_ATTACHMENT)
+            .and_then(|handle| images.get(handle))
+        else {
+            return Vec::new();
+        };
+
+        let density_map = match kind.density_map {
+            Some((attachment_index, channel)) => {
+                let (Some(attachment), Some(image)) = (
+                    node_atlas.attachments.get(attachment_index),
+                    data.attachments
+                        .get(&attachment_index)
+                        .and_then(|handle| images.get(handle)),
+                ) else {
+                    return Vec::new();
+                };
+
+                Some((attachment, image, channel))
+            }
+            None => None,
+        };
+
+        let node_biomes = match (kind.biomes.is_empty(), biomes) {
+            (true, _) => None,
+            (false, Some(biomes)) => match biomes.node(node_atlas, images, node_id) {
+                Some(node_biomes) => Some(node_biomes),
+                None => return Vec::new(),
+            },
+            (false, None) => return Vec::new(),
+        };
+
+        let NodeCoordinate { lod, x, y } = node_id.into();
+        let node_size = (node_atlas.leaf_node_size << lod) as f32;
+        let cells = cell_count(kind, node_size);
+
+        let step = 1.0 / height_attachment.center_size as f32;
+        let max_gradient = kind.max_slope.tan();
+        let (min_height, max_height) = kind.height_range;
+
+        let sample_height = |coords: Vec2| {
+            height_attachment.sample(height_image, coords.clamp(Vec2::ZERO, Vec2::ONE), 0)
+                * node_atlas.height
+        };
+
+        iproduct!(0..cells, 0..cells)
+            .map(|(cell_y, cell_x)| UVec2::new(cell_x, cell_y))
+            .filter(|&local_cell| {
+                region.map_or(true, |region| {
+                    !cell_rect(local_cell, cells, node_size)
+                        .intersect(region)
+                        .is_empty()
+                })
+            })
+            .filter_map(|local_cell| {
+                let UVec2 {
+                    x: cell_x,
+                    y: cell_y,
+                } = local_cell;
+                let cell = UVec2::new(x * cells + cell_x, y * cells + cell_y);
+                let mut rng = cell_rng(self.seed, PROP_SALT, kind_index, cell);
+
+                let coords = (Vec2::new(cell_x as f32, cell_y as f32)
+                    + Vec2::new(rng.f32(), rng.f32()))
+                    / cells as f32;
+
+                let density = density_map.map_or(1.0, |(attachment, image, channel)| {
+                    attachment.sample(image, coords, channel)
+                }) * node_biomes
+                    .as_ref()
+                    .map_or(1.0, |node_biomes| node_biomes.weight(coords, &kind.biomes));
+
+                if rng.f32() >= density {
+                    return None;
+                }
+
+                let height = sample_height(coords);
+
+                if height < min_height || height > max_height {
+                    return None;
+                }
+
+                let gradient = Vec2::new(
+                    sample_height(coords + Vec2::X * step) - sample_height(coords - Vec2::X * step),
+                    sample_height(coords + Vec2::Y * step) - sample_height(coords - Vec2::Y * step),
+                ) / (2.0 * step * node_size);
+
+                if gradient.length() > max_gradient {
+                    return None;
+                }
+
+                let position = coords * node_size;
+                let scale = kind.min_scale + (kind.max_scale - kind.min_scale) * rng.f32();
+
+                Some((
+                    local_cell,
+                    Transform::from_xyz(position.x, height, position.y)
+                        .with_rotation(Quat::from_rotation_y(rng.f32() * TAU))
+                        .with_scale(Vec3::splat(scale)),
+                ))
+            })
+            .collect()
+    }
+
+    /// Spawns the props of the kind as children of the parent of the node.
+    fn spawn(
+        &self,
+        commands: &mut Commands,
+        parent: Entity,
+        kind_index: usize,
+        placed: Vec<(UVec2, Transform)>,
+    ) -> Vec<(usize, UVec2, Entity)> {
+        let kind = &self.kinds[kind_index];
+
+        placed
+            .into_iter()
+            .map(|(cell, transform)| {
+                let mut prop = commands.spawn(SceneBundle {
+                    scene: kind.scene.clone(),
+                    transform,
+                    ..default()
+                });
+
+                if let Some(collider) = kind.collider {
+                    prop.insert(collider);
+                }
+
+                let prop = prop.id();
+                commands.entity(parent).add_child(prop);
+
+                (kind_index, cell, prop)
+            })
+            .collect()
+    }
+}
+
+/// The number of grid cells along each axis of the node for the kind.
+fn cell_count(kind: &PropKind, node_size: f32) -> u32 {
+    ((node_size * kind.density.max(0.0).sqrt()).ceil() as u32).clamp(1, MAX_PROP_CELLS)
+}
+
+/// The region covered by the grid cell (relative to the node).
+fn cell_rect(cell: UVec2, cells: u32, node_size: f32) -> Rect {
+    let cell_size = node_size / cells as f32;
+    let min = cell.as_vec2() * cell_size;
+
+    Rect::from_corners(min, min + cell_size)
+}
+
+/// Adds the props of the terrains with a [`TerrainProps`] component.
+pub struct TerrainPropsPlugin;
+
+impl Plugin for TerrainPropsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(
+            update_terrain_props
+                .after(TerrainSystemSet::Update)
+                .after(generate_biome_splats)
+                .in_base_set(CoreSet::Last),
+        );
+
+        #[cfg(feature = "rapier")]
+        app.add_system(crate::collision::rapier::insert_rapier_prop_colliders);
+
+        #[cfg(feature = "avian")]
+        app.add_system(crate::collision::avian::insert_avian_prop_colliders);
+    }
+}
+
+/// Spawns and despawns the props of all terrains,
+/// according to the lifecycle of the nodes of their node atlas.
+///
+/// The props in the edited regions of the activated nodes are re-placed.
+fn update_terrain_props(
+    mut commands: Commands,
+    images: Res<Assets<Image>>,
+    mut terrain_query: Query<(
+        Entity,
+        &NodeAtlas,
+        Option<&TerrainBiomes>,
+        &mut TerrainProps,
+    )>,
+) {
+    for (terrain, node_atlas, biomes, mut props) in terrain_query.iter_mut() {
+        let props = &mut *props;
+        let lod = props.lod;
+
+        let deactivated = node_atlas
+            .lifecycle_events
+            .iter()
+            .filter(|&&(_, lifecycle)| lifecycle == NodeLifecycle::Deactivated)
+            .map(|&(node_id, _)| node_id)
+            .collect::<Vec<_>>();
+
+        for (_, node_props) in props
+            .props
+            .drain_filter(|node_id, _| deactivated.contains(node_id))
+        {
+            commands.entity(node_props.parent).despawn_recursive();
+        }
+
+        // the gradient of the props is sampled from the neighbouring pixels as well
+        let pixel_size = 1.0 / node_atlas.attachments[HEIGHT_ATTACHMENT].center_size as f32;
+
+        for &(node_id, region) in &node_atlas.edited_regions {
+            let Some(mut node_props) = props.props.remove(&node_id) else {
+                continue;
+            };
+
+            let NodeCoordinate { lod, x, y } = node_id.into();
+            let node_size = (node_atlas.leaf_node_size << lod) as f32;
+            let node_origin = Vec2::new(x as f32, y as f32) * node_size;
+            let region = Rect::from_corners(region.min - node_origin, region.max - node_origin)
+                .inset(pixel_size * node_size);
+
+            node_props.props.retain(|&(kind_index, cell, entity)| {
+                let cells = cell_count(&props.kinds[kind_index], node_size);
+                let edited = !cell_rect(cell, cells, node_size)
+                    .intersect(region)
+                    .is_empty();
+
+                if edited {
+                    commands.entity(entity).despawn_recursive();
+                }
+
+                !edited
+            });
+
+            for kind_index in 0..props.kinds.len() {
+                let placed = props.place(
+                    node_atlas,
+                    biomes,
+                    &images,
+                    node_id,
+                    kind_index,
+                    Some(region),
+                );
+                node_props.props.extend(props.spawn(
+                    &mut commands,
+                    node_props.parent,
+                    kind_index,
+                    placed,
+                ));
+            }
+
+            props.props.insert(node_id, node_props);
+        }
+
+        let activated = node_atlas
+            .lifecycle_events
+            .iter()
+            .filter(|&&(_, lifecycle)| lifecycle == NodeLifecycle::Activated)
+            .map(|&(node_id, _)| node_id)
+            .chain(node_atlas.edited_nodes.iter().copied())
+            .unique()
+            .filter(|&node_id| {
+                NodeCoordinate::from(node_id).lod == lod
+                    && node_atlas.nodes.contains_key(&node_id)
+                    && !props.props.contains_key(&node_id)
+            })
+            .collect::<Vec<_>>();
+
+        for node_id in activated {
+            let NodeCoordinate { lod, x, y } = node_id.into();
+            let size = (node_atlas.leaf_node_size << lod) as f32;
+
+            let parent = commands
+                .spawn(SpatialBundle::from_transform(Transform::from_xyz(
+                    x as f32 * size,
+                    0.0,
+                    y as f32 * size,
+                )))
+                .id();
+
+            let mut node_props = NodeProps {
+                parent,
+                props: Vec::new(),
+            };
+
+            for kind_index in 0..props.kinds.len() {
+                let placed = props.place(node_atlas, biomes, &images, node_id, kind_index, None);
+                node_props
+                    .props
+                    .extend(props.spawn(&mut commands, parent, kind_index, placed));
+            }
+
+            commands.entity(terrain).add_child(parent);
+            props.props.insert(node_id, node_props);
+        }
+    }
+}
@@ -0,0 +1,579 @@
+//! An attachment loader, which streams node data from slippy map (XYZ/TMS) tile servers.
+//!
+//! Each node of the quadtree corresponds to exactly one tile of the server,
+//! where the lod zero nodes are fetched from the most detailed zoom level.
+//! The tiles are fetched asynchronously by one [`UrlSource`] per attachment and then fed into
+//! the [`NodeAtlas`], just like the ones loaded from disk.
+//! Because the requests are blocking, each source fetches its tiles on a few dedicated threads,
+//! instead of stalling the [`IoTaskPool`](bevy::tasks::IoTaskPool), which loads the nodes from disk.
+//!
+//! Tiles do not have a border, so the attachments should be configured with a border size of
+//! zero and a texture size matching the tile size of the server (usually 256 or 512).
+//! Fetched tiles can be kept in a persistent [`TileCache`] on disk, so that repeatedly viewed
+//! areas are not downloaded again and the terrain remains available offline.
+//! Requires the `remote` feature.
+
+use crate::{
+    formats::{decode_image, quantized_mesh::QuantizedMesh, terrain_rgb::decode_heights},
+    node_source::{finish_loading_node, NodeSource},
+    terrain::TerrainConfig,
+    terrain_data::{
+        node_atlas::NodeAtlas, AttachmentConfig, AttachmentIndex, FileFormat, NodeCoordinate,
+        NodeId, HEIGHT_ATTACHMENT,
+    },
+};
+use anyhow::{anyhow, Result};
+use bevy::{
+    prelude::*,
+    render::{render_resource::*, texture::CompressedImageFormats},
+    utils::HashMap,
+};
+use crossbeam_channel::{Receiver, Sender};
+use std::{
+    fs,
+    io::Read,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// The number of threads of each url source, which limits its concurrent requests.
+const FETCH_THREAD_COUNT: usize = 4;
+
+/// The configuration of a remote tile server.
+#[derive(Clone, Debug)]
+pub struct TileServer {
+    /// The url of the tiles, where `{z}`, `{x}` and `{y}` are replaced by the tile coordinate.
+    ///
+    /// E.g. `https://tiles.example.com/terrain-rgb/{z}/{x}/{y}.png`.
+    pub url: String,
+    /// The zoom level of the tiles, that correspond to the nodes with lod zero.
+    pub max_zoom: u32,
+    /// Whether the server uses the TMS scheme, which counts the rows from the south.
+    pub tms: bool,
+}
+
+impl TileServer {
+    /// Creates the config of an XYZ tile server.
+    pub fn new(url: String, max_zoom: u32) -> Self {
+        Self {
+            url,
+            max_zoom,
+            tms: false,
+        }
+    }
+
+    /// Formats the url of the tile, that corresponds to the node.
+    fn tile_url(&self, node_id: NodeId) -> String {
+        let coord = NodeCoordinate::from(node_id);
+        let zoom = self.max_zoom.saturating_sub(coord.lod);
+        let y = if self.tms {
+            ((1 << zoom) - 1) - coord.y
+        } else {
+            coord.y
+        };
+
+        self.url
+            .replace("{z}", &zoom.to_string())
+            .replace("{x}", &coord.x.to_string())
+            .replace("{y}", &y.to_string())
+    }
+}
+
+pub(crate) struct AttachmentFromUrl {
+    pub(crate) server: TileServer,
+    pub(crate) texture_size: u32,
+    pub(crate) border_size: u32,
+    pub(crate) format: TextureFormat,
+    pub(crate) file_format: FileFormat,
+}
+
+impl AttachmentFromUrl {
+    pub(crate) fn new(attachment: &AttachmentConfig, server: TileServer) -> Self {
+        Self {
+            server,
+            texture_size: attachment.texture_size,
+            border_size: attachment.border_size,
+            format: attachment.format.into(),
+            file_format: attachment.file_format,
+        }
+    }
+
+    /// Decodes the fetched tile into the data of the node.
+    fn decode(&self, bytes: &[u8], height: f32) -> Result<Image> {
+        let image = match self.file_format {
+            FileFormat::QuantizedMesh { min_elevation } => QuantizedMesh::decode(bytes)?.to_image(
+                self.texture_size,
+                self.border_size,
+                self.format,
+                min_elevation,
+                height,
+            )?,
+            file_format => {
+                let mut image = decode_image(bytes, file_format, CompressedImageFormats::NONE)?;
+
+                if let FileFormat::TerrainRGB { min_elevation } = file_format {
+                    image.data = decode_heights(&image.data, self.format, min_elevation, height)?;
+                }
+
+                image
+            }
+        };
+
+        Ok(image)
+    }
+
+    /// Creates an empty image, which replaces tiles that could not be fetched.
+    fn empty_image(&self) -> Image {
+        let pixel = vec![0; self.format.describe().block_size as usize];
+
+        Image::new_fill(
+            Extent3d {
+                width: self.texture_size,
+                height: self.texture_size,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &pixel,
+            self.format,
+        )
+    }
+}
+
+/// A cached tile file.
+struct CachedTile {
+    size: u64,
+    /// The tick of the last access, used to evict the least recently used tiles first.
+    last_access: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    tiles: HashMap<String, CachedTile>,
+    total_size: u64,
+    tick: u64,
+}
+
+/// A persistent cache of fetched tiles in a local directory, which is shared between runs.
+///
+/// Once the cache exceeds its maximum size, the least recently used tiles are evicted.
+/// The recency of the tiles cached by previous runs is restored from their modification time.
+pub struct TileCache {
+    directory: PathBuf,
+    max_size: u64,
+    offline: AtomicBool,
+    state: Mutex<CacheState>,
+}
+
+impl TileCache {
+    /// Opens the cache in the directory, which may hold up to the maximum size (in bytes).
+    ///
+    /// The directory is created, if it does not exist yet.
+    pub fn new(directory: impl Into<PathBuf>, max_size: u64) -> Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+
+        let mut tiles = Vec::new();
+
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            // skips unfinished writes of crashed runs
+            if !name.ends_with(".tile") {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            tiles.push((metadata.modified().ok(), name, metadata.len()));
+        }
+
+        tiles.sort();
+
+        let mut state = CacheState::default();
+
+        for (_, name, size) in tiles {
+            state.tick += 1;
+            state.total_size += size;
+            state.tiles.insert(
+                name,
+                CachedTile {
+                    size,
+                    last_access: state.tick,
+                },
+            );
+        }
+
+        let cache = Self {
+            directory,
+            max_size,
+            offline: AtomicBool::new(false),
+            state: Mutex::new(state),
+        };
+
+        cache.evict(&mut cache.state.lock().unwrap());
+
+        Ok(cache)
+    }
+
+    /// Serves the tiles from the cache only, without fetching the missing ones.
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::Relaxed);
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    /// The total size of the cached tiles in bytes.
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().total_size
+    }
+
+    /// Removes all cached tiles.
+    pub fn clear(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        for name in state.tiles.keys() {
+            fs::remove_file(self.directory.join(name))?;
+        }
+
+        *state = CacheState::default();
+
+        Ok(())
+    }
+
+    /// The name of the cache file of the url, using a hash that is stable between runs.
+    fn file_name(url: &str) -> String {
+        // FNV-1a
+        let hash = url.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+
+        format!("{hash:016x}.tile")
+    }
+
+    /// Removes the least recently used tiles, until the cache fits into its maximum size.
+    fn evict(&self, state: &mut CacheState) {
+        while state.total_size > self.max_size {
+            let Some(name) = state
+                .tiles
+                .iter()
+                .min_by_key(|(_, tile)| tile.last_access)
+                .map(|(name, _)| name.clone())
+            else {
+                break;
+            };
+
+            let tile = state.tiles.remove(&name).unwrap();
+            state.total_size -= tile.size;
+
+            let _ = fs::remove_file(self.directory.join(name));
+        }
+    }
+
+    /// Reads the tile of the url from the cache.
+    fn get(&self, url: &str) -> Option<Vec<u8>> {
+        let name = Self::file_name(url);
+
+        {
+            let mut state = self.state.lock().unwrap();
+            state.tick += 1;
+            let tick = state.tick;
+            state.tiles.get_mut(&name)?.last_access = tick;
+        }
+
+        fs::read(self.directory.join(name)).ok()
+    }
+
+    /// Stores the tile of the url in the cache.
+    fn insert(&self, url: &str, bytes: &[u8]) -> Result<()> {
+        let name = Self::file_name(url);
+
+        // the tile is written to a temporary file first, so that it never gets cached partially
+        let temporary = self.directory.join(format!("{name}.tmp"));
+        fs::write(&temporary, bytes)?;
+        fs::rename(&temporary, self.directory.join(&name))?;
+
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+
+        let tile = CachedTile {
+            size: bytes.len() as u64,
+            last_access: state.tick,
+        };
+
+        state.total_size += tile.size;
+
+        if let Some(previous) = state.tiles.insert(name, tile) {
+            state.total_size -= previous.size;
+        }
+
+        self.evict(&mut state);
+
+        Ok(())
+    }
+
+    /// Returns the cached tile of the url, or fetches and caches it, unless the cache is offline.
+    fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+        if let Some(bytes) = self.get(url) {
+            return Ok(bytes);
+        }
+
+        if self.is_offline() {
+            return Err(anyhow!("The tile {url} is not cached."));
+        }
+
+        let bytes = fetch_tile(url)?;
+
+        if let Err(error) = self.insert(url, &bytes) {
+            warn!("Failed to cache the tile {url}: {error}");
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// A tile, which is requested from a fetch thread.
+struct FetchJob {
+    node_id: NodeId,
+    url: String,
+    cache: Option<Arc<TileCache>>,
+}
+
+/// Fetches the requested tiles one after another, until its url source is dropped.
+fn fetch_tiles(jobs: Receiver<FetchJob>, results: Sender<(NodeId, Result<Vec<u8>>)>) {
+    for FetchJob {
+        node_id,
+        url,
+        cache,
+    } in jobs
+    {
+        let result = match cache {
+            Some(cache) => cache.fetch(&url),
+            None => fetch_tile(&url),
+        };
+
+        if results.send((node_id, result)).is_err() {
+            break;
+        }
+    }
+}
+
+/// A node source, that fetches the tiles of one attachment from a tile server.
+///
+/// Tiles, that could not be fetched or decoded, are replaced by empty images,
+/// because tile servers usually omit the tiles without data (e.g. over the ocean).
+pub struct UrlSource {
+    attachment: AttachmentFromUrl,
+    height: f32,
+    /// The cache of the fetched tiles, if present.
+    cache: Option<Arc<TileCache>>,
+    /// Queues the requested tiles for the fetch threads.
+    jobs: Sender<FetchJob>,
+    /// Receives the fetched tiles from the fetch threads.
+    results: Receiver<(NodeId, Result<Vec<u8>>)>,
+}
+
+impl UrlSource {
+    /// Creates a url source for the attachment of the terrain, which is served by the tile server.
+    pub fn new(config: &TerrainConfig, attachment: &AttachmentConfig, server: TileServer) -> Self {
+        Self::from_attachment(AttachmentFromUrl::new(attachment, server), config.height)
+    }
+
+    fn from_attachment(attachment: AttachmentFromUrl, height: f32) -> Self {
+        let (jobs, job_receiver) = crossbeam_channel::unbounded();
+        let (result_sender, results) = crossbeam_channel::unbounded();
+
+        for _ in 0..FETCH_THREAD_COUNT {
+            let job_receiver = job_receiver.clone();
+            let result_sender = result_sender.clone();
+
+            thread::Builder::new()
+                .name("tile fetcher".to_string())
+                .spawn(move || fetch_tiles(job_receiver, result_sender))
+                .expect("Failed to spawn a tile fetch thread.");
+        }
+
+        Self {
+            attachment,
+            height,
+            cache: None,
+            jobs,
+            results,
+        }
+    }
+
+    /// Caches the fetched tiles in the tile cache.
+    pub fn set_cache(&mut self, cache: Arc<TileCache>) {
+        self.cache = Some(cache);
+    }
+}
+
+impl NodeSource for UrlSource {
+    fn request(&mut self, node_id: NodeId) {
+        let job = FetchJob {
+            node_id,
+            url: self.attachment.server.tile_url(node_id),
+            cache: self.cache.clone(),
+        };
+
+        // the fetch threads only stop, once the source is dropped
+        let _ = self.jobs.send(job);
+    }
+
+    fn poll(&mut self, images: &mut Assets<Image>) -> Vec<(NodeId, Result<Handle<Image>>)> {
+        let UrlSource {
+            ref attachment,
+            height,
+            ref results,
+            ..
+        } = *self;
+
+        results
+            .try_iter()
+            .map(|(node_id, result)| {
+                let mut image = match result.and_then(|bytes| attachment.decode(&bytes, height)) {
+                    Ok(image) => image,
+                    Err(error) => {
+                        warn!("Failed to fetch the tile of node {node_id}: {error}");
+                        attachment.empty_image()
+                    }
+                };
+
+                image.texture_descriptor.format = attachment.format;
+                (node_id, Ok(images.add(image)))
+            })
+            .collect()
+    }
+}
+
+/// This component is used to stream attachments from tile servers into the corresponding [`NodeAtlas`].
+#[derive(Default, Component)]
+pub struct AttachmentFromUrlLoader {
+    pub(crate) sources: HashMap<AttachmentIndex, UrlSource>,
+    /// The minmax attachment, which is derived from the height tiles, as servers do not provide it.
+    pub(crate) minmax_attachment: Option<AttachmentIndex>,
+    /// The cache of the fetched tiles, if present.
+    cache: Option<Arc<TileCache>>,
+}
+
+impl AttachmentFromUrlLoader {
+    /// Caches the fetched tiles of all attachments in the tile cache.
+    ///
+    /// The cache may be shared between terrains, whose tile urls differ.
+    pub fn set_cache(&mut self, cache: Arc<TileCache>) {
+        for source in self.sources.values_mut() {
+            source.set_cache(cache.clone());
+        }
+
+        self.cache = Some(cache);
+    }
+
+    /// Streams the attachment through the url source, which shares the cache of the loader.
+    pub(crate) fn add_source(&mut self, attachment_index: AttachmentIndex, mut source: UrlSource) {
+        if let Some(cache) = &self.cache {
+            source.set_cache(cache.clone());
+        }
+
+        self.sources.insert(attachment_index, source);
+    }
+}
+
+fn fetch_tile(url: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+
+    ureq::get(url)
+        .call()?
+        .into_reader()
+        .read_to_end(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+/// Derives the minmax data of a node from its height data, without any extra filtering.
+fn height_to_minmax(height_image: &Image) -> Image {
+    let data = match height_image.texture_descriptor.format {
+        TextureFormat::R32Float => height_image
+            .data
+            .chunks_exact(4)
+            .flat_map(|pixel| {
+                let value = f32::from_le_bytes(pixel.try_into().unwrap()) * u16::MAX as f32;
+                let (min, max) = (value.floor() as u16, value.ceil() as u16);
+
+                [min.to_le_bytes(), max.to_le_bytes()].concat()
+            })
+            .collect(),
+        _ => height_image
+            .data
+            .chunks_exact(2)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[0], pixel[1]])
+            .collect(),
+    };
+
+    let mut image = Image::new(
+        height_image.texture_descriptor.size,
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rg16Unorm,
+    );
+    image.texture_descriptor.usage |= TextureUsages::COPY_SRC;
+    image
+}
+
+pub(crate) fn start_loading_attachment_from_url(
+    mut terrain_query: Query<(&NodeAtlas, &mut AttachmentFromUrlLoader)>,
+) {
+    for (node_atlas, mut loader) in terrain_query.iter_mut() {
+        for &node_id in node_atlas.load_events.iter() {
+            for source in loader.sources.values_mut() {
+                source.request(node_id);
+            }
+        }
+    }
+}
+
+pub(crate) fn finish_loading_attachment_from_url(
+    mut images: ResMut<Assets<Image>>,
+    mut terrain_query: Query<(&mut NodeAtlas, &mut AttachmentFromUrlLoader)>,
+) {
+    for (mut node_atlas, mut loader) in terrain_query.iter_mut() {
+        let AttachmentFromUrlLoader {
+            ref mut sources,
+            minmax_attachment,
+            ..
+        } = *loader;
+
+        for (&attachment_index, source) in sources.iter_mut() {
+            for (node_id, result) in source.poll(&mut images) {
+                if let (Some(minmax_index), Ok(handle)) = (minmax_attachment, &result) {
+                    if attachment_index == HEIGHT_ATTACHMENT {
+                        let minmax = images.get(handle).map(height_to_minmax).ok_or_else(|| {
+                            anyhow!("The height tile of node {node_id} is not available.")
+                        });
+                        let minmax = minmax.map(|image| images.add(image));
+
+                        finish_loading_node(
+                            &mut node_atlas,
+                            &mut images,
+                            node_id,
+                            minmax_index,
+                            minmax,
+                        );
+                    }
+                }
+
+                finish_loading_node(
+                    &mut node_atlas,
+                    &mut images,
+                    node_id,
+                    attachment_index,
+                    result,
+                );
+            }
+        }
+    }
+}
@@ -3,7 +3,8 @@ use crate::{
         culling::CullingBindGroup,
         render_pipeline::TerrainPipelineConfig,
         shaders::{PREPARE_INDIRECT_SHADER, REFINE_TILES_SHADER},
-        terrain_data::terrain_bind_group_layout,
+        shadows::TerrainShadowData,
+        terrain_data::{atlas_shard_shader_defs, terrain_bind_group_layout},
         terrain_view_data::TerrainViewConfigUniform,
         terrain_view_data::TerrainViewData,
         CULL_DATA_LAYOUT, PREPARE_INDIRECT_LAYOUT, REFINE_TILES_LAYOUT,
@@ -30,6 +31,7 @@ pub enum TerrainComputePipelineId {
     PrepareRoot,
     PrepareNext,
     PrepareRender,
+    BuildClipmap,
 }
 
 bitflags::bitflags! {
@@ -68,6 +70,8 @@ pub struct TerrainComputePipelines {
     pub(crate) refine_tiles_layout: BindGroupLayout,
     pub(crate) cull_data_layout: BindGroupLayout,
     pub(crate) terrain_layout: BindGroupLayout,
+    /// The shader defs of the shards of the attachments.
+    atlas_shard_shader_defs: Vec<ShaderDefVal>,
     prepare_indirect_shader: Handle<Shader>,
     refine_tiles_shader: Handle<Shader>,
     pipelines: [Option<CachedComputePipelineId>; TerrainComputePipelineId::COUNT],
@@ -81,7 +85,19 @@ impl FromWorld for TerrainComputePipelines {
         let prepare_indirect_layout = device.create_bind_group_layout(&PREPARE_INDIRECT_LAYOUT);
         let refine_tiles_layout = device.create_bind_group_layout(&REFINE_TILES_LAYOUT);
         let cull_data_layout = device.create_bind_group_layout(&CULL_DATA_LAYOUT);
-        let terrain_layout = terrain_bind_group_layout(device, config.attachment_count);
+        let terrain_layout = terrain_bind_group_layout(
+            device,
+            config.attachment_count,
+            config.atlas_shard_count,
+            config.decals,
+            config.detail_layer,
+        );
+
+        let atlas_shard_shader_defs = atlas_shard_shader_defs(
+            config.attachment_count,
+            config.atlas_shard_size,
+            config.atlas_shard_count,
+        );
 
         let prepare_indirect_shader = PREPARE_INDIRECT_SHADER.typed();
         let refine_tiles_shader = REFINE_TILES_SHADER.typed();
@@ -91,6 +107,7 @@ impl FromWorld for TerrainComputePipelines {
             refine_tiles_layout,
             cull_data_layout,
             terrain_layout,
+            atlas_shard_shader_defs,
             prepare_indirect_shader,
             refine_tiles_shader,
             pipelines: [None; TerrainComputePipelineId::COUNT],
@@ -106,7 +123,8 @@ impl SpecializedComputePipeline for TerrainComputePipelines {
         let shader;
         let entry_point;
 
-        let shader_defs = key.1.shader_defs();
+        let mut shader_defs = key.1.shader_defs();
+        shader_defs.extend(self.atlas_shard_shader_defs.iter().cloned());
 
         match key.0 {
             TerrainComputePipelineId::RefineTiles => {
@@ -148,6 +166,15 @@ impl SpecializedComputePipeline for TerrainComputePipelines {
                 shader = self.prepare_indirect_shader.clone();
                 entry_point = "prepare_render".into();
             }
+            TerrainComputePipelineId::BuildClipmap => {
+                layout = vec![
+                    self.refine_tiles_layout.clone(),
+                    self.cull_data_layout.clone(),
+                    self.terrain_layout.clone(),
+                ];
+                shader = self.refine_tiles_shader.clone();
+                entry_point = "build_clipmap".into();
+            }
         }
 
         ComputePipelineDescriptor {
@@ -182,13 +209,28 @@ impl TerrainComputeNode {
         view_data: &'a TerrainViewData,
         terrain_data: &'a TerrainData,
         culling_bind_group: &'a BindGroup,
-        refinement_count: u32,
+        view_config: &TerrainViewConfigUniform,
     ) {
         pass.set_bind_group(0, &view_data.refine_tiles_bind_group, &[]);
         pass.set_bind_group(1, culling_bind_group, &[]);
         pass.set_bind_group(2, &terrain_data.terrain_bind_group, &[]);
         pass.set_bind_group(3, &view_data.prepare_indirect_bind_group, &[]);
 
+        if view_config.clipmap_ring_count > 0 {
+            let ring_tiles = 2 * view_config.clipmap_ring_size;
+            let tile_count = view_config.clipmap_ring_count * ring_tiles * ring_tiles;
+
+            pass.set_pipeline(pipelines[TerrainComputePipelineId::BuildClipmap as usize]);
+            pass.dispatch_workgroups((tile_count + 63) / 64, 1, 1);
+
+            pass.set_pipeline(pipelines[TerrainComputePipelineId::PrepareRender as usize]);
+            pass.dispatch_workgroups(1, 1, 1);
+
+            return;
+        }
+
+        let refinement_count = view_config.refinement_count;
+
         pass.set_pipeline(pipelines[TerrainComputePipelineId::PrepareRoot as usize]);
         pass.dispatch_workgroups(1, 1, 1);
 
@@ -227,6 +269,7 @@ impl render_graph::Node for TerrainComputeNode {
         let terrain_data = world.resource::<TerrainComponents<TerrainData>>();
         let terrain_view_data = world.resource::<TerrainViewComponents<TerrainViewData>>();
         let culling_bind_groups = world.resource::<TerrainViewComponents<CullingBindGroup>>();
+        let shadow_data = world.resource::<TerrainViewComponents<TerrainShadowData>>();
 
         let debug = world.get_resource::<DebugTerrain>();
 
@@ -254,18 +297,39 @@ impl render_graph::Node for TerrainComputeNode {
         for terrain in self.terrain_query.iter_manual(world) {
             let terrain_data = terrain_data.get(&terrain).unwrap();
             for view in self.view_query.iter_manual(world) {
-                let view_config = view_config_uniforms.get(&(terrain, view)).unwrap();
-                let view_data = terrain_view_data.get(&(terrain, view)).unwrap();
-                let culling_bind_group = culling_bind_groups.get(&(terrain, view)).unwrap();
-
-                TerrainComputeNode::tessellate_terrain(
-                    pass,
-                    pipelines,
-                    view_data,
-                    terrain_data,
-                    &culling_bind_group.value,
-                    view_config.refinement_count,
-                );
+                // only tessellate the terrain for views registered with it
+                if let (Some(view_config), Some(view_data), Some(culling_bind_group)) = (
+                    view_config_uniforms.get(&(terrain, view)),
+                    terrain_view_data.get(&(terrain, view)),
+                    culling_bind_groups.get(&(terrain, view)),
+                ) {
+                    TerrainComputeNode::tessellate_terrain(
+                        pass,
+                        pipelines,
+                        view_data,
+                        terrain_data,
+                        &culling_bind_group.value,
+                        view_config,
+                    );
+                }
+
+                // the coarse tiles, which cast the shadows of the directional lights
+                let Some(data) = shadow_data.get(&(terrain, view)) else {
+                    continue;
+                };
+
+                for tiles in data.lights.values() {
+                    if let Some(culling_bind_group) = &tiles.culling_bind_group {
+                        TerrainComputeNode::tessellate_terrain(
+                            pass,
+                            pipelines,
+                            &tiles.view_data,
+                            terrain_data,
+                            culling_bind_group,
+                            &tiles.view_config_uniform,
+                        );
+                    }
+                }
             }
         }
 
@@ -1,10 +1,18 @@
-use crate::{terrain::Terrain, TerrainComputePipelines, TerrainView, TerrainViewComponents};
+use crate::{
+    render::depth_pyramid::DepthPyramids,
+    render::terrain_view_data::{TerrainViewConfigUniform, TerrainViewData},
+    terrain::TerrainComponents,
+    terrain_data::gpu_node_atlas::GpuNodeAtlas,
+    TerrainComputePipelines, TerrainView, TerrainViewComponents,
+};
 use bevy::{
-    math::Vec3Swizzles,
     prelude::*,
     render::{render_resource::*, renderer::RenderDevice, view::ExtractedView},
 };
 
+/// The data required to cull the tiles of a terrain from the point of view of a viewer.
+///
+/// The position and the planes are specified in the local space of the terrain.
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, ShaderType)]
 pub struct CullingData {
@@ -12,6 +20,13 @@ pub struct CullingData {
     pub(crate) view_proj: Mat4,
     pub(crate) model: Mat4,
     pub(crate) planes: [Vec4; 5],
+    /// The view projection of the depth pyramid, which includes the transform of the terrain.
+    pub(crate) occlusion_view_proj: Mat4,
+    /// The size of the first mip level of the depth pyramid.
+    pub(crate) occlusion_size: Vec2,
+    pub(crate) occlusion_mip_count: u32,
+    /// Whether the tiles are culled using the depth pyramid.
+    pub(crate) occlusion_culling: u32,
 }
 
 #[derive(Component)]
@@ -34,51 +49,116 @@ pub fn planes(view_projection: &Mat4) -> [Vec4; 5] {
     planes
 }
 
+/// Creates the bind group, which culls the tiles of a terrain using the culling data.
+pub(crate) fn create_culling_bind_group(
+    device: &RenderDevice,
+    compute_pipelines: &TerrainComputePipelines,
+    culling_data: &CullingData,
+    gpu_node_atlas: &GpuNodeAtlas,
+    depth_pyramid_view: &TextureView,
+) -> BindGroup {
+    let mut buffer = encase::UniformBuffer::new(Vec::new());
+    buffer.write(culling_data).unwrap();
+
+    let buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+        label: None,
+        contents: &buffer.into_inner(),
+        usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+    });
+
+    device.create_bind_group(&BindGroupDescriptor {
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: gpu_node_atlas.bounds_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::TextureView(depth_pyramid_view),
+            },
+        ],
+        label: None,
+        layout: &compute_pipelines.cull_data_layout,
+    })
+}
+
 pub(crate) fn prepare_and_queue_terrain_culling_bind_group(
     device: Res<RenderDevice>,
     compute_pipelines: Res<TerrainComputePipelines>,
+    terrain_view_data: Res<TerrainViewComponents<TerrainViewData>>,
+    view_config_uniforms: Res<TerrainViewComponents<TerrainViewConfigUniform>>,
+    gpu_node_atlases: Res<TerrainComponents<GpuNodeAtlas>>,
+    depth_pyramids: Res<DepthPyramids>,
     mut culling_bind_groups: ResMut<TerrainViewComponents<CullingBindGroup>>,
-    terrain_query: Query<Entity, With<Terrain>>,
-    view_query: Query<(Entity, &ExtractedView), With<TerrainView>>,
+    view_query: Query<&ExtractedView, With<TerrainView>>,
 ) {
-    for (view, extracted_view) in view_query.iter() {
+    culling_bind_groups.0.clear();
+
+    for &(terrain, view) in terrain_view_data.0.keys() {
+        let (extracted_view, view_config_uniform) = match (
+            view_query.get(view),
+            view_config_uniforms.get(&(terrain, view)),
+        ) {
+            (Ok(extracted_view), Some(view_config_uniform)) => {
+                (extracted_view, view_config_uniform)
+            }
+            _ => continue,
+        };
+
         let view_proj =
             extracted_view.projection * extracted_view.transform.compute_matrix().inverse();
 
-        let planes = planes(&view_proj);
-
-        for terrain in terrain_query.iter() {
-            let culling_data = CullingData {
-                world_position: extracted_view.transform.translation().xyzx(),
-                view_proj,
-                model: default(),
-                planes,
-            };
+        // the tiles are culled in the local space of the terrain
+        let model = view_config_uniform.model;
 
-            let mut buffer = encase::UniformBuffer::new(Vec::new());
-            buffer.write(&culling_data).unwrap();
+        // the depth pyramid is only used once it has been built
+        let depth_pyramid = depth_pyramids
+            .pyramids
+            .get(&view)
+            .filter(|_| view_config_uniform.occlusion_culling != 0)
+            .and_then(|pyramid| Some((pyramid, pyramid.view_proj?)));
 
-            let buffer = device.create_buffer_with_data(&BufferInitDescriptor {
-                label: None,
-                contents: &buffer.into_inner(),
-                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        let (occlusion_view_proj, occlusion_size, occlusion_mip_count) =
+            depth_pyramid.map_or((Mat4::IDENTITY, Vec2::ZERO, 0), |(pyramid, view_proj)| {
+                (
+                    (view_proj.as_dmat4() * model.as_dmat4()).as_mat4(),
+                    pyramid.size.as_vec2(),
+                    pyramid.mip_count,
+                )
             });
 
-            let cull_bind_group = device.create_bind_group(&BindGroupDescriptor {
-                entries: &[BindGroupEntry {
-                    binding: 0,
-                    resource: buffer.as_entire_binding(),
-                }],
-                label: None,
-                layout: &compute_pipelines.cull_data_layout,
-            });
+        let culling_data = CullingData {
+            world_position: view_config_uniform.view_position,
+            view_proj,
+            model,
+            planes: planes(&(view_proj.as_dmat4() * model.as_dmat4()).as_mat4()),
+            occlusion_view_proj,
+            occlusion_size,
+            occlusion_mip_count,
+            occlusion_culling: depth_pyramid.is_some() as u32,
+        };
+
+        let Some(gpu_node_atlas) = gpu_node_atlases.get(&terrain) else {
+            continue;
+        };
+
+        let cull_bind_group = create_culling_bind_group(
+            &device,
+            &compute_pipelines,
+            &culling_data,
+            gpu_node_atlas,
+            depth_pyramid.map_or(&depth_pyramids.fallback_view, |(pyramid, _)| &pyramid.view),
+        );
 
-            culling_bind_groups.insert(
-                (terrain, view),
-                CullingBindGroup {
-                    value: cull_bind_group,
-                },
-            );
-        }
+        culling_bind_groups.insert(
+            (terrain, view),
+            CullingBindGroup {
+                value: cull_bind_group,
+            },
+        );
     }
 }
@@ -0,0 +1,260 @@
+//! Projects decals (e.g. scorch marks, road markings or blood splats) onto the terrain surface.
+//!
+//! Generic mesh decals break, once the geometry of the terrain morphs between lods or its
+//! nodes are swapped. Instead, the decals of the terrain are evaluated by the shared fragment
+//! entry point in the local space of the terrain, where they stay in place regardless of the lod.
+//! The default and the splat material blend the decals into their base color before lighting.
+//!
+//! Enable the decals with [`TerrainPlugin::decals`](crate::TerrainPlugin::decals) and insert
+//! the [`TerrainDecals`] component alongside the terrain.
+//! The decal texture stores one decal per layer of a 2D array texture.
+
+use crate::{
+    render::{render_pipeline::TerrainPipelineConfig, terrain_data::TerrainData},
+    terrain::TerrainComponents,
+    terrain_data::MAX_ATTACHMENT_COUNT,
+};
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Extract,
+    },
+};
+
+/// The binding of the decal buffer, which is followed by the decal texture.
+pub(crate) const DECAL_BINDING: u32 = MAX_ATTACHMENT_COUNT as u32 + 2;
+
+/// The maximum number of decals per terrain.
+pub const MAX_DECAL_COUNT: usize = 256;
+
+/// A decal, which is projected onto the terrain from above.
+#[derive(Clone, Copy, Debug)]
+pub struct Decal {
+    /// The center of the decal along the x and z axes (in the local space of the terrain).
+    pub position: Vec2,
+    /// The size of the decal along its axes.
+    pub size: Vec2,
+    /// The rotation of the decal around the y axis in radians.
+    pub rotation: f32,
+    /// The layer of the decal texture.
+    pub layer: u32,
+    /// The opacity of the decal (from zero to one).
+    pub opacity: f32,
+}
+
+impl Decal {
+    /// Creates a new decal of the layer at the position.
+    pub fn new(layer: u32, position: Vec2, size: Vec2) -> Self {
+        Self {
+            position,
+            size,
+            rotation: 0.0,
+            layer,
+            opacity: 1.0,
+        }
+    }
+}
+
+/// This component projects its decals onto the terrain.
+#[derive(Clone, Component)]
+pub struct TerrainDecals {
+    /// The 2D array texture, which stores one decal per layer.
+    pub texture: Handle<Image>,
+    /// The decals of the terrain, where later decals are drawn on top of earlier ones.
+    /// Only the first [`MAX_DECAL_COUNT`] decals are drawn.
+    pub decals: Vec<Decal>,
+}
+
+impl TerrainDecals {
+    /// Creates a new set of decals, which sample the decal texture.
+    pub fn new(texture: Handle<Image>) -> Self {
+        Self {
+            texture,
+            decals: Vec::new(),
+        }
+    }
+}
+
+/// The decal data that is available in shaders.
+#[derive(Clone, Copy, Default, ShaderType)]
+struct DecalUniform {
+    position: Vec2,
+    half_size: Vec2,
+    /// The cosine and sine of the rotation.
+    rotation: Vec2,
+    layer: u32,
+    opacity: f32,
+}
+
+impl From<&Decal> for DecalUniform {
+    fn from(decal: &Decal) -> Self {
+        Self {
+            position: decal.position,
+            half_size: decal.size / 2.0,
+            rotation: Vec2::from_angle(decal.rotation),
+            layer: decal.layer,
+            opacity: decal.opacity.clamp(0.0, 1.0),
+        }
+    }
+}
+
+#[derive(ShaderType)]
+struct DecalList {
+    count: u32,
+    #[size(runtime)]
+    decals: Vec<DecalUniform>,
+}
+
+impl DecalList {
+    fn new(decals: &[Decal]) -> Self {
+        let decals = decals
+            .iter()
+            .take(MAX_DECAL_COUNT)
+            .map(DecalUniform::from)
+            .collect::<Vec<_>>();
+
+        Self {
+            count: decals.len() as u32,
+            decals,
+        }
+    }
+
+    /// Writes the list into the bytes of a storage buffer with room for all decals.
+    fn bytes(&self) -> Vec<u8> {
+        let mut buffer = encase::StorageBuffer::new(Vec::new());
+        buffer.write(self).unwrap();
+        buffer.into_inner()
+    }
+}
+
+/// Creates a storage buffer, which fits the maximum number of decals.
+fn create_decal_buffer(device: &RenderDevice) -> Buffer {
+    let capacity = DecalList {
+        count: 0,
+        decals: vec![DecalUniform::default(); MAX_DECAL_COUNT],
+    };
+
+    device.create_buffer_with_data(&BufferInitDescriptor {
+        label: "decal_buffer".into(),
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        contents: &capacity.bytes(),
+    })
+}
+
+/// The empty decal buffer and texture, which are bound to the terrains without decals.
+#[derive(Resource)]
+pub struct DecalFallback {
+    pub(crate) buffer: Buffer,
+    pub(crate) texture_view: TextureView,
+}
+
+impl FromWorld for DecalFallback {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+
+        let texture = device.create_texture(&TextureDescriptor {
+            label: "decal_fallback_texture".into(),
+            size: Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8UnormSrgb,
+            usage: TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        Self {
+            buffer: create_decal_buffer(device),
+            texture_view: texture.create_view(&TextureViewDescriptor {
+                dimension: Some(TextureViewDimension::D2Array),
+                ..default()
+            }),
+        }
+    }
+}
+
+/// Stores the decal buffer of a terrain alongside the decals extracted this frame.
+pub struct GpuTerrainDecals {
+    buffer: Buffer,
+    texture: Handle<Image>,
+    decals: Vec<Decal>,
+}
+
+pub(crate) fn extract_terrain_decals(
+    device: Res<RenderDevice>,
+    pipeline_config: Res<TerrainPipelineConfig>,
+    mut gpu_terrain_decals: ResMut<TerrainComponents<GpuTerrainDecals>>,
+    terrain_query: Extract<Query<(Entity, &TerrainDecals)>>,
+) {
+    if !pipeline_config.decals {
+        return;
+    }
+
+    // the decals of terrains, whose component has been removed, are cleared
+    for gpu_decals in gpu_terrain_decals.0.values_mut() {
+        gpu_decals.decals.clear();
+    }
+
+    for (terrain, decals) in terrain_query.iter() {
+        let gpu_decals = gpu_terrain_decals
+            .0
+            .entry(terrain)
+            .or_insert_with(|| GpuTerrainDecals {
+                buffer: create_decal_buffer(&device),
+                texture: decals.texture.clone(),
+                decals: Vec::new(),
+            });
+
+        gpu_decals.texture = decals.texture.clone();
+        gpu_decals.decals.clone_from(&decals.decals);
+    }
+}
+
+pub(crate) fn prepare_terrain_decals(
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    images: Res<RenderAssets<Image>>,
+    gpu_terrain_decals: Res<TerrainComponents<GpuTerrainDecals>>,
+    mut terrain_data: ResMut<TerrainComponents<TerrainData>>,
+) {
+    for (terrain, gpu_decals) in gpu_terrain_decals.0.iter() {
+        let Some(terrain_data) = terrain_data.get_mut(terrain) else {
+            continue;
+        };
+
+        queue.write_buffer(
+            &gpu_decals.buffer,
+            0,
+            &DecalList::new(&gpu_decals.decals).bytes(),
+        );
+
+        if terrain_data.decal_texture.as_ref() == Some(&gpu_decals.texture) {
+            continue;
+        }
+
+        // the bind group keeps the fallback texture, until the decal texture has been loaded
+        let Some(texture) = images.get(&gpu_decals.texture) else {
+            continue;
+        };
+
+        let texture_view = texture.texture.create_view(&TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::D2Array),
+            ..default()
+        });
+
+        terrain_data.bind_decals(
+            &device,
+            &images,
+            &gpu_decals.buffer,
+            &gpu_decals.texture,
+            &texture_view,
+        );
+    }
+}
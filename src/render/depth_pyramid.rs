@@ -0,0 +1,353 @@
+//! Builds a hierarchical depth buffer (depth pyramid) of the views, which occlusion cull the terrain.
+//!
+//! After a view has been rendered, its depth buffer is copied and successively down sampled,
+//! where each texel stores the farthest depth of the region it covers.
+//! In the following frame, the tiles are projected with the view projection of the pyramid
+//! and culled, if they lie behind the depth stored in the pyramid, e.g. valleys hidden by mountains.
+//! As the pyramid lags behind by one frame, fast camera movements may cause tiles
+//! to appear one frame late.
+
+use crate::{
+    render::{
+        shaders::DEPTH_PYRAMID_SHADER, terrain_view_data::TerrainViewConfigUniform,
+        DEPTH_PYRAMID_COPY_LAYOUT, DEPTH_PYRAMID_COPY_MULTISAMPLED_LAYOUT,
+        DEPTH_PYRAMID_DOWNSAMPLE_LAYOUT,
+    },
+    TerrainView, TerrainViewComponents,
+};
+use bevy::{
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_graph::{self},
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice},
+        view::{ExtractedView, ViewDepthTexture},
+    },
+    utils::HashMap,
+};
+use std::num::NonZeroU32;
+
+/// The depth pyramid of a view.
+pub struct DepthPyramid {
+    /// The view of all mip levels, which is sampled during the culling.
+    pub(crate) view: TextureView,
+    /// The views of the individual mip levels.
+    mip_views: Vec<TextureView>,
+    /// The size of the first mip level, which matches the size of the depth buffer.
+    pub(crate) size: UVec2,
+    pub(crate) mip_count: u32,
+    /// The view projection matrix of the view, when the pyramid was last built.
+    pub(crate) view_proj: Option<Mat4>,
+    /// The view projection matrix of the current frame, which the pyramid is built with.
+    next_view_proj: Option<Mat4>,
+    /// The bind groups of the copy pass followed by the ones of the down sample passes.
+    bind_groups: Vec<BindGroup>,
+}
+
+impl DepthPyramid {
+    fn new(device: &RenderDevice, size: UVec2) -> Self {
+        let mip_count = 32 - size.max_element().leading_zeros();
+
+        let texture = device.create_texture(&TextureDescriptor {
+            label: "depth_pyramid_texture".into(),
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: mip_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R32Float,
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        let view = texture.create_view(&default());
+        let mip_views = (0..mip_count)
+            .map(|mip_level| {
+                texture.create_view(&TextureViewDescriptor {
+                    base_mip_level: mip_level,
+                    mip_level_count: NonZeroU32::new(1),
+                    ..default()
+                })
+            })
+            .collect();
+
+        Self {
+            view,
+            mip_views,
+            size,
+            mip_count,
+            view_proj: None,
+            next_view_proj: None,
+            bind_groups: Vec::new(),
+        }
+    }
+
+    fn mip_size(&self, mip_level: u32) -> UVec2 {
+        (self.size >> mip_level).max(UVec2::ONE)
+    }
+}
+
+/// Stores the depth pyramids of all views, that occlusion cull at least one terrain.
+#[derive(Resource)]
+pub struct DepthPyramids {
+    pub(crate) pyramids: HashMap<Entity, DepthPyramid>,
+    /// A placeholder, which is bound for the views without a (complete) depth pyramid.
+    pub(crate) fallback_view: TextureView,
+}
+
+impl FromWorld for DepthPyramids {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+
+        let fallback_view = device
+            .create_texture(&TextureDescriptor {
+                label: "depth_pyramid_fallback_texture".into(),
+                size: Extent3d::default(),
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::R32Float,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            })
+            .create_view(&default());
+
+        Self {
+            pyramids: default(),
+            fallback_view,
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct DepthPyramidPipelines {
+    copy_layout: BindGroupLayout,
+    copy_multisampled_layout: BindGroupLayout,
+    downsample_layout: BindGroupLayout,
+    copy_pipeline: CachedComputePipelineId,
+    copy_multisampled_pipeline: CachedComputePipelineId,
+    downsample_pipeline: CachedComputePipelineId,
+}
+
+impl FromWorld for DepthPyramidPipelines {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+
+        let copy_layout = device.create_bind_group_layout(&DEPTH_PYRAMID_COPY_LAYOUT);
+        let copy_multisampled_layout =
+            device.create_bind_group_layout(&DEPTH_PYRAMID_COPY_MULTISAMPLED_LAYOUT);
+        let downsample_layout = device.create_bind_group_layout(&DEPTH_PYRAMID_DOWNSAMPLE_LAYOUT);
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        let queue_pipeline =
+            |layout: &BindGroupLayout, shader_defs: Vec<ShaderDefVal>, entry_point: &str| {
+                pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                    label: Some("depth_pyramid_pipeline".into()),
+                    layout: vec![layout.clone()],
+                    push_constant_ranges: default(),
+                    shader: DEPTH_PYRAMID_SHADER.typed(),
+                    shader_defs,
+                    entry_point: entry_point.to_string().into(),
+                })
+            };
+
+        let copy_pipeline = queue_pipeline(&copy_layout, vec![], "copy_depth");
+        let copy_multisampled_pipeline = queue_pipeline(
+            &copy_multisampled_layout,
+            vec!["MULTISAMPLED".into()],
+            "copy_depth",
+        );
+        let downsample_pipeline = queue_pipeline(&downsample_layout, vec![], "downsample");
+
+        Self {
+            copy_layout,
+            copy_multisampled_layout,
+            downsample_layout,
+            copy_pipeline,
+            copy_multisampled_pipeline,
+            downsample_pipeline,
+        }
+    }
+}
+
+/// Creates, resizes and removes the depth pyramids of the views, depending on whether they
+/// occlusion cull any terrain.
+pub(crate) fn prepare_depth_pyramids(
+    device: Res<RenderDevice>,
+    view_config_uniforms: Res<TerrainViewComponents<TerrainViewConfigUniform>>,
+    mut depth_pyramids: ResMut<DepthPyramids>,
+    view_query: Query<(Entity, &ExtractedView, &ExtractedCamera), With<TerrainView>>,
+) {
+    let occluding_views = view_config_uniforms
+        .0
+        .iter()
+        .filter(|(_, view_config_uniform)| view_config_uniform.occlusion_culling != 0)
+        .map(|(&(_, view), _)| view)
+        .collect::<Vec<_>>();
+
+    depth_pyramids
+        .pyramids
+        .retain(|view, _| occluding_views.contains(view));
+
+    for (view, extracted_view, camera) in view_query.iter() {
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+
+        if !occluding_views.contains(&view) || size.min_element() == 0 {
+            continue;
+        }
+
+        let pyramid = depth_pyramids
+            .pyramids
+            .entry(view)
+            .or_insert_with(|| DepthPyramid::new(&device, size));
+
+        // the content of the pyramid is discarded, once the size of the view changes
+        if pyramid.size != size {
+            *pyramid = DepthPyramid::new(&device, size);
+        }
+
+        // the pyramid built last frame is used for culling this frame
+        if let Some(view_proj) = pyramid.next_view_proj.take() {
+            pyramid.view_proj = Some(view_proj);
+        }
+
+        pyramid.next_view_proj =
+            Some(extracted_view.projection * extracted_view.transform.compute_matrix().inverse());
+        pyramid.bind_groups.clear();
+    }
+}
+
+/// Creates the bind groups, which build the depth pyramids from the depth buffers of this frame.
+pub(crate) fn queue_depth_pyramids(
+    device: Res<RenderDevice>,
+    msaa: Res<Msaa>,
+    pipeline_cache: Res<PipelineCache>,
+    pipelines: Res<DepthPyramidPipelines>,
+    mut depth_pyramids: ResMut<DepthPyramids>,
+    view_query: Query<&ViewDepthTexture, With<TerrainView>>,
+) {
+    let multisampled = msaa.samples() > 1;
+
+    let (copy_layout, copy_pipeline) = if multisampled {
+        (
+            &pipelines.copy_multisampled_layout,
+            pipelines.copy_multisampled_pipeline,
+        )
+    } else {
+        (&pipelines.copy_layout, pipelines.copy_pipeline)
+    };
+
+    let ready = pipeline_cache.get_compute_pipeline(copy_pipeline).is_some()
+        && pipeline_cache
+            .get_compute_pipeline(pipelines.downsample_pipeline)
+            .is_some();
+
+    for (&view, pyramid) in depth_pyramids.pyramids.iter_mut() {
+        // the pyramid is only valid, if it is actually built this frame
+        let (Ok(depth_texture), true) = (view_query.get(view), ready) else {
+            pyramid.next_view_proj = None;
+            continue;
+        };
+
+        let depth_view = depth_texture.texture.create_view(&TextureViewDescriptor {
+            aspect: TextureAspect::DepthOnly,
+            ..default()
+        });
+
+        pyramid
+            .bind_groups
+            .push(device.create_bind_group(&BindGroupDescriptor {
+                label: "depth_pyramid_copy_bind_group".into(),
+                layout: copy_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(&depth_view),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::TextureView(&pyramid.mip_views[0]),
+                    },
+                ],
+            }));
+
+        for mip_level in 1..pyramid.mip_count as usize {
+            pyramid
+                .bind_groups
+                .push(device.create_bind_group(&BindGroupDescriptor {
+                    label: "depth_pyramid_downsample_bind_group".into(),
+                    layout: &pipelines.downsample_layout,
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 2,
+                            resource: BindingResource::TextureView(
+                                &pyramid.mip_views[mip_level - 1],
+                            ),
+                        },
+                        BindGroupEntry {
+                            binding: 3,
+                            resource: BindingResource::TextureView(&pyramid.mip_views[mip_level]),
+                        },
+                    ],
+                }));
+        }
+    }
+}
+
+/// Builds the depth pyramids, after the views have been rendered.
+pub struct DepthPyramidNode;
+
+impl render_graph::Node for DepthPyramidNode {
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipelines = world.resource::<DepthPyramidPipelines>();
+        let depth_pyramids = world.resource::<DepthPyramids>();
+
+        let copy_pipeline = if world.resource::<Msaa>().samples() > 1 {
+            pipelines.copy_multisampled_pipeline
+        } else {
+            pipelines.copy_pipeline
+        };
+
+        let (Some(copy_pipeline), Some(downsample_pipeline)) = (
+            pipeline_cache.get_compute_pipeline(copy_pipeline),
+            pipeline_cache.get_compute_pipeline(pipelines.downsample_pipeline),
+        ) else {
+            return Ok(());
+        };
+
+        let pass = &mut context
+            .command_encoder()
+            .begin_compute_pass(&ComputePassDescriptor::default());
+
+        for pyramid in depth_pyramids.pyramids.values() {
+            for (mip_level, bind_group) in pyramid.bind_groups.iter().enumerate() {
+                let pipeline = if mip_level == 0 {
+                    copy_pipeline
+                } else {
+                    downsample_pipeline
+                };
+
+                let size = pyramid.mip_size(mip_level as u32);
+
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, bind_group, &[]);
+                pass.dispatch_workgroups((size.x + 7) / 8, (size.y + 7) / 8, 1);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,539 @@
+//! A high resolution displacement layer for transient deformations (e.g. footprints,
+//! tire tracks or plow furrows), which follows the camera.
+//!
+//! Terrain edits modify the streamed heights of the nodes, which is far too coarse and too
+//! expensive for the impressions left behind by characters and vehicles.
+//! Instead, the detail layer is a small grid of displacements centered on its focus entity
+//! (usually the camera or the player), which is composited over the streamed heights by the
+//! shaders. Gameplay code stamps impressions into the layer, which fade out over time.
+//! Each texel stores its displacement along with the time it was stamped at, so that the shaders
+//! fade the impressions out and only the stamped texels have to be uploaded.
+//! The grid moves along with its focus in whole texels, so that the impressions stay in place,
+//! while the ones, which fall out of the grid, are discarded. Its texels wrap around its edges,
+//! thus moving it only clears and uploads the texels, which enter the grid.
+//!
+//! Enable the layer with [`TerrainPlugin::detail_layer`](crate::TerrainPlugin::detail_layer) and
+//! insert the [`TerrainDetailLayer`] component alongside the terrain.
+//! The vertex shader displaces the surface and the default and the splat material bend their
+//! normals along the impressions, which are usually smaller than the vertex spacing.
+
+use crate::{
+    render::{
+        decals::DECAL_BINDING, render_pipeline::TerrainPipelineConfig, terrain_data::TerrainData,
+    },
+    terrain::{world_to_terrain, TerrainComponents},
+};
+use bevy::{
+    math::Vec3Swizzles,
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Extract,
+    },
+};
+use itertools::iproduct;
+use std::num::NonZeroU32;
+
+/// The binding of the detail layer uniform, which is followed by the detail texture.
+pub(crate) const DETAIL_LAYER_BINDING: u32 = DECAL_BINDING + 2;
+
+/// The maximum amount of regions, that are uploaded separately per frame,
+/// before the entire layer is uploaded instead.
+const MAX_DIRTY_RECTS: usize = 16;
+
+/// An impression, which is stamped into the detail layer.
+#[derive(Clone, Copy, Debug)]
+pub struct DetailStamp {
+    /// The horizontal world position of the center of the stamp.
+    pub position: Vec2,
+    /// The radius of the stamp in world space.
+    pub radius: f32,
+    /// The depth of the impression (in the local space of the terrain).
+    /// Negative depths raise the surface, e.g. for the ridges beside a furrow.
+    pub depth: f32,
+    /// The fraction of the radius (from zero to one), across which the impression
+    /// fades into the surrounding surface.
+    pub falloff: f32,
+}
+
+impl DetailStamp {
+    /// Creates a new stamp, whose impression fades out across the outer half of its radius.
+    pub fn new(position: Vec2, radius: f32, depth: f32) -> Self {
+        Self {
+            position,
+            radius,
+            depth,
+            falloff: 0.5,
+        }
+    }
+
+    /// Uses the fraction of the radius, across which the impression fades out.
+    pub fn with_falloff(mut self, falloff: f32) -> Self {
+        self.falloff = falloff;
+        self
+    }
+}
+
+/// This component composites a high resolution displacement layer over the terrain.
+#[derive(Clone, Component)]
+pub struct TerrainDetailLayer {
+    /// The entity, which the layer is centered on, e.g. the camera.
+    pub focus: Entity,
+    /// The time in seconds, after which the impressions have faded out.
+    pub fade_duration: f32,
+    /// The side length of the layer in the local space of the terrain.
+    size: f32,
+    /// The number of texels along each side of the layer.
+    resolution: u32,
+    /// The texel coordinate of the first texel of the layer.
+    origin: IVec2,
+    /// The displacements of the texels and the times they were stamped at.
+    /// The texels are stored at their texel coordinates wrapped around the resolution.
+    texels: Vec<Vec2>,
+    stamps: Vec<DetailStamp>,
+    /// The time in seconds since the creation of the layer.
+    time: f32,
+    /// The first and the last stored texel of the regions, which have been modified this frame.
+    dirty_rects: Vec<(UVec2, UVec2)>,
+}
+
+impl TerrainDetailLayer {
+    /// Creates a new detail layer, which is centered on the focus entity and covers the size
+    /// (in the local space of the terrain) with the number of texels along each side.
+    pub fn new(focus: Entity, size: f32, resolution: u32) -> Self {
+        let resolution = resolution.max(2);
+
+        Self {
+            focus,
+            fade_duration: 30.0,
+            size,
+            resolution,
+            origin: IVec2::ZERO,
+            texels: vec![Vec2::ZERO; (resolution * resolution) as usize],
+            stamps: Vec::new(),
+            time: 0.0,
+            dirty_rects: Vec::new(),
+        }
+    }
+
+    /// Uses the time in seconds, after which the impressions have faded out.
+    pub fn with_fade_duration(mut self, fade_duration: f32) -> Self {
+        self.fade_duration = fade_duration;
+        self
+    }
+
+    /// The side length of the layer in the local space of the terrain.
+    pub fn size(&self) -> f32 {
+        self.size
+    }
+
+    /// The number of texels along each side of the layer.
+    pub fn resolution(&self) -> u32 {
+        self.resolution
+    }
+
+    /// Stamps the impression into the layer, once it is updated this frame.
+    pub fn stamp(&mut self, stamp: DetailStamp) {
+        self.stamps.push(stamp);
+    }
+
+    /// Stamps a continuous trail of impressions between the two horizontal world positions,
+    /// e.g. for tire tracks or plow furrows.
+    pub fn stamp_trail(&mut self, start: Vec2, end: Vec2, radius: f32, depth: f32) {
+        // the stamps overlap, so that the trail has no visible gaps
+        let count = (start.distance(end) / (0.5 * radius).max(f32::EPSILON)).ceil() as u32;
+
+        for i in 0..=count {
+            let position = start.lerp(end, i as f32 / count.max(1) as f32);
+            self.stamp(DetailStamp::new(position, radius, depth));
+        }
+    }
+
+    fn texel_size(&self) -> f32 {
+        self.size / self.resolution as f32
+    }
+
+    /// The rate, at which the displacements decay exponentially.
+    fn fade_rate(&self) -> f32 {
+        // the displacements decay to less than two percent after the fade duration
+        4.0 / self.fade_duration.max(f32::EPSILON)
+    }
+
+    /// Determines the index of the texel at the texel coordinate, which wraps around the resolution.
+    fn texel_index(&self, texel: IVec2) -> usize {
+        let resolution = self.resolution as i32;
+        let texel = IVec2::new(
+            texel.x.rem_euclid(resolution),
+            texel.y.rem_euclid(resolution),
+        );
+
+        (texel.y * resolution + texel.x) as usize
+    }
+
+    /// Marks the texels between the first and the last texel coordinate (inside the layer)
+    /// as modified, which are split along the edges, where they wrap around.
+    fn mark_dirty(&mut self, first: IVec2, last: IVec2) {
+        let resolution = self.resolution as i32;
+
+        let split = |first: i32, last: i32| {
+            let start = first.rem_euclid(resolution);
+            let end = start + last - first;
+
+            if end < resolution {
+                vec![(start as u32, end as u32)]
+            } else {
+                vec![
+                    (start as u32, resolution as u32 - 1),
+                    (0, (end - resolution) as u32),
+                ]
+            }
+        };
+
+        for ((first_x, last_x), (first_y, last_y)) in
+            iproduct!(split(first.x, last.x), split(first.y, last.y))
+        {
+            self.dirty_rects
+                .push((UVec2::new(first_x, first_y), UVec2::new(last_x, last_y)));
+        }
+
+        // uploading many small regions is slower than uploading the entire layer
+        if self.dirty_rects.len() > MAX_DIRTY_RECTS {
+            self.dirty_rects = vec![(UVec2::ZERO, UVec2::splat(self.resolution - 1))];
+        }
+    }
+
+    /// Clears the texels between the first and the last texel coordinate.
+    fn clear(&mut self, first: IVec2, last: IVec2) {
+        for (x, y) in iproduct!(first.x..=last.x, first.y..=last.y) {
+            let index = self.texel_index(IVec2::new(x, y));
+            self.texels[index] = Vec2::ZERO;
+        }
+
+        self.mark_dirty(first, last);
+    }
+
+    /// Moves the layer to the texel closest to the focus position, while the displacements,
+    /// which are still covered by the layer, keep their position.
+    /// Only the texels entering the layer are cleared.
+    fn center_on(&mut self, focus_position: Vec2) {
+        let resolution = self.resolution as i32;
+        let origin =
+            (focus_position / self.texel_size()).round().as_ivec2() - IVec2::splat(resolution / 2);
+
+        if origin == self.origin {
+            return;
+        }
+
+        let (previous, last) = (self.origin, origin + resolution - 1);
+        let shift = origin - previous;
+        self.origin = origin;
+
+        if shift.abs().max_element() >= resolution {
+            self.clear(origin, last);
+            return;
+        }
+
+        // the columns and rows, which enter the layer
+        if shift.x > 0 {
+            self.clear(IVec2::new(previous.x + resolution, origin.y), last);
+        } else if shift.x < 0 {
+            self.clear(origin, IVec2::new(previous.x - 1, last.y));
+        }
+
+        if shift.y > 0 {
+            self.clear(IVec2::new(origin.x, previous.y + resolution), last);
+        } else if shift.y < 0 {
+            self.clear(origin, IVec2::new(last.x, previous.y - 1));
+        }
+    }
+
+    /// Stamps the impression, whose center and radius are given in the local space of the terrain.
+    fn apply_stamp(&mut self, stamp: &DetailStamp, center: Vec2, radius: f32) {
+        let texel_size = self.texel_size();
+        let resolution = self.resolution as i32;
+        let falloff = stamp.falloff.clamp(f32::EPSILON, 1.0);
+        let fade_rate = self.fade_rate();
+
+        let first = ((center - radius) / texel_size - 0.5).floor().as_ivec2();
+        let last = ((center + radius) / texel_size - 0.5).ceil().as_ivec2();
+        let first = first.max(self.origin);
+        let last = last.min(self.origin + resolution - 1);
+
+        if first.cmpgt(last).any() {
+            return;
+        }
+
+        for (x, y) in iproduct!(first.x..=last.x, first.y..=last.y) {
+            // the displacements are stored at the centers of the texels
+            let position = (IVec2::new(x, y).as_vec2() + 0.5) * texel_size;
+            let t = position.distance(center) / radius;
+
+            if t >= 1.0 {
+                continue;
+            }
+
+            let t = ((t - (1.0 - falloff)) / falloff).clamp(0.0, 1.0);
+            let offset = -stamp.depth * (1.0 - t * t * (3.0 - 2.0 * t));
+
+            let index = self.texel_index(IVec2::new(x, y));
+            let texel = &mut self.texels[index];
+            let displacement = texel.x * (-fade_rate * (self.time - texel.y)).exp();
+
+            // overlapping impressions keep the deepest one instead of digging ever deeper
+            let deeper = if stamp.depth >= 0.0 {
+                offset < displacement
+            } else {
+                offset > displacement
+            };
+
+            if deeper {
+                *texel = Vec2::new(offset, self.time);
+            }
+        }
+
+        self.mark_dirty(first, last);
+    }
+}
+
+/// Moves and stamps the detail layers of all terrains, while the impressions fade out
+/// in the shaders.
+pub(crate) fn update_detail_layers(
+    time: Res<Time>,
+    mut terrain_query: Query<(&mut TerrainDetailLayer, &GlobalTransform)>,
+    focus_query: Query<&GlobalTransform>,
+) {
+    for (mut layer, transform) in terrain_query.iter_mut() {
+        // the modified regions of the previous frame have been extracted in the meantime
+        layer.dirty_rects.clear();
+        layer.time += time.delta_seconds();
+
+        if let Ok(focus_transform) = focus_query.get(layer.focus) {
+            let focus_position = world_to_terrain(transform, focus_transform.translation()).xz();
+            layer.center_on(focus_position);
+        }
+
+        if !layer.stamps.is_empty() {
+            let scale = transform.compute_transform().scale.x;
+
+            for stamp in std::mem::take(&mut layer.stamps) {
+                let center = world_to_terrain(
+                    transform,
+                    Vec3::new(stamp.position.x, 0.0, stamp.position.y),
+                )
+                .xz();
+
+                layer.apply_stamp(&stamp, center, stamp.radius / scale);
+            }
+        }
+    }
+}
+
+/// The detail layer data that is available in shaders.
+#[derive(Clone, Copy, Default, ShaderType)]
+struct DetailLayerUniform {
+    /// The position of the corner of the layer (in the local space of the terrain).
+    origin: Vec2,
+    texel_size: f32,
+    /// The number of texels along each side, which is zero for disabled layers.
+    resolution: u32,
+    /// The stored position of the first texel, since the texels wrap around.
+    offset: UVec2,
+    /// The time of the layer, which the displacements are faded by.
+    time: f32,
+    /// The rate, at which the displacements decay exponentially.
+    fade_rate: f32,
+}
+
+fn create_detail_buffer(device: &RenderDevice, uniform: &DetailLayerUniform) -> Buffer {
+    let mut buffer = encase::UniformBuffer::new(Vec::new());
+    buffer.write(uniform).unwrap();
+
+    device.create_buffer_with_data(&BufferInitDescriptor {
+        label: "detail_layer_buffer".into(),
+        usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        contents: &buffer.into_inner(),
+    })
+}
+
+fn create_detail_texture(device: &RenderDevice, resolution: u32) -> Texture {
+    device.create_texture(&TextureDescriptor {
+        label: "detail_layer_texture".into(),
+        size: Extent3d {
+            width: resolution,
+            height: resolution,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: TextureFormat::Rg32Float,
+        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
+
+/// The disabled detail layer, which is bound to the terrains without a detail layer.
+#[derive(Resource)]
+pub struct DetailLayerFallback {
+    pub(crate) buffer: Buffer,
+    pub(crate) texture_view: TextureView,
+}
+
+impl FromWorld for DetailLayerFallback {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+
+        Self {
+            buffer: create_detail_buffer(device, &DetailLayerUniform::default()),
+            texture_view: create_detail_texture(device, 1).create_view(&default()),
+        }
+    }
+}
+
+/// Stores the detail texture of a terrain alongside the layer extracted this frame.
+pub struct GpuTerrainDetailLayer {
+    buffer: Buffer,
+    texture: Texture,
+    texture_view: TextureView,
+    resolution: u32,
+    uniform: DetailLayerUniform,
+    /// The regions of the texels, which have changed and still have to be uploaded,
+    /// as their first stored texel, their size and their texels.
+    texel_updates: Vec<(UVec2, UVec2, Vec<[f32; 2]>)>,
+}
+
+impl GpuTerrainDetailLayer {
+    fn new(device: &RenderDevice, resolution: u32) -> Self {
+        let texture = create_detail_texture(device, resolution);
+
+        Self {
+            buffer: create_detail_buffer(device, &DetailLayerUniform::default()),
+            texture_view: texture.create_view(&default()),
+            texture,
+            resolution,
+            uniform: default(),
+            texel_updates: Vec::new(),
+        }
+    }
+}
+
+pub(crate) fn extract_terrain_detail_layers(
+    device: Res<RenderDevice>,
+    pipeline_config: Res<TerrainPipelineConfig>,
+    mut gpu_detail_layers: ResMut<TerrainComponents<GpuTerrainDetailLayer>>,
+    terrain_query: Extract<Query<(Entity, &TerrainDetailLayer)>>,
+) {
+    if !pipeline_config.detail_layer {
+        return;
+    }
+
+    // the layers of terrains, whose component has been removed, are disabled
+    for gpu_layer in gpu_detail_layers.0.values_mut() {
+        gpu_layer.uniform.resolution = 0;
+    }
+
+    for (terrain, layer) in terrain_query.iter() {
+        let resolution = layer.resolution;
+        let mut recreated = !gpu_detail_layers.0.contains_key(&terrain);
+
+        let gpu_layer = gpu_detail_layers
+            .0
+            .entry(terrain)
+            .or_insert_with(|| GpuTerrainDetailLayer::new(&device, resolution));
+
+        if gpu_layer.resolution != resolution {
+            *gpu_layer = GpuTerrainDetailLayer::new(&device, resolution);
+            recreated = true;
+        }
+
+        let origin = layer.origin;
+
+        gpu_layer.uniform = DetailLayerUniform {
+            origin: origin.as_vec2() * layer.texel_size(),
+            texel_size: layer.texel_size(),
+            resolution,
+            offset: IVec2::new(
+                origin.x.rem_euclid(resolution as i32),
+                origin.y.rem_euclid(resolution as i32),
+            )
+            .as_uvec2(),
+            time: layer.time,
+            fade_rate: layer.fade_rate(),
+        };
+
+        let full_rect = [(UVec2::ZERO, UVec2::splat(resolution - 1))];
+        let dirty_rects = if recreated {
+            &full_rect[..]
+        } else {
+            &layer.dirty_rects[..]
+        };
+
+        for &(first, last) in dirty_rects {
+            let texels = iproduct!(first.y..=last.y, first.x..=last.x)
+                .map(|(y, x)| layer.texels[(y * resolution + x) as usize].to_array())
+                .collect();
+
+            gpu_layer
+                .texel_updates
+                .push((first, last - first + 1, texels));
+        }
+    }
+}
+
+pub(crate) fn prepare_terrain_detail_layers(
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    images: Res<RenderAssets<Image>>,
+    mut gpu_detail_layers: ResMut<TerrainComponents<GpuTerrainDetailLayer>>,
+    mut terrain_data: ResMut<TerrainComponents<TerrainData>>,
+) {
+    for (terrain, gpu_layer) in gpu_detail_layers.0.iter_mut() {
+        let mut buffer = encase::UniformBuffer::new(Vec::new());
+        buffer.write(&gpu_layer.uniform).unwrap();
+        queue.write_buffer(&gpu_layer.buffer, 0, &buffer.into_inner());
+
+        for (first, size, texels) in gpu_layer.texel_updates.drain(..) {
+            queue.write_texture(
+                ImageCopyTexture {
+                    texture: &gpu_layer.texture,
+                    mip_level: 0,
+                    origin: Origin3d {
+                        x: first.x,
+                        y: first.y,
+                        z: 0,
+                    },
+                    aspect: TextureAspect::All,
+                },
+                bytemuck::cast_slice(&texels),
+                ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(size.x * 8),
+                    rows_per_image: None,
+                },
+                Extent3d {
+                    width: size.x,
+                    height: size.y,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let Some(terrain_data) = terrain_data.get_mut(terrain) else {
+            continue;
+        };
+
+        if terrain_data.detail_texture == Some(gpu_layer.texture.id()) {
+            continue;
+        }
+
+        terrain_data.bind_detail_layer(
+            &device,
+            &images,
+            &gpu_layer.buffer,
+            &gpu_layer.texture,
+            &gpu_layer.texture_view,
+        );
+    }
+}
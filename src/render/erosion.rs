@@ -0,0 +1,379 @@
+//! Runs the hydraulic erosion jobs of the [`crate::erosion`] module on the GPU.
+//!
+//! Each job uploads its grid of heights into storage buffers, which are eroded by the compute
+//! shaders of the virtual pipe model for the requested number of iterations.
+//! To keep the frame time stable, at most [`ITERATIONS_PER_FRAME`] iterations are dispatched per
+//! frame. Afterwards the eroded heights are copied into a staging buffer, which is read back
+//! asynchronously and sent to the main world.
+
+use crate::{
+    erosion::{ErosionJob, ErosionResult, HydraulicErosion},
+    render::{shaders::EROSION_SHADER, EROSION_LAYOUT},
+};
+use bevy::{
+    prelude::*,
+    render::{
+        render_graph::{self},
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice},
+    },
+};
+use crossbeam_channel::{Receiver, Sender};
+use std::mem;
+
+/// The maximum number of erosion iterations dispatched per job and frame.
+pub const ITERATIONS_PER_FRAME: u32 = 64;
+
+/// The erosion config that is available in the shader.
+#[derive(ShaderType)]
+struct ErosionConfig {
+    size: UVec2,
+    spacing: f32,
+    time_step: f32,
+    rain_rate: f32,
+    evaporation_rate: f32,
+    sediment_capacity: f32,
+    dissolving_rate: f32,
+    deposition_rate: f32,
+    gravity: f32,
+    min_tilt: f32,
+}
+
+impl ErosionConfig {
+    fn new(erosion: &HydraulicErosion, size: UVec2, spacing: f32) -> Self {
+        Self {
+            size,
+            spacing,
+            time_step: erosion.time_step,
+            rain_rate: erosion.rain_rate,
+            evaporation_rate: erosion.evaporation_rate,
+            sediment_capacity: erosion.sediment_capacity,
+            dissolving_rate: erosion.dissolving_rate,
+            deposition_rate: erosion.deposition_rate,
+            gravity: erosion.gravity,
+            min_tilt: erosion.min_tilt,
+        }
+    }
+}
+
+/// The progress of an erosion job.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ErosionState {
+    /// The simulation still has to dispatch the remaining iterations.
+    Running { remaining: u32 },
+    /// The eroded heights are copied into the staging buffer,
+    /// which is read back during the next frame.
+    Copying,
+}
+
+/// The buffers and bind groups of an erosion job.
+struct GpuErosionJob {
+    id: u32,
+    size: UVec2,
+    /// The bind groups of the even and the odd iterations, which swap the sediment buffers.
+    bind_groups: [BindGroup; 2],
+    terrain_buffer: Buffer,
+    staging_buffer: Buffer,
+    state: ErosionState,
+    /// The number of iterations, that have already been dispatched.
+    first_iteration: u32,
+    /// The number of iterations dispatched this frame.
+    frame_iterations: u32,
+}
+
+impl GpuErosionJob {
+    fn new(device: &RenderDevice, pipelines: &ErosionPipelines, job: ErosionJob) -> Self {
+        let ErosionJob {
+            id,
+            size,
+            spacing,
+            heights,
+            erosion,
+        } = job;
+
+        let cell_count = (size.x * size.y) as BufferAddress;
+        let terrain_size = cell_count * mem::size_of::<f32>() as BufferAddress;
+
+        let mut buffer = encase::UniformBuffer::new(Vec::new());
+        buffer
+            .write(&ErosionConfig::new(&erosion, size, spacing))
+            .unwrap();
+
+        let config_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+            label: "erosion_config_buffer".into(),
+            usage: BufferUsages::UNIFORM,
+            contents: &buffer.into_inner(),
+        });
+
+        let terrain_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+            label: "erosion_terrain_buffer".into(),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            contents: bytemuck::cast_slice(&heights),
+        });
+
+        // the remaining buffers start out zeroed
+        let create_buffer = |label: &'static str, element_size: BufferAddress| {
+            device.create_buffer(&BufferDescriptor {
+                label: label.into(),
+                size: cell_count * element_size,
+                usage: BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        };
+
+        let water_buffer = create_buffer("erosion_water_buffer", 4);
+        let sediment_buffers = [
+            create_buffer("erosion_sediment_buffer", 4),
+            create_buffer("erosion_sediment_buffer", 4),
+        ];
+        let flux_buffer = create_buffer("erosion_flux_buffer", 16);
+        let velocity_buffer = create_buffer("erosion_velocity_buffer", 16);
+
+        let staging_buffer = device.create_buffer(&BufferDescriptor {
+            label: "erosion_staging_buffer".into(),
+            size: terrain_size,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let bind_groups = [0, 1].map(|parity| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: "erosion_bind_group".into(),
+                layout: &pipelines.layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: config_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: terrain_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: water_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: sediment_buffers[parity].as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 4,
+                        resource: sediment_buffers[1 - parity].as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 5,
+                        resource: flux_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 6,
+                        resource: velocity_buffer.as_entire_binding(),
+                    },
+                ],
+            })
+        });
+
+        Self {
+            id,
+            size,
+            bind_groups,
+            terrain_buffer,
+            staging_buffer,
+            state: ErosionState::Running {
+                remaining: erosion.iterations,
+            },
+            first_iteration: 0,
+            frame_iterations: 0,
+        }
+    }
+
+    /// Reads the eroded heights back from the staging buffer and sends them to the main world.
+    fn read_back(self, sender: &Sender<ErosionResult>) {
+        let GpuErosionJob {
+            id, staging_buffer, ..
+        } = self;
+
+        let sender = sender.clone();
+        let buffer = staging_buffer.clone();
+
+        staging_buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |result| {
+                if let Err(error) = result {
+                    error!("Failed to read back the eroded heights: {error}");
+                    return;
+                }
+
+                let heights: Vec<f32> =
+                    bytemuck::cast_slice(&buffer.slice(..).get_mapped_range()).to_vec();
+                buffer.unmap();
+
+                sender.send(ErosionResult { id, heights }).ok();
+            });
+    }
+}
+
+/// Stores the erosion jobs, which are currently simulated on the GPU.
+#[derive(Resource)]
+pub struct GpuErosion {
+    jobs: Vec<GpuErosionJob>,
+    receiver: Receiver<ErosionJob>,
+    sender: Sender<ErosionResult>,
+}
+
+impl GpuErosion {
+    pub(crate) fn new(receiver: Receiver<ErosionJob>, sender: Sender<ErosionResult>) -> Self {
+        Self {
+            jobs: Vec::new(),
+            receiver,
+            sender,
+        }
+    }
+}
+
+/// Starts the newly requested erosion jobs, schedules the iterations of this frame and
+/// reads back the jobs, that have finished during the last frame.
+pub(crate) fn prepare_erosion(
+    device: Res<RenderDevice>,
+    pipeline_cache: Res<PipelineCache>,
+    pipelines: Res<ErosionPipelines>,
+    mut gpu_erosion: ResMut<GpuErosion>,
+) {
+    let GpuErosion {
+        ref mut jobs,
+        ref receiver,
+        ref sender,
+    } = *gpu_erosion;
+
+    jobs.extend(
+        receiver
+            .try_iter()
+            .map(|job| GpuErosionJob::new(&device, &pipelines, job)),
+    );
+
+    if jobs.is_empty() {
+        return;
+    }
+
+    let ready = pipelines
+        .pipelines
+        .iter()
+        .all(|&pipeline| pipeline_cache.get_compute_pipeline(pipeline).is_some());
+
+    // the staging buffers of these jobs have been written last frame
+    for job in mem::take(jobs) {
+        if job.state == ErosionState::Copying {
+            job.read_back(sender);
+        } else {
+            jobs.push(job);
+        }
+    }
+
+    for job in jobs.iter_mut() {
+        job.first_iteration += job.frame_iterations;
+        job.frame_iterations = 0;
+
+        if let ErosionState::Running { remaining } = job.state {
+            if !ready {
+                continue;
+            }
+
+            job.frame_iterations = remaining.min(ITERATIONS_PER_FRAME);
+
+            job.state = match remaining - job.frame_iterations {
+                0 => ErosionState::Copying,
+                remaining => ErosionState::Running { remaining },
+            };
+        }
+    }
+
+    // invokes the callbacks of the buffers, that have been mapped
+    device.wgpu_device().poll(Maintain::Poll);
+}
+
+#[derive(Resource)]
+pub struct ErosionPipelines {
+    layout: BindGroupLayout,
+    /// The pipelines of the flux update, the erosion and the sediment transport.
+    pipelines: [CachedComputePipelineId; 3],
+}
+
+impl FromWorld for ErosionPipelines {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+
+        let layout = device.create_bind_group_layout(&EROSION_LAYOUT);
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        let pipelines = ["update_flux", "erode", "transport"].map(|entry_point| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(format!("erosion_{entry_point}_pipeline").into()),
+                layout: vec![layout.clone()],
+                push_constant_ranges: default(),
+                shader: EROSION_SHADER.typed(),
+                shader_defs: default(),
+                entry_point: entry_point.into(),
+            })
+        });
+
+        Self { layout, pipelines }
+    }
+}
+
+/// Dispatches the erosion iterations scheduled this frame and copies the heights of the
+/// finished jobs into their staging buffers.
+pub struct ErosionNode;
+
+impl render_graph::Node for ErosionNode {
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipelines = world.resource::<ErosionPipelines>();
+        let gpu_erosion = world.resource::<GpuErosion>();
+
+        let Some(pipelines) = pipelines
+            .pipelines
+            .iter()
+            .map(|&pipeline| pipeline_cache.get_compute_pipeline(pipeline))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return Ok(());
+        };
+
+        for job in &gpu_erosion.jobs {
+            if job.frame_iterations > 0 {
+                let pass = &mut context
+                    .command_encoder()
+                    .begin_compute_pass(&ComputePassDescriptor::default());
+
+                let workgroup_count = (job.size + 7) / 8;
+
+                for iteration in job.first_iteration..job.first_iteration + job.frame_iterations {
+                    pass.set_bind_group(0, &job.bind_groups[iteration as usize % 2], &[]);
+
+                    for pipeline in &pipelines {
+                        pass.set_pipeline(pipeline);
+                        pass.dispatch_workgroups(workgroup_count.x, workgroup_count.y, 1);
+                    }
+                }
+            }
+
+            if job.state == ErosionState::Copying {
+                context.command_encoder().copy_buffer_to_buffer(
+                    &job.terrain_buffer,
+                    0,
+                    &job.staging_buffer,
+                    0,
+                    job.staging_buffer.size(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,627 @@
+//! Generates the attachments of procedural terrains directly on the GPU.
+//!
+//! Instead of loading the nodes from disk or synthesizing them on the CPU, a compute shader
+//! evaluates a height function for each pixel of the requested nodes and writes the results
+//! straight into their slots of the atlas attachment (including all mip levels).
+//!
+//! The height function is defined in WGSL, see `generation/default.wgsl` for an example.
+//! Custom generators define `fn generate_height(position: vec2<f32>) -> f32`, may use the
+//! noise functions of `#import bevy_terrain::noise` (value and gradient noise, fBm,
+//! ridged noise and domain warping) and end with `#import bevy_terrain::generator`.
+//!
+//! The generated attachments have to use the [`AttachmentFormat::R32F`](crate::terrain_data::AttachmentFormat::R32F),
+//! because the storage textures of WebGPU do not support 16 bit normalized formats.
+//! As the data never reaches the CPU, it is not available for sampling, raycasts or collisions.
+//!
+//! The generated attachments of a node only finish loading, once the render world reports,
+//! that their generation has been dispatched, so that the nodes are never activated before
+//! their data has been written.
+
+use crate::{
+    render::{shaders::DEFAULT_GENERATOR_SHADER, NODE_GENERATOR_LAYOUT},
+    terrain::{Terrain, TerrainComponents},
+    terrain_data::{
+        gpu_node_atlas::GpuNodeAtlas, node_atlas::NodeAtlas, AtlasAttachment, AtlasIndex,
+        AttachmentIndex, NodeCoordinate, NodeId,
+    },
+};
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_graph::{self},
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        Extract, MainWorld,
+    },
+    utils::HashMap,
+};
+use crossbeam_channel::{Receiver, Sender};
+use std::{mem, num::NonZeroU32};
+
+/// The shader of the default generator.
+pub fn default_generator() -> Handle<Shader> {
+    DEFAULT_GENERATOR_SHADER.typed()
+}
+
+/// A node, whose attachments are generated on the GPU.
+#[derive(Clone, Copy, ShaderType)]
+pub(crate) struct GeneratedNode {
+    /// The position of the node origin (in the local space of the terrain).
+    position: Vec2,
+    /// The size of the node (in the local space of the terrain).
+    size: f32,
+    atlas_index: u32,
+}
+
+/// This component is used to generate attachments on the GPU into the corresponding [`NodeAtlas`].
+#[derive(Component)]
+pub struct AttachmentFromGpuLoader {
+    /// The generator shader of each generated attachment.
+    pub(crate) generators: HashMap<AttachmentIndex, Handle<Shader>>,
+    /// The minmax attachment, which is filled with conservative bounds, as it can not be generated.
+    pub(crate) minmax_attachment: Option<AttachmentIndex>,
+    minmax_image: Option<Handle<Image>>,
+    /// The nodes, that have been requested this frame.
+    generated_nodes: Vec<(NodeId, GeneratedNode)>,
+    /// Delivers the nodes and atlas indices, whose generation has been submitted,
+    /// back from the render world.
+    sender: Sender<(NodeId, AtlasIndex)>,
+    receiver: Receiver<(NodeId, AtlasIndex)>,
+}
+
+impl Default for AttachmentFromGpuLoader {
+    fn default() -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+
+        Self {
+            generators: default(),
+            minmax_attachment: None,
+            minmax_image: None,
+            generated_nodes: Vec::new(),
+            sender,
+            receiver,
+        }
+    }
+}
+
+/// Creates a minmax node, which spans the entire height range of the terrain.
+fn conservative_minmax(node_atlas: &NodeAtlas, minmax_index: AttachmentIndex) -> Image {
+    let attachment = &node_atlas.attachments[minmax_index];
+
+    let pixel_count = (0..attachment.mip_level_count)
+        .map(|mip_level| (attachment.texture_size >> mip_level).pow(2) as usize)
+        .sum();
+    let pixel = [0_u16.to_le_bytes(), u16::MAX.to_le_bytes()].concat();
+
+    let mut image = Image {
+        data: pixel.repeat(pixel_count),
+        ..default()
+    };
+    image.texture_descriptor.size = Extent3d {
+        width: attachment.texture_size,
+        height: attachment.texture_size,
+        depth_or_array_layers: 1,
+    };
+    image.texture_descriptor.mip_level_count = attachment.mip_level_count;
+    image.texture_descriptor.format = attachment.format();
+    image.texture_descriptor.usage |= TextureUsages::COPY_SRC;
+    image
+}
+
+pub(crate) fn start_loading_attachment_from_gpu(
+    mut images: ResMut<Assets<Image>>,
+    mut terrain_query: Query<(&mut NodeAtlas, &mut AttachmentFromGpuLoader)>,
+) {
+    for (mut node_atlas, mut loader) in terrain_query.iter_mut() {
+        let AttachmentFromGpuLoader {
+            ref generators,
+            minmax_attachment,
+            ref mut minmax_image,
+            ref mut generated_nodes,
+            ..
+        } = loader.as_mut();
+
+        let minmax_image = minmax_attachment.map(|minmax_index| {
+            minmax_image
+                .get_or_insert_with(|| images.add(conservative_minmax(&node_atlas, minmax_index)))
+                .clone()
+        });
+
+        let leaf_node_size = node_atlas.leaf_node_size;
+
+        let NodeAtlas {
+            ref mut loading_nodes,
+            ref load_events,
+            ..
+        } = node_atlas.as_mut();
+
+        for &node_id in load_events.iter() {
+            let node = loading_nodes.get_mut(&node_id).unwrap();
+
+            // without a render world the generated attachments are never written
+            #[cfg(feature = "headless")]
+            for &attachment_index in generators.keys() {
+                node.loaded(attachment_index);
+            }
+
+            if let (Some(minmax_index), Some(minmax_image)) = (*minmax_attachment, &minmax_image) {
+                node.set_attachment(minmax_index, minmax_image.clone());
+                node.loaded(minmax_index);
+            }
+
+            let coord = NodeCoordinate::from(node_id);
+            let node_size = (leaf_node_size << coord.lod) as f32;
+
+            // the atlas index might be reused by a node requested later this frame
+            generated_nodes
+                .retain(|(_, generated)| generated.atlas_index != node.atlas_index as u32);
+            generated_nodes.push((
+                node_id,
+                GeneratedNode {
+                    position: Vec2::new(coord.x as f32, coord.y as f32) * node_size,
+                    size: node_size,
+                    atlas_index: node.atlas_index as u32,
+                },
+            ));
+        }
+    }
+}
+
+/// Marks the generated attachments of the nodes as loaded, once their generation has been
+/// submitted in the render world.
+pub(crate) fn finish_loading_attachment_from_gpu(
+    mut terrain_query: Query<(&mut NodeAtlas, &AttachmentFromGpuLoader)>,
+) {
+    for (mut node_atlas, loader) in terrain_query.iter_mut() {
+        for (node_id, atlas_index) in loader.receiver.try_iter() {
+            // the node might have been evicted and requested again in the meantime
+            let Some(node) = node_atlas
+                .loading_nodes
+                .get_mut(&node_id)
+                .filter(|node| node.atlas_index == atlas_index)
+            else {
+                continue;
+            };
+
+            for &attachment_index in loader.generators.keys() {
+                node.loaded(attachment_index);
+            }
+        }
+    }
+}
+
+/// The config of a generated mip level of an attachment.
+#[derive(ShaderType)]
+struct GeneratorConfig {
+    texture_size: u32,
+    border_size: f32,
+    center_size: f32,
+    mip_scale: f32,
+}
+
+struct GeneratedAttachment {
+    attachment_index: AttachmentIndex,
+    shader: Handle<Shader>,
+    pipeline: Option<CachedComputePipelineId>,
+    /// The bind groups and sizes of all mip levels of each shard of the atlas.
+    shards: Vec<Vec<(BindGroup, u32)>>,
+}
+
+/// The nodes of a shard of the atlas, that are generated this frame.
+struct GeneratorShard {
+    node_buffer: Buffer,
+    /// The number of nodes written into the node buffer this frame.
+    node_count: u32,
+}
+
+/// Stores the generated attachments of a terrain alongside the nodes,
+/// that still have to be generated.
+pub struct GpuNodeGenerator {
+    attachments: Vec<GeneratedAttachment>,
+    /// The allocated shards of the atlas.
+    shards: Vec<GeneratorShard>,
+    /// The nodes, that are waiting for the pipelines to be compiled.
+    pending_nodes: Vec<(NodeId, GeneratedNode)>,
+    /// The nodes and their atlas indices, that are generated this frame.
+    dispatched_nodes: Vec<(NodeId, AtlasIndex)>,
+    /// Reports the dispatched nodes back to the [`AttachmentFromGpuLoader`].
+    sender: Sender<(NodeId, AtlasIndex)>,
+    /// The size of the atlas, which the node buffers and the bind groups were created for.
+    atlas_size: AtlasIndex,
+}
+
+impl GpuNodeGenerator {
+    fn new(
+        device: &RenderDevice,
+        images: &RenderAssets<Image>,
+        pipelines: &NodeGeneratorPipelines,
+        gpu_node_atlas: &GpuNodeAtlas,
+        loader: &AttachmentFromGpuLoader,
+    ) -> Self {
+        let attachments = loader
+            .generators
+            .iter()
+            .map(|(&attachment_index, shader)| {
+                assert_eq!(
+                    gpu_node_atlas.atlas_attachments[attachment_index]
+                        .0
+                        .format(),
+                    TextureFormat::R32Float,
+                    "Only R32F attachments can be generated on the GPU."
+                );
+
+                GeneratedAttachment {
+                    attachment_index,
+                    shader: shader.clone(),
+                    pipeline: None,
+                    shards: Vec::new(),
+                }
+            })
+            .collect();
+
+        let mut generator = Self {
+            attachments,
+            shards: Vec::new(),
+            pending_nodes: Vec::new(),
+            dispatched_nodes: Vec::new(),
+            sender: loader.sender.clone(),
+            atlas_size: 0,
+        };
+
+        generator.resize(device, images, pipelines, gpu_node_atlas);
+        generator
+    }
+
+    /// Recreates the node buffers and the bind groups of all allocated shards of the atlas,
+    /// after the atlas has grown.
+    fn resize(
+        &mut self,
+        device: &RenderDevice,
+        images: &RenderAssets<Image>,
+        pipelines: &NodeGeneratorPipelines,
+        gpu_node_atlas: &GpuNodeAtlas,
+    ) {
+        self.atlas_size = gpu_node_atlas.size;
+        self.shards = (0..gpu_node_atlas.attachments[0].len())
+            .map(|shard| gpu_node_atlas.shard_layers(shard, gpu_node_atlas.size))
+            .take_while(|&layers| layers > 0)
+            .map(|layers| GeneratorShard {
+                node_buffer: create_node_buffer(device, layers),
+                node_count: 0,
+            })
+            .collect();
+
+        for generated in &mut self.attachments {
+            let attachment = &gpu_node_atlas.atlas_attachments[generated.attachment_index].0;
+
+            generated.shards = self
+                .shards
+                .iter()
+                .zip(&gpu_node_atlas.attachments[generated.attachment_index])
+                .map(|(shard, handle)| {
+                    let atlas_texture = &images.get(handle).unwrap().texture;
+
+                    bind_mip_levels(
+                        device,
+                        pipelines,
+                        attachment,
+                        &shard.node_buffer,
+                        atlas_texture,
+                    )
+                })
+                .collect();
+        }
+    }
+}
+
+/// Creates the bind groups of all mip levels of the shard of the generated attachment.
+fn bind_mip_levels(
+    device: &RenderDevice,
+    pipelines: &NodeGeneratorPipelines,
+    attachment: &AtlasAttachment,
+    node_buffer: &Buffer,
+    atlas_texture: &Texture,
+) -> Vec<(BindGroup, u32)> {
+    (0..attachment.mip_level_count)
+        .map(|mip_level| {
+            let texture_size = attachment.texture_size >> mip_level;
+
+            let mut buffer = encase::UniformBuffer::new(Vec::new());
+            buffer
+                .write(&GeneratorConfig {
+                    texture_size,
+                    border_size: attachment.border_size as f32,
+                    center_size: attachment.center_size as f32,
+                    mip_scale: (1 << mip_level) as f32,
+                })
+                .unwrap();
+
+            let config_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+                label: "generator_config_buffer".into(),
+                usage: BufferUsages::UNIFORM,
+                contents: &buffer.into_inner(),
+            });
+
+            let atlas_view = atlas_texture.create_view(&TextureViewDescriptor {
+                dimension: Some(TextureViewDimension::D2Array),
+                base_mip_level: mip_level,
+                mip_level_count: NonZeroU32::new(1),
+                ..default()
+            });
+
+            let bind_group = device.create_bind_group(&BindGroupDescriptor {
+                label: "node_generator_bind_group".into(),
+                layout: &pipelines.node_generator_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: config_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: node_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: BindingResource::TextureView(&atlas_view),
+                    },
+                ],
+            });
+
+            (bind_group, texture_size)
+        })
+        .collect()
+}
+
+/// Creates the buffer of the nodes of a shard, which are generated in one frame.
+fn create_node_buffer(device: &RenderDevice, layer_count: u32) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: "generated_nodes_buffer".into(),
+        size: GeneratedNode::min_size().get() * layer_count as BufferAddress,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Initializes the [`GpuNodeGenerator`] of newly created terrains.
+pub(crate) fn initialize_gpu_node_generator(
+    device: Res<RenderDevice>,
+    images: Res<RenderAssets<Image>>,
+    pipelines: Res<NodeGeneratorPipelines>,
+    gpu_node_atlases: Res<TerrainComponents<GpuNodeAtlas>>,
+    mut gpu_node_generators: ResMut<TerrainComponents<GpuNodeGenerator>>,
+    terrain_query: Extract<Query<(Entity, &AttachmentFromGpuLoader), Added<Terrain>>>,
+) {
+    for (terrain, loader) in terrain_query.iter() {
+        let gpu_node_atlas = gpu_node_atlases.get(&terrain).unwrap();
+
+        gpu_node_generators.insert(
+            terrain,
+            GpuNodeGenerator::new(&device, &images, &pipelines, gpu_node_atlas, loader),
+        );
+    }
+}
+
+/// Extracts the nodes requested this frame from all [`AttachmentFromGpuLoader`]s
+/// into the corresponding [`GpuNodeGenerator`]s.
+pub(crate) fn extract_node_generator(
+    mut main_world: ResMut<MainWorld>,
+    mut gpu_node_generators: ResMut<TerrainComponents<GpuNodeGenerator>>,
+) {
+    let mut terrain_query = main_world.query::<(Entity, &mut AttachmentFromGpuLoader)>();
+
+    for (terrain, mut loader) in terrain_query.iter_mut(&mut main_world) {
+        if let Some(gpu_node_generator) = gpu_node_generators.get_mut(&terrain) {
+            let GpuNodeGenerator { pending_nodes, .. } = gpu_node_generator;
+
+            for (node_id, node) in mem::take(&mut loader.generated_nodes) {
+                pending_nodes.retain(|(_, pending)| pending.atlas_index != node.atlas_index);
+                pending_nodes.push((node_id, node));
+            }
+        }
+    }
+}
+
+/// Writes the pending nodes into the node buffers of their shards,
+/// once all generator pipelines are ready.
+///
+/// The generators of grown atlases are rebound to the reallocated attachments beforehand
+/// and the pending nodes are moved along with the migrated nodes.
+pub(crate) fn prepare_node_generator(
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    images: Res<RenderAssets<Image>>,
+    pipeline_cache: Res<PipelineCache>,
+    pipelines: Res<NodeGeneratorPipelines>,
+    gpu_node_atlases: Res<TerrainComponents<GpuNodeAtlas>>,
+    mut gpu_node_generators: ResMut<TerrainComponents<GpuNodeGenerator>>,
+) {
+    for (terrain, gpu_node_generator) in gpu_node_generators.0.iter_mut() {
+        for shard in &mut gpu_node_generator.shards {
+            shard.node_count = 0;
+        }
+
+        let Some(gpu_node_atlas) = gpu_node_atlases.get(terrain) else {
+            continue;
+        };
+
+        if gpu_node_atlas.size != gpu_node_generator.atlas_size {
+            gpu_node_generator.resize(&device, &images, &pipelines, gpu_node_atlas);
+        }
+
+        // the pending nodes follow the nodes, that have been migrated this frame
+        for &(from, to) in &gpu_node_atlas.migrated_nodes {
+            let (from, to) = (from as u32, to as u32);
+
+            gpu_node_generator
+                .pending_nodes
+                .retain(|(_, pending)| pending.atlas_index != to);
+
+            for (_, pending) in &mut gpu_node_generator.pending_nodes {
+                if pending.atlas_index == from {
+                    pending.atlas_index = to;
+                }
+            }
+        }
+
+        let ready = gpu_node_generator.attachments.iter().all(|attachment| {
+            attachment
+                .pipeline
+                .and_then(|id| pipeline_cache.get_compute_pipeline(id))
+                .is_some()
+        });
+
+        if !ready || gpu_node_generator.pending_nodes.is_empty() {
+            continue;
+        }
+
+        // the nodes are generated into the layers of their shards
+        let mut shard_nodes = vec![Vec::new(); gpu_node_generator.shards.len()];
+
+        for (node_id, mut node) in mem::take(&mut gpu_node_generator.pending_nodes) {
+            let atlas_index = node.atlas_index as AtlasIndex;
+            let (shard, layer) = gpu_node_atlas.shard(atlas_index);
+            node.atlas_index = layer;
+
+            if let Some(nodes) = shard_nodes.get_mut(shard) {
+                nodes.push(node);
+                gpu_node_generator
+                    .dispatched_nodes
+                    .push((node_id, atlas_index));
+            }
+        }
+
+        for (shard, nodes) in gpu_node_generator.shards.iter_mut().zip(shard_nodes) {
+            if nodes.is_empty() {
+                continue;
+            }
+
+            let mut buffer = encase::StorageBuffer::new(Vec::new());
+            buffer.write(&nodes).unwrap();
+            queue.write_buffer(&shard.node_buffer, 0, &buffer.into_inner());
+
+            shard.node_count = nodes.len() as u32;
+        }
+    }
+}
+
+/// Reports the nodes, that have been generated this frame, back to the main world,
+/// once the commands of the frame have been submitted.
+pub(crate) fn report_generated_nodes(
+    mut gpu_node_generators: ResMut<TerrainComponents<GpuNodeGenerator>>,
+) {
+    for gpu_node_generator in gpu_node_generators.0.values_mut() {
+        for node in gpu_node_generator.dispatched_nodes.drain(..) {
+            // the loader might have been removed in the meantime
+            let _ = gpu_node_generator.sender.send(node);
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct NodeGeneratorPipelines {
+    pub(crate) node_generator_layout: BindGroupLayout,
+}
+
+impl FromWorld for NodeGeneratorPipelines {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+
+        Self {
+            node_generator_layout: device.create_bind_group_layout(&NODE_GENERATOR_LAYOUT),
+        }
+    }
+}
+
+impl SpecializedComputePipeline for NodeGeneratorPipelines {
+    type Key = Handle<Shader>;
+
+    fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+        ComputePipelineDescriptor {
+            label: Some("node_generator_pipeline".into()),
+            layout: vec![self.node_generator_layout.clone()],
+            push_constant_ranges: default(),
+            shader: key,
+            shader_defs: default(),
+            entry_point: "generate".into(),
+        }
+    }
+}
+
+pub(crate) fn queue_node_generator_pipelines(
+    pipeline_cache: Res<PipelineCache>,
+    generator_pipelines: Res<NodeGeneratorPipelines>,
+    mut pipelines: ResMut<SpecializedComputePipelines<NodeGeneratorPipelines>>,
+    mut gpu_node_generators: ResMut<TerrainComponents<GpuNodeGenerator>>,
+) {
+    for gpu_node_generator in gpu_node_generators.0.values_mut() {
+        for attachment in &mut gpu_node_generator.attachments {
+            attachment.pipeline = Some(pipelines.specialize(
+                &pipeline_cache,
+                &generator_pipelines,
+                attachment.shader.clone(),
+            ));
+        }
+    }
+}
+
+/// Dispatches the generator shaders for all nodes written into the node buffers this frame.
+pub struct NodeGeneratorNode;
+
+impl render_graph::Node for NodeGeneratorNode {
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let gpu_node_generators = world.resource::<TerrainComponents<GpuNodeGenerator>>();
+
+        let pass = &mut context
+            .command_encoder()
+            .begin_compute_pass(&ComputePassDescriptor::default());
+
+        for gpu_node_generator in gpu_node_generators.0.values() {
+            if gpu_node_generator
+                .shards
+                .iter()
+                .all(|shard| shard.node_count == 0)
+            {
+                continue;
+            }
+
+            for attachment in &gpu_node_generator.attachments {
+                let Some(pipeline) = attachment
+                    .pipeline
+                    .and_then(|id| pipeline_cache.get_compute_pipeline(id))
+                else {
+                    continue;
+                };
+
+                pass.set_pipeline(pipeline);
+
+                for (shard, mip_levels) in gpu_node_generator.shards.iter().zip(&attachment.shards)
+                {
+                    if shard.node_count == 0 {
+                        continue;
+                    }
+
+                    for (bind_group, texture_size) in mip_levels {
+                        let workgroup_count = (texture_size + 7) / 8;
+
+                        pass.set_bind_group(0, bind_group, &[]);
+                        pass.dispatch_workgroups(
+                            workgroup_count,
+                            workgroup_count,
+                            shard.node_count,
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
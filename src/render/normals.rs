@@ -0,0 +1,532 @@
+//! Recomputes the normal attachment of the edited regions of the terrain on the GPU.
+//!
+//! The normals baked during preprocessing become stale, once the heights are edited at runtime.
+//! Instead of baking them again on the CPU, the regions of the height attachment, that have been
+//! written this frame, are collected for each node and a compute shader derives the normals of
+//! these regions (including a one pixel margin) from the updated heights for all mip levels.
+//!
+//! Insert the [`RecomputeNormals`] component alongside the terrain to enable the recomputation.
+//! The normal attachment has to use the [`AttachmentFormat::Rg16`](crate::terrain_data::AttachmentFormat::Rg16)
+//! and share the texture and border size with the height attachment.
+//! As the normal attachment is written as a storage texture, which is not guaranteed for 16 bit
+//! normalized formats, the recomputation is skipped on adapters that do not support it.
+//! The recomputed normals only live on the GPU, the files of the nodes are not modified.
+
+use crate::{
+    preprocess::normal::NORMAL_ATTACHMENT,
+    render::{shaders::NORMALS_SHADER, NORMAL_RECOMPUTATION_LAYOUT},
+    terrain::{Terrain, TerrainComponents},
+    terrain_data::{
+        gpu_node_atlas::GpuNodeAtlas, node_atlas::NodeAtlas, AtlasIndex, AttachmentIndex,
+        NodeCoordinate, HEIGHT_ATTACHMENT,
+    },
+};
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_graph::{self},
+        render_resource::*,
+        renderer::{RenderAdapter, RenderContext, RenderDevice, RenderQueue},
+        Extract,
+    },
+    utils::HashMap,
+};
+use std::{mem, num::NonZeroU32};
+
+/// This component recomputes the normal attachment of the edited regions of the terrain on the GPU.
+#[derive(Clone, Copy, Component)]
+pub struct RecomputeNormals {
+    /// The index of the normal attachment, which is derived from the height attachment.
+    pub normal_attachment: AttachmentIndex,
+}
+
+impl Default for RecomputeNormals {
+    fn default() -> Self {
+        Self {
+            normal_attachment: NORMAL_ATTACHMENT,
+        }
+    }
+}
+
+impl RecomputeNormals {
+    /// Checks whether the normal attachment of the node atlas can be recomputed on this device.
+    pub(crate) fn validate(
+        &self,
+        node_atlas: &NodeAtlas,
+        device: &RenderDevice,
+        adapter: &RenderAdapter,
+    ) -> Result<(), &'static str> {
+        let (Some(height), Some(normal)) = (
+            node_atlas.attachments.get(HEIGHT_ATTACHMENT),
+            node_atlas.attachments.get(self.normal_attachment),
+        ) else {
+            return Err("The terrain has no height or normal attachment.");
+        };
+
+        if normal.format() != TextureFormat::Rg16Unorm {
+            return Err("The normal attachment has to use the Rg16 format.");
+        }
+
+        if normal.texture_size != height.texture_size
+            || normal.border_size != height.border_size
+            || normal.mip_level_count > height.mip_level_count
+        {
+            return Err("The normal attachment has to match the size of the height attachment.");
+        }
+
+        let storage_supported = device
+            .features()
+            .contains(WgpuFeatures::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+            && adapter
+                .get_texture_format_features(TextureFormat::Rg16Unorm)
+                .allowed_usages
+                .contains(TextureUsages::STORAGE_BINDING);
+
+        if !storage_supported {
+            return Err("The adapter does not support writing Rg16 storage textures.");
+        }
+
+        Ok(())
+    }
+}
+
+/// The edited region of a node (in pixels of the first mip level).
+#[derive(Clone, Copy, ShaderType)]
+pub(crate) struct DirtyRegion {
+    origin: UVec2,
+    size: UVec2,
+    atlas_index: u32,
+    lod: u32,
+}
+
+impl DirtyRegion {
+    /// Extends the region to also cover the other one.
+    fn union(&mut self, other: &Self) {
+        let first = self.origin.min(other.origin);
+        let last = (self.origin + self.size).max(other.origin + other.size);
+
+        self.origin = first;
+        self.size = last - first;
+    }
+}
+
+/// The config of a recomputed mip level of the normal attachment.
+#[derive(ShaderType)]
+struct NormalConfig {
+    mip_level: u32,
+    texture_size: u32,
+    height: f32,
+}
+
+/// The regions of a shard of the atlas, that are recomputed this frame.
+struct NormalShard {
+    region_buffer: Buffer,
+    /// The bind groups of all mip levels.
+    mip_levels: Vec<BindGroup>,
+    /// The number of regions written into the region buffer this frame.
+    region_count: u32,
+}
+
+/// Stores the bind groups of the normal attachment of a terrain alongside the regions,
+/// that still have to be recomputed.
+pub struct GpuNormalRecomputation {
+    normal_attachment: AttachmentIndex,
+    /// The allocated shards of the atlas.
+    shards: Vec<NormalShard>,
+    texture_size: u32,
+    mip_level_count: u32,
+    height: f32,
+    /// The size of the atlas, which the region buffers and the bind groups were created for.
+    atlas_size: AtlasIndex,
+    /// The regions, that are waiting for the pipeline to be compiled.
+    pending_regions: HashMap<AtlasIndex, DirtyRegion>,
+    /// The largest extent of the regions written this frame.
+    max_size: u32,
+}
+
+impl GpuNormalRecomputation {
+    fn new(
+        device: &RenderDevice,
+        images: &RenderAssets<Image>,
+        pipeline: &NormalRecomputationPipeline,
+        node_atlas: &NodeAtlas,
+        gpu_node_atlas: &GpuNodeAtlas,
+        recompute: &RecomputeNormals,
+    ) -> Self {
+        let normal_attachment = &node_atlas.attachments[recompute.normal_attachment];
+
+        let mut recomputation = Self {
+            normal_attachment: recompute.normal_attachment,
+            shards: Vec::new(),
+            texture_size: normal_attachment.texture_size,
+            mip_level_count: normal_attachment.mip_level_count,
+            height: node_atlas.height,
+            atlas_size: 0,
+            pending_regions: default(),
+            max_size: 0,
+        };
+
+        recomputation.resize(device, images, pipeline, gpu_node_atlas);
+        recomputation
+    }
+
+    /// Recreates the region buffers and the bind groups of all allocated shards of the atlas,
+    /// after the atlas has grown.
+    fn resize(
+        &mut self,
+        device: &RenderDevice,
+        images: &RenderAssets<Image>,
+        pipeline: &NormalRecomputationPipeline,
+        gpu_node_atlas: &GpuNodeAtlas,
+    ) {
+        self.atlas_size = gpu_node_atlas.size;
+        self.shards = gpu_node_atlas.attachments[HEIGHT_ATTACHMENT]
+            .iter()
+            .zip(&gpu_node_atlas.attachments[self.normal_attachment])
+            .enumerate()
+            .map(|(shard, handles)| (gpu_node_atlas.shard_layers(shard, self.atlas_size), handles))
+            .take_while(|&(layers, _)| layers > 0)
+            .map(|(layers, (height_handle, normal_handle))| {
+                let region_buffer = create_region_buffer(device, layers);
+                let mip_levels = self.bind_mip_levels(
+                    device,
+                    pipeline,
+                    &region_buffer,
+                    &images.get(height_handle).unwrap().texture_view,
+                    &images.get(normal_handle).unwrap().texture,
+                );
+
+                NormalShard {
+                    region_buffer,
+                    mip_levels,
+                    region_count: 0,
+                }
+            })
+            .collect();
+    }
+
+    /// Creates the bind groups of all mip levels of the shard of the normal attachment.
+    fn bind_mip_levels(
+        &self,
+        device: &RenderDevice,
+        pipeline: &NormalRecomputationPipeline,
+        region_buffer: &Buffer,
+        height_view: &TextureView,
+        normal_texture: &Texture,
+    ) -> Vec<BindGroup> {
+        (0..self.mip_level_count)
+            .map(|mip_level| {
+                let texture_size = self.texture_size >> mip_level;
+
+                let mut buffer = encase::UniformBuffer::new(Vec::new());
+                buffer
+                    .write(&NormalConfig {
+                        mip_level,
+                        texture_size,
+                        height: self.height,
+                    })
+                    .unwrap();
+
+                let config_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+                    label: "normal_config_buffer".into(),
+                    usage: BufferUsages::UNIFORM,
+                    contents: &buffer.into_inner(),
+                });
+
+                let normal_view = normal_texture.create_view(&TextureViewDescriptor {
+                    dimension: Some(TextureViewDimension::D2Array),
+                    base_mip_level: mip_level,
+                    mip_level_count: NonZeroU32::new(1),
+                    ..default()
+                });
+
+                device.create_bind_group(&BindGroupDescriptor {
+                    label: "normal_recomputation_bind_group".into(),
+                    layout: &pipeline.layout,
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: config_buffer.as_entire_binding(),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: region_buffer.as_entire_binding(),
+                        },
+                        BindGroupEntry {
+                            binding: 2,
+                            resource: BindingResource::TextureView(height_view),
+                        },
+                        BindGroupEntry {
+                            binding: 3,
+                            resource: BindingResource::TextureView(&normal_view),
+                        },
+                    ],
+                })
+            })
+            .collect()
+    }
+}
+
+/// Creates the buffer of the regions of a shard, which are recomputed in one frame.
+fn create_region_buffer(device: &RenderDevice, layer_count: u32) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: "dirty_regions_buffer".into(),
+        size: DirtyRegion::min_size().get() * layer_count as BufferAddress,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Initializes the [`GpuNormalRecomputation`] of newly created terrains.
+pub(crate) fn initialize_gpu_normal_recomputation(
+    device: Res<RenderDevice>,
+    adapter: Res<RenderAdapter>,
+    images: Res<RenderAssets<Image>>,
+    pipeline: Res<NormalRecomputationPipeline>,
+    gpu_node_atlases: Res<TerrainComponents<GpuNodeAtlas>>,
+    mut gpu_normal_recomputations: ResMut<TerrainComponents<GpuNormalRecomputation>>,
+    terrain_query: Extract<Query<(Entity, &NodeAtlas, &RecomputeNormals), Added<Terrain>>>,
+) {
+    for (terrain, node_atlas, recompute) in terrain_query.iter() {
+        if let Err(error) = recompute.validate(node_atlas, &device, &adapter) {
+            warn!("The normals of the terrain can not be recomputed: {error}");
+            continue;
+        }
+
+        let gpu_node_atlas = gpu_node_atlases.get(&terrain).unwrap();
+
+        gpu_normal_recomputations.insert(
+            terrain,
+            GpuNormalRecomputation::new(
+                &device,
+                &images,
+                &pipeline,
+                node_atlas,
+                gpu_node_atlas,
+                recompute,
+            ),
+        );
+    }
+}
+
+/// Collects the regions of the height attachment, that are written this frame,
+/// from all [`GpuNodeAtlas`]es into the corresponding [`GpuNormalRecomputation`]s.
+pub(crate) fn extract_normal_recomputation(
+    gpu_node_atlases: Res<TerrainComponents<GpuNodeAtlas>>,
+    mut gpu_normal_recomputations: ResMut<TerrainComponents<GpuNormalRecomputation>>,
+    terrain_query: Extract<Query<(Entity, &NodeAtlas), With<RecomputeNormals>>>,
+) {
+    for (terrain, node_atlas) in terrain_query.iter() {
+        let (Some(gpu_node_atlas), Some(gpu_normal_recomputation)) = (
+            gpu_node_atlases.get(&terrain),
+            gpu_normal_recomputations.get_mut(&terrain),
+        ) else {
+            continue;
+        };
+
+        let GpuNormalRecomputation {
+            texture_size,
+            ref mut pending_regions,
+            ..
+        } = *gpu_normal_recomputation;
+
+        // the pending regions follow the nodes, that have been migrated this frame
+        for &(from, to) in &gpu_node_atlas.migrated_nodes {
+            pending_regions.remove(&to);
+
+            if let Some(mut region) = pending_regions.remove(&from) {
+                region.atlas_index = to as u32;
+                pending_regions.insert(to, region);
+            }
+        }
+
+        // the slots of newly loaded nodes are overwritten with their own normals
+        for node in &gpu_node_atlas.loaded_nodes {
+            pending_regions.remove(&node.atlas_index);
+        }
+
+        let mut updates = gpu_node_atlas
+            .attachment_updates
+            .iter()
+            .filter(|update| update.attachment_index == HEIGHT_ATTACHMENT && update.mip_level == 0)
+            .peekable();
+
+        if updates.peek().is_none() {
+            continue;
+        }
+
+        let lods = node_atlas
+            .nodes
+            .iter()
+            .map(|(&node_id, node)| (node.atlas_index, NodeCoordinate::from(node_id).lod))
+            .collect::<HashMap<_, _>>();
+
+        for update in updates {
+            let Some(&lod) = lods.get(&update.atlas_index) else {
+                continue;
+            };
+
+            // the normals of the neighbouring pixels depend on the edited heights as well
+            let first = update.origin.max(UVec2::ONE) - UVec2::ONE;
+            let last = (update.origin + update.size + UVec2::ONE).min(UVec2::splat(texture_size));
+
+            let region = DirtyRegion {
+                origin: first,
+                size: last - first,
+                atlas_index: update.atlas_index as u32,
+                lod,
+            };
+
+            pending_regions
+                .entry(update.atlas_index)
+                .and_modify(|pending| pending.union(&region))
+                .or_insert(region);
+        }
+    }
+}
+
+/// Writes the pending regions into the region buffers of their shards,
+/// once the pipeline is ready.
+///
+/// The recomputations of grown atlases are rebound to the reallocated attachments beforehand.
+pub(crate) fn prepare_normal_recomputation(
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    images: Res<RenderAssets<Image>>,
+    pipeline_cache: Res<PipelineCache>,
+    pipeline: Res<NormalRecomputationPipeline>,
+    gpu_node_atlases: Res<TerrainComponents<GpuNodeAtlas>>,
+    mut gpu_normal_recomputations: ResMut<TerrainComponents<GpuNormalRecomputation>>,
+) {
+    let ready = pipeline_cache
+        .get_compute_pipeline(pipeline.pipeline)
+        .is_some();
+
+    for (terrain, gpu_normal_recomputation) in gpu_normal_recomputations.0.iter_mut() {
+        for shard in &mut gpu_normal_recomputation.shards {
+            shard.region_count = 0;
+        }
+
+        let Some(gpu_node_atlas) = gpu_node_atlases.get(terrain) else {
+            continue;
+        };
+
+        if gpu_node_atlas.size != gpu_normal_recomputation.atlas_size {
+            gpu_normal_recomputation.resize(&device, &images, &pipeline, gpu_node_atlas);
+        }
+
+        if !ready || gpu_normal_recomputation.pending_regions.is_empty() {
+            continue;
+        }
+
+        let regions = mem::take(&mut gpu_normal_recomputation.pending_regions);
+
+        gpu_normal_recomputation.max_size = regions
+            .values()
+            .map(|region| region.size.max_element())
+            .max()
+            .unwrap();
+
+        // the regions are recomputed in the layers of their shards
+        let mut shard_regions = vec![Vec::new(); gpu_normal_recomputation.shards.len()];
+
+        for (atlas_index, mut region) in regions {
+            let (shard, layer) = gpu_node_atlas.shard(atlas_index);
+            region.atlas_index = layer;
+
+            if let Some(regions) = shard_regions.get_mut(shard) {
+                regions.push(region);
+            }
+        }
+
+        for (shard, regions) in gpu_normal_recomputation
+            .shards
+            .iter_mut()
+            .zip(shard_regions)
+        {
+            if regions.is_empty() {
+                continue;
+            }
+
+            let mut buffer = encase::StorageBuffer::new(Vec::new());
+            buffer.write(&regions).unwrap();
+            queue.write_buffer(&shard.region_buffer, 0, &buffer.into_inner());
+
+            shard.region_count = regions.len() as u32;
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct NormalRecomputationPipeline {
+    layout: BindGroupLayout,
+    pipeline: CachedComputePipelineId,
+}
+
+impl FromWorld for NormalRecomputationPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+
+        let layout = device.create_bind_group_layout(&NORMAL_RECOMPUTATION_LAYOUT);
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("normal_recomputation_pipeline".into()),
+            layout: vec![layout.clone()],
+            push_constant_ranges: default(),
+            shader: NORMALS_SHADER.typed(),
+            shader_defs: default(),
+            entry_point: "recompute_normals".into(),
+        });
+
+        Self { layout, pipeline }
+    }
+}
+
+/// Dispatches the normal recomputation for all regions written into the region buffers this frame.
+pub struct NormalRecomputationNode;
+
+impl render_graph::Node for NormalRecomputationNode {
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipeline = world.resource::<NormalRecomputationPipeline>();
+        let gpu_normal_recomputations =
+            world.resource::<TerrainComponents<GpuNormalRecomputation>>();
+
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(pipeline.pipeline) else {
+            return Ok(());
+        };
+
+        let pass = &mut context
+            .command_encoder()
+            .begin_compute_pass(&ComputePassDescriptor::default());
+
+        pass.set_pipeline(pipeline);
+
+        for gpu_normal_recomputation in gpu_normal_recomputations.0.values() {
+            for shard in &gpu_normal_recomputation.shards {
+                if shard.region_count == 0 {
+                    continue;
+                }
+
+                for (mip_level, bind_group) in shard.mip_levels.iter().enumerate() {
+                    // the region may straddle an additional pixel of the coarser mip levels
+                    let size = (gpu_normal_recomputation.max_size >> mip_level) + 2;
+                    let workgroup_count = (size + 7) / 8;
+
+                    pass.set_bind_group(0, bind_group, &[]);
+                    pass.dispatch_workgroups(workgroup_count, workgroup_count, shard.region_count);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,15 +1,24 @@
 use crate::{
+    preprocess::{ambient_occlusion::AMBIENT_OCCLUSION_ATTACHMENT, horizon::HORIZON_ATTACHMENT},
     render::{
         shaders::DEFAULT_SHADER,
-        terrain_data::{terrain_bind_group_layout, SetTerrainBindGroup},
-        terrain_view_data::{DrawTerrainCommand, SetTerrainViewBindGroup},
-        TERRAIN_VIEW_LAYOUT,
+        shadows::{
+            queue_terrain_shadow_culling, DrawTerrainShadowCommand, SetTerrainShadowTilesBindGroup,
+            SetTerrainShadowViewBindGroup, TerrainShadowData, TerrainShadowViews,
+        },
+        terrain_data::{
+            atlas_shard_binding, atlas_shard_shader_defs, terrain_bind_group_layout,
+            SetTerrainBindGroup,
+        },
+        terrain_view_data::{DrawTerrainCommand, SetTerrainViewBindGroup, TerrainViewData},
+        SHADOW_VIEW_LAYOUT, TERRAIN_VIEW_LAYOUT,
     },
-    DebugTerrain, Terrain,
+    terrain_data::AttachmentIndex,
+    DebugTerrain, Terrain, TerrainSystemSet, TerrainViewComponents,
 };
 use bevy::{
     core_pipeline::core_3d::Opaque3d,
-    pbr::{MeshPipeline, RenderMaterials, SetMaterialBindGroup, SetMeshViewBindGroup},
+    pbr::{MeshPipeline, RenderMaterials, SetMaterialBindGroup, SetMeshViewBindGroup, Shadow},
     prelude::*,
     render::{
         render_phase::{AddRenderCommand, DrawFunctions, RenderPhase, SetItemPipeline},
@@ -27,6 +36,22 @@ use bevy::pbr::{MAX_CASCADES_PER_LIGHT, MAX_DIRECTIONAL_LIGHTS};
 pub struct TerrainPipelineConfig {
     /// The number of terrain attachments.
     pub attachment_count: usize,
+    /// The index of the hole attachment, whose masked fragments are discarded.
+    pub hole_attachment: Option<AttachmentIndex>,
+    /// The index of the snow attachment, which raises and covers the surface.
+    pub snow_attachment: Option<AttachmentIndex>,
+    /// The index of the normal attachment, which the default material shades the surface with.
+    pub normal_attachment: Option<AttachmentIndex>,
+    /// The index of the albedo attachment, which the default material uses as its base color.
+    pub albedo_attachment: Option<AttachmentIndex>,
+    /// Whether the decals of the terrains are projected onto their surface.
+    pub decals: bool,
+    /// Whether the detail layers of the terrains are composited over their heights.
+    pub detail_layer: bool,
+    /// The amount of atlas indices per texture of the sharded node atlases.
+    pub atlas_shard_size: Option<u32>,
+    /// The amount of textures, which each attachment of the sharded node atlases is split into.
+    pub atlas_shard_count: usize,
 }
 
 pub struct TerrainPipelineKey<M: Material> {
@@ -86,6 +111,11 @@ pub struct TerrainPipelineFlags: u32 {
     const TEST1              = (1 << 12);
     const TEST2              = (1 << 13);
     const TEST3              = (1 << 14);
+    const SHADOW             = (1 << 15);
+    const SHOW_LOD_HEATMAP   = (1 << 16);
+    const SHOW_NODE_CHECKERS = (1 << 17);
+    const SHOW_UV_DENSITY    = (1 << 18);
+    const SHOW_MORPH         = (1 << 19);
 
     const MSAA_RESERVED_BITS = TerrainPipelineFlags::MSAA_MASK_BITS << TerrainPipelineFlags::MSAA_SHIFT_BITS;
 }
@@ -124,6 +154,18 @@ impl TerrainPipelineFlags {
         if debug.show_nodes {
             key |= TerrainPipelineFlags::SHOW_NODES;
         }
+        if debug.show_lod_heatmap {
+            key |= TerrainPipelineFlags::SHOW_LOD_HEATMAP;
+        }
+        if debug.show_node_checkers {
+            key |= TerrainPipelineFlags::SHOW_NODE_CHECKERS;
+        }
+        if debug.show_uv_density {
+            key |= TerrainPipelineFlags::SHOW_UV_DENSITY;
+        }
+        if debug.show_morph {
+            key |= TerrainPipelineFlags::SHOW_MORPH;
+        }
         if debug.mesh_morph {
             key |= TerrainPipelineFlags::MESH_MORPH;
         }
@@ -156,6 +198,10 @@ impl TerrainPipelineFlags {
         ((self.bits >> Self::MSAA_SHIFT_BITS) & Self::MSAA_MASK_BITS) + 1
     }
 
+    pub fn shadow(&self) -> bool {
+        (self.bits & TerrainPipelineFlags::SHADOW.bits) != 0
+    }
+
     pub fn polygon_mode(&self) -> PolygonMode {
         match (self.bits & TerrainPipelineFlags::WIREFRAME.bits) != 0 {
             true => PolygonMode::Line,
@@ -181,6 +227,18 @@ impl TerrainPipelineFlags {
         if (self.bits & TerrainPipelineFlags::SHOW_MINMAX_ERROR.bits) != 0 {
             shader_defs.push("SHOW_MINMAX_ERROR".into());
         }
+        if (self.bits & TerrainPipelineFlags::SHOW_LOD_HEATMAP.bits) != 0 {
+            shader_defs.push("SHOW_LOD_HEATMAP".into());
+        }
+        if (self.bits & TerrainPipelineFlags::SHOW_NODE_CHECKERS.bits) != 0 {
+            shader_defs.push("SHOW_NODE_CHECKERS".into());
+        }
+        if (self.bits & TerrainPipelineFlags::SHOW_UV_DENSITY.bits) != 0 {
+            shader_defs.push("SHOW_UV_DENSITY".into());
+        }
+        if (self.bits & TerrainPipelineFlags::SHOW_MORPH.bits) != 0 {
+            shader_defs.push("SHOW_MORPH".into());
+        }
         if (self.bits & TerrainPipelineFlags::MINMAX.bits) != 0 {
             shader_defs.push("MINMAX".into());
         }
@@ -208,6 +266,9 @@ impl TerrainPipelineFlags {
         if (self.bits & TerrainPipelineFlags::TEST3.bits) != 0 {
             shader_defs.push("TEST3".into());
         }
+        if (self.bits & TerrainPipelineFlags::SHADOW.bits) != 0 {
+            shader_defs.push("SHADOW".into());
+        }
 
         shader_defs
     }
@@ -218,9 +279,19 @@ impl TerrainPipelineFlags {
 pub struct TerrainRenderPipeline<M: Material> {
     pub(crate) view_layout: BindGroupLayout,
     pub(crate) view_layout_multisampled: BindGroupLayout,
+    pub(crate) shadow_view_layout: BindGroupLayout,
     pub(crate) terrain_layout: BindGroupLayout,
     pub(crate) terrain_view_layout: BindGroupLayout,
     pub(crate) material_layout: BindGroupLayout,
+    pub(crate) attachment_count: usize,
+    pub(crate) hole_attachment: Option<AttachmentIndex>,
+    pub(crate) snow_attachment: Option<AttachmentIndex>,
+    pub(crate) normal_attachment: Option<AttachmentIndex>,
+    pub(crate) albedo_attachment: Option<AttachmentIndex>,
+    pub(crate) decals: bool,
+    pub(crate) detail_layer: bool,
+    pub(crate) atlas_shard_size: Option<u32>,
+    pub(crate) atlas_shard_count: usize,
     pub vertex_shader: Handle<Shader>,
     pub fragment_shader: Handle<Shader>,
     marker: PhantomData<M>,
@@ -235,7 +306,14 @@ impl<M: Material> FromWorld for TerrainRenderPipeline<M> {
 
         let view_layout = mesh_pipeline.view_layout.clone();
         let view_layout_multisampled = mesh_pipeline.view_layout_multisampled.clone();
-        let terrain_layout = terrain_bind_group_layout(device, config.attachment_count);
+        let shadow_view_layout = device.create_bind_group_layout(&SHADOW_VIEW_LAYOUT);
+        let terrain_layout = terrain_bind_group_layout(
+            device,
+            config.attachment_count,
+            config.atlas_shard_count,
+            config.decals,
+            config.detail_layer,
+        );
         let terrain_view_layout = device.create_bind_group_layout(&TERRAIN_VIEW_LAYOUT);
         let material_layout = M::bind_group_layout(device);
 
@@ -254,9 +332,19 @@ impl<M: Material> FromWorld for TerrainRenderPipeline<M> {
         Self {
             view_layout,
             view_layout_multisampled,
+            shadow_view_layout,
             terrain_layout,
             terrain_view_layout,
             material_layout,
+            attachment_count: config.attachment_count,
+            hole_attachment: config.hole_attachment,
+            snow_attachment: config.snow_attachment,
+            normal_attachment: config.normal_attachment,
+            albedo_attachment: config.albedo_attachment,
+            decals: config.decals,
+            detail_layer: config.detail_layer,
+            atlas_shard_size: config.atlas_shard_size,
+            atlas_shard_count: config.atlas_shard_count,
             vertex_shader,
             fragment_shader,
             marker: PhantomData,
@@ -273,12 +361,105 @@ where
     fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
         let mut shader_defs = key.flags.shader_defs();
 
+        // the hole attachment is bound by the shared fragment entry point of all materials
+        if let Some(index) = self.hole_attachment {
+            shader_defs.push("HOLES".into());
+            shader_defs.push(ShaderDefVal::UInt(
+                "HOLE_BINDING".to_string(),
+                index as u32 + 2,
+            ));
+        }
+
+        // the snow attachment is bound by the shared vertex and fragment functions as well
+        if let Some(index) = self.snow_attachment {
+            shader_defs.push("SNOW".into());
+            shader_defs.push(ShaderDefVal::UInt(
+                "SNOW_BINDING".to_string(),
+                index as u32 + 2,
+            ));
+        }
+
+        // the normal and albedo attachments are bound by the default material at their
+        // configured indices
+        for (name, index) in [
+            ("NORMAL_ATTACHMENT", self.normal_attachment),
+            ("ALBEDO_ATTACHMENT", self.albedo_attachment),
+        ] {
+            let Some(index) = index else {
+                continue;
+            };
+
+            shader_defs.push(name.into());
+            shader_defs.push(ShaderDefVal::UInt(
+                format!("{name}_BINDING"),
+                index as u32 + 2,
+            ));
+            shader_defs.push(ShaderDefVal::UInt(format!("{name}_INDEX"), index as u32));
+        }
+
+        // the baked attachments of the default material are bound at their fixed indices,
+        // unless these belong to one of the configured attachments
+        let configured_attachments = [
+            self.hole_attachment,
+            self.snow_attachment,
+            self.normal_attachment,
+            self.albedo_attachment,
+        ];
+
+        for (name, index) in [
+            ("AMBIENT_OCCLUSION", AMBIENT_OCCLUSION_ATTACHMENT),
+            ("HORIZON", HORIZON_ATTACHMENT),
+        ] {
+            if index < self.attachment_count && !configured_attachments.contains(&Some(index)) {
+                shader_defs.push(name.into());
+            }
+        }
+
+        if self.decals {
+            shader_defs.push("DECALS".into());
+        }
+
+        if self.detail_layer {
+            shader_defs.push("DETAIL_LAYER".into());
+        }
+
+        // the shards of the configured attachments are bound by the pipeline as well
+        shader_defs.extend(atlas_shard_shader_defs(
+            self.attachment_count,
+            self.atlas_shard_size,
+            self.atlas_shard_count,
+        ));
+
+        if self.atlas_shard_size.is_some() {
+            for (name, index) in [
+                ("HOLE", self.hole_attachment),
+                ("SNOW", self.snow_attachment),
+                ("NORMAL_ATTACHMENT", self.normal_attachment),
+                ("ALBEDO_ATTACHMENT", self.albedo_attachment),
+            ] {
+                let Some(index) = index else {
+                    continue;
+                };
+
+                for shard in 1..self.atlas_shard_count {
+                    shader_defs.push(ShaderDefVal::UInt(
+                        format!("{name}_SHARD_{shard}_BINDING"),
+                        atlas_shard_binding(index, shard),
+                    ));
+                }
+            }
+        }
+
         shader_defs.push(ShaderDefVal::UInt("MAX_DIRECTIONAL_LIGHTS".to_string(), MAX_DIRECTIONAL_LIGHTS as u32));
         shader_defs.push(ShaderDefVal::UInt("MAX_CASCADES_PER_LIGHT".to_string(), MAX_CASCADES_PER_LIGHT as u32));
 
-        let mut bind_group_layout = match key.flags.msaa_samples() {
-            1 => vec![self.view_layout.clone()],
-            _ => {
+        let shadow = key.flags.shadow();
+
+        // the shadow cascades only bind their view uniform
+        let mut bind_group_layout = match (shadow, key.flags.msaa_samples()) {
+            (true, _) => vec![self.shadow_view_layout.clone()],
+            (false, 1) => vec![self.view_layout.clone()],
+            (false, _) => {
                 shader_defs.push("MULTISAMPLED".into());
                 vec![self.view_layout_multisampled.clone()]
             }
@@ -300,27 +481,42 @@ where
             },
             primitive: PrimitiveState {
                 front_face: FrontFace::Ccw,
-                cull_mode: Some(Face::Back),
+                // both sides of the terrain cast shadows, e.g. when the light is low
+                cull_mode: if shadow { None } else { Some(Face::Back) },
                 unclipped_depth: false,
                 polygon_mode: key.flags.polygon_mode(),
                 conservative: false,
                 topology: PrimitiveTopology::TriangleStrip,
                 strip_index_format: None,
             },
-            fragment: Some(FragmentState {
-                shader: self.fragment_shader.clone(),
-                shader_defs,
-                entry_point: "fragment".into(),
-                targets: vec![Some(ColorTargetState {
-                    format: TextureFormat::bevy_default(),
-                    blend: Some(BlendState::REPLACE),
-                    write_mask: ColorWrites::ALL,
-                })],
-            }),
+            // the shadow cascades are depth only, but still have to discard the holes
+            fragment: match (shadow, self.hole_attachment) {
+                (false, _) => Some(FragmentState {
+                    shader: self.fragment_shader.clone(),
+                    shader_defs,
+                    entry_point: "fragment".into(),
+                    targets: vec![Some(ColorTargetState {
+                        format: TextureFormat::bevy_default(),
+                        blend: Some(BlendState::REPLACE),
+                        write_mask: ColorWrites::ALL,
+                    })],
+                }),
+                (true, Some(_)) => Some(FragmentState {
+                    shader: self.fragment_shader.clone(),
+                    shader_defs,
+                    entry_point: "depth_fragment".into(),
+                    targets: Vec::new(),
+                }),
+                (true, None) => None,
+            },
             depth_stencil: Some(DepthStencilState {
                 format: TextureFormat::Depth32Float,
                 depth_write_enabled: true,
-                depth_compare: CompareFunction::Greater,
+                depth_compare: if shadow {
+                    CompareFunction::GreaterEqual
+                } else {
+                    CompareFunction::Greater
+                },
                 stencil: StencilState {
                     front: StencilFaceState::IGNORE,
                     back: StencilFaceState::IGNORE,
@@ -353,6 +549,16 @@ pub(crate) type DrawTerrain<M> = (
     DrawTerrainCommand,
 );
 
+/// The draw function of the terrain for the shadow cascades of the directional lights.
+pub(crate) type DrawTerrainShadow<M> = (
+    SetItemPipeline,
+    SetTerrainShadowViewBindGroup<0>,
+    SetTerrainShadowTilesBindGroup<1>,
+    SetTerrainBindGroup<2>,
+    SetMaterialBindGroup<M, 3>,
+    DrawTerrainShadowCommand,
+);
+
 /// Queses all terrain entities for rendering via the terrain pipeline.
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn queue_terrain<M: Material>(
@@ -363,15 +569,21 @@ pub(crate) fn queue_terrain<M: Material>(
     terrain_pipeline: Res<TerrainRenderPipeline<M>>,
     pipeline_cache: Res<PipelineCache>,
     mut pipelines: ResMut<SpecializedRenderPipelines<TerrainRenderPipeline<M>>>,
-    mut view_query: Query<&mut RenderPhase<Opaque3d>>,
+    terrain_view_data: Res<TerrainViewComponents<TerrainViewData>>,
+    mut view_query: Query<(Entity, &mut RenderPhase<Opaque3d>)>,
     terrain_query: Query<(Entity, &Handle<M>), With<Terrain>>,
 ) where
     M::Data: PartialEq + Eq + Hash + Clone,
 {
     let draw_function = draw_functions.read().get_id::<DrawTerrain<M>>().unwrap();
 
-    for mut opaque_phase in view_query.iter_mut() {
+    for (view, mut opaque_phase) in view_query.iter_mut() {
         for (entity, material) in terrain_query.iter() {
+            // only draw the terrain for views registered with it
+            if !terrain_view_data.contains_key(&(entity, view)) {
+                continue;
+            }
+
             if let Some(material) = render_materials.get(material) {
                 let mut flags = TerrainPipelineFlags::from_msaa_samples(msaa.samples());
 
@@ -402,9 +614,78 @@ pub(crate) fn queue_terrain<M: Material>(
     }
 }
 
+/// Queues all terrain entities for rendering into the shadow cascades of the directional lights.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn queue_terrain_shadows<M: Material>(
+    draw_functions: Res<DrawFunctions<Shadow>>,
+    debug: Option<Res<DebugTerrain>>,
+    render_materials: Res<RenderMaterials<M>>,
+    terrain_pipeline: Res<TerrainRenderPipeline<M>>,
+    pipeline_cache: Res<PipelineCache>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<TerrainRenderPipeline<M>>>,
+    shadow_views: Res<TerrainShadowViews>,
+    shadow_data: Res<TerrainViewComponents<TerrainShadowData>>,
+    mut cascade_query: Query<(Entity, &mut RenderPhase<Shadow>)>,
+    terrain_query: Query<(Entity, &Handle<M>), With<Terrain>>,
+) where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    let draw_function = draw_functions
+        .read()
+        .get_id::<DrawTerrainShadow<M>>()
+        .unwrap();
+
+    for (cascade_view, mut shadow_phase) in cascade_query.iter_mut() {
+        for (entity, material) in terrain_query.iter() {
+            // only draw the terrain into the cascades of views registered with it
+            if shadow_views
+                .tiles(&shadow_data, entity, cascade_view)
+                .is_none()
+            {
+                continue;
+            }
+
+            if let Some(material) = render_materials.get(material) {
+                let mut flags =
+                    TerrainPipelineFlags::from_msaa_samples(1) | TerrainPipelineFlags::SHADOW;
+
+                if debug.as_ref().map_or(true, |debug| debug.mesh_morph) {
+                    flags |= TerrainPipelineFlags::MESH_MORPH;
+                }
+
+                let key = TerrainPipelineKey {
+                    flags,
+                    bind_group_data: material.key.clone(),
+                };
+
+                let pipeline_id = pipelines.specialize(&pipeline_cache, &terrain_pipeline, key);
+
+                shadow_phase.add(Shadow {
+                    entity,
+                    pipeline: pipeline_id,
+                    draw_function,
+                    distance: 0.0,
+                });
+            }
+        }
+    }
+}
+
 /// This plugin adds a custom material for a terrain.
 ///
 /// It can be used to render the terrain using a custom vertex and fragment shader.
+///
+/// Instead of replacing the entire shader, the fragment shader of a material can also extend
+/// the built-in terrain materials, by importing `bevy_terrain::default_material`
+/// (or `bevy_terrain::splat_material`) and implementing the WGSL hooks `extend_pbr_input`
+/// and `extend_color`, which adjust the shading before and after the lighting.
+/// Hooks that are not needed are imported from `bevy_terrain::default_pbr_input`
+/// and `bevy_terrain::default_color` respectively.
+/// The bindings of the material are placed in group 3.
+/// The hooks receive the interpolated vertex outputs (world position, morph factor and
+/// splat weights), which are documented with the `VertexOutput` of `bevy_terrain::functions`.
+/// Custom vertex shaders importing `bevy_terrain::vertex` have to implement the hooks
+/// `vertex_height` and `vertex_splat_weights`.
 pub struct TerrainMaterialPlugin<M: Material>(PhantomData<M>);
 
 impl<M: Material> Default for TerrainMaterialPlugin<M> {
@@ -431,9 +712,20 @@ where
                 //     prepare_materials::<M>.after(PrepareAssetLabel::PreAssetPrepare),
                 // )
                 .add_render_command::<Opaque3d, DrawTerrain<M>>()
+                .add_render_command::<Shadow, DrawTerrainShadow<M>>()
                 .init_resource::<TerrainRenderPipeline<M>>()
                 .init_resource::<SpecializedRenderPipelines<TerrainRenderPipeline<M>>>()
-                .add_system(queue_terrain::<M>.in_set(RenderSet::Queue));
+                .add_system(
+                    queue_terrain::<M>
+                        .in_set(TerrainSystemSet::Render)
+                        .in_set(RenderSet::Queue),
+                )
+                .add_system(
+                    queue_terrain_shadows::<M>
+                        .after(queue_terrain_shadow_culling)
+                        .in_set(TerrainSystemSet::Render)
+                        .in_set(RenderSet::Queue),
+                );
         }
     }
 }
@@ -0,0 +1,223 @@
+//! Renders the scattered detail objects (see [`crate::scatter`]) with GPU instancing.
+//!
+//! The instances of each layer and node are uploaded once into an instance buffer,
+//! which is reused until the instances change, and drawn with a single instanced draw call.
+
+use crate::{
+    render::shaders::SCATTER_SHADER,
+    scatter::{ScatterInstance, ScatterInstances},
+};
+use bevy::{
+    core_pipeline::core_3d::Opaque3d,
+    ecs::system::{lifetimeless::*, SystemParamItem},
+    pbr::{MeshPipeline, MeshPipelineKey, MeshUniform, SetMeshBindGroup, SetMeshViewBindGroup},
+    prelude::*,
+    render::{
+        mesh::{GpuBufferInfo, MeshVertexBufferLayout},
+        render_asset::RenderAssets,
+        render_phase::{
+            DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult, RenderPhase,
+            SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::*,
+        renderer::RenderDevice,
+        view::{ExtractedView, VisibleEntities},
+    },
+    utils::HashMap,
+};
+use std::{mem, sync::Arc};
+
+/// The render pipeline of the scattered instances, which extends the mesh pipeline
+/// by the instance buffer.
+#[derive(Resource)]
+pub struct ScatterPipeline {
+    mesh_pipeline: MeshPipeline,
+    shader: Handle<Shader>,
+}
+
+impl FromWorld for ScatterPipeline {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            mesh_pipeline: world.resource::<MeshPipeline>().clone(),
+            shader: SCATTER_SHADER.typed(),
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for ScatterPipeline {
+    type Key = MeshPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
+        descriptor.label = Some("scatter_pipeline".into());
+        descriptor.vertex.shader = self.shader.clone();
+        descriptor.vertex.buffers.push(VertexBufferLayout {
+            array_stride: 3 * mem::size_of::<[f32; 4]>() as BufferAddress,
+            step_mode: VertexStepMode::Instance,
+            attributes: vec![
+                // position and scale
+                VertexAttribute {
+                    format: VertexFormat::Float32x4,
+                    offset: 0,
+                    shader_location: 8,
+                },
+                // rotation and fade distances
+                VertexAttribute {
+                    format: VertexFormat::Float32x4,
+                    offset: 4 * 4,
+                    shader_location: 9,
+                },
+                // color
+                VertexAttribute {
+                    format: VertexFormat::Float32x4,
+                    offset: 8 * 4,
+                    shader_location: 10,
+                },
+            ],
+        });
+        descriptor.fragment.as_mut().unwrap().shader = self.shader.clone();
+
+        Ok(descriptor)
+    }
+}
+
+/// The instance buffer of the scattered instances of an entity.
+#[derive(Component)]
+pub struct ScatterBuffer {
+    buffer: Buffer,
+    length: u32,
+}
+
+/// Caches the instance buffers, so that they are only uploaded, once their instances change.
+#[derive(Default, Resource)]
+pub struct ScatterBuffers(HashMap<Entity, (Arc<[ScatterInstance]>, Buffer)>);
+
+pub(crate) fn prepare_scatter_buffers(
+    mut commands: Commands,
+    device: Res<RenderDevice>,
+    mut scatter_buffers: ResMut<ScatterBuffers>,
+    scatter_query: Query<(Entity, &ScatterInstances)>,
+) {
+    let mut buffers = HashMap::default();
+
+    for (entity, ScatterInstances(instances)) in scatter_query.iter() {
+        let buffer = match scatter_buffers.0.remove(&entity) {
+            Some((cached, buffer)) if Arc::ptr_eq(&cached, instances) => buffer,
+            _ => device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("scatter_instance_buffer"),
+                contents: bytemuck::cast_slice(instances),
+                usage: BufferUsages::VERTEX,
+            }),
+        };
+
+        commands.entity(entity).insert(ScatterBuffer {
+            buffer: buffer.clone(),
+            length: instances.len() as u32,
+        });
+
+        buffers.insert(entity, (instances.clone(), buffer));
+    }
+
+    // the buffers of the despawned entities are dropped
+    scatter_buffers.0 = buffers;
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn queue_scatter(
+    draw_functions: Res<DrawFunctions<Opaque3d>>,
+    scatter_pipeline: Res<ScatterPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<ScatterPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    msaa: Res<Msaa>,
+    meshes: Res<RenderAssets<Mesh>>,
+    scatter_query: Query<(&MeshUniform, &Handle<Mesh>), With<ScatterBuffer>>,
+    mut view_query: Query<(&ExtractedView, &VisibleEntities, &mut RenderPhase<Opaque3d>)>,
+) {
+    let draw_function = draw_functions.read().id::<DrawScatter>();
+    let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+
+    for (view, visible_entities, mut opaque_phase) in view_query.iter_mut() {
+        let view_key = msaa_key | MeshPipelineKey::from_hdr(view.hdr);
+        let rangefinder = view.rangefinder3d();
+
+        for &entity in &visible_entities.entities {
+            let Ok((mesh_uniform, mesh_handle)) = scatter_query.get(entity) else {
+                continue;
+            };
+
+            let Some(mesh) = meshes.get(mesh_handle) else {
+                continue;
+            };
+
+            let key = view_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+
+            let pipeline =
+                match pipelines.specialize(&pipeline_cache, &scatter_pipeline, key, &mesh.layout) {
+                    Ok(pipeline) => pipeline,
+                    Err(error) => {
+                        error!("{error}");
+                        continue;
+                    }
+                };
+
+            opaque_phase.add(Opaque3d {
+                entity,
+                pipeline,
+                draw_function,
+                distance: rangefinder.distance(&mesh_uniform.transform),
+            });
+        }
+    }
+}
+
+pub struct DrawMeshInstanced;
+
+impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
+    type Param = SRes<RenderAssets<Mesh>>;
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = (Read<Handle<Mesh>>, Read<ScatterBuffer>);
+
+    #[inline]
+    fn render<'w>(
+        _: &P,
+        _: (),
+        (mesh_handle, scatter_buffer): (&'w Handle<Mesh>, &'w ScatterBuffer),
+        meshes: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(gpu_mesh) = meshes.into_inner().get(mesh_handle) else {
+            return RenderCommandResult::Failure;
+        };
+
+        pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
+        pass.set_vertex_buffer(1, scatter_buffer.buffer.slice(..));
+
+        match &gpu_mesh.buffer_info {
+            GpuBufferInfo::Indexed {
+                buffer,
+                index_format,
+                count,
+            } => {
+                pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+                pass.draw_indexed(0..*count, 0, 0..scatter_buffer.length);
+            }
+            GpuBufferInfo::NonIndexed { vertex_count } => {
+                pass.draw(0..*vertex_count, 0..scatter_buffer.length);
+            }
+        }
+
+        RenderCommandResult::Success
+    }
+}
+
+/// The draw function of the scattered instances.
+pub type DrawScatter = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    DrawMeshInstanced,
+);
@@ -0,0 +1,63 @@
+// Builds the hierarchical depth buffer of a view, which stores the farthest depth of each region.
+// Bevy uses a reversed depth buffer, so the farthest depth is the minimum.
+
+#ifdef MULTISAMPLED
+@group(0) @binding(0)
+var depth_texture: texture_depth_multisampled_2d;
+#else
+@group(0) @binding(0)
+var depth_texture: texture_depth_2d;
+#endif
+@group(0) @binding(1)
+var first_mip: texture_storage_2d<r32float, write>;
+
+@group(0) @binding(2)
+var previous_mip: texture_2d<f32>;
+@group(0) @binding(3)
+var next_mip: texture_storage_2d<r32float, write>;
+
+@compute @workgroup_size(8, 8, 1)
+fn copy_depth(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let coords = vec2<i32>(invocation_id.xy);
+
+    if (any(coords >= vec2<i32>(textureDimensions(first_mip)))) {
+        return;
+    }
+
+#ifdef MULTISAMPLED
+    var depth = 1.0;
+
+    for (var sample = 0; sample < i32(textureNumSamples(depth_texture)); sample = sample + 1) {
+        depth = min(depth, textureLoad(depth_texture, coords, sample));
+    }
+#else
+    let depth = textureLoad(depth_texture, coords, 0);
+#endif
+
+    textureStore(first_mip, coords, vec4<f32>(depth, 0.0, 0.0, 0.0));
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn downsample(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let coords = vec2<i32>(invocation_id.xy);
+    let size = vec2<i32>(textureDimensions(next_mip));
+
+    if (any(coords >= size)) {
+        return;
+    }
+
+    // the last row and column of odd sized mips also cover the remaining texels
+    let previous_size = vec2<i32>(textureDimensions(previous_mip));
+    let start = 2 * coords;
+    let end = min(select(start + 1, previous_size - 1, coords == size - 1), previous_size - 1);
+
+    var depth = 1.0;
+
+    for (var y = start.y; y <= end.y; y = y + 1) {
+        for (var x = start.x; x <= end.x; x = x + 1) {
+            depth = min(depth, textureLoad(previous_mip, vec2<i32>(x, y), 0).x);
+        }
+    }
+
+    textureStore(next_mip, coords, vec4<f32>(depth, 0.0, 0.0, 0.0));
+}
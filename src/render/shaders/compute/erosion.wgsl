@@ -0,0 +1,193 @@
+// The virtual pipe model of hydraulic erosion, which simulates the water as a shallow layer on top of
+// the terrain, that flows towards its lower neighbours through four virtual pipes per cell.
+// Each iteration runs the three entry points in order: 'update_flux', 'erode' and 'transport'.
+
+struct ErosionConfig {
+    size: vec2<u32>,
+    spacing: f32,
+    time_step: f32,
+    rain_rate: f32,
+    evaporation_rate: f32,
+    sediment_capacity: f32,
+    dissolving_rate: f32,
+    deposition_rate: f32,
+    gravity: f32,
+    min_tilt: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> config: ErosionConfig;
+@group(0) @binding(1)
+var<storage, read_write> terrain: array<f32>;
+@group(0) @binding(2)
+var<storage, read_write> water: array<f32>;
+@group(0) @binding(3)
+var<storage, read_write> sediment: array<f32>;
+// the sediment after its transport, which is swapped with the sediment every iteration
+@group(0) @binding(4)
+var<storage, read_write> transported: array<f32>;
+// the outflow towards the left, right, upper and lower neighbour
+@group(0) @binding(5)
+var<storage, read_write> flux: array<vec4<f32>>;
+// the velocity of the water in xy and the sine of the tilt angle in z
+@group(0) @binding(6)
+var<storage, read_write> velocity: array<vec4<f32>>;
+
+fn inside(cell: vec2<i32>) -> bool {
+    return all(cell >= vec2<i32>(0)) && all(cell < vec2<i32>(config.size));
+}
+
+// The index of the cell, where the cells outside of the grid are clamped to its border.
+fn cell_index(cell: vec2<i32>) -> u32 {
+    let clamped = clamp(cell, vec2<i32>(0), vec2<i32>(config.size) - vec2<i32>(1));
+
+    return u32(clamped.y) * config.size.x + u32(clamped.x);
+}
+
+fn surface_height(cell: vec2<i32>) -> f32 {
+    let index = cell_index(cell);
+
+    return terrain[index] + water[index];
+}
+
+// Accelerates the outflow towards the neighbour by the difference of their water surfaces.
+fn outflow(current: f32, height: f32, neighbour: vec2<i32>) -> f32 {
+    if (!inside(neighbour)) {
+        return 0.0;
+    }
+
+    let difference = height - surface_height(neighbour);
+
+    return max(current + config.time_step * config.gravity * difference / config.spacing, 0.0);
+}
+
+// The flux of the neighbour into the cell, where 'direction' selects the pipe pointing towards the cell.
+fn inflow(neighbour: vec2<i32>, direction: u32) -> f32 {
+    if (!inside(neighbour)) {
+        return 0.0;
+    }
+
+    return flux[cell_index(neighbour)][direction];
+}
+
+fn sample_sediment(position: vec2<f32>) -> f32 {
+    let cell = vec2<i32>(floor(position));
+    let t = fract(position);
+
+    let top = mix(sediment[cell_index(cell)], sediment[cell_index(cell + vec2<i32>(1, 0))], t.x);
+    let bottom = mix(sediment[cell_index(cell + vec2<i32>(0, 1))], sediment[cell_index(cell + vec2<i32>(1, 1))], t.x);
+
+    return mix(top, bottom, t.y);
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn update_flux(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    if (any(invocation_id.xy >= config.size)) {
+        return;
+    }
+
+    let cell = vec2<i32>(invocation_id.xy);
+    let index = cell_index(cell);
+    let height = terrain[index] + water[index];
+    let current = flux[index];
+
+    var new_flux = vec4<f32>(
+        outflow(current.x, height, cell + vec2<i32>(-1,  0)),
+        outflow(current.y, height, cell + vec2<i32>( 1,  0)),
+        outflow(current.z, height, cell + vec2<i32>( 0, -1)),
+        outflow(current.w, height, cell + vec2<i32>( 0,  1)),
+    );
+
+    // the outflow is limited to the water available in the cell
+    let total = new_flux.x + new_flux.y + new_flux.z + new_flux.w;
+
+    if (total > 0.0) {
+        new_flux = new_flux * min(water[index] / (total * config.time_step), 1.0);
+    }
+
+    flux[index] = new_flux;
+
+    let left  = terrain[cell_index(cell + vec2<i32>(-1,  0))];
+    let right = terrain[cell_index(cell + vec2<i32>( 1,  0))];
+    let up    = terrain[cell_index(cell + vec2<i32>( 0, -1))];
+    let down  = terrain[cell_index(cell + vec2<i32>( 0,  1))];
+
+    let gradient = vec2<f32>(right - left, down - up) / (2.0 * config.spacing);
+    let slope = dot(gradient, gradient);
+
+    velocity[index].z = sqrt(slope / (1.0 + slope));
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn erode(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    if (any(invocation_id.xy >= config.size)) {
+        return;
+    }
+
+    let cell = vec2<i32>(invocation_id.xy);
+    let index = cell_index(cell);
+    let cell_flux = flux[index];
+
+    let from_left  = inflow(cell + vec2<i32>(-1,  0), 1u);
+    let from_right = inflow(cell + vec2<i32>( 1,  0), 0u);
+    let from_up    = inflow(cell + vec2<i32>( 0, -1), 3u);
+    let from_down  = inflow(cell + vec2<i32>( 0,  1), 2u);
+
+    let total_inflow = from_left + from_right + from_up + from_down;
+    let total_outflow = cell_flux.x + cell_flux.y + cell_flux.z + cell_flux.w;
+
+    let old_water = water[index];
+    let new_water = max(old_water + config.time_step * (total_inflow - total_outflow), 0.0);
+    let mean_water = 0.5 * (old_water + new_water);
+
+    // the velocity is derived from the water passing through the cell
+    let passing = 0.5 * vec2<f32>(
+        from_left - cell_flux.x + cell_flux.y - from_right,
+        from_up - cell_flux.z + cell_flux.w - from_down,
+    );
+
+    var water_velocity = vec2<f32>(0.0);
+
+    if (mean_water > 0.0001) {
+        water_velocity = passing * config.spacing / mean_water;
+    }
+
+    let tilt = max(velocity[index].z, config.min_tilt);
+    let capacity = config.sediment_capacity * tilt * length(water_velocity);
+
+    var height = terrain[index];
+    var suspended = sediment[index];
+
+    if (capacity > suspended) {
+        let amount = config.dissolving_rate * (capacity - suspended) * config.time_step;
+        height = height - amount;
+        suspended = suspended + amount;
+    } else {
+        let amount = config.deposition_rate * (suspended - capacity) * config.time_step;
+        height = height + amount;
+        suspended = suspended - amount;
+    }
+
+    terrain[index] = max(height, 0.0);
+    sediment[index] = suspended;
+    water[index] = new_water;
+    velocity[index] = vec4<f32>(water_velocity, tilt, 0.0);
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn transport(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    if (any(invocation_id.xy >= config.size)) {
+        return;
+    }
+
+    let cell = vec2<i32>(invocation_id.xy);
+    let index = cell_index(cell);
+
+    // the sediment is advected backwards along the velocity of the water
+    let source = vec2<f32>(cell) - velocity[index].xy * config.time_step / config.spacing;
+    transported[index] = sample_sediment(source);
+
+    // the rain falls after the water has flown, so that the neighbours never see a partial update
+    let evaporated = water[index] * max(1.0 - config.evaporation_rate * config.time_step, 0.0);
+    water[index] = evaporated + config.rain_rate * config.time_step;
+}
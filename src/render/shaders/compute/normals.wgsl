@@ -0,0 +1,57 @@
+struct NormalConfig {
+    mip_level: u32,
+    texture_size: u32,
+    height: f32,
+}
+
+// The region of a node, whose heights have been edited (measured in pixels of the first mip level).
+struct DirtyRegion {
+    origin: vec2<u32>,
+    size: vec2<u32>,
+    atlas_index: u32,
+    lod: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> normal_config: NormalConfig;
+@group(0) @binding(1)
+var<storage> regions: array<DirtyRegion>;
+@group(0) @binding(2)
+var height_atlas: texture_2d_array<f32>;
+@group(0) @binding(3)
+var normal_atlas: texture_storage_2d_array<rg16unorm, write>;
+
+// Loads the height of the pixel, where the pixels outside of the node are clamped to its border.
+fn load_height(pixel: vec2<i32>, atlas_index: i32) -> f32 {
+    let clamped = clamp(pixel, vec2<i32>(0), vec2<i32>(i32(normal_config.texture_size) - 1));
+
+    return textureLoad(height_atlas, clamped, atlas_index, i32(normal_config.mip_level)).x * normal_config.height;
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn recompute_normals(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let region = regions[invocation_id.z];
+
+    let first = region.origin >> vec2<u32>(normal_config.mip_level);
+    let last = (region.origin + region.size - 1u) >> vec2<u32>(normal_config.mip_level);
+    let pixel = first + invocation_id.xy;
+
+    if (any(pixel > last)) {
+        return;
+    }
+
+    let coords = vec2<i32>(pixel);
+    let atlas_index = i32(region.atlas_index);
+
+    let left  = load_height(coords + vec2<i32>(-1,  0), atlas_index);
+    let up    = load_height(coords + vec2<i32>( 0, -1), atlas_index);
+    let right = load_height(coords + vec2<i32>( 1,  0), atlas_index);
+    let down  = load_height(coords + vec2<i32>( 0,  1), atlas_index);
+
+    // the distance between the neighbouring heights (in pixels of the highest lod),
+    // which matches the normals baked during preprocessing and derived by the shader
+    let distance = f32(2u << (region.lod + normal_config.mip_level));
+    let normal = normalize(vec3<f32>(right - left, distance, down - up));
+
+    textureStore(normal_atlas, coords, atlas_index, vec4<f32>(0.5 * normal.xz + 0.5, 0.0, 1.0));
+}
@@ -11,14 +11,20 @@ struct TerrainConfig {
     minmax_size: f32,
     _empty: u32,
     _empty: u32,
+    _empty: vec4<f32>,
     height_scale: f32,
     minmax_scale: f32,
     _empty: u32,
     _empty: u32,
+    _empty: vec4<f32>,
     height_offset: f32,
     minmax_offset: f32,
     _empty: u32,
     _empty: u32,
+    _empty: vec4<f32>,
+
+    terrain_extent: vec2<f32>,
+    planet_radius: f32,
 }
 
 struct CullingData {
@@ -26,6 +32,10 @@ struct CullingData {
     view_proj: mat4x4<f32>,
     model: mat4x4<f32>,
     planes: array<vec4<f32>, 5>,
+    occlusion_view_proj: mat4x4<f32>,
+    occlusion_size: vec2<f32>,
+    occlusion_mip_count: u32,
+    occlusion_culling: u32,
 }
 
 @group(0) @binding(0)
@@ -41,6 +51,10 @@ var<storage, read_write> parameters: Parameters;
 
 @group(1) @binding(0)
 var<uniform> view: CullingData;
+@group(1) @binding(1)
+var<storage> node_bounds: array<vec2<f32>>;
+@group(1) @binding(2)
+var depth_pyramid: texture_2d<f32>;
 
  // terrain bindings
 @group(2) @binding(0)
@@ -67,18 +81,8 @@ fn final_index() -> i32 {
     return atomicAdd(&parameters.final_index, 1);
 }
 
-fn frustum_cull(tile: Tile) -> bool {
-    let size = f32(tile.size) * view_config.tile_scale;
-    let local_position = (vec2<f32>(tile.coords) + 0.5) * size;
-
-    let minmax = vec2<f32>(0.0, config.height); // 2D frustum culling
-    // Todo: enable this
-    // let minmax = minmax(local_position, size); // 3D frustum culling
-
+fn frustum_cull_aabb(aabb_min: vec3<f32>, aabb_max: vec3<f32>) -> bool {
     // frustum culling optimized
-    let aabb_min = vec3<f32>(local_position.x - size / 2.0, minmax.x, local_position.y - size / 2.0);
-    let aabb_max = vec3<f32>(local_position.x + size / 2.0, minmax.y, local_position.y + size / 2.0);
-
     for (var i = 0; i < 5; i = i + 1) {
         let plane = view.planes[i];
 
@@ -101,15 +105,117 @@ fn frustum_cull(tile: Tile) -> bool {
     return false;
 }
 
+fn tile_bounds(local_position: vec2<f32>, size: f32) -> vec2<f32> {
+    // the height bounds of the node, which contains the entire tile and whose bounds are tightly
+    // fitted around its heights, fall back to the entire height of the terrain
+    let fallback = vec2<f32>(0.0, config.height);
+
+    // the smallest lod, whose nodes contain the entire tile
+    let lod = u32(max(ceil(log2(size / f32(config.leaf_node_size))), 0.0));
+
+    if (lod >= config.lod_count) {
+        return fallback;
+    }
+
+    // the quadtree only covers the nodes around the viewer (with a margin for its traversal threshold)
+    let coordinate = floor(local_position / node_size(lod));
+    let grid_coordinate = floor(view_config.view_position.xz / node_size(lod) + 0.5 - f32(view_config.node_count >> 1u));
+
+    if (any(coordinate < grid_coordinate + 1.0) || any(coordinate >= grid_coordinate + f32(view_config.node_count) - 1.0)) {
+        return fallback;
+    }
+
+    let lookup = lookup_node(lod, local_position);
+    let atlas_index = u32(lookup.atlas_index);
+
+    if (atlas_index >= arrayLength(&node_bounds)) {
+        return fallback;
+    }
+
+    return node_bounds[atlas_index];
+}
+
+fn frustum_cull(tile: Tile) -> bool {
+    // cull tiles, whose bounding boxes are outside of the view frustum
+    let size = f32(tile.size) * view_config.tile_scale;
+    let local_position = (vec2<f32>(tile.coords) + 0.5) * size;
+    let bounds = tile_bounds(local_position, size);
+
+    let aabb_min = vec3<f32>(local_position.x - size / 2.0, bounds.x, local_position.y - size / 2.0);
+    let aabb_max = vec3<f32>(local_position.x + size / 2.0, bounds.y, local_position.y + size / 2.0);
+
+    return frustum_cull_aabb(aabb_min, aabb_max);
+}
+
 fn outside_cull(tile: Tile) -> bool {
     // cull tiles outside of the terrain
     let local_position = vec2<f32>(tile.coords * tile.size) * view_config.tile_scale ;
 
-    return local_position.x > f32(config.terrain_size) || local_position.y > f32(config.terrain_size);
+    return local_position.x > config.terrain_extent.x || local_position.y > config.terrain_extent.y;
+}
+
+fn occlusion_cull(tile: Tile) -> bool {
+    // cull tiles, that are hidden behind the depth of the previous frame
+    if (view.occlusion_culling == 0u) {
+        return false;
+    }
+
+    let size = f32(tile.size) * view_config.tile_scale;
+    let local_position = (vec2<f32>(tile.coords) + 0.5) * size;
+    let minmax = minmax(local_position, size);
+
+    var ndc_min = vec2<f32>(1.0);
+    var ndc_max = vec2<f32>(-1.0);
+    var nearest_depth = 0.0;
+
+    for (var i = 0u; i < 8u; i = i + 1u) {
+        let corner = vec3<f32>(local_position.x + size * (f32(i & 1u) - 0.5),
+                               select(minmax.x, minmax.y, (i & 2u) != 0u),
+                               local_position.y + size * (f32((i >> 2u) & 1u) - 0.5));
+        let clip_position = view.occlusion_view_proj * vec4<f32>(corner, 1.0);
+
+        // the bounding box intersects the near plane
+        if (clip_position.w <= 0.0) {
+            return false;
+        }
+
+        let ndc_position = clip_position.xyz / clip_position.w;
+        ndc_min = min(ndc_min, ndc_position.xy);
+        ndc_max = max(ndc_max, ndc_position.xy);
+        // the depth is reversed
+        nearest_depth = max(nearest_depth, ndc_position.z);
+    }
+
+    let uv_min = clamp(vec2<f32>(ndc_min.x, -ndc_max.y) * 0.5 + 0.5, vec2<f32>(0.0), vec2<f32>(1.0));
+    let uv_max = clamp(vec2<f32>(ndc_max.x, -ndc_min.y) * 0.5 + 0.5, vec2<f32>(0.0), vec2<f32>(1.0));
+
+    // tiles outside of the screen are left to the frustum culling
+    if (any(uv_min >= uv_max)) {
+        return false;
+    }
+
+    // the mip level, at which the bounds of the tile cover about two by two texels
+    let extent = (uv_max - uv_min) * view.occlusion_size;
+    let mip_level = min(u32(ceil(log2(max(max(extent.x, extent.y), 1.0)))), view.occlusion_mip_count - 1u);
+    let mip_size = vec2<i32>(textureDimensions(depth_pyramid, i32(mip_level)));
+
+    let texel_min = min(vec2<i32>(uv_min * vec2<f32>(mip_size)), mip_size - 1);
+    let texel_max = min(vec2<i32>(uv_max * vec2<f32>(mip_size)), mip_size - 1);
+
+    var farthest_depth = 1.0;
+
+    for (var y = texel_min.y; y <= texel_max.y; y = y + 1) {
+        for (var x = texel_min.x; x <= texel_max.x; x = x + 1) {
+            farthest_depth = min(farthest_depth, textureLoad(depth_pyramid, vec2<i32>(x, y), i32(mip_level)).x);
+        }
+    }
+
+    return nearest_depth < farthest_depth;
 }
 
 fn cull(tile: Tile) -> bool {
-    return outside_cull(tile) || frustum_cull(tile);
+    // the bounding boxes of the tiles do not account for the curvature of planets
+    return outside_cull(tile) || (config.planet_radius == 0.0 && (frustum_cull(tile) || occlusion_cull(tile)));
 }
 
 fn should_be_divided(tile: Tile) -> bool {
@@ -125,10 +231,10 @@ fn should_be_divided(tile: Tile) -> bool {
 
         let local_position = vec2<f32>(corner_coords * tile.size) * view_config.tile_scale;
         let world_position = approximate_world_position(local_position);
-        dist = min(dist, distance(world_position.xyz, view.world_position.xyz));
+        dist = min(dist, distance(world_position.xyz, view_config.view_position.xyz));
     }
 
-    return dist < view_config.morph_distance * f32(tile.size);
+    return refinement_distance(tile, dist) < view_config.morph_distance * f32(tile.size);
 }
 
 fn subdivide(tile: Tile) {
@@ -161,3 +267,47 @@ fn refine_tiles(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
         final_tiles.data[final_index()] = tile;
     }
 }
+
+// Selects the tiles of the geometry clipmap, which consists of concentric rings of tiles around
+// the viewer. The tiles double in size with each ring and each ring leaves out the area
+// covered by the previous one. The rings are snapped to the grid of the next larger tiles,
+// so that they line up with each other.
+@compute @workgroup_size(64, 1, 1)
+fn build_clipmap(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let ring_size = i32(view_config.clipmap_ring_size);
+    let ring_tiles = 2u * view_config.clipmap_ring_size;
+    let ring = invocation_id.x / (ring_tiles * ring_tiles);
+
+    if (ring >= view_config.clipmap_ring_count) {
+        return;
+    }
+
+    let index = invocation_id.x % (ring_tiles * ring_tiles);
+    let offset = vec2<i32>(vec2<u32>(index % ring_tiles, index / ring_tiles)) - ring_size;
+
+    let size = 1u << ring;
+    let view_coords = view_config.view_position.xz / view_config.tile_scale;
+    let center = 2 * vec2<i32>(round(view_coords / f32(2u * size)));
+    let coords = center + offset;
+
+    if (ring > 0u) {
+        // the center of the previous ring, measured in its smaller tiles
+        let inner_center = 2 * vec2<i32>(round(view_coords / f32(size)));
+        let inner_first = 2 * coords;
+        let inner_last = 2 * coords + 2;
+
+        if (all(inner_first >= inner_center - ring_size) && all(inner_last <= inner_center + ring_size)) {
+            return;
+        }
+    }
+
+    if (any(coords < vec2<i32>(0))) {
+        return;
+    }
+
+    let tile = Tile(vec2<u32>(coords), size);
+
+    if (!cull(tile)) {
+        final_tiles.data[final_index()] = tile;
+    }
+}
@@ -23,7 +23,27 @@ fn lod_color(lod: u32) -> vec4<f32> {
     return vec4<f32>(0.0);
 }
 
-fn show_tiles(tile: Tile, world_position: vec4<f32>) -> vec4<f32> {
+// Maps the value (from zero to one) onto a ramp from blue over green to red.
+fn heatmap(value: f32) -> vec4<f32> {
+    let t = clamp(value, 0.0, 1.0);
+
+    let red   = smoothstep(0.5, 1.0, t);
+    let green = 1.0 - abs(2.0 * t - 1.0);
+    let blue  = 1.0 - smoothstep(0.0, 0.5, t);
+
+    return vec4<f32>(red, green, blue, 1.0);
+}
+
+// Returns a pseudo random color for the index.
+fn index_color(index: u32) -> vec4<f32> {
+    var state = index * 747796405u + 2891336453u;
+    state = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    state = (state >> 22u) ^ state;
+
+    return vec4<f32>(f32(state & 255u), f32((state >> 8u) & 255u), f32((state >> 16u) & 255u), 255.0) / 255.0;
+}
+
+fn show_tiles(tile: Tile, terrain_position: vec4<f32>) -> vec4<f32> {
     var color: vec4<f32>;
 
     if ((tile.coords.x + tile.coords.y) % 2u == 0u) {
@@ -37,7 +57,7 @@ fn show_tiles(tile: Tile, world_position: vec4<f32>) -> vec4<f32> {
     color = mix(color, lod_color(lod), 0.5);
 
 #ifdef MESH_MORPH
-    let morph = calculate_morph(tile, world_position);
+    let morph = calculate_morph(tile, terrain_position);
     color = color + vec4<f32>(1.0, 1.0, 1.0, 1.0) * morph;
 #endif
 
@@ -64,11 +84,11 @@ fn show_minmax_error(tile: Tile, height: f32) -> vec4<f32> {
     return color;
 }
 
-fn show_lod(lod: u32, world_position: vec3<f32>) -> vec4<f32> {
+fn show_lod(lod: u32, terrain_position: vec3<f32>) -> vec4<f32> {
     var color = lod_color(lod);
 
     for (var i = 0u; i < config.lod_count; i = i + 1u) {
-        let viewer_distance = distance(view.world_position.xyz, world_position);
+        let viewer_distance = distance(view_config.view_position.xyz, terrain_position);
         let circle = f32(1u << i) * view_config.blend_distance;
 
         if (viewer_distance < circle && circle - f32(8 << i) < viewer_distance) {
@@ -77,12 +97,12 @@ fn show_lod(lod: u32, world_position: vec3<f32>) -> vec4<f32> {
 
 #ifdef SHOW_NODES
         let node_size = node_size(i);
-        let grid_position = floor(view.world_position.xz / node_size + 0.5 - f32(view_config.node_count >> 1u)) * node_size;
+        let grid_position = floor(view_config.view_position.xz / node_size + 0.5 - f32(view_config.node_count >> 1u)) * node_size;
         let grid_size = node_size * f32(view_config.node_count);
         let thickness = f32(8u << i);
 
-        let grid_outer = step(grid_position, world_position.xz) * step(world_position.xz, grid_position + grid_size);
-        let grid_inner = step(grid_position + thickness, world_position.xz) * step(world_position.xz, grid_position + grid_size - thickness);
+        let grid_outer = step(grid_position, terrain_position.xz) * step(terrain_position.xz, grid_position + grid_size);
+        let grid_inner = step(grid_position + thickness, terrain_position.xz) * step(terrain_position.xz, grid_position + grid_size - thickness);
         let outline = grid_outer.x * grid_outer.y - grid_inner.x * grid_inner.y;
 
         color = mix(color, lod_color(i) * 10.0, outline);
@@ -91,3 +111,31 @@ fn show_lod(lod: u32, world_position: vec3<f32>) -> vec4<f32> {
 
     return color;
 }
+
+fn show_lod_heatmap(lod: u32) -> vec4<f32> {
+    return heatmap(f32(lod) / f32(max(config.lod_count, 2u) - 1u));
+}
+
+// Alternates the brightness of neighbouring nodes, which are colored by their atlas index.
+fn show_node_checkers(lookup: NodeLookup, local_position: vec2<f32>) -> vec4<f32> {
+    let coordinate = vec2<u32>(local_position / node_size(lookup.atlas_lod));
+    var color = index_color(u32(lookup.atlas_index));
+
+    if (((coordinate.x + coordinate.y) & 1u) == 0u) {
+        color = color * 0.5;
+    }
+
+    return vec4<f32>(color.xyz, 1.0);
+}
+
+// Visualizes the amount of node texels per pixel, where blue is magnified (blurry),
+// green matches the resolution of the screen and red is minified (aliasing).
+fn show_uv_density(lod: u32, ddx: vec2<f32>, ddy: vec2<f32>) -> vec4<f32> {
+    let texels_per_pixel = max(length(ddx), length(ddy)) / f32(1u << lod);
+
+    return heatmap(0.5 + 0.25 * log2(texels_per_pixel));
+}
+
+fn show_morph(morph: f32) -> vec4<f32> {
+    return heatmap(morph);
+}
@@ -5,21 +5,45 @@ struct VertexInput {
     @builtin(vertex_index)   vertex_index: u32,
 }
 
+// The outputs of the terrain vertices, which are interpolated into the `FragmentInput`.
+// local_position:   the position inside of the terrain (x and z in terrain space)
+// world_position:   the position in world space
+// terrain_position: the position in the space of the terrain transform
+// morph:            the blend (from zero to one) towards the grid of the next coarser lod
+// splat_weights:    the splat weights at the vertex (see `vertex_splat_weights`)
 struct VertexOutput {
     @builtin(position)       frag_coord: vec4<f32>,
     @location(0)             local_position: vec2<f32>,
     @location(1)             world_position: vec4<f32>,
     @location(2)             debug_color: vec4<f32>,
+    @location(3)             terrain_position: vec4<f32>,
+    @location(4)             morph: f32,
+    @location(5)             splat_weights: vec4<f32>,
 }
 
 fn vertex_output(local_position: vec2<f32>, height: f32) -> VertexOutput {
-    var world_position = vec4<f32>(local_position.x, height, local_position.y, 1.0);
+    var terrain_position = vec4<f32>(surface_position(local_position, height), 1.0);
+    var world_position = view_config.model * terrain_position;
+
+    // the position relative to the camera stays precise far away from the origin
+    let relative_position = (view_config.camera_model * terrain_position).xyz;
+    let view_rotation = mat3x3<f32>(view.inverse_view[0].xyz, view.inverse_view[1].xyz, view.inverse_view[2].xyz);
 
     var output: VertexOutput;
+#ifdef SHADOW
+    // the cascades of the lights are projected from world space
     output.frag_coord = view.view_proj * world_position;
+    // the casters in front of the near plane of the cascade are clamped onto it, so that they still cast shadows
+    output.frag_coord.z = min(output.frag_coord.z, 1.0);
+#else
+    output.frag_coord = view.projection * vec4<f32>(view_rotation * relative_position, 1.0);
+#endif
     output.local_position = vec2<f32>(local_position);
     output.world_position = world_position;
     output.debug_color = vec4<f32>(0.0);
+    output.terrain_position = terrain_position;
+    output.morph = 0.0;
+    output.splat_weights = vec4<f32>(0.0);
 
     return output;
 }
@@ -30,6 +54,9 @@ struct FragmentInput {
     @location(0)             local_position: vec2<f32>,
     @location(1)             world_position: vec4<f32>,
     @location(2)             debug_color: vec4<f32>,
+    @location(3)             terrain_position: vec4<f32>,
+    @location(4)             morph: f32,
+    @location(5)             splat_weights: vec4<f32>,
 }
 
 struct FragmentOutput {
@@ -47,19 +74,42 @@ struct Blend {
     ratio: f32,
 }
 
-fn calculate_blend(world_position: vec4<f32>) -> Blend {
-    let viewer_distance = distance(world_position.xyz, view.world_position.xyz);
+// Calculates the lod blend based on a position in the local space of the terrain.
+fn calculate_blend(terrain_position: vec4<f32>) -> Blend {
+    let viewer_distance = distance(terrain_position.xyz, view_config.view_position.xyz);
     let log_distance = max(log2(2.0 * viewer_distance / view_config.blend_distance), 0.0);
     let ratio = (1.0 - log_distance % 1.0) / view_config.blend_range;
 
     return Blend(u32(log_distance), ratio);
 }
 
-fn calculate_morph(tile: Tile, world_position: vec4<f32>) -> f32 {
-    let viewer_distance = distance(world_position.xyz, view.world_position.xyz);
+// Returns the morph range of the lod of the tile.
+fn tile_morph_range(tile: Tile) -> f32 {
+    let lod = min(u32(round(log2(f32(tile.size)))), 15u);
+
+    return view_config.morph_ranges[lod >> 2u][lod & 3u];
+}
+
+// Scales down the distance of rough tiles to the viewer, so that they are refined further.
+// The roughness is the ratio of the height variation of the tile to its size.
+fn refinement_distance(tile: Tile, viewer_distance: f32) -> f32 {
+    if (view_config.adaptive_refinement == 0.0) {
+        return viewer_distance;
+    }
+
+    let size = f32(tile.size) * view_config.tile_scale;
+    let bounds = minmax((vec2<f32>(tile.coords) + 0.5) * size, size);
+    let roughness = (bounds.y - bounds.x) / size;
+
+    return viewer_distance / (1.0 + view_config.adaptive_refinement * roughness);
+}
+
+// Calculates the mesh morph based on a position in the local space of the terrain.
+fn calculate_morph(tile: Tile, terrain_position: vec4<f32>) -> f32 {
+    let viewer_distance = refinement_distance(tile, distance(terrain_position.xyz, view_config.view_position.xyz));
     let morph_distance = view_config.morph_distance * f32(tile.size << 1u);
 
-    return clamp(1.0 - (1.0 - viewer_distance / morph_distance) / view_config.morph_range, 0.0, 1.0);
+    return clamp(1.0 - (1.0 - viewer_distance / morph_distance) / tile_morph_range(tile), 0.0, 1.0);
 }
 
 fn calculate_grid_position(grid_index: u32) -> vec2<u32>{
@@ -70,40 +120,190 @@ fn calculate_grid_position(grid_index: u32) -> vec2<u32>{
     return vec2<u32>(column_index + (row_index & 1u), row_index >> 1u);
 }
 
+// Whether the vertex belongs to the skirt, which surrounds the grid of the tile.
+fn is_skirt(grid_position: vec2<u32>) -> bool {
+    let last = u32(view_config.grid_size) + 2u;
+
+    return view_config.seam_mode == 1u && (any(grid_position == vec2<u32>(0u)) || any(grid_position == vec2<u32>(last)));
+}
+
+// Calculates the amount by which the vertex is lowered, to form the skirt of the tile.
+fn calculate_skirt_offset(tile: Tile, grid_position: vec2<u32>) -> f32 {
+    if (!is_skirt(grid_position)) {
+        return 0.0;
+    }
+
+    return view_config.skirt_depth * f32(tile.size) * view_config.tile_scale;
+}
+
 fn calculate_local_position(tile: Tile, grid_position: vec2<u32>) -> vec2<f32> {
     let size = f32(tile.size) * view_config.tile_scale;
 
-    var local_position = (vec2<f32>(tile.coords) + vec2<f32>(grid_position) / view_config.grid_size) * size;
+    // the skirt duplicates the outermost vertices of the grid
+    var tile_position = grid_position;
+
+    if (view_config.seam_mode == 1u) {
+        tile_position = vec2<u32>(clamp(vec2<i32>(grid_position) - 1, vec2<i32>(0), vec2<i32>(i32(view_config.grid_size))));
+    }
+
+    var local_position = (vec2<f32>(tile.coords) + vec2<f32>(tile_position) / view_config.grid_size) * size;
+    var morph = 0.0;
 
 #ifdef MESH_MORPH
     let world_position = approximate_world_position(local_position);
-    let morph = calculate_morph(tile, world_position);
-    let even_grid_position = vec2<f32>(grid_position & vec2<u32>(1u));
-    local_position = local_position - morph * even_grid_position / view_config.grid_size * size;
+    morph = calculate_morph(tile, world_position);
 #endif
 
-    local_position.x = clamp(local_position.x, 0.0, f32(config.terrain_size));
-    local_position.y = clamp(local_position.y, 0.0, f32(config.terrain_size));
+    // the stitches snap the vertices on the edges onto the grid of the next coarser lod
+    let last = u32(view_config.grid_size);
+
+    if (view_config.seam_mode == 2u && (any(tile_position == vec2<u32>(0u)) || any(tile_position == vec2<u32>(last)))) {
+        morph = 1.0;
+    }
+
+    let even_tile_position = vec2<f32>(tile_position & vec2<u32>(1u));
+    local_position = local_position - morph * even_tile_position / view_config.grid_size * size;
+
+    local_position = clamp(local_position, vec2<f32>(0.0), config.terrain_extent);
 
     return local_position;
 }
 
-fn calculate_normal(coords: vec2<f32>, atlas_index: i32, atlas_lod: u32, ddx: vec2<f32>, ddy: vec2<f32>) -> vec3<f32> {
+#ifdef ATLAS_SHARDS
+// The further shards of the height and minmax attachments of a sharded node atlas.
+@group(2) @binding(#{ATTACHMENT_0_SHARD_1_BINDING})
+var height_atlas_1: texture_2d_array<f32>;
+#ifdef ATLAS_SHARD_2
+@group(2) @binding(#{ATTACHMENT_0_SHARD_2_BINDING})
+var height_atlas_2: texture_2d_array<f32>;
+#endif
+#ifdef ATLAS_SHARD_3
+@group(2) @binding(#{ATTACHMENT_0_SHARD_3_BINDING})
+var height_atlas_3: texture_2d_array<f32>;
+#endif
+@group(2) @binding(#{ATTACHMENT_1_SHARD_1_BINDING})
+var minmax_atlas_1: texture_2d_array<f32>;
+#ifdef ATLAS_SHARD_2
+@group(2) @binding(#{ATTACHMENT_1_SHARD_2_BINDING})
+var minmax_atlas_2: texture_2d_array<f32>;
+#endif
+#ifdef ATLAS_SHARD_3
+@group(2) @binding(#{ATTACHMENT_1_SHARD_3_BINDING})
+var minmax_atlas_3: texture_2d_array<f32>;
+#endif
+#endif
+
+// Returns the shard of the node atlas, which stores the atlas index.
+fn atlas_shard(atlas_index: i32) -> i32 {
+#ifdef ATLAS_SHARDS
+    return atlas_index / i32(#{ATLAS_SHARD_SIZE});
+#else
+    return 0;
+#endif
+}
+
+// Returns the layer of the atlas index inside of its shard.
+fn atlas_layer(atlas_index: i32) -> i32 {
+#ifdef ATLAS_SHARDS
+    return atlas_index % i32(#{ATLAS_SHARD_SIZE});
+#else
+    return atlas_index;
+#endif
+}
+
+// Samples the layer of the attachment, zero gradients sample the first mip level.
+fn sample_attachment(attachment: texture_2d_array<f32>, coords: vec2<f32>, layer: i32, ddx: vec2<f32>, ddy: vec2<f32>) -> vec4<f32> {
 #ifdef SAMPLE_GRAD
-    let offset = 1.0 / config.height_size;
-    let left  = textureSampleGrad(height_atlas, atlas_sampler, coords + vec2<f32>(-offset,     0.0), atlas_index, ddx, ddy).x;
-    let up    = textureSampleGrad(height_atlas, atlas_sampler, coords + vec2<f32>(    0.0, -offset), atlas_index, ddx, ddy).x;
-    let right = textureSampleGrad(height_atlas, atlas_sampler, coords + vec2<f32>( offset,     0.0), atlas_index, ddx, ddy).x;
-    let down  = textureSampleGrad(height_atlas, atlas_sampler, coords + vec2<f32>(    0.0,  offset), atlas_index, ddx, ddy).x;
+    return textureSampleGrad(attachment, atlas_sampler, coords, layer, ddx, ddy);
 #else
-    let left  = textureSampleLevel(height_atlas, atlas_sampler, coords, atlas_index, 0.0, vec2<i32>(-1,  0)).x;
-    let up    = textureSampleLevel(height_atlas, atlas_sampler, coords, atlas_index, 0.0, vec2<i32>( 0, -1)).x;
-    let right = textureSampleLevel(height_atlas, atlas_sampler, coords, atlas_index, 0.0, vec2<i32>( 1,  0)).x;
-    let down  = textureSampleLevel(height_atlas, atlas_sampler, coords, atlas_index, 0.0, vec2<i32>( 0,  1)).x;
+    return textureSampleLevel(attachment, atlas_sampler, coords, layer, 0.0);
+#endif
+}
 
+#ifdef ATLAS_SHARDS
+// Samples the attachment of a sharded node atlas from the shard, which stores the atlas index.
+// Materials call this with the shards of their attachments, the third and fourth shard are
+// only bound, if ATLAS_SHARD_2 and ATLAS_SHARD_3 are defined.
+fn sample_sharded(
+    shard_0: texture_2d_array<f32>,
+    shard_1: texture_2d_array<f32>,
+#ifdef ATLAS_SHARD_2
+    shard_2: texture_2d_array<f32>,
+#endif
+#ifdef ATLAS_SHARD_3
+    shard_3: texture_2d_array<f32>,
+#endif
+    coords: vec2<f32>, atlas_index: i32, ddx: vec2<f32>, ddy: vec2<f32>) -> vec4<f32> {
+    let layer = atlas_layer(atlas_index);
+    var value: vec4<f32>;
+
+    switch (atlas_shard(atlas_index)) {
+        case 1: { value = sample_attachment(shard_1, coords, layer, ddx, ddy); }
+#ifdef ATLAS_SHARD_2
+        case 2: { value = sample_attachment(shard_2, coords, layer, ddx, ddy); }
+#endif
+#ifdef ATLAS_SHARD_3
+        case 3: { value = sample_attachment(shard_3, coords, layer, ddx, ddy); }
+#endif
+        default: { value = sample_attachment(shard_0, coords, layer, ddx, ddy); }
+    }
+
+    return value;
+}
+#endif
+
+// Samples the height attachment at the atlas index.
+fn sample_height(coords: vec2<f32>, atlas_index: i32, ddx: vec2<f32>, ddy: vec2<f32>) -> f32 {
+#ifdef ATLAS_SHARDS
+    return sample_sharded(
+        height_atlas,
+        height_atlas_1,
+#ifdef ATLAS_SHARD_2
+        height_atlas_2,
+#endif
+#ifdef ATLAS_SHARD_3
+        height_atlas_3,
+#endif
+        coords, atlas_index, ddx, ddy).x;
+#else
+    return sample_attachment(height_atlas, coords, atlas_index, ddx, ddy).x;
 #endif
+}
+
+fn calculate_normal(coords: vec2<f32>, atlas_index: i32, atlas_lod: u32, ddx: vec2<f32>, ddy: vec2<f32>) -> vec3<f32> {
+    let offset = 1.0 / config.height_size;
+    let left  = sample_height(coords + vec2<f32>(-offset,     0.0), atlas_index, ddx, ddy);
+    let up    = sample_height(coords + vec2<f32>(    0.0, -offset), atlas_index, ddx, ddy);
+    let right = sample_height(coords + vec2<f32>( offset,     0.0), atlas_index, ddx, ddy);
+    let down  = sample_height(coords + vec2<f32>(    0.0,  offset), atlas_index, ddx, ddy);
+
+    let local_normal = normalize(vec3<f32>(right - left, f32(2u << atlas_lod) / config.height, down - up));
 
-    return normalize(vec3<f32>(right - left, f32(2u << atlas_lod) / config.height, down - up));
+    // Todo: use the inverse transpose for non-uniformly scaled terrains
+    return normalize((view_config.model * vec4<f32>(local_normal, 0.0)).xyz);
+}
+
+// Bends the normal of the flat terrain onto the curved surface, for the faces of planets.
+fn planet_normal(local_position: vec2<f32>, world_normal: vec3<f32>) -> vec3<f32> {
+    if (config.planet_radius == 0.0) {
+        return world_normal;
+    }
+
+    let up = normalize((view_config.model * vec4<f32>(0.0, 1.0, 0.0, 0.0)).xyz);
+    let surface_normal = normalize((view_config.model * vec4<f32>(surface_position(local_position, 0.0), 0.0)).xyz);
+
+    // rotate the normal by the rotation between the up axis and the surface normal
+    let axis = cross(up, surface_normal);
+    let sin_angle = length(axis);
+    let cos_angle = dot(up, surface_normal);
+
+    if (sin_angle < 0.00001) {
+        return world_normal;
+    }
+
+    let k = axis / sin_angle;
+
+    return world_normal * cos_angle + cross(k, world_normal) * sin_angle + k * dot(k, world_normal) * (1.0 - cos_angle);
 }
 
 fn minmax(local_position: vec2<f32>, size: f32) -> vec2<f32> {
@@ -117,8 +317,35 @@ fn minmax(local_position: vec2<f32>, size: f32) -> vec2<f32> {
     let atlas_index = lookup.atlas_index;
     let minmax_coords = lookup.atlas_coords * config.minmax_scale + config.minmax_offset;
 
-    let min_gather = textureGather(0, minmax_atlas, atlas_sampler, minmax_coords, atlas_index);
-    let max_gather = textureGather(1, minmax_atlas, atlas_sampler, minmax_coords, atlas_index);
+    let layer = atlas_layer(atlas_index);
+    var min_gather: vec4<f32>;
+    var max_gather: vec4<f32>;
+
+    // the gathers name their texture statically, so the shard is selected by branching
+    switch (atlas_shard(atlas_index)) {
+#ifdef ATLAS_SHARDS
+        case 1: {
+            min_gather = textureGather(0, minmax_atlas_1, atlas_sampler, minmax_coords, layer);
+            max_gather = textureGather(1, minmax_atlas_1, atlas_sampler, minmax_coords, layer);
+        }
+#endif
+#ifdef ATLAS_SHARD_2
+        case 2: {
+            min_gather = textureGather(0, minmax_atlas_2, atlas_sampler, minmax_coords, layer);
+            max_gather = textureGather(1, minmax_atlas_2, atlas_sampler, minmax_coords, layer);
+        }
+#endif
+#ifdef ATLAS_SHARD_3
+        case 3: {
+            min_gather = textureGather(0, minmax_atlas_3, atlas_sampler, minmax_coords, layer);
+            max_gather = textureGather(1, minmax_atlas_3, atlas_sampler, minmax_coords, layer);
+        }
+#endif
+        default: {
+            min_gather = textureGather(0, minmax_atlas, atlas_sampler, minmax_coords, layer);
+            max_gather = textureGather(1, minmax_atlas, atlas_sampler, minmax_coords, layer);
+        }
+    }
 
     var min_height = min(min(min_gather.x, min_gather.y), min(min_gather.z, min_gather.w));
     var max_height = max(max(max_gather.x, max_gather.y), max(max_gather.z, max_gather.w));
@@ -0,0 +1,16 @@
+#import bevy_terrain::noise
+
+// The default generator, which distorts ridged mountains with domain warping
+// and blends them into rolling hills.
+fn generate_height(position: vec2<f32>) -> f32 {
+    let p = position / 1024.0;
+
+    let warped = domain_warp(p, 0.4, 3u);
+    let mountains = ridged(warped * 2.0, 6u, 2.0, 0.5);
+    let hills = fbm(p * 4.0, 4u, 2.0, 0.5);
+    let mask = smoothstep(0.4, 0.7, fbm(p * 0.5, 2u, 2.0, 0.5));
+
+    return mix(hills * 0.3, mountains, mask);
+}
+
+#import bevy_terrain::generator
@@ -0,0 +1,46 @@
+#define_import_path bevy_terrain::generator
+
+// Your generator shader has to define the following function, which is evaluated for each pixel
+// of the generated nodes. It may use the noise functions of `bevy_terrain::noise`.
+// The position is in the local space of the terrain and the result is the normalized height
+// (from zero to one).
+// fn generate_height(position: vec2<f32>) -> f32;
+
+struct GeneratorConfig {
+    texture_size: u32,
+    border_size: f32,
+    center_size: f32,
+    mip_scale: f32,
+}
+
+struct GeneratedNode {
+    position: vec2<f32>,
+    size: f32,
+    atlas_index: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> generator: GeneratorConfig;
+@group(0) @binding(1)
+var<storage> nodes: array<GeneratedNode>;
+@group(0) @binding(2)
+var atlas: texture_storage_2d_array<r32float, write>;
+
+@compute @workgroup_size(8, 8, 1)
+fn generate(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let pixel = invocation_id.xy;
+
+    if (pixel.x >= generator.texture_size || pixel.y >= generator.texture_size) {
+        return;
+    }
+
+    let node = nodes[invocation_id.z];
+
+    // the center of the pixel (in the local space of the terrain), analogous to the preprocessor
+    let pixel_size = node.size / generator.center_size;
+    let position = node.position + ((vec2<f32>(pixel) + 0.5) * generator.mip_scale - generator.border_size) * pixel_size;
+
+    let height = clamp(generate_height(position), 0.0, 1.0);
+
+    textureStore(atlas, vec2<i32>(pixel), i32(node.atlas_index), vec4<f32>(height, 0.0, 0.0, 1.0));
+}
@@ -0,0 +1,97 @@
+#define_import_path bevy_terrain::noise
+
+// A small library of noise functions for generating terrains on the GPU.
+// All functions return values in the range from zero to one.
+
+fn hash(p: vec2<f32>) -> f32 {
+    var p3 = fract(vec3<f32>(p.xyx) * 0.1031);
+    p3 += dot(p3, p3.yzx + 33.33);
+    return fract((p3.x + p3.y) * p3.z);
+}
+
+fn hash2(p: vec2<f32>) -> vec2<f32> {
+    var p3 = fract(vec3<f32>(p.xyx) * vec3<f32>(0.1031, 0.1030, 0.0973));
+    p3 += dot(p3, p3.yzx + 33.33);
+    return fract((p3.xx + p3.yz) * p3.zy);
+}
+
+// Quintic interpolation curve, whose first and second derivatives vanish at zero and one.
+fn fade(t: vec2<f32>) -> vec2<f32> {
+    return t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+}
+
+fn value_noise(p: vec2<f32>) -> f32 {
+    let i = floor(p);
+    let f = fade(fract(p));
+
+    let a = hash(i);
+    let b = hash(i + vec2<f32>(1.0, 0.0));
+    let c = hash(i + vec2<f32>(0.0, 1.0));
+    let d = hash(i + vec2<f32>(1.0, 1.0));
+
+    return mix(mix(a, b, f.x), mix(c, d, f.x), f.y);
+}
+
+fn gradient_noise(p: vec2<f32>) -> f32 {
+    let i = floor(p);
+    let f = fract(p);
+    let u = fade(f);
+
+    let a = dot(hash2(i) * 2.0 - 1.0, f);
+    let b = dot(hash2(i + vec2<f32>(1.0, 0.0)) * 2.0 - 1.0, f - vec2<f32>(1.0, 0.0));
+    let c = dot(hash2(i + vec2<f32>(0.0, 1.0)) * 2.0 - 1.0, f - vec2<f32>(0.0, 1.0));
+    let d = dot(hash2(i + vec2<f32>(1.0, 1.0)) * 2.0 - 1.0, f - vec2<f32>(1.0, 1.0));
+
+    return mix(mix(a, b, u.x), mix(c, d, u.x), u.y) * 0.5 + 0.5;
+}
+
+// Fractional Brownian motion, which sums up octaves of gradient noise with increasing frequency
+// and decreasing amplitude.
+fn fbm(p: vec2<f32>, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+    var position = p;
+    var amplitude = 0.5;
+    var value = 0.0;
+    var total = 0.0;
+
+    for (var octave = 0u; octave < octaves; octave = octave + 1u) {
+        value += amplitude * gradient_noise(position);
+        total += amplitude;
+        position = position * lacunarity + vec2<f32>(17.13, 31.71);
+        amplitude *= gain;
+    }
+
+    return value / total;
+}
+
+// Ridged multifractal noise, which folds the octaves into sharp crests (e.g. for mountain ranges).
+fn ridged(p: vec2<f32>, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+    var position = p;
+    var amplitude = 0.5;
+    var weight = 1.0;
+    var value = 0.0;
+    var total = 0.0;
+
+    for (var octave = 0u; octave < octaves; octave = octave + 1u) {
+        var ridge = 1.0 - abs(gradient_noise(position) * 2.0 - 1.0);
+        ridge = ridge * ridge * weight;
+        weight = clamp(ridge * 2.0, 0.0, 1.0);
+
+        value += amplitude * ridge;
+        total += amplitude;
+        position = position * lacunarity + vec2<f32>(17.13, 31.71);
+        amplitude *= gain;
+    }
+
+    return value / total;
+}
+
+// Offsets the position by two independent fbm fields, which distorts the features of the noise
+// sampled at the returned position.
+fn domain_warp(p: vec2<f32>, strength: f32, octaves: u32) -> vec2<f32> {
+    let offset = vec2<f32>(
+        fbm(p, octaves, 2.0, 0.5),
+        fbm(p + vec2<f32>(5.2, 1.3), octaves, 2.0, 0.5),
+    );
+
+    return p + (offset * 2.0 - 1.0) * strength;
+}
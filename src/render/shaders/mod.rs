@@ -16,14 +16,40 @@ const VERTEX_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 187371091254673438);
 const FRAGMENT_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 312347731894135735);
+const DEFAULT_MATERIAL_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 830418526930147263);
+const SPLAT_MATERIAL_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 492750361849027561);
+const DEFAULT_PBR_INPUT_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 175903648201937584);
+const DEFAULT_COLOR_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 608237419562038471);
+
+const NOISE_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 463781570264839875);
+const GENERATOR_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 671209348572610934);
 
 pub(crate) const PREPARE_INDIRECT_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 242384313596767307);
 pub(crate) const REFINE_TILES_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 938732132468373352);
+pub(crate) const DEPTH_PYRAMID_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 614027395813264970);
+pub(crate) const NORMALS_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 729463018257346195);
+pub(crate) const EROSION_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 318904627153890274);
 
 pub(crate) const DEFAULT_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 556563744564564658);
+pub(crate) const SPLAT_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 874309512367840125);
+pub(crate) const SCATTER_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 583016294750361842);
+
+pub(crate) const DEFAULT_GENERATOR_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 395728164037512896);
 
 pub(crate) fn add_shader(app: &mut App) {
     let mut assets = app.world.resource_mut::<Assets<_>>();
@@ -53,10 +79,34 @@ pub(crate) fn add_shader(app: &mut App) {
         FRAGMENT_SHADER,
         Shader::from_wgsl(include_str!("render/fragment.wgsl")),
     );
+    assets.set_untracked(
+        DEFAULT_MATERIAL_SHADER,
+        Shader::from_wgsl(include_str!("render/default_material.wgsl")),
+    );
+    assets.set_untracked(
+        SPLAT_MATERIAL_SHADER,
+        Shader::from_wgsl(include_str!("render/splat_material.wgsl")),
+    );
+    assets.set_untracked(
+        DEFAULT_PBR_INPUT_SHADER,
+        Shader::from_wgsl(include_str!("render/default_pbr_input.wgsl")),
+    );
+    assets.set_untracked(
+        DEFAULT_COLOR_SHADER,
+        Shader::from_wgsl(include_str!("render/default_color.wgsl")),
+    );
     assets.set_untracked(
         DEFAULT_SHADER,
         Shader::from_wgsl(include_str!("render/default.wgsl")),
     );
+    assets.set_untracked(
+        SPLAT_SHADER,
+        Shader::from_wgsl(include_str!("render/splat.wgsl")),
+    );
+    assets.set_untracked(
+        SCATTER_SHADER,
+        Shader::from_wgsl(include_str!("render/scatter.wgsl")),
+    );
 
     assets.set_untracked(
         PREPARE_INDIRECT_SHADER,
@@ -66,4 +116,29 @@ pub(crate) fn add_shader(app: &mut App) {
         REFINE_TILES_SHADER,
         Shader::from_wgsl(include_str!("compute/refine_tiles.wgsl")),
     );
+    assets.set_untracked(
+        DEPTH_PYRAMID_SHADER,
+        Shader::from_wgsl(include_str!("compute/depth_pyramid.wgsl")),
+    );
+    assets.set_untracked(
+        NORMALS_SHADER,
+        Shader::from_wgsl(include_str!("compute/normals.wgsl")),
+    );
+    assets.set_untracked(
+        EROSION_SHADER,
+        Shader::from_wgsl(include_str!("compute/erosion.wgsl")),
+    );
+
+    assets.set_untracked(
+        NOISE_SHADER,
+        Shader::from_wgsl(include_str!("generation/noise.wgsl")),
+    );
+    assets.set_untracked(
+        GENERATOR_SHADER,
+        Shader::from_wgsl(include_str!("generation/generator.wgsl")),
+    );
+    assets.set_untracked(
+        DEFAULT_GENERATOR_SHADER,
+        Shader::from_wgsl(include_str!("generation/default.wgsl")),
+    );
 }
@@ -7,8 +7,21 @@ struct NodeLookup {
     atlas_coords: vec2<f32>,
 }
 
+// Returns the position of the terrain surface in the local space of the terrain,
+// which is projected onto the sphere for the faces of planets.
+fn surface_position(local_position: vec2<f32>, height: f32) -> vec3<f32> {
+    if (config.planet_radius == 0.0) {
+        return vec3<f32>(local_position.x, height, local_position.y);
+    }
+
+    let cube_position = vec3<f32>(2.0 * local_position.x / config.terrain_extent.x - 1.0, 1.0,
+                                  2.0 * local_position.y / config.terrain_extent.y - 1.0);
+
+    return normalize(cube_position) * (config.planet_radius + height);
+}
+
 fn approximate_world_position(local_position: vec2<f32>) -> vec4<f32> {
-    return vec4<f32>(local_position.x, view_config.approximate_height, local_position.y, 1.0);
+    return vec4<f32>(surface_position(local_position, view_config.approximate_height), 1.0);
 }
 
 fn node_size(lod: u32) -> f32 {
@@ -22,7 +35,7 @@ fn lookup_node(lod: u32, local_position: vec2<f32>) -> NodeLookup {
     var quadtree_lod = 0u;
     for (; quadtree_lod < config.lod_count; quadtree_lod = quadtree_lod + 1u) {
         let coordinate = local_position / node_size(quadtree_lod);
-        let grid_coordinate = floor(view.world_position.xz / node_size(quadtree_lod) + 0.5 - f32(view_config.node_count >> 1u));
+        let grid_coordinate = floor(view_config.view_position.xz / node_size(quadtree_lod) + 0.5 - f32(view_config.node_count >> 1u));
 
         let grid = step(grid_coordinate, coordinate) * (1.0 - step(grid_coordinate + f32(view_config.node_count), coordinate));
 
@@ -0,0 +1,6 @@
+#define_import_path bevy_terrain::default_color
+
+// Adjusts the final color of the fragment, after the lighting has been evaluated.
+fn extend_color(input: FragmentInput, data: FragmentData, color: vec4<f32>) -> vec4<f32> {
+    return color;
+}
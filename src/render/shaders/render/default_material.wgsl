@@ -0,0 +1,461 @@
+#define_import_path bevy_terrain::default_material
+
+// The default terrain material, which can be extended with custom shading.
+// A shader extending this material imports it and implements the hooks `extend_pbr_input`
+// and `extend_color`, or imports their default implementations from
+// `bevy_terrain::default_pbr_input` and `bevy_terrain::default_color` instead.
+// Its custom material bindings are placed in group 3.
+// The hooks receive the `FragmentInput` (see `bevy_terrain::functions`) and the blended `FragmentData`.
+
+#import bevy_terrain::types
+
+struct TerrainConfig {
+    lod_count: u32,
+    height: f32,
+    leaf_node_size: u32,
+    terrain_size: u32,
+
+    // the attachments after the base attachment are looked up by their index,
+    // as the configured attachments (e.g. the normal and albedo ones) may be bound at any of them
+    height_size: f32,
+    minmax_size: f32,
+    attachment_2_size: f32,
+    attachment_3_size: f32,
+    attachment_4_size: f32,
+    attachment_5_size: f32,
+    attachment_6_size: f32,
+    attachment_7_size: f32,
+    height_scale: f32,
+    minmax_scale: f32,
+    attachment_2_scale: f32,
+    attachment_3_scale: f32,
+    attachment_4_scale: f32,
+    attachment_5_scale: f32,
+    attachment_6_scale: f32,
+    attachment_7_scale: f32,
+    height_offset: f32,
+    minmax_offset: f32,
+    attachment_2_offset: f32,
+    attachment_3_offset: f32,
+    attachment_4_offset: f32,
+    attachment_5_offset: f32,
+    attachment_6_offset: f32,
+    attachment_7_offset: f32,
+
+    terrain_extent: vec2<f32>,
+    planet_radius: f32,
+}
+
+// view bindings
+#import bevy_pbr::mesh_view_bindings
+
+// terrain view bindings
+@group(1) @binding(0)
+var<uniform> view_config: TerrainViewConfig;
+@group(1) @binding(1)
+var quadtree: texture_2d_array<u32>;
+@group(1) @binding(2)
+var<storage> tiles: TileList;
+
+// terrain bindings
+@group(2) @binding(0)
+var<uniform> config: TerrainConfig;
+@group(2) @binding(1)
+var atlas_sampler: sampler;
+@group(2) @binding(2)
+var height_atlas: texture_2d_array<f32>;
+@group(2) @binding(3)
+var minmax_atlas: texture_2d_array<f32>;
+#ifdef AMBIENT_OCCLUSION
+@group(2) @binding(4)
+var ambient_occlusion_atlas: texture_2d_array<f32>;
+#ifdef ATLAS_SHARDS
+@group(2) @binding(#{ATTACHMENT_2_SHARD_1_BINDING})
+var ambient_occlusion_atlas_1: texture_2d_array<f32>;
+#ifdef ATLAS_SHARD_2
+@group(2) @binding(#{ATTACHMENT_2_SHARD_2_BINDING})
+var ambient_occlusion_atlas_2: texture_2d_array<f32>;
+#endif
+#ifdef ATLAS_SHARD_3
+@group(2) @binding(#{ATTACHMENT_2_SHARD_3_BINDING})
+var ambient_occlusion_atlas_3: texture_2d_array<f32>;
+#endif
+#endif
+#endif
+#ifdef HORIZON
+@group(2) @binding(5)
+var horizon_atlas: texture_2d_array<f32>;
+#ifdef ATLAS_SHARDS
+@group(2) @binding(#{ATTACHMENT_3_SHARD_1_BINDING})
+var horizon_atlas_1: texture_2d_array<f32>;
+#ifdef ATLAS_SHARD_2
+@group(2) @binding(#{ATTACHMENT_3_SHARD_2_BINDING})
+var horizon_atlas_2: texture_2d_array<f32>;
+#endif
+#ifdef ATLAS_SHARD_3
+@group(2) @binding(#{ATTACHMENT_3_SHARD_3_BINDING})
+var horizon_atlas_3: texture_2d_array<f32>;
+#endif
+#endif
+#endif
+#ifdef NORMAL_ATTACHMENT
+@group(2) @binding(#{NORMAL_ATTACHMENT_BINDING})
+var normal_atlas: texture_2d_array<f32>;
+#ifdef ATLAS_SHARDS
+@group(2) @binding(#{NORMAL_ATTACHMENT_SHARD_1_BINDING})
+var normal_atlas_1: texture_2d_array<f32>;
+#ifdef ATLAS_SHARD_2
+@group(2) @binding(#{NORMAL_ATTACHMENT_SHARD_2_BINDING})
+var normal_atlas_2: texture_2d_array<f32>;
+#endif
+#ifdef ATLAS_SHARD_3
+@group(2) @binding(#{NORMAL_ATTACHMENT_SHARD_3_BINDING})
+var normal_atlas_3: texture_2d_array<f32>;
+#endif
+#endif
+#endif
+#ifdef ALBEDO_ATTACHMENT
+@group(2) @binding(#{ALBEDO_ATTACHMENT_BINDING})
+var albedo_atlas: texture_2d_array<f32>;
+#ifdef ATLAS_SHARDS
+@group(2) @binding(#{ALBEDO_ATTACHMENT_SHARD_1_BINDING})
+var albedo_atlas_1: texture_2d_array<f32>;
+#ifdef ATLAS_SHARD_2
+@group(2) @binding(#{ALBEDO_ATTACHMENT_SHARD_2_BINDING})
+var albedo_atlas_2: texture_2d_array<f32>;
+#endif
+#ifdef ATLAS_SHARD_3
+@group(2) @binding(#{ALBEDO_ATTACHMENT_SHARD_3_BINDING})
+var albedo_atlas_3: texture_2d_array<f32>;
+#endif
+#endif
+#endif
+
+#import bevy_pbr::mesh_types
+#import bevy_pbr::pbr_types
+
+#import bevy_pbr::utils
+#import bevy_pbr::clustered_forward
+#import bevy_pbr::lighting
+#import bevy_pbr::pbr_ambient
+#import bevy_pbr::shadows
+#import bevy_pbr::fog
+#import bevy_pbr::pbr_functions
+
+#import bevy_terrain::node
+#import bevy_terrain::functions
+#import bevy_terrain::debug
+
+struct FragmentData {
+    world_normal: vec3<f32>,
+    occlusion: f32,
+    horizon: vec4<f32>,
+    debug_color: vec4<f32>,
+}
+
+// Returns the scale and offset of the attachment, which map the atlas coordinates onto the center
+// of its texture.
+fn attachment_transform(index: u32) -> vec2<f32> {
+    var transform: vec2<f32>;
+
+    switch (index) {
+        case 0u: { transform = vec2<f32>(config.height_scale, config.height_offset); }
+        case 1u: { transform = vec2<f32>(config.minmax_scale, config.minmax_offset); }
+        case 2u: { transform = vec2<f32>(config.attachment_2_scale, config.attachment_2_offset); }
+        case 3u: { transform = vec2<f32>(config.attachment_3_scale, config.attachment_3_offset); }
+        case 4u: { transform = vec2<f32>(config.attachment_4_scale, config.attachment_4_offset); }
+        case 5u: { transform = vec2<f32>(config.attachment_5_scale, config.attachment_5_offset); }
+        case 6u: { transform = vec2<f32>(config.attachment_6_scale, config.attachment_6_offset); }
+        default: { transform = vec2<f32>(config.attachment_7_scale, config.attachment_7_offset); }
+    }
+
+    return transform;
+}
+
+fn attachment_coords(index: u32, atlas_coords: vec2<f32>) -> vec2<f32> {
+    let transform = attachment_transform(index);
+
+    return atlas_coords * transform.x + transform.y;
+}
+
+// Converts the derivative of the local position (divided by the size of the lod) into the one of
+// the texture coordinates of the attachment. The texels per unit of the terrain scale with the
+// center size of the attachment, so that finer attachments (e.g. albedo imagery with a multiple of
+// the resolution of the heights) select their mip levels correctly.
+fn attachment_gradient(index: u32, derivative: vec2<f32>) -> vec2<f32> {
+    return derivative * attachment_transform(index).x / f32(config.leaf_node_size);
+}
+
+// Samples the attachments through the shard of the node atlas, which stores the atlas index.
+#ifdef AMBIENT_OCCLUSION
+fn sample_ambient_occlusion(coords: vec2<f32>, atlas_index: i32, ddx: vec2<f32>, ddy: vec2<f32>) -> vec4<f32> {
+#ifdef ATLAS_SHARDS
+    return sample_sharded(
+        ambient_occlusion_atlas,
+        ambient_occlusion_atlas_1,
+#ifdef ATLAS_SHARD_2
+        ambient_occlusion_atlas_2,
+#endif
+#ifdef ATLAS_SHARD_3
+        ambient_occlusion_atlas_3,
+#endif
+        coords, atlas_index, ddx, ddy);
+#else
+    return sample_attachment(ambient_occlusion_atlas, coords, atlas_index, ddx, ddy);
+#endif
+}
+#endif
+
+#ifdef HORIZON
+fn sample_horizon(coords: vec2<f32>, atlas_index: i32, ddx: vec2<f32>, ddy: vec2<f32>) -> vec4<f32> {
+#ifdef ATLAS_SHARDS
+    return sample_sharded(
+        horizon_atlas,
+        horizon_atlas_1,
+#ifdef ATLAS_SHARD_2
+        horizon_atlas_2,
+#endif
+#ifdef ATLAS_SHARD_3
+        horizon_atlas_3,
+#endif
+        coords, atlas_index, ddx, ddy);
+#else
+    return sample_attachment(horizon_atlas, coords, atlas_index, ddx, ddy);
+#endif
+}
+#endif
+
+#ifdef NORMAL_ATTACHMENT
+fn sample_normal(coords: vec2<f32>, atlas_index: i32, ddx: vec2<f32>, ddy: vec2<f32>) -> vec4<f32> {
+#ifdef ATLAS_SHARDS
+    return sample_sharded(
+        normal_atlas,
+        normal_atlas_1,
+#ifdef ATLAS_SHARD_2
+        normal_atlas_2,
+#endif
+#ifdef ATLAS_SHARD_3
+        normal_atlas_3,
+#endif
+        coords, atlas_index, ddx, ddy);
+#else
+    return sample_attachment(normal_atlas, coords, atlas_index, ddx, ddy);
+#endif
+}
+#endif
+
+#ifdef ALBEDO_ATTACHMENT
+fn sample_albedo(coords: vec2<f32>, atlas_index: i32, ddx: vec2<f32>, ddy: vec2<f32>) -> vec4<f32> {
+#ifdef ATLAS_SHARDS
+    return sample_sharded(
+        albedo_atlas,
+        albedo_atlas_1,
+#ifdef ATLAS_SHARD_2
+        albedo_atlas_2,
+#endif
+#ifdef ATLAS_SHARD_3
+        albedo_atlas_3,
+#endif
+        coords, atlas_index, ddx, ddy);
+#else
+    return sample_attachment(albedo_atlas, coords, atlas_index, ddx, ddy);
+#endif
+}
+#endif
+
+fn vertex_height(lookup: NodeLookup) -> f32 {
+    let height_coords = lookup.atlas_coords * config.height_scale + config.height_offset;
+    let height = sample_height(height_coords, lookup.atlas_index, vec2<f32>(0.0), vec2<f32>(0.0));
+
+    return height * config.height;
+}
+
+// The default material is not splatted.
+fn vertex_splat_weights(lookup: NodeLookup) -> vec4<f32> {
+    return vec4<f32>(0.0);
+}
+
+fn lookup_fragment_data(input: FragmentInput, lookup: NodeLookup, ddx: vec2<f32>, ddy: vec2<f32>) -> FragmentData {
+    let atlas_lod = lookup.atlas_lod;
+    let atlas_index = lookup.atlas_index;
+    let atlas_coords = lookup.atlas_coords;
+    let ddx = ddx / f32(1u << atlas_lod);
+    let ddy = ddy / f32(1u << atlas_lod);
+
+    let height_coords = atlas_coords * config.height_scale + config.height_offset;
+    let height_ddx = ddx / 512.0;
+    let height_ddy = ddy / 512.0;
+
+#ifdef NORMAL_ATTACHMENT
+    let normal_coords = attachment_coords(#{NORMAL_ATTACHMENT_INDEX}u, atlas_coords);
+    let normal_ddx = attachment_gradient(#{NORMAL_ATTACHMENT_INDEX}u, ddx);
+    let normal_ddy = attachment_gradient(#{NORMAL_ATTACHMENT_INDEX}u, ddy);
+
+    let normal_xz = sample_normal(normal_coords, atlas_index, normal_ddx, normal_ddy).xy * 2.0 - 1.0;
+
+    // the y component is reconstructed from the baked x and z components
+    let local_normal = vec3<f32>(normal_xz.x, sqrt(max(1.0 - dot(normal_xz, normal_xz), 0.0)), normal_xz.y);
+    let world_normal = normalize((view_config.model * vec4<f32>(local_normal, 0.0)).xyz);
+#else
+    let world_normal = calculate_normal(height_coords, atlas_index, atlas_lod, height_ddx, height_ddy);
+#endif
+
+    var occlusion = 1.0;
+
+#ifdef AMBIENT_OCCLUSION
+    let ambient_occlusion_coords = attachment_coords(2u, atlas_coords);
+    let ambient_occlusion_ddx = attachment_gradient(2u, ddx);
+    let ambient_occlusion_ddy = attachment_gradient(2u, ddy);
+
+    occlusion = sample_ambient_occlusion(ambient_occlusion_coords, atlas_index, ambient_occlusion_ddx, ambient_occlusion_ddy).x;
+#endif
+
+    var horizon = vec4<f32>(0.0);
+
+#ifdef HORIZON
+    let horizon_coords = attachment_coords(3u, atlas_coords);
+    let horizon_ddx = attachment_gradient(3u, ddx);
+    let horizon_ddy = attachment_gradient(3u, ddy);
+
+    horizon = sample_horizon(horizon_coords, atlas_index, horizon_ddx, horizon_ddy);
+#endif
+
+    // the base color, which the debug views are blended with
+    var debug_color = vec4<f32>(0.5);
+
+#ifdef ALBEDO_ATTACHMENT
+    let albedo_coords = attachment_coords(#{ALBEDO_ATTACHMENT_INDEX}u, atlas_coords);
+    let albedo_ddx = attachment_gradient(#{ALBEDO_ATTACHMENT_INDEX}u, ddx);
+    let albedo_ddy = attachment_gradient(#{ALBEDO_ATTACHMENT_INDEX}u, ddy);
+
+    debug_color = sample_albedo(albedo_coords, atlas_index, albedo_ddx, albedo_ddy);
+#endif
+
+#ifdef SHOW_LOD
+    debug_color = mix(debug_color, show_lod(atlas_lod, input.terrain_position.xyz), 0.4);
+#endif
+
+#ifdef SHOW_UV
+    debug_color = mix(debug_color, vec4<f32>(atlas_coords.x, atlas_coords.y, 0.0, 1.0), 0.5);
+#endif
+
+    return FragmentData(world_normal, occlusion, horizon, debug_color);
+}
+
+fn blend_fragment_data(data1: FragmentData, data2: FragmentData, blend_ratio: f32) -> FragmentData {
+    let world_normal = mix(data2.world_normal, data1.world_normal, blend_ratio);
+    let occlusion = mix(data2.occlusion, data1.occlusion, blend_ratio);
+    let horizon = mix(data2.horizon, data1.horizon, blend_ratio);
+    let debug_color = mix(data2.debug_color, data1.debug_color, blend_ratio);
+
+    return FragmentData(world_normal, occlusion, horizon, debug_color);
+}
+
+#ifdef HORIZON
+// The range of sun elevations (as sines), across which the horizon shadow fades in.
+const HORIZON_SOFTNESS: f32 = 0.05;
+
+// Returns the visibility of the first directional light (the sun) above the horizon,
+// which is stored for the x, z, -x and -z directions of the terrain.
+fn horizon_visibility(horizon: vec4<f32>) -> f32 {
+    let model = mat3x3<f32>(view_config.model[0].xyz, view_config.model[1].xyz, view_config.model[2].xyz);
+    let direction = normalize(transpose(model) * lights.directional_lights[0].direction_to_light);
+
+    let sector = (atan2(direction.z, direction.x) / (0.5 * PI) + 4.0) % 4.0;
+    let index = u32(sector);
+
+    var horizons = horizon;
+    let elevation = mix(horizons[index % 4u], horizons[(index + 1u) % 4u], fract(sector));
+
+    return smoothstep(elevation - HORIZON_SOFTNESS, elevation + HORIZON_SOFTNESS, direction.y);
+}
+
+// Removes the light of the sun (which is already attenuated by its shadow map) from the color,
+// where the sun is occluded by the horizon.
+fn apply_horizon_shadow(pbr_input: PbrInput, color: vec4<f32>, horizon: vec4<f32>) -> vec4<f32> {
+    if (lights.n_directional_lights == 0u || config.planet_radius != 0.0) {
+        return color;
+    }
+
+    let visibility = horizon_visibility(horizon);
+
+    if (visibility >= 1.0) {
+        return color;
+    }
+
+    let base_color = pbr_input.material.base_color.rgb;
+    let metallic = pbr_input.material.metallic;
+    let reflectance = pbr_input.material.reflectance;
+    let roughness = perceptualRoughnessToRoughness(pbr_input.material.perceptual_roughness);
+    let NdotV = max(dot(pbr_input.N, pbr_input.V), 0.0001);
+    let F0 = 0.16 * reflectance * reflectance * (1.0 - metallic) + base_color * metallic;
+    let diffuse_color = base_color * (1.0 - metallic);
+    let R = reflect(-pbr_input.V, pbr_input.N);
+
+    var shadow = 1.0;
+
+    if ((pbr_input.flags & MESH_FLAGS_SHADOW_RECEIVER_BIT) != 0u
+            && (lights.directional_lights[0].flags & DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
+        let view_z = dot(vec4<f32>(view.inverse_view[0].z, view.inverse_view[1].z, view.inverse_view[2].z, view.inverse_view[3].z), pbr_input.world_position);
+        shadow = fetch_directional_shadow(0u, pbr_input.world_position, pbr_input.world_normal, view_z);
+    }
+
+    let sun_light = directional_light(0u, roughness, NdotV, pbr_input.N, pbr_input.V, R, F0, diffuse_color) * shadow;
+
+    return vec4<f32>(max(color.rgb - (1.0 - visibility) * sun_light, vec3<f32>(0.0)), color.a);
+}
+#endif
+
+fn process_fragment(input: FragmentInput, data: FragmentData) -> Fragment {
+    let do_discard = input.local_position.x < 2.0 || input.local_position.x > config.terrain_extent.x - 2.0 ||
+                     input.local_position.y < 2.0 || input.local_position.y > config.terrain_extent.y - 2.0;
+
+    var color = data.debug_color;
+    var world_normal = data.world_normal;
+
+#ifdef DETAIL_LAYER
+    world_normal = apply_detail_normal(input.local_position, world_normal);
+#endif
+
+#ifdef DECALS
+    color = apply_decals(input.local_position, color);
+#endif
+
+#ifdef SNOW
+    color = apply_snow(input, color);
+#endif
+
+    color = mix(color, vec4<f32>(input.debug_color.xyz, 1.0), input.debug_color.w);
+
+#ifdef LIGHTING
+    var pbr_input: PbrInput = pbr_input_new();
+    pbr_input.material.base_color = color;
+    pbr_input.material.perceptual_roughness = 1.0;
+    pbr_input.material.reflectance = 0.0;
+    pbr_input.occlusion = data.occlusion;
+    pbr_input.frag_coord = input.frag_coord;
+    pbr_input.world_position = input.world_position;
+    pbr_input.world_normal = planet_normal(input.local_position, world_normal);
+    pbr_input.is_orthographic = view.projection[3].w == 1.0;
+    pbr_input.N = pbr_input.world_normal;
+    pbr_input.V = calculate_view(input.world_position, pbr_input.is_orthographic);
+    pbr_input = extend_pbr_input(input, data, pbr_input);
+    color = pbr(pbr_input);
+
+#ifdef HORIZON
+    color = apply_horizon_shadow(pbr_input, color, data.horizon);
+#endif
+#endif
+
+    color = extend_color(input, data, color);
+
+    return Fragment(color, do_discard);
+}
+
+#ifndef MINMAX
+#import bevy_terrain::vertex
+#else
+#import bevy_terrain::minmax
+#endif
+
+#import bevy_terrain::fragment
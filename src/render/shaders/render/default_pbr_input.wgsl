@@ -0,0 +1,7 @@
+#define_import_path bevy_terrain::default_pbr_input
+
+// Adjusts the pbr input of the fragment, before the lighting is evaluated.
+// This is only called, if the terrain is lit.
+fn extend_pbr_input(input: FragmentInput, data: FragmentData, pbr_input: PbrInput) -> PbrInput {
+    return pbr_input;
+}
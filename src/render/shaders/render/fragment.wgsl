@@ -17,14 +17,265 @@
 // blended fragment data.
 // fn process_fragment(input: FragmentInput, data: FragmentData) -> Fragment;
 
+#ifdef HOLES
+// The hole attachment, which is bound by the pipeline instead of the material.
+@group(2) @binding(#{HOLE_BINDING})
+var hole_atlas: texture_2d_array<f32>;
+#ifdef ATLAS_SHARDS
+@group(2) @binding(#{HOLE_SHARD_1_BINDING})
+var hole_atlas_1: texture_2d_array<f32>;
+#ifdef ATLAS_SHARD_2
+@group(2) @binding(#{HOLE_SHARD_2_BINDING})
+var hole_atlas_2: texture_2d_array<f32>;
+#endif
+#ifdef ATLAS_SHARD_3
+@group(2) @binding(#{HOLE_SHARD_3_BINDING})
+var hole_atlas_3: texture_2d_array<f32>;
+#endif
+#endif
+
+// Samples the first mip level of the hole attachment from the shard, which stores the atlas index.
+fn sample_hole(coords: vec2<f32>, atlas_index: i32) -> vec4<f32> {
+#ifdef ATLAS_SHARDS
+    return sample_sharded(
+        hole_atlas,
+        hole_atlas_1,
+#ifdef ATLAS_SHARD_2
+        hole_atlas_2,
+#endif
+#ifdef ATLAS_SHARD_3
+        hole_atlas_3,
+#endif
+        coords, atlas_index, vec2<f32>(0.0), vec2<f32>(0.0));
+#else
+    return sample_attachment(hole_atlas, coords, atlas_index, vec2<f32>(0.0), vec2<f32>(0.0));
+#endif
+}
+
+// Returns whether the fragment lies inside a hole of the terrain.
+fn is_hole(lookup: NodeLookup) -> bool {
+    // the hole attachment has a border of one pixel, like the one created by `hole_attachment`
+    let hole_size = vec2<f32>(textureDimensions(hole_atlas));
+    let hole_coords = (lookup.atlas_coords * (hole_size - 2.0) + 1.0) / hole_size;
+
+    return sample_hole(hole_coords, lookup.atlas_index).x >= 0.5;
+}
+#endif
+
+#ifdef SNOW
+// The snow attachment, which is bound by the pipeline instead of the material.
+@group(2) @binding(#{SNOW_BINDING})
+var snow_atlas: texture_2d_array<f32>;
+#ifdef ATLAS_SHARDS
+@group(2) @binding(#{SNOW_SHARD_1_BINDING})
+var snow_atlas_1: texture_2d_array<f32>;
+#ifdef ATLAS_SHARD_2
+@group(2) @binding(#{SNOW_SHARD_2_BINDING})
+var snow_atlas_2: texture_2d_array<f32>;
+#endif
+#ifdef ATLAS_SHARD_3
+@group(2) @binding(#{SNOW_SHARD_3_BINDING})
+var snow_atlas_3: texture_2d_array<f32>;
+#endif
+#endif
+
+// Samples the first mip level of the snow attachment from the shard, which stores the atlas index.
+fn sample_snow(coords: vec2<f32>, atlas_index: i32) -> vec4<f32> {
+#ifdef ATLAS_SHARDS
+    return sample_sharded(
+        snow_atlas,
+        snow_atlas_1,
+#ifdef ATLAS_SHARD_2
+        snow_atlas_2,
+#endif
+#ifdef ATLAS_SHARD_3
+        snow_atlas_3,
+#endif
+        coords, atlas_index, vec2<f32>(0.0), vec2<f32>(0.0));
+#else
+    return sample_attachment(snow_atlas, coords, atlas_index, vec2<f32>(0.0), vec2<f32>(0.0));
+#endif
+}
+
+// The albedo of the snow, which covers the base color of the materials.
+const SNOW_ALBEDO: vec3<f32> = vec3<f32>(0.92, 0.94, 0.97);
+
+// Returns the depth of the snow (in the local space of the terrain) and its coverage.
+fn lookup_snow(lookup: NodeLookup) -> vec2<f32> {
+    // the snow attachment has a border of one pixel, like the one created by `snow_attachment`
+    let snow_size = vec2<f32>(textureDimensions(snow_atlas));
+    let snow_coords = (lookup.atlas_coords * (snow_size - 2.0) + 1.0) / snow_size;
+    let snow = sample_snow(snow_coords, lookup.atlas_index).xy;
+
+    return vec2<f32>(snow.x * config.height, snow.y);
+}
+
+// Blends the snow covering the fragment into the base color.
+// Materials call this before lighting, so that the snow is shaded like the terrain.
+fn apply_snow(input: FragmentInput, base_color: vec4<f32>) -> vec4<f32> {
+    let blend = calculate_blend(input.terrain_position);
+    var coverage = lookup_snow(lookup_node(blend.lod, input.local_position)).y;
+
+    if (blend.ratio < 1.0) {
+        let coverage2 = lookup_snow(lookup_node(blend.lod + 1u, input.local_position)).y;
+        coverage      = mix(coverage2, coverage, blend.ratio);
+    }
+
+    return vec4<f32>(mix(base_color.rgb, SNOW_ALBEDO, coverage), base_color.a);
+}
+#endif
+
+#ifdef DECALS
+struct Decal {
+    position: vec2<f32>,
+    half_size: vec2<f32>,
+    rotation: vec2<f32>,
+    layer: u32,
+    opacity: f32,
+}
+
+struct DecalList {
+    count: u32,
+    data: array<Decal>,
+}
+
+// The decals are bound after all possible attachments (see `DECAL_BINDING`).
+@group(2) @binding(10)
+var<storage> decals: DecalList;
+@group(2) @binding(11)
+var decal_texture: texture_2d_array<f32>;
+
+// Blends the decals covering the position (in the local space of the terrain) into the base color.
+// Materials call this before lighting, so that the decals are shaded like the terrain.
+fn apply_decals(local_position: vec2<f32>, base_color: vec4<f32>) -> vec4<f32> {
+    var color = base_color;
+
+    for (var i = 0u; i < decals.count; i = i + 1u) {
+        let decal = decals.data[i];
+        let offset = local_position - decal.position;
+
+        // rotates the offset into the space of the decal
+        let decal_coords = vec2<f32>(decal.rotation.x * offset.x + decal.rotation.y * offset.y,
+                                     decal.rotation.x * offset.y - decal.rotation.y * offset.x) / decal.half_size;
+
+        if (any(abs(decal_coords) > vec2<f32>(1.0))) {
+            continue;
+        }
+
+        let decal_color = textureSampleLevel(decal_texture, atlas_sampler, 0.5 * decal_coords + 0.5, i32(decal.layer), 0.0);
+        color = vec4<f32>(mix(color.rgb, decal_color.rgb, decal_color.a * decal.opacity), color.a);
+    }
+
+    return color;
+}
+#endif
+
+#ifdef DETAIL_LAYER
+struct DetailLayer {
+    origin: vec2<f32>,
+    texel_size: f32,
+    resolution: u32,
+    offset: vec2<u32>,
+    time: f32,
+    fade_rate: f32,
+}
+
+// The detail layer is bound after the decals (see `DETAIL_LAYER_BINDING`).
+// It is declared here, because this module is imported alongside the vertex entry point as well.
+@group(2) @binding(12)
+var<uniform> detail_layer: DetailLayer;
+@group(2) @binding(13)
+var detail_texture: texture_2d<f32>;
+
+// Loads the displacement of the texel, which is zero outside of the layer.
+// The texels wrap around the edges of the layer and store their displacement alongside
+// the time they were stamped at, which it fades out by.
+fn load_detail_displacement(texel: vec2<i32>) -> f32 {
+    let resolution = vec2<i32>(i32(detail_layer.resolution));
+
+    if (any(texel < vec2<i32>(0)) || any(texel >= resolution)) {
+        return 0.0;
+    }
+
+    let stored_texel = (vec2<u32>(texel) + detail_layer.offset) % detail_layer.resolution;
+    let stamp = textureLoad(detail_texture, vec2<i32>(stored_texel), 0).xy;
+    let displacement = stamp.x * exp(-detail_layer.fade_rate * max(detail_layer.time - stamp.y, 0.0));
+
+    // the faded out displacements are cut off
+    return select(displacement, 0.0, abs(displacement) < 0.001);
+}
+
+// Returns the displacement of the detail layer at the position (in the local space of the terrain),
+// which is added to the height of the terrain.
+fn detail_displacement(local_position: vec2<f32>) -> f32 {
+    if (detail_layer.resolution == 0u) {
+        return 0.0;
+    }
+
+    // the displacements are stored at the centers of the texels and interpolated manually,
+    // because float textures are not filterable
+    let coords = (local_position - detail_layer.origin) / detail_layer.texel_size - 0.5;
+    let texel = vec2<i32>(floor(coords));
+    let t = coords - floor(coords);
+
+    let top    = mix(load_detail_displacement(texel),                      load_detail_displacement(texel + vec2<i32>(1, 0)), t.x);
+    let bottom = mix(load_detail_displacement(texel + vec2<i32>(0, 1)), load_detail_displacement(texel + vec2<i32>(1, 1)), t.x);
+
+    return mix(top, bottom, t.y);
+}
+
+// Bends the world normal of the terrain along the slopes of the detail layer at the position.
+// The impressions are usually smaller than the vertex spacing and only show up in the shading.
+fn apply_detail_normal(local_position: vec2<f32>, world_normal: vec3<f32>) -> vec3<f32> {
+    if (detail_layer.resolution == 0u) {
+        return world_normal;
+    }
+
+    let offset = detail_layer.texel_size;
+    let left  = detail_displacement(local_position + vec2<f32>(-offset,     0.0));
+    let up    = detail_displacement(local_position + vec2<f32>(    0.0, -offset));
+    let right = detail_displacement(local_position + vec2<f32>( offset,     0.0));
+    let down  = detail_displacement(local_position + vec2<f32>(    0.0,  offset));
+    let slope = vec2<f32>(right - left, down - up) / (2.0 * offset);
+
+    // the slopes of the surface and the detail layer add up in the local space of the terrain
+    let model = mat3x3<f32>(view_config.model[0].xyz, view_config.model[1].xyz, view_config.model[2].xyz);
+    let local_normal = normalize(transpose(model) * world_normal);
+    let detail_normal = local_normal / max(local_normal.y, 0.01) - vec3<f32>(slope.x, 0.0, slope.y);
+
+    return normalize(model * detail_normal);
+}
+#endif
+
+#ifdef HOLES
+// The fragment entry point of the depth only passes (e.g. the shadow cascades),
+// which only cuts the holes out of the terrain, so that they do not cast shadows.
+@fragment
+fn depth_fragment(input: FragmentInput) {
+    let blend  = calculate_blend(input.terrain_position);
+    let lookup = lookup_node(blend.lod, input.local_position);
+
+    if (is_hole(lookup)) {
+        discard;
+    }
+}
+#endif
+
 // The default fragment entry point, which blends the terrain data at the fringe between two lods.
 @fragment
 fn fragment(input: FragmentInput) -> FragmentOutput {
     let ddx   = dpdx(input.local_position);
     let ddy   = dpdy(input.local_position);
-    let blend = calculate_blend(input.world_position);
+    let blend = calculate_blend(input.terrain_position);
 
     let lookup = lookup_node(blend.lod, input.local_position);
+
+#ifdef HOLES
+    if (is_hole(lookup)) {
+        discard;
+    }
+#endif
+
     var data   = lookup_fragment_data(input, lookup, ddx, ddy);
 
     if (blend.ratio < 1.0) {
@@ -39,5 +290,21 @@ fn fragment(input: FragmentInput) -> FragmentOutput {
         discard;
     }
 
-    return FragmentOutput(fragment.color);
+    // the debug views replace the color of any material
+    var color = fragment.color;
+
+#ifdef SHOW_LOD_HEATMAP
+    color = show_lod_heatmap(lookup.atlas_lod);
+#endif
+#ifdef SHOW_NODE_CHECKERS
+    color = show_node_checkers(lookup, input.local_position);
+#endif
+#ifdef SHOW_UV_DENSITY
+    color = show_uv_density(lookup.atlas_lod, ddx, ddy);
+#endif
+#ifdef SHOW_MORPH
+    color = show_morph(input.morph);
+#endif
+
+    return FragmentOutput(color);
 }
\ No newline at end of file
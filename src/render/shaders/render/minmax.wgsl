@@ -40,7 +40,7 @@ fn vertex(in: VertexInput) -> VertexOutput {
 
     var output = vertex_output(local_position, height);
 
-    let color = show_tiles(tile, output.world_position);
+    let color = show_tiles(tile, output.terrain_position);
     output.debug_color = color;
 
     return output;
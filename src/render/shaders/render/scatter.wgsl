@@ -0,0 +1,66 @@
+// Renders the instances of the scattered detail objects.
+#import bevy_pbr::mesh_types
+#import bevy_pbr::mesh_view_bindings
+
+@group(1) @binding(0)
+var<uniform> mesh: Mesh;
+
+#import bevy_pbr::mesh_functions
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(8) position_scale: vec4<f32>,
+    @location(9) rotation_fade: vec4<f32>,
+    @location(10) color: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_normal: vec3<f32>,
+    @location(1) color: vec4<f32>,
+}
+
+fn rotate_y(v: vec3<f32>, angle: f32) -> vec3<f32> {
+    let c = cos(angle);
+    let s = sin(angle);
+
+    return vec3<f32>(c * v.x + s * v.z, v.y, c * v.z - s * v.x);
+}
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    let instance_position = vec4<f32>(vertex.position_scale.xyz, 1.0);
+    let world_instance = mesh_position_local_to_world(mesh.model, instance_position);
+
+    let fade_start = vertex.rotation_fade.y;
+    let fade_end = vertex.rotation_fade.z;
+    let view_distance = distance(world_instance.xyz, view.world_position);
+    let fade = 1.0 - smoothstep(fade_start, max(fade_end, fade_start + 0.0001), view_distance);
+
+    // the instances shrink away towards the fade end, so that they do not pop out of existence
+    let scale = vertex.position_scale.w * fade;
+    let local_position = rotate_y(vertex.position, vertex.rotation_fade.x) * scale + vertex.position_scale.xyz;
+    let world_position = mesh_position_local_to_world(mesh.model, vec4<f32>(local_position, 1.0));
+
+    var out: VertexOutput;
+    out.clip_position = mesh_position_world_to_clip(world_position);
+    out.world_normal = mesh_normal_local_to_world(rotate_y(vertex.normal, vertex.rotation_fade.x));
+    out.color = vertex.color;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    var lighting = lights.ambient_color.rgb;
+
+    if (lights.n_directional_lights > 0u) {
+        let light = lights.directional_lights[0];
+
+        // thin objects like grass blades are lit from both sides
+        let diffuse = abs(dot(normalize(in.world_normal), light.direction_to_light));
+        lighting = lighting + light.color.rgb * diffuse;
+    }
+
+    return vec4<f32>(in.color.rgb * lighting, in.color.a);
+}
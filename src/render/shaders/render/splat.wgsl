@@ -0,0 +1,4 @@
+// The splat terrain shader, which uses the splat material without any extensions.
+#import bevy_terrain::splat_material
+#import bevy_terrain::default_pbr_input
+#import bevy_terrain::default_color
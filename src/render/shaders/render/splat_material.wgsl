@@ -0,0 +1,443 @@
+#define_import_path bevy_terrain::splat_material
+
+// The splat terrain material, which can be extended with custom shading like the default material.
+// The `FragmentData` passed to the hooks contains the painted splat weights,
+// which are also interpolated per vertex into the `splat_weights` of the `FragmentInput`.
+// Shaders extending this material have to use the bindings of the `SplatMaterial` in group 3.
+
+#import bevy_terrain::types
+
+struct TerrainConfig {
+    lod_count: u32,
+    height: f32,
+    leaf_node_size: u32,
+    terrain_size: u32,
+
+    height_size: f32,
+    minmax_size: f32,
+    splat_size: f32,
+    _empty: u32,
+    _empty: vec4<f32>,
+    height_scale: f32,
+    minmax_scale: f32,
+    splat_scale: f32,
+    _empty: u32,
+    _empty: vec4<f32>,
+    height_offset: f32,
+    minmax_offset: f32,
+    splat_offset: f32,
+    _empty: u32,
+    _empty: vec4<f32>,
+
+    terrain_extent: vec2<f32>,
+    planet_radius: f32,
+}
+
+// view bindings
+#import bevy_pbr::mesh_view_bindings
+
+// terrain view bindings
+@group(1) @binding(0)
+var<uniform> view_config: TerrainViewConfig;
+@group(1) @binding(1)
+var quadtree: texture_2d_array<u32>;
+@group(1) @binding(2)
+var<storage> tiles: TileList;
+
+// terrain bindings
+@group(2) @binding(0)
+var<uniform> config: TerrainConfig;
+@group(2) @binding(1)
+var atlas_sampler: sampler;
+@group(2) @binding(2)
+var height_atlas: texture_2d_array<f32>;
+@group(2) @binding(3)
+var minmax_atlas: texture_2d_array<f32>;
+@group(2) @binding(4)
+var splat_atlas: texture_2d_array<f32>;
+#ifdef ATLAS_SHARDS
+@group(2) @binding(#{ATTACHMENT_2_SHARD_1_BINDING})
+var splat_atlas_1: texture_2d_array<f32>;
+#ifdef ATLAS_SHARD_2
+@group(2) @binding(#{ATTACHMENT_2_SHARD_2_BINDING})
+var splat_atlas_2: texture_2d_array<f32>;
+#endif
+#ifdef ATLAS_SHARD_3
+@group(2) @binding(#{ATTACHMENT_2_SHARD_3_BINDING})
+var splat_atlas_3: texture_2d_array<f32>;
+#endif
+#endif
+
+struct TexturingRule {
+    ranges: vec4<f32>,
+    layer: u32,
+    blend_width: f32,
+}
+
+struct SplatMaterial {
+    layer_scales: vec4<f32>,
+    flags: u32,
+    slope_threshold: f32,
+    blend_sharpness: f32,
+    rules: array<TexturingRule, 8>,
+    rule_count: u32,
+}
+
+const SPLAT_MATERIAL_FLAGS_TRIPLANAR: u32 = 1u;
+const SPLAT_MATERIAL_FLAGS_STOCHASTIC: u32 = 2u;
+
+// material bindings
+@group(3) @binding(0)
+var<uniform> material: SplatMaterial;
+@group(3) @binding(1)
+var albedo_texture: texture_2d_array<f32>;
+@group(3) @binding(2)
+var layer_sampler: sampler;
+@group(3) @binding(3)
+var normal_texture: texture_2d_array<f32>;
+@group(3) @binding(4)
+var roughness_texture: texture_2d_array<f32>;
+
+#import bevy_pbr::mesh_types
+#import bevy_pbr::pbr_types
+
+#import bevy_pbr::utils
+#import bevy_pbr::clustered_forward
+#import bevy_pbr::lighting
+#import bevy_pbr::pbr_ambient
+#import bevy_pbr::shadows
+#import bevy_pbr::fog
+#import bevy_pbr::pbr_functions
+
+#import bevy_terrain::node
+#import bevy_terrain::functions
+#import bevy_terrain::debug
+
+struct FragmentData {
+    world_normal: vec3<f32>,
+    weights: vec4<f32>,
+    debug_color: vec4<f32>,
+}
+
+// The texture layers sampled at the fragment and blended by their weights.
+struct Layer {
+    albedo: vec4<f32>,
+    normal: vec3<f32>,
+    roughness: f32,
+}
+
+// A cell of the triangular grid used for stochastic sampling.
+struct StochasticTile {
+    uv: vec2<f32>,
+    rotation: mat2x2<f32>,
+    weight: f32,
+}
+
+fn hash(vertex: vec2<f32>) -> vec2<f32> {
+    let value = vec2<f32>(dot(vertex, vec2<f32>(127.1, 311.7)), dot(vertex, vec2<f32>(269.5, 183.3)));
+
+    return fract(sin(value) * 43758.5453);
+}
+
+fn stochastic_tile(uv: vec2<f32>, vertex: vec2<f32>, weight: f32) -> StochasticTile {
+    let random = hash(vertex);
+    let angle = random.x * 6.2831853;
+    let rotation = mat2x2<f32>(cos(angle), sin(angle), -sin(angle), cos(angle));
+
+    return StochasticTile(rotation * uv + random, rotation, weight);
+}
+
+// Determines the three cells of the triangular grid, that overlap the uv, and their blend weights.
+fn stochastic_tiles(uv: vec2<f32>) -> array<StochasticTile, 3> {
+    let grid = uv * 3.4641016; // 2 * sqrt(3)
+    let skewed = vec2<f32>(grid.x, -0.57735027 * grid.x + 1.15470054 * grid.y);
+    let base = floor(skewed);
+    let fraction = fract(skewed);
+    let z = 1.0 - fraction.x - fraction.y;
+
+    var vertices: array<vec2<f32>, 3>;
+    var weights: vec3<f32>;
+
+    if (z > 0.0) {
+        vertices = array<vec2<f32>, 3>(base, base + vec2<f32>(0.0, 1.0), base + vec2<f32>(1.0, 0.0));
+        weights = vec3<f32>(z, fraction.y, fraction.x);
+    } else {
+        vertices = array<vec2<f32>, 3>(base + vec2<f32>(1.0), base + vec2<f32>(1.0, 0.0), base + vec2<f32>(0.0, 1.0));
+        weights = vec3<f32>(-z, 1.0 - fraction.y, 1.0 - fraction.x);
+    }
+
+    // sharpen the blend, to preserve the contrast of the textures
+    weights = pow(weights, vec3<f32>(4.0));
+    weights = weights / dot(weights, vec3<f32>(1.0));
+
+    return array<StochasticTile, 3>(
+        stochastic_tile(uv, vertices[0], weights.x),
+        stochastic_tile(uv, vertices[1], weights.y),
+        stochastic_tile(uv, vertices[2], weights.z),
+    );
+}
+
+// Samples and blends all layers, with a random offset and rotation per cell of a triangular grid.
+fn sample_layers_stochastic(position: vec2<f32>, weights: vec4<f32>) -> Layer {
+    var albedo = vec4<f32>(0.0);
+    var normal = vec3<f32>(0.0);
+    var roughness = 0.0;
+
+    for (var i = 0; i < 4; i = i + 1) {
+        let uv = position / material.layer_scales[i];
+        let ddx = dpdx(uv);
+        let ddy = dpdy(uv);
+        var tiles = stochastic_tiles(uv);
+
+        for (var j = 0; j < 3; j = j + 1) {
+            let tile = tiles[j];
+            let tile_ddx = tile.rotation * ddx;
+            let tile_ddy = tile.rotation * ddy;
+            let weight = weights[i] * tile.weight;
+
+            // rotate the tangent space normal back into the orientation of the layer
+            var tile_normal = textureSampleGrad(normal_texture, layer_sampler, tile.uv, i, tile_ddx, tile_ddy).xyz * 2.0 - 1.0;
+            tile_normal = vec3<f32>(transpose(tile.rotation) * tile_normal.xy, tile_normal.z);
+
+            albedo = albedo + textureSampleGrad(albedo_texture, layer_sampler, tile.uv, i, tile_ddx, tile_ddy) * weight;
+            normal = normal + tile_normal * weight;
+            roughness = roughness + textureSampleGrad(roughness_texture, layer_sampler, tile.uv, i, tile_ddx, tile_ddy).x * weight;
+        }
+    }
+
+    return Layer(albedo, normal, roughness);
+}
+
+// Samples and blends all layers by projecting the textures onto the plane.
+// All layers are sampled, because texture sampling requires uniform control flow.
+fn sample_layers(position: vec2<f32>, weights: vec4<f32>) -> Layer {
+    if ((material.flags & SPLAT_MATERIAL_FLAGS_STOCHASTIC) != 0u) {
+        return sample_layers_stochastic(position, weights);
+    }
+
+    var albedo = vec4<f32>(0.0);
+    var normal = vec3<f32>(0.0);
+    var roughness = 0.0;
+
+    for (var i = 0; i < 4; i = i + 1) {
+        let uv = position / material.layer_scales[i];
+
+        albedo = albedo + textureSample(albedo_texture, layer_sampler, uv, i) * weights[i];
+        normal = normal + (textureSample(normal_texture, layer_sampler, uv, i).xyz * 2.0 - 1.0) * weights[i];
+        roughness = roughness + textureSample(roughness_texture, layer_sampler, uv, i).x * weights[i];
+    }
+
+    return Layer(albedo, normal, roughness);
+}
+
+// Samples the layers from above and converts the tangent space normal into world space.
+fn sample_planar(position: vec3<f32>, world_normal: vec3<f32>, weights: vec4<f32>) -> Layer {
+    var layer = sample_layers(position.xz, weights);
+
+    // The texture space of the layers is aligned with the x and z axes of the terrain.
+    let tangent = normalize(cross(world_normal, vec3<f32>(0.0, 0.0, 1.0)));
+    let bitangent = cross(tangent, world_normal);
+    layer.normal = normalize(tangent * layer.normal.x + bitangent * layer.normal.y + world_normal * layer.normal.z);
+
+    return layer;
+}
+
+// Samples the layers along all three axes and blends them by the orientation of the surface.
+// The tangent space normals are combined with the surface normal using a whiteout blend.
+fn sample_triplanar(position: vec3<f32>, world_normal: vec3<f32>, weights: vec4<f32>) -> Layer {
+    var blend = pow(abs(world_normal), vec3<f32>(material.blend_sharpness));
+    blend = blend / dot(blend, vec3<f32>(1.0));
+
+    let x = sample_layers(position.zy, weights);
+    let y = sample_layers(position.xz, weights);
+    let z = sample_layers(position.xy, weights);
+
+    let normal_x = vec3<f32>(x.normal.xy + world_normal.zy, abs(x.normal.z) * world_normal.x).zyx;
+    let normal_y = vec3<f32>(y.normal.xy + world_normal.xz, abs(y.normal.z) * world_normal.y).xzy;
+    let normal_z = vec3<f32>(z.normal.xy + world_normal.xy, abs(z.normal.z) * world_normal.z);
+
+    let albedo = x.albedo * blend.x + y.albedo * blend.y + z.albedo * blend.z;
+    let normal = normalize(normal_x * blend.x + normal_y * blend.y + normal_z * blend.z);
+    let roughness = x.roughness * blend.x + y.roughness * blend.y + z.roughness * blend.z;
+
+    return Layer(albedo, normal, roughness);
+}
+
+// Determines how much of the range is covered by the value, with a smooth transition at its borders.
+fn range_coverage(value: f32, range: vec2<f32>, blend_width: f32) -> f32 {
+    let width = max(blend_width, 0.0001);
+
+    return smoothstep(range.x - width, range.x, value) * (1.0 - smoothstep(range.y, range.y + width, value));
+}
+
+// Generates the layer weights by layering the texturing rules on top of each other.
+fn rule_weights(height: f32, slope: f32) -> vec4<f32> {
+    var weights = vec4<f32>(0.0);
+
+    for (var i = 0u; i < material.rule_count; i = i + 1u) {
+        let rule = material.rules[i];
+        let coverage = range_coverage(height, rule.ranges.xy, rule.blend_width) *
+                       range_coverage(slope, rule.ranges.zw, rule.blend_width);
+
+        var layer = vec4<f32>(0.0);
+        layer[min(rule.layer, 3u)] = 1.0;
+
+        weights = mix(weights, layer, coverage);
+    }
+
+    return weights;
+}
+
+// Samples the attachments through the shard of the node atlas, which stores the atlas index.
+fn sample_splat(coords: vec2<f32>, atlas_index: i32, ddx: vec2<f32>, ddy: vec2<f32>) -> vec4<f32> {
+#ifdef ATLAS_SHARDS
+    return sample_sharded(
+        splat_atlas,
+        splat_atlas_1,
+#ifdef ATLAS_SHARD_2
+        splat_atlas_2,
+#endif
+#ifdef ATLAS_SHARD_3
+        splat_atlas_3,
+#endif
+        coords, atlas_index, ddx, ddy);
+#else
+    return sample_attachment(splat_atlas, coords, atlas_index, ddx, ddy);
+#endif
+}
+
+fn vertex_height(lookup: NodeLookup) -> f32 {
+    let height_coords = lookup.atlas_coords * config.height_scale + config.height_offset;
+    let height = sample_height(height_coords, lookup.atlas_index, vec2<f32>(0.0), vec2<f32>(0.0));
+
+    return height * config.height;
+}
+
+// The painted splat weights of the vertex, which custom shading can use,
+// e.g. to blend the extensions of the layers smoothly across the tiles.
+fn vertex_splat_weights(lookup: NodeLookup) -> vec4<f32> {
+    let splat_coords = lookup.atlas_coords * config.splat_scale + config.splat_offset;
+
+    return sample_splat(splat_coords, lookup.atlas_index, vec2<f32>(0.0), vec2<f32>(0.0));
+}
+
+fn lookup_fragment_data(input: FragmentInput, lookup: NodeLookup, ddx: vec2<f32>, ddy: vec2<f32>) -> FragmentData {
+    let atlas_lod = lookup.atlas_lod;
+    let atlas_index = lookup.atlas_index;
+    let atlas_coords = lookup.atlas_coords;
+    let ddx = ddx / f32(1u << atlas_lod);
+    let ddy = ddy / f32(1u << atlas_lod);
+
+    let height_coords = atlas_coords * config.height_scale + config.height_offset;
+    let height_ddx = ddx / config.height_size;
+    let height_ddy = ddy / config.height_size;
+    let splat_coords = atlas_coords * config.splat_scale + config.splat_offset;
+    let splat_ddx = ddx / config.splat_size;
+    let splat_ddy = ddy / config.splat_size;
+
+    let world_normal = calculate_normal(height_coords, atlas_index, atlas_lod, height_ddx, height_ddy);
+
+    var weights = sample_splat(splat_coords, atlas_index, splat_ddx, splat_ddy);
+
+    var debug_color = vec4<f32>(0.0);
+
+#ifdef SHOW_LOD
+    debug_color = show_lod(atlas_lod, input.terrain_position.xyz);
+#endif
+
+    return FragmentData(world_normal, weights, debug_color);
+}
+
+fn blend_fragment_data(data1: FragmentData, data2: FragmentData, blend_ratio: f32) -> FragmentData {
+    let world_normal = mix(data2.world_normal, data1.world_normal, blend_ratio);
+    let weights = mix(data2.weights, data1.weights, blend_ratio);
+    let debug_color = mix(data2.debug_color, data1.debug_color, blend_ratio);
+
+    return FragmentData(world_normal, weights, debug_color);
+}
+
+fn process_fragment(input: FragmentInput, data: FragmentData) -> Fragment {
+    let do_discard = input.local_position.x < 2.0 || input.local_position.x > config.terrain_extent.x - 2.0 ||
+                     input.local_position.y < 2.0 || input.local_position.y > config.terrain_extent.y - 2.0;
+
+    let world_normal = normalize(data.world_normal);
+    var weights = data.weights;
+
+    // The painted weights take precedence over the ones generated by the rules.
+    if (material.rule_count > 0u) {
+        let height = input.terrain_position.y / config.height;
+        let slope = 1.0 - abs(world_normal.y);
+        let painted = clamp(dot(weights, vec4<f32>(1.0)), 0.0, 1.0);
+
+        weights = mix(rule_weights(height, slope), weights, painted);
+    }
+
+    // Fall back to the first layer, where nothing has been painted.
+    let weight_sum = dot(weights, vec4<f32>(1.0));
+    if (weight_sum > 0.0001) {
+        weights = weights / weight_sum;
+    } else {
+        weights = vec4<f32>(1.0, 0.0, 0.0, 0.0);
+    }
+
+    var layer = sample_planar(input.terrain_position.xyz, world_normal, weights);
+
+    if ((material.flags & SPLAT_MATERIAL_FLAGS_TRIPLANAR) != 0u) {
+        let triplanar = sample_triplanar(input.terrain_position.xyz, world_normal, weights);
+
+        // fade to the triplanar projection just above the slope threshold
+        let slope = 1.0 - abs(world_normal.y);
+        let ratio = smoothstep(material.slope_threshold, material.slope_threshold + 0.1, slope);
+
+        layer.albedo = mix(layer.albedo, triplanar.albedo, ratio);
+        layer.normal = normalize(mix(layer.normal, triplanar.normal, ratio));
+        layer.roughness = mix(layer.roughness, triplanar.roughness, ratio);
+    }
+
+    var normal = layer.normal;
+    let roughness = layer.roughness;
+
+#ifdef DETAIL_LAYER
+    normal = apply_detail_normal(input.local_position, normal);
+#endif
+
+    var color = layer.albedo;
+
+#ifdef DECALS
+    color = apply_decals(input.local_position, color);
+#endif
+
+#ifdef SNOW
+    color = apply_snow(input, color);
+#endif
+
+    color = mix(color, vec4<f32>(data.debug_color.xyz, 1.0), data.debug_color.w * 0.4);
+    color = mix(color, vec4<f32>(input.debug_color.xyz, 1.0), input.debug_color.w);
+
+#ifdef LIGHTING
+    var pbr_input: PbrInput = pbr_input_new();
+    pbr_input.material.base_color = color;
+    pbr_input.material.perceptual_roughness = roughness;
+    pbr_input.material.reflectance = 0.1;
+    pbr_input.frag_coord = input.frag_coord;
+    pbr_input.world_position = input.world_position;
+    pbr_input.world_normal = planet_normal(input.local_position, normal);
+    pbr_input.is_orthographic = view.projection[3].w == 1.0;
+    pbr_input.N = pbr_input.world_normal;
+    pbr_input.V = calculate_view(input.world_position, pbr_input.is_orthographic);
+    pbr_input = extend_pbr_input(input, data, pbr_input);
+    color = pbr(pbr_input);
+#endif
+
+    color = extend_color(input, data, color);
+
+    return Fragment(color, do_discard);
+}
+
+#ifndef MINMAX
+#import bevy_terrain::vertex
+#else
+#import bevy_terrain::minmax
+#endif
+
+#import bevy_terrain::fragment
@@ -4,6 +4,11 @@
 // This will happen once or twice (lod fringe).
 // fn vertex_height(lookup: AtlasLookup) -> f32;
 
+// The function that evaluates the splat weights of the vertex, which are interpolated
+// into the `FragmentInput`. Materials without splat weights return zero.
+// This will happen once or twice (lod fringe).
+// fn vertex_splat_weights(lookup: AtlasLookup) -> vec4<f32>;
+
 // The default vertex entry point, which blends the height at the fringe between two lods.
 @vertex
 fn vertex(in: VertexInput) -> VertexOutput {
@@ -20,17 +25,41 @@ fn vertex(in: VertexInput) -> VertexOutput {
 
     let lookup = lookup_node(blend.lod, local_position);
     var height = vertex_height(lookup);
+    var splat_weights = vertex_splat_weights(lookup);
+
+#ifdef SNOW
+    // the snow attachment is declared by the shared fragment module
+    height = height + lookup_snow(lookup).x;
+#endif
 
     if (blend.ratio < 1.0) {
         let lookup2 = lookup_node(blend.lod + 1u, local_position);
-        let height2 = vertex_height(lookup2);
-        height      = mix(height2, height, blend.ratio);
+        var height2 = vertex_height(lookup2);
+
+#ifdef SNOW
+        height2 = height2 + lookup_snow(lookup2).x;
+#endif
+
+        height        = mix(height2, height, blend.ratio);
+        splat_weights = mix(vertex_splat_weights(lookup2), splat_weights, blend.ratio);
     }
 
+#ifdef DETAIL_LAYER
+    // the detail layer is declared by the shared fragment module
+    height = height + detail_displacement(local_position);
+#endif
+
+    height = height - calculate_skirt_offset(tile, grid_position);
+
     var output = vertex_output(local_position, height);
+    output.splat_weights = splat_weights;
+
+#ifdef MESH_MORPH
+    output.morph = calculate_morph(tile, world_position);
+#endif
 
 #ifdef SHOW_TILES
-    output.debug_color = show_tiles(tile, output.world_position);
+    output.debug_color = show_tiles(tile, output.terrain_position);
 #endif
 
 #ifdef SHOW_MINMAX_ERROR
@@ -14,6 +14,16 @@ struct TerrainViewConfig {
     blend_distance: f32,
     morph_range: f32,
     blend_range: f32,
+    view_position: vec4<f32>,
+    model: mat4x4<f32>,
+    camera_model: mat4x4<f32>,
+    morph_ranges: array<vec4<f32>, 4>,
+    clipmap_ring_count: u32,
+    clipmap_ring_size: u32,
+    seam_mode: u32,
+    skirt_depth: f32,
+    adaptive_refinement: f32,
+    occlusion_culling: u32,
 }
 
 struct Tile {
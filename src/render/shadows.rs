@@ -0,0 +1,354 @@
+//! Renders the terrain into the shadow cascades of the directional lights.
+//!
+//! The tiles of a view are refined around the camera and culled against its frustum, so the
+//! terrain behind the camera or outside of its LOD selection would not cast any shadows.
+//! Therefore each terrain view refines a separate, coarser tile list for each of its
+//! directional lights, which is only culled against the cascades of the light.
+//! This tile list is then drawn into all of the cascades of the light.
+
+use crate::{
+    render::{
+        compute_pipelines::TerrainComputePipelines,
+        culling::{create_culling_bind_group, planes, CullingData},
+        depth_pyramid::DepthPyramids,
+        terrain_view_data::{TerrainViewConfigUniform, TerrainViewData},
+        SHADOW_VIEW_LAYOUT,
+    },
+    terrain::TerrainComponents,
+    terrain_data::gpu_node_atlas::GpuNodeAtlas,
+    TerrainView, TerrainViewComponents, TerrainViewConfig,
+};
+use bevy::{
+    ecs::{
+        query::ROQueryItem,
+        system::{
+            lifetimeless::{Read, SRes},
+            SystemParamItem,
+        },
+    },
+    pbr::{LightEntity, ViewLightEntities},
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_phase::{PhaseItem, RenderCommand, RenderCommandResult, TrackedRenderPass},
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        view::{ExtractedView, ViewUniformOffset, ViewUniforms},
+        Extract,
+    },
+    utils::HashMap,
+};
+
+/// The coarse tile list of a terrain view, which is drawn into the cascades of a directional light.
+pub struct ShadowTiles {
+    pub(crate) view_data: TerrainViewData,
+    pub(crate) view_config_uniform: TerrainViewConfigUniform,
+    /// Culls the tiles against the cascades of the light, if it has any this frame.
+    pub(crate) culling_bind_group: Option<BindGroup>,
+}
+
+/// The shadow casting tiles of a terrain view for each of its directional lights.
+#[derive(Default)]
+pub struct TerrainShadowData {
+    pub(crate) lights: HashMap<Entity, ShadowTiles>,
+}
+
+/// Maps the shadow cascade views of this frame to their terrain view and directional light.
+#[derive(Resource)]
+pub struct TerrainShadowViews {
+    views: HashMap<Entity, (Entity, Entity)>,
+    view_layout: BindGroupLayout,
+    view_bind_group: Option<BindGroup>,
+}
+
+impl FromWorld for TerrainShadowViews {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+
+        Self {
+            views: default(),
+            view_layout: device.create_bind_group_layout(&SHADOW_VIEW_LAYOUT),
+            view_bind_group: None,
+        }
+    }
+}
+
+impl TerrainShadowViews {
+    /// Returns the tiles of the terrain, which are drawn into the shadow cascade view.
+    pub(crate) fn tiles<'a>(
+        &self,
+        shadow_data: &'a TerrainViewComponents<TerrainShadowData>,
+        terrain: Entity,
+        cascade_view: Entity,
+    ) -> Option<&'a ShadowTiles> {
+        let &(view, light) = self.views.get(&cascade_view)?;
+
+        shadow_data.get(&(terrain, view))?.lights.get(&light)
+    }
+}
+
+/// Computes the culling planes, which enclose all cascades of a directional light from the sides.
+///
+/// The shadow casters may lie anywhere towards the light, thus the tiles are not culled along
+/// its direction.
+fn cascade_planes(cascades: &[&ExtractedView], model: Mat4) -> (Mat4, [Vec4; 5]) {
+    // all cascades of a light share its orientation
+    let light_from_world = cascades[0].transform.compute_matrix().inverse();
+
+    let (min, max) = cascades
+        .iter()
+        .flat_map(|cascade| {
+            let light_from_ndc = light_from_world
+                * cascade.transform.compute_matrix()
+                * cascade.projection.inverse();
+
+            (0..8).map(move |corner| {
+                let ndc = Vec3::new(
+                    if corner & 1 == 0 { -1.0 } else { 1.0 },
+                    if corner & 2 == 0 { -1.0 } else { 1.0 },
+                    if corner & 4 == 0 { 0.0 } else { 1.0 },
+                );
+
+                light_from_ndc.project_point3(ndc)
+            })
+        })
+        .fold(
+            (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+            |(min, max), position| (min.min(position), max.max(position)),
+        );
+
+    let view_proj = Mat4::orthographic_rh(min.x, max.x, min.y, max.y, 0.0, 1.0) * light_from_world;
+
+    let mut planes = planes(&(view_proj.as_dmat4() * model.as_dmat4()).as_mat4());
+    planes[4] = Vec4::ZERO;
+
+    (view_proj, planes)
+}
+
+/// Creates and removes the shadow tiles of the terrain views, depending on the directional lights
+/// casting shadows.
+pub(crate) fn extract_terrain_shadows(
+    device: Res<RenderDevice>,
+    images: Res<RenderAssets<Image>>,
+    view_config_uniforms: Res<TerrainViewComponents<TerrainViewConfigUniform>>,
+    mut shadow_data: ResMut<TerrainViewComponents<TerrainShadowData>>,
+    view_configs: Extract<Res<TerrainViewComponents<TerrainViewConfig>>>,
+    light_query: Extract<Query<(Entity, &DirectionalLight, &ComputedVisibility)>>,
+) {
+    let lights = light_query
+        .iter()
+        .filter(|(_, light, visibility)| light.shadows_enabled && visibility.is_visible())
+        .map(|(light, _, _)| light)
+        .collect::<Vec<_>>();
+
+    // remove the data of views, that are no longer registered or do not cast shadows
+    shadow_data.0.retain(|key, _| {
+        view_configs
+            .get(key)
+            .map_or(false, |view_config| view_config.shadow_casting)
+    });
+
+    for (&(terrain, view), view_config) in &view_configs.0 {
+        let Some(view_config_uniform) = view_config_uniforms.get(&(terrain, view)) else {
+            continue;
+        };
+
+        // the quadtree texture is created by the corresponding gpu quadtree
+        if !view_config.shadow_casting || images.get(&view_config.quadtree_handle).is_none() {
+            continue;
+        }
+
+        let data = shadow_data.0.entry((terrain, view)).or_default();
+        data.lights.retain(|light, _| lights.contains(light));
+
+        for &light in &lights {
+            let tiles = data.lights.entry(light).or_insert_with(|| ShadowTiles {
+                view_data: TerrainViewData::new(&device, &images, view_config),
+                view_config_uniform: default(),
+                culling_bind_group: None,
+            });
+
+            tiles.view_config_uniform = view_config_uniform.shadow(view_config.shadow_lod_bias);
+        }
+    }
+}
+
+pub(crate) fn prepare_terrain_shadows(
+    queue: Res<RenderQueue>,
+    shadow_data: Res<TerrainViewComponents<TerrainShadowData>>,
+) {
+    for data in shadow_data.0.values() {
+        for tiles in data.lights.values() {
+            tiles.view_data.update(&queue, &tiles.view_config_uniform);
+        }
+    }
+}
+
+/// Culls the shadow tiles against the cascades of their lights, which are set up during the
+/// preparation of the lights.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn queue_terrain_shadow_culling(
+    device: Res<RenderDevice>,
+    compute_pipelines: Res<TerrainComputePipelines>,
+    view_uniforms: Res<ViewUniforms>,
+    gpu_node_atlases: Res<TerrainComponents<GpuNodeAtlas>>,
+    depth_pyramids: Res<DepthPyramids>,
+    mut shadow_views: ResMut<TerrainShadowViews>,
+    mut shadow_data: ResMut<TerrainViewComponents<TerrainShadowData>>,
+    view_query: Query<&ViewLightEntities, With<TerrainView>>,
+    cascade_query: Query<(&ExtractedView, &LightEntity)>,
+) {
+    let shadow_views = &mut *shadow_views;
+
+    shadow_views.views.clear();
+    shadow_views.view_bind_group = view_uniforms.uniforms.binding().map(|binding| {
+        device.create_bind_group(&BindGroupDescriptor {
+            label: "terrain_shadow_view_bind_group".into(),
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: binding,
+            }],
+            layout: &shadow_views.view_layout,
+        })
+    });
+
+    for (&(terrain, view), data) in &mut shadow_data.0 {
+        for tiles in data.lights.values_mut() {
+            tiles.culling_bind_group = None;
+        }
+
+        let (Ok(view_lights), Some(gpu_node_atlas)) =
+            (view_query.get(view), gpu_node_atlases.get(&terrain))
+        else {
+            continue;
+        };
+
+        let mut cascades = HashMap::<Entity, Vec<(Entity, &ExtractedView)>>::default();
+
+        for &cascade_view in &view_lights.lights {
+            if let Ok((extracted_view, LightEntity::Directional { light_entity, .. })) =
+                cascade_query.get(cascade_view)
+            {
+                cascades
+                    .entry(*light_entity)
+                    .or_default()
+                    .push((cascade_view, extracted_view));
+            }
+        }
+
+        for (light, cascades) in cascades {
+            let Some(tiles) = data.lights.get_mut(&light) else {
+                continue;
+            };
+
+            let model = tiles.view_config_uniform.model;
+            let extracted_views = cascades
+                .iter()
+                .map(|&(_, extracted_view)| extracted_view)
+                .collect::<Vec<_>>();
+            let (view_proj, planes) = cascade_planes(&extracted_views, model);
+
+            let culling_data = CullingData {
+                world_position: tiles.view_config_uniform.view_position,
+                view_proj,
+                model,
+                planes,
+                ..default()
+            };
+
+            tiles.culling_bind_group = Some(create_culling_bind_group(
+                &device,
+                &compute_pipelines,
+                &culling_data,
+                gpu_node_atlas,
+                &depth_pyramids.fallback_view,
+            ));
+
+            shadow_views.views.extend(
+                cascades
+                    .iter()
+                    .map(|&(cascade_view, _)| (cascade_view, (view, light))),
+            );
+        }
+    }
+}
+
+pub struct SetTerrainShadowViewBindGroup<const I: usize>;
+
+impl<const I: usize, P: PhaseItem> RenderCommand<P> for SetTerrainShadowViewBindGroup<I> {
+    type Param = SRes<TerrainShadowViews>;
+    type ViewWorldQuery = Read<ViewUniformOffset>;
+    type ItemWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        _: &P,
+        view_uniform: ROQueryItem<'w, Self::ViewWorldQuery>,
+        _: ROQueryItem<'w, Self::ItemWorldQuery>,
+        shadow_views: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(view_bind_group) = &shadow_views.into_inner().view_bind_group else {
+            return RenderCommandResult::Failure;
+        };
+
+        pass.set_bind_group(I, view_bind_group, &[view_uniform.offset]);
+        RenderCommandResult::Success
+    }
+}
+
+pub struct SetTerrainShadowTilesBindGroup<const I: usize>;
+
+impl<const I: usize, P: PhaseItem> RenderCommand<P> for SetTerrainShadowTilesBindGroup<I> {
+    type Param = (
+        SRes<TerrainShadowViews>,
+        SRes<TerrainViewComponents<TerrainShadowData>>,
+    );
+    type ViewWorldQuery = Entity;
+    type ItemWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        cascade_view: ROQueryItem<'w, Self::ViewWorldQuery>,
+        _: ROQueryItem<'w, Self::ItemWorldQuery>,
+        (shadow_views, shadow_data): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(tiles) = shadow_views.tiles(shadow_data.into_inner(), item.entity(), cascade_view)
+        else {
+            return RenderCommandResult::Failure;
+        };
+
+        pass.set_bind_group(I, &tiles.view_data.terrain_view_bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
+pub(crate) struct DrawTerrainShadowCommand;
+
+impl<P: PhaseItem> RenderCommand<P> for DrawTerrainShadowCommand {
+    type Param = (
+        SRes<TerrainShadowViews>,
+        SRes<TerrainViewComponents<TerrainShadowData>>,
+    );
+    type ViewWorldQuery = Entity;
+    type ItemWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        cascade_view: ROQueryItem<'w, Self::ViewWorldQuery>,
+        _: ROQueryItem<'w, Self::ItemWorldQuery>,
+        (shadow_views, shadow_data): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(tiles) = shadow_views.tiles(shadow_data.into_inner(), item.entity(), cascade_view)
+        else {
+            return RenderCommandResult::Failure;
+        };
+
+        pass.draw_indirect(&tiles.view_data.indirect_buffer, 0);
+        RenderCommandResult::Success
+    }
+}
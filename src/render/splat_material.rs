@@ -0,0 +1,219 @@
+//! A built-in terrain material, that blends multiple texture layers using a splatmap.
+//!
+//! The splatmap is a regular terrain attachment, which stores the weight of each layer in
+//! one of its channels and is streamed per node like any other attachment.
+//! Use it together with the [`TerrainMaterialPlugin`](crate::render::render_pipeline::TerrainMaterialPlugin).
+
+use crate::{
+    render::shaders::SPLAT_SHADER,
+    terrain_data::{AttachmentConfig, AttachmentFormat, AttachmentIndex},
+};
+use bevy::{
+    prelude::*,
+    reflect::TypeUuid,
+    render::{render_asset::RenderAssets, render_resource::*},
+};
+
+/// The index of the splatmap attachment, which has to be added directly after the base attachments.
+pub const SPLAT_ATTACHMENT: AttachmentIndex = 2;
+/// The maximum number of texturing rules of a splat material.
+pub const MAX_TEXTURING_RULES: usize = 8;
+
+bitflags::bitflags! {
+#[repr(transparent)]
+pub struct SplatMaterialFlags: u32 {
+    const NONE       = 0;
+    const TRIPLANAR  = (1 << 0);
+    const STOCHASTIC = (1 << 1);
+}
+}
+
+/// Configures the triplanar projection of the layer textures onto steep slopes.
+#[derive(Clone, Copy)]
+pub struct Triplanar {
+    /// The steepness (from zero for flat to one for vertical), above which the textures
+    /// are projected triplanar instead of from above.
+    pub slope_threshold: f32,
+    /// The exponent used to sharpen the blend between the three projections.
+    pub blend_sharpness: f32,
+}
+
+impl Default for Triplanar {
+    fn default() -> Self {
+        Self {
+            slope_threshold: 0.3,
+            blend_sharpness: 4.0,
+        }
+    }
+}
+
+/// A rule, that procedurally assigns a layer to the terrain inside of a height and slope range.
+#[derive(Clone, Copy)]
+pub struct TexturingRule {
+    /// The layer assigned by the rule.
+    pub layer: u32,
+    /// The minimum and maximum height, relative to the height of the terrain (from zero to one).
+    pub height: Vec2,
+    /// The minimum and maximum steepness (from zero for flat to one for vertical).
+    pub slope: Vec2,
+    /// The width of the transition at the borders of both ranges.
+    pub blend_width: f32,
+}
+
+impl TexturingRule {
+    /// Creates a new rule, that assigns the layer everywhere.
+    pub fn new(layer: u32) -> Self {
+        Self {
+            layer,
+            height: Vec2::new(0.0, 1.0),
+            slope: Vec2::new(0.0, 1.0),
+            blend_width: 0.05,
+        }
+    }
+
+    /// Restricts the rule to the height range.
+    pub fn with_height(mut self, min: f32, max: f32) -> Self {
+        self.height = Vec2::new(min, max);
+        self
+    }
+
+    /// Restricts the rule to the slope range.
+    pub fn with_slope(mut self, min: f32, max: f32) -> Self {
+        self.slope = Vec2::new(min, max);
+        self
+    }
+}
+
+#[derive(Clone, Copy, Default, ShaderType)]
+struct TexturingRuleUniform {
+    ranges: Vec4,
+    layer: u32,
+    blend_width: f32,
+}
+
+impl From<&TexturingRule> for TexturingRuleUniform {
+    fn from(rule: &TexturingRule) -> Self {
+        Self {
+            ranges: Vec4::new(rule.height.x, rule.height.y, rule.slope.x, rule.slope.y),
+            layer: rule.layer,
+            blend_width: rule.blend_width,
+        }
+    }
+}
+
+/// The splat material data that is available in shaders.
+#[derive(Clone, Default, ShaderType)]
+pub struct SplatMaterialUniform {
+    layer_scales: Vec4,
+    flags: u32,
+    slope_threshold: f32,
+    blend_sharpness: f32,
+    rules: [TexturingRuleUniform; MAX_TEXTURING_RULES],
+    rule_count: u32,
+}
+
+/// A terrain material, that blends four texture layers according to the weights of the splatmap.
+///
+/// Every texture is an array texture with one layer per splatmap channel.
+// Todo: support more than four layers by using multiple splatmaps
+#[derive(AsBindGroup, TypeUuid, Clone)]
+#[uuid = "a4b3d2d7-5e42-4b8e-9c61-7d1f0c2e8b35"]
+#[uniform(0, SplatMaterialUniform)]
+pub struct SplatMaterial {
+    /// The world space size of one repetition of the textures of each layer.
+    pub layer_scales: Vec4,
+    /// Enables the triplanar projection of the textures onto steep slopes,
+    /// which prevents them from stretching on cliffs.
+    pub triplanar: Option<Triplanar>,
+    /// Enables stochastic sampling, which hides the repetition of the textures at a distance.
+    ///
+    /// Each cell of a triangular grid samples the textures with a random offset and rotation,
+    /// which are blended across the cell borders.
+    pub stochastic: bool,
+    /// The rules used to generate the layer weights from the height and slope of the terrain.
+    ///
+    /// Later rules are layered on top of earlier ones and the weights painted into the splatmap
+    /// take precedence over the generated ones.
+    /// Only the first [`MAX_TEXTURING_RULES`] rules are used.
+    pub rules: Vec<TexturingRule>,
+    /// The albedo textures of the layers.
+    #[texture(1, dimension = "2d_array")]
+    #[sampler(2)]
+    pub albedo_texture: Handle<Image>,
+    /// The tangent space normal maps of the layers, which have to be stored in a linear format.
+    #[texture(3, dimension = "2d_array")]
+    pub normal_texture: Handle<Image>,
+    /// The roughness of the layers, stored in the red channel.
+    #[texture(4, dimension = "2d_array")]
+    pub roughness_texture: Handle<Image>,
+}
+
+impl SplatMaterial {
+    /// Creates a new splat material from the layer textures, which repeat every ten units.
+    pub fn new(
+        albedo_texture: Handle<Image>,
+        normal_texture: Handle<Image>,
+        roughness_texture: Handle<Image>,
+    ) -> Self {
+        Self {
+            layer_scales: Vec4::splat(10.0),
+            triplanar: None,
+            stochastic: false,
+            rules: Vec::new(),
+            albedo_texture,
+            normal_texture,
+            roughness_texture,
+        }
+    }
+
+    /// Creates the config of the splatmap attachment, with one layer weight per channel.
+    pub fn attachment(texture_size: u32, mip_level_count: u32) -> AttachmentConfig {
+        AttachmentConfig::new(
+            "splat".to_string(),
+            texture_size,
+            1,
+            mip_level_count,
+            AttachmentFormat::Rgba8Linear,
+        )
+    }
+}
+
+impl AsBindGroupShaderType<SplatMaterialUniform> for SplatMaterial {
+    fn as_bind_group_shader_type(&self, _images: &RenderAssets<Image>) -> SplatMaterialUniform {
+        let mut flags = SplatMaterialFlags::NONE;
+        let triplanar = self.triplanar.unwrap_or_default();
+
+        if self.triplanar.is_some() {
+            flags |= SplatMaterialFlags::TRIPLANAR;
+        }
+        if self.stochastic {
+            flags |= SplatMaterialFlags::STOCHASTIC;
+        }
+
+        let mut rules = [TexturingRuleUniform::default(); MAX_TEXTURING_RULES];
+
+        for (uniform, rule) in rules.iter_mut().zip(&self.rules) {
+            *uniform = rule.into();
+        }
+
+        SplatMaterialUniform {
+            layer_scales: self.layer_scales,
+            flags: flags.bits(),
+            slope_threshold: triplanar.slope_threshold,
+            blend_sharpness: triplanar.blend_sharpness,
+            rules,
+            rule_count: self.rules.len().min(MAX_TEXTURING_RULES) as u32,
+        }
+    }
+}
+
+impl Material for SplatMaterial {
+    // the vertex stage interpolates the splat weights as well
+    fn vertex_shader() -> ShaderRef {
+        SPLAT_SHADER.typed().into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        SPLAT_SHADER.typed().into()
+    }
+}
@@ -1,6 +1,14 @@
 use crate::{
-    render::TERRAIN_CONFIG_SIZE,
+    render::{
+        decals::{DecalFallback, DECAL_BINDING},
+        detail_layer::{DetailLayerFallback, DETAIL_LAYER_BINDING},
+        render_pipeline::TerrainPipelineConfig,
+        DETAIL_LAYER_SIZE, TERRAIN_CONFIG_SIZE,
+    },
     terrain::{Terrain, TerrainComponents},
+    terrain_data::{
+        gpu_node_atlas::GpuNodeAtlas, AttachmentIndex, MAX_ATLAS_SHARDS, MAX_ATTACHMENT_COUNT,
+    },
     TerrainConfig,
 };
 use bevy::{
@@ -14,11 +22,84 @@ use bevy::{
         render_phase::{PhaseItem, RenderCommand, RenderCommandResult, TrackedRenderPass},
         render_resource::*,
         renderer::RenderDevice,
+        settings::WgpuLimits,
         Extract,
     },
 };
 use std::num::NonZeroU8;
 
+/// The binding of the second shard of the first attachment.
+///
+/// The additional shards of the attachments are bound after the detail layer.
+pub(crate) const ATLAS_SHARD_BINDING: u32 = DETAIL_LAYER_BINDING + 2;
+
+/// Returns the binding of the additional shard (starting with one) of the attachment.
+pub(crate) fn atlas_shard_binding(attachment_index: AttachmentIndex, shard: usize) -> u32 {
+    ATLAS_SHARD_BINDING + ((shard - 1) * MAX_ATTACHMENT_COUNT + attachment_index) as u32
+}
+
+/// Returns the shader defs, which allow the shaders to bind and index the shards of the attachments.
+///
+/// E.g. `ATTACHMENT_3_SHARD_1_BINDING` is the binding of the second shard of the fourth attachment.
+/// The third and fourth shard are only bound, if `ATLAS_SHARD_2` and `ATLAS_SHARD_3` are defined.
+pub(crate) fn atlas_shard_shader_defs(
+    attachment_count: usize,
+    atlas_shard_size: Option<u32>,
+    atlas_shard_count: usize,
+) -> Vec<ShaderDefVal> {
+    let Some(atlas_shard_size) = atlas_shard_size else {
+        return Vec::new();
+    };
+
+    let mut shader_defs = vec![
+        "ATLAS_SHARDS".into(),
+        ShaderDefVal::UInt("ATLAS_SHARD_SIZE".to_string(), atlas_shard_size),
+    ];
+
+    shader_defs.extend((2..atlas_shard_count).map(|shard| format!("ATLAS_SHARD_{shard}").into()));
+
+    for attachment_index in 0..attachment_count {
+        for shard in 1..atlas_shard_count {
+            shader_defs.push(ShaderDefVal::UInt(
+                format!("ATTACHMENT_{attachment_index}_SHARD_{shard}_BINDING"),
+                atlas_shard_binding(attachment_index, shard),
+            ));
+        }
+    }
+
+    shader_defs
+}
+
+/// Returns the amount of textures of the terrain layout, which are sampled per shader stage.
+pub(crate) fn sampled_texture_count(
+    attachment_count: usize,
+    decals: bool,
+    detail_layer: bool,
+    atlas_shard_count: usize,
+) -> u32 {
+    (attachment_count * atlas_shard_count) as u32 + decals as u32 + detail_layer as u32
+}
+
+/// Returns the largest amount of shards (at most [`MAX_ATLAS_SHARDS`]) of each attachment,
+/// whose textures the device can bind per shader stage.
+///
+/// The storage textures are not limited by the shard count,
+/// since the node generators only write to a single shard at a time.
+pub(crate) fn max_atlas_shard_count(
+    limits: &WgpuLimits,
+    attachment_count: usize,
+    decals: bool,
+    detail_layer: bool,
+) -> usize {
+    (1..=MAX_ATLAS_SHARDS)
+        .rev()
+        .find(|&atlas_shard_count| {
+            sampled_texture_count(attachment_count, decals, detail_layer, atlas_shard_count)
+                <= limits.max_sampled_textures_per_shader_stage
+        })
+        .unwrap_or(1)
+}
+
 /// The terrain config data that is available in shaders.
 #[derive(Clone, Default, ShaderType)]
 pub(crate) struct TerrainConfigUniform {
@@ -26,17 +107,30 @@ pub(crate) struct TerrainConfigUniform {
     height: f32,
     chunk_size: u32,
     terrain_size: u32,
-    attachment_sizes: Vec4,
-    attachment_scales: Vec4,
-    attachment_offsets: Vec4,
+    attachment_sizes: [Vec4; MAX_ATTACHMENT_COUNT / 4],
+    attachment_scales: [Vec4; MAX_ATTACHMENT_COUNT / 4],
+    attachment_offsets: [Vec4; MAX_ATTACHMENT_COUNT / 4],
+    terrain_extent: Vec2,
+    planet_radius: f32,
+}
+
+/// Packs the values of all attachments into vectors.
+fn pack(values: [f32; MAX_ATTACHMENT_COUNT]) -> [Vec4; MAX_ATTACHMENT_COUNT / 4] {
+    let mut vectors = [Vec4::ZERO; MAX_ATTACHMENT_COUNT / 4];
+
+    for (vector, values) in vectors.iter_mut().zip(values.chunks_exact(4)) {
+        *vector = Vec4::from_slice(values);
+    }
+
+    vectors
 }
 
 impl From<&TerrainConfig> for TerrainConfigUniform {
     fn from(config: &TerrainConfig) -> Self {
-        // Todo: figure out a better way to store data for more than four attachments
-        let mut sizes = [0.0; 4];
-        let mut scales = [1.0; 4];
-        let mut offsets = [0.0; 4];
+        // the values of four attachments are packed into each vector
+        let mut sizes = [0.0; MAX_ATTACHMENT_COUNT];
+        let mut scales = [1.0; MAX_ATTACHMENT_COUNT];
+        let mut offsets = [0.0; MAX_ATTACHMENT_COUNT];
 
         for (i, attachment) in config.attachments.iter().enumerate() {
             sizes[i] = attachment.texture_size as f32;
@@ -49,9 +143,11 @@ impl From<&TerrainConfig> for TerrainConfigUniform {
             height: config.height,
             chunk_size: config.leaf_node_size,
             terrain_size: config.terrain_size,
-            attachment_sizes: Vec4::from_array(sizes),
-            attachment_scales: Vec4::from_array(scales),
-            attachment_offsets: Vec4::from_array(offsets),
+            attachment_sizes: pack(sizes),
+            attachment_scales: pack(scales),
+            attachment_offsets: pack(offsets),
+            terrain_extent: config.terrain_extent.as_vec2(),
+            planet_radius: config.planet_radius.unwrap_or(0.0),
         }
     }
 }
@@ -59,6 +155,9 @@ impl From<&TerrainConfig> for TerrainConfigUniform {
 pub fn terrain_bind_group_layout(
     device: &RenderDevice,
     attachment_count: usize,
+    atlas_shard_count: usize,
+    decals: bool,
+    detail_layer: bool,
 ) -> BindGroupLayout {
     let mut entries = vec![
         BindGroupLayoutEntry {
@@ -90,6 +189,72 @@ pub fn terrain_bind_group_layout(
         count: None,
     }));
 
+    // the additional shards are bound after all other bindings
+    entries.extend((0..attachment_count).flat_map(|attachment_index| {
+        (1..atlas_shard_count).map(move |shard| BindGroupLayoutEntry {
+            binding: atlas_shard_binding(attachment_index, shard),
+            visibility: ShaderStages::all(),
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension: TextureViewDimension::D2Array,
+                multisampled: false,
+            },
+            count: None,
+        })
+    }));
+
+    // the decals are bound after all possible attachments
+    if decals {
+        entries.extend([
+            BindGroupLayoutEntry {
+                binding: DECAL_BINDING,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: DECAL_BINDING + 1,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::D2Array,
+                    multisampled: false,
+                },
+                count: None,
+            },
+        ]);
+    }
+
+    // the detail layer is bound after the decals and displaces the vertices as well
+    if detail_layer {
+        entries.extend([
+            BindGroupLayoutEntry {
+                binding: DETAIL_LAYER_BINDING,
+                visibility: ShaderStages::VERTEX_FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(DETAIL_LAYER_SIZE),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: DETAIL_LAYER_BINDING + 1,
+                visibility: ShaderStages::VERTEX_FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+        ]);
+    }
+
     device.create_bind_group_layout(&BindGroupLayoutDescriptor {
         label: "terrain_layout".into(),
         entries: &entries,
@@ -98,6 +263,19 @@ pub fn terrain_bind_group_layout(
 
 pub struct TerrainData {
     pub(crate) terrain_bind_group: BindGroup,
+    config_buffer: Buffer,
+    sampler: Sampler,
+    /// The shards of each attachment.
+    attachments: Vec<Vec<Handle<Image>>>,
+    /// The decal buffer and texture view, which are bound to the bind group, if decals are enabled.
+    decals: Option<(Buffer, TextureView)>,
+    /// The decal texture, which is bound to the bind group, if decals are enabled.
+    pub(crate) decal_texture: Option<Handle<Image>>,
+    /// The detail layer buffer and texture view, which are bound to the bind group,
+    /// if the detail layer is enabled.
+    detail_layer: Option<(Buffer, TextureView)>,
+    /// The detail texture, which is bound to the bind group, if the detail layer is enabled.
+    pub(crate) detail_texture: Option<TextureId>,
 }
 
 impl TerrainData {
@@ -105,9 +283,10 @@ impl TerrainData {
         device: &RenderDevice,
         images: &RenderAssets<Image>,
         config: &TerrainConfig,
+        gpu_node_atlas: &GpuNodeAtlas,
+        decal_fallback: Option<&DecalFallback>,
+        detail_layer_fallback: Option<&DetailLayerFallback>,
     ) -> Self {
-        let layout = terrain_bind_group_layout(device, config.attachments.len());
-
         let mut buffer = encase::UniformBuffer::new(Vec::new());
         buffer.write(&TerrainConfigUniform::from(config)).unwrap();
 
@@ -130,50 +309,200 @@ impl TerrainData {
 
         let sampler = device.create_sampler(&sampler_descriptor);
 
-        let mut entries = vec![
-            BindGroupEntry {
-                binding: 0,
-                resource: config_buffer.as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 1,
-                resource: BindingResource::Sampler(&sampler),
-            },
-        ];
+        let attachments = gpu_node_atlas.attachments.clone();
+
+        let decals =
+            decal_fallback.map(|fallback| (fallback.buffer.clone(), fallback.texture_view.clone()));
+        let detail_layer = detail_layer_fallback
+            .map(|fallback| (fallback.buffer.clone(), fallback.texture_view.clone()));
+
+        let terrain_bind_group = create_bind_group(
+            device,
+            images,
+            &config_buffer,
+            &sampler,
+            &attachments,
+            decals.as_ref(),
+            detail_layer.as_ref(),
+        );
 
-        entries.extend(
-            config
-                .attachments
-                .iter()
-                .enumerate()
-                .map(|(binding, attachment)| {
-                    let attachment = images.get(&attachment.handle).unwrap();
+        Self {
+            terrain_bind_group,
+            config_buffer,
+            sampler,
+            attachments,
+            decals,
+            decal_texture: None,
+            detail_layer,
+            detail_texture: None,
+        }
+    }
+
+    /// Recreates the bind group with the decals of the terrain.
+    pub(crate) fn bind_decals(
+        &mut self,
+        device: &RenderDevice,
+        images: &RenderAssets<Image>,
+        decal_buffer: &Buffer,
+        decal_texture: &Handle<Image>,
+        decal_view: &TextureView,
+    ) {
+        self.decals = Some((decal_buffer.clone(), decal_view.clone()));
+        self.decal_texture = Some(decal_texture.clone());
+        self.recreate_bind_group(device, images);
+    }
+
+    /// Recreates the bind group with the detail layer of the terrain.
+    pub(crate) fn bind_detail_layer(
+        &mut self,
+        device: &RenderDevice,
+        images: &RenderAssets<Image>,
+        detail_buffer: &Buffer,
+        detail_texture: &Texture,
+        detail_view: &TextureView,
+    ) {
+        self.detail_layer = Some((detail_buffer.clone(), detail_view.clone()));
+        self.detail_texture = Some(detail_texture.id());
+        self.recreate_bind_group(device, images);
+    }
+
+    pub(crate) fn recreate_bind_group(
+        &mut self,
+        device: &RenderDevice,
+        images: &RenderAssets<Image>,
+    ) {
+        self.terrain_bind_group = create_bind_group(
+            device,
+            images,
+            &self.config_buffer,
+            &self.sampler,
+            &self.attachments,
+            self.decals.as_ref(),
+            self.detail_layer.as_ref(),
+        );
+    }
+}
+
+fn create_bind_group(
+    device: &RenderDevice,
+    images: &RenderAssets<Image>,
+    config_buffer: &Buffer,
+    sampler: &Sampler,
+    attachments: &[Vec<Handle<Image>>],
+    decals: Option<&(Buffer, TextureView)>,
+    detail_layer: Option<&(Buffer, TextureView)>,
+) -> BindGroup {
+    let atlas_shard_count = attachments.iter().map(Vec::len).max().unwrap_or(1);
+
+    let layout = terrain_bind_group_layout(
+        device,
+        attachments.len(),
+        atlas_shard_count,
+        decals.is_some(),
+        detail_layer.is_some(),
+    );
+
+    let mut entries = vec![
+        BindGroupEntry {
+            binding: 0,
+            resource: config_buffer.as_entire_binding(),
+        },
+        BindGroupEntry {
+            binding: 1,
+            resource: BindingResource::Sampler(sampler),
+        },
+    ];
+
+    entries.extend(attachments.iter().enumerate().map(|(binding, shards)| {
+        let attachment = images.get(&shards[0]).unwrap();
+
+        BindGroupEntry {
+            binding: binding as u32 + 2,
+            resource: BindingResource::TextureView(&attachment.texture_view),
+        }
+    }));
+
+    entries.extend(
+        attachments
+            .iter()
+            .enumerate()
+            .flat_map(|(attachment_index, shards)| {
+                (1..atlas_shard_count).map(move |shard| {
+                    // the shards, which are not allocated yet, are never sampled
+                    let attachment = shards
+                        .get(shard)
+                        .and_then(|handle| images.get(handle))
+                        .unwrap_or_else(|| images.get(&shards[0]).unwrap());
 
                     BindGroupEntry {
-                        binding: binding as u32 + 2,
+                        binding: atlas_shard_binding(attachment_index, shard),
                         resource: BindingResource::TextureView(&attachment.texture_view),
                     }
-                }),
-        );
+                })
+            }),
+    );
 
-        let terrain_bind_group = device.create_bind_group(&BindGroupDescriptor {
-            label: "terrain_bind_group".into(),
-            entries: &entries,
-            layout: &layout,
-        });
+    if let Some((decal_buffer, decal_view)) = decals {
+        entries.extend([
+            BindGroupEntry {
+                binding: DECAL_BINDING,
+                resource: decal_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: DECAL_BINDING + 1,
+                resource: BindingResource::TextureView(decal_view),
+            },
+        ]);
+    }
 
-        Self { terrain_bind_group }
+    if let Some((detail_buffer, detail_view)) = detail_layer {
+        entries.extend([
+            BindGroupEntry {
+                binding: DETAIL_LAYER_BINDING,
+                resource: detail_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: DETAIL_LAYER_BINDING + 1,
+                resource: BindingResource::TextureView(detail_view),
+            },
+        ]);
     }
+
+    device.create_bind_group(&BindGroupDescriptor {
+        label: "terrain_bind_group".into(),
+        entries: &entries,
+        layout: &layout,
+    })
 }
 
 pub(crate) fn initialize_terrain_data(
     device: Res<RenderDevice>,
     images: Res<RenderAssets<Image>>,
+    pipeline_config: Res<TerrainPipelineConfig>,
+    decal_fallback: Res<DecalFallback>,
+    detail_layer_fallback: Res<DetailLayerFallback>,
+    gpu_node_atlases: Res<TerrainComponents<GpuNodeAtlas>>,
     mut terrain_data: ResMut<TerrainComponents<TerrainData>>,
     terrain_query: Extract<Query<(Entity, &TerrainConfig), Added<Terrain>>>,
 ) {
+    // the terrains without decals bind the empty fallback, until their decal texture is loaded
+    let decal_fallback = pipeline_config.decals.then_some(&*decal_fallback);
+    let detail_layer_fallback = pipeline_config
+        .detail_layer
+        .then_some(&*detail_layer_fallback);
+
     for (terrain, config) in terrain_query.iter() {
-        terrain_data.insert(terrain, TerrainData::new(&device, &images, config));
+        terrain_data.insert(
+            terrain,
+            TerrainData::new(
+                &device,
+                &images,
+                config,
+                gpu_node_atlases.get(&terrain).unwrap(),
+                decal_fallback,
+                detail_layer_fallback,
+            ),
+        );
     }
 }
 
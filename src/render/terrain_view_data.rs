@@ -3,8 +3,8 @@ use crate::{
         INDIRECT_BUFFER_SIZE, PARAMETER_BUFFER_SIZE, PREPARE_INDIRECT_LAYOUT, REFINE_TILES_LAYOUT,
         TERRAIN_VIEW_CONFIG_SIZE, TERRAIN_VIEW_LAYOUT, TILE_SIZE,
     },
-    terrain::{Terrain, TerrainConfig},
-    terrain_view::{TerrainView, TerrainViewConfig},
+    terrain::{terrain_to_camera, world_to_terrain, SeamGeometry, TerrainConfig, TerrainGeometry},
+    terrain_view::{projection_scale, TerrainView, TerrainViewConfig, MAX_MORPH_LODS},
     TerrainViewComponents,
 };
 use bevy::{
@@ -36,11 +36,75 @@ pub(crate) struct TerrainViewConfigUniform {
     blend_distance: f32,
     morph_range: f32,
     blend_range: f32,
+    /// The position of the viewer in the local space of the terrain.
+    pub(crate) view_position: Vec4,
+    /// The transform of the terrain from local to world space.
+    pub(crate) model: Mat4,
+    /// The transform of the terrain from local to world space, relative to the viewer.
+    pub(crate) camera_model: Mat4,
+    /// The morph ranges of the lods, packed into vectors of four.
+    morph_ranges: [Vec4; MAX_MORPH_LODS / 4],
+    /// The count of clipmap rings, or zero if the terrain is refined like a quadtree.
+    pub(crate) clipmap_ring_count: u32,
+    /// The amount of tiles from the center to the edge of each clipmap ring.
+    pub(crate) clipmap_ring_size: u32,
+    /// The geometry, which hides the cracks between the tiles (none, skirts or stitches).
+    seam_mode: u32,
+    /// The depth of the skirts (measured in multiples of the tile size).
+    skirt_depth: f32,
+    adaptive_refinement: f32,
+    /// Whether the tiles are culled using the depth pyramid of the view.
+    pub(crate) occlusion_culling: u32,
 }
 
 impl TerrainViewConfigUniform {
-    fn new(config: &TerrainConfig, view_config: &TerrainViewConfig) -> Self {
-        let view_distance = view_config.view_distance * config.leaf_node_size as f32;
+    fn new(
+        config: &TerrainConfig,
+        view_config: &TerrainViewConfig,
+        terrain_transform: &GlobalTransform,
+        view_transform: &GlobalTransform,
+        projection_scale: Option<f32>,
+    ) -> Self {
+        let (view_distance, _) = view_config.lod_distances(config.leaf_node_size, projection_scale);
+        let view_distance = view_distance * config.leaf_node_size as f32;
+
+        let (clipmap_ring_count, clipmap_ring_size) = match config.geometry {
+            TerrainGeometry::Quadtree => (0, 0),
+            TerrainGeometry::Clipmap {
+                ring_count,
+                ring_size,
+            } => (ring_count, ring_size.max(4)),
+        };
+
+        let (seam_mode, skirt_depth) = match config.seams {
+            SeamGeometry::None => (0, 0.0),
+            SeamGeometry::Skirts { depth } => (1, depth),
+            SeamGeometry::Stitches => (2, 0.0),
+        };
+
+        // the skirts add an outer ring of vertices to the grid of each tile
+        let mesh_size = match config.seams {
+            SeamGeometry::Skirts { .. } => view_config.grid_size + 2,
+            _ => view_config.grid_size,
+        };
+
+        // the tiles of a clipmap ring are fully morphed into the ones of the next ring at its edge,
+        // while keeping a margin of one tile for the snapping of the rings
+        let morph_distance = match config.geometry {
+            TerrainGeometry::Quadtree => {
+                view_distance / 2.0_f32.powf(view_config.additional_refinement as f32 + 1.0)
+            }
+            TerrainGeometry::Clipmap { .. } => {
+                (clipmap_ring_size - 1) as f32 * view_config.tile_scale / 2.0
+            }
+        };
+        let view_position = world_to_terrain(terrain_transform, view_transform.translation());
+
+        let mut morph_ranges = [view_config.morph_range; MAX_MORPH_LODS];
+
+        for (range, &lod_range) in morph_ranges.iter_mut().zip(&view_config.morph_ranges) {
+            *range = lod_range;
+        }
 
         TerrainViewConfigUniform {
             height_under_viewer: view_config.height_under_viewer,
@@ -49,13 +113,38 @@ impl TerrainViewConfigUniform {
             refinement_count: view_config.refinement_count,
             tile_scale: view_config.tile_scale,
             grid_size: view_config.grid_size as f32,
-            vertices_per_row: 2 * (view_config.grid_size + 2),
-            vertices_per_tile: 2 * view_config.grid_size * (view_config.grid_size + 2),
-            morph_distance: view_distance
-                / 2.0_f32.powf(view_config.additional_refinement as f32 + 1.0),
+            vertices_per_row: 2 * (mesh_size + 2),
+            vertices_per_tile: 2 * mesh_size * (mesh_size + 2),
+            morph_distance,
             blend_distance: view_distance,
             morph_range: view_config.morph_range,
             blend_range: view_config.blend_range,
+            view_position: view_position.extend(1.0),
+            model: terrain_transform.compute_matrix(),
+            camera_model: terrain_to_camera(terrain_transform, view_transform.translation()),
+            morph_ranges: [0, 4, 8, 12].map(|lod| Vec4::from_slice(&morph_ranges[lod..lod + 4])),
+            clipmap_ring_count,
+            clipmap_ring_size,
+            seam_mode,
+            skirt_depth,
+            adaptive_refinement: view_config.adaptive_refinement,
+            occlusion_culling: view_config.occlusion_culling as u32,
+        }
+    }
+
+    /// Derives the config of the coarse tiles, which are rendered into the shadow cascades.
+    ///
+    /// The clipmap rings are already coarser with the distance and are kept as is.
+    pub(crate) fn shadow(&self, shadow_lod_bias: f32) -> Self {
+        let morph_distance = match self.clipmap_ring_count {
+            0 => self.morph_distance / shadow_lod_bias.max(1.0),
+            _ => self.morph_distance,
+        };
+
+        Self {
+            morph_distance,
+            occlusion_culling: 0,
+            ..self.clone()
         }
     }
 }
@@ -69,7 +158,7 @@ pub struct TerrainViewData {
 }
 
 impl TerrainViewData {
-    fn new(
+    pub(crate) fn new(
         device: &RenderDevice,
         images: &RenderAssets<Image>,
         view_config: &TerrainViewConfig,
@@ -202,13 +291,17 @@ pub(crate) fn initialize_terrain_view_data(
     images: Res<RenderAssets<Image>>,
     mut terrain_view_data: ResMut<TerrainViewComponents<TerrainViewData>>,
     view_configs: Extract<Res<TerrainViewComponents<TerrainViewConfig>>>,
-    view_query: Extract<Query<Entity, With<TerrainView>>>,
-    terrain_query: Extract<Query<Entity, Added<Terrain>>>,
 ) {
-    for terrain in terrain_query.iter() {
-        for view in view_query.iter() {
-            let view_config = view_configs.get(&(terrain, view)).unwrap();
+    // remove the data of views, that are no longer registered
+    terrain_view_data
+        .0
+        .retain(|&(terrain, view), _| view_configs.contains_key(&(terrain, view)));
 
+    for (&(terrain, view), view_config) in &view_configs.0 {
+        // the quadtree texture is created by the corresponding gpu quadtree
+        if !terrain_view_data.contains_key(&(terrain, view))
+            && images.get(&view_config.quadtree_handle).is_some()
+        {
             terrain_view_data.insert(
                 (terrain, view),
                 TerrainViewData::new(&device, &images, view_config),
@@ -219,15 +312,29 @@ pub(crate) fn initialize_terrain_view_data(
 
 pub(crate) fn extract_terrain_view_config(
     mut view_config_uniforms: ResMut<TerrainViewComponents<TerrainViewConfigUniform>>,
-    configs: Extract<Query<&TerrainConfig>>,
     view_configs: Extract<Res<TerrainViewComponents<TerrainViewConfig>>>,
+    terrain_query: Extract<Query<(&TerrainConfig, &GlobalTransform)>>,
+    view_query: Extract<
+        Query<(&GlobalTransform, Option<&Camera>, Option<&Projection>), With<TerrainView>>,
+    >,
 ) {
+    view_config_uniforms.0.clear();
+
     for (&(terrain, view), view_config) in &view_configs.0 {
-        let config = configs.get(terrain).unwrap();
-        view_config_uniforms.insert(
-            (terrain, view),
-            TerrainViewConfigUniform::new(config, view_config),
-        )
+        if let (Ok((config, terrain_transform)), Ok((view_transform, camera, projection))) =
+            (terrain_query.get(terrain), view_query.get(view))
+        {
+            view_config_uniforms.insert(
+                (terrain, view),
+                TerrainViewConfigUniform::new(
+                    config,
+                    view_config,
+                    terrain_transform,
+                    view_transform,
+                    projection_scale(camera, projection),
+                ),
+            )
+        }
     }
 }
 
@@ -237,8 +344,9 @@ pub(crate) fn prepare_terrain_view_config(
     view_config_uniforms: Res<TerrainViewComponents<TerrainViewConfigUniform>>,
 ) {
     for (&(terrain, view), data) in &mut terrain_view_data.0 {
-        let view_config_uniform = view_config_uniforms.get(&(terrain, view)).unwrap();
-        data.update(&queue, view_config_uniform)
+        if let Some(view_config_uniform) = view_config_uniforms.get(&(terrain, view)) {
+            data.update(&queue, view_config_uniform)
+        }
     }
 }
 
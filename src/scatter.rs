@@ -0,0 +1,600 @@
+//! Scatters detail objects (e.g. grass, flowers or pebbles) across the terrain, which are
+//! rendered with GPU instancing.
+//!
+//! Each [`ScatterLayer`] places instances of one mesh on a jittered grid, whose density is scaled
+//! by the weight of a splat layer (see [`crate::render::splat_material`]), so that e.g. grass
+//! only grows where the grass layer has been painted. Slopes steeper than the maximum slope of the
+//! layer stay empty, while the instances shrink away between the fade distances of the layer.
+//! Each terrain with a [`TerrainScatter`] component spawns one instanced entity per layer and
+//! activated node of the configured lod. The instances share the lifecycle of their nodes,
+//! so they are despawned again, once their node is deactivated, and rebuilt, once their node
+//! is edited, its splatmap is painted or a finer node inside it is activated.
+//! The instances are placed in the [`AsyncComputeTaskPool`] on the heights of the finest loaded
+//! nodes, so that they rest on the most detailed surface.
+//! The placement only depends on the position of each grid cell, so that the same instances
+//! reappear each time a node is activated.
+//!
+//! The instances are lit by the first directional light and the ambient light, but neither cast
+//! nor receive shadows. Add the [`TerrainScatterPlugin`] to enable the scattering.
+
+use crate::{
+    biome::{generate_biome_splats, BiomeId, NodeBiomes, TerrainBiomes},
+    render::{
+        scatter::{
+            prepare_scatter_buffers, queue_scatter, DrawScatter, ScatterBuffers, ScatterPipeline,
+        },
+        splat_material::SPLAT_ATTACHMENT,
+    },
+    terrain_data::{
+        calc_node_id,
+        node_atlas::{LoadingState, NodeAtlas, NodeLifecycle},
+        AtlasAttachment, AttachmentIndex, NodeCoordinate, NodeId, HEIGHT_ATTACHMENT,
+    },
+    TerrainSystemSet,
+};
+use bevy::{
+    core_pipeline::core_3d::Opaque3d,
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        primitives::Aabb,
+        render_phase::AddRenderCommand,
+        render_resource::SpecializedMeshPipelines,
+        RenderApp, RenderSet,
+    },
+    tasks::{futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::HashMap,
+};
+use bytemuck::{Pod, Zeroable};
+use itertools::{iproduct, Itertools};
+use std::{
+    f32::consts::{FRAC_PI_4, TAU},
+    sync::Arc,
+};
+
+/// The maximum number of grid cells along each axis of a node, which limits the number of
+/// instances per node and layer.
+pub const MAX_SCATTER_CELLS: u32 = 512;
+
+/// The salt of the random number generators of the grid cells (see [`cell_rng`]).
+const SCATTER_SALT: u64 = 1;
+
+/// A kind of detail objects, which are scattered across the terrain.
+#[derive(Clone)]
+pub struct ScatterLayer {
+    /// The mesh of the instances, which requires positions and normals.
+    pub mesh: Handle<Mesh>,
+    /// The color of the instances.
+    pub color: Color,
+    /// The splat layer (the channel of the splatmap), whose weight scales the density,
+    /// or `None` to scatter the instances across the entire terrain.
+    pub splat_layer: Option<usize>,
+    /// The biomes (see [`crate::biome`]), whose weights scale the density,
+    /// or an empty list to scatter the instances across all biomes.
+    pub biomes: Vec<BiomeId>,
+    /// The number of instances per square unit (in the local space of the terrain) at full weight.
+    pub density: f32,
+    /// The smallest scale of the instances.
+    pub min_scale: f32,
+    /// The largest scale of the instances.
+    pub max_scale: f32,
+    /// The slope angle in radians, above which no instances are placed.
+    pub max_slope: f32,
+    /// The world space distance from the view, at which the instances start to shrink.
+    pub fade_start: f32,
+    /// The world space distance from the view, beyond which the instances are hidden.
+    pub fade_end: f32,
+}
+
+impl ScatterLayer {
+    /// Creates a new layer, which scatters the mesh with the density across the entire terrain.
+    pub fn new(mesh: Handle<Mesh>, color: Color, density: f32) -> Self {
+        Self {
+            mesh,
+            color,
+            splat_layer: None,
+            biomes: Vec::new(),
+            density,
+            min_scale: 0.8,
+            max_scale: 1.2,
+            max_slope: FRAC_PI_4,
+            fade_start: 40.0,
+            fade_end: 60.0,
+        }
+    }
+
+    /// Scales the density by the weight of the splat layer.
+    pub fn with_splat_layer(mut self, splat_layer: usize) -> Self {
+        self.splat_layer = Some(splat_layer);
+        self
+    }
+
+    /// Restricts the layer to the biomes, whose weights scale the density.
+    pub fn with_biomes(mut self, biomes: impl Into<Vec<BiomeId>>) -> Self {
+        self.biomes = biomes.into();
+        self
+    }
+
+    /// Uses the distances, between which the instances shrink away.
+    pub fn with_fade(mut self, fade_start: f32, fade_end: f32) -> Self {
+        self.fade_start = fade_start;
+        self.fade_end = fade_end;
+        self
+    }
+}
+
+/// The data of a single instance, which is stored in the instance buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+pub(crate) struct ScatterInstance {
+    /// The position relative to the node and the scale.
+    position_scale: [f32; 4],
+    /// The rotation around the y axis in radians and the fade distances.
+    rotation_fade: [f32; 4],
+    color: [f32; 4],
+}
+
+/// The instances of a layer and a node, which are drawn with a single instanced draw call.
+#[derive(Clone, Component, ExtractComponent)]
+pub struct ScatterInstances(pub(crate) Arc<[ScatterInstance]>);
+
+/// Configures the scattered detail objects of a terrain.
+#[derive(Component)]
+pub struct TerrainScatter {
+    /// The lod of the nodes, for which instances are scattered.
+    pub lod: u32,
+    /// The index of the splatmap attachment, which the density of the layers is derived from.
+    pub splat_attachment: AttachmentIndex,
+    /// The seed of the placement.
+    pub seed: u64,
+    /// The scattered layers.
+    pub layers: Vec<ScatterLayer>,
+    /// The instanced entities of the currently activated nodes, per node and layer.
+    chunks: HashMap<(NodeId, usize), Entity>,
+    /// The pending placements of the nodes, which yield the instances per layer.
+    tasks: Vec<(NodeId, Task<Vec<(usize, Vec<ScatterInstance>)>>)>,
+}
+
+impl TerrainScatter {
+    /// Creates a new scatter config, which scatters instances for the nodes of the lod.
+    pub fn new(lod: u32) -> Self {
+        Self {
+            lod,
+            splat_attachment: SPLAT_ATTACHMENT,
+            seed: 0,
+            layers: Vec::new(),
+            chunks: default(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Adds the scattered layer.
+    pub fn with_layer(mut self, layer: ScatterLayer) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// Copies the data required to place the instances of all layers on the node.
+    ///
+    /// Returns `None` if the height data of the node is not available on the CPU.
+    fn job(
+        &self,
+        node_atlas: &NodeAtlas,
+        biomes: Option<&TerrainBiomes>,
+        images: &Assets<Image>,
+        node_id: NodeId,
+    ) -> Option<ScatterJob> {
+        let node = node_atlas.nodes.get(&node_id)?;
+        let data = &node_atlas.data[node.atlas_index as usize];
+        let NodeCoordinate { lod, x, y } = node_id.into();
+
+        // the loaded nodes inside the node, including the node itself
+        let heights = node_atlas
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.state == LoadingState::Loaded)
+            .filter_map(|(&node_id, node)| {
+                let coordinate = NodeCoordinate::from(node_id);
+                let shift = lod.checked_sub(coordinate.lod)?;
+
+                if (coordinate.x >> shift, coordinate.y >> shift) != (x, y) {
+                    return None;
+                }
+
+                let handle = node_atlas.data[node.atlas_index as usize]
+                    .attachments
+                    .get(&HEIGHT_ATTACHMENT)?;
+
+                Some((
+                    coordinate.lod,
+                    UVec2::new(coordinate.x, coordinate.y),
+                    images.get(handle)?.clone(),
+                ))
+            })
+            .sorted_by_key(|&(lod, _, _)| lod)
+            .collect::<Vec<_>>();
+
+        if heights.last().map(|&(node_lod, _, _)| node_lod) != Some(lod) {
+            return None;
+        }
+
+        let splat = self
+            .layers
+            .iter()
+            .any(|layer| layer.splat_layer.is_some())
+            .then(|| {
+                Some((
+                    node_atlas.attachments.get(self.splat_attachment)?.clone(),
+                    images
+                        .get(data.attachments.get(&self.splat_attachment)?)?
+                        .clone(),
+                ))
+            })
+            .flatten();
+
+        let biomes = self
+            .layers
+            .iter()
+            .any(|layer| !layer.biomes.is_empty())
+            .then(|| {
+                let biomes = biomes?;
+                let (attachment, image) = biomes.node_map(node_atlas, images, node_id)?;
+
+                Some((biomes.clone(), attachment.clone(), image.clone()))
+            })
+            .flatten();
+
+        Some(ScatterJob {
+            node_id,
+            seed: self.seed,
+            layers: self.layers.clone(),
+            leaf_node_size: node_atlas.leaf_node_size,
+            height: node_atlas.height,
+            height_attachment: node_atlas.attachments[HEIGHT_ATTACHMENT].clone(),
+            heights,
+            splat,
+            biomes,
+        })
+    }
+}
+
+/// The data required to place the instances on a node, which is copied from the node atlas,
+/// so that the instances can be placed in the [`AsyncComputeTaskPool`].
+struct ScatterJob {
+    node_id: NodeId,
+    seed: u64,
+    layers: Vec<ScatterLayer>,
+    leaf_node_size: u32,
+    height: f32,
+    height_attachment: AtlasAttachment,
+    /// The lod, the coordinate and the height image of the loaded nodes inside the node,
+    /// ordered from the finest to the coarsest one (the node itself).
+    heights: Vec<(u32, UVec2, Image)>,
+    /// The splatmap of the node, if any layer is scaled by a splat layer.
+    splat: Option<(AtlasAttachment, Image)>,
+    /// The biome map of the node, if any layer is restricted to biomes.
+    biomes: Option<(TerrainBiomes, AtlasAttachment, Image)>,
+}
+
+impl ScatterJob {
+    /// Places the instances of all layers, which cover the node.
+    fn run(self) -> Vec<(usize, Vec<ScatterInstance>)> {
+        (0..self.layers.len())
+            .filter_map(|layer_index| Some((layer_index, self.scatter(layer_index)?)))
+            .collect()
+    }
+
+    /// Samples the height at the coordinates inside the node from the finest loaded node.
+    fn sample_height(&self, coords: Vec2) -> f32 {
+        let NodeCoordinate { lod, x, y } = self.node_id.into();
+        let position = (UVec2::new(x, y).as_vec2() + coords) * (self.leaf_node_size << lod) as f32;
+
+        self.heights
+            .iter()
+            .find_map(|(node_lod, coordinate, image)| {
+                let coords =
+                    position / (self.leaf_node_size << node_lod) as f32 - coordinate.as_vec2();
+
+                let contained = coords.cmpge(Vec2::ZERO).all() && coords.cmplt(Vec2::ONE).all();
+
+                // the coordinates outside of the node itself are clamped to its border
+                (contained || *node_lod == lod).then(|| {
+                    self.height_attachment
+                        .sample(image, coords.clamp(Vec2::ZERO, Vec2::ONE), 0)
+                })
+            })
+            .unwrap_or(0.0)
+            * self.height
+    }
+
+    /// Places the instances of the layer on the node.
+    ///
+    /// Returns `None` if the node is not covered by the layer or its splat or biome data
+    /// is not available on the CPU.
+    fn scatter(&self, layer_index: usize) -> Option<Vec<ScatterInstance>> {
+        let layer = &self.layers[layer_index];
+
+        let splat = match layer.splat_layer {
+            Some(channel) => {
+                let (attachment, image) = self.splat.as_ref()?;
+                Some((channel, attachment, image))
+            }
+            None => None,
+        };
+
+        let node_biomes = if layer.biomes.is_empty() {
+            None
+        } else {
+            let (biomes, attachment, image) = self.biomes.as_ref()?;
+            Some(NodeBiomes::new(biomes, attachment, image))
+        };
+
+        let NodeCoordinate { lod, x, y } = self.node_id.into();
+        let node_size = (self.leaf_node_size << lod) as f32;
+        let cells =
+            ((node_size * layer.density.max(0.0).sqrt()).ceil() as u32).clamp(1, MAX_SCATTER_CELLS);
+
+        let step = 1.0 / self.height_attachment.center_size as f32;
+        let max_gradient = layer.max_slope.tan();
+        let color = Vec4::from(layer.color.as_linear_rgba_f32());
+
+        let mut instances = Vec::new();
+
+        for (cell_y, cell_x) in iproduct!(0..cells, 0..cells) {
+            let cell = UVec2::new(x * cells + cell_x, y * cells + cell_y);
+            let mut rng = cell_rng(self.seed, SCATTER_SALT, layer_index, cell);
+
+            let coords = (Vec2::new(cell_x as f32, cell_y as f32)
+                + Vec2::new(rng.f32(), rng.f32()))
+                / cells as f32;
+
+            let weight = splat.map_or(1.0, |(channel, attachment, image)| {
+                attachment.sample(image, coords, channel)
+            }) * node_biomes
+                .as_ref()
+                .map_or(1.0, |node_biomes| node_biomes.weight(coords, &layer.biomes));
+
+            if rng.f32() >= weight {
+                continue;
+            }
+
+            let gradient = Vec2::new(
+                self.sample_height(coords + Vec2::X * step)
+                    - self.sample_height(coords - Vec2::X * step),
+                self.sample_height(coords + Vec2::Y * step)
+                    - self.sample_height(coords - Vec2::Y * step),
+            ) / (2.0 * step * node_size);
+
+            if gradient.length() > max_gradient {
+                continue;
+            }
+
+            let position = coords * node_size;
+            let scale = layer.min_scale + (layer.max_scale - layer.min_scale) * rng.f32();
+            let brightness = 0.85 + 0.15 * rng.f32();
+
+            instances.push(ScatterInstance {
+                position_scale: [position.x, self.sample_height(coords), position.y, scale],
+                rotation_fade: [rng.f32() * TAU, layer.fade_start, layer.fade_end, 0.0],
+                color: (color * Vec4::new(brightness, brightness, brightness, 1.0)).to_array(),
+            });
+        }
+
+        (!instances.is_empty()).then_some(instances)
+    }
+}
+
+/// Creates the random number generator of the grid cell (counted across the entire terrain).
+///
+/// The seed only depends on the cell, so that the placement is the same on every activation.
+/// The salt distinguishes the systems scattering with the same seed, so that their placements
+/// are independent of each other.
+pub(crate) fn cell_rng(seed: u64, salt: u64, layer_index: usize, cell: UVec2) -> fastrand::Rng {
+    fastrand::Rng::with_seed(
+        seed ^ salt.wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            ^ ((layer_index as u64) << 56)
+            ^ ((cell.y as u64) << 28)
+            ^ cell.x as u64,
+    )
+}
+
+/// Calculates the bounds of the instances (relative to their node), which are used for culling.
+fn instance_bounds(
+    instances: &[ScatterInstance],
+    mesh_bounds: Option<Aabb>,
+    max_scale: f32,
+) -> Aabb {
+    let (min, max) = instances.iter().fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(min, max), instance| {
+            let position = Vec3::from_slice(&instance.position_scale[..3]);
+            (min.min(position), max.max(position))
+        },
+    );
+
+    // the instances are rotated around their vertical axis, so the horizontal extent is a circle
+    let extent = mesh_bounds.map_or(Vec3::ONE, |bounds| {
+        let center = Vec3::from(bounds.center);
+        let half_extents = Vec3::from(bounds.half_extents);
+        let radius = (center.xz().abs() + half_extents.xz()).length();
+
+        Vec3::new(radius, center.y.abs() + half_extents.y, radius)
+    }) * max_scale;
+
+    Aabb::from_min_max(min - extent, max + extent)
+}
+
+/// Adds the scattered detail objects of the terrains with a [`TerrainScatter`] component.
+pub struct TerrainScatterPlugin;
+
+impl Plugin for TerrainScatterPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(ExtractComponentPlugin::<ScatterInstances>::default())
+            .add_system(
+                update_scatter_instances
+                    .after(TerrainSystemSet::Update)
+                    .after(generate_biome_splats)
+                    .in_base_set(CoreSet::Last),
+            );
+
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            warn!("The scattered instances are not rendered without a renderer.");
+            return;
+        };
+
+        render_app
+            .add_render_command::<Opaque3d, DrawScatter>()
+            .init_resource::<ScatterPipeline>()
+            .init_resource::<SpecializedMeshPipelines<ScatterPipeline>>()
+            .init_resource::<ScatterBuffers>()
+            .add_system(prepare_scatter_buffers.in_set(RenderSet::Prepare))
+            .add_system(queue_scatter.in_set(RenderSet::Queue));
+    }
+}
+
+/// Spawns and despawns the instanced entities of all terrains,
+/// according to the lifecycle of the nodes of their node atlas.
+///
+/// The instances of the activated and modified nodes are placed in the [`AsyncComputeTaskPool`].
+/// The previous instances of a node are kept, until its placement has finished.
+fn update_scatter_instances(
+    mut commands: Commands,
+    images: Res<Assets<Image>>,
+    meshes: Res<Assets<Mesh>>,
+    mut terrain_query: Query<(
+        Entity,
+        &NodeAtlas,
+        Option<&TerrainBiomes>,
+        &mut TerrainScatter,
+    )>,
+) {
+    for (terrain, node_atlas, biomes, mut scatter) in terrain_query.iter_mut() {
+        let scatter = &mut *scatter;
+        let (lod, splat_attachment) = (scatter.lod, scatter.splat_attachment);
+
+        // painting the splatmap changes the density of the layers and
+        // finer nodes provide more detailed heights
+        let modified = node_atlas
+            .edited_nodes
+            .iter()
+            .copied()
+            .chain(
+                node_atlas
+                    .painted_nodes
+                    .iter()
+                    .filter(|&&(_, attachment_index)| attachment_index == splat_attachment)
+                    .map(|&(node_id, _)| node_id),
+            )
+            .chain(
+                node_atlas
+                    .lifecycle_events
+                    .iter()
+                    .filter(|&&(_, lifecycle)| lifecycle == NodeLifecycle::Activated)
+                    .map(|&(node_id, _)| NodeCoordinate::from(node_id))
+                    .filter(|coordinate| coordinate.lod < lod)
+                    .map(|coordinate| {
+                        let shift = lod - coordinate.lod;
+                        calc_node_id(lod, coordinate.x >> shift, coordinate.y >> shift)
+                    }),
+            )
+            .collect::<Vec<_>>();
+
+        let deactivated = node_atlas
+            .lifecycle_events
+            .iter()
+            .filter(|&&(_, lifecycle)| lifecycle == NodeLifecycle::Deactivated)
+            .map(|&(node_id, _)| node_id)
+            .collect::<Vec<_>>();
+
+        for (_, entity) in scatter
+            .chunks
+            .drain_filter(|(node_id, _), _| deactivated.contains(node_id))
+        {
+            commands.entity(entity).despawn_recursive();
+        }
+
+        // dropping the tasks cancels them
+        scatter
+            .tasks
+            .retain(|(node_id, _)| !deactivated.contains(node_id));
+
+        let activated = node_atlas
+            .lifecycle_events
+            .iter()
+            .filter(|&&(_, lifecycle)| lifecycle == NodeLifecycle::Activated)
+            .map(|&(node_id, _)| node_id)
+            .chain(modified)
+            .unique()
+            .filter(|&node_id| {
+                NodeCoordinate::from(node_id).lod == lod && node_atlas.nodes.contains_key(&node_id)
+            })
+            .collect::<Vec<_>>();
+
+        for node_id in activated {
+            scatter
+                .tasks
+                .retain(|&(task_node_id, _)| task_node_id != node_id);
+
+            let Some(job) = scatter.job(node_atlas, biomes, &images, node_id) else {
+                for (_, entity) in scatter
+                    .chunks
+                    .drain_filter(|&(chunk_node_id, _), _| chunk_node_id == node_id)
+                {
+                    commands.entity(entity).despawn_recursive();
+                }
+
+                continue;
+            };
+
+            let task = AsyncComputeTaskPool::get().spawn(async move { job.run() });
+            scatter.tasks.push((node_id, task));
+        }
+
+        let mut scattered = Vec::new();
+
+        scatter.tasks.retain_mut(|(node_id, task)| {
+            match future::block_on(future::poll_once(task)) {
+                Some(instances) => {
+                    scattered.push((*node_id, instances));
+                    false
+                }
+                None => true,
+            }
+        });
+
+        for (node_id, instances) in scattered {
+            for (_, entity) in scatter
+                .chunks
+                .drain_filter(|&(chunk_node_id, _), _| chunk_node_id == node_id)
+            {
+                commands.entity(entity).despawn_recursive();
+            }
+
+            for (layer_index, instances) in instances {
+                let layer = &scatter.layers[layer_index];
+                let NodeCoordinate { lod, x, y } = node_id.into();
+                let size = (node_atlas.leaf_node_size << lod) as f32;
+
+                let mesh_bounds = meshes.get(&layer.mesh).and_then(Mesh::compute_aabb);
+                let bounds = instance_bounds(&instances, mesh_bounds, layer.max_scale);
+
+                // the bounds cover all instances, so that they are not computed from the single mesh
+                let chunk = commands
+                    .spawn((
+                        SpatialBundle::from_transform(Transform::from_xyz(
+                            x as f32 * size,
+                            0.0,
+                            y as f32 * size,
+                        )),
+                        layer.mesh.clone(),
+                        bounds,
+                        ScatterInstances(instances.into()),
+                    ))
+                    .id();
+
+                commands.entity(terrain).add_child(chunk);
+                scatter.chunks.insert((node_id, layer_index), chunk);
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Deterministic seeded generation of procedural terrains.
+//!
+//! The height functions of the [`ProceduralSource`](crate::node_source::ProceduralSource)
+//! are evaluated with floating point math, whose results (e.g. of transcendental functions)
+//! may differ between platforms and compilers. [`SeededNoise`] instead evaluates fractal
+//! value noise with integer arithmetic only, starting from the integer coordinates of the pixels.
+//! Thus a given seed produces bit-identical nodes on every platform, which lets the clients
+//! of a multiplayer game generate the same terrain locally, instead of streaming it.
+//!
+//! Positions and values are fixed point numbers with [`FRACTION_BITS`] fractional bits.
+//! Add the generator to a terrain with
+//! [`TerrainConfig::add_seeded_base_attachment`](crate::terrain::TerrainConfig::add_seeded_base_attachment).
+
+use crate::terrain_data::{AtlasAttachment, NodeCoordinate, NodeId};
+use bevy::prelude::*;
+
+/// The number of fractional bits of the fixed point numbers.
+pub const FRACTION_BITS: u32 = 16;
+
+/// The fixed point representation of one.
+const ONE: i64 = 1 << FRACTION_BITS;
+
+/// Fractal value noise, which is evaluated with integer arithmetic only.
+#[derive(Clone, Copy, Debug)]
+pub struct SeededNoise {
+    /// The seed of the noise.
+    pub seed: u64,
+    /// The number of octaves, each of which halves the wavelength of the previous one.
+    pub octaves: u32,
+    /// The wavelength of the first octave (in the local space of the terrain).
+    pub wavelength: u32,
+    /// The amplitude of each octave relative to the previous one (in fixed point).
+    pub persistence: u32,
+}
+
+impl SeededNoise {
+    /// Creates new noise with eight octaves, whose amplitudes halve.
+    pub fn new(seed: u64, wavelength: u32) -> Self {
+        Self {
+            seed,
+            octaves: 8,
+            wavelength: wavelength.max(1),
+            persistence: (ONE / 2) as u32,
+        }
+    }
+
+    /// Uses the number of octaves.
+    pub fn with_octaves(mut self, octaves: u32) -> Self {
+        self.octaves = octaves.max(1);
+        self
+    }
+
+    /// Uses the relative amplitude (from zero to one) for each octave.
+    pub fn with_persistence(mut self, persistence: f32) -> Self {
+        // rounded once on construction, so that all clients use the same fixed point value
+        self.persistence = (persistence.clamp(0.0, 1.0) * ONE as f32).round() as u32;
+        self
+    }
+
+    /// Hashes the lattice point of the octave into a value between zero and one (in fixed point).
+    fn hash(&self, x: i64, y: i64, octave: u32) -> i64 {
+        let mut hash = self.seed
+            ^ (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            ^ (y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f)
+            ^ (octave as u64).wrapping_mul(0x1656_67b1_9e37_79f9);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+
+        (hash >> (64 - FRACTION_BITS)) as i64
+    }
+
+    /// Evaluates one octave of the value noise at the fixed point position.
+    fn octave(&self, position: (i64, i64), wavelength: i64, octave: u32) -> i64 {
+        let cell = (
+            position.0.div_euclid(wavelength),
+            position.1.div_euclid(wavelength),
+        );
+        let fract = (
+            position.0.rem_euclid(wavelength) * ONE / wavelength,
+            position.1.rem_euclid(wavelength) * ONE / wavelength,
+        );
+
+        // smoothstep interpolation between the lattice points
+        let smooth = |t: i64| t * t / ONE * (3 * ONE - 2 * t) / ONE;
+        let lerp = |a: i64, b: i64, t: i64| a + (b - a) * t / ONE;
+        let t = (smooth(fract.0), smooth(fract.1));
+
+        let top = lerp(
+            self.hash(cell.0, cell.1, octave),
+            self.hash(cell.0 + 1, cell.1, octave),
+            t.0,
+        );
+        let bottom = lerp(
+            self.hash(cell.0, cell.1 + 1, octave),
+            self.hash(cell.0 + 1, cell.1 + 1, octave),
+            t.0,
+        );
+
+        lerp(top, bottom, t.1)
+    }
+
+    /// Evaluates the noise at the fixed point position (in the local space of the terrain).
+    ///
+    /// Returns the normalized height between zero and one (in fixed point).
+    pub fn sample(&self, position: (i64, i64)) -> i64 {
+        let mut wavelength = (self.wavelength as i64) << FRACTION_BITS;
+        let mut amplitude = ONE;
+        let mut value = 0;
+        let mut total = 0;
+
+        for octave in 0..self.octaves.max(1) {
+            value += self.octave(position, wavelength, octave) * amplitude / ONE;
+            total += amplitude;
+
+            wavelength = (wavelength / 2).max(1);
+            amplitude = amplitude * self.persistence as i64 / ONE;
+        }
+
+        (value * ONE / total.max(1)).clamp(0, ONE)
+    }
+}
+
+impl AtlasAttachment {
+    /// Calculates the fixed point position of the pixel center (in the local space of the terrain)
+    /// from the integer coordinates of the node and the pixel.
+    pub(crate) fn fixed_pixel_position(
+        &self,
+        node_id: NodeId,
+        leaf_node_size: u32,
+        pixel: UVec2,
+    ) -> (i64, i64) {
+        let coord = NodeCoordinate::from(node_id);
+        let center_size = self.center_size as i64;
+        let border_size = self.border_size as i64;
+        let node_size = (leaf_node_size as i64) << coord.lod;
+
+        // the pixel center lies half a pixel into the pixel, hence the doubled coordinates
+        let position = |node: u32, pixel: u32| {
+            let offset = 2 * (node as i64 * center_size + pixel as i64 - border_size) + 1;
+            (offset * node_size << FRACTION_BITS).div_euclid(2 * center_size)
+        };
+
+        (position(coord.x, pixel.x), position(coord.y, pixel.y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain_data::{calc_node_id, AttachmentFormat, FileFormat};
+
+    // These values pin the generated terrains, which have to stay bit-identical across
+    // platforms and versions, so that the clients of a multiplayer game agree on them.
+
+    #[test]
+    fn sample_golden_values() {
+        let noise = SeededNoise::new(42, 256);
+
+        assert_eq!(noise.sample((0, 0)), 51338);
+        assert_eq!(noise.sample((123 << 16, 456 << 16)), 37447);
+        assert_eq!(noise.sample((-1000 << 16, 77 << 16 | 12345)), 35188);
+        assert_eq!(noise.sample((1 << 30, (1 << 28) + 5)), 32587);
+
+        let noise = SeededNoise::new(0xdead_beef, 64)
+            .with_octaves(4)
+            .with_persistence(0.6);
+
+        assert_eq!(noise.persistence, 39322);
+        assert_eq!(noise.sample((0, 0)), 27638);
+        assert_eq!(noise.sample((5 << 16, 9 << 16)), 23653);
+    }
+
+    #[test]
+    fn fixed_pixel_position_golden_values() {
+        let attachment = AtlasAttachment {
+            handle: default(),
+            name: "height".to_string(),
+            texture_size: 516,
+            center_size: 512,
+            border_size: 2,
+            mip_level_count: 1,
+            attachment_format: AttachmentFormat::R16,
+            file_format: FileFormat::TDF,
+        };
+        let noise = SeededNoise::new(42, 256);
+
+        let position = attachment.fixed_pixel_position(calc_node_id(0, 0, 0), 512, UVec2::ZERO);
+        assert_eq!(position, (-98304, -98304));
+        assert_eq!(noise.sample(position), 51360);
+
+        let position =
+            attachment.fixed_pixel_position(calc_node_id(0, 3, 5), 512, UVec2::new(10, 20));
+        assert_eq!(position, (101220352, 168984576));
+        assert_eq!(noise.sample(position), 36057);
+
+        let position =
+            attachment.fixed_pixel_position(calc_node_id(2, 1, 2), 512, UVec2::new(515, 0));
+        assert_eq!(position, (268828672, 268042240));
+        assert_eq!(noise.sample(position), 32361);
+    }
+}
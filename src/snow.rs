@@ -0,0 +1,512 @@
+//! Accumulates and melts snow on the terrain over time.
+//!
+//! The snow attachment stores the depth of the snow (normalized to the height of the terrain)
+//! in its first channel and its coverage (from zero to one) in its second one.
+//! Once it is configured as the [`TerrainPlugin::snow_attachment`](crate::TerrainPlugin),
+//! the vertex shader raises the surface by the depth of the snow and the default and the splat
+//! material blend the snow over their color by its coverage.
+//! The material shader must not bind the snow attachment itself.
+//!
+//! Each terrain with a [`TerrainSnow`] component accumulates snow on the loaded nodes,
+//! that are requested by a quadtree, where flat regions collect more snow than steep ones,
+//! while the snow melts at a constant rate.
+//! The snow only accumulates directly on the most detailed of these nodes, coarser nodes
+//! downsample it from their children, wherever they are loaded, so that all lods agree.
+//! Nodes, whose snow no longer changes, are skipped until the config changes or they are edited,
+//! while nodes, that are not requested, catch up on the elapsed time, once they are requested again.
+//! Only the changed region of each node is uploaded to the GPU.
+//! The accumulation and melt rates can be changed at any time, e.g. by a weather system.
+//! Add the [`TerrainSnowPlugin`] to enable the accumulation.
+//!
+//! The snow raises the height bounds of the nodes and their minmax attachment, so that culling
+//! and raycasts account for it. The physics colliders include it as well and
+//! [`TerrainSampler::height_at`](crate::terrain_data::sampling::TerrainSampler::height_at)
+//! samples the height of its surface.
+//! The accumulated snow is not persisted, nodes that are loaded again start out with the
+//! snow stored by their source.
+
+use crate::{
+    terrain_data::{
+        calc_node_id,
+        node_atlas::{NodeAtlas, NodeLifecycle},
+        AtlasAttachment, AttachmentConfig, AttachmentFormat, AttachmentIndex, NodeCoordinate,
+        NodeId, HEIGHT_ATTACHMENT, MINMAX_ATTACHMENT,
+    },
+    TerrainSystemSet,
+};
+use bevy::{
+    prelude::*,
+    render::render_resource::TextureFormat,
+    utils::{HashMap, HashSet},
+};
+use itertools::iproduct;
+use std::{f32::consts::FRAC_PI_4, mem};
+
+/// Creates the config of the snow attachment.
+pub fn snow_attachment(texture_size: u32, mip_level_count: u32) -> AttachmentConfig {
+    AttachmentConfig::new(
+        "snow".to_string(),
+        texture_size,
+        1,
+        mip_level_count,
+        AttachmentFormat::Rg16,
+    )
+}
+
+/// The progress of the snow accumulation of a terrain.
+#[derive(Clone, Default)]
+struct SnowState {
+    /// The time elapsed since the last update.
+    elapsed: f32,
+    /// The total time, that has been simulated.
+    time: f32,
+    /// The simulated time of the last update of each node.
+    updated_nodes: HashMap<NodeId, f32>,
+    /// The nodes, whose snow did not change during their last update.
+    settled_nodes: HashSet<NodeId>,
+    /// The rates and limits, with which the settled nodes have settled.
+    settings: [f32; 6],
+    /// The depth of the snow, by which the minmax attachment of each node has been raised.
+    raised_nodes: HashMap<NodeId, f32>,
+}
+
+/// Configures the snow accumulation of a terrain.
+#[derive(Clone, Component)]
+pub struct TerrainSnow {
+    /// The index of the snow attachment.
+    pub attachment_index: AttachmentIndex,
+    /// The depth, that the snow accumulates per second on flat ground
+    /// (in the local space of the terrain).
+    pub accumulation_rate: f32,
+    /// The depth, that the snow melts per second (in the local space of the terrain).
+    pub melt_rate: f32,
+    /// The maximum depth of the snow on flat ground (in the local space of the terrain).
+    pub max_depth: f32,
+    /// The depth of the snow, at which it fully covers the ground (in the local space of the terrain).
+    pub coverage_depth: f32,
+    /// The slope angle in radians, above which no snow accumulates.
+    pub max_slope: f32,
+    /// The range of slope angles in radians below the maximum slope,
+    /// across which the accumulation fades out.
+    pub slope_falloff: f32,
+    /// The time in seconds between two updates of the snow attachment.
+    pub update_interval: f32,
+    state: SnowState,
+}
+
+impl TerrainSnow {
+    /// Creates a new snow config, which accumulates snow in the snow attachment.
+    pub fn new(attachment_index: AttachmentIndex) -> Self {
+        Self {
+            attachment_index,
+            accumulation_rate: 0.01,
+            melt_rate: 0.0,
+            max_depth: 1.0,
+            coverage_depth: 0.05,
+            max_slope: FRAC_PI_4,
+            slope_falloff: 0.15,
+            update_interval: 0.5,
+            state: default(),
+        }
+    }
+
+    /// Uses the depths, that the snow accumulates and melts per second.
+    pub fn with_rates(mut self, accumulation_rate: f32, melt_rate: f32) -> Self {
+        self.accumulation_rate = accumulation_rate;
+        self.melt_rate = melt_rate;
+        self
+    }
+
+    /// Calculates the fraction of the snow (from zero to one), that sticks to the slope angle.
+    fn slope_factor(&self, slope: f32) -> f32 {
+        let falloff = self.slope_falloff.max(f32::EPSILON);
+        let t = ((slope - (self.max_slope - falloff)) / falloff).clamp(0.0, 1.0);
+
+        1.0 - t * t * (3.0 - 2.0 * t)
+    }
+
+    /// Forgets the progress of the nodes, whose data has been replaced or edited this frame.
+    fn invalidate_nodes(&mut self, node_atlas: &NodeAtlas) {
+        let state = &mut self.state;
+
+        let invalidated_nodes = node_atlas
+            .lifecycle_events
+            .iter()
+            .filter(|&&(_, lifecycle)| {
+                matches!(
+                    lifecycle,
+                    NodeLifecycle::Activated | NodeLifecycle::Deactivated
+                )
+            })
+            .map(|&(node_id, _)| node_id)
+            .chain(node_atlas.edited_nodes.iter().copied());
+
+        for node_id in invalidated_nodes {
+            state.updated_nodes.remove(&node_id);
+            state.settled_nodes.remove(&node_id);
+            state.raised_nodes.remove(&node_id);
+        }
+    }
+
+    /// Accumulates and melts the snow of the requested nodes, from the most detailed to the
+    /// coarsest ones, and skips the nodes, whose snow has settled.
+    fn update(&mut self, node_atlas: &mut NodeAtlas, images: &mut Assets<Image>, delta: f32) {
+        let mut state = mem::take(&mut self.state);
+        state.time += delta;
+
+        let settings = [
+            self.accumulation_rate,
+            self.melt_rate,
+            self.max_depth,
+            self.coverage_depth,
+            self.max_slope,
+            self.slope_falloff,
+        ];
+
+        if state.settings != settings {
+            state.settled_nodes.clear();
+            state.settings = settings;
+        }
+
+        let mut node_ids = node_atlas
+            .requested_nodes()
+            .filter(|&(_, loaded, _)| loaded)
+            .map(|(node_id, _, _)| node_id)
+            .collect::<Vec<_>>();
+        node_ids.sort_by_key(|&node_id| NodeCoordinate::from(node_id).lod);
+
+        let mut updated_nodes = HashSet::new();
+        let mut changed_nodes = HashSet::new();
+
+        for node_id in node_ids {
+            let NodeCoordinate { lod, x, y } = node_id.into();
+
+            // the children are updated before their parent, which downsamples their snow
+            let children = (lod > 0).then(|| {
+                [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .map(|(dx, dy)| calc_node_id(lod - 1, 2 * x + dx, 2 * y + dy))
+                    .map(|child_id| updated_nodes.contains(&child_id).then_some(child_id))
+            });
+
+            let children_changed = children
+                .iter()
+                .flatten()
+                .flatten()
+                .any(|child_id| changed_nodes.contains(child_id));
+
+            let previous_time = state
+                .updated_nodes
+                .insert(node_id, state.time)
+                .unwrap_or(state.time - delta);
+            updated_nodes.insert(node_id);
+
+            if state.settled_nodes.contains(&node_id) && !children_changed {
+                continue;
+            }
+
+            let children = children.unwrap_or_default();
+
+            let Some((changed, max_depth)) = self.update_node(
+                node_atlas,
+                images,
+                node_id,
+                &children,
+                state.time - previous_time,
+            ) else {
+                continue;
+            };
+
+            if changed {
+                state.settled_nodes.remove(&node_id);
+                changed_nodes.insert(node_id);
+            } else {
+                state.settled_nodes.insert(node_id);
+            }
+
+            let raised_depth = state.raised_nodes.entry(node_id).or_default();
+
+            if max_depth > *raised_depth {
+                raise_minmax(node_atlas, images, node_id, max_depth - *raised_depth);
+                *raised_depth = max_depth;
+            }
+
+            // the bounds of the snowed nodes are updated by their consumers
+            if let Some(node) = node_atlas.nodes.get(&node_id) {
+                let data = &mut node_atlas.data[node.atlas_index as usize];
+
+                if changed || data.snow_depth != max_depth {
+                    data.snow_depth = max_depth;
+                    node_atlas.snowed_nodes.push(node_id);
+                }
+            }
+        }
+
+        self.state = state;
+    }
+
+    /// Accumulates and melts the snow of the loaded node during the elapsed time.
+    /// The regions covered by the updated children are downsampled from them instead.
+    ///
+    /// Returns whether the snow of the node has changed and its maximum depth
+    /// (in the local space of the terrain).
+    fn update_node(
+        &self,
+        node_atlas: &mut NodeAtlas,
+        images: &mut Assets<Image>,
+        node_id: NodeId,
+        children: &[Option<NodeId>; 4],
+        delta: f32,
+    ) -> Option<(bool, f32)> {
+        let (height, leaf_node_size) = (node_atlas.height, node_atlas.leaf_node_size);
+        let NodeAtlas {
+            ref attachments,
+            ref nodes,
+            ref data,
+            ref mut attachment_updates,
+            ..
+        } = *node_atlas;
+
+        let node = nodes.get(&node_id)?;
+        let node_data = &data[node.atlas_index as usize];
+
+        let snow_attachment = attachments.get(self.attachment_index)?;
+        let snow_handle = node_data.attachments.get(&self.attachment_index)?;
+        let height_handle = node_data.attachments.get(&HEIGHT_ATTACHMENT)?;
+
+        let NodeCoordinate { lod, x, y } = node_id.into();
+        let node_size = (leaf_node_size << lod) as f32;
+        let node_origin = Vec2::new(x as f32, y as f32) * node_size;
+        let size = snow_attachment.texture_size;
+
+        let child_images = children.map(|child_id| {
+            let child = nodes.get(&child_id?)?;
+            images.get(
+                data[child.atlas_index as usize]
+                    .attachments
+                    .get(&self.attachment_index)?,
+            )
+        });
+
+        // the slopes are computed from the heights of the node, before the snow is modified
+        let height_image = images.get(height_handle)?;
+
+        let targets = iproduct!(0..size, 0..size)
+            .map(|(y, x)| {
+                let position =
+                    snow_attachment.pixel_position(node_origin, node_size, UVec2::new(x, y));
+                let coords = (position - node_origin) / node_size;
+
+                if coords.cmpge(Vec2::ZERO).all() && coords.cmplt(Vec2::ONE).all() {
+                    let quadrant = (coords * 2.0).floor();
+
+                    if let Some(child_image) =
+                        child_images[(quadrant.y * 2.0 + quadrant.x) as usize]
+                    {
+                        let child_coords = coords * 2.0 - quadrant;
+
+                        return SnowTarget::Downsampled(Vec2::new(
+                            snow_attachment.sample(child_image, child_coords, 0),
+                            snow_attachment.sample(child_image, child_coords, 1),
+                        ));
+                    }
+                }
+
+                let slope = slope_at(
+                    &attachments[HEIGHT_ATTACHMENT],
+                    height_image,
+                    node_origin,
+                    node_size,
+                    height,
+                    position,
+                );
+
+                SnowTarget::Accumulated(self.slope_factor(slope))
+            })
+            .collect::<Vec<_>>();
+
+        let snow_image = images.get_mut(snow_handle)?;
+
+        let (_, channel_count) = snow_attachment.pixel_layout()?;
+        let mut changed: Option<(UVec2, UVec2)> = None;
+        let mut max_depth = 0.0_f32;
+
+        for (y, x) in iproduct!(0..size, 0..size) {
+            let pixel = UVec2::new(x, y);
+            let previous = Vec2::new(
+                snow_attachment.load(snow_image, x, y, 0),
+                snow_attachment.load(snow_image, x, y, channel_count.min(2) - 1),
+            );
+
+            let (depth, coverage) = match targets[(y * size + x) as usize] {
+                SnowTarget::Accumulated(slope_factor) => {
+                    let depth = previous.x * height;
+                    let depth = (depth - self.melt_rate * delta).max(0.0);
+
+                    // the snow only accumulates up to the maximum depth of the slope,
+                    // without removing any
+                    let accumulated = depth + self.accumulation_rate * slope_factor * delta;
+                    let depth = depth.max(accumulated.min(self.max_depth * slope_factor));
+                    let coverage = (depth / self.coverage_depth.max(f32::EPSILON)).min(1.0);
+
+                    (depth / height, coverage)
+                }
+                SnowTarget::Downsampled(snow) => (snow.x, snow.y),
+            };
+
+            snow_attachment.store(snow_image, x, y, 0, depth);
+
+            if channel_count > 1 {
+                snow_attachment.store(snow_image, x, y, 1, coverage);
+            }
+
+            max_depth = max_depth.max(depth * height);
+
+            let current = Vec2::new(
+                snow_attachment.load(snow_image, x, y, 0),
+                snow_attachment.load(snow_image, x, y, channel_count.min(2) - 1),
+            );
+
+            if current != previous {
+                changed = Some(match changed {
+                    Some((first, last)) => (first.min(pixel), last.max(pixel)),
+                    None => (pixel, pixel),
+                });
+            }
+        }
+
+        if let Some((first, last)) = changed {
+            attachment_updates.extend(snow_attachment.updates(
+                snow_image,
+                node.atlas_index,
+                self.attachment_index,
+                first,
+                last,
+            ));
+        }
+
+        Some((changed.is_some(), max_depth))
+    }
+}
+
+/// The new snow of a pixel of a node.
+#[derive(Clone, Copy)]
+enum SnowTarget {
+    /// The snow accumulates and melts with the fraction of the snow, that sticks to the slope.
+    Accumulated(f32),
+    /// The normalized depth and the coverage of the snow, downsampled from a child.
+    Downsampled(Vec2),
+}
+
+/// Raises the maximum heights of the minmax attachment of the node by the depth
+/// (in the local space of the terrain), so that its bounds contain the snow.
+fn raise_minmax(
+    node_atlas: &mut NodeAtlas,
+    images: &mut Assets<Image>,
+    node_id: NodeId,
+    depth: f32,
+) {
+    let height = node_atlas.height;
+    let NodeAtlas {
+        ref attachments,
+        ref nodes,
+        ref data,
+        ref mut attachment_updates,
+        ..
+    } = *node_atlas;
+
+    let Some(node) = nodes.get(&node_id) else {
+        return;
+    };
+
+    let node_data = &data[node.atlas_index as usize];
+
+    let (Some(attachment), Some(image)) = (
+        attachments
+            .get(MINMAX_ATTACHMENT)
+            .filter(|attachment| attachment.format() == TextureFormat::Rg16Unorm),
+        node_data
+            .attachments
+            .get(&MINMAX_ATTACHMENT)
+            .and_then(|handle| images.get_mut(handle)),
+    ) else {
+        return;
+    };
+
+    let size = attachment.texture_size;
+
+    for (y, x) in iproduct!(0..size, 0..size) {
+        let max = attachment.load(image, x, y, 1) + depth / height;
+        attachment.store(image, x, y, 1, max);
+    }
+
+    attachment_updates.extend(attachment.updates(
+        image,
+        node.atlas_index,
+        MINMAX_ATTACHMENT,
+        UVec2::ZERO,
+        UVec2::splat(size - 1),
+    ));
+}
+
+/// Calculates the slope angle in radians at the position (in the local space of the terrain)
+/// from the heights of the node.
+fn slope_at(
+    attachment: &AtlasAttachment,
+    image: &Image,
+    node_origin: Vec2,
+    node_size: f32,
+    height: f32,
+    position: Vec2,
+) -> f32 {
+    let pixel_size = node_size / attachment.center_size as f32;
+    let pixel = ((position - node_origin) / pixel_size + attachment.border_size as f32 - 0.5)
+        .round()
+        .as_ivec2()
+        .clamp(IVec2::ONE, IVec2::splat(attachment.texture_size as i32 - 2))
+        .as_uvec2();
+
+    let load = |x: u32, y: u32| attachment.load(image, x, y, 0) * height;
+
+    let gradient = Vec2::new(
+        load(pixel.x + 1, pixel.y) - load(pixel.x - 1, pixel.y),
+        load(pixel.x, pixel.y + 1) - load(pixel.x, pixel.y - 1),
+    ) / (2.0 * pixel_size);
+
+    gradient.length().atan()
+}
+
+/// Accumulates the snow of the terrains with a [`TerrainSnow`] component.
+pub struct TerrainSnowPlugin;
+
+impl Plugin for TerrainSnowPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(
+            update_terrain_snow
+                .after(TerrainSystemSet::Update)
+                .in_base_set(CoreSet::Last),
+        );
+    }
+}
+
+/// Updates the snow of the requested nodes, once the update interval of the terrain has elapsed.
+fn update_terrain_snow(
+    time: Res<Time>,
+    mut images: ResMut<Assets<Image>>,
+    mut terrain_query: Query<(&mut NodeAtlas, &mut TerrainSnow)>,
+) {
+    for (mut node_atlas, mut snow) in terrain_query.iter_mut() {
+        // the snowed nodes of the previous update have been seen by the consumers in the meantime
+        node_atlas.snowed_nodes.clear();
+        node_atlas.snow_attachment = Some(snow.attachment_index);
+
+        snow.invalidate_nodes(&node_atlas);
+        snow.state.elapsed += time.delta_seconds();
+
+        if snow.state.elapsed < snow.update_interval {
+            continue;
+        }
+
+        let delta = mem::take(&mut snow.state.elapsed);
+        snow.update(&mut node_atlas, &mut images, delta);
+    }
+}
@@ -2,10 +2,28 @@
 
 use crate::{
     attachment_loader::{AttachmentFromDisk, AttachmentFromDiskLoader},
-    preprocess::{BaseConfig, Preprocessor, TileConfig},
-    terrain_data::{AtlasAttachment, AttachmentConfig, AttachmentIndex, NodeId},
+    edit::history::DEFAULT_EDIT_HISTORY_SIZE,
+    node_source::{
+        AttachmentFromSourceLoader, HeightFunction, ImageSource, NodeSource, ProceduralSource,
+    },
+    preprocess::{
+        ambient_occlusion::AmbientOcclusionConfig, horizon::HorizonConfig, BaseConfig,
+        Preprocessor, TileConfig,
+    },
+    render::node_generator::AttachmentFromGpuLoader,
+    seeded::SeededNoise,
+    terrain_data::{
+        calc_node_id, AtlasAttachment, AttachmentConfig, AttachmentFormat, AttachmentIndex, NodeId,
+        MAX_ATTACHMENT_COUNT,
+    },
 };
+
+#[cfg(feature = "mmap")]
+use crate::mapped_source::{MappedHeightmap, MappedSource};
+#[cfg(feature = "remote")]
+use crate::remote_loader::{AttachmentFromUrlLoader, TileServer, UrlSource};
 use bevy::{
+    math::DVec3,
     prelude::*,
     render::extract_component::ExtractComponent,
     utils::{HashMap, HashSet},
@@ -38,13 +56,108 @@ impl<C> FromWorld for TerrainComponents<C> {
 }
 
 /// A marker component used to identify a terrain entity.
-#[derive(Clone, Copy, Component, ExtractComponent)]
+#[derive(Clone, Copy, Default, Component, ExtractComponent, Reflect)]
+#[reflect(Component)]
 pub struct Terrain;
 
+/// Places the terrain edge to edge with other tiles of the same world.
+///
+/// The offset is measured in terrains. Tiles with adjacent offsets are neighbours, whose
+/// quadtrees select the same lod for the nodes along their shared edges, so that the joins
+/// stay free of cracks. Neighbouring tiles have to share the same size, lod count and view configs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Component, Reflect)]
+#[reflect(Component)]
+pub struct TerrainTile(pub IVec2);
+
+/// Transforms a world space position into the local space of the terrain.
+///
+/// All level of detail decisions are made in this space, which allows terrains to be
+/// translated, rotated and scaled arbitrarily.
+pub fn world_to_terrain(terrain_transform: &GlobalTransform, position: Vec3) -> Vec3 {
+    world_to_terrain_precise(terrain_transform, position).as_vec3()
+}
+
+/// Transforms a world space position into the local space of the terrain in double precision.
+///
+/// This preserves the precision of positions far away from the origin of the terrain,
+/// which is required for planetary-scale terrains.
+pub(crate) fn world_to_terrain_precise(
+    terrain_transform: &GlobalTransform,
+    position: Vec3,
+) -> DVec3 {
+    terrain_transform
+        .compute_matrix()
+        .as_dmat4()
+        .inverse()
+        .transform_point3(position.as_dvec3())
+}
+
+/// Computes the transform from the local space of the terrain into world space,
+/// relative to the camera position.
+///
+/// Rendering relative to the camera preserves the precision of the geometry close to the camera,
+/// regardless of the distance to the origin.
+pub(crate) fn terrain_to_camera(
+    terrain_transform: &GlobalTransform,
+    camera_position: Vec3,
+) -> Mat4 {
+    let mut model = terrain_transform.compute_matrix().as_dmat4();
+    model.w_axis -= camera_position.as_dvec3().extend(0.0);
+    model.as_mat4()
+}
+
+/// The geometry, which the terrain is rendered with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect, FromReflect)]
+pub enum TerrainGeometry {
+    /// Tiles of varying size, which are refined like a quadtree on the GPU around each view.
+    #[default]
+    Quadtree,
+    /// A geometry clipmap, consisting of concentric rings of tiles following the view,
+    /// whose tiles double in size with each ring.
+    ///
+    /// The amount of tiles is bounded by the ring count and size, which makes its GPU cost
+    /// predictable. The terrain is still sampled from the same node atlas.
+    Clipmap {
+        /// The count of rings.
+        ring_count: u32,
+        /// The amount of tiles from the center to the edge of each ring, which has to be at least four.
+        ring_size: u32,
+    },
+}
+
+/// The geometry, which hides the cracks between adjacent tiles of different lods.
+#[derive(Clone, Copy, Debug, PartialEq, Reflect, FromReflect)]
+pub enum SeamGeometry {
+    /// No additional geometry, the mesh morph alone has to close the cracks.
+    None,
+    /// An additional ring of lowered vertices around each tile, which covers the cracks.
+    Skirts {
+        /// The depth of the skirts (measured in multiples of the tile size).
+        depth: f32,
+    },
+    /// Snaps the vertices on the edges of each tile onto the grid of the next coarser lod,
+    /// so that adjacent tiles share their edge vertices.
+    ///
+    /// This closes the cracks without additional vertices, as long as adjacent tiles
+    /// differ by at most one lod, in exchange for less detail along the edges.
+    Stitches,
+}
+
+impl Default for SeamGeometry {
+    fn default() -> Self {
+        Self::Skirts { depth: 0.1 }
+    }
+}
+
 /// The configuration of a terrain.
 ///
 /// Here you can define all fundamental parameters of the terrain.
-#[derive(Clone, Component)]
+///
+/// Scenes store the config alongside the [`Terrain`] marker.
+/// Once a terrain is loaded from a scene, its node atlas is recreated from the config,
+/// but its loaders and material have to be added again.
+#[derive(Clone, Component, Reflect)]
+#[reflect(Component)]
 pub struct TerrainConfig {
     /// The count of level of detail layers.
     pub lod_count: u32,
@@ -54,13 +167,74 @@ pub struct TerrainConfig {
     pub leaf_node_size: u32, // Todo: reconsider this
     /// The size of the terrain.
     pub terrain_size: u32, // Todo: reconsider this
+    /// The extent of the terrain in x and z direction.
+    ///
+    /// Defaults to a square of the terrain size, see [`TerrainConfig::with_extent`] for rectangular terrains.
+    pub terrain_extent: UVec2,
+    /// The radius of the planet (in local units), if the terrain is one of its faces.
+    pub planet_radius: Option<f32>,
+    /// The geometry, which the terrain is rendered with.
+    pub geometry: TerrainGeometry,
+    /// The geometry, which hides the cracks between the tiles.
+    pub seams: SeamGeometry,
     /// The amount of nodes the can be loaded simultaneously in the node atlas.
     pub node_atlas_size: u32,
+    /// The amount of nodes, that the node atlas can grow to at runtime,
+    /// once all of its atlas indices are requested.
+    ///
+    /// Defaults to the size of the node atlas, so that it does not grow.
+    /// See [`TerrainConfig::with_max_node_atlas_size`] for the limits of the GPU.
+    pub max_node_atlas_size: u32,
+    /// The maximum amount of nodes, that are kept in the node atlas after they are no longer used.
+    ///
+    /// Defaults to the size of the node atlas, so that unused nodes are only evicted
+    /// once their atlas index is required.
+    pub cache_size: u32,
+    /// The maximum amount of nodes, that start loading per frame.
+    ///
+    /// Requested nodes are loaded in the order of their distance to the nearest viewer,
+    /// so that the remaining ones are the furthest away. Defaults to unlimited.
+    pub load_budget: Option<u32>,
+    /// The maximum amount of nodes, that are activated per frame after they finished loading.
+    ///
+    /// Limiting this prevents hitches after fast camera movements, the remaining nodes are
+    /// activated in the following frames. Defaults to unlimited.
+    pub activation_budget: Option<u32>,
+    /// The maximum amount of (edited) attachment regions, that are written into the
+    /// node atlas per frame. Defaults to unlimited.
+    pub atlas_write_budget: Option<u32>,
+    /// The maximum amount of loaded nodes, that are migrated into lower atlas indices per frame,
+    /// to defragment the node atlas during long sessions.
+    ///
+    /// Each migrated node is copied on the GPU and adjusts the quadtrees.
+    /// Defaults to none, which disables the compaction.
+    pub compaction_budget: Option<u32>,
+    /// The maximum amount of runtime edits, that can be undone.
+    ///
+    /// Older edits are baked into the data of the nodes they affect, which is kept in memory
+    /// like restored saves, so that only the recent edits are reapplied to loaded nodes.
+    /// Defaults to 64.
+    pub edit_history_size: u32,
     /// The path to the terrain folder inside the assets directory.
     pub path: String,
     /// The attachments of the terrain.
     pub attachments: Vec<AtlasAttachment>,
+    /// The nodes of the terrain, that can be loaded.
+    ///
+    /// These are not reflected, but restored from the height bounds for terrains loaded from a scene.
+    #[reflect(ignore)]
     pub nodes: HashSet<NodeId>,
+    /// The normalized minimum and maximum height of the nodes, determined during preprocessing.
+    ///
+    /// These are used for nodes, whose height data is not accessible on the CPU.
+    pub height_bounds: HashMap<NodeId, Vec2>,
+}
+
+impl Default for TerrainConfig {
+    /// An empty config, which the terrains loaded from a scene are based on.
+    fn default() -> Self {
+        Self::new(0, 1, 1.0, 0, String::new())
+    }
 }
 
 impl TerrainConfig {
@@ -76,23 +250,109 @@ impl TerrainConfig {
             height,
             leaf_node_size: 0,
             terrain_size,
+            terrain_extent: UVec2::splat(terrain_size),
+            planet_radius: None,
+            geometry: default(),
+            seams: default(),
             node_atlas_size,
+            max_node_atlas_size: node_atlas_size,
+            cache_size: node_atlas_size,
+            load_budget: None,
+            activation_budget: None,
+            atlas_write_budget: None,
+            compaction_budget: None,
+            edit_history_size: DEFAULT_EDIT_HISTORY_SIZE,
             path,
             attachments: vec![],
             nodes: HashSet::new(),
+            height_bounds: HashMap::new(),
         }
     }
 }
 
 impl TerrainConfig {
+    /// Returns the GPU memory (in bytes) occupied by a single node, including all attachments
+    /// and their mip levels.
+    pub fn node_memory_size(&self) -> u64 {
+        self.attachments
+            .iter()
+            .map(|attachment| {
+                let info = attachment.format().describe();
+                let (block_width, block_height) = info.block_dimensions;
+
+                (0..attachment.mip_level_count)
+                    .map(|mip_level| {
+                        let size = (attachment.texture_size >> mip_level).max(1);
+                        let blocks_x = (size + block_width as u32 - 1) / block_width as u32;
+                        let blocks_y = (size + block_height as u32 - 1) / block_height as u32;
+
+                        (blocks_x * blocks_y) as u64 * info.block_size as u64
+                    })
+                    .sum::<u64>()
+            })
+            .sum()
+    }
+
+    /// Uses a rectangular extent (in pixels) instead of a square one.
+    ///
+    /// The extent does not have to be a power of two, or a multiple of the node size,
+    /// the nodes on the edges of the terrain are only partially covered instead.
+    /// The terrain size is set to the larger side, which the lod count has to cover.
+    ///
+    /// Has to be called before any attachments are added.
+    pub fn with_extent(mut self, extent: UVec2) -> Self {
+        self.terrain_size = extent.max_element();
+        self.terrain_extent = extent;
+        self
+    }
+
+    /// Limits the size of the node atlas and the cache to the GPU memory budget (in bytes).
+    ///
+    /// Has to be called after all attachments have been added.
+    pub fn with_memory_budget(mut self, budget: u64) -> Self {
+        let node_memory_size = self.node_memory_size().max(1);
+
+        self.node_atlas_size = (budget / node_memory_size).min(u16::MAX as u64) as u32;
+        self.max_node_atlas_size = self.node_atlas_size;
+        self.cache_size = self.cache_size.min(self.node_atlas_size);
+        self
+    }
+
+    /// Lets the node atlas grow up to the maximum size at runtime, instead of leaving the
+    /// requested nodes queued, once all of its atlas indices are in use.
+    ///
+    /// Each time the atlas fills up, its size is doubled and its attachments are reallocated
+    /// on the GPU. The maximum size is clamped to the `max_texture_array_layers` limit
+    /// of the device (or to the shards of a sharded atlas).
+    pub fn with_max_node_atlas_size(mut self, max_size: u32) -> Self {
+        self.max_node_atlas_size = max_size.clamp(self.node_atlas_size, u16::MAX as u32);
+        self
+    }
+
     /// Adds an attachment to the terrain.
     ///
     /// The attachment will not be loaded automatically, but the caller has to handle the loading instead.
+    /// Each attachment has its own format and resolution, but all of them are streamed together
+    /// per node. A terrain supports up to [`MAX_ATTACHMENT_COUNT`] attachments.
     pub fn add_attachment(&mut self, attachment: AttachmentConfig) -> AttachmentIndex {
+        assert!(
+            self.attachments.len() < MAX_ATTACHMENT_COUNT,
+            "A terrain can not have more than {MAX_ATTACHMENT_COUNT} attachments."
+        );
+
         self.attachments.push(attachment.into());
         self.attachments.len() - 1
     }
 
+    /// Returns the index of the attachment with the name, if the terrain has one.
+    ///
+    /// This allows systems to look up custom attachments, instead of relying on their order.
+    pub fn attachment_index(&self, name: &str) -> Option<AttachmentIndex> {
+        self.attachments
+            .iter()
+            .position(|attachment| attachment.name == name)
+    }
+
     /// Adds an attachment to the terrain, which will be loaded from disk automatically.
     pub fn add_attachment_from_disk(
         &mut self,
@@ -111,6 +371,250 @@ impl TerrainConfig {
         preprocessor.attachments.push((tile, attachment));
     }
 
+    /// Adds an attachment to the terrain, which will be loaded from the node source automatically.
+    pub fn add_attachment_from_source(
+        &mut self,
+        loader: &mut AttachmentFromSourceLoader,
+        attachment: AttachmentConfig,
+        source: impl NodeSource,
+    ) {
+        let attachment_index = self.add_attachment(attachment);
+
+        loader.sources.insert(attachment_index, Box::new(source));
+    }
+
+    /// Adds the base attachment, which will be synthesized from the height function on demand.
+    ///
+    /// Nodes, that have been preprocessed from authored data, are loaded from disk instead.
+    /// All nodes covered by the terrain are assumed to exist.
+    pub fn add_procedural_base_attachment(
+        &mut self,
+        loader: &mut AttachmentFromSourceLoader,
+        base: BaseConfig,
+        height: HeightFunction,
+    ) {
+        self.leaf_node_size = base.texture_size - 2 * base.border_size;
+
+        let height_source = ProceduralSource::new(self, &base.height_attachment(), height.clone());
+        let minmax_source = ProceduralSource::new(self, &base.minmax_attachment(), height);
+
+        loader
+            .sources
+            .insert(self.attachments.len(), Box::new(height_source));
+        loader
+            .sources
+            .insert(self.attachments.len() + 1, Box::new(minmax_source));
+
+        self.add_base_attachment(base);
+        self.add_all_nodes();
+    }
+
+    /// Adds the base attachment, which will be generated deterministically from the seeded noise.
+    ///
+    /// Unlike [`TerrainConfig::add_procedural_base_attachment`], the generated nodes are
+    /// bit-identical across platforms, e.g. for multiplayer clients generating the terrain locally.
+    /// Nodes, that have been preprocessed from authored data, are loaded from disk instead.
+    /// All nodes covered by the terrain are assumed to exist.
+    pub fn add_seeded_base_attachment(
+        &mut self,
+        loader: &mut AttachmentFromSourceLoader,
+        base: BaseConfig,
+        noise: SeededNoise,
+    ) {
+        self.leaf_node_size = base.texture_size - 2 * base.border_size;
+
+        let height_source = ProceduralSource::seeded(self, &base.height_attachment(), noise);
+        let minmax_source = ProceduralSource::seeded(self, &base.minmax_attachment(), noise);
+
+        loader
+            .sources
+            .insert(self.attachments.len(), Box::new(height_source));
+        loader
+            .sources
+            .insert(self.attachments.len() + 1, Box::new(minmax_source));
+
+        self.add_base_attachment(base);
+        self.add_all_nodes();
+    }
+
+    /// Adds the base attachment, which will be sliced from the heightmap image at runtime.
+    ///
+    /// This skips the preprocessing and is intended for prototyping small terrains.
+    /// All nodes covered by the terrain are assumed to exist.
+    pub fn add_base_attachment_from_image(
+        &mut self,
+        loader: &mut AttachmentFromSourceLoader,
+        base: BaseConfig,
+        heightmap: Handle<Image>,
+    ) {
+        self.leaf_node_size = base.texture_size - 2 * base.border_size;
+
+        let height_source = ImageSource::new(self, &base.height_attachment(), heightmap);
+        let minmax_source = height_source.sibling(&base.minmax_attachment());
+
+        loader
+            .sources
+            .insert(self.attachments.len(), Box::new(height_source));
+        loader
+            .sources
+            .insert(self.attachments.len() + 1, Box::new(minmax_source));
+
+        self.add_base_attachment(base);
+        self.add_all_nodes();
+    }
+
+    /// Adds the base attachment, which will be sliced from the memory-mapped heightmap on demand.
+    ///
+    /// This avoids preprocessing large heightmaps into node files.
+    /// All nodes covered by the terrain are assumed to exist.
+    #[cfg(feature = "mmap")]
+    pub fn add_mapped_base_attachment(
+        &mut self,
+        loader: &mut AttachmentFromSourceLoader,
+        base: BaseConfig,
+        heightmap: std::sync::Arc<MappedHeightmap>,
+    ) {
+        self.leaf_node_size = base.texture_size - 2 * base.border_size;
+
+        let height_source = MappedSource::new(self, &base.height_attachment(), heightmap.clone());
+        let minmax_source = MappedSource::new(self, &base.minmax_attachment(), heightmap);
+
+        loader
+            .sources
+            .insert(self.attachments.len(), Box::new(height_source));
+        loader
+            .sources
+            .insert(self.attachments.len() + 1, Box::new(minmax_source));
+
+        self.add_base_attachment(base);
+        self.add_all_nodes();
+    }
+
+    /// Adds an attachment to the terrain, which will be generated by the compute shader on the GPU.
+    ///
+    /// The attachment has to use the [`AttachmentFormat::R32F`].
+    pub fn add_attachment_from_gpu(
+        &mut self,
+        loader: &mut AttachmentFromGpuLoader,
+        attachment: AttachmentConfig,
+        shader: Handle<Shader>,
+    ) {
+        assert!(
+            matches!(attachment.format, AttachmentFormat::R32F),
+            "Only R32F attachments can be generated on the GPU."
+        );
+
+        let attachment_index = self.add_attachment(attachment);
+
+        loader.generators.insert(attachment_index, shader);
+    }
+
+    /// Adds the base attachment, whose height data will be generated by the compute shader on the GPU.
+    ///
+    /// The heights are stored as 32 bit floats and the minmax data spans the entire height range,
+    /// as it can not be derived on the CPU.
+    /// All nodes covered by the terrain are assumed to exist.
+    pub fn add_gpu_base_attachment(
+        &mut self,
+        loader: &mut AttachmentFromGpuLoader,
+        base: BaseConfig,
+        shader: Handle<Shader>,
+    ) {
+        let base = base.with_float_heights();
+
+        self.leaf_node_size = base.texture_size - 2 * base.border_size;
+
+        loader.generators.insert(self.attachments.len(), shader);
+        loader.minmax_attachment = Some(self.attachments.len() + 1);
+
+        self.add_base_attachment(base);
+        self.add_all_nodes();
+    }
+
+    /// Adds an ambient occlusion attachment to the terrain, which will be loaded from disk automatically.
+    ///
+    /// Instead of a source tile, the occlusion is baked from the heights during preprocessing.
+    /// The default terrain shader samples it, if it is added directly after the base attachment
+    /// (see [`AMBIENT_OCCLUSION_ATTACHMENT`](crate::preprocess::ambient_occlusion::AMBIENT_OCCLUSION_ATTACHMENT)).
+    pub fn add_ambient_occlusion_attachment_from_disk(
+        &mut self,
+        preprocessor: &mut Preprocessor,
+        loader: &mut AttachmentFromDiskLoader,
+        attachment: AttachmentConfig,
+        ambient_occlusion: AmbientOcclusionConfig,
+    ) {
+        let attachment_index = self.add_attachment(attachment.clone());
+
+        loader.attachments.insert(
+            attachment_index,
+            AttachmentFromDisk::new(&attachment, &self.path),
+        );
+
+        preprocessor
+            .ambient_occlusions
+            .push((ambient_occlusion, attachment));
+    }
+
+    /// Adds a horizon attachment to the terrain, which will be loaded from disk automatically.
+    ///
+    /// Like the ambient occlusion, the horizon map is baked from the heights during preprocessing.
+    /// The default terrain shader shadows the terrain from the sun with it, if it is added
+    /// directly after the ambient occlusion attachment
+    /// (see [`HORIZON_ATTACHMENT`](crate::preprocess::horizon::HORIZON_ATTACHMENT)).
+    pub fn add_horizon_attachment_from_disk(
+        &mut self,
+        preprocessor: &mut Preprocessor,
+        loader: &mut AttachmentFromDiskLoader,
+        attachment: AttachmentConfig,
+        horizon: HorizonConfig,
+    ) {
+        let attachment_index = self.add_attachment(attachment.clone());
+
+        loader.attachments.insert(
+            attachment_index,
+            AttachmentFromDisk::new(&attachment, &self.path),
+        );
+
+        preprocessor.horizons.push((horizon, attachment));
+    }
+
+    /// Adds a normal attachment to the terrain, which will be loaded from disk automatically.
+    ///
+    /// The normals are baked from the heights during preprocessing and replace the normals
+    /// derived from the heights in the shader, which are faceted at low lods.
+    /// The default terrain shader samples them, once the index of the attachment is configured
+    /// as the [`TerrainPlugin::normal_attachment`](crate::TerrainPlugin).
+    pub fn add_normal_attachment_from_disk(
+        &mut self,
+        preprocessor: &mut Preprocessor,
+        loader: &mut AttachmentFromDiskLoader,
+        attachment: AttachmentConfig,
+    ) {
+        let attachment_index = self.add_attachment(attachment.clone());
+
+        loader.attachments.insert(
+            attachment_index,
+            AttachmentFromDisk::new(&attachment, &self.path),
+        );
+
+        preprocessor.normals.push(attachment);
+    }
+
+    /// Adds an albedo attachment to the terrain, which will be loaded from disk automatically.
+    ///
+    /// The color imagery of the tile (e.g. an orthophoto) is draped over the elevation data.
+    /// The default terrain shader uses it as the base color of the surface, once its index is
+    /// configured as the [`TerrainPlugin::albedo_attachment`](crate::TerrainPlugin).
+    pub fn add_albedo_attachment_from_disk(
+        &mut self,
+        preprocessor: &mut Preprocessor,
+        loader: &mut AttachmentFromDiskLoader,
+        attachment: AttachmentConfig,
+        tile: TileConfig,
+    ) {
+        self.add_attachment_from_disk(preprocessor, loader, attachment, tile);
+    }
+
     /// Adds the base attachment, which contains a height and minmax information.
     ///
     /// This is required by terrains, that use the default render pipeline.
@@ -141,4 +645,54 @@ impl TerrainConfig {
 
         preprocessor.base = Some((tile, base));
     }
+
+    /// Adds an attachment to the terrain, which will be streamed from the tile server automatically.
+    #[cfg(feature = "remote")]
+    pub fn add_attachment_from_url(
+        &mut self,
+        loader: &mut AttachmentFromUrlLoader,
+        attachment: AttachmentConfig,
+        server: TileServer,
+    ) {
+        let source = UrlSource::new(self, &attachment, server);
+        let attachment_index = self.add_attachment(attachment);
+
+        loader.add_source(attachment_index, source);
+    }
+
+    /// Adds the base attachment, whose height data will be streamed from the tile server automatically.
+    ///
+    /// The minmax data is derived from the height of each tile and all nodes
+    /// covered by the terrain are assumed to be available on the server.
+    #[cfg(feature = "remote")]
+    pub fn add_base_attachment_from_url(
+        &mut self,
+        loader: &mut AttachmentFromUrlLoader,
+        base: BaseConfig,
+        server: TileServer,
+    ) {
+        self.leaf_node_size = base.texture_size - 2 * base.border_size;
+
+        loader.add_source(
+            self.attachments.len(),
+            UrlSource::new(self, &base.height_attachment(), server),
+        );
+        loader.minmax_attachment = Some(self.attachments.len() + 1);
+
+        self.add_base_attachment(base);
+        self.add_all_nodes();
+    }
+
+    /// Marks all nodes covered by the terrain as existing, for terrains whose nodes are
+    /// not known upfront.
+    pub(crate) fn add_all_nodes(&mut self) {
+        for lod in 0..self.lod_count {
+            let node_size = self.leaf_node_size << lod;
+            let node_count = (self.terrain_extent + node_size - 1) / node_size;
+
+            for (x, y) in itertools::iproduct!(0..node_count.x, 0..node_count.y) {
+                self.nodes.insert(calc_node_id(lod, x, y));
+            }
+        }
+    }
 }
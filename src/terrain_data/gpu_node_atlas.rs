@@ -1,4 +1,9 @@
 use crate::{
+    edit::AttachmentUpdate,
+    render::{
+        node_generator::AttachmentFromGpuLoader, normals::RecomputeNormals,
+        render_pipeline::TerrainPipelineConfig, terrain_data::TerrainData,
+    },
     terrain::{Terrain, TerrainComponents},
     terrain_data::{
         node_atlas::{LoadingNode, NodeAtlas},
@@ -6,105 +11,390 @@ use crate::{
     },
 };
 use bevy::{
+    core::cast_slice,
     prelude::*,
     render::{
         render_asset::RenderAssets,
         render_resource::*,
-        renderer::{RenderDevice, RenderQueue},
+        renderer::{RenderAdapter, RenderDevice, RenderQueue},
+        settings::WgpuLimits,
         texture::GpuImage,
         Extract, MainWorld,
     },
 };
-use std::mem;
+use std::{iter, mem, num::NonZeroU32};
 
 impl AtlasAttachment {
     /// Creates the attachment from its config.
+    ///
+    /// Generated and recomputed attachments are written by compute shaders and thus require
+    /// storage access. All attachments can be copied, so that the atlas can grow.
     fn create(
         &self,
         device: &RenderDevice,
         images: &mut RenderAssets<Image>,
-        node_atlas_size: AtlasIndex,
-    ) -> Handle<Image> {
+        handle: &Handle<Image>,
+        layer_count: u32,
+        generated: bool,
+    ) {
+        let mut usage =
+            TextureUsages::COPY_SRC | TextureUsages::COPY_DST | TextureUsages::TEXTURE_BINDING;
+
+        if generated {
+            usage |= TextureUsages::STORAGE_BINDING;
+        }
+
         let texture = device.create_texture(&TextureDescriptor {
             label: Some(&(self.name.to_string() + "_attachment")),
             size: Extent3d {
                 width: self.texture_size,
                 height: self.texture_size,
-                depth_or_array_layers: node_atlas_size as u32,
+                depth_or_array_layers: layer_count,
             },
             mip_level_count: self.mip_level_count,
             sample_count: 1,
             dimension: TextureDimension::D2,
-            format: self.format,
-            usage: TextureUsages::COPY_DST | TextureUsages::TEXTURE_BINDING,
+            format: self.format(),
+            usage,
             view_formats: &[],
         });
 
         images.insert(
-            self.handle.clone(),
+            handle.clone(),
             GpuImage {
-                texture_view: texture.create_view(&TextureViewDescriptor::default()),
+                // a texture with a single layer is viewed as an array as well
+                texture_view: texture.create_view(&TextureViewDescriptor {
+                    dimension: Some(TextureViewDimension::D2Array),
+                    ..default()
+                }),
                 texture,
-                texture_format: self.format,
+                texture_format: self.format(),
                 sampler: device.create_sampler(&SamplerDescriptor::default()),
                 size: Vec2::splat(self.texture_size as f32),
                 mip_level_count: self.mip_level_count,
             },
         );
+    }
+
+    /// Returns the extent of the mip level including the partially covered compression blocks.
+    fn physical_mip_size(&self, mip_level: u32) -> Extent3d {
+        let (block_width, block_height) = self.format().describe().block_dimensions;
+        let (block_width, block_height) = (block_width as u32, block_height as u32);
+        let size = (self.texture_size >> mip_level).max(1);
 
-        self.handle.clone()
+        Extent3d {
+            width: (size + block_width - 1) / block_width * block_width,
+            height: (size + block_height - 1) / block_height * block_height,
+            depth_or_array_layers: 1,
+        }
     }
 }
 
+/// Creates the buffer of the height bounds of all nodes of the atlas.
+fn create_bounds_buffer(device: &RenderDevice, node_atlas_size: AtlasIndex) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: "node_bounds_buffer".into(),
+        size: mem::size_of::<Vec2>() as BufferAddress * node_atlas_size as BufferAddress,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Returns the maximum amount of nodes, that a node atlas with the shard size and count can store
+/// on a device with the limits.
+///
+/// Each shard is a single texture array, whose layer count is limited by the device.
+pub(crate) fn node_atlas_limit(
+    limits: &WgpuLimits,
+    atlas_shard_size: Option<u32>,
+    atlas_shard_count: usize,
+) -> AtlasIndex {
+    let max_layers = limits.max_texture_array_layers;
+
+    let limit = match atlas_shard_size {
+        Some(shard_size) if shard_size.max(1) <= max_layers => {
+            shard_size.max(1).saturating_mul(atlas_shard_count as u32)
+        }
+        Some(shard_size) => {
+            error!(
+                "The atlas shard size ({shard_size}) exceeds the {max_layers} texture array layers supported by this device, only the first shard is used."
+            );
+            max_layers
+        }
+        None => max_layers,
+    };
+
+    limit.min(AtlasIndex::MAX as u32) as AtlasIndex
+}
+
 /// Stores the GPU representation of the [`NodeAtlas`] (array textures)
 /// alongside the data to update it.
 ///
 /// All attachments of newly loaded nodes are copied into their according atlas attachment.
 #[derive(Component)]
 pub struct GpuNodeAtlas {
-    /// Stores the atlas attachments of the terrain.
-    pub(crate) attachments: Vec<Handle<Image>>,
+    /// Stores the shards of the atlas attachments of the terrain.
+    ///
+    /// Unsharded atlases store a single shard per attachment.
+    /// The shards are allocated, once the atlas grows into them.
+    pub(crate) attachments: Vec<Vec<Handle<Image>>>,
     /// Stores the nodes, that have finished loading this frame.
     pub(crate) loaded_nodes: Vec<LoadingNode>,
+    /// Stores the regions of the attachments, that have been edited this frame.
+    pub(crate) attachment_updates: Vec<AttachmentUpdate>,
+    /// Stores the previous and current atlas indices of the nodes, that have been migrated
+    /// this frame.
+    pub(crate) migrated_nodes: Vec<(AtlasIndex, AtlasIndex)>,
+    /// Stores the height bounds of all nodes, indexed by their atlas index,
+    /// which are used to cull the tiles on the GPU.
+    pub(crate) bounds_buffer: Buffer,
+    /// Stores the height bounds of the nodes, that have been loaded or edited this frame.
+    pub(crate) bounds_updates: Vec<(AtlasIndex, Vec2)>,
+    /// The configurations of the atlas attachments, alongside whether they are written
+    /// by compute shaders, which are required to reallocate them.
+    pub(crate) atlas_attachments: Vec<(AtlasAttachment, bool)>,
+    /// The amount of nodes, that the atlas attachments are currently allocated for.
+    pub(crate) size: AtlasIndex,
+    /// The size of the [`NodeAtlas`], which the attachments have to grow to.
+    requested_size: AtlasIndex,
+    /// The amount of atlas indices per shard.
+    pub(crate) shard_size: u32,
 }
 
 impl GpuNodeAtlas {
     /// Creates a new gpu node atlas and initializes its attachment textures.
+    #[allow(clippy::too_many_arguments)]
     fn new(
         device: &RenderDevice,
+        adapter: &RenderAdapter,
         images: &mut RenderAssets<Image>,
         node_atlas: &NodeAtlas,
+        atlas_shard_size: Option<u32>,
+        atlas_shard_count: usize,
+        generator: Option<&AttachmentFromGpuLoader>,
+        recompute_normals: Option<&RecomputeNormals>,
     ) -> Self {
-        let attachments = node_atlas
+        // the normal attachment is only written, if it can be recomputed on this device
+        let recomputed_attachment = recompute_normals
+            .filter(|recompute| recompute.validate(node_atlas, device, adapter).is_ok())
+            .map(|recompute| recompute.normal_attachment);
+
+        let atlas_attachments = node_atlas
             .attachments
             .iter()
-            .map(|attachment| attachment.create(device, images, node_atlas.size))
+            .enumerate()
+            .map(|(attachment_index, attachment)| {
+                let generated = generator.map_or(false, |generator| {
+                    generator.generators.contains_key(&attachment_index)
+                }) || recomputed_attachment == Some(attachment_index);
+
+                (attachment.clone(), generated)
+            })
+            .collect::<Vec<_>>();
+
+        let (shard_count, shard_size) = match atlas_shard_size {
+            Some(shard_size) => (atlas_shard_count, shard_size.max(1)),
+            None => (1, u32::MAX),
+        };
+
+        // the first shard uses the handle of the attachment
+        let attachments = atlas_attachments
+            .iter()
+            .map(|(attachment, _)| {
+                iter::once(attachment.handle.clone())
+                    .chain((1..shard_count).map(|_| AtlasAttachment::create_handle()))
+                    .collect()
+            })
             .collect();
 
-        Self {
+        // the node atlas is clamped to the same limit (`NodeAtlasLimit`), before it starts loading
+        let size = node_atlas.size.min(node_atlas_limit(
+            &device.limits(),
+            atlas_shard_size,
+            atlas_shard_count,
+        ));
+
+        let mut gpu_node_atlas = Self {
             attachments,
             loaded_nodes: Vec::new(),
+            attachment_updates: Vec::new(),
+            migrated_nodes: Vec::new(),
+            bounds_buffer: create_bounds_buffer(device, size),
+            bounds_updates: Vec::new(),
+            atlas_attachments,
+            size: 0,
+            requested_size: size,
+            shard_size,
+        };
+
+        gpu_node_atlas.allocate_shards(device, images, None);
+        gpu_node_atlas.size = size;
+        gpu_node_atlas
+    }
+
+    /// Returns the shard and the layer inside of it, which store the atlas index.
+    pub(crate) fn shard(&self, atlas_index: AtlasIndex) -> (usize, u32) {
+        let atlas_index = atlas_index as u32;
+
+        (
+            (atlas_index / self.shard_size) as usize,
+            atlas_index % self.shard_size,
+        )
+    }
+
+    /// Returns the amount of layers of the shard in an atlas of the size.
+    pub(crate) fn shard_layers(&self, shard: usize, size: AtlasIndex) -> u32 {
+        (size as u32)
+            .saturating_sub((shard as u32).saturating_mul(self.shard_size))
+            .min(self.shard_size)
+    }
+
+    /// Allocates all shards, whose layer count differs between the current and the requested
+    /// size of the atlas, and copies over the layers of the previous shards.
+    fn allocate_shards(
+        &self,
+        device: &RenderDevice,
+        images: &mut RenderAssets<Image>,
+        mut command_encoder: Option<&mut CommandEncoder>,
+    ) {
+        for (shards, (attachment, generated)) in
+            self.attachments.iter().zip(&self.atlas_attachments)
+        {
+            for (shard, handle) in shards.iter().enumerate() {
+                let previous_layers = self.shard_layers(shard, self.size);
+                let layers = self.shard_layers(shard, self.requested_size);
+
+                if layers == previous_layers {
+                    continue;
+                }
+
+                let previous = images.remove(handle);
+                attachment.create(device, images, handle, layers, *generated);
+
+                let (Some(previous), Some(command_encoder)) =
+                    (previous, command_encoder.as_deref_mut())
+                else {
+                    continue;
+                };
+
+                let current = &images[handle];
+
+                for mip_level in 0..attachment.mip_level_count {
+                    command_encoder.copy_texture_to_texture(
+                        ImageCopyTexture {
+                            texture: &previous.texture,
+                            mip_level,
+                            origin: Origin3d::ZERO,
+                            aspect: TextureAspect::All,
+                        },
+                        ImageCopyTexture {
+                            texture: &current.texture,
+                            mip_level,
+                            origin: Origin3d::ZERO,
+                            aspect: TextureAspect::All,
+                        },
+                        Extent3d {
+                            depth_or_array_layers: previous_layers,
+                            ..attachment.physical_mip_size(mip_level)
+                        },
+                    );
+                }
+            }
         }
     }
 
+    /// Returns the shards of the atlas attachments, which are allocated, but missing from the
+    /// render assets.
+    fn missing_shards<'a>(
+        &'a self,
+        images: &'a RenderAssets<Image>,
+    ) -> impl Iterator<Item = (&'a AtlasAttachment, usize)> + 'a {
+        self.attachments
+            .iter()
+            .zip(&self.atlas_attachments)
+            .flat_map(move |(shards, (attachment, _))| {
+                shards
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(shard, handle)| {
+                        (self.shard_layers(shard, self.size) > 0 && !images.contains_key(handle))
+                            .then_some((attachment, shard))
+                    })
+            })
+    }
+
+    /// Reallocates the shards of the atlas attachments and the bounds buffer with the
+    /// requested size and copies over the data of all previous atlas indices.
+    ///
+    /// The growth is deferred and `false` is returned, if any of the allocated shards is
+    /// missing, because its data could not be copied over.
+    fn grow(
+        &mut self,
+        device: &RenderDevice,
+        images: &mut RenderAssets<Image>,
+        command_encoder: &mut CommandEncoder,
+    ) -> bool {
+        if let Some((attachment, shard)) = self.missing_shards(images).next() {
+            error!(
+                "The shard {shard} of the {} attachment is not available, the node atlas can not grow.",
+                attachment.name
+            );
+            return false;
+        }
+
+        // only the last shard and the newly used ones are reallocated
+        self.allocate_shards(device, images, Some(command_encoder));
+
+        let bounds_buffer = create_bounds_buffer(device, self.requested_size);
+        command_encoder.copy_buffer_to_buffer(
+            &self.bounds_buffer,
+            0,
+            &bounds_buffer,
+            0,
+            mem::size_of::<Vec2>() as BufferAddress * self.size as BufferAddress,
+        );
+
+        self.bounds_buffer = bounds_buffer;
+        self.size = self.requested_size;
+        true
+    }
+
     /// Updates the atlas attachments, by copying over the data of the nodes that have
     /// finished loading this frame.
+    ///
+    /// Attachments without node data are generated on the GPU and thus skipped.
+    /// The nodes beyond the current size are kept, until the atlas has grown.
     fn update(&mut self, command_encoder: &mut CommandEncoder, images: &RenderAssets<Image>) {
-        for node in self.loaded_nodes.drain(..) {
+        let (loaded_nodes, deferred_nodes) = mem::take(&mut self.loaded_nodes)
+            .into_iter()
+            .partition::<Vec<_>, _>(|node| node.atlas_index < self.size);
+        self.loaded_nodes = deferred_nodes;
+
+        for node in loaded_nodes {
+            let (shard, layer) = self.shard(node.atlas_index);
+
             for (node_handle, atlas_handle) in
                 self.attachments
                     .iter()
                     .enumerate()
-                    .map(|(index, atlas_handle)| {
-                        let node_handle = node.attachments.get(&index).unwrap();
+                    .filter_map(|(index, shards)| {
+                        let node_handle = node.attachments.get(&index)?;
 
-                        (node_handle, atlas_handle)
+                        Some((node_handle, shards.get(shard)?))
                     })
             {
                 if let (Some(node_attachment), Some(atlas_attachment)) =
                     (images.get(node_handle), images.get(atlas_handle))
                 {
+                    // compressed textures are copied in whole blocks
+                    let (block_width, block_height) =
+                        node_attachment.texture_format.describe().block_dimensions;
+                    let (block_width, block_height) = (block_width as u32, block_height as u32);
+
                     for mip_level in 0..node_attachment.mip_level_count {
+                        let width = ((node_attachment.size.x as u32) >> mip_level).max(1);
+                        let height = ((node_attachment.size.y as u32) >> mip_level).max(1);
+
                         // Todo: change to queue.write_texture
                         command_encoder.copy_texture_to_texture(
                             ImageCopyTexture {
@@ -119,13 +409,13 @@ impl GpuNodeAtlas {
                                 origin: Origin3d {
                                     x: 0,
                                     y: 0,
-                                    z: node.atlas_index as u32,
+                                    z: layer,
                                 },
                                 aspect: TextureAspect::All,
                             },
                             Extent3d {
-                                width: (node_attachment.size.x as u32) >> mip_level,
-                                height: (node_attachment.size.y as u32) >> mip_level,
+                                width: (width + block_width - 1) / block_width * block_width,
+                                height: (height + block_height - 1) / block_height * block_height,
                                 depth_or_array_layers: 1,
                             },
                         );
@@ -136,21 +426,158 @@ impl GpuNodeAtlas {
             }
         }
     }
+
+    /// Copies the attachments of the migrated nodes from their previous atlas index into
+    /// their current one.
+    ///
+    /// This has to happen before the loaded nodes are copied, as their atlas indices may have
+    /// been freed by a migration in the same frame. The migrated nodes themselves are never
+    /// pending to be copied.
+    fn migrate(&self, command_encoder: &mut CommandEncoder, images: &RenderAssets<Image>) {
+        for &(from, to) in &self.migrated_nodes {
+            let (from_shard, from_layer) = self.shard(from);
+            let (to_shard, to_layer) = self.shard(to);
+
+            for (shards, (attachment, _)) in self.attachments.iter().zip(&self.atlas_attachments) {
+                let (Some(source), Some(destination)) = (
+                    shards.get(from_shard).and_then(|handle| images.get(handle)),
+                    shards.get(to_shard).and_then(|handle| images.get(handle)),
+                ) else {
+                    continue;
+                };
+
+                for mip_level in 0..attachment.mip_level_count {
+                    command_encoder.copy_texture_to_texture(
+                        ImageCopyTexture {
+                            texture: &source.texture,
+                            mip_level,
+                            origin: Origin3d {
+                                x: 0,
+                                y: 0,
+                                z: from_layer,
+                            },
+                            aspect: TextureAspect::All,
+                        },
+                        ImageCopyTexture {
+                            texture: &destination.texture,
+                            mip_level,
+                            origin: Origin3d {
+                                x: 0,
+                                y: 0,
+                                z: to_layer,
+                            },
+                            aspect: TextureAspect::All,
+                        },
+                        Extent3d {
+                            depth_or_array_layers: 1,
+                            ..attachment.physical_mip_size(mip_level)
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Writes the height bounds of the loaded and edited nodes into the bounds buffer.
+    ///
+    /// The bounds of the nodes beyond the current size are kept, until the atlas has grown.
+    fn write_bounds(&mut self, queue: &RenderQueue) {
+        let (bounds_updates, deferred_updates) = mem::take(&mut self.bounds_updates)
+            .into_iter()
+            .partition::<Vec<_>, _>(|&(atlas_index, _)| atlas_index < self.size);
+        self.bounds_updates = deferred_updates;
+
+        for (atlas_index, bounds) in bounds_updates {
+            queue.write_buffer(
+                &self.bounds_buffer,
+                mem::size_of::<Vec2>() as BufferAddress * atlas_index as BufferAddress,
+                cast_slice(&[bounds]),
+            );
+        }
+    }
+
+    /// Writes the edited regions of the attachments into the atlas attachments.
+    ///
+    /// The updates of the nodes beyond the current size are kept, until the atlas has grown.
+    fn write_updates(&mut self, queue: &RenderQueue, images: &RenderAssets<Image>) {
+        let (attachment_updates, deferred_updates) = mem::take(&mut self.attachment_updates)
+            .into_iter()
+            .partition::<Vec<_>, _>(|update| update.atlas_index < self.size);
+        self.attachment_updates = deferred_updates;
+
+        for update in attachment_updates {
+            let (shard, layer) = self.shard(update.atlas_index);
+
+            if let Some(atlas_attachment) = self.attachments[update.attachment_index]
+                .get(shard)
+                .and_then(|handle| images.get(handle))
+            {
+                queue.write_texture(
+                    ImageCopyTexture {
+                        texture: &atlas_attachment.texture,
+                        mip_level: update.mip_level,
+                        origin: Origin3d {
+                            x: update.origin.x,
+                            y: update.origin.y,
+                            z: layer,
+                        },
+                        aspect: TextureAspect::All,
+                    },
+                    &update.data,
+                    ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: NonZeroU32::new(update.data.len() as u32 / update.size.y),
+                        rows_per_image: None,
+                    },
+                    Extent3d {
+                        width: update.size.x,
+                        height: update.size.y,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+    }
 }
 
 /// Initializes the [`GpuNodeAtlas`] of newly created terrains.
 pub(crate) fn initialize_gpu_node_atlas(
     device: Res<RenderDevice>,
+    adapter: Res<RenderAdapter>,
+    pipeline_config: Res<TerrainPipelineConfig>,
     mut images: ResMut<RenderAssets<Image>>,
     mut gpu_node_atlases: ResMut<TerrainComponents<GpuNodeAtlas>>,
-    mut terrain_query: Extract<Query<(Entity, &NodeAtlas), Added<Terrain>>>,
+    mut terrain_query: Extract<
+        Query<
+            (
+                Entity,
+                &NodeAtlas,
+                Option<&AttachmentFromGpuLoader>,
+                Option<&RecomputeNormals>,
+            ),
+            Added<Terrain>,
+        >,
+    >,
 ) {
-    for (terrain, node_atlas) in terrain_query.iter_mut() {
-        gpu_node_atlases.insert(terrain, GpuNodeAtlas::new(&device, &mut images, node_atlas));
+    for (terrain, node_atlas, generator, recompute_normals) in terrain_query.iter_mut() {
+        gpu_node_atlases.insert(
+            terrain,
+            GpuNodeAtlas::new(
+                &device,
+                &adapter,
+                &mut images,
+                node_atlas,
+                pipeline_config.atlas_shard_size,
+                pipeline_config.atlas_shard_count,
+                generator,
+                recompute_normals,
+            ),
+        );
     }
 }
 
-/// Extracts the nodes that have finished loading from all [`NodeAtlas`]es into the
+/// Extracts the nodes that have finished loading or have been migrated and the edited regions
+/// of the attachments (limited by the write budget) from all [`NodeAtlas`]es into the
 /// corresponding [`GpuNodeAtlas`]es.
 pub(crate) fn extract_node_atlas(
     mut main_world: ResMut<MainWorld>,
@@ -160,28 +587,94 @@ pub(crate) fn extract_node_atlas(
 
     for (terrain, mut node_atlas) in terrain_query.iter_mut(&mut main_world) {
         let gpu_node_atlas = gpu_node_atlases.get_mut(&terrain).unwrap();
-        mem::swap(
-            &mut node_atlas.loaded_nodes,
-            &mut gpu_node_atlas.loaded_nodes,
-        );
+        gpu_node_atlas.requested_size = node_atlas.size;
+
+        // the deferred updates of reused atlas indices would overwrite the loaded data
+        gpu_node_atlas.attachment_updates.retain(|update| {
+            !node_atlas
+                .loaded_nodes
+                .iter()
+                .any(|node| node.atlas_index == update.atlas_index)
+        });
+
+        // the nodes, whose copies have been deferred until the atlas has grown, are kept
+        gpu_node_atlas
+            .loaded_nodes
+            .append(&mut node_atlas.loaded_nodes);
+        gpu_node_atlas.migrated_nodes = mem::take(&mut node_atlas.migrated_nodes);
+
+        // only the bounds of the nodes, that changed this frame, are written
+        let changed_nodes = gpu_node_atlas
+            .loaded_nodes
+            .iter()
+            .map(|node| node.atlas_index)
+            .chain(gpu_node_atlas.migrated_nodes.iter().map(|&(_, to)| to))
+            .chain(
+                node_atlas
+                    .edited_nodes
+                    .iter()
+                    .chain(&node_atlas.snowed_nodes)
+                    .filter_map(|node_id| Some(node_atlas.nodes.get(node_id)?.atlas_index)),
+            )
+            .collect::<Vec<_>>();
+
+        gpu_node_atlas
+            .bounds_updates
+            .extend(changed_nodes.into_iter().map(|atlas_index| {
+                (
+                    atlas_index,
+                    node_atlas.data[atlas_index as usize].height_bounds(),
+                )
+            }));
+
+        // the remaining updates are carried over to the following frames,
+        // those of evicted or reused atlas indices are discarded by the node atlas
+        let write_count = node_atlas
+            .attachment_updates
+            .len()
+            .min(node_atlas.write_budget);
+        let updates = node_atlas.attachment_updates.drain(..write_count);
+        gpu_node_atlas.attachment_updates.extend(updates);
     }
 }
 
 /// Queues the attachments of the nodes that have finished loading to be copied into the
-/// corresponding atlas attachments.
+/// corresponding atlas attachments and writes the edited regions afterwards.
+///
+/// Atlases, whose [`NodeAtlas`] has grown, are reallocated and the migrated nodes are copied
+/// beforehand.
 pub(crate) fn prepare_node_atlas(
     device: Res<RenderDevice>,
     queue: Res<RenderQueue>,
-    images: Res<RenderAssets<Image>>,
+    mut images: ResMut<RenderAssets<Image>>,
     mut gpu_node_atlases: ResMut<TerrainComponents<GpuNodeAtlas>>,
+    mut terrain_data: ResMut<TerrainComponents<TerrainData>>,
     terrain_query: Query<Entity, With<Terrain>>,
 ) {
     let mut command_encoder = device.create_command_encoder(&CommandEncoderDescriptor::default());
 
     for terrain in terrain_query.iter() {
         let gpu_node_atlas = gpu_node_atlases.get_mut(&terrain).unwrap();
+
+        if gpu_node_atlas.requested_size > gpu_node_atlas.size
+            && gpu_node_atlas.grow(&device, &mut images, &mut command_encoder)
+        {
+            // the bind group still references the previous attachments
+            if let Some(terrain_data) = terrain_data.get_mut(&terrain) {
+                terrain_data.recreate_bind_group(&device, &images);
+            }
+        }
+
+        gpu_node_atlas.migrate(&mut command_encoder, &images);
         gpu_node_atlas.update(&mut command_encoder, &images);
     }
 
     queue.submit(vec![command_encoder.finish()]);
+
+    // the edits have to be written after the copies, in case the node was loaded this frame
+    for terrain in terrain_query.iter() {
+        let gpu_node_atlas = gpu_node_atlases.get_mut(&terrain).unwrap();
+        gpu_node_atlas.write_updates(&queue, &images);
+        gpu_node_atlas.write_bounds(&queue);
+    }
 }
@@ -1,7 +1,5 @@
 use crate::{
-    terrain::Terrain,
     terrain_data::quadtree::{Quadtree, QuadtreeEntry},
-    terrain_view::TerrainView,
     TerrainViewComponents,
 };
 use bevy::{
@@ -21,13 +19,16 @@ use std::num::NonZeroU32;
 /// Stores the GPU representation of the [`Quadtree`] (array texture)
 /// alongside the data to update it.
 ///
-/// The data is synchronized each frame by copying it from the [`Quadtree`] to the texture.
+/// The data is synchronized by copying it from the [`Quadtree`] to the texture,
+/// whenever the quadtree has been adjusted.
 #[derive(Component)]
 pub struct GpuQuadtree {
     /// The handle of the quadtree texture.
     handle: Handle<Image>,
     /// The current cpu quadtree data. This is synced each frame with the quadtree data.
     data: Array3<QuadtreeEntry>,
+    /// Indicates whether the data has changed this frame and has to be copied to the texture.
+    changed: bool,
     /// The count of level of detail layers.
     lod_count: u32,
     /// The count of nodes in x and y direction per layer.
@@ -72,6 +73,7 @@ impl GpuQuadtree {
         Self {
             handle: quadtree.handle.clone(),
             data: default(),
+            changed: false,
             lod_count: quadtree.lod_count,
             node_count: quadtree.node_count,
         }
@@ -103,19 +105,20 @@ impl GpuQuadtree {
     }
 }
 
-/// Initializes the [`GpuQuadtree`] of newly created terrains.
+/// Initializes the [`GpuQuadtree`] of newly registered terrain views.
 pub(crate) fn initialize_gpu_quadtree(
     device: Res<RenderDevice>,
     mut images: ResMut<RenderAssets<Image>>,
     mut gpu_quadtrees: ResMut<TerrainViewComponents<GpuQuadtree>>,
     quadtrees: Extract<Res<TerrainViewComponents<Quadtree>>>,
-    view_query: Extract<Query<Entity, With<TerrainView>>>,
-    terrain_query: Extract<Query<Entity, Added<Terrain>>>,
 ) {
-    for terrain in terrain_query.iter() {
-        for view in view_query.iter() {
-            let quadtree = quadtrees.get(&(terrain, view)).unwrap();
+    // remove the gpu quadtrees of views, that are no longer registered
+    gpu_quadtrees
+        .0
+        .retain(|&(terrain, view), _| quadtrees.contains_key(&(terrain, view)));
 
+    for (&(terrain, view), quadtree) in &quadtrees.0 {
+        if !gpu_quadtrees.contains_key(&(terrain, view)) {
             gpu_quadtrees.insert(
                 (terrain, view),
                 GpuQuadtree::new(&device, &mut images, quadtree),
@@ -128,18 +131,19 @@ pub(crate) fn initialize_gpu_quadtree(
 pub(crate) fn extract_quadtree(
     mut gpu_quadtrees: ResMut<TerrainViewComponents<GpuQuadtree>>,
     quadtrees: Extract<Res<TerrainViewComponents<Quadtree>>>,
-    view_query: Extract<Query<Entity, With<TerrainView>>>,
-    terrain_query: Extract<Query<Entity, With<Terrain>>>,
 ) {
-    for terrain in terrain_query.iter() {
-        for view in view_query.iter() {
-            let quadtree = quadtrees.get(&(terrain, view)).unwrap();
-            let gpu_quadtree = gpu_quadtrees.get_mut(&(terrain, view)).unwrap();
+    for (&(terrain, view), quadtree) in &quadtrees.0 {
+        let gpu_quadtree = gpu_quadtrees.get_mut(&(terrain, view)).unwrap();
 
-            // Todo: enable this again once mutable access to the main world in extract is less painful
-            // mem::swap(&mut gpu_quadtree.data, &mut gpu_gpu_quadtree.data);
-            gpu_quadtree.data = quadtree.data.clone();
+        gpu_quadtree.changed = quadtree.adjusted;
+
+        if !quadtree.adjusted {
+            continue;
         }
+
+        // Todo: enable this again once mutable access to the main world in extract is less painful
+        // mem::swap(&mut gpu_quadtree.data, &mut gpu_gpu_quadtree.data);
+        gpu_quadtree.data = quadtree.data.clone();
     }
 }
 
@@ -147,13 +151,10 @@ pub(crate) fn extract_quadtree(
 pub(crate) fn prepare_quadtree(
     queue: Res<RenderQueue>,
     images: Res<RenderAssets<Image>>,
-    mut gpu_quadtrees: ResMut<TerrainViewComponents<GpuQuadtree>>,
-    view_query: Query<Entity, With<TerrainView>>,
-    terrain_query: Query<Entity, With<Terrain>>,
+    gpu_quadtrees: Res<TerrainViewComponents<GpuQuadtree>>,
 ) {
-    for terrain in terrain_query.iter() {
-        for view in view_query.iter() {
-            let gpu_quadtree = gpu_quadtrees.get_mut(&(terrain, view)).unwrap();
+    for gpu_quadtree in gpu_quadtrees.0.values() {
+        if gpu_quadtree.changed {
             gpu_quadtree.update(&queue, &images);
         }
     }
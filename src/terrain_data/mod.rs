@@ -22,7 +22,12 @@ use std::str::FromStr;
 pub mod gpu_node_atlas;
 pub mod gpu_quadtree;
 pub mod node_atlas;
+pub(crate) mod node_entities;
+pub mod node_events;
 pub mod quadtree;
+pub mod raycast;
+pub mod sampling;
+pub mod statistics;
 
 // Todo: may be swap to u64 for giant terrains
 // Todo: consider 3 bit face data, for cube sphere
@@ -41,6 +46,23 @@ pub const INVALID_LOD: u16 = u16::MAX;
 /// Identifier of an attachment inside the node atlas.
 pub type AttachmentIndex = usize;
 
+/// The maximum amount of attachments of a terrain.
+///
+/// The sizes, scales and offsets of all attachments are passed to the shaders in the
+/// terrain config, which reserves space for this many attachments.
+pub const MAX_ATTACHMENT_COUNT: usize = 8;
+
+/// The maximum amount of textures, which each attachment of a sharded node atlas is split into.
+///
+/// Each shard holds [`TerrainPlugin::atlas_shard_size`](crate::TerrainPlugin::atlas_shard_size)
+/// atlas indices, so that the node atlas is not limited by the layer count of a single texture.
+pub const MAX_ATLAS_SHARDS: usize = 4;
+
+/// The index of the height attachment, which is the first attachment of the base attachment.
+pub const HEIGHT_ATTACHMENT: AttachmentIndex = 0;
+/// The index of the minmax attachment, which is the second attachment of the base attachment.
+pub const MINMAX_ATTACHMENT: AttachmentIndex = 1;
+
 /// The global coordinate of a node.
 pub struct NodeCoordinate {
     /// The lod of the node, where 0 is the highest level of detail with the smallest size
@@ -71,7 +93,7 @@ pub fn calc_node_id(lod: u32, x: u32, y: u32) -> NodeId {
 }
 
 /// The data format of an attachment.
-#[derive(Encode, Decode, Clone, Copy, Debug)]
+#[derive(Encode, Decode, Clone, Copy, Debug, Reflect, FromReflect)]
 pub enum AttachmentFormat {
     /// Three channels  8 bit
     Rgb8,
@@ -81,6 +103,18 @@ pub enum AttachmentFormat {
     R16,
     /// Two   channels 16 bit
     Rg16,
+    /// One   channel  32 bit float
+    ///
+    /// Used for heightmaps that require more precision than 16 bit.
+    /// The values are normalized like the ones of the integer formats.
+    /// Filtering this format requires the `TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES`
+    /// feature and an adapter that supports it (e.g. not WebGL2).
+    R32F,
+    /// Four  channels  8 bit, stored linearly
+    ///
+    /// Used for data, that is not a color (e.g. splat weights), which would be distorted by
+    /// the sRGB encoding of the other 8 bit formats.
+    Rgba8Linear,
 }
 
 impl From<AttachmentFormat> for TextureFormat {
@@ -88,20 +122,79 @@ impl From<AttachmentFormat> for TextureFormat {
         match format {
             AttachmentFormat::Rgb8 => TextureFormat::Rgba8UnormSrgb,
             AttachmentFormat::Rgba8 => TextureFormat::Rgba8UnormSrgb,
+            AttachmentFormat::Rgba8Linear => TextureFormat::Rgba8Unorm,
             AttachmentFormat::R16 => TextureFormat::R16Unorm,
             AttachmentFormat::Rg16 => TextureFormat::Rg16Unorm,
+            AttachmentFormat::R32F => TextureFormat::R32Float,
+        }
+    }
+}
+
+impl AttachmentFormat {
+    /// The block compressed texture format, which stores the data of the attachment format.
+    pub(crate) fn compressed(self) -> TextureFormat {
+        match self {
+            AttachmentFormat::Rgb8 => TextureFormat::Bc7RgbaUnormSrgb,
+            AttachmentFormat::Rgba8 => TextureFormat::Bc7RgbaUnormSrgb,
+            AttachmentFormat::Rgba8Linear => TextureFormat::Bc7RgbaUnorm,
+            AttachmentFormat::R16 => TextureFormat::Bc4RUnorm,
+            AttachmentFormat::Rg16 => TextureFormat::Bc5RgUnorm,
+            AttachmentFormat::R32F => TextureFormat::Bc6hRgbUfloat,
         }
     }
 }
 
 /// The file format used to store the terrain data.
-#[derive(Encode, Decode, Clone, Copy, Debug)]
+#[derive(Encode, Decode, Clone, Copy, Debug, Reflect, FromReflect)]
 pub enum FileFormat {
     TDF,
     PNG,
     TIF,
     QOI,
     DTM,
+    /// KTX2 containers with block compressed payloads (BC4 for R16, BC5 for Rg16, BC6H for R32F
+    /// and BC7 for Rgb8 and Rgba8 attachments), which stay compressed on the GPU.
+    ///
+    /// These nodes can not be created by the preprocessor, but have to be converted from
+    /// preprocessed nodes with an external tool.
+    /// Compressed attachments are not available on the CPU, so sampling, editing and
+    /// collisions require an uncompressed height attachment.
+    /// Requires the `ktx2` feature.
+    KTX2,
+    /// GeoTIFF elevation data, which can only be imported as source tiles of the height attachment.
+    ///
+    /// Requires the `elevation` feature.
+    GeoTIFF,
+    /// Classic USGS DEM elevation data, which can only be imported as source tiles
+    /// of the height attachment.
+    ///
+    /// Requires the `elevation` feature.
+    DEM,
+    /// Mapbox Terrain-RGB PNGs, whose elevation is decoded into the height attachment on load.
+    ///
+    /// The `min_elevation` (in meters) is mapped to the bottom of the terrain and heights
+    /// above it are scaled with the height of the terrain.
+    /// These nodes can not be created or read by the preprocessor.
+    TerrainRGB {
+        min_elevation: f32,
+    },
+    /// Cesium quantized-mesh tiles, which are rasterized into the height attachment on load.
+    /// Their triangles are not rendered directly, but resampled into the regular grid.
+    ///
+    /// The `min_elevation` (in meters) is mapped to the bottom of the terrain and heights
+    /// above it are scaled with the height of the terrain.
+    /// The tiles have to be stored uncompressed and can not be created or read by the preprocessor.
+    QuantizedMesh {
+        min_elevation: f32,
+    },
+    /// Compact node files, which store the quantized and compressed data of all attachments
+    /// of a node in one file (see [`TNF`](crate::formats::tnf::TNF)).
+    ///
+    /// These nodes can not be created by the preprocessor directly, but have to be packed
+    /// from preprocessed nodes with
+    /// [`Preprocessor::pack_nodes`](crate::preprocess::Preprocessor::pack_nodes).
+    /// Requires the `tnf` feature.
+    TNF,
 }
 
 impl Default for FileFormat {
@@ -118,12 +211,18 @@ impl FileFormat {
             Self::TIF => "tif",
             Self::QOI => "qoi",
             Self::DTM => "dtm",
+            Self::KTX2 => "ktx2",
+            Self::GeoTIFF => "tif",
+            Self::DEM => "dem",
+            Self::TerrainRGB { .. } => "png",
+            Self::QuantizedMesh { .. } => "terrain",
+            Self::TNF => "tnf",
         }
     }
 }
 
 /// Configures an attachment.
-#[derive(Encode, Decode, Clone, Debug)]
+#[derive(Encode, Decode, Clone, Debug, Reflect, FromReflect)]
 pub struct AttachmentConfig {
     /// The name of the attachment.
     pub name: String,
@@ -162,9 +261,12 @@ impl AttachmentConfig {
 }
 
 /// An attachment of a [`NodeAtlas`](node_atlas::NodeAtlas).
-#[derive(Clone)]
+#[derive(Clone, Reflect, FromReflect)]
 pub struct AtlasAttachment {
     /// The handle of the attachment array texture.
+    ///
+    /// It is not reflected, but assigned again to attachments loaded from a scene.
+    #[reflect(ignore)]
     pub(crate) handle: Handle<Image>,
     /// The name of the attachment.
     pub(crate) name: String,
@@ -174,27 +276,51 @@ pub struct AtlasAttachment {
     /// The overlapping border size around the node, used to prevent sampling artifacts.
     pub(crate) border_size: u32,
     pub mip_level_count: u32,
-    /// The format of the attachment.
-    pub(crate) format: TextureFormat,
+    /// The data format of the attachment.
+    pub(crate) attachment_format: AttachmentFormat,
+    /// The file format of the attachment, which determines whether it is compressed.
+    pub(crate) file_format: FileFormat,
 }
 
-impl From<AttachmentConfig> for AtlasAttachment {
-    fn from(config: AttachmentConfig) -> Self {
+impl AtlasAttachment {
+    /// Creates a new handle of an attachment array texture.
+    pub(crate) fn create_handle() -> Handle<Image> {
         // Todo: fix this awful hack
-        let handle = HandleUntyped::weak_from_u64(
+        HandleUntyped::weak_from_u64(
             Uuid::from_str("6ea26da6-6cf8-4ea2-9986-1d7bf6c17d6f").unwrap(),
             fastrand::u64(..),
         )
-        .typed();
+        .typed()
+    }
+
+    /// The texture format of the attachment.
+    pub(crate) fn format(&self) -> TextureFormat {
+        match self.file_format {
+            FileFormat::KTX2 => self.attachment_format.compressed(),
+            _ => self.attachment_format.into(),
+        }
+    }
+}
+
+impl From<AttachmentConfig> for AtlasAttachment {
+    fn from(config: AttachmentConfig) -> Self {
+        if let FileFormat::KTX2 = config.file_format {
+            assert_eq!(
+                config.texture_size % 4,
+                0,
+                "The texture size of compressed attachments has to be a multiple of four."
+            );
+        }
 
         Self {
-            handle,
+            handle: Self::create_handle(),
             name: config.name,
             texture_size: config.texture_size,
             center_size: config.center_size,
             border_size: config.border_size,
             mip_level_count: config.mip_level_count,
-            format: config.format.into(),
+            attachment_format: config.format,
+            file_format: config.file_format,
         }
     }
 }
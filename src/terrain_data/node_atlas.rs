@@ -1,15 +1,23 @@
 use crate::{
+    edit::{
+        history::{BakedEdit, DEFAULT_EDIT_HISTORY_SIZE},
+        save::SavedData,
+        AppliedEdit, AttachmentUpdate,
+    },
     terrain::{Terrain, TerrainConfig},
     terrain_data::{
-        quadtree::Quadtree, AtlasAttachment, AtlasIndex, AttachmentIndex, NodeId, INVALID_NODE_ID,
+        quadtree::Quadtree, AtlasAttachment, AtlasIndex, AttachmentIndex, NodeCoordinate, NodeId,
+        HEIGHT_ATTACHMENT, INVALID_ATLAS_INDEX, INVALID_NODE_ID,
     },
-    TerrainView, TerrainViewComponents,
+    TerrainViewComponents,
 };
 use bevy::{
+    math::DVec2,
     prelude::*,
-    utils::{HashMap, HashSet},
+    tasks::{ComputeTaskPool, ParallelSliceMut},
+    utils::{HashMap, HashSet, Instant},
 };
-use std::collections::VecDeque;
+use std::{collections::VecDeque, mem, sync::Arc};
 
 /// Stores all of the attachments of the node, alongside their loading state.
 #[derive(Clone)]
@@ -21,6 +29,8 @@ pub struct LoadingNode {
     pub(crate) attachments: HashMap<AttachmentIndex, Handle<Image>>,
     /// The set of still loading attachments. Is empty if the node is fully loaded.
     loading_attachments: HashSet<AttachmentIndex>,
+    /// The time the node started loading at.
+    start: Instant,
 }
 
 impl LoadingNode {
@@ -45,14 +55,65 @@ impl LoadingNode {
 pub struct NodeData {
     // Todo: replace with array or vec of options
     /// Stores all of the cpu accessible attachments of the node.
-    pub(crate) _attachments: HashMap<AttachmentIndex, Handle<Image>>,
+    pub(crate) attachments: HashMap<AttachmentIndex, Handle<Image>>,
+    /// The minimum and maximum height of the node (in the local space of the terrain).
+    pub(crate) height_bounds: Vec2,
+    /// The maximum depth of the snow on the node (in the local space of the terrain).
+    pub(crate) snow_depth: f32,
+}
+
+impl NodeData {
+    /// Creates the data of a loaded node and determines its height bounds,
+    /// which are used to accelerate raycasts.
+    ///
+    /// If the height data is not accessible on the CPU, the bounds determined during
+    /// preprocessing are used instead, otherwise the bounds span the entire height of the terrain.
+    fn new(
+        mut attachments: HashMap<AttachmentIndex, Handle<Image>>,
+        atlas_attachments: &[AtlasAttachment],
+        images: &Assets<Image>,
+        height: f32,
+        preprocessed_bounds: Option<Vec2>,
+    ) -> Self {
+        // compressed attachments are only used on the GPU
+        attachments
+            .retain(|&attachment_index, _| atlas_attachments[attachment_index].is_cpu_accessible());
+
+        let height_bounds = attachments
+            .get(&HEIGHT_ATTACHMENT)
+            .and_then(|handle| images.get(handle))
+            .map(|image| atlas_attachments[HEIGHT_ATTACHMENT].bounds(image, 0))
+            .or(preprocessed_bounds)
+            .map_or(Vec2::new(0.0, height), |bounds| bounds * height);
+
+        Self {
+            attachments,
+            height_bounds,
+            snow_depth: 0.0,
+        }
+    }
+
+    /// Returns the minimum and maximum height of the node (in the local space of the terrain).
+    ///
+    /// The bounds include the border of the node and the accumulated snow and are kept up to
+    /// date with the edits, which makes them suitable for constructing conservative bounding boxes.
+    pub fn height_bounds(&self) -> Vec2 {
+        self.height_bounds + Vec2::new(0.0, self.snow_depth)
+    }
+
+    /// Returns the handle of the cpu accessible attachment, if it is available.
+    pub fn attachment(&self, attachment_index: AttachmentIndex) -> Option<&Handle<Image>> {
+        self.attachments.get(&attachment_index)
+    }
 }
 
 /// The current state of a node of a [`NodeAtlas`].
 ///
-/// This indicates, whether the node is loading or loaded and ready to be used.
+/// This indicates, whether the node is queued, loading or loaded and ready to be used.
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub(crate) enum LoadingState {
+    /// The node is waiting to be loaded and has not been assigned an atlas index yet.
+    Queued,
     /// The node is loading, but can not be used yet.
     Loading,
     /// The node is loaded and can be used.
@@ -69,6 +130,69 @@ pub(crate) struct AtlasNode {
     requests: u32,
 }
 
+/// Controls the streaming of the nodes of a terrain, e.g. to suspend it during cutscenes,
+/// loading screens or a photo mode, without despawning the terrain.
+///
+/// Terrains without this component are streamed as if it was [`StreamingState::Active`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Component, Reflect, FromReflect)]
+#[reflect(Component)]
+pub enum StreamingState {
+    /// The nodes are loaded and evicted according to the requests of the quadtrees.
+    #[default]
+    Active,
+    /// No nodes start loading and no nodes are evicted.
+    /// The nodes, which are already loading, are still activated.
+    Paused,
+    /// The selection of the nodes stays exactly as it is.
+    /// The quadtrees are not traversed and the loaded nodes are not activated either.
+    Frozen,
+}
+
+/// A summary of the current occupancy of a [`NodeAtlas`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NodeAtlasUsage {
+    /// The amount of nodes the can be loaded simultaneously in the node atlas.
+    pub size: usize,
+    /// The amount of nodes, that the node atlas can grow to.
+    pub max_size: usize,
+    /// The amount of loaded nodes, that are requested by a quadtree.
+    pub active: usize,
+    /// The amount of requested nodes, that have not started loading yet.
+    pub queued: usize,
+    /// The amount of nodes, that are currently loading.
+    pub loading: usize,
+    /// The amount of loaded nodes, including the cached ones.
+    pub loaded: usize,
+    /// The amount of loaded nodes, that are no longer requested, but kept in the cache.
+    pub cached: usize,
+    /// The amount of free atlas indices, which lie below the highest occupied one.
+    pub fragmented: usize,
+}
+
+/// A change of the lifecycle of a node in the [`NodeAtlas`],
+/// which is sent as the corresponding [`node_events`](super::node_events).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NodeLifecycle {
+    /// The node has been queued to be loaded.
+    Queued,
+    /// All attachments of the node have finished loading.
+    Loaded,
+    /// The node has been activated and can be used.
+    Activated,
+    /// The node has been evicted after it was activated and can no longer be used.
+    Deactivated,
+}
+
+/// A callback, which is invoked with the id of each node evicted from the [`NodeAtlas`].
+pub type EvictionCallback = Box<dyn Fn(NodeId) + Send + Sync>;
+
+/// The maximum amount of nodes, that the node atlases can store on the current device.
+///
+/// The sizes of all node atlases are clamped to this limit, before they start loading nodes.
+/// It is only inserted, if the terrains are rendered.
+#[derive(Resource)]
+pub(crate) struct NodeAtlasLimit(pub(crate) AtlasIndex);
+
 /// A node which is not currently requested by any [`Quadtree`].
 struct UnusedNode {
     node_id: NodeId,
@@ -78,47 +202,166 @@ struct UnusedNode {
 /// A sparse storage of all terrain attachments, which streams data in and out of memory
 /// depending on the decisions of the corresponding [`Quadtree`]s.
 ///
-/// A node is considered present and assigned an [`AtlasIndex`] as soon as it is
-/// requested by any quadtree. Then the node atlas will start loading all of its attachments
+/// A node is considered present as soon as it is requested by any quadtree.
+/// Requested nodes are queued and assigned an [`AtlasIndex`], in the order of their distance
+/// to the nearest viewer. Then the node atlas will start loading all of its attachments
 /// by storing the [`NodeId`] (for one frame) in `load_events` for which attachment-loading-systems
 /// can listen.
+/// Nodes prefetched by a quadtree are loaded after all requested ones and placed directly
+/// into the cache.
 /// Nodes that are not being used by any quadtree anymore are cached (LRU),
-/// until new atlas indices are required.
+/// until new atlas indices are required or the cache exceeds its capacity.
+/// Once all atlas indices are requested, the atlas grows up to its maximum size.
+/// Beyond that, the remaining requested nodes stay queued, until atlas indices are released.
+/// Over time the occupied atlas indices become scattered, so a few loaded nodes are migrated
+/// into the lowest free atlas indices each frame (limited by the `compaction_budget`).
 ///
 /// The [`AtlasIndex`] can be used for accessing the attached data in systems by the CPU
 /// and in shaders by the GPU.
-#[derive(Component)]
+///
+/// Only the configuration of the node atlas is reflected, its streaming state is skipped.
+/// It is not stored in scenes, but recreated from the [`TerrainConfig`] instead.
+#[derive(Component, Reflect)]
 pub struct NodeAtlas {
     /// Nodes that are requested to be loaded this frame.
+    #[reflect(ignore)]
     pub load_events: Vec<NodeId>,
+    /// The lifecycle changes of the nodes this frame.
+    #[reflect(ignore)]
+    pub(crate) lifecycle_events: Vec<(NodeId, NodeLifecycle)>,
     /// Stores the cpu accessible data of all loaded nodes.
-    pub(crate) data: Vec<NodeData>, // Todo: build api for accessing data on the cpu
+    #[reflect(ignore)]
+    pub(crate) data: Vec<NodeData>,
+    /// The normalized height bounds of the nodes, that were determined during preprocessing.
+    #[reflect(ignore)]
+    pub(crate) preprocessed_bounds: HashMap<NodeId, Vec2>,
     /// Stores the atlas attachments of the terrain.
     pub(crate) attachments: Vec<AtlasAttachment>,
     /// Stores the nodes, that have finished loading this frame.
     /// This data will be send to the
     /// [`GpuNodeAtlas`](super::gpu_node_atlas::GpuNodeAtlas) each frame.
+    #[reflect(ignore)]
     pub(crate) loaded_nodes: Vec<LoadingNode>,
     /// Stores the currently loading nodes.
+    #[reflect(ignore)]
     pub(crate) loading_nodes: HashMap<NodeId, LoadingNode>,
+    /// Stores the regions of the attachments, that have been edited this frame.
+    /// This data will be send to the
+    /// [`GpuNodeAtlas`](super::gpu_node_atlas::GpuNodeAtlas) each frame.
+    #[reflect(ignore)]
+    pub(crate) attachment_updates: Vec<AttachmentUpdate>,
+    /// The nodes, that have been edited this frame.
+    #[reflect(ignore)]
+    pub(crate) edited_nodes: Vec<NodeId>,
+    /// The regions (in the local space of the terrain), in which the edited nodes have changed
+    /// this frame. Reloaded nodes have changed entirely.
+    #[reflect(ignore)]
+    pub(crate) edited_regions: Vec<(NodeId, Rect)>,
+    /// The nodes and attachments, whose layer weights have been painted this frame.
+    #[reflect(ignore)]
+    pub(crate) painted_nodes: Vec<(NodeId, AttachmentIndex)>,
+    /// The index of the snow attachment, which raises the surface of the terrain
+    /// (see [`TerrainSnow`](crate::snow::TerrainSnow)).
+    #[reflect(ignore)]
+    pub(crate) snow_attachment: Option<AttachmentIndex>,
+    /// The nodes, whose snow has changed during the last update of the snow.
+    /// Unlike the other changes, these are cleared by the next update of the snow instead.
+    #[reflect(ignore)]
+    pub(crate) snowed_nodes: Vec<NodeId>,
+    /// The recent edits applied to the terrain, which can be undone
+    /// and are reapplied to newly loaded nodes.
+    #[reflect(ignore)]
+    pub(crate) edits: Vec<AppliedEdit>,
+    /// The edits, that have been undone and can be redone.
+    #[reflect(ignore)]
+    pub(crate) undone_edits: Vec<AppliedEdit>,
+    /// The edits, that dropped out of the history, but have not been baked into
+    /// the saved data of all nodes they affect yet.
+    #[reflect(ignore)]
+    pub(crate) baked_edits: Vec<BakedEdit>,
+    /// The maximum amount of edits, that can be undone.
+    pub(crate) edit_history_size: usize,
+    /// The data of the node attachments restored from a save,
+    /// which replaces or patches the loaded data of these nodes.
+    #[reflect(ignore)]
+    pub(crate) saved_nodes: HashMap<(NodeId, AttachmentIndex), Arc<SavedData>>,
+    /// The data of the nodes evicted during the last update, whose modifications are kept
+    /// in the saved data, before their images are dropped.
+    #[reflect(ignore)]
+    pub(crate) evicted_nodes: Vec<(NodeId, NodeData)>,
     /// The amount of nodes the can be loaded simultaneously in the node atlas.
     pub(crate) size: u16,
+    /// The amount of nodes, that the node atlas can grow to, once all atlas indices are requested.
+    pub(crate) max_size: u16,
+    /// The count of level of detail layers.
+    pub(crate) lod_count: u32,
+    /// The size of the smallest nodes (with lod 0).
+    pub(crate) leaf_node_size: u32,
+    /// The maximum height of the terrain.
+    pub(crate) height: f32,
     /// Stores the states of all present nodes.
+    #[reflect(ignore)]
     pub(crate) nodes: HashMap<NodeId, AtlasNode>,
+    #[reflect(ignore)]
     pub(crate) existing_nodes: HashSet<NodeId>,
     /// Lists the unused nodes in least recently used order.
+    #[reflect(ignore)]
     unused_nodes: VecDeque<UnusedNode>,
+    /// The maximum amount of unused nodes, that are kept in the cache.
+    pub(crate) cache_size: u16,
+    /// The callbacks, which are invoked for each evicted node.
+    #[reflect(ignore)]
+    eviction_callbacks: Vec<EvictionCallback>,
+    /// The requested nodes, that have not started loading yet.
+    #[reflect(ignore)]
+    load_queue: Vec<NodeId>,
+    /// The maximum amount of nodes, that start loading per frame.
+    pub(crate) load_budget: usize,
+    /// Is incremented each time a node finished loading or was evicted,
+    /// so that the quadtrees only have to be adjusted after a change.
+    #[reflect(ignore)]
+    pub(crate) generation: u64,
+    /// The nodes, that have finished loading, but have not been activated yet.
+    #[reflect(ignore)]
+    activation_queue: VecDeque<(NodeId, LoadingNode)>,
+    /// The maximum amount of nodes, that are activated per frame.
+    pub(crate) activation_budget: usize,
+    /// The amount of nodes, that have been activated this frame.
+    #[reflect(ignore)]
+    pub(crate) activation_count: usize,
+    /// The average time (in seconds) between the start of loading and the activation
+    /// of the nodes, that have been activated this frame.
+    #[reflect(ignore)]
+    pub(crate) load_latency: Option<f32>,
+    /// The maximum amount of attachment updates, that are written into the atlas per frame.
+    pub(crate) write_budget: usize,
+    /// The maximum amount of nodes, that are migrated to lower atlas indices per frame.
+    pub(crate) compaction_budget: usize,
+    /// The nodes, that have been migrated since the last extraction, as pairs of their previous
+    /// and their current atlas index. This data will be send to the
+    /// [`GpuNodeAtlas`](super::gpu_node_atlas::GpuNodeAtlas) each frame.
+    #[reflect(ignore)]
+    pub(crate) migrated_nodes: Vec<(AtlasIndex, AtlasIndex)>,
 }
 
 impl NodeAtlas {
-    /// Creates a new quadtree from parameters.
+    /// Creates a new node atlas from parameters.
     ///
     /// * `size` - The amount of nodes the can be loaded simultaneously in the node atlas.
     /// * `attachments` - The atlas attachments of the terrain.
+    /// * `existing_nodes` - The nodes of the terrain, that can be loaded.
+    /// * `lod_count` - The count of level of detail layers.
+    /// * `leaf_node_size` - The size of the smallest nodes (with lod 0).
+    /// * `height` - The maximum height of the terrain.
+    /// * `cache_size` - The maximum amount of unused nodes, that are kept in the cache.
     pub fn new(
         size: u16,
         attachments: Vec<AtlasAttachment>,
         existing_nodes: HashSet<NodeId>,
+        lod_count: u32,
+        leaf_node_size: u32,
+        height: f32,
+        cache_size: u16,
     ) -> Self {
         let unused_nodes = (0..size)
             .map(|atlas_index| UnusedNode {
@@ -129,36 +372,122 @@ impl NodeAtlas {
 
         Self {
             load_events: default(),
+            lifecycle_events: default(),
             loaded_nodes: default(),
             loading_nodes: default(),
+            attachment_updates: default(),
+            edited_nodes: default(),
+            edited_regions: default(),
+            painted_nodes: default(),
+            snow_attachment: None,
+            snowed_nodes: default(),
+            edits: default(),
+            undone_edits: default(),
+            baked_edits: default(),
+            edit_history_size: DEFAULT_EDIT_HISTORY_SIZE as usize,
+            saved_nodes: default(),
+            evicted_nodes: default(),
             nodes: default(),
             data: vec![default(); size as usize],
+            preprocessed_bounds: default(),
             attachments,
             size,
+            max_size: size,
+            lod_count,
+            leaf_node_size,
+            height,
             unused_nodes,
             existing_nodes,
+            cache_size,
+            eviction_callbacks: default(),
+            load_queue: default(),
+            load_budget: usize::MAX,
+            generation: 0,
+            activation_queue: default(),
+            activation_budget: usize::MAX,
+            activation_count: 0,
+            load_latency: None,
+            write_budget: usize::MAX,
+            compaction_budget: 0,
+            migrated_nodes: default(),
         }
     }
 
-    /// Creates a new quadtree from a terrain config.
+    /// Creates a new node atlas from a terrain config.
     pub fn from_config(config: &TerrainConfig) -> Self {
-        Self::new(
-            config.node_atlas_size as u16,
-            config.attachments.clone(),
-            config.nodes.clone(),
-        )
+        let budget = |budget: Option<u32>| budget.map_or(usize::MAX, |budget| budget as usize);
+
+        Self {
+            load_budget: budget(config.load_budget),
+            activation_budget: budget(config.activation_budget),
+            write_budget: budget(config.atlas_write_budget),
+            compaction_budget: config.compaction_budget.unwrap_or(0) as usize,
+            edit_history_size: config.edit_history_size as usize,
+            preprocessed_bounds: config.height_bounds.clone(),
+            max_size: config
+                .max_node_atlas_size
+                .max(config.node_atlas_size)
+                .min(u16::MAX as u32) as u16,
+            ..Self::new(
+                config.node_atlas_size as u16,
+                config.attachments.clone(),
+                config.nodes.clone(),
+                config.lod_count,
+                config.leaf_node_size,
+                config.height,
+                config.cache_size.min(config.node_atlas_size) as u16,
+            )
+        }
+    }
+
+    /// Clamps the size and the maximum size of the node atlas to the limit of the device.
+    ///
+    /// The size can only be reduced, while none of the atlas indices beyond the limit are in use.
+    fn limit_size(&mut self, limit: AtlasIndex) {
+        if self.max_size > limit {
+            error!(
+                "The maximum size of the node atlas ({}) exceeds the {limit} nodes supported by this device and is clamped.",
+                self.max_size
+            );
+            self.max_size = limit;
+        }
+
+        if self.size <= limit {
+            return;
+        }
+
+        if self
+            .nodes
+            .values()
+            .any(|node| node.atlas_index != INVALID_ATLAS_INDEX && node.atlas_index >= limit)
+        {
+            return;
+        }
+
+        self.unused_nodes
+            .retain(|unused_node| unused_node.atlas_index < limit);
+        self.data.truncate(limit as usize);
+        self.size = limit;
+        self.cache_size = self.cache_size.min(limit);
+    }
+
+    /// Registers a callback, which is invoked with the id of each node evicted from the atlas.
+    ///
+    /// Nodes are evicted, once their atlas index is reused or the cache exceeds its capacity.
+    pub fn on_evict(&mut self, callback: impl Fn(NodeId) + Send + Sync + 'static) {
+        self.eviction_callbacks.push(Box::new(callback));
     }
 
     /// Adjusts the node atlas according to the requested and released nodes of the [`Quadtree`]
-    /// and starts loading not already present nodes.
-    fn fulfill_request(&mut self, quadtree: &mut Quadtree) {
+    /// and queues not already present nodes to be loaded.
+    pub(crate) fn fulfill_request(&mut self, quadtree: &mut Quadtree) {
         let NodeAtlas {
-            attachments,
             unused_nodes,
             nodes,
-            loading_nodes,
-            load_events,
+            load_queue,
             existing_nodes,
+            lifecycle_events,
+            cache_size,
             ..
         } = self;
 
@@ -173,7 +502,11 @@ impl NodeAtlas {
                 .expect("Tried releasing a node, which is not present.");
             node.requests -= 1;
 
-            if node.requests == 0 {
+            if node.requests == 0 && node.state == LoadingState::Queued {
+                // the node has not started loading yet and can be dropped
+                nodes.remove(&node_id);
+                load_queue.retain(|&queued_node_id| queued_node_id != node_id);
+            } else if node.requests == 0 {
                 // the node is not used anymore
                 unused_nodes.push_back(UnusedNode {
                     node_id,
@@ -190,93 +523,776 @@ impl NodeAtlas {
 
             // check if the node is already present else start loading it
             if let Some(node) = nodes.get_mut(&node_id) {
-                if node.requests == 0 {
+                if node.requests == 0 && node.state != LoadingState::Queued {
                     // the node is now used again
                     unused_nodes.retain(|unused_node| node.atlas_index != unused_node.atlas_index);
                 }
 
                 node.requests += 1;
             } else {
-                // Todo: implement better loading strategy
-                // remove least recently used node and reuse its atlas index
-                let unused_node = unused_nodes.pop_front().expect("Atlas out of indices");
-
-                nodes.remove(&unused_node.node_id);
                 nodes.insert(
                     node_id,
                     AtlasNode {
                         requests: 1,
-                        state: LoadingState::Loading,
-                        atlas_index: unused_node.atlas_index,
+                        state: LoadingState::Queued,
+                        atlas_index: INVALID_ATLAS_INDEX,
                     },
                 );
 
-                // start loading the node
-                load_events.push(node_id);
-                loading_nodes.insert(
-                    node_id,
-                    LoadingNode {
-                        atlas_index: unused_node.atlas_index,
-                        loading_attachments: (0..attachments.len()).collect(),
-                        attachments: default(),
-                    },
-                );
+                load_queue.push(node_id);
+                lifecycle_events.push((node_id, NodeLifecycle::Queued));
             }
         }
 
+        // the prefetched nodes are placed into the cache, so only its free capacity is queued
+        let cached_count = unused_nodes
+            .iter()
+            .filter(|unused_node| unused_node.node_id != INVALID_NODE_ID)
+            .count()
+            + load_queue
+                .iter()
+                .filter(|node_id| nodes[node_id].requests == 0)
+                .count();
+        let mut prefetch_count = (*cache_size as usize).saturating_sub(cached_count);
+
+        // queue nodes that are prefetched, without requesting them
+        // the prefetched nodes are kept, until they are present or the quadtree is traversed again,
+        // so that evicted ones are not loaded over and over again
+        quadtree.prefetched_nodes.retain(|&node_id| {
+            if !existing_nodes.contains(&node_id) || nodes.contains_key(&node_id) {
+                return false;
+            }
+
+            if prefetch_count == 0 {
+                return true;
+            }
+
+            prefetch_count -= 1;
+
+            nodes.insert(
+                node_id,
+                AtlasNode {
+                    requests: 0,
+                    state: LoadingState::Queued,
+                    atlas_index: INVALID_ATLAS_INDEX,
+                },
+            );
+
+            load_queue.push(node_id);
+            lifecycle_events.push((node_id, NodeLifecycle::Queued));
+
+            true
+        });
+
         // println!(
         //     "Currently there are {} nodes in use.",
         //     self.size as usize - self.unused_nodes.len()
         // );
     }
 
+    /// Evicts the least recently used nodes, which exceed the capacity of the cache.
+    fn evict_unused_nodes(&mut self) {
+        let NodeAtlas {
+            data,
+            unused_nodes,
+            nodes,
+            attachment_updates,
+            cache_size,
+            eviction_callbacks,
+            evicted_nodes,
+            lifecycle_events,
+            generation,
+            ..
+        } = self;
+
+        let mut cached_count = unused_nodes
+            .iter()
+            .filter(|unused_node| unused_node.node_id != INVALID_NODE_ID)
+            .count();
+
+        while cached_count > *cache_size as usize {
+            let position = unused_nodes
+                .iter()
+                .position(|unused_node| unused_node.node_id != INVALID_NODE_ID)
+                .unwrap();
+            let unused_node = unused_nodes.remove(position).unwrap();
+            let (node_id, atlas_index) = (unused_node.node_id, unused_node.atlas_index);
+
+            if let Some(node_data) = evict(
+                nodes,
+                data,
+                eviction_callbacks,
+                lifecycle_events,
+                unused_node,
+            ) {
+                evicted_nodes.push((node_id, node_data));
+            }
+            *generation += 1;
+            cached_count -= 1;
+
+            // the carried over updates of the evicted node are obsolete
+            attachment_updates.retain(|update| update.atlas_index != atlas_index);
+
+            // free atlas indices are reused first
+            unused_nodes.push_front(UnusedNode {
+                node_id: INVALID_NODE_ID,
+                atlas_index,
+            });
+        }
+    }
+
+    /// Starts loading the queued nodes, which are closest to any of the viewers.
+    ///
+    /// The nodes are prioritized by their distance to the nearest viewer (measured in node sizes),
+    /// so that coarser nodes are preferred when the distance is equal.
+    /// Prefetched nodes are loaded after all requested ones and are cached right away.
+    /// At most `load_budget` nodes start loading per frame, the remaining requested ones
+    /// stay queued, while the remaining prefetched ones are dropped.
+    fn start_loading(&mut self, viewer_positions: &[DVec2]) {
+        let NodeAtlas {
+            attachments,
+            data,
+            unused_nodes,
+            nodes,
+            load_queue,
+            loading_nodes,
+            load_events,
+            attachment_updates,
+            eviction_callbacks,
+            evicted_nodes,
+            lifecycle_events,
+            leaf_node_size,
+            load_budget,
+            generation,
+            size,
+            max_size,
+            ..
+        } = self;
+
+        let priority = |&node_id: &NodeId| {
+            let NodeCoordinate { lod, x, y } = node_id.into();
+            let node_size = (*leaf_node_size << lod) as f64;
+            let node_position = (DVec2::new(x as f64, y as f64) + 0.5) * node_size;
+
+            let distance = viewer_positions
+                .iter()
+                .map(|&viewer_position| viewer_position.distance(node_position) / node_size)
+                .fold(f64::MAX, f64::min);
+
+            (nodes[&node_id].requests == 0, distance, u32::MAX - lod)
+        };
+
+        // the nodes with the highest priority are at the end of the queue
+        load_queue.sort_by(|a, b| priority(b).partial_cmp(&priority(a)).unwrap());
+
+        let load_count = load_queue.len().min(*load_budget);
+
+        // only the requested nodes grow the atlas, the prefetched ones only use free indices
+        let requested_count = load_queue[load_queue.len() - load_count..]
+            .iter()
+            .filter(|node_id| nodes[node_id].requests > 0)
+            .count();
+
+        if requested_count > unused_nodes.len() && *size < *max_size {
+            let missing = (requested_count - unused_nodes.len()).min(u16::MAX as usize) as u16;
+            let grown_size = size
+                .saturating_mul(2)
+                .max(size.saturating_add(missing))
+                .min(*max_size);
+
+            // free atlas indices are reused first
+            for atlas_index in (*size..grown_size).rev() {
+                unused_nodes.push_front(UnusedNode {
+                    node_id: INVALID_NODE_ID,
+                    atlas_index,
+                });
+            }
+
+            data.resize(grown_size as usize, default());
+            *size = grown_size;
+        }
+
+        // the remaining nodes wait in the queue, until atlas indices become available
+        let load_count = load_count.min(unused_nodes.len());
+
+        for node_id in load_queue.drain(load_queue.len() - load_count..).rev() {
+            // remove least recently used node and reuse its atlas index
+            let unused_node = unused_nodes.pop_front().unwrap();
+            let atlas_index = unused_node.atlas_index;
+
+            if unused_node.node_id != INVALID_NODE_ID {
+                let evicted_id = unused_node.node_id;
+
+                if let Some(node_data) = evict(
+                    nodes,
+                    data,
+                    eviction_callbacks,
+                    lifecycle_events,
+                    unused_node,
+                ) {
+                    evicted_nodes.push((evicted_id, node_data));
+                }
+
+                *generation += 1;
+            }
+
+            // the carried over updates of the previous node would overwrite the loaded data
+            attachment_updates.retain(|update| update.atlas_index != atlas_index);
+
+            let node = nodes.get_mut(&node_id).unwrap();
+            node.state = LoadingState::Loading;
+            node.atlas_index = atlas_index;
+
+            if node.requests == 0 {
+                // the node is only prefetched and thus unused
+                unused_nodes.push_back(UnusedNode {
+                    node_id,
+                    atlas_index,
+                });
+            }
+
+            // start loading the node
+            load_events.push(node_id);
+            loading_nodes.insert(
+                node_id,
+                LoadingNode {
+                    atlas_index,
+                    loading_attachments: (0..attachments.len()).collect(),
+                    attachments: default(),
+                    start: Instant::now(),
+                },
+            );
+        }
+
+        // prefetched nodes are queued again during the next traversal
+        load_queue.retain(|node_id| {
+            let prefetched = nodes[node_id].requests == 0;
+
+            if prefetched {
+                nodes.remove(node_id);
+            }
+
+            !prefetched
+        });
+    }
+
+    /// Migrates the loaded nodes with the highest atlas indices into the lowest free atlas indices,
+    /// so that the occupied part of the atlas stays contiguous.
+    ///
+    /// At most `compaction_budget` nodes are migrated per frame. Nodes, whose data is still pending
+    /// (because they are loading, reloading or waiting to be activated) are not migrated.
+    /// The GPU copies the attachments of the migrated nodes and the quadtrees are adjusted
+    /// to the new atlas indices afterwards.
+    fn compact(&mut self) {
+        let NodeAtlas {
+            data,
+            nodes,
+            unused_nodes,
+            loading_nodes,
+            loaded_nodes,
+            activation_queue,
+            attachment_updates,
+            migrated_nodes,
+            compaction_budget,
+            generation,
+            ..
+        } = self;
+
+        if *compaction_budget == 0 {
+            return;
+        }
+
+        let pending_nodes = loading_nodes
+            .keys()
+            .chain(activation_queue.iter().map(|(node_id, _)| node_id))
+            .copied()
+            .collect::<HashSet<_>>();
+        let pending_indices = loaded_nodes
+            .iter()
+            .map(|node| node.atlas_index)
+            .collect::<HashSet<_>>();
+
+        for _ in 0..*compaction_budget {
+            let Some((free_position, to)) = unused_nodes
+                .iter()
+                .enumerate()
+                .filter(|(_, unused_node)| {
+                    // the data of an evicted node may still be copied into the atlas index
+                    unused_node.node_id == INVALID_NODE_ID
+                        && !pending_indices.contains(&unused_node.atlas_index)
+                })
+                .map(|(position, unused_node)| (position, unused_node.atlas_index))
+                .min_by_key(|&(_, atlas_index)| atlas_index)
+            else {
+                break;
+            };
+
+            let Some((&node_id, node)) = nodes
+                .iter_mut()
+                .filter(|(node_id, node)| {
+                    node.state == LoadingState::Loaded
+                        && !pending_nodes.contains(*node_id)
+                        && !pending_indices.contains(&node.atlas_index)
+                })
+                .max_by_key(|(_, node)| node.atlas_index)
+            else {
+                break;
+            };
+
+            let from = node.atlas_index;
+
+            if from < to {
+                // the occupied atlas indices are already contiguous
+                break;
+            }
+
+            node.atlas_index = to;
+            data.swap(from as usize, to as usize);
+
+            // the previous atlas index of the node is free instead
+            unused_nodes[free_position].atlas_index = from;
+
+            if let Some(cached_node) = unused_nodes
+                .iter_mut()
+                .find(|unused_node| unused_node.node_id == node_id)
+            {
+                cached_node.atlas_index = to;
+            }
+
+            // the carried over updates of the free atlas index belong to an evicted node
+            attachment_updates.retain(|update| update.atlas_index != to);
+
+            for update in attachment_updates.iter_mut() {
+                if update.atlas_index == from {
+                    update.atlas_index = to;
+                }
+            }
+
+            migrated_nodes.push((from, to));
+            *generation += 1;
+        }
+    }
+
+    /// Loads the data of the node again, e.g. after its files have been modified.
+    ///
+    /// The node keeps its current data, until the reloaded data is activated. Then its region
+    /// of the atlas is overwritten, its cpu accessible data is replaced and the edits are reapplied.
+    /// Nodes, that are not present or have not started loading yet, are not affected.
+    pub fn reload_node(&mut self, node_id: NodeId) {
+        let Some(node) = self.nodes.get(&node_id) else {
+            return;
+        };
+
+        if node.state == LoadingState::Queued {
+            return;
+        }
+
+        self.load_events.push(node_id);
+        self.loading_nodes.insert(
+            node_id,
+            LoadingNode {
+                atlas_index: node.atlas_index,
+                loading_attachments: (0..self.attachments.len()).collect(),
+                attachments: default(),
+                start: Instant::now(),
+            },
+        );
+    }
+
+    /// Discards the data of the node, e.g. after its files have been removed.
+    ///
+    /// The node is evicted from the atlas, which clears its cpu accessible data, so that the
+    /// terrain falls back to its loaded ancestors. Requested nodes are queued again and load
+    /// without the attachments, whose files are missing, while unused nodes are dropped.
+    /// Nodes, that are not present or have not started loading yet, are not affected.
+    pub fn invalidate_node(&mut self, node_id: NodeId) {
+        let Some(node) = self.nodes.get(&node_id) else {
+            return;
+        };
+
+        if node.state == LoadingState::Queued {
+            return;
+        }
+
+        let (requests, atlas_index) = (node.requests, node.atlas_index);
+
+        self.loading_nodes.remove(&node_id);
+        self.unused_nodes
+            .retain(|unused_node| unused_node.atlas_index != atlas_index);
+        self.attachment_updates
+            .retain(|update| update.atlas_index != atlas_index);
+
+        evict(
+            &mut self.nodes,
+            &mut self.data,
+            &self.eviction_callbacks,
+            &mut self.lifecycle_events,
+            UnusedNode {
+                node_id,
+                atlas_index,
+            },
+        );
+        self.generation += 1;
+
+        // free atlas indices are reused first
+        self.unused_nodes.push_front(UnusedNode {
+            node_id: INVALID_NODE_ID,
+            atlas_index,
+        });
+
+        if requests > 0 {
+            self.nodes.insert(
+                node_id,
+                AtlasNode {
+                    requests,
+                    state: LoadingState::Queued,
+                    atlas_index: INVALID_ATLAS_INDEX,
+                },
+            );
+
+            self.load_queue.push(node_id);
+            self.lifecycle_events.push((node_id, NodeLifecycle::Queued));
+        }
+    }
+
+    /// Returns the index of the attachment with the name, if the atlas has one.
+    pub fn attachment_index(&self, name: &str) -> Option<AttachmentIndex> {
+        self.attachments
+            .iter()
+            .position(|attachment| attachment.name == name)
+    }
+
+    /// Returns the cpu accessible data of the node, if it is finished loading.
+    pub fn node_data(&self, node_id: NodeId) -> Option<&NodeData> {
+        self.nodes
+            .get(&node_id)
+            .filter(|node| node.state == LoadingState::Loaded)
+            .map(|node| &self.data[node.atlas_index as usize])
+    }
+
+    /// Returns a summary of the current occupancy of the atlas.
+    pub fn usage(&self) -> NodeAtlasUsage {
+        let mut usage = NodeAtlasUsage {
+            size: self.size as usize,
+            max_size: self.max_size as usize,
+            cached: self
+                .unused_nodes
+                .iter()
+                .filter(|unused_node| unused_node.node_id != INVALID_NODE_ID)
+                .count(),
+            ..default()
+        };
+
+        let highest_index = self
+            .nodes
+            .values()
+            .filter(|node| node.state != LoadingState::Queued)
+            .map(|node| node.atlas_index)
+            .max();
+
+        if let Some(highest_index) = highest_index {
+            usage.fragmented = self
+                .unused_nodes
+                .iter()
+                .filter(|unused_node| {
+                    unused_node.node_id == INVALID_NODE_ID
+                        && unused_node.atlas_index < highest_index
+                })
+                .count();
+        }
+
+        for node in self.nodes.values() {
+            match node.state {
+                LoadingState::Queued => usage.queued += 1,
+                LoadingState::Loading => usage.loading += 1,
+                LoadingState::Loaded => usage.loaded += 1,
+            }
+
+            if node.state == LoadingState::Loaded && node.requests > 0 {
+                usage.active += 1;
+            }
+        }
+
+        usage
+    }
+
+    /// Returns the nodes, that are currently requested by a quadtree, together with whether
+    /// they finished loading and their height bounds (in the local space of the terrain).
+    ///
+    /// The bounds of the nodes, that have not finished loading, are estimated from the
+    /// preprocessed bounds or span the entire height of the terrain.
+    pub(crate) fn requested_nodes(&self) -> impl Iterator<Item = (NodeId, bool, Vec2)> + '_ {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| node.requests > 0)
+            .map(|(&node_id, node)| {
+                let loaded = node.state == LoadingState::Loaded;
+
+                let height_bounds = if loaded {
+                    self.data[node.atlas_index as usize].height_bounds()
+                } else {
+                    self.preprocessed_bounds
+                        .get(&node_id)
+                        .map_or(Vec2::new(0.0, self.height), |&bounds| bounds * self.height)
+                };
+
+                (node_id, loaded, height_bounds)
+            })
+    }
+
+    /// Returns the ids of all nodes, that are present and finished loading.
+    pub(crate) fn loaded_node_ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| node.state == LoadingState::Loaded)
+            .map(|(&node_id, _)| node_id)
+    }
+
+    /// Clears the load events and statistics of the previous update.
+    ///
+    /// The lifecycle changes and the edited and painted nodes are kept until the end of the frame
+    /// instead (see [`clear_node_atlas_events`]).
+    fn clear_frame_events(&mut self) {
+        self.load_events.clear();
+        self.activation_count = 0;
+        self.load_latency = None;
+    }
+
     /// Checks all nodes that have finished loading, marks them accordingly and prepares the data
     /// to be send to the gpu by the [`GpuNodeAtlas`](super::gpu_node_atlas::GpuNodeAtlas).
-    fn update_loaded_nodes(&mut self) {
+    ///
+    /// At most `activation_budget` nodes are activated per frame, the remaining ones are
+    /// carried over to the following frames.
+    fn update_loaded_nodes(&mut self, images: &mut Assets<Image>) {
         let NodeAtlas {
+            ref attachments,
+            ref height,
+            ref preprocessed_bounds,
             ref mut data,
-            ref mut load_events,
+            ref mut lifecycle_events,
+            ref mut edited_nodes,
+            ref mut edited_regions,
             ref mut nodes,
             ref mut loading_nodes,
             ref mut loaded_nodes,
+            ref mut activation_queue,
+            ref mut generation,
+            ref mut activation_count,
+            ref mut load_latency,
+            activation_budget,
+            leaf_node_size,
             ..
         } = self;
 
-        load_events.clear();
+        let mut finished_nodes = Vec::new();
+        let mut total_latency = 0.0;
+
+        for (node_id, node) in loading_nodes.drain_filter(|_, node| node.finished_loading()) {
+            lifecycle_events.push((node_id, NodeLifecycle::Loaded));
+            activation_queue.push_back((node_id, node));
+        }
+
+        let activation_limit = activation_queue.len().min(*activation_budget);
 
         // update all nodes that have finished loading
-        for (node_id, loading_node) in loading_nodes.drain_filter(|_, node| node.finished_loading())
-        {
-            if let Some(node) = nodes.get_mut(&node_id) {
-                node.state = LoadingState::Loaded;
+        for (node_id, loading_node) in activation_queue.drain(..activation_limit) {
+            // the node might have been evicted and requested again while it was queued
+            match nodes.get_mut(&node_id) {
+                Some(node) if node.atlas_index == loading_node.atlas_index => {
+                    if node.state == LoadingState::Loaded {
+                        // the node has been reloaded, thus the data derived from it is outdated
+                        let NodeCoordinate { lod, x, y } = node_id.into();
+                        let node_size = (*leaf_node_size << lod) as f32;
+                        let node_origin = Vec2::new(x as f32, y as f32) * node_size;
 
-                // Todo: only keep attachments required by the CPU around
-                data[node.atlas_index as usize] = NodeData {
-                    _attachments: loading_node.attachments.clone(),
-                };
+                        edited_nodes.push(node_id);
+                        edited_regions.push((
+                            node_id,
+                            Rect::from_corners(node_origin, node_origin + node_size),
+                        ));
+                    } else {
+                        lifecycle_events.push((node_id, NodeLifecycle::Activated));
+                    }
 
-                loaded_nodes.push(loading_node);
-            } else {
-                dbg!("Dropped node after loading.");
-                // node no longer required, can safely be ignored
+                    node.state = LoadingState::Loaded;
+                    *generation += 1;
+
+                    // Todo: only keep attachments required by the CPU around
+                    data[node.atlas_index as usize] = NodeData::new(
+                        loading_node.attachments.clone(),
+                        attachments,
+                        images,
+                        *height,
+                        preprocessed_bounds.get(&node_id).copied(),
+                    );
+
+                    total_latency += loading_node.start.elapsed().as_secs_f32();
+
+                    loaded_nodes.push(loading_node);
+                    finished_nodes.push(node_id);
+                }
+                _ => {
+                    dbg!("Dropped node after loading.");
+                    // node no longer required, can safely be ignored
+                }
             }
         }
+
+        *activation_count = finished_nodes.len();
+        *load_latency =
+            (!finished_nodes.is_empty()).then(|| total_latency / finished_nodes.len() as f32);
+
+        for node_id in finished_nodes {
+            self.restore_saved_node(images, node_id);
+            self.bake_pending_edits(images, node_id);
+            self.reapply_edits(images, node_id);
+        }
+    }
+}
+
+/// Discards the loaded nodes and attachment updates, which would otherwise be sent to the GPU.
+///
+/// In `headless` mode there is no renderer, so only the cpu accessible data of the nodes is kept.
+#[cfg(feature = "headless")]
+pub(crate) fn discard_gpu_updates(mut terrain_query: Query<&mut NodeAtlas>) {
+    for mut node_atlas in terrain_query.iter_mut() {
+        node_atlas.loaded_nodes.clear();
+        node_atlas.attachment_updates.clear();
+        node_atlas.migrated_nodes.clear();
     }
 }
 
+/// Clears the lifecycle changes and the edited and painted nodes of the previous frame.
+///
+/// With the [`TerrainScheduling::FixedUpdate`](crate::TerrainScheduling::FixedUpdate) and
+/// [`TerrainScheduling::Manual`](crate::TerrainScheduling::Manual) schedules, the node atlas
+/// may be updated several times (or not at all) per frame. Thus these changes accumulate
+/// across all updates of the frame, so that their consumers (e.g. the extraction into the
+/// [`GpuNodeAtlas`](super::gpu_node_atlas::GpuNodeAtlas)) see each of them.
+pub(crate) fn clear_node_atlas_events(mut terrain_query: Query<&mut NodeAtlas>) {
+    for mut node_atlas in terrain_query.iter_mut() {
+        node_atlas.lifecycle_events.clear();
+        node_atlas.edited_nodes.clear();
+        node_atlas.edited_regions.clear();
+        node_atlas.painted_nodes.clear();
+    }
+}
+
+/// Creates the node atlas of the terrains, that have been loaded from a scene.
+///
+/// Scenes only store the [`TerrainConfig`], so the handles of its attachments and the nodes
+/// of the terrain are restored here as well.
+pub(crate) fn initialize_scene_terrains(
+    mut commands: Commands,
+    mut terrain_query: Query<(Entity, &mut TerrainConfig), (With<Terrain>, Without<NodeAtlas>)>,
+) {
+    for (terrain, mut config) in terrain_query.iter_mut() {
+        for attachment in &mut config.attachments {
+            attachment.handle = AtlasAttachment::create_handle();
+        }
+
+        if config.nodes.is_empty() {
+            config.nodes = config.height_bounds.keys().copied().collect();
+        }
+
+        commands
+            .entity(terrain)
+            .insert(NodeAtlas::from_config(&config));
+    }
+}
+
+/// Removes the node from the atlas, so that its atlas index can be reused.
+///
+/// Returns the data of the node, if it was loaded.
+fn evict(
+    nodes: &mut HashMap<NodeId, AtlasNode>,
+    data: &mut [NodeData],
+    eviction_callbacks: &[EvictionCallback],
+    lifecycle_events: &mut Vec<(NodeId, NodeLifecycle)>,
+    unused_node: UnusedNode,
+) -> Option<NodeData> {
+    let loaded = nodes
+        .remove(&unused_node.node_id)
+        .map_or(false, |node| node.state == LoadingState::Loaded);
+
+    if loaded {
+        lifecycle_events.push((unused_node.node_id, NodeLifecycle::Deactivated));
+    }
+
+    let node_data = mem::take(&mut data[unused_node.atlas_index as usize]);
+
+    for callback in eviction_callbacks {
+        callback(unused_node.node_id);
+    }
+
+    loaded.then_some(node_data)
+}
+
 /// Updates the node atlas according to all corresponding quadtrees.
+///
+/// The [`StreamingState`] of each terrain determines, which parts of the update are skipped.
+///
+/// The requests of all viewers of a terrain are combined, so that each node requested by
+/// any of them is loaded, starting with the ones nearest to a viewer.
+/// The requests of different terrains are fulfilled in parallel.
 pub(crate) fn update_node_atlas(
+    mut images: ResMut<Assets<Image>>,
     mut quadtrees: ResMut<TerrainViewComponents<Quadtree>>,
-    view_query: Query<Entity, With<TerrainView>>,
-    mut terrain_query: Query<(Entity, &mut NodeAtlas), With<Terrain>>,
+    limit: Option<Res<NodeAtlasLimit>>,
+    mut terrain_query: Query<(Entity, &mut NodeAtlas, Option<&StreamingState>), With<Terrain>>,
 ) {
-    for (terrain, mut node_atlas) in terrain_query.iter_mut() {
-        node_atlas.update_loaded_nodes();
+    // the limit is applied before the atlas indices are assigned to any nodes
+    if let Some(limit) = limit {
+        for (_, mut node_atlas, _) in &mut terrain_query {
+            if node_atlas.size > limit.0 || node_atlas.max_size > limit.0 {
+                node_atlas.limit_size(limit.0);
+            }
+        }
+    }
 
-        for view in view_query.iter() {
-            if let Some(quadtree) = quadtrees.get_mut(&(terrain, view)) {
-                node_atlas.fulfill_request(quadtree);
+    // activating the loaded nodes requires mutable access to the images,
+    // thus the terrains are processed sequentially here
+    let mut terrains = terrain_query
+        .iter_mut()
+        .filter_map(|(terrain, mut node_atlas, state)| {
+            let state = state.copied().unwrap_or_default();
+
+            node_atlas.clear_frame_events();
+
+            if state == StreamingState::Frozen {
+                return None;
             }
+
+            node_atlas.preserve_evicted_nodes(&images);
+            node_atlas.update_loaded_nodes(&mut images);
+            Some((terrain, (node_atlas, state, Vec::new())))
+        })
+        .collect::<HashMap<_, _>>();
+
+    for (&(terrain, _), quadtree) in &mut quadtrees.0 {
+        if let Some((_, _, terrain_quadtrees)) = terrains.get_mut(&terrain) {
+            terrain_quadtrees.push(quadtree);
         }
     }
+
+    let mut terrains = terrains.into_values().collect::<Vec<_>>();
+
+    // each terrain only accesses its own quadtrees
+    terrains.par_splat_map_mut(ComputeTaskPool::get(), None, |terrains| {
+        for (node_atlas, state, quadtrees) in terrains {
+            let mut viewer_positions = Vec::new();
+
+            for quadtree in quadtrees {
+                node_atlas.fulfill_request(quadtree);
+                viewer_positions.push(quadtree.viewer_position.xz());
+            }
+
+            // paused terrains neither evict, start loading nor migrate nodes
+            if *state == StreamingState::Active {
+                node_atlas.evict_unused_nodes();
+                node_atlas.start_loading(&viewer_positions);
+                node_atlas.compact();
+            }
+        }
+    });
 }
@@ -0,0 +1,82 @@
+//! Tracks the entities, which are spawned for the loaded nodes of a lod
+//! (e.g. the colliders or the water surfaces).
+
+use crate::terrain_data::{
+    node_atlas::{LoadingState, NodeAtlas},
+    NodeCoordinate, NodeId,
+};
+use bevy::{prelude::*, utils::HashMap};
+
+/// An entity spawned for a node.
+struct NodeEntity {
+    entity: Entity,
+    /// The amount of consecutive frames, the node has not been loaded.
+    unloaded_frames: u32,
+}
+
+/// The entities of the loaded nodes of a lod.
+///
+/// Once a node has been unloaded for longer than the hysteresis, its entity is removed.
+/// This prevents the entities from being churned, when nodes are reloaded shortly after.
+#[derive(Default)]
+pub(crate) struct NodeEntities {
+    entities: HashMap<NodeId, NodeEntity>,
+}
+
+impl NodeEntities {
+    /// Stores the entity of the node.
+    pub(crate) fn insert(&mut self, node_id: NodeId, entity: Entity) {
+        self.entities.insert(
+            node_id,
+            NodeEntity {
+                entity,
+                unloaded_frames: 0,
+            },
+        );
+    }
+
+    /// Determines the nodes of the lod, that have been loaded since the last update,
+    /// and removes the entities of all nodes, that have not been loaded for longer than the
+    /// hysteresis (measured in frames) or whose entities are outdated.
+    ///
+    /// Returns the ids of the newly loaded nodes and the removed entities.
+    pub(crate) fn update(
+        &mut self,
+        node_atlas: &NodeAtlas,
+        lod: u32,
+        hysteresis: u32,
+        outdated: impl Fn(NodeId) -> bool,
+    ) -> (Vec<NodeId>, Vec<Entity>) {
+        let loaded_nodes = node_atlas
+            .nodes
+            .iter()
+            .filter(|&(&node_id, node)| {
+                NodeCoordinate::from(node_id).lod == lod && node.state == LoadingState::Loaded
+            })
+            .map(|(&node_id, _)| node_id)
+            .collect::<Vec<_>>();
+
+        for (node_id, node_entity) in &mut self.entities {
+            if loaded_nodes.contains(node_id) {
+                node_entity.unloaded_frames = 0;
+            } else {
+                node_entity.unloaded_frames += 1;
+            }
+        }
+
+        let removed = self
+            .entities
+            .drain_filter(|&node_id, node_entity| {
+                node_entity.unloaded_frames > hysteresis || outdated(node_id)
+            })
+            .map(|(_, node_entity)| node_entity.entity)
+            .collect();
+
+        let added = loaded_nodes
+            .into_iter()
+            .filter(|node_id| !self.entities.contains_key(node_id))
+            .collect();
+
+        (added, removed)
+    }
+}
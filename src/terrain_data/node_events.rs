@@ -0,0 +1,89 @@
+//! Events, which are sent for each change of the lifecycle of a node in the [`NodeAtlas`].
+//!
+//! A node is first queued, then loaded and finally activated, once it can be used.
+//! It is deactivated again, when it is evicted from the atlas.
+//! Gameplay systems can use these events to spawn and despawn content (e.g. props,
+//! colliders or AI annotations) for each streamed region of the terrain.
+
+use crate::{
+    terrain::Terrain,
+    terrain_data::{
+        node_atlas::{NodeAtlas, NodeLifecycle},
+        NodeCoordinate, NodeId,
+    },
+};
+use bevy::{math::Vec3Swizzles, prelude::*};
+
+/// Describes the node, whose lifecycle changed.
+#[derive(Clone, Copy, Debug)]
+pub struct NodeEvent {
+    /// The terrain entity of the node.
+    pub terrain: Entity,
+    /// The id of the node.
+    pub node_id: NodeId,
+    /// The lod of the node.
+    pub lod: u32,
+    /// The region covered by the node along the x and z axes (in world space).
+    ///
+    /// For the faces of planets, this is the region covered by the flat face.
+    pub rect: Rect,
+}
+
+/// Sent, when a node has been queued to be loaded.
+#[derive(Clone, Copy, Debug, Deref)]
+pub struct NodeQueued(pub NodeEvent);
+
+/// Sent, when all attachments of a node have finished loading.
+#[derive(Clone, Copy, Debug, Deref)]
+pub struct NodeLoaded(pub NodeEvent);
+
+/// Sent, when a node has been activated and its data can be accessed.
+#[derive(Clone, Copy, Debug, Deref)]
+pub struct NodeActivated(pub NodeEvent);
+
+/// Sent, when an activated node has been evicted and its data can no longer be accessed.
+#[derive(Clone, Copy, Debug, Deref)]
+pub struct NodeDeactivated(pub NodeEvent);
+
+/// Sends the events for the lifecycle changes of the nodes this frame.
+pub(crate) fn send_node_events(
+    terrain_query: Query<(Entity, &GlobalTransform, &NodeAtlas), With<Terrain>>,
+    mut queued_events: EventWriter<NodeQueued>,
+    mut loaded_events: EventWriter<NodeLoaded>,
+    mut activated_events: EventWriter<NodeActivated>,
+    mut deactivated_events: EventWriter<NodeDeactivated>,
+) {
+    for (terrain, transform, node_atlas) in &terrain_query {
+        for &(node_id, lifecycle) in &node_atlas.lifecycle_events {
+            let NodeCoordinate { lod, x, y } = node_id.into();
+            let node_size = (node_atlas.leaf_node_size << lod) as f32;
+
+            let corners = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(dx, dy)| {
+                let local_position = Vec2::new((x + dx) as f32, (y + dy) as f32) * node_size;
+
+                transform
+                    .transform_point(Vec3::new(local_position.x, 0.0, local_position.y))
+                    .xz()
+            });
+
+            let rect = Rect {
+                min: corners.into_iter().reduce(Vec2::min).unwrap(),
+                max: corners.into_iter().reduce(Vec2::max).unwrap(),
+            };
+
+            let event = NodeEvent {
+                terrain,
+                node_id,
+                lod,
+                rect,
+            };
+
+            match lifecycle {
+                NodeLifecycle::Queued => queued_events.send(NodeQueued(event)),
+                NodeLifecycle::Loaded => loaded_events.send(NodeLoaded(event)),
+                NodeLifecycle::Activated => activated_events.send(NodeActivated(event)),
+                NodeLifecycle::Deactivated => deactivated_events.send(NodeDeactivated(event)),
+            }
+        }
+    }
+}
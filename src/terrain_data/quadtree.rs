@@ -1,13 +1,20 @@
 use crate::{
-    terrain::{Terrain, TerrainConfig},
+    planet::{face_to_sphere, project_onto_face},
+    terrain::{world_to_terrain, world_to_terrain_precise, Terrain, TerrainConfig, TerrainTile},
     terrain_data::{
         calc_node_id,
-        node_atlas::{LoadingState, NodeAtlas},
+        node_atlas::{LoadingState, NodeAtlas, StreamingState},
         AtlasIndex, NodeCoordinate, NodeId, INVALID_ATLAS_INDEX, INVALID_LOD, INVALID_NODE_ID,
     },
+    terrain_view::projection_scale,
     TerrainView, TerrainViewComponents, TerrainViewConfig,
 };
-use bevy::{math::Vec3Swizzles, prelude::*};
+use bevy::{
+    math::{DVec2, DVec3, Vec3Swizzles},
+    prelude::*,
+    tasks::{ComputeTaskPool, ParallelSliceMut},
+    utils::{HashMap, HashSet},
+};
 use bytemuck::{Pod, Zeroable};
 use itertools::iproduct;
 use ndarray::Array3;
@@ -84,27 +91,66 @@ impl Default for QuadtreeEntry {
 /// After the [`NodeAtlas`] has adjusted to these requests, the quadtree retrieves the best
 /// currently loaded nodes from the node atlas via the
 /// `adjust` methode, which can later be used to access the terrain data.
-#[derive(Default, Component)]
+///
+/// Only the configuration of the quadtree is reflected, its traversal state is skipped.
+#[derive(Default, Component, Reflect)]
 pub struct Quadtree {
     /// The handle of the quadtree texture.
+    #[reflect(ignore)]
     pub(crate) handle: Handle<Image>,
     /// The current cpu quadtree data. This is synced each frame with the gpu quadtree data.
+    #[reflect(ignore)]
     pub(crate) data: Array3<QuadtreeEntry>,
     /// Nodes that are no longer required by this quadtree.
+    #[reflect(ignore)]
     pub(crate) released_nodes: Vec<NodeId>,
     /// Nodes that are requested to be loaded by this quadtree.
+    #[reflect(ignore)]
     pub(crate) requested_nodes: Vec<NodeId>,
+    /// Nodes that are close to being requested and should be loaded ahead of time.
+    #[reflect(ignore)]
+    pub(crate) prefetched_nodes: Vec<NodeId>,
     /// The count of level of detail layers.
     pub(crate) lod_count: u32,
     /// The count of nodes in x and y direction per layer.
     pub(crate) node_count: u32,
     /// The size of the smallest nodes (with lod 0).
     leaf_node_size: u32,
+    /// The finest lod, whose nodes are requested.
+    min_lod: u32,
     /// The distance (measured in node sizes) until which to request nodes to be loaded.
     load_distance: f32,
+    /// The additional distance (measured in node sizes) until which to prefetch nodes.
+    prefetch_distance: f32,
+    /// The additional distance (measured in node sizes) until which requested nodes are kept.
+    load_hysteresis: f32,
+    /// The distance (measured in leaf node sizes) the viewer has to move, before the quadtree
+    /// is traversed again.
+    traversal_threshold: f32,
     height: f32,
     height_under_viewer: f32,
+    /// The extent of the terrain.
+    terrain_extent: DVec2,
+    /// The radius of the planet, if the terrain is one of its faces.
+    planet_radius: Option<f64>,
+    /// The position of the viewer (in the local space of the terrain) during the last traversal.
+    pub(crate) viewer_position: DVec3,
+    /// The height under the viewer during the last traversal.
+    traversal_height: f32,
+    /// Indicates whether the quadtree has to be traversed, regardless of the viewer movement.
+    #[reflect(ignore)]
+    needs_traversal: bool,
+    /// Indicates whether the quadtree has been traversed this frame.
+    #[reflect(ignore)]
+    traversed: bool,
+    /// Indicates whether the quadtree has been adjusted this frame and has to be synced with the gpu.
+    #[reflect(ignore)]
+    pub(crate) adjusted: bool,
+    /// The generation of the node atlas, the quadtree has last been adjusted to.
+    #[reflect(ignore)]
+    atlas_generation: u64,
     /// The internal node states of the quadtree.
+    #[reflect(ignore)]
     nodes: Array3<TreeNode>,
 }
 
@@ -116,6 +162,7 @@ impl Quadtree {
     /// * `node_count` - The count of nodes in x and y direction per layer.
     /// * `leaf_node_size` - The size of the smallest nodes (with lod 0).
     /// * `load_distance` - The distance (measured in node sizes) until which to request nodes to be loaded.
+    /// * `prefetch_distance` - The additional distance (measured in node sizes) until which to prefetch nodes.
     /// * `height` - The height of the terrain.
     pub fn new(
         handle: Handle<Image>,
@@ -123,6 +170,7 @@ impl Quadtree {
         node_count: u32,
         leaf_node_size: u32,
         load_distance: f32,
+        prefetch_distance: f32,
         height: f32,
     ) -> Self {
         Self {
@@ -130,26 +178,47 @@ impl Quadtree {
             lod_count,
             node_count,
             leaf_node_size,
+            min_lod: 0,
             load_distance,
+            prefetch_distance,
+            load_hysteresis: 0.0,
+            traversal_threshold: 0.0,
             height,
             height_under_viewer: height / 2.0,
+            terrain_extent: default(),
+            planet_radius: None,
+            viewer_position: default(),
+            traversal_height: 0.0,
+            needs_traversal: true,
+            traversed: false,
+            adjusted: false,
+            atlas_generation: 0,
             data: Array3::default((lod_count as usize, node_count as usize, node_count as usize)),
             nodes: Array3::default((lod_count as usize, node_count as usize, node_count as usize)),
             released_nodes: default(),
             requested_nodes: default(),
+            prefetched_nodes: default(),
         }
     }
 
     /// Creates a new quadtree from a terrain and a terrain view config.
     pub fn from_configs(config: &TerrainConfig, view_config: &TerrainViewConfig) -> Self {
-        Self::new(
-            view_config.quadtree_handle.clone(),
-            config.lod_count,
-            view_config.node_count,
-            config.leaf_node_size,
-            view_config.load_distance,
-            config.height,
-        )
+        Self {
+            load_hysteresis: view_config.load_hysteresis,
+            traversal_threshold: view_config.traversal_threshold,
+            min_lod: view_config.min_lod(),
+            terrain_extent: config.terrain_extent.as_dvec2(),
+            planet_radius: config.planet_radius.map(f64::from),
+            ..Self::new(
+                view_config.quadtree_handle.clone(),
+                config.lod_count,
+                view_config.node_count,
+                config.leaf_node_size,
+                view_config.load_distance,
+                view_config.prefetch_distance,
+                config.height,
+            )
+        }
     }
 
     /// Calculates the size of a node.
@@ -158,16 +227,88 @@ impl Quadtree {
         self.leaf_node_size * (1 << lod)
     }
 
+    /// Returns the position of the terrain surface (in the local space of the terrain),
+    /// which is curved for the faces of planets.
+    fn surface_position(&self, local_position: DVec2, height: f64) -> DVec3 {
+        match self.planet_radius {
+            Some(radius) => face_to_sphere(local_position, height, radius, self.terrain_extent),
+            None => DVec3::new(local_position.x, height, local_position.y),
+        }
+    }
+
+    /// Applies changes of the view config and the projection of the view, which affect the traversal.
+    fn update_config(&mut self, view_config: &TerrainViewConfig, projection_scale: Option<f32>) {
+        let (_, load_distance) = view_config.lod_distances(self.leaf_node_size, projection_scale);
+
+        let distances = (
+            load_distance,
+            view_config.prefetch_distance,
+            view_config.load_hysteresis,
+            view_config.traversal_threshold,
+            view_config.min_lod(),
+        );
+
+        if distances
+            != (
+                self.load_distance,
+                self.prefetch_distance,
+                self.load_hysteresis,
+                self.traversal_threshold,
+                self.min_lod,
+            )
+        {
+            (
+                self.load_distance,
+                self.prefetch_distance,
+                self.load_hysteresis,
+                self.traversal_threshold,
+                self.min_lod,
+            ) = distances;
+            self.needs_traversal = true;
+        }
+    }
+
+    /// Returns whether the viewer moved far enough since the last traversal,
+    /// for the selected nodes to change.
+    fn requires_traversal(&self, viewer_position: DVec3) -> bool {
+        let threshold = self.traversal_threshold * self.leaf_node_size as f32;
+
+        self.needs_traversal
+            || viewer_position.distance(self.viewer_position) > threshold as f64
+            || (self.height_under_viewer - self.traversal_height).abs() > threshold
+    }
+
     /// Traverses the quadtree and updates the node states,
-    /// while selecting newly requested and released nodes.
-    pub(crate) fn compute_requests(&mut self, viewer_position: Vec3) {
+    /// while selecting newly requested, released and prefetched nodes.
+    ///
+    /// The distances are computed in double precision, so that the selection of the nodes stays
+    /// stable far away from the origin of the terrain.
+    pub(crate) fn compute_requests(&mut self, viewer_position: DVec3) {
+        self.viewer_position = viewer_position;
+        self.traversal_height = self.height_under_viewer;
+        self.needs_traversal = false;
+        self.prefetched_nodes.clear();
+
+        // the faces of planets are centered under the projection of the viewer
+        let grid_position = match self.planet_radius {
+            Some(_) => project_onto_face(viewer_position, self.terrain_extent),
+            None => viewer_position.xz(),
+        };
+
+        // an infinite load distance already demands all nodes of the grid, so none are prefetched
+        let prefetch_distance = self.load_distance + self.prefetch_distance;
+        let prefetch_distance = if prefetch_distance.is_finite() {
+            prefetch_distance
+        } else {
+            0.0
+        };
+
         for lod in 0..self.lod_count {
             let node_size = self.node_size(lod);
 
             // bottom left position of grid in node coordinates
-            let grid_coordinate: IVec2 = (viewer_position.xz() / node_size as f32 + 0.5
-                - (self.node_count >> 1) as f32)
-                .as_ivec2();
+            let grid_coordinate: IVec2 =
+                (grid_position / node_size as f64 + 0.5 - (self.node_count >> 1) as f64).as_ivec2();
 
             for coordinate in iproduct!(0..self.node_count as i32, 0..self.node_count as i32)
                 .filter_map(|(x, y)| {
@@ -198,11 +339,20 @@ impl Quadtree {
                     node.node_id = node_id;
                 }
 
-                let node_position = (coordinate.as_vec2() + 0.5) * node_size as f32;
+                let node_position = (coordinate.as_dvec2() + 0.5) * node_size as f64;
                 let world_position =
-                    Vec3::new(node_position.x, self.height_under_viewer, node_position.y);
-                let distance = viewer_position.xyz().distance(world_position);
-                let mut demanded = distance < self.load_distance * node_size as f32;
+                    self.surface_position(node_position, self.height_under_viewer as f64);
+                let distance = viewer_position.distance(world_position) as f32;
+
+                // requested nodes are only released beyond the hysteresis,
+                // to prevent them from flipping back and forth at the load distance
+                let load_distance = match node.state {
+                    RequestState::Requested => self.load_distance + self.load_hysteresis,
+                    RequestState::Released => self.load_distance,
+                };
+
+                let mut demanded =
+                    lod >= self.min_lod && distance < load_distance * node_size as f32;
                 demanded |= lod == self.lod_count - 1; // always request highest lod
 
                 // request or release node based on their distance to the viewer
@@ -217,35 +367,90 @@ impl Quadtree {
                     }
                     (_, _) => {}
                 }
+
+                // the nodes finer than the minimum lod are never requested, so they are not prefetched
+                if !demanded
+                    && lod >= self.min_lod
+                    && distance < prefetch_distance * node_size as f32
+                {
+                    self.prefetched_nodes.push(node.node_id);
+                }
             }
         }
     }
 
-    /// Adjusts the quadtree to the node atlas by updating the entries with the best available nodes.
-    fn adjust(&mut self, node_atlas: &NodeAtlas) {
-        for ((lod, x, y), node) in self.nodes.indexed_iter_mut() {
-            let mut node_id = node.node_id;
-            let mut coordinate = NodeCoordinate::from(node_id);
-
-            let (atlas_index, atlas_lod) = loop {
-                if coordinate.lod == self.lod_count || node_id == INVALID_NODE_ID {
-                    // highest lod is not loaded
-                    break (INVALID_ATLAS_INDEX, u16::MAX);
+    /// Releases all currently requested nodes.
+    ///
+    /// This is used to hand back the nodes of a viewer, that no longer observes the terrain.
+    pub(crate) fn release_all(&mut self) {
+        self.prefetched_nodes.clear();
+
+        for node in self.nodes.iter_mut() {
+            if node.state == RequestState::Requested {
+                self.released_nodes.push(node.node_id);
+                node.state = RequestState::Released;
+            }
+        }
+    }
+
+    /// Returns the amount of requested nodes and the amount of those, that are loaded
+    /// in the node atlas, for each lod layer of the quadtree.
+    ///
+    /// Requested nodes, that are not loaded yet, are substituted by their coarser ancestors.
+    pub fn occupancy(&self) -> Vec<(u32, u32)> {
+        let mut occupancy = vec![(0, 0); self.lod_count as usize];
+
+        for ((lod, x, y), node) in self.nodes.indexed_iter() {
+            if node.state == RequestState::Requested {
+                occupancy[lod].0 += 1;
+
+                if self.data[[lod, y, x]].atlas_lod as usize == lod {
+                    occupancy[lod].1 += 1;
                 }
+            }
+        }
+
+        occupancy
+    }
+
+    /// Returns the atlas index and lod of the best loaded node, that covers the node
+    /// and is at least as coarse as the minimum lod.
+    fn best_loaded_node(
+        &self,
+        node_atlas: &NodeAtlas,
+        node_id: NodeId,
+        min_lod: u32,
+    ) -> (AtlasIndex, u16) {
+        let mut node_id = node_id;
+        let mut coordinate = NodeCoordinate::from(node_id);
+
+        loop {
+            if coordinate.lod == self.lod_count || node_id == INVALID_NODE_ID {
+                // highest lod is not loaded
+                break (INVALID_ATLAS_INDEX, u16::MAX);
+            }
 
+            if coordinate.lod >= min_lod {
                 if let Some(atlas_node) = node_atlas.nodes.get(&node_id) {
                     if atlas_node.state == LoadingState::Loaded {
                         // found best loaded node
                         break (atlas_node.atlas_index, coordinate.lod as u16);
                     }
                 }
+            }
 
-                // node not loaded, try parent
-                coordinate.lod += 1;
-                coordinate.x >>= 1;
-                coordinate.y >>= 1;
-                node_id = calc_node_id(coordinate.lod, coordinate.x, coordinate.y);
-            };
+            // node not loaded, try parent
+            coordinate.lod += 1;
+            coordinate.x >>= 1;
+            coordinate.y >>= 1;
+            node_id = calc_node_id(coordinate.lod, coordinate.x, coordinate.y);
+        }
+    }
+
+    /// Adjusts the quadtree to the node atlas by updating the entries with the best available nodes.
+    fn adjust(&mut self, node_atlas: &NodeAtlas) {
+        for ((lod, x, y), node) in self.nodes.indexed_iter() {
+            let (atlas_index, atlas_lod) = self.best_loaded_node(node_atlas, node.node_id, 0);
 
             self.data[[lod, y, x]] = QuadtreeEntry {
                 atlas_index,
@@ -253,97 +458,322 @@ impl Quadtree {
             };
         }
     }
+
+    /// Returns the node of the quadtree at the coordinate, if it lies inside the quadtree.
+    fn slot(&self, lod: u32, coordinate: UVec2) -> Option<[usize; 2]> {
+        let slot = [
+            (coordinate.x % self.node_count) as usize,
+            (coordinate.y % self.node_count) as usize,
+        ];
+
+        (self.nodes[[lod as usize, slot[0], slot[1]]].node_id
+            == calc_node_id(lod, coordinate.x, coordinate.y))
+        .then_some(slot)
+    }
+
+    /// Coarsens the entries of the nodes along the shared edge with the neighbouring tile,
+    /// until both quadtrees select the same lod on either side of the edge.
+    ///
+    /// The neighbour lies in the positive direction (`IVec2::X` or `IVec2::Y`) of this tile.
+    /// Returns whether any entry has changed.
+    fn stitch(
+        &mut self,
+        node_atlas: &NodeAtlas,
+        neighbour: &mut Quadtree,
+        neighbour_atlas: &NodeAtlas,
+        direction: IVec2,
+    ) -> bool {
+        let mut changed = false;
+
+        for lod in 0..self.lod_count {
+            let node_count = (self.terrain_extent.as_uvec2() / self.node_size(lod)).max(UVec2::ONE);
+            let edge = direction.as_uvec2() * (node_count - 1);
+
+            for (x, y) in iproduct!(0..self.node_count, 0..self.node_count) {
+                let node_id = self.nodes[[lod as usize, x as usize, y as usize]].node_id;
+
+                if node_id == INVALID_NODE_ID {
+                    continue;
+                }
+
+                let coordinate = NodeCoordinate::from(node_id);
+                let coordinate = UVec2::new(coordinate.x, coordinate.y);
+
+                // only the nodes touching the shared edge have to be stitched
+                if coordinate.cmplt(node_count).all()
+                    && direction.as_uvec2().dot(coordinate) == direction.as_uvec2().dot(edge)
+                {
+                    let neighbour_coordinate = coordinate * direction.yx().as_uvec2();
+                    let Some([nx, ny]) = neighbour.slot(lod, neighbour_coordinate) else {
+                        continue;
+                    };
+                    let neighbour_id = neighbour.nodes[[lod as usize, nx, ny]].node_id;
+
+                    let entry = self.data[[lod as usize, y as usize, x as usize]];
+                    let neighbour_entry = neighbour.data[[lod as usize, ny, nx]];
+
+                    if entry.atlas_lod == neighbour_entry.atlas_lod {
+                        continue;
+                    }
+
+                    // coarsen both sides, until a lod is loaded for both of them
+                    let mut target_lod = entry.atlas_lod.max(neighbour_entry.atlas_lod);
+
+                    let (entry, neighbour_entry) = loop {
+                        if target_lod == u16::MAX {
+                            break (QuadtreeEntry::default(), QuadtreeEntry::default());
+                        }
+
+                        let (atlas_index, atlas_lod) =
+                            self.best_loaded_node(node_atlas, node_id, target_lod as u32);
+                        let (neighbour_index, neighbour_lod) = neighbour.best_loaded_node(
+                            neighbour_atlas,
+                            neighbour_id,
+                            target_lod as u32,
+                        );
+
+                        if atlas_lod == neighbour_lod {
+                            break (
+                                QuadtreeEntry {
+                                    atlas_index,
+                                    atlas_lod,
+                                },
+                                QuadtreeEntry {
+                                    atlas_index: neighbour_index,
+                                    atlas_lod: neighbour_lod,
+                                },
+                            );
+                        }
+
+                        target_lod = atlas_lod.max(neighbour_lod);
+                    };
+
+                    self.data[[lod as usize, y as usize, x as usize]] = entry;
+                    neighbour.data[[lod as usize, ny, nx]] = neighbour_entry;
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
 }
 
 /// Traverses all quadtrees and updates the node states,
 /// while selecting newly requested and released nodes.
+///
+/// Each registered viewer traverses its own quadtree, so that every view requests exactly
+/// the nodes it requires. The [`NodeAtlas`] then loads the union of all requested nodes.
+/// Quadtrees are only traversed, once their viewer moved or their view config changed.
+/// The quadtrees of frozen terrains are not traversed at all.
+/// The traversals of all quadtrees run in parallel.
 pub(crate) fn compute_quadtree_request(
     mut quadtrees: ResMut<TerrainViewComponents<Quadtree>>,
-    view_query: Query<(Entity, &GlobalTransform), With<TerrainView>>,
-    terrain_query: Query<(Entity, &GlobalTransform), With<Terrain>>,
+    view_configs: Res<TerrainViewComponents<TerrainViewConfig>>,
+    view_query: Query<(&GlobalTransform, Option<&Camera>, Option<&Projection>), With<TerrainView>>,
+    terrain_query: Query<(&GlobalTransform, Option<&StreamingState>), With<Terrain>>,
 ) {
-    // Todo: properly take the terrain transform into account
-    for (terrain, _terrain_transform) in terrain_query.iter() {
-        for (view, view_transform) in view_query.iter() {
-            let view_position = view_transform.translation();
-            let quadtree = quadtrees.get_mut(&(terrain, view)).unwrap();
+    let mut traversals = Vec::new();
+
+    for (&(terrain, view), quadtree) in &mut quadtrees.0 {
+        quadtree.traversed = false;
+
+        if let (Ok((terrain_transform, state)), Ok((view_transform, camera, projection))) =
+            (terrain_query.get(terrain), view_query.get(view))
+        {
+            if state == Some(&StreamingState::Frozen) {
+                continue;
+            }
 
-            quadtree.compute_requests(view_position);
+            if let Some(view_config) = view_configs.get(&(terrain, view)) {
+                quadtree.update_config(view_config, projection_scale(camera, projection));
+            }
+
+            // the distances are measured in the local space of the terrain,
+            // thus the load distance scales with the terrain
+            let view_position =
+                world_to_terrain_precise(terrain_transform, view_transform.translation());
+
+            if quadtree.requires_traversal(view_position) {
+                traversals.push((quadtree, view_position));
+            }
+        }
+    }
+
+    traversals.par_splat_map_mut(ComputeTaskPool::get(), None, |traversals| {
+        for (quadtree, view_position) in traversals {
+            quadtree.compute_requests(*view_position);
+            quadtree.traversed = true;
+        }
+    });
+}
+
+/// Releases all nodes of viewers, whose view or terrain no longer exists, and removes
+/// their quadtrees and view configs.
+pub(crate) fn remove_terrain_views(
+    mut quadtrees: ResMut<TerrainViewComponents<Quadtree>>,
+    mut view_configs: ResMut<TerrainViewComponents<TerrainViewConfig>>,
+    view_query: Query<(), With<TerrainView>>,
+    mut terrain_query: Query<&mut NodeAtlas, With<Terrain>>,
+) {
+    let removed_views = quadtrees
+        .0
+        .keys()
+        .filter(|&&(terrain, view)| !terrain_query.contains(terrain) || !view_query.contains(view))
+        .copied()
+        .collect::<Vec<_>>();
+
+    for (terrain, view) in removed_views {
+        let mut quadtree = quadtrees.remove(&(terrain, view)).unwrap();
+        view_configs.remove(&(terrain, view));
+
+        if let Ok(mut node_atlas) = terrain_query.get_mut(terrain) {
+            quadtree.release_all();
+            node_atlas.fulfill_request(&mut quadtree);
         }
     }
 }
 
 /// Adjusts all quadtrees to their corresponding node atlas
 /// by updating the entries with the best available nodes.
+///
+/// Quadtrees are only adjusted, if they have been traversed or the node atlas changed.
+/// The adjustments of all quadtrees run in parallel.
 pub(crate) fn adjust_quadtree(
     mut quadtrees: ResMut<TerrainViewComponents<Quadtree>>,
-    view_query: Query<Entity, With<TerrainView>>,
-    mut terrain_query: Query<(Entity, &NodeAtlas), With<Terrain>>,
+    terrain_query: Query<&NodeAtlas, With<Terrain>>,
 ) {
-    for (terrain, node_atlas) in terrain_query.iter_mut() {
-        for view in view_query.iter() {
-            let quadtree = quadtrees.get_mut(&(terrain, view)).unwrap();
+    let mut adjustments = Vec::new();
 
-            quadtree.adjust(node_atlas);
+    for (&(terrain, _), quadtree) in &mut quadtrees.0 {
+        quadtree.adjusted = false;
+
+        if let Ok(node_atlas) = terrain_query.get(terrain) {
+            if quadtree.traversed || quadtree.atlas_generation != node_atlas.generation {
+                adjustments.push((quadtree, node_atlas));
+            }
         }
     }
+
+    adjustments.par_splat_map_mut(ComputeTaskPool::get(), None, |adjustments| {
+        for (quadtree, node_atlas) in adjustments {
+            quadtree.adjust(node_atlas);
+            quadtree.atlas_generation = node_atlas.generation;
+            quadtree.adjusted = true;
+        }
+    });
 }
 
-pub(crate) fn update_height_under_viewer(
-    images: Res<Assets<Image>>,
+/// Stitches the quadtrees of neighbouring [`TerrainTile`]s, so that they select the same lod
+/// for the nodes along their shared edges.
+///
+/// Once any quadtree of the tiles observed by a view has been adjusted, all quadtrees of that
+/// view are adjusted again, because the previous stitches are no longer valid. The stitches are
+/// then repeated, until the lods of all shared edges (including the corners of the tiles) agree.
+pub(crate) fn stitch_quadtrees(
     mut quadtrees: ResMut<TerrainViewComponents<Quadtree>>,
-    mut terrain_view_configs: ResMut<TerrainViewComponents<TerrainViewConfig>>,
-    view_query: Query<(Entity, &GlobalTransform), With<TerrainView>>,
-    mut terrain_query: Query<(Entity, &NodeAtlas), With<Terrain>>,
+    terrain_query: Query<(Entity, &TerrainTile, &NodeAtlas), With<Terrain>>,
 ) {
-    for (terrain, node_atlas) in terrain_query.iter_mut() {
-        for (view, view_transform) in view_query.iter() {
+    let tiles = terrain_query
+        .iter()
+        .map(|(terrain, &TerrainTile(offset), _)| (offset, terrain))
+        .collect::<HashMap<_, _>>();
+
+    let views = quadtrees
+        .0
+        .iter()
+        .filter(|(&(terrain, _), quadtree)| quadtree.adjusted && terrain_query.contains(terrain))
+        .map(|(&(_, view), _)| view)
+        .collect::<HashSet<_>>();
+
+    for view in views {
+        let mut pairs = Vec::new();
+
+        for (terrain, &TerrainTile(offset), node_atlas) in &terrain_query {
             if let Some(quadtree) = quadtrees.get_mut(&(terrain, view)) {
-                quadtree.height_under_viewer = height_under_viewer(
-                    quadtree,
+                if !quadtree.adjusted {
+                    quadtree.adjust(node_atlas);
+                    quadtree.adjusted = true;
+                }
+            }
+
+            for direction in [IVec2::X, IVec2::Y] {
+                if let Some(&neighbour) = tiles.get(&(offset + direction)) {
+                    pairs.push((terrain, neighbour, direction));
+                }
+            }
+        }
+
+        let mut changed = true;
+
+        while changed {
+            changed = false;
+
+            for &(terrain, neighbour, direction) in &pairs {
+                if !quadtrees.contains_key(&(terrain, view))
+                    || !quadtrees.contains_key(&(neighbour, view))
+                {
+                    continue;
+                }
+
+                let mut quadtree = quadtrees.remove(&(terrain, view)).unwrap();
+                let mut neighbour_quadtree = quadtrees.remove(&(neighbour, view)).unwrap();
+
+                let (_, _, node_atlas) = terrain_query.get(terrain).unwrap();
+                let (_, _, neighbour_atlas) = terrain_query.get(neighbour).unwrap();
+
+                changed |= quadtree.stitch(
                     node_atlas,
-                    &images,
-                    view_transform.translation().xz(),
+                    &mut neighbour_quadtree,
+                    neighbour_atlas,
+                    direction,
                 );
 
-                terrain_view_configs
-                    .get_mut(&(terrain, view))
-                    .unwrap()
-                    .height_under_viewer = quadtree.height_under_viewer;
+                quadtrees.insert((terrain, view), quadtree);
+                quadtrees.insert((neighbour, view), neighbour_quadtree);
             }
         }
     }
 }
 
-fn height_under_viewer(
-    quadtree: &Quadtree,
-    node_atlas: &NodeAtlas,
-    images: &Assets<Image>,
-    viewer_position: Vec2,
-) -> f32 {
-    let coordinate =
-        (viewer_position / quadtree.leaf_node_size as f32).as_uvec2() % quadtree.node_count;
+/// Updates the height of the terrain under each viewer.
+///
+/// Neighbouring [`TerrainTile`]s share the height of the tile under the viewer, so that their
+/// quadtrees measure the same distances along the shared edges.
+pub(crate) fn update_height_under_viewer(
+    images: Res<Assets<Image>>,
+    mut quadtrees: ResMut<TerrainViewComponents<Quadtree>>,
+    mut terrain_view_configs: ResMut<TerrainViewComponents<TerrainViewConfig>>,
+    view_query: Query<&GlobalTransform, With<TerrainView>>,
+    terrain_query: Query<(&NodeAtlas, &GlobalTransform, Option<&TerrainTile>), With<Terrain>>,
+) {
+    let mut tile_heights = HashMap::new();
 
-    let node = &quadtree.data[[0, coordinate.y as usize, coordinate.x as usize]];
-    let atlas_coords = (viewer_position / quadtree.leaf_node_size as f32) % 1.0;
+    for (&(terrain, view), quadtree) in &mut quadtrees.0 {
+        if let (Ok((node_atlas, terrain_transform, tile)), Ok(view_transform)) =
+            (terrain_query.get(terrain), view_query.get(view))
+        {
+            let view_position = world_to_terrain(terrain_transform, view_transform.translation());
 
-    if node.atlas_index == INVALID_ATLAS_INDEX {
-        return 0.0;
-    }
+            if let Some(height) = node_atlas.height_at(&images, view_position.xz()) {
+                quadtree.height_under_viewer = height;
 
-    if let Some(node) = node_atlas.data[node.atlas_index as usize]
-        ._attachments
-        .get(&0)
-    {
-        if let Some(image) = images.get(node) {
-            let position = (image.size() * atlas_coords).as_uvec2();
-            let index = 2 * (position.x + position.y * image.size().x as u32) as usize;
-            let height = ((image.data[index + 1] as u16) << 8) + image.data[index] as u16;
-            let height = height as f32 / u16::MAX as f32 * quadtree.height;
-
-            return height;
+                if tile.is_some() {
+                    tile_heights.insert(view, height);
+                }
+            }
         }
     }
 
-    quadtree.height_under_viewer
+    for (&(terrain, view), quadtree) in &mut quadtrees.0 {
+        if let Ok((_, _, Some(_))) = terrain_query.get(terrain) {
+            if let Some(&height) = tile_heights.get(&view) {
+                quadtree.height_under_viewer = height;
+            }
+        }
+
+        if let Some(view_config) = terrain_view_configs.get_mut(&(terrain, view)) {
+            view_config.height_under_viewer = quadtree.height_under_viewer;
+        }
+    }
 }
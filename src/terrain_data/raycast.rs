@@ -0,0 +1,241 @@
+//! Provides raycasts against the currently loaded terrain data.
+//!
+//! The loaded nodes of the [`NodeAtlas`] are traversed hierarchically, starting at the
+//! coarsest lod. Their height bounds are used to skip all nodes, that are missed by the ray.
+//! Inside the best loaded node, the ray is then marched along the heightfield
+//! and the intersection is refined using a binary search.
+//! Where children are absent or not yet loaded, the heightfield of their loaded parent is used.
+//! Line of sight queries reuse the same traversal, but stop at the first intersection
+//! between both points.
+
+use crate::terrain_data::{
+    calc_node_id,
+    node_atlas::{LoadingState, NodeAtlas},
+    NodeCoordinate, NodeId, HEIGHT_ATTACHMENT,
+};
+use bevy::{math::Vec3Swizzles, prelude::*};
+
+/// The amount of binary search steps used to refine an intersection.
+const REFINE_STEPS: u32 = 8;
+
+/// The intersection of a ray with a terrain.
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainHit {
+    /// The position of the intersection.
+    pub position: Vec3,
+    /// The normal of the terrain at the intersection.
+    pub normal: Vec3,
+    /// The distance along the ray, measured in multiples of the ray direction.
+    pub distance: f32,
+}
+
+/// Intersects the ray with the axis aligned bounding box and returns the entry and exit distance.
+fn intersect_aabb(ray: &Ray, min: Vec3, max: Vec3) -> Option<(f32, f32)> {
+    let inverse_direction = ray.direction.recip();
+    let t0 = (min - ray.origin) * inverse_direction;
+    let t1 = (max - ray.origin) * inverse_direction;
+
+    let near = t0.min(t1).max_element().max(0.0);
+    let far = t0.max(t1).min_element();
+
+    (near <= far).then_some((near, far))
+}
+
+impl NodeAtlas {
+    /// Returns whether the node is present and finished loading.
+    fn is_loaded(&self, node_id: NodeId) -> bool {
+        self.nodes
+            .get(&node_id)
+            .map_or(false, |node| node.state == LoadingState::Loaded)
+    }
+
+    /// Casts the ray (in the local space of the terrain) against the currently loaded nodes.
+    ///
+    /// Returns the closest intersection or `None` if the ray misses all loaded nodes.
+    pub fn raycast(&self, images: &Assets<Image>, ray: Ray) -> Option<TerrainHit> {
+        let top_lod = self.lod_count - 1;
+
+        let distance = self
+            .nodes
+            .iter()
+            .filter(|&(&node_id, node)| {
+                NodeCoordinate::from(node_id).lod == top_lod && node.state == LoadingState::Loaded
+            })
+            .filter_map(|(&node_id, _)| self.raycast_node(images, &ray, node_id, f32::INFINITY))
+            .min_by(f32::total_cmp)?;
+
+        let position = ray.origin + ray.direction * distance;
+        let normal = self.normal_at(images, position.xz()).unwrap_or(Vec3::Y);
+
+        Some(TerrainHit {
+            position,
+            normal,
+            distance,
+        })
+    }
+
+    /// Checks whether the segment between both points (in the local space of the terrain)
+    /// is not blocked by any of the currently loaded nodes.
+    ///
+    /// The height bounds of the nodes reject most segments passing above the terrain,
+    /// without marching any heightfield. Regions without loaded nodes never block the segment.
+    pub fn has_line_of_sight(&self, images: &Assets<Image>, a: Vec3, b: Vec3) -> bool {
+        if a == b {
+            return true;
+        }
+
+        let top_lod = self.lod_count - 1;
+        let ray = Ray {
+            origin: a,
+            direction: b - a,
+        };
+
+        !self
+            .nodes
+            .iter()
+            .filter(|&(&node_id, node)| {
+                NodeCoordinate::from(node_id).lod == top_lod && node.state == LoadingState::Loaded
+            })
+            .any(|(&node_id, _)| self.raycast_node(images, &ray, node_id, 1.0).is_some())
+    }
+
+    /// Intersects the ray with a loaded node, by descending into its loaded children
+    /// and by marching the heightfield of the node itself inside the footprints of the others.
+    ///
+    /// Only intersections up to the maximum distance along the ray are considered.
+    fn raycast_node(
+        &self,
+        images: &Assets<Image>,
+        ray: &Ray,
+        node_id: NodeId,
+        max_distance: f32,
+    ) -> Option<f32> {
+        let NodeCoordinate { lod, x, y } = node_id.into();
+        let node = &self.nodes[&node_id];
+        let data = &self.data[node.atlas_index as usize];
+
+        let node_size = (self.leaf_node_size << lod) as f32;
+        let min = Vec3::new(
+            x as f32 * node_size,
+            data.height_bounds().x,
+            y as f32 * node_size,
+        );
+        let max = Vec3::new(
+            (x + 1) as f32 * node_size,
+            data.height_bounds().y,
+            (y + 1) as f32 * node_size,
+        );
+
+        let (near, far) = intersect_aabb(ray, min, max)?;
+        let far = far.min(max_distance);
+
+        if near > far {
+            return None;
+        }
+
+        if lod == 0 {
+            return self.march_node(images, ray, node_id, near, far);
+        }
+
+        let child_size = 0.5 * node_size;
+
+        [(0, 0), (1, 0), (0, 1), (1, 1)]
+            .into_iter()
+            .filter_map(|(dx, dy)| {
+                let child_id = calc_node_id(lod - 1, 2 * x + dx, 2 * y + dy);
+
+                if self.is_loaded(child_id) {
+                    return self.raycast_node(images, ray, child_id, max_distance);
+                }
+
+                // absent or unloaded children fall back to the heights of this node
+                let child_min = min + Vec3::new(dx as f32, 0.0, dy as f32) * child_size;
+                let child_max =
+                    Vec3::new(child_min.x + child_size, max.y, child_min.z + child_size);
+
+                let (near, far) = intersect_aabb(ray, child_min, child_max)?;
+                let far = far.min(max_distance);
+
+                if near > far {
+                    return None;
+                }
+
+                self.march_node(images, ray, node_id, near, far)
+            })
+            .min_by(f32::total_cmp)
+    }
+
+    /// Marches the ray along the heightfield of the loaded node between both distances
+    /// and returns the refined distance of the first intersection.
+    fn march_node(
+        &self,
+        images: &Assets<Image>,
+        ray: &Ray,
+        node_id: NodeId,
+        near: f32,
+        far: f32,
+    ) -> Option<f32> {
+        let NodeCoordinate { lod, x, y } = node_id.into();
+        let node = &self.nodes[&node_id];
+        let data = &self.data[node.atlas_index as usize];
+
+        let node_size = (self.leaf_node_size << lod) as f32;
+        let origin = Vec2::new(x as f32, y as f32) * node_size;
+
+        let image = images.get(data.attachments.get(&HEIGHT_ATTACHMENT)?)?;
+        let attachment = &self.attachments[HEIGHT_ATTACHMENT];
+
+        // the snow raises the surface of the terrain
+        let snow = self.snow_attachment.and_then(|attachment_index| {
+            let image = images.get(data.attachments.get(&attachment_index)?)?;
+            Some((&self.attachments[attachment_index], image))
+        });
+
+        // the height of the ray above the terrain at the distance
+        let height_above = |t: f32| {
+            let position = ray.origin + ray.direction * t;
+            let coords = ((position.xz() - origin) / node_size).clamp(Vec2::ZERO, Vec2::ONE);
+            let snow_depth = snow.map_or(0.0, |(snow_attachment, snow_image)| {
+                snow_attachment.sample(snow_image, coords, 0)
+            });
+
+            position.y - (attachment.sample(image, coords, 0) + snow_depth) * self.height
+        };
+
+        // advance the ray by about half a pixel of the node per step
+        let pixel_size = node_size / attachment.center_size as f32;
+        let step_count = ((far - near) * ray.direction.xz().length() / (0.5 * pixel_size))
+            .ceil()
+            .max(1.0) as u32;
+
+        if height_above(near) <= 0.0 {
+            return Some(near);
+        }
+
+        let mut previous = near;
+
+        for step in 1..=step_count {
+            let current = near + (far - near) * step as f32 / step_count as f32;
+
+            if height_above(current) <= 0.0 {
+                let (mut above, mut below) = (previous, current);
+
+                for _ in 0..REFINE_STEPS {
+                    let middle = 0.5 * (above + below);
+
+                    if height_above(middle) > 0.0 {
+                        above = middle;
+                    } else {
+                        below = middle;
+                    }
+                }
+
+                return Some(below);
+            }
+
+            previous = current;
+        }
+
+        None
+    }
+}
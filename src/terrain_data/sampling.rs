@@ -0,0 +1,378 @@
+//! Provides CPU side access to the terrain data of the currently loaded nodes.
+//!
+//! The data is always looked up from the best currently loaded node of the [`NodeAtlas`],
+//! so that it matches the terrain rendered on the GPU as closely as possible.
+
+use crate::{
+    terrain::{world_to_terrain, Terrain},
+    terrain_data::{
+        calc_node_id,
+        node_atlas::{LoadingState, NodeAtlas},
+        raycast::TerrainHit,
+        statistics::{RegionStatistics, StatisticsRegion},
+        AtlasAttachment, AttachmentIndex, HEIGHT_ATTACHMENT,
+    },
+};
+use bevy::{ecs::system::SystemParam, math::Vec3Swizzles, prelude::*, render::render_resource::*};
+use itertools::iproduct;
+
+impl AtlasAttachment {
+    /// Whether the data of the attachment can be accessed on the CPU.
+    pub(crate) fn is_cpu_accessible(&self) -> bool {
+        self.pixel_layout().is_some()
+    }
+
+    /// Returns the size of a pixel in bytes and the count of channels of the attachment.
+    ///
+    /// Returns `None` if the format can not be accessed on the CPU (e.g. compressed formats).
+    pub(crate) fn pixel_layout(&self) -> Option<(usize, usize)> {
+        match self.format() {
+            TextureFormat::Rgba8UnormSrgb | TextureFormat::Rgba8Unorm => Some((1, 4)),
+            TextureFormat::R16Unorm => Some((2, 1)),
+            TextureFormat::Rg16Unorm => Some((2, 2)),
+            TextureFormat::R32Float => Some((4, 1)),
+            _ => None,
+        }
+    }
+
+    /// Loads the normalized value of the channel of the texel at the pixel position.
+    ///
+    /// Attachments, which can not be accessed on the CPU, load zero.
+    pub(crate) fn load(&self, image: &Image, x: u32, y: u32, channel: usize) -> f32 {
+        let Some((pixel_size, channel_count)) = self.pixel_layout() else {
+            return 0.0;
+        };
+        let width = image.texture_descriptor.size.width as usize;
+        let index = pixel_size * (channel_count * (y as usize * width + x as usize) + channel);
+
+        match pixel_size {
+            1 => image.data[index] as f32 / u8::MAX as f32,
+            2 => {
+                let value = u16::from_le_bytes([image.data[index], image.data[index + 1]]);
+                value as f32 / u16::MAX as f32
+            }
+            4 => f32::from_le_bytes(image.data[index..index + 4].try_into().unwrap()),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Stores the normalized value into the channel of the texel at the pixel position.
+    ///
+    /// Attachments, which can not be accessed on the CPU, are left unchanged.
+    pub(crate) fn store(&self, image: &mut Image, x: u32, y: u32, channel: usize, value: f32) {
+        let Some((pixel_size, channel_count)) = self.pixel_layout() else {
+            return;
+        };
+        let width = image.texture_descriptor.size.width as usize;
+        let index = pixel_size * (channel_count * (y as usize * width + x as usize) + channel);
+        let value = value.clamp(0.0, 1.0);
+
+        match pixel_size {
+            1 => image.data[index] = (value * u8::MAX as f32).round() as u8,
+            2 => {
+                let value = (value * u16::MAX as f32).round() as u16;
+                image.data[index..index + 2].copy_from_slice(&value.to_le_bytes());
+            }
+            4 => image.data[index..index + 4].copy_from_slice(&value.to_le_bytes()),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Determines the minimum and maximum normalized value of the channel of the attachment.
+    ///
+    /// The border is included, which makes these bounds conservative.
+    pub(crate) fn bounds(&self, image: &Image, channel: usize) -> Vec2 {
+        iproduct!(0..self.texture_size, 0..self.texture_size).fold(
+            Vec2::new(f32::MAX, f32::MIN),
+            |bounds, (x, y)| {
+                let value = self.load(image, x, y, channel);
+                Vec2::new(bounds.x.min(value), bounds.y.max(value))
+            },
+        )
+    }
+
+    /// Samples the channel of the attachment bilinearly at the coordinates inside the node.
+    ///
+    /// The coordinates range from zero to one and cover the center of the node,
+    /// the border is only used for interpolating.
+    pub(crate) fn sample(&self, image: &Image, coords: Vec2, channel: usize) -> f32 {
+        let position = coords * self.center_size as f32 + self.border_size as f32 - 0.5;
+        let base = position.floor();
+        let fract = position - base;
+
+        let max = self.texture_size as i32 - 1;
+        let load = |x: i32, y: i32| {
+            self.load(
+                image,
+                x.clamp(0, max) as u32,
+                y.clamp(0, max) as u32,
+                channel,
+            )
+        };
+
+        let (x, y) = (base.x as i32, base.y as i32);
+        let top = load(x, y) * (1.0 - fract.x) + load(x + 1, y) * fract.x;
+        let bottom = load(x, y + 1) * (1.0 - fract.x) + load(x + 1, y + 1) * fract.x;
+
+        top * (1.0 - fract.y) + bottom * fract.y
+    }
+}
+
+impl NodeAtlas {
+    /// Looks up the best loaded node containing the position (in the local space of the terrain).
+    ///
+    /// Returns the lod of the node, the handle of its attachment and the
+    /// coordinates of the position inside the node.
+    pub(crate) fn lookup_attachment(
+        &self,
+        attachment_index: AttachmentIndex,
+        position: Vec2,
+    ) -> Option<(u32, &Handle<Image>, Vec2)> {
+        self.lookup_attachment_from_lod(attachment_index, position, 0)
+    }
+
+    /// Looks up the best loaded node containing the position, whose lod is at least the minimum lod.
+    ///
+    /// Coarser lods answer queries over large regions with fewer samples.
+    pub(crate) fn lookup_attachment_from_lod(
+        &self,
+        attachment_index: AttachmentIndex,
+        position: Vec2,
+        min_lod: u32,
+    ) -> Option<(u32, &Handle<Image>, Vec2)> {
+        if position.x < 0.0 || position.y < 0.0 {
+            return None;
+        }
+
+        for lod in min_lod..self.lod_count {
+            let node_position = position / (self.leaf_node_size << lod) as f32;
+            let coordinate = node_position.as_uvec2();
+            let node_id = calc_node_id(lod, coordinate.x, coordinate.y);
+
+            if let Some(node) = self.nodes.get(&node_id) {
+                if node.state != LoadingState::Loaded {
+                    continue;
+                }
+
+                if let Some(handle) = self.data[node.atlas_index as usize]
+                    .attachments
+                    .get(&attachment_index)
+                {
+                    return Some((lod, handle, node_position - coordinate.as_vec2()));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Samples the height and the lod of the best loaded node at the position.
+    fn sample_height(&self, images: &Assets<Image>, position: Vec2) -> Option<(f32, u32)> {
+        let (lod, handle, coords) = self.lookup_attachment(HEIGHT_ATTACHMENT, position)?;
+        let image = images.get(handle)?;
+        let attachment = &self.attachments[HEIGHT_ATTACHMENT];
+
+        Some((attachment.sample(image, coords, 0) * self.height, lod))
+    }
+
+    /// Samples the height of the terrain at the position (in the local space of the terrain).
+    ///
+    /// The height is interpolated bilinearly using the best currently loaded node.
+    /// Returns `None` if no node containing the position is loaded.
+    pub fn height_at(&self, images: &Assets<Image>, position: Vec2) -> Option<f32> {
+        self.sample_height(images, position)
+            .map(|(height, _)| height)
+    }
+
+    /// Samples the height of the surface of the terrain at the position
+    /// (in the local space of the terrain), which includes the accumulated snow.
+    ///
+    /// Returns `None` if no node containing the position is loaded.
+    pub fn surface_height_at(&self, images: &Assets<Image>, position: Vec2) -> Option<f32> {
+        let height = self.height_at(images, position)?;
+
+        let snow_depth = self.snow_attachment.and_then(|attachment_index| {
+            let (_, handle, coords) = self.lookup_attachment(attachment_index, position)?;
+            let attachment = &self.attachments[attachment_index];
+
+            Some(attachment.sample(images.get(handle)?, coords, 0) * self.height)
+        });
+
+        Some(height + snow_depth.unwrap_or(0.0))
+    }
+
+    /// Calculates the normal of the terrain at the position (in the local space of the terrain).
+    ///
+    /// The normal is approximated using the central differences of the heights of
+    /// the best currently loaded node.
+    /// Returns `None` if no node containing the position is loaded.
+    pub fn normal_at(&self, images: &Assets<Image>, position: Vec2) -> Option<Vec3> {
+        let (height, lod) = self.sample_height(images, position)?;
+        let attachment = &self.attachments[HEIGHT_ATTACHMENT];
+
+        // the distance between two pixels of the node
+        let offset = (self.leaf_node_size << lod) as f32 / attachment.center_size as f32;
+        let height_at = |x: f32, y: f32| {
+            self.height_at(images, position + Vec2::new(x, y))
+                .unwrap_or(height)
+        };
+
+        let left = height_at(-offset, 0.0);
+        let right = height_at(offset, 0.0);
+        let up = height_at(0.0, -offset);
+        let down = height_at(0.0, offset);
+
+        Some(Vec3::new(left - right, 2.0 * offset, up - down).normalize())
+    }
+}
+
+/// A system parameter, used to sample the data of all terrains in world space.
+///
+/// The horizontal positions are converted into the local space of the terrain,
+/// which assumes that the terrain is only rotated around its vertical axis.
+#[derive(SystemParam)]
+pub struct TerrainSampler<'w, 's> {
+    images: Res<'w, Assets<Image>>,
+    terrain_query:
+        Query<'w, 's, (Entity, &'static NodeAtlas, &'static GlobalTransform), With<Terrain>>,
+}
+
+impl<'w, 's> TerrainSampler<'w, 's> {
+    /// Samples the world space height of the terrain at the horizontal world position.
+    ///
+    /// This is the height of its surface, which includes the accumulated snow.
+    /// Returns `None` if the entity is not a terrain or no node containing the position is loaded.
+    pub fn height_at(&self, terrain: Entity, position: Vec2) -> Option<f32> {
+        let (_, node_atlas, transform) = self.terrain_query.get(terrain).ok()?;
+        let local_position = world_to_terrain(transform, Vec3::new(position.x, 0.0, position.y));
+        let height = node_atlas.surface_height_at(&self.images, local_position.xz())?;
+
+        Some(
+            transform
+                .transform_point(Vec3::new(local_position.x, height, local_position.z))
+                .y,
+        )
+    }
+
+    /// Samples the height of the terrain in its local space at the horizontal world position.
+    ///
+    /// These heights range from zero to the height of the terrain and match the heights
+    /// used by [`TerrainEdit`](crate::edit::TerrainEdit)s, thus they exclude the snow.
+    pub fn local_height_at(&self, terrain: Entity, position: Vec2) -> Option<f32> {
+        let (_, node_atlas, transform) = self.terrain_query.get(terrain).ok()?;
+        let local_position = world_to_terrain(transform, Vec3::new(position.x, 0.0, position.y));
+
+        node_atlas.height_at(&self.images, local_position.xz())
+    }
+
+    /// Calculates the world space normal of the terrain at the horizontal world position.
+    ///
+    /// Returns `None` if the entity is not a terrain or no node containing the position is loaded.
+    pub fn normal_at(&self, terrain: Entity, position: Vec2) -> Option<Vec3> {
+        let (_, node_atlas, transform) = self.terrain_query.get(terrain).ok()?;
+        let local_position = world_to_terrain(transform, Vec3::new(position.x, 0.0, position.y));
+        let normal = node_atlas.normal_at(&self.images, local_position.xz())?;
+
+        Some(
+            transform
+                .compute_matrix()
+                .inverse()
+                .transpose()
+                .transform_vector3(normal)
+                .normalize(),
+        )
+    }
+
+    /// Casts the world space ray against the terrain.
+    ///
+    /// Returns the closest intersection in world space or `None` if the entity is not a terrain
+    /// or the ray misses all loaded nodes.
+    pub fn raycast_terrain(&self, terrain: Entity, ray: Ray) -> Option<TerrainHit> {
+        let (_, node_atlas, transform) = self.terrain_query.get(terrain).ok()?;
+        let world_to_local = transform.compute_matrix().inverse();
+
+        // the direction is not normalized, so that the distance is preserved in world space
+        let local_ray = Ray {
+            origin: world_to_local.transform_point3(ray.origin),
+            direction: world_to_local.transform_vector3(ray.direction),
+        };
+
+        let hit = node_atlas.raycast(&self.images, local_ray)?;
+
+        Some(TerrainHit {
+            position: transform.transform_point(hit.position),
+            normal: world_to_local
+                .transpose()
+                .transform_vector3(hit.normal)
+                .normalize(),
+            distance: hit.distance,
+        })
+    }
+
+    /// Aggregates the height and slope statistics of the horizontal world space region.
+    ///
+    /// The spacing of the samples and the heights of the statistics are measured in the local
+    /// space of the terrain, like the heights of [`Self::local_height_at`].
+    /// Returns `None` if the entity is not a terrain or no sample hits a loaded node.
+    pub fn region_statistics(
+        &self,
+        terrain: Entity,
+        region: &StatisticsRegion,
+        spacing: f32,
+    ) -> Option<RegionStatistics> {
+        let (_, node_atlas, transform) = self.terrain_query.get(terrain).ok()?;
+        let world_to_local = transform.compute_matrix().inverse();
+
+        let corners = match region {
+            StatisticsRegion::Rect(rect) => vec![
+                rect.min,
+                Vec2::new(rect.max.x, rect.min.y),
+                rect.max,
+                Vec2::new(rect.min.x, rect.max.y),
+            ],
+            StatisticsRegion::Polygon(corners) => corners.clone(),
+        };
+
+        let local_region = StatisticsRegion::Polygon(
+            corners
+                .into_iter()
+                .map(|corner| {
+                    world_to_local
+                        .transform_point3(Vec3::new(corner.x, 0.0, corner.y))
+                        .xz()
+                })
+                .collect(),
+        );
+
+        node_atlas.region_statistics(&self.images, &local_region, spacing)
+    }
+
+    /// Checks whether the segment between both world space points is not blocked by the terrain,
+    /// e.g. for the visibility checks of AI agents.
+    ///
+    /// Returns `true` if the entity is not a terrain. Regions without loaded nodes never block
+    /// the segment.
+    pub fn has_line_of_sight(&self, terrain: Entity, a: Vec3, b: Vec3) -> bool {
+        let Ok((_, node_atlas, transform)) = self.terrain_query.get(terrain) else {
+            return true;
+        };
+
+        let world_to_local = transform.compute_matrix().inverse();
+
+        node_atlas.has_line_of_sight(
+            &self.images,
+            world_to_local.transform_point3(a),
+            world_to_local.transform_point3(b),
+        )
+    }
+
+    /// Casts the world space ray against all terrains.
+    ///
+    /// Returns the closest intersection and the corresponding terrain entity.
+    pub fn raycast(&self, ray: Ray) -> Option<(Entity, TerrainHit)> {
+        self.terrain_query
+            .iter()
+            .filter_map(|(terrain, _, _)| Some((terrain, self.raycast_terrain(terrain, ray)?)))
+            .min_by(|(_, a), (_, b)| a.distance.total_cmp(&b.distance))
+    }
+}
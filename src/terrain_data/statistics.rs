@@ -0,0 +1,215 @@
+//! Aggregates statistics of the heights and slopes of regions of the currently loaded terrain.
+//!
+//! The region is sampled in a regular grid, whose spacing selects the lod of the sampled nodes,
+//! so that coarse answers over large regions only touch the coarse nodes, while small regions
+//! are answered by the best loaded nodes. This is useful e.g. for validating the placement
+//! of buildings or for finding flat areas for procedurally generated cities.
+
+use crate::terrain_data::{node_atlas::NodeAtlas, HEIGHT_ATTACHMENT};
+use bevy::prelude::*;
+use itertools::iproduct;
+use std::f32::consts::FRAC_PI_2;
+
+/// The number of bins of the slope histogram, each covering five degrees.
+pub const SLOPE_BINS: usize = 18;
+
+/// The maximum number of samples along each axis of a region.
+const MAX_SAMPLES: u32 = 1024;
+
+/// A horizontal region of the terrain (in the local space of the terrain).
+#[derive(Clone, Debug)]
+pub enum StatisticsRegion {
+    /// An axis aligned rectangle.
+    Rect(Rect),
+    /// A simple polygon, given by its corners.
+    Polygon(Vec<Vec2>),
+}
+
+impl StatisticsRegion {
+    /// The bounding rectangle of the region.
+    fn bounds(&self) -> Rect {
+        match self {
+            StatisticsRegion::Rect(rect) => *rect,
+            StatisticsRegion::Polygon(corners) => Rect {
+                min: corners
+                    .iter()
+                    .copied()
+                    .reduce(Vec2::min)
+                    .unwrap_or_default(),
+                max: corners
+                    .iter()
+                    .copied()
+                    .reduce(Vec2::max)
+                    .unwrap_or_default(),
+            },
+        }
+    }
+
+    /// Checks whether the position lies inside of the region.
+    fn contains(&self, position: Vec2) -> bool {
+        match self {
+            StatisticsRegion::Rect(rect) => rect.contains(position),
+            StatisticsRegion::Polygon(corners) => {
+                // counts the crossings of a ray along the x axis with the edges of the polygon
+                let edges = corners.iter().zip(corners.iter().cycle().skip(1));
+
+                edges
+                    .filter(|&(a, b)| {
+                        (a.y > position.y) != (b.y > position.y)
+                            && position.x < a.x + (position.y - a.y) / (b.y - a.y) * (b.x - a.x)
+                    })
+                    .count()
+                    % 2
+                    == 1
+            }
+        }
+    }
+}
+
+/// The aggregated statistics of a region of the terrain.
+#[derive(Clone, Debug)]
+pub struct RegionStatistics {
+    /// The number of samples inside the region, which hit loaded nodes.
+    pub sample_count: u32,
+    /// The fraction of the samples inside the region, which hit loaded nodes.
+    pub coverage: f32,
+    /// The minimum height (in the local space of the terrain).
+    pub min_height: f32,
+    /// The maximum height (in the local space of the terrain).
+    pub max_height: f32,
+    /// The mean height (in the local space of the terrain).
+    pub mean_height: f32,
+    /// The mean slope angle in radians.
+    pub mean_slope: f32,
+    /// The maximum slope angle in radians.
+    pub max_slope: f32,
+    /// The number of samples per slope range, where each bin covers five degrees.
+    pub slope_histogram: [u32; SLOPE_BINS],
+}
+
+impl RegionStatistics {
+    /// Approximates the fraction of the samples, which are flatter than the slope angle,
+    /// using the slope histogram.
+    pub fn fraction_flatter_than(&self, slope: f32) -> f32 {
+        let bin = slope / FRAC_PI_2 * SLOPE_BINS as f32;
+        let full_bins = (bin.floor() as usize).min(SLOPE_BINS);
+
+        let mut count = self.slope_histogram[..full_bins].iter().sum::<u32>() as f32;
+
+        if full_bins < SLOPE_BINS {
+            count += self.slope_histogram[full_bins] as f32 * bin.fract();
+        }
+
+        count / self.sample_count.max(1) as f32
+    }
+}
+
+impl NodeAtlas {
+    /// Samples the height of the best loaded node, whose lod is at least the minimum lod.
+    fn sample_height_from_lod(
+        &self,
+        images: &Assets<Image>,
+        position: Vec2,
+        min_lod: u32,
+    ) -> Option<f32> {
+        let (_, handle, coords) =
+            self.lookup_attachment_from_lod(HEIGHT_ATTACHMENT, position, min_lod)?;
+        let image = images.get(handle)?;
+
+        Some(self.attachments[HEIGHT_ATTACHMENT].sample(image, coords, 0) * self.height)
+    }
+
+    /// Aggregates the height and slope statistics of the region (in the local space of the terrain).
+    ///
+    /// The region is sampled in a grid with the spacing, which selects the finest lod,
+    /// whose pixels are at most as large as the spacing.
+    /// Returns `None` if no sample inside the region hits a loaded node.
+    pub fn region_statistics(
+        &self,
+        images: &Assets<Image>,
+        region: &StatisticsRegion,
+        spacing: f32,
+    ) -> Option<RegionStatistics> {
+        let bounds = region.bounds();
+
+        if bounds.is_empty() {
+            return None;
+        }
+
+        let samples = (bounds.size() / spacing.max(f32::EPSILON))
+            .ceil()
+            .as_uvec2()
+            .clamp(UVec2::ONE, UVec2::splat(MAX_SAMPLES));
+        let step = bounds.size() / samples.as_vec2();
+
+        let attachment = &self.attachments[HEIGHT_ATTACHMENT];
+        let leaf_pixel_size = self.leaf_node_size as f32 / attachment.center_size as f32;
+        let min_lod = (step.min_element() / leaf_pixel_size)
+            .log2()
+            .floor()
+            .clamp(0.0, (self.lod_count - 1) as f32) as u32;
+
+        // the slopes are approximated with the spacing of the samples
+        let offset = step
+            .min_element()
+            .max(leaf_pixel_size * (1 << min_lod) as f32);
+        let height_at = |position: Vec2| self.sample_height_from_lod(images, position, min_lod);
+
+        let mut total_samples = 0;
+        let mut statistics = RegionStatistics {
+            sample_count: 0,
+            coverage: 0.0,
+            min_height: f32::MAX,
+            max_height: f32::MIN,
+            mean_height: 0.0,
+            mean_slope: 0.0,
+            max_slope: 0.0,
+            slope_histogram: [0; SLOPE_BINS],
+        };
+
+        for (y, x) in iproduct!(0..samples.y, 0..samples.x) {
+            let position = bounds.min + (Vec2::new(x as f32, y as f32) + 0.5) * step;
+
+            if !region.contains(position) {
+                continue;
+            }
+
+            total_samples += 1;
+
+            let Some(height) = height_at(position) else {
+                continue;
+            };
+
+            let sample = |dx: f32, dy: f32| {
+                height_at(position + Vec2::new(dx, dy) * offset).unwrap_or(height)
+            };
+
+            let gradient = Vec2::new(
+                sample(1.0, 0.0) - sample(-1.0, 0.0),
+                sample(0.0, 1.0) - sample(0.0, -1.0),
+            ) / (2.0 * offset);
+            let slope = gradient.length().atan();
+
+            let bin = ((slope / FRAC_PI_2 * SLOPE_BINS as f32) as usize).min(SLOPE_BINS - 1);
+
+            statistics.sample_count += 1;
+            statistics.min_height = statistics.min_height.min(height);
+            statistics.max_height = statistics.max_height.max(height);
+            statistics.mean_height += height;
+            statistics.mean_slope += slope;
+            statistics.max_slope = statistics.max_slope.max(slope);
+            statistics.slope_histogram[bin] += 1;
+        }
+
+        if statistics.sample_count == 0 {
+            return None;
+        }
+
+        let count = statistics.sample_count as f32;
+        statistics.coverage = count / total_samples as f32;
+        statistics.mean_height /= count;
+        statistics.mean_slope /= count;
+
+        Some(statistics)
+    }
+}
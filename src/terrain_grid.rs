@@ -0,0 +1,151 @@
+//! A world streaming layer, which spawns and despawns terrains in a grid around the viewers.
+//!
+//! Each cell of the grid is covered by its own terrain, which is created by a user provided
+//! callback. This allows wiring up each terrain with procedural or disk node sources,
+//! so that the world appears endless, without managing the lifecycles of the terrains manually.
+
+use crate::{
+    minimap::TerrainCapture,
+    terrain::{TerrainConfig, TerrainTile},
+    terrain_data::quadtree::Quadtree,
+    terrain_view::{TerrainView, TerrainViewComponents, TerrainViewConfig},
+};
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use itertools::iproduct;
+
+/// A callback, which spawns the terrain of a grid cell and returns its entity and config.
+///
+/// The transform of the terrain is set by the grid, according to the cell coordinate.
+pub type SpawnTerrainCell =
+    Box<dyn Fn(&mut Commands, IVec2) -> (Entity, TerrainConfig) + Send + Sync>;
+
+/// Streams terrains in a grid around all [`TerrainView`]s, except for the captures
+/// (see [`TerrainCapture`]), which only observe their own terrain.
+///
+/// Insert this resource to enable the streaming. Terrains are spawned for all cells inside the
+/// load radius of any view and despawned once they are further away than the
+/// load radius plus the unload margin from all views.
+#[derive(Resource)]
+pub struct TerrainGrid {
+    /// The size of a cell in world units, which has to match the (scaled) size of the terrains.
+    pub cell_size: f32,
+    /// The distance (measured in cells) around each view, inside which the terrains are spawned.
+    pub load_radius: u32,
+    /// The additional distance (measured in cells), which prevents the terrains at the edge of
+    /// the load radius from being spawned and despawned repeatedly.
+    pub unload_margin: u32,
+    /// The view config, the views are registered with for each terrain.
+    pub view_config: TerrainViewConfig,
+    spawn_cell: SpawnTerrainCell,
+    cells: HashMap<IVec2, (Entity, TerrainConfig)>,
+}
+
+impl TerrainGrid {
+    /// Creates a new terrain grid.
+    ///
+    /// * `cell_size` - The size of a cell in world units.
+    /// * `load_radius` - The distance (measured in cells) around each view, inside which the terrains are spawned.
+    /// * `view_config` - The view config, the views are registered with for each terrain.
+    /// * `spawn_cell` - The callback, which spawns the terrain of a cell.
+    pub fn new(
+        cell_size: f32,
+        load_radius: u32,
+        view_config: TerrainViewConfig,
+        spawn_cell: impl Fn(&mut Commands, IVec2) -> (Entity, TerrainConfig) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            cell_size,
+            load_radius,
+            unload_margin: 1,
+            view_config,
+            spawn_cell: Box::new(spawn_cell),
+            cells: default(),
+        }
+    }
+
+    /// Returns the coordinate of the cell containing the world position.
+    pub fn cell_at(&self, position: Vec3) -> IVec2 {
+        (position.xz() / self.cell_size).floor().as_ivec2()
+    }
+
+    /// Returns the terrain entity of the cell, if it is spawned.
+    pub fn terrain(&self, cell: IVec2) -> Option<Entity> {
+        self.cells.get(&cell).map(|&(terrain, _)| terrain)
+    }
+
+    /// Returns the cells within the radius around the position.
+    fn cells_around(&self, position: Vec3, radius: u32) -> impl Iterator<Item = IVec2> {
+        let center = self.cell_at(position);
+        let radius = radius as i32;
+
+        iproduct!(-radius..=radius, -radius..=radius).map(move |(x, y)| center + IVec2::new(x, y))
+    }
+}
+
+/// Spawns and despawns the terrains of the [`TerrainGrid`] around the views and registers
+/// all views with the spawned terrains.
+pub(crate) fn update_terrain_grid(
+    mut commands: Commands,
+    mut grid: ResMut<TerrainGrid>,
+    mut quadtrees: ResMut<TerrainViewComponents<Quadtree>>,
+    mut view_configs: ResMut<TerrainViewComponents<TerrainViewConfig>>,
+    view_query: Query<(Entity, &GlobalTransform), (With<TerrainView>, Without<TerrainCapture>)>,
+) {
+    let mut required_cells = HashSet::new();
+    let mut retained_cells = HashSet::new();
+
+    for (_, view_transform) in view_query.iter() {
+        let position = view_transform.translation();
+
+        required_cells.extend(grid.cells_around(position, grid.load_radius));
+        retained_cells.extend(grid.cells_around(position, grid.load_radius + grid.unload_margin));
+    }
+
+    // the views are released by the terrains, once they are despawned
+    grid.cells.retain(|cell, &mut (terrain, _)| {
+        let retained = retained_cells.contains(cell);
+
+        if !retained {
+            commands.entity(terrain).despawn_recursive();
+        }
+
+        retained
+    });
+
+    for cell in required_cells {
+        if grid.cells.contains_key(&cell) {
+            continue;
+        }
+
+        let (terrain, config) = (grid.spawn_cell)(&mut commands, cell);
+        let translation = Vec3::new(cell.x as f32, 0.0, cell.y as f32) * grid.cell_size;
+
+        commands
+            .entity(terrain)
+            .insert((Transform::from_translation(translation), TerrainTile(cell)));
+
+        grid.cells.insert(cell, (terrain, config));
+    }
+
+    // register all views with all terrains of the grid
+    for (&(terrain, ref config), (view, _)) in iproduct!(grid.cells.values(), view_query.iter()) {
+        if quadtrees.contains_key(&(terrain, view)) {
+            continue;
+        }
+
+        // each pair of terrain and view requires its own quadtree texture
+        let view_config = TerrainViewConfig {
+            quadtree_handle: TerrainViewConfig::default().quadtree_handle,
+            ..grid.view_config.clone()
+        };
+
+        quadtrees.insert(
+            (terrain, view),
+            Quadtree::from_configs(config, &view_config),
+        );
+        view_configs.insert((terrain, view), view_config);
+    }
+}
@@ -8,6 +8,10 @@ use bevy::{
 use std::str::FromStr;
 
 /// Resource that stores components that are associated to a terrain entity and a view entity.
+///
+/// Each registered (terrain, view) pair is considered a separate viewer of the terrain.
+/// A view only has to be registered with the terrains it should observe, which allows
+/// split-screen, minimap and shadow cameras to stream their own detail independently.
 #[derive(Clone, Resource)]
 pub struct TerrainViewComponents<C>(pub HashMap<(Entity, Entity), C>);
 
@@ -23,6 +27,30 @@ impl<C> TerrainViewComponents<C> {
     pub fn insert(&mut self, k: (Entity, Entity), v: C) {
         self.0.insert(k, v);
     }
+
+    pub fn remove(&mut self, k: &(Entity, Entity)) -> Option<C> {
+        self.0.remove(k)
+    }
+
+    pub fn contains_key(&self, k: &(Entity, Entity)) -> bool {
+        self.0.contains_key(k)
+    }
+
+    /// Iterates over all views registered for the terrain.
+    pub fn iter_terrain(&self, terrain: Entity) -> impl Iterator<Item = (Entity, &C)> {
+        self.0
+            .iter()
+            .filter(move |(&(t, _), _)| t == terrain)
+            .map(|(&(_, view), c)| (view, c))
+    }
+
+    /// Iterates mutably over all views registered for the terrain.
+    pub fn iter_terrain_mut(&mut self, terrain: Entity) -> impl Iterator<Item = (Entity, &mut C)> {
+        self.0
+            .iter_mut()
+            .filter(move |(&(t, _), _)| t == terrain)
+            .map(|(&(_, view), c)| (view, c))
+    }
 }
 
 impl<C> FromWorld for TerrainViewComponents<C> {
@@ -31,21 +59,85 @@ impl<C> FromWorld for TerrainViewComponents<C> {
     }
 }
 
+/// The maximum amount of lods, whose morph range can be configured individually.
+pub const MAX_MORPH_LODS: usize = 16;
+
+/// The metric, which selects the level of detail of the nodes around a view.
+#[derive(Clone, Copy, Debug, PartialEq, Reflect, FromReflect)]
+pub enum LodMetric {
+    /// The level of detail changes at the view and load distances (measured in node sizes).
+    Distance,
+    /// The level of detail is refined, while the texels of the next coarser level of detail
+    /// would exceed the pixel threshold on screen.
+    ///
+    /// This adapts to the field of view and the resolution of perspective cameras
+    /// and falls back to the distance metric for other views.
+    /// The load distance keeps its margin to the view distance.
+    ScreenSpaceError {
+        /// The maximum size (in pixels) of a texel on screen.
+        pixel_threshold: f32,
+    },
+    /// Requests all nodes of the lod and the coarser lods inside the node grid of the view,
+    /// regardless of their distance, while the finer lods are not loaded at all.
+    ///
+    /// This renders the area around the view with a uniform level of detail,
+    /// e.g. for top-down captures of the terrain (see [`crate::minimap`]).
+    Uniform {
+        /// The finest lod, which is loaded.
+        lod: u32,
+    },
+}
+
+/// Computes the size (in pixels) of one world unit at a distance of one world unit in front
+/// of the camera, which is used by the [`LodMetric::ScreenSpaceError`].
+pub(crate) fn projection_scale(
+    camera: Option<&Camera>,
+    projection: Option<&Projection>,
+) -> Option<f32> {
+    let viewport_size = camera?.physical_viewport_size()?;
+
+    match projection? {
+        Projection::Perspective(perspective) => {
+            Some(viewport_size.y as f32 / (2.0 * (perspective.fov / 2.0).tan()))
+        }
+        Projection::Orthographic(_) => None,
+    }
+}
+
 /// A marker component used to identify a terrain view entity.
-#[derive(Clone, Copy, Component, ExtractComponent)]
+#[derive(Clone, Copy, Default, Component, ExtractComponent, Reflect)]
+#[reflect(Component)]
 pub struct TerrainView;
 
 /// The configuration of a terrain view.
 ///
 /// A terrain view describes the quality settings the corresponding terrain will be rendered with.
-#[derive(Clone, Component)]
+#[derive(Clone, Component, Reflect)]
+#[reflect(Component)]
 pub struct TerrainViewConfig {
     /// A handle to the quadtree texture.
+    #[reflect(ignore)]
     pub quadtree_handle: Handle<Image>,
     /// The current height under the viewer.
+    #[reflect(ignore)]
     pub height_under_viewer: f32,
     /// The distance (measured in multiples of the node size) until which to request nodes to be loaded.
     pub load_distance: f32,
+    /// The additional distance (measured in multiples of the node size) beyond the load distance,
+    /// until which nodes are prefetched into the cache of the node atlas.
+    ///
+    /// Prefetching hides the loading latency, when the viewer moves towards these nodes.
+    /// Only the nodes covered by the `node_count` are considered.
+    pub prefetch_distance: f32,
+    /// The additional distance (measured in multiples of the node size) beyond the load distance,
+    /// which requested nodes have to exceed before they are released again.
+    ///
+    /// This prevents nodes from being requested and released repeatedly,
+    /// while the viewer hovers around the load distance.
+    pub load_hysteresis: f32,
+    /// The distance (measured in multiples of the leaf node size) the viewer has to move,
+    /// before the quadtree is traversed again.
+    pub traversal_threshold: f32,
     /// The count of nodes in x and y direction per quadtree layer.
     pub node_count: u32,
     /// The size of the tile buffer.
@@ -54,6 +146,30 @@ pub struct TerrainViewConfig {
     pub refinement_count: u32,
     /// The amount of steps the tiles will be further refined than there are new LOD layers.
     pub additional_refinement: u32,
+    /// Refines rough tiles further than smooth ones, based on the height variation of their
+    /// minmax data. Zero disables the adaptive refinement.
+    ///
+    /// The refinement distance of each tile is divided by one plus this factor times the ratio
+    /// of its height variation to its size. This adds detail to steep terrain close up,
+    /// without loading nodes of a higher level of detail.
+    /// As wgpu does not expose hardware tessellation, the tiles are subdivided by the
+    /// refinement compute passes instead.
+    pub adaptive_refinement: f32,
+    /// Culls the tiles, that are hidden behind closer geometry (e.g. valleys behind mountains),
+    /// using a hierarchical depth buffer of the previous frame.
+    ///
+    /// Only supported by camera views. As the depth buffer lags behind by one frame,
+    /// fast camera movements may cause tiles to appear one frame late.
+    pub occlusion_culling: bool,
+    /// Renders the terrain into the shadow cascades of the directional lights of this view.
+    ///
+    /// The shadow casters are refined separately from the view and only culled against
+    /// the cascades, so that terrain behind the camera or outside of its LOD selection
+    /// (e.g. distant mountains) still casts shadows.
+    pub shadow_casting: bool,
+    /// The factor by which the tiles rendered into the shadow cascades are coarser than the ones
+    /// of the view. One keeps the detail of the view.
+    pub shadow_lod_bias: f32,
     /// A factor that scales tiles smaller or larger.
     pub tile_scale: f32,
     /// The number of rows and columns of the tile grid.
@@ -62,8 +178,47 @@ pub struct TerrainViewConfig {
     pub view_distance: f32,
     /// The morph percentage of the mesh.
     pub morph_range: f32,
+    /// Overrides the morph range of the mesh for each lod, starting with the most detailed tiles.
+    ///
+    /// The lods without an entry use the `morph_range`. Wider ranges morph the vertices over a
+    /// longer distance, which hides the transitions of coarse lods in exchange for less detail.
+    /// At most [`MAX_MORPH_LODS`] ranges are considered.
+    pub morph_ranges: Vec<f32>,
     /// The blend percentage in the vertex and fragment shader.
     pub blend_range: f32,
+    /// The metric, which selects the level of detail.
+    pub lod_metric: LodMetric,
+}
+
+impl TerrainViewConfig {
+    /// Returns the view and load distance (measured in node sizes) according to the lod metric.
+    pub(crate) fn lod_distances(
+        &self,
+        leaf_node_size: u32,
+        projection_scale: Option<f32>,
+    ) -> (f32, f32) {
+        match (self.lod_metric, projection_scale) {
+            (LodMetric::ScreenSpaceError { pixel_threshold }, Some(projection_scale)) => {
+                // the texels of the parent nodes are twice as large as the ones of their children
+                let view_distance =
+                    2.0 * projection_scale / (pixel_threshold * leaf_node_size as f32);
+                let margin = (self.load_distance - self.view_distance).max(0.0);
+
+                (view_distance, view_distance + margin)
+            }
+            // all nodes of the node grid are loaded, and their data is rendered up to its edge
+            (LodMetric::Uniform { lod }, _) => ((self.node_count << lod) as f32, f32::INFINITY),
+            _ => (self.view_distance, self.load_distance),
+        }
+    }
+
+    /// Returns the finest lod, which is requested according to the lod metric.
+    pub(crate) fn min_lod(&self) -> u32 {
+        match self.lod_metric {
+            LodMetric::Uniform { lod } => lod,
+            _ => 0,
+        }
+    }
 }
 
 impl Default for TerrainViewConfig {
@@ -76,15 +231,24 @@ impl Default for TerrainViewConfig {
             .typed(), // Todo: fix this awful hack
             height_under_viewer: 0.0,
             load_distance: 5.0,
+            prefetch_distance: 0.0,
+            load_hysteresis: 0.25,
+            traversal_threshold: 0.05,
             node_count: 10,
             tile_count: 1000000,
             refinement_count: 20,
             additional_refinement: 0,
+            adaptive_refinement: 0.0,
+            occlusion_culling: false,
+            shadow_casting: true,
+            shadow_lod_bias: 4.0,
             tile_scale: 32.0,
             grid_size: 8,
             view_distance: 4.0,
             morph_range: 0.2,
+            morph_ranges: Vec::new(),
             blend_range: 0.2,
+            lod_metric: LodMetric::Distance,
         }
     }
 }
@@ -0,0 +1,421 @@
+//! Replaces the heightfield with voxel chunks inside volumetric regions, e.g. for overhangs,
+//! arches and tunnels.
+//!
+//! Each volumetric region spans a rectangle of the terrain (in its local space) between two heights.
+//! The solid parts of the regions are described by a [`DensityFunction`], which receives the
+//! height of the terrain at each voxel, so that it can carve into or build on top of the heightfield.
+//! Each terrain with a [`TerrainVolumes`] component spawns one chunk child per region and
+//! activated node of the configured lod, which is meshed in the [`AsyncComputeTaskPool`].
+//! The chunks are aligned to the voxel grid of their nodes and overlap the following chunks
+//! by one voxel, so that the faces between neighbouring chunks are meshed without seams.
+//! The chunks share the lifecycle of their nodes, so they are despawned again, once their
+//! node is deactivated, and rebuilt, once their node is edited.
+//!
+//! The heightfield itself is cut out of the volumetric regions by the hole attachment
+//! (see [`crate::holes`]), whose mask should cover the regions.
+//! Add the [`TerrainVolumePlugin`] to enable the volumetric regions.
+
+use crate::{
+    terrain_data::{
+        node_atlas::{NodeAtlas, NodeLifecycle},
+        NodeCoordinate, NodeId, HEIGHT_ATTACHMENT,
+    },
+    TerrainSystemSet,
+};
+use bevy::{
+    prelude::*,
+    render::{mesh::Indices, render_resource::PrimitiveTopology},
+    tasks::{futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::HashMap,
+};
+use itertools::iproduct;
+use std::sync::Arc;
+
+/// The density of the volume at a position (in the local space of the terrain),
+/// given the height of the terrain at that position.
+/// Positive densities are solid, negative ones are empty.
+///
+/// The density of the plain heightfield is `height - position.y`, which tunnels can be
+/// carved out of by taking the minimum with the negated distance to the tunnel.
+pub type DensityFunction = Arc<dyn Fn(Vec3, f32) -> f32 + Send + Sync>;
+
+/// A region of the terrain, which is rendered with voxel chunks instead of the heightfield.
+#[derive(Clone, Copy, Debug)]
+pub struct VolumetricRegion {
+    /// The region covered along the x and z axes (in the local space of the terrain).
+    pub rect: Rect,
+    /// The lowest height of the region.
+    pub min_height: f32,
+    /// The highest height of the region.
+    pub max_height: f32,
+}
+
+/// A voxel chunk of a node and a region.
+struct VolumeChunk {
+    /// The entity of the chunk.
+    entity: Entity,
+    /// The meshing task of the chunk, until it has finished.
+    task: Option<Task<Option<Mesh>>>,
+}
+
+/// Configures the volumetric regions of a terrain.
+#[derive(Component)]
+pub struct TerrainVolumes {
+    /// The lod of the nodes, for which voxel chunks are generated.
+    pub lod: u32,
+    /// The amount of voxels along the x and z axes of each node.
+    pub resolution: u32,
+    /// The material of the voxel chunks.
+    pub material: Handle<StandardMaterial>,
+    /// The density of the volumetric regions.
+    pub density: DensityFunction,
+    /// The volumetric regions.
+    pub regions: Vec<VolumetricRegion>,
+    /// The voxel chunks of the currently activated nodes, per node and region.
+    chunks: HashMap<(NodeId, usize), VolumeChunk>,
+}
+
+impl TerrainVolumes {
+    /// Creates a new volume config, which generates voxel chunks for the nodes of the lod.
+    pub fn new(lod: u32, material: Handle<StandardMaterial>, density: DensityFunction) -> Self {
+        Self {
+            lod,
+            resolution: 32,
+            material,
+            density,
+            regions: Vec::new(),
+            chunks: default(),
+        }
+    }
+
+    /// Adds the volumetric region.
+    pub fn with_region(mut self, region: VolumetricRegion) -> Self {
+        self.regions.push(region);
+        self
+    }
+
+    /// Starts meshing the chunk of the node and the region.
+    ///
+    /// Returns `None` if the node does not intersect the region or its height data
+    /// is not available on the CPU.
+    fn start_meshing(
+        &self,
+        node_atlas: &NodeAtlas,
+        images: &Assets<Image>,
+        node_id: NodeId,
+        region: &VolumetricRegion,
+    ) -> Option<(Vec3, Task<Option<Mesh>>)> {
+        let NodeCoordinate { lod, x, y } = node_id.into();
+        let node_size = (node_atlas.leaf_node_size << lod) as f32;
+        let node_rect = Rect::new(
+            x as f32 * node_size,
+            y as f32 * node_size,
+            (x + 1) as f32 * node_size,
+            (y + 1) as f32 * node_size,
+        );
+
+        let rect = node_rect.intersect(region.rect);
+
+        if rect.is_empty() || region.max_height <= region.min_height {
+            return None;
+        }
+
+        let node = node_atlas.nodes.get(&node_id)?;
+        let image = images.get(
+            node_atlas.data[node.atlas_index as usize]
+                .attachments
+                .get(&HEIGHT_ATTACHMENT)?,
+        )?;
+        let attachment = &node_atlas.attachments[HEIGHT_ATTACHMENT];
+
+        let voxel_size = node_size / self.resolution as f32;
+
+        // the voxels are counted from the corner of the node, so that neighbouring chunks
+        // share the positions of their samples
+        let first = ((rect.min - node_rect.min) / voxel_size).floor();
+        let last = ((rect.max - node_rect.min) / voxel_size).ceil();
+        let origin = Vec3::new(
+            node_rect.min.x + first.x * voxel_size,
+            region.min_height,
+            node_rect.min.y + first.y * voxel_size,
+        );
+
+        // the additional samples along the x and z axes overlap the following chunks
+        let cells = UVec3::new(
+            (last.x - first.x) as u32,
+            ((region.max_height - region.min_height) / voxel_size).ceil() as u32,
+            (last.y - first.y) as u32,
+        );
+        let overlap = UVec3::new(1, 0, 1);
+        let dimensions = cells + overlap + 1;
+
+        // the heights are sampled on the main thread, since the node atlas may change meanwhile
+        let heights = iproduct!(0..dimensions.z, 0..dimensions.x)
+            .map(|(z, x)| {
+                let position =
+                    Vec2::new(origin.x, origin.z) + Vec2::new(x as f32, z as f32) * voxel_size;
+
+                // the overlapping samples are taken from the neighbouring nodes, like their chunks do
+                let height = node_atlas
+                    .lookup_attachment_from_lod(HEIGHT_ATTACHMENT, position, lod)
+                    .and_then(|(_, handle, coords)| {
+                        Some(attachment.sample(images.get(handle)?, coords, 0))
+                    })
+                    .unwrap_or_else(|| {
+                        let coords =
+                            ((position - node_rect.min) / node_size).clamp(Vec2::ZERO, Vec2::ONE);
+                        attachment.sample(image, coords, 0)
+                    });
+
+                height * node_atlas.height
+            })
+            .collect::<Vec<_>>();
+
+        let density = self.density.clone();
+
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            let densities = iproduct!(0..dimensions.z, 0..dimensions.y, 0..dimensions.x)
+                .map(|(z, y, x)| {
+                    let position = origin + Vec3::new(x as f32, y as f32, z as f32) * voxel_size;
+                    let height = heights[(z * dimensions.x + x) as usize];
+
+                    density(position, height)
+                })
+                .collect::<Vec<_>>();
+
+            surface_nets(&densities, dimensions, cells, voxel_size)
+        });
+
+        Some((origin, task))
+    }
+}
+
+/// Meshes the boundary between the solid and the empty voxels with naive surface nets,
+/// which place one vertex per cell crossed by the surface and connect the vertices of
+/// the four cells around each crossed edge with a quad.
+///
+/// Only the crossed edges starting inside the owned cells are meshed, so that the cells
+/// overlapping the following chunk merely complete the quads on the faces shared with it.
+///
+/// Returns `None` if the surface does not cross the chunk.
+fn surface_nets(
+    densities: &[f32],
+    dimensions: UVec3,
+    owned_cells: UVec3,
+    voxel_size: f32,
+) -> Option<Mesh> {
+    let sample_index =
+        |point: UVec3| ((point.z * dimensions.y + point.y) * dimensions.x + point.x) as usize;
+    let cells = dimensions - 1;
+    let cell_index = |cell: UVec3| ((cell.z * cells.y + cell.y) * cells.x + cell.x) as usize;
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut cell_vertices = vec![u32::MAX; (cells.x * cells.y * cells.z) as usize];
+
+    for (z, y, x) in iproduct!(0..cells.z, 0..cells.y, 0..cells.x) {
+        let cell = UVec3::new(x, y, z);
+        let corners = [0, 1, 2, 3, 4, 5, 6, 7].map(|corner| {
+            let offset = UVec3::new(corner & 1, (corner >> 1) & 1, corner >> 2);
+            (offset.as_vec3(), densities[sample_index(cell + offset)])
+        });
+
+        let solid_count = corners.iter().filter(|(_, density)| *density > 0.0).count();
+
+        if solid_count == 0 || solid_count == 8 {
+            continue;
+        }
+
+        // the vertex is placed at the average of the surface crossings along the edges of the cell
+        let mut crossing_sum = Vec3::ZERO;
+        let mut crossing_count = 0;
+
+        for (a, b) in iproduct!(0..8, 0..8) {
+            // each edge connects two corners, which differ in exactly one axis
+            if a >= b || (a ^ b).count_ones() != 1 {
+                continue;
+            }
+
+            let ((position_a, density_a), (position_b, density_b)) = (corners[a], corners[b]);
+
+            if (density_a > 0.0) != (density_b > 0.0) {
+                let t = density_a / (density_a - density_b);
+                crossing_sum += position_a.lerp(position_b, t);
+                crossing_count += 1;
+            }
+        }
+
+        // the densities increase towards the solid side, so the surface faces along the negated gradient
+        let gradient = corners
+            .iter()
+            .fold(Vec3::ZERO, |gradient, &(offset, density)| {
+                gradient + (offset * 2.0 - 1.0) * density
+            });
+
+        let position = cell.as_vec3() + crossing_sum / crossing_count as f32;
+
+        cell_vertices[cell_index(cell)] = positions.len() as u32;
+        positions.push((position * voxel_size).to_array());
+        normals.push((-gradient).normalize_or_zero().to_array());
+    }
+
+    let mut indices = Vec::new();
+
+    // each crossed edge is surrounded by four cells, whose vertices form a quad,
+    // where the second and third axis follow the first one cyclically
+    for axis in 0..3 {
+        let axes = [UVec3::X, UVec3::Y, UVec3::Z];
+        let (a, b, c) = (axes[axis], axes[(axis + 1) % 3], axes[(axis + 2) % 3]);
+
+        for (z, y, x) in iproduct!(0..dimensions.z, 0..dimensions.y, 0..dimensions.x) {
+            let point = UVec3::new(x, y, z);
+            let end = point + a;
+
+            // the edge has to start inside the owned cells and all four cells around it have to exist
+            if point.dot(a) >= owned_cells.dot(a)
+                || point.dot(b) == 0
+                || point.dot(c) == 0
+                || point.dot(b) >= cells.dot(b)
+                || point.dot(c) >= cells.dot(c)
+            {
+                continue;
+            }
+
+            let solid = densities[sample_index(point)] > 0.0;
+
+            if solid == (densities[sample_index(end)] > 0.0) {
+                continue;
+            }
+
+            let quad = [point - b - c, point - c, point, point - b]
+                .map(|cell| cell_vertices[cell_index(cell)]);
+
+            if quad.contains(&u32::MAX) {
+                continue;
+            }
+
+            // the quad faces away from the solid side of the edge
+            if solid {
+                indices.extend([quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+            } else {
+                indices.extend([quad[0], quad[2], quad[1], quad[0], quad[3], quad[2]]);
+            }
+        }
+    }
+
+    if indices.is_empty() {
+        return None;
+    }
+
+    let uvs = positions
+        .iter()
+        .map(|&[x, _, z]| [x, z])
+        .collect::<Vec<_>>();
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(indices)));
+
+    Some(mesh)
+}
+
+/// Adds the voxel chunks of the terrains with a [`TerrainVolumes`] component.
+pub struct TerrainVolumePlugin;
+
+impl Plugin for TerrainVolumePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(
+            update_volume_chunks
+                .after(TerrainSystemSet::Update)
+                .in_base_set(CoreSet::Last),
+        );
+    }
+}
+
+/// Spawns and despawns the voxel chunks of all terrains,
+/// according to the lifecycle of the nodes of their node atlas.
+fn update_volume_chunks(
+    mut commands: Commands,
+    images: Res<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut terrain_query: Query<(Entity, &NodeAtlas, &mut TerrainVolumes)>,
+) {
+    for (terrain, node_atlas, mut volumes) in terrain_query.iter_mut() {
+        let lod = volumes.lod;
+
+        let deactivated = node_atlas
+            .lifecycle_events
+            .iter()
+            .filter(|&&(_, lifecycle)| lifecycle == NodeLifecycle::Deactivated)
+            .map(|&(node_id, _)| node_id)
+            .chain(node_atlas.edited_nodes.iter().copied())
+            .collect::<Vec<_>>();
+
+        // dropping the meshing task of a chunk cancels it
+        for (_, chunk) in volumes
+            .chunks
+            .drain_filter(|(node_id, _), _| deactivated.contains(node_id))
+        {
+            commands.entity(chunk.entity).despawn_recursive();
+        }
+
+        let activated = node_atlas
+            .lifecycle_events
+            .iter()
+            .filter(|&&(_, lifecycle)| lifecycle == NodeLifecycle::Activated)
+            .map(|&(node_id, _)| node_id)
+            .chain(node_atlas.edited_nodes.iter().copied())
+            .filter(|&node_id| {
+                NodeCoordinate::from(node_id).lod == lod && node_atlas.nodes.contains_key(&node_id)
+            })
+            .collect::<Vec<_>>();
+
+        let started = iproduct!(activated, 0..volumes.regions.len())
+            .filter_map(|(node_id, region_index)| {
+                let region = &volumes.regions[region_index];
+                let (origin, task) = volumes.start_meshing(node_atlas, &images, node_id, region)?;
+
+                Some(((node_id, region_index), origin, task))
+            })
+            .collect::<Vec<_>>();
+
+        for (key, origin, task) in started {
+            let chunk = commands
+                .spawn(SpatialBundle::from_transform(Transform::from_translation(
+                    origin,
+                )))
+                .id();
+
+            commands.entity(terrain).add_child(chunk);
+            volumes.chunks.insert(
+                key,
+                VolumeChunk {
+                    entity: chunk,
+                    task: Some(task),
+                },
+            );
+        }
+
+        let material = volumes.material.clone();
+
+        for chunk in volumes.chunks.values_mut() {
+            let Some(task) = &mut chunk.task else {
+                continue;
+            };
+
+            let Some(mesh) = future::block_on(future::poll_once(task)) else {
+                continue;
+            };
+
+            chunk.task = None;
+
+            if let Some(mesh) = mesh {
+                commands
+                    .entity(chunk.entity)
+                    .insert((meshes.add(mesh), material.clone()));
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Renders water surfaces, which follow the streamed terrain.
+//!
+//! The water attachment stores a mask of the submerged regions in its first channel and the
+//! water level (normalized to the height of the terrain) in its second one, so that lakes at
+//! different levels can share the same terrain.
+//! Each terrain with a [`TerrainWater`] component spawns one water mesh child per loaded node
+//! of the configured lod, which only covers the masked pixels of the node.
+//! Like the colliders, the meshes are despawned again, once their node has been unloaded
+//! for longer than the configured hysteresis, and rebuilt, once their node is edited.
+//! Add the [`TerrainWaterPlugin`] to enable the water surfaces.
+
+use crate::{
+    terrain_data::{
+        node_atlas::NodeAtlas, node_entities::NodeEntities, AttachmentConfig, AttachmentFormat,
+        AttachmentIndex, NodeCoordinate, NodeId,
+    },
+    TerrainSystemSet,
+};
+use bevy::{
+    pbr::NotShadowCaster,
+    prelude::*,
+    render::{mesh::Indices, render_resource::PrimitiveTopology},
+};
+use itertools::iproduct;
+
+/// The mask value, above which a pixel of the water attachment is covered by water.
+const MASK_THRESHOLD: f32 = 0.5;
+
+/// Creates the config of the water attachment.
+pub fn water_attachment(texture_size: u32, mip_level_count: u32) -> AttachmentConfig {
+    AttachmentConfig::new(
+        "water".to_string(),
+        texture_size,
+        1,
+        mip_level_count,
+        AttachmentFormat::Rg16,
+    )
+}
+
+/// Configures the water surfaces of a terrain.
+#[derive(Component)]
+pub struct TerrainWater {
+    /// The index of the water attachment.
+    pub attachment_index: AttachmentIndex,
+    /// The lod of the nodes, for which water surfaces are generated.
+    pub lod: u32,
+    /// The material of the water surfaces.
+    pub material: Handle<StandardMaterial>,
+    /// A fixed water level (in the local space of the terrain), which overrides the levels
+    /// stored in the water attachment, e.g. for an ocean.
+    pub level: Option<f32>,
+    /// The amount of frames a node has to be unloaded, before its water surface is despawned.
+    pub hysteresis: u32,
+    /// The water surface entities of the currently loaded nodes.
+    surfaces: NodeEntities,
+}
+
+impl TerrainWater {
+    /// Creates a new water config, which renders the water attachment of the nodes of the lod.
+    pub fn new(
+        attachment_index: AttachmentIndex,
+        lod: u32,
+        material: Handle<StandardMaterial>,
+    ) -> Self {
+        Self {
+            attachment_index,
+            lod,
+            material,
+            level: None,
+            hysteresis: 60,
+            surfaces: default(),
+        }
+    }
+
+    /// Uses the fixed water level instead of the levels of the water attachment.
+    pub fn with_level(mut self, level: f32) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Determines the nodes of the lod, that have been loaded since the last update,
+    /// and removes the surfaces of all nodes, that have not been loaded for too long.
+    /// The surfaces of edited nodes are rebuilt.
+    ///
+    /// Returns the ids of the newly loaded nodes and the entities of the outdated surfaces.
+    fn update(&mut self, node_atlas: &NodeAtlas) -> (Vec<NodeId>, Vec<Entity>) {
+        let attachment_index = self.attachment_index;
+
+        self.surfaces
+            .update(node_atlas, self.lod, self.hysteresis, |node_id| {
+                node_atlas.edited_nodes.contains(&node_id)
+                    || node_atlas
+                        .painted_nodes
+                        .contains(&(node_id, attachment_index))
+            })
+    }
+
+    /// Builds the water mesh of the node, with one quad per masked pixel of the water attachment.
+    ///
+    /// Returns `None` if the node is not covered by water or its water data is not available on the CPU.
+    fn build_mesh(
+        &self,
+        node_atlas: &NodeAtlas,
+        images: &Assets<Image>,
+        node_id: NodeId,
+    ) -> Option<Mesh> {
+        let node = node_atlas.nodes.get(&node_id)?;
+        let image = images.get(
+            node_atlas.data[node.atlas_index as usize]
+                .attachments
+                .get(&self.attachment_index)?,
+        )?;
+
+        let attachment = &node_atlas.attachments[self.attachment_index];
+        let size = (node_atlas.leaf_node_size << NodeCoordinate::from(node_id).lod) as f32;
+        let (center_size, border_size) = (attachment.center_size, attachment.border_size);
+        let resolution = center_size + 1;
+
+        let mut positions = Vec::new();
+        let mut uvs = Vec::new();
+
+        for (y, x) in iproduct!(0..resolution, 0..resolution) {
+            let coords = Vec2::new(x as f32, y as f32) / center_size as f32;
+            let level = self
+                .level
+                .unwrap_or_else(|| attachment.sample(image, coords, 1) * node_atlas.height);
+
+            positions.push([coords.x * size, level, coords.y * size]);
+            uvs.push(coords.to_array());
+        }
+
+        let mut indices = Vec::new();
+
+        // the surface is clipped to the masked pixels, the terrain hides it where it lies above
+        for (y, x) in iproduct!(0..center_size, 0..center_size) {
+            let mask = attachment.load(image, x + border_size, y + border_size, 0);
+
+            if mask < MASK_THRESHOLD {
+                continue;
+            }
+
+            let index = y * resolution + x;
+
+            indices.extend([
+                index,
+                index + resolution,
+                index + 1,
+                index + 1,
+                index + resolution,
+                index + resolution + 1,
+            ]);
+        }
+
+        if indices.is_empty() {
+            return None;
+        }
+
+        let normals = vec![[0.0, 1.0, 0.0]; positions.len()];
+
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+        mesh.set_indices(Some(Indices::U32(indices)));
+
+        Some(mesh)
+    }
+}
+
+/// Adds the water surfaces of the terrains with a [`TerrainWater`] component.
+pub struct TerrainWaterPlugin;
+
+impl Plugin for TerrainWaterPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(
+            update_water_surfaces
+                .after(TerrainSystemSet::Update)
+                .in_base_set(CoreSet::Last),
+        );
+    }
+}
+
+/// Spawns and despawns the water surfaces of all terrains,
+/// according to the loaded nodes of their node atlas.
+fn update_water_surfaces(
+    mut commands: Commands,
+    images: Res<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut terrain_query: Query<(Entity, &NodeAtlas, &mut TerrainWater)>,
+) {
+    for (terrain, node_atlas, mut water) in terrain_query.iter_mut() {
+        let (added, removed) = water.update(node_atlas);
+
+        for entity in removed {
+            commands.entity(entity).despawn_recursive();
+        }
+
+        for node_id in added {
+            let NodeCoordinate { lod, x, y } = node_id.into();
+            let size = (node_atlas.leaf_node_size << lod) as f32;
+
+            // nodes without water keep an empty entity, so that they are not rebuilt every frame
+            let mut surface = commands.spawn(SpatialBundle::from_transform(Transform::from_xyz(
+                x as f32 * size,
+                0.0,
+                y as f32 * size,
+            )));
+
+            if let Some(mesh) = water.build_mesh(node_atlas, &images, node_id) {
+                surface.insert((meshes.add(mesh), water.material.clone(), NotShadowCaster));
+            }
+
+            let surface = surface.id();
+
+            commands.entity(terrain).add_child(surface);
+            water.surfaces.insert(node_id, surface);
+        }
+    }
+}